@@ -1,16 +1,25 @@
-use std::path::PathBuf;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
 fn main() {
     let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL").unwrap());
 
+    let ramdisk_path = out_dir.join("initrd.tar");
+    fs::write(&ramdisk_path, pack_initrd(Path::new("initrd"))).unwrap();
+    println!("cargo:rerun-if-changed=initrd");
+
     let uefi_path = out_dir.join("uefi.img");
     bootloader::UefiBoot::new(&kernel)
+        .set_ramdisk(&ramdisk_path)
         .create_disk_image(&uefi_path)
         .unwrap();
 
     let bios_path = out_dir.join("bios.img");
     bootloader::BiosBoot::new(&kernel)
+        .set_ramdisk(&ramdisk_path)
         .create_disk_image(&bios_path)
         .unwrap();
 
@@ -18,3 +27,70 @@ fn main() {
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
 }
+
+/// Packs the files under `dir` into a ustar archive, with paths relative to `dir`.
+fn pack_initrd(dir: &Path) -> Vec<u8> {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files);
+    files.sort();
+
+    let mut archive = Vec::new();
+
+    for path in files {
+        let name = path
+            .strip_prefix(dir)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .replace('\\', "/");
+        let data = fs::read(&path).unwrap();
+
+        archive.extend_from_slice(&tar_header(&name, data.len()));
+        archive.extend_from_slice(&data);
+        archive.resize(archive.len().next_multiple_of(512), 0);
+    }
+
+    // The archive ends with two zeroed blocks.
+    archive.resize(archive.len() + 1024, 0);
+    archive
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries {
+        let path = entry.unwrap().path();
+
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+fn tar_header(name: &str, size: usize) -> [u8; 512] {
+    assert!(name.len() < 100, "initrd path too long: {name}");
+
+    let mut header = [0u8; 512];
+    let mut field = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+
+    field(0, name.as_bytes());
+    field(100, b"0000644\0"); // mode
+    field(108, b"0000000\0"); // uid
+    field(116, b"0000000\0"); // gid
+    field(124, format!("{size:011o}\0").as_bytes());
+    field(136, b"00000000000\0"); // mtime
+    field(148, b"        "); // checksum, counted as spaces
+    field(156, b"0"); // regular file
+    field(257, b"ustar\0");
+    field(263, b"00");
+
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+    header
+}
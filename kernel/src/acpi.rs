@@ -0,0 +1,196 @@
+//! Discovery of ACPI tables.
+//!
+//! The firmware leaves a RSDP (Root System Description Pointer) in memory, whose address the bootloader
+//! passes to us. It points to the RSDT (32-bit entries) or, since ACPI 2.0, the XSDT (64-bit entries),
+//! which lists the physical addresses of all other tables. Every table starts with the same `SdtHeader`.
+
+use conquer_once::spin::OnceCell;
+use core::{mem, ptr, slice};
+use x86_64::PhysAddr;
+
+use crate::memory;
+
+pub mod aml;
+
+/// Offsets of the 32-bit and 64-bit DSDT pointers inside the FADT.
+const FADT_DSDT_OFFSET: usize = 40;
+const FADT_X_DSDT_OFFSET: usize = 140;
+
+static ROOT_TABLE: OnceCell<RootTable> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy)]
+struct RootTable {
+    address: PhysAddr,
+    /// 4 bytes for the RSDT, 8 bytes for the XSDT.
+    entry_size: usize,
+}
+
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // The fields below only exist from revision 2 onwards.
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Header shared by all system description tables.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Generic Address Structure, used by tables to describe register locations.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    /// 0 = system memory, 1 = system I/O.
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    InvalidRsdp,
+    InvalidChecksum,
+    AlreadyInitialized,
+}
+
+/// All bytes of a table (including the checksum field) must add up to zero.
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+unsafe fn bytes_at(address: PhysAddr, len: usize) -> &'static [u8] {
+    unsafe { slice::from_raw_parts(memory::phys_to_virt(address).as_ptr::<u8>(), len) }
+}
+
+/// Validates the RSDP and remembers the root table. Requires `memory::init`.
+pub fn init(rsdp_address: PhysAddr) -> Result<(), AcpiError> {
+    let rsdp = unsafe { ptr::read_unaligned(memory::phys_to_virt(rsdp_address).as_ptr::<Rsdp>()) };
+
+    if &rsdp.signature != b"RSD PTR " {
+        return Err(AcpiError::InvalidRsdp);
+    }
+
+    // The first 20 bytes are covered by the ACPI 1.0 checksum.
+    if !checksum_ok(unsafe { bytes_at(rsdp_address, 20) }) {
+        return Err(AcpiError::InvalidChecksum);
+    }
+
+    let root = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        if !checksum_ok(unsafe { bytes_at(rsdp_address, rsdp.length as usize) }) {
+            return Err(AcpiError::InvalidChecksum);
+        }
+
+        RootTable {
+            address: PhysAddr::new(rsdp.xsdt_address),
+            entry_size: 8,
+        }
+    } else {
+        RootTable {
+            address: PhysAddr::new(rsdp.rsdt_address as u64),
+            entry_size: 4,
+        }
+    };
+
+    ROOT_TABLE
+        .try_init_once(|| root)
+        .map_err(|_| AcpiError::AlreadyInitialized)
+}
+
+fn header_at(address: PhysAddr) -> SdtHeader {
+    unsafe { ptr::read_unaligned(memory::phys_to_virt(address).as_ptr::<SdtHeader>()) }
+}
+
+/// Physical addresses of every table listed in the RSDT/XSDT.
+pub fn tables() -> impl Iterator<Item = PhysAddr> {
+    let root = ROOT_TABLE.get().copied();
+
+    let (address, entry_size, count) = match root {
+        Some(root) => {
+            let header = header_at(root.address);
+            let entries_len = header.length as usize - mem::size_of::<SdtHeader>();
+            (root.address, root.entry_size, entries_len / root.entry_size)
+        }
+        None => (PhysAddr::zero(), 8, 0),
+    };
+
+    let entries = address + mem::size_of::<SdtHeader>() as u64;
+
+    (0..count).map(move |index| {
+        let entry = memory::phys_to_virt(entries + (index * entry_size) as u64);
+
+        let table_address = unsafe {
+            if entry_size == 8 {
+                ptr::read_unaligned(entry.as_ptr::<u64>())
+            } else {
+                ptr::read_unaligned(entry.as_ptr::<u32>()) as u64
+            }
+        };
+
+        PhysAddr::new(table_address)
+    })
+}
+
+/// Finds the first table with the given signature (e.g. `b"HPET"`) whose checksum is valid.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    tables().find(|&address| {
+        let header = header_at(address);
+
+        header.signature == *signature
+            && checksum_ok(unsafe { bytes_at(address, header.length as usize) })
+    })
+}
+
+/// The whole table at `address`, header included.
+pub fn table_bytes(address: PhysAddr) -> &'static [u8] {
+    let header = header_at(address);
+    unsafe { bytes_at(address, header.length as usize) }
+}
+
+/// The DSDT, whose address is only found in the FADT.
+pub fn dsdt() -> Option<PhysAddr> {
+    let fadt = find_table(b"FACP")?;
+    let header = header_at(fadt);
+
+    // ACPI 2.0 added a 64-bit pointer, which takes precedence when set.
+    if header.length as usize >= FADT_X_DSDT_OFFSET + 8 {
+        let bytes: [u8; FADT_X_DSDT_OFFSET + 8] = unsafe { read_table(fadt) };
+        let x_dsdt = u64::from_le_bytes(bytes[FADT_X_DSDT_OFFSET..].try_into().unwrap());
+
+        if x_dsdt != 0 {
+            return Some(PhysAddr::new(x_dsdt));
+        }
+    }
+
+    let bytes: [u8; FADT_DSDT_OFFSET + 4] = unsafe { read_table(fadt) };
+    let dsdt = u32::from_le_bytes(bytes[FADT_DSDT_OFFSET..].try_into().unwrap());
+    (dsdt != 0).then(|| PhysAddr::new(dsdt as u64))
+}
+
+/// Reads a table whose layout begins with an `SdtHeader`.
+///
+/// # Safety
+///
+/// `address` must point to a table that really has the layout of `T`.
+pub unsafe fn read_table<T: Copy>(address: PhysAddr) -> T {
+    unsafe { ptr::read_unaligned(memory::phys_to_virt(address).as_ptr::<T>()) }
+}
@@ -0,0 +1,781 @@
+//! A small subset of AML, the bytecode in the DSDT and SSDTs.
+//!
+//! Modern firmware may declare devices (PS/2 controller, serial ports, RTC) only in AML, so their I/O ports
+//! and IRQs have to be read from the namespace instead of being hard-coded. Loading a table walks its
+//! definition blocks (scopes, devices, names, methods) and records every named object under its absolute
+//! path, such as `\_SB_.PCI0.KBD_`.
+//!
+//! This is not a full interpreter. Conditional definitions (`If`/`Else`) are skipped, and a method can only
+//! be evaluated when its body is a single `Return` of a constant or of another named object, which is
+//! enough for the usual `_HID`, `_STA` and `_CRS` methods. When an opcode that isn't understood shows up in
+//! a definition block, the rest of the enclosing scope is skipped, since the length of an unknown term
+//! can't be known.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::mem;
+
+use super::SdtHeader;
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const ALIAS_OP: u8 = 0x06;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0A;
+const WORD_PREFIX: u8 = 0x0B;
+const DWORD_PREFIX: u8 = 0x0C;
+const STRING_PREFIX: u8 = 0x0D;
+const QWORD_PREFIX: u8 = 0x0E;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const PACKAGE_OP: u8 = 0x12;
+const VAR_PACKAGE_OP: u8 = 0x13;
+const METHOD_OP: u8 = 0x14;
+const EXTERNAL_OP: u8 = 0x15;
+const DUAL_NAME_PREFIX: u8 = 0x2E;
+const MULTI_NAME_PREFIX: u8 = 0x2F;
+const EXT_OP_PREFIX: u8 = 0x5B;
+const ROOT_CHAR: u8 = b'\\';
+const PARENT_PREFIX: u8 = b'^';
+const IF_OP: u8 = 0xA0;
+const ELSE_OP: u8 = 0xA1;
+const WHILE_OP: u8 = 0xA2;
+const RETURN_OP: u8 = 0xA4;
+const ONES_OP: u8 = 0xFF;
+
+// Second byte of the extended opcodes.
+const MUTEX_OP: u8 = 0x01;
+const EVENT_OP: u8 = 0x02;
+const REVISION_OP: u8 = 0x30;
+const REGION_OP: u8 = 0x80;
+const FIELD_OP: u8 = 0x81;
+const DEVICE_OP: u8 = 0x82;
+const PROCESSOR_OP: u8 = 0x83;
+const POWER_RES_OP: u8 = 0x84;
+const THERMAL_ZONE_OP: u8 = 0x85;
+const INDEX_FIELD_OP: u8 = 0x86;
+const BANK_FIELD_OP: u8 = 0x87;
+
+/// Value of `Revision`, the version of the AML interpreter.
+const INTERPRETER_REVISION: u64 = 2;
+
+/// How many names a method evaluation may follow before giving up, which also stops reference cycles.
+const MAX_EVALUATION_DEPTH: usize = 8;
+
+static NAMESPACE: OnceCell<Namespace> = OnceCell::uninit();
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Object {
+    Integer(u64),
+    String(&'static str),
+    /// The initializer of a buffer. Buffers declared larger than their initializer aren't zero-padded.
+    Buffer(&'static [u8]),
+    Package(Vec<Object>),
+    /// A name inside a package or an alias, resolved when evaluated.
+    Reference(String),
+    Device,
+    /// Scopes, processors, power resources and thermal zones, which only contain other objects.
+    Scope,
+    Method {
+        arg_count: u8,
+        body: &'static [u8],
+    },
+    /// Operation regions, mutexes and events: they exist, but aren't interpreted.
+    Opaque,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmlError {
+    /// The FADT doesn't point to a DSDT, or ACPI wasn't initialized.
+    NoDsdt,
+    AlreadyInitialized,
+    UnexpectedEnd,
+    /// The opcode, or the second byte of an extended opcode.
+    UnsupportedOpcode(u8),
+    InvalidString,
+    NotFound,
+    /// The method does more than returning a value.
+    UnsupportedMethod,
+    TooDeep,
+}
+
+/// A resource from a `_CRS` resource template.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Io {
+        base: u16,
+        length: u16,
+    },
+    Memory {
+        base: u64,
+        length: u64,
+    },
+    /// A legacy IRQ line (0-15).
+    Irq(u8),
+    /// A global system interrupt from an extended interrupt descriptor.
+    Interrupt(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device<'a> {
+    pub path: &'a str,
+    /// The hardware ID, such as `PNP0303` for a PS/2 keyboard controller.
+    pub hid: Option<String>,
+    pub resources: Vec<Resource>,
+}
+
+#[derive(Debug, Default)]
+pub struct Namespace {
+    objects: BTreeMap<String, Object>,
+}
+
+impl Namespace {
+    pub fn new() -> Self {
+        Namespace::default()
+    }
+
+    /// Loads a definition block, i.e. a table without its header.
+    pub fn load(&mut self, aml: &'static [u8]) -> Result<(), AmlError> {
+        let mut parser = Parser {
+            bytes: aml,
+            position: 0,
+        };
+        parser.term_list(self, "\\", aml.len())
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Object> {
+        self.objects.get(path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// Finds `name` as seen from `scope`. A single name segment is searched in `scope` and then in each of
+    /// its parents, as AML requires; any other name is resolved exactly.
+    pub fn lookup(&self, scope: &str, name: &str) -> Option<(&str, &Object)> {
+        let single_segment = !name.starts_with(['\\', '^']) && !name.contains('.');
+
+        if !single_segment {
+            let path = resolve(scope, name);
+            return self
+                .objects
+                .get_key_value(&path)
+                .map(|(path, object)| (path.as_str(), object));
+        }
+
+        let mut scope = scope;
+        loop {
+            let path = resolve(scope, name);
+            if let Some((path, object)) = self.objects.get_key_value(&path) {
+                return Some((path.as_str(), object));
+            }
+            if scope == "\\" {
+                return None;
+            }
+            scope = parent(scope);
+        }
+    }
+
+    /// The value of the object at `path`, running it if it's a method.
+    pub fn evaluate(&self, path: &str) -> Result<Object, AmlError> {
+        self.evaluate_at(path, 0)
+    }
+
+    fn evaluate_at(&self, path: &str, depth: usize) -> Result<Object, AmlError> {
+        if depth > MAX_EVALUATION_DEPTH {
+            return Err(AmlError::TooDeep);
+        }
+
+        let (path, object) = self.lookup("\\", path).ok_or(AmlError::NotFound)?;
+
+        match object {
+            Object::Method { body, .. } => {
+                let mut parser = Parser {
+                    bytes: body,
+                    position: 0,
+                };
+
+                if parser.byte()? != RETURN_OP {
+                    return Err(AmlError::UnsupportedMethod);
+                }
+
+                if parser.peek_name()? {
+                    let name = parser.name_string()?;
+                    let (target, _) = self.lookup(path, &name).ok_or(AmlError::NotFound)?;
+                    self.evaluate_at(target, depth + 1)
+                } else {
+                    parser.data_object().map_err(|error| match error {
+                        AmlError::UnsupportedOpcode(_) => AmlError::UnsupportedMethod,
+                        error => error,
+                    })
+                }
+            }
+            Object::Reference(name) => {
+                let (target, _) = self.lookup(parent(path), name).ok_or(AmlError::NotFound)?;
+                self.evaluate_at(target, depth + 1)
+            }
+            object => Ok(object.clone()),
+        }
+    }
+
+    /// Every device with its hardware ID and current resources, when they can be evaluated.
+    pub fn devices(&self) -> impl Iterator<Item = Device<'_>> {
+        self.objects
+            .iter()
+            .filter(|(_, object)| **object == Object::Device)
+            .map(|(path, _)| {
+                let child = |name: &str| self.evaluate(&resolve(path, name));
+
+                let hid = match child("_HID") {
+                    Ok(Object::Integer(id)) => Some(decode_eisa_id(id as u32)),
+                    Ok(Object::String(id)) => Some(String::from(id)),
+                    _ => None,
+                };
+
+                let resources = match child("_CRS") {
+                    Ok(Object::Buffer(template)) => parse_resources(template),
+                    _ => Vec::new(),
+                };
+
+                Device {
+                    path,
+                    hid,
+                    resources,
+                }
+            })
+    }
+
+    /// The `SLP_TYPa` and `SLP_TYPb` values for sleep state `state` (e.g. 5 for soft-off), from `\_Sx_`.
+    pub fn sleep_types(&self, state: u8) -> Option<(u8, u8)> {
+        let name = [b'\\', b'_', b'S', b'0' + state, b'_'];
+        let name = core::str::from_utf8(&name).ok()?;
+
+        let Ok(Object::Package(values)) = self.evaluate(name) else {
+            return None;
+        };
+
+        let value = |index: usize| match values.get(index) {
+            Some(Object::Integer(value)) => Some(*value as u8),
+            _ => None,
+        };
+
+        Some((value(0)?, value(1).unwrap_or(0)))
+    }
+
+    fn insert(&mut self, path: String, object: Object) {
+        self.objects.insert(path, object);
+    }
+}
+
+/// Loads the DSDT and every SSDT into the global namespace. Requires `acpi::init` and the heap.
+pub fn init() -> Result<(), AmlError> {
+    let dsdt = super::dsdt().ok_or(AmlError::NoDsdt)?;
+
+    let mut namespace = Namespace::new();
+    namespace.load(definition_block(dsdt))?;
+
+    for table in super::tables() {
+        let header: SdtHeader = unsafe { super::read_table(table) };
+
+        if &header.signature == b"SSDT" {
+            namespace.load(definition_block(table))?;
+        }
+    }
+
+    NAMESPACE
+        .try_init_once(|| namespace)
+        .map_err(|_| AmlError::AlreadyInitialized)
+}
+
+pub fn namespace() -> Option<&'static Namespace> {
+    NAMESPACE.get()
+}
+
+fn definition_block(table: x86_64::PhysAddr) -> &'static [u8] {
+    &super::table_bytes(table)[mem::size_of::<SdtHeader>()..]
+}
+
+/// Joins a name to the absolute path of a scope.
+fn resolve(scope: &str, name: &str) -> String {
+    if name.starts_with('\\') {
+        return String::from(name);
+    }
+
+    let mut scope = scope;
+    let mut name = name;
+    while let Some(rest) = name.strip_prefix('^') {
+        scope = parent(scope);
+        name = rest;
+    }
+
+    let mut path = String::from(scope);
+    if !name.is_empty() {
+        if scope != "\\" {
+            path.push('.');
+        }
+        path.push_str(name);
+    }
+    path
+}
+
+fn parent(path: &str) -> &str {
+    match path.rfind('.') {
+        Some(index) => &path[..index],
+        None => "\\",
+    }
+}
+
+/// Decodes a compressed EISA ID such as `EisaId("PNP0303")`.
+pub fn decode_eisa_id(id: u32) -> String {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+    // Stored in big-endian order inside a little-endian integer.
+    let id = id.swap_bytes();
+    let mut decoded = String::with_capacity(7);
+
+    for shift in [26, 21, 16] {
+        decoded.push((b'@' + ((id >> shift) & 0x1F) as u8) as char);
+    }
+    for shift in [12, 8, 4, 0] {
+        decoded.push(HEX[((id >> shift) & 0xF) as usize] as char);
+    }
+
+    decoded
+}
+
+/// Decodes a resource template, as returned by `_CRS`. Malformed descriptors end the list.
+pub fn parse_resources(template: &[u8]) -> Vec<Resource> {
+    const SMALL_IRQ: u8 = 0x04;
+    const SMALL_IO: u8 = 0x08;
+    const SMALL_FIXED_IO: u8 = 0x09;
+    const SMALL_END: u8 = 0x0F;
+    const LARGE_MEMORY_32: u8 = 0x05;
+    const LARGE_FIXED_MEMORY_32: u8 = 0x06;
+    const LARGE_DWORD_ADDRESS: u8 = 0x07;
+    const LARGE_WORD_ADDRESS: u8 = 0x08;
+    const LARGE_EXTENDED_IRQ: u8 = 0x09;
+    const LARGE_QWORD_ADDRESS: u8 = 0x0A;
+    const ADDRESS_MEMORY: u8 = 0;
+    const ADDRESS_IO: u8 = 1;
+
+    fn read(data: &[u8], offset: usize, len: usize) -> Option<u64> {
+        let bytes = data.get(offset..offset + len)?;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |value, &byte| (value << 8) | byte as u64),
+        )
+    }
+
+    let mut resources = Vec::new();
+    let mut position = 0;
+
+    while let Some(&tag) = template.get(position) {
+        if tag & 0x80 == 0 {
+            let len = (tag & 0x07) as usize;
+            let Some(data) = template.get(position + 1..position + 1 + len) else {
+                break;
+            };
+
+            match (tag >> 3) & 0x0F {
+                SMALL_IRQ => {
+                    let mask = read(data, 0, 2).unwrap_or(0);
+                    resources.extend(
+                        (0..16)
+                            .filter(|line| mask & (1 << line) != 0)
+                            .map(Resource::Irq),
+                    );
+                }
+                SMALL_IO => {
+                    if let (Some(base), Some(length)) = (read(data, 1, 2), read(data, 6, 1)) {
+                        resources.push(Resource::Io {
+                            base: base as u16,
+                            length: length as u16,
+                        });
+                    }
+                }
+                SMALL_FIXED_IO => {
+                    if let (Some(base), Some(length)) = (read(data, 0, 2), read(data, 2, 1)) {
+                        resources.push(Resource::Io {
+                            // Fixed I/O descriptors only decode 10 address bits.
+                            base: (base & 0x3FF) as u16,
+                            length: length as u16,
+                        });
+                    }
+                }
+                SMALL_END => break,
+                _ => {}
+            }
+
+            position += 1 + len;
+        } else {
+            let Some(len) = read(template, position + 1, 2) else {
+                break;
+            };
+            let len = len as usize;
+            let Some(data) = template.get(position + 3..position + 3 + len) else {
+                break;
+            };
+
+            // Address space descriptors: (granularity offset, field size).
+            let address_layout = match tag & 0x7F {
+                LARGE_WORD_ADDRESS => Some((3, 2)),
+                LARGE_DWORD_ADDRESS => Some((3, 4)),
+                LARGE_QWORD_ADDRESS => Some((3, 8)),
+                _ => None,
+            };
+
+            match tag & 0x7F {
+                LARGE_MEMORY_32 => {
+                    if let (Some(base), Some(length)) = (read(data, 1, 4), read(data, 13, 4)) {
+                        resources.push(Resource::Memory { base, length });
+                    }
+                }
+                LARGE_FIXED_MEMORY_32 => {
+                    if let (Some(base), Some(length)) = (read(data, 1, 4), read(data, 5, 4)) {
+                        resources.push(Resource::Memory { base, length });
+                    }
+                }
+                LARGE_EXTENDED_IRQ => {
+                    let count = data.get(1).copied().unwrap_or(0) as usize;
+                    resources.extend(
+                        (0..count)
+                            .filter_map(|index| read(data, 2 + index * 4, 4))
+                            .map(|interrupt| Resource::Interrupt(interrupt as u32)),
+                    );
+                }
+                _ => {}
+            }
+
+            // Granularity, minimum, maximum, translation offset and length follow each other.
+            if let Some((granularity, size)) = address_layout {
+                let minimum = read(data, granularity + size, size);
+                let length = read(data, granularity + 4 * size, size);
+
+                match (data.first().copied(), minimum, length) {
+                    (Some(ADDRESS_MEMORY), Some(base), Some(length)) => {
+                        resources.push(Resource::Memory { base, length })
+                    }
+                    (Some(ADDRESS_IO), Some(base), Some(length)) => resources.push(Resource::Io {
+                        base: base as u16,
+                        length: length as u16,
+                    }),
+                    _ => {}
+                }
+            }
+
+            position += 3 + len;
+        }
+    }
+
+    resources
+}
+
+struct Parser {
+    bytes: &'static [u8],
+    position: usize,
+}
+
+impl Parser {
+    fn byte(&mut self) -> Result<u8, AmlError> {
+        let byte = *self
+            .bytes
+            .get(self.position)
+            .ok_or(AmlError::UnexpectedEnd)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn peek(&self) -> Result<u8, AmlError> {
+        self.bytes
+            .get(self.position)
+            .copied()
+            .ok_or(AmlError::UnexpectedEnd)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'static [u8], AmlError> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + len)
+            .ok_or(AmlError::UnexpectedEnd)?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    /// Takes the rest of a package ending at `end`, which what was read of it may already be past.
+    fn take_until(&mut self, end: usize) -> Result<&'static [u8], AmlError> {
+        if end < self.position || end > self.bytes.len() {
+            return Err(AmlError::UnexpectedEnd);
+        }
+        self.take(end - self.position)
+    }
+
+    fn integer(&mut self, len: usize) -> Result<u64, AmlError> {
+        let bytes = self.take(len)?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| (value << 8) | byte as u64))
+    }
+
+    /// Reads a PkgLength and returns the position where the package ends. The length counts itself.
+    fn package_end(&mut self) -> Result<usize, AmlError> {
+        let start = self.position;
+        let lead = self.byte()?;
+        let extra_bytes = (lead >> 6) as usize;
+
+        let length = if extra_bytes == 0 {
+            (lead & 0x3F) as usize
+        } else {
+            let mut length = (lead & 0x0F) as usize;
+            for index in 0..extra_bytes {
+                length |= (self.byte()? as usize) << (4 + 8 * index);
+            }
+            length
+        };
+
+        let end = start + length;
+        if end > self.bytes.len() {
+            return Err(AmlError::UnexpectedEnd);
+        }
+        Ok(end)
+    }
+
+    /// Whether a name string starts here, rather than an opcode.
+    fn peek_name(&self) -> Result<bool, AmlError> {
+        let byte = self.peek()?;
+        Ok(matches!(
+            byte,
+            b'A'..=b'Z' | b'_' | ROOT_CHAR | PARENT_PREFIX | DUAL_NAME_PREFIX | MULTI_NAME_PREFIX
+        ))
+    }
+
+    /// Reads a name string as text, e.g. `\_SB_.PCI0` or `^^FOO_`.
+    fn name_string(&mut self) -> Result<String, AmlError> {
+        let mut name = String::new();
+
+        match self.peek()? {
+            ROOT_CHAR => {
+                self.position += 1;
+                name.push('\\');
+            }
+            PARENT_PREFIX => {
+                while self.peek()? == PARENT_PREFIX {
+                    self.position += 1;
+                    name.push('^');
+                }
+            }
+            _ => {}
+        }
+
+        let segments = match self.byte()? {
+            ZERO_OP => 0,
+            DUAL_NAME_PREFIX => 2,
+            MULTI_NAME_PREFIX => self.byte()? as usize,
+            _ => {
+                self.position -= 1;
+                1
+            }
+        };
+
+        for index in 0..segments {
+            if index > 0 {
+                name.push('.');
+            }
+
+            let segment = self.take(4)?;
+            if !segment
+                .iter()
+                .all(|byte| matches!(byte, b'A'..=b'Z' | b'0'..=b'9' | b'_'))
+            {
+                return Err(AmlError::InvalidString);
+            }
+            name.extend(segment.iter().map(|&byte| byte as char));
+        }
+
+        Ok(name)
+    }
+
+    fn data_object(&mut self) -> Result<Object, AmlError> {
+        let object = match self.byte()? {
+            ZERO_OP => Object::Integer(0),
+            ONE_OP => Object::Integer(1),
+            ONES_OP => Object::Integer(u64::MAX),
+            BYTE_PREFIX => Object::Integer(self.integer(1)?),
+            WORD_PREFIX => Object::Integer(self.integer(2)?),
+            DWORD_PREFIX => Object::Integer(self.integer(4)?),
+            QWORD_PREFIX => Object::Integer(self.integer(8)?),
+            STRING_PREFIX => {
+                let rest = &self.bytes[self.position..];
+                let len = rest
+                    .iter()
+                    .position(|&byte| byte == 0)
+                    .ok_or(AmlError::UnexpectedEnd)?;
+                let string =
+                    core::str::from_utf8(&rest[..len]).map_err(|_| AmlError::InvalidString)?;
+                self.position += len + 1;
+                Object::String(string)
+            }
+            BUFFER_OP => {
+                let end = self.package_end()?;
+                let Object::Integer(size) = self.data_object()? else {
+                    return Err(AmlError::UnsupportedOpcode(BUFFER_OP));
+                };
+                let initializer = self.take_until(end)?;
+                Object::Buffer(&initializer[..initializer.len().min(size as usize)])
+            }
+            PACKAGE_OP => {
+                let end = self.package_end()?;
+                // The declared element count; the elements actually present are what matters here.
+                self.byte()?;
+                Object::Package(self.package_elements(end)?)
+            }
+            VAR_PACKAGE_OP => {
+                let end = self.package_end()?;
+                self.data_object()?;
+                Object::Package(self.package_elements(end)?)
+            }
+            EXT_OP_PREFIX if self.peek()? == REVISION_OP => {
+                self.position += 1;
+                Object::Integer(INTERPRETER_REVISION)
+            }
+            opcode => return Err(AmlError::UnsupportedOpcode(opcode)),
+        };
+
+        Ok(object)
+    }
+
+    fn package_elements(&mut self, end: usize) -> Result<Vec<Object>, AmlError> {
+        let mut elements = Vec::new();
+
+        while self.position < end {
+            if self.peek_name()? {
+                elements.push(Object::Reference(self.name_string()?));
+            } else {
+                elements.push(self.data_object()?);
+            }
+        }
+
+        Ok(elements)
+    }
+
+    /// Parses definitions until `end`, recording them under `scope`.
+    fn term_list(
+        &mut self,
+        namespace: &mut Namespace,
+        scope: &str,
+        end: usize,
+    ) -> Result<(), AmlError> {
+        while self.position < end {
+            match self.term(namespace, scope) {
+                Ok(()) => {}
+                Err(AmlError::UnsupportedOpcode(_)) | Err(AmlError::InvalidString) => break,
+                Err(error) => return Err(error),
+            }
+        }
+
+        self.position = end;
+        Ok(())
+    }
+
+    fn term(&mut self, namespace: &mut Namespace, scope: &str) -> Result<(), AmlError> {
+        match self.byte()? {
+            NAME_OP => {
+                let path = resolve(scope, &self.name_string()?);
+                let object = self.data_object()?;
+                namespace.insert(path, object);
+            }
+            ALIAS_OP => {
+                let source = resolve(scope, &self.name_string()?);
+                let alias = resolve(scope, &self.name_string()?);
+                namespace.insert(alias, Object::Reference(source));
+            }
+            SCOPE_OP => {
+                let end = self.package_end()?;
+                let path = resolve(scope, &self.name_string()?);
+                namespace
+                    .objects
+                    .entry(path.clone())
+                    .or_insert(Object::Scope);
+                self.term_list(namespace, &path, end)?;
+            }
+            METHOD_OP => {
+                let end = self.package_end()?;
+                let path = resolve(scope, &self.name_string()?);
+                let flags = self.byte()?;
+                let body = self.take_until(end)?;
+
+                namespace.insert(
+                    path,
+                    Object::Method {
+                        arg_count: flags & 0x07,
+                        body,
+                    },
+                );
+            }
+            EXTERNAL_OP => {
+                // Declares an object defined in another table: object type and argument count.
+                self.name_string()?;
+                self.take(2)?;
+            }
+            IF_OP | ELSE_OP | WHILE_OP => {
+                self.position = self.package_end()?;
+            }
+            EXT_OP_PREFIX => match self.byte()? {
+                DEVICE_OP | PROCESSOR_OP | POWER_RES_OP | THERMAL_ZONE_OP => {
+                    let opcode = self.bytes[self.position - 1];
+                    let end = self.package_end()?;
+                    let path = resolve(scope, &self.name_string()?);
+
+                    let object = match opcode {
+                        DEVICE_OP => Object::Device,
+                        PROCESSOR_OP => {
+                            // Processor ID, PBLK address and PBLK length.
+                            self.take(6)?;
+                            Object::Scope
+                        }
+                        POWER_RES_OP => {
+                            // System level and resource order.
+                            self.take(3)?;
+                            Object::Scope
+                        }
+                        _ => Object::Scope,
+                    };
+
+                    namespace.insert(path.clone(), object);
+                    self.term_list(namespace, &path, end)?;
+                }
+                REGION_OP => {
+                    let path = resolve(scope, &self.name_string()?);
+                    // Region space, then offset and length, which must be constants here.
+                    self.byte()?;
+                    self.data_object()?;
+                    self.data_object()?;
+                    namespace.insert(path, Object::Opaque);
+                }
+                FIELD_OP | INDEX_FIELD_OP | BANK_FIELD_OP => {
+                    self.position = self.package_end()?;
+                }
+                MUTEX_OP => {
+                    let path = resolve(scope, &self.name_string()?);
+                    self.byte()?;
+                    namespace.insert(path, Object::Opaque);
+                }
+                EVENT_OP => {
+                    let path = resolve(scope, &self.name_string()?);
+                    namespace.insert(path, Object::Opaque);
+                }
+                opcode => return Err(AmlError::UnsupportedOpcode(opcode)),
+            },
+            opcode => return Err(AmlError::UnsupportedOpcode(opcode)),
+        }
+
+        Ok(())
+    }
+}
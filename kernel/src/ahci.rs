@@ -0,0 +1,632 @@
+//! AHCI: SATA disks behind an Advanced Host Controller Interface, like the ICH9 controller of QEMU's q35
+//! machine.
+//!
+//! The HBA's (host bus adapter's) registers are in BAR 5, followed by a block of registers per port.
+//! Each port reads commands from a command list in memory: a header per command slot, pointing at a
+//! command table with the command as a host-to-device FIS (frame information structure) and the list of
+//! memory regions (PRDs) the data goes to. The disk's replies land in the port's received FIS area.
+//!
+//! Probing `DRIVER` sets up every port a SATA disk is attached to, and identifies the disk. Commands go
+//! one at a time per port, through slot 0 and `BUFFER_PAGES` pages of memory, without native command
+//! queuing. A task waiting for a command sleeps until the port interrupts. Without an interrupt line,
+//! waiting tasks poll the port each time the executor runs them. A failed command restarts the port,
+//! which is enough unless the disk itself hangs.
+
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{
+    ata::Identity,
+    dma::{DeviceBuffer, DmaBuffer, DmaError},
+    driver::{Bus, Busy, Claims, Driver, PciMatch, Priority, ProbeError},
+    init_state::{AlreadyInitialized, InitState},
+    interrupts, memory,
+    pci::{self, Bar},
+    println,
+    storage::{BlockDevice, BlockError, check_range},
+    task,
+    time::pit,
+};
+
+const CLASS_STORAGE: u8 = 0x01;
+const SUBCLASS_SATA: u8 = 0x06;
+const PROG_IF_AHCI: u8 = 0x01;
+/// The BAR with the HBA's registers, ABAR.
+const ABAR: usize = 5;
+
+// HBA registers.
+const HBA_CAP: u64 = 0x00;
+const HBA_GHC: u64 = 0x04;
+const HBA_IS: u64 = 0x08;
+const HBA_PI: u64 = 0x0c;
+/// Capability bit set when the HBA can reach memory above 4 GiB.
+const CAP_64_BIT: u32 = 1 << 31;
+const GHC_INTERRUPT_ENABLE: u32 = 1 << 1;
+const GHC_AHCI_ENABLE: u32 = 1 << 31;
+
+const PORTS_OFFSET: u64 = 0x100;
+const PORT_SIZE: u64 = 0x80;
+const MAX_PORTS: u8 = 32;
+
+// Port registers.
+const PX_CLB: u64 = 0x00;
+const PX_CLBU: u64 = 0x04;
+const PX_FB: u64 = 0x08;
+const PX_FBU: u64 = 0x0c;
+const PX_IS: u64 = 0x10;
+const PX_IE: u64 = 0x14;
+const PX_CMD: u64 = 0x18;
+const PX_TFD: u64 = 0x20;
+const PX_SIG: u64 = 0x24;
+const PX_SSTS: u64 = 0x28;
+const PX_SERR: u64 = 0x30;
+const PX_CI: u64 = 0x38;
+
+const CMD_START: u32 = 1 << 0;
+const CMD_FIS_RECEIVE: u32 = 1 << 4;
+const CMD_FIS_RECEIVE_RUNNING: u32 = 1 << 14;
+const CMD_LIST_RUNNING: u32 = 1 << 15;
+/// A register FIS arrived from the disk, which ends every command used here.
+const IS_REGISTER_FIS: u32 = 1 << 0;
+const IS_TASK_FILE_ERROR: u32 = 1 << 30;
+const TFD_ERROR: u32 = 1 << 0;
+/// A disk is attached and talking to the HBA.
+const SSTS_DEVICE_PRESENT: u32 = 3;
+const SSTS_ACTIVE: u32 = 1;
+/// The signature of an ATA disk, as opposed to e.g. an ATAPI drive.
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+const FIS_REGISTER_H2D: u8 = 0x27;
+/// The FIS carries a command, rather than a write to the device control register.
+const FIS_COMMAND: u8 = 1 << 7;
+const DEVICE_LBA: u8 = 1 << 6;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_IDENTIFY: u8 = 0xec;
+
+pub const SECTOR_SIZE: usize = 512;
+/// Pages a command's data goes through.
+const BUFFER_PAGES: usize = 8;
+const PAGE_SIZE: usize = 4096;
+/// Where the received FIS area is in the page of the command list.
+const RECEIVED_FIS_OFFSET: u64 = 1024;
+/// Where the PRD starts in the command table.
+const PRDT_OFFSET: usize = 0x80;
+/// How long the port and polled commands are waited for, in milliseconds.
+const TIMEOUT_MILLIS: u32 = 1000;
+
+static INIT: InitState = InitState::new("ahci");
+static CONTROLLER: OnceCell<Controller> = OnceCell::uninit();
+/// Whether the HBA interrupts when a command is done, rather than waiting tasks polling it.
+static INTERRUPTS: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AhciError {
+    /// The controller has no memory BAR 5.
+    NoRegisters,
+    /// The registers aren't in the physical memory mapping.
+    NotMapped(PhysAddr),
+    /// The port didn't stop or start.
+    PortHung(u8),
+    /// The disk on the port didn't answer IDENTIFY DEVICE.
+    IdentifyFailed(u8),
+    OutOfMemory,
+    /// Memory for the port is above 4 GiB, which the HBA can't reach.
+    HighMemory,
+    Busy(Busy),
+    AlreadyInitialized(AlreadyInitialized),
+}
+
+impl From<Busy> for AhciError {
+    fn from(error: Busy) -> Self {
+        AhciError::Busy(error)
+    }
+}
+
+impl From<DmaError> for AhciError {
+    fn from(error: DmaError) -> Self {
+        match error {
+            DmaError::Unreachable => AhciError::HighMemory,
+            _ => AhciError::OutOfMemory,
+        }
+    }
+}
+
+impl From<AlreadyInitialized> for AhciError {
+    fn from(error: AlreadyInitialized) -> Self {
+        AhciError::AlreadyInitialized(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Registers(VirtAddr);
+
+impl Registers {
+    fn read(self, offset: u64) -> u32 {
+        unsafe { (self.0 + offset).as_ptr::<u32>().read_volatile() }
+    }
+
+    fn write(self, offset: u64, value: u32) {
+        unsafe { (self.0 + offset).as_mut_ptr::<u32>().write_volatile(value) }
+    }
+
+    /// Waits until the bits of `mask` in register `offset` are clear, and returns whether they were in
+    /// time.
+    fn wait_clear(self, offset: u64, mask: u32) -> bool {
+        for _ in 0..TIMEOUT_MILLIS {
+            if self.read(offset) & mask == 0 {
+                return true;
+            }
+            pit::busy_wait_micros(1000);
+        }
+        self.read(offset) & mask == 0
+    }
+}
+
+struct Controller {
+    hba: Registers,
+    disks: Vec<AhciDisk>,
+}
+
+/// The memory the HBA reads commands from and writes replies to, which it owns while the port runs.
+struct PortMemory {
+    /// The command list, and the received FIS area at `RECEIVED_FIS_OFFSET`.
+    command_list: DeviceBuffer,
+    command_table: DeviceBuffer,
+}
+
+/// A SATA disk on a port of the HBA.
+pub struct AhciDisk {
+    port: u8,
+    registers: Registers,
+    /// Only `None` once the disk is dropped.
+    memory: Option<PortMemory>,
+    /// The `BUFFER_PAGES` pages a command's data goes through, held while a command is issued. Only `None`
+    /// while the disk owns it, during a command.
+    buffer: task::sync::Mutex<Option<DmaBuffer>>,
+    waker: AtomicWaker,
+    /// Set by the interrupt handler when a command failed.
+    failed: AtomicBool,
+    identity: Identity,
+}
+
+/// The register FIS for ATA `command` on `count` sectors from `lba`.
+fn command_fis(command: u8, lba: u64, count: u16) -> [u8; 20] {
+    let lba = lba.to_le_bytes();
+    let count = count.to_le_bytes();
+    let mut fis = [0; 20];
+    fis[0] = FIS_REGISTER_H2D;
+    fis[1] = FIS_COMMAND;
+    fis[2] = command;
+    fis[4..7].copy_from_slice(&lba[..3]);
+    fis[7] = DEVICE_LBA;
+    fis[8..11].copy_from_slice(&lba[3..6]);
+    fis[12..14].copy_from_slice(&count);
+    fis
+}
+
+/// Stops the port from processing commands and receiving FISes, and returns whether it did in time.
+fn stop(registers: Registers) -> bool {
+    let command = registers.read(PX_CMD);
+    registers.write(PX_CMD, command & !CMD_START);
+    if !registers.wait_clear(PX_CMD, CMD_LIST_RUNNING) {
+        return false;
+    }
+    registers.write(PX_CMD, command & !(CMD_START | CMD_FIS_RECEIVE));
+    registers.wait_clear(PX_CMD, CMD_FIS_RECEIVE_RUNNING)
+}
+
+fn start(registers: Registers) {
+    registers.write(PX_CMD, registers.read(PX_CMD) | CMD_FIS_RECEIVE);
+    registers.write(PX_CMD, registers.read(PX_CMD) | CMD_START);
+}
+
+impl AhciDisk {
+    /// Sets up `port` if a SATA disk is attached to it.
+    fn new(hba: Registers, port: u8, wide_dma: bool) -> Result<Option<Self>, AhciError> {
+        let registers = Registers(hba.0 + PORTS_OFFSET + u64::from(port) * PORT_SIZE);
+        let status = registers.read(PX_SSTS);
+        if status & 0xf != SSTS_DEVICE_PRESENT
+            || (status >> 8) & 0xf != SSTS_ACTIVE
+            || registers.read(PX_SIG) != SIGNATURE_ATA
+        {
+            return Ok(None);
+        }
+        if !stop(registers) {
+            return Err(AhciError::PortHung(port));
+        }
+
+        let allocate = |len| {
+            if wide_dma {
+                DmaBuffer::new(len)
+            } else {
+                DmaBuffer::new_32bit(len)
+            }
+        };
+        let command_list = allocate(PAGE_SIZE)?;
+        let command_table = allocate(PAGE_SIZE)?;
+        let buffer = allocate(BUFFER_PAGES * PAGE_SIZE)?;
+
+        let disk = AhciDisk {
+            port,
+            registers,
+            memory: Some(PortMemory {
+                command_list: command_list.give(),
+                command_table: command_table.give(),
+            }),
+            buffer: task::sync::Mutex::new(None),
+            waker: AtomicWaker::new(),
+            failed: AtomicBool::new(false),
+            identity: Identity::parse(&[0; 256]),
+        };
+        disk.attach(buffer).map(Some)
+    }
+
+    /// Points the port at the disk's memory, starts it and identifies the disk through `buffer`, which
+    /// then becomes the disk's.
+    fn attach(mut self, buffer: DmaBuffer) -> Result<Self, AhciError> {
+        let command_list = self.memory().command_list.bus_address().as_u64();
+        let received_fis = command_list + RECEIVED_FIS_OFFSET;
+        self.registers.write(PX_CLB, command_list as u32);
+        self.registers.write(PX_CLBU, (command_list >> 32) as u32);
+        self.registers.write(PX_FB, received_fis as u32);
+        self.registers.write(PX_FBU, (received_fis >> 32) as u32);
+        self.registers.write(PX_SERR, !0);
+        self.registers.write(PX_IS, !0);
+        start(self.registers);
+
+        let given = buffer.give();
+        self.issue(ATA_IDENTIFY, 0, 0, &given, SECTOR_SIZE, false);
+        let result = self.wait_polling();
+        // SAFETY: the command is done, or the port was restarted, which drops it.
+        let buffer = unsafe { given.reclaim() };
+        result.map_err(|_| AhciError::IdentifyFailed(self.port))?;
+        self.identity = Identity::parse(&buffer.read(0));
+        self.buffer = task::sync::Mutex::new(Some(buffer));
+
+        self.registers
+            .write(PX_IE, IS_REGISTER_FIS | IS_TASK_FILE_ERROR);
+        Ok(self)
+    }
+
+    pub fn port(&self) -> u8 {
+        self.port
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    fn memory(&self) -> &PortMemory {
+        self.memory.as_ref().expect("disk dropped")
+    }
+
+    /// Puts `command` on `count` sectors from `lba` in slot 0, with `len` bytes of data in `buffer`, and
+    /// issues it.
+    fn issue(
+        &self,
+        command: u8,
+        lba: u64,
+        count: u16,
+        buffer: &DeviceBuffer,
+        len: usize,
+        write: bool,
+    ) {
+        let PortMemory {
+            command_list,
+            command_table,
+        } = self.memory();
+        let table = command_table.bus_address().as_u64();
+
+        // The FIS is 5 dwords long, and the data is contiguous, so a single PRD covers it.
+        let flags = 5 | u32::from(write) << 6 | 1 << 16;
+        command_list.write(0, flags);
+        command_list.write(4, 0u32);
+        command_list.write(8, table as u32);
+        command_list.write(12, (table >> 32) as u32);
+
+        command_table.write(0, command_fis(command, lba, count));
+        let data = buffer.bus_address().as_u64();
+        command_table.write(PRDT_OFFSET, data as u32);
+        command_table.write(PRDT_OFFSET + 4, (data >> 32) as u32);
+        command_table.write(PRDT_OFFSET + 8, 0u32);
+        command_table.write(PRDT_OFFSET + 12, len as u32 - 1);
+
+        self.failed.store(false, Ordering::Relaxed);
+        self.registers.write(PX_IS, !0);
+        self.registers.write(PX_CI, 1);
+    }
+
+    /// The result of the command issued last, once it is done.
+    fn poll_command(&self) -> Option<Result<(), BlockError>> {
+        let failed = self.failed.swap(false, Ordering::Acquire)
+            || self.registers.read(PX_IS) & IS_TASK_FILE_ERROR != 0;
+        if !failed && self.registers.read(PX_CI) & 1 != 0 {
+            return None;
+        }
+        if failed || self.registers.read(PX_TFD) & TFD_ERROR != 0 {
+            self.recover();
+            return Some(Err(BlockError::Io));
+        }
+        Some(Ok(()))
+    }
+
+    /// Waits for the command issued last without sleeping, for when there is no executor yet.
+    fn wait_polling(&self) -> Result<(), BlockError> {
+        for _ in 0..TIMEOUT_MILLIS {
+            if let Some(result) = self.poll_command() {
+                return result;
+            }
+            pit::busy_wait_micros(1000);
+        }
+        self.recover();
+        Err(BlockError::Io)
+    }
+
+    /// Restarts the port after a failed command, which clears the error and the command.
+    fn recover(&self) {
+        stop(self.registers);
+        self.registers.write(PX_SERR, !0);
+        self.registers.write(PX_IS, !0);
+        start(self.registers);
+    }
+
+    /// Runs `command` on the first `len` bytes of `buffer`, which the disk owns meanwhile. The buffer is
+    /// back once this completes, even if the command failed.
+    async fn transfer(
+        &self,
+        buffer: &mut Option<DmaBuffer>,
+        command: u8,
+        lba: u64,
+        len: usize,
+    ) -> Result<(), BlockError> {
+        let count = (len / SECTOR_SIZE) as u16;
+        let given = buffer.take().expect("a command is already running").give();
+        self.issue(
+            command,
+            lba,
+            count,
+            &given,
+            len,
+            command == ATA_WRITE_DMA_EXT,
+        );
+        Completion {
+            disk: self,
+            buffer,
+            given: Some(given),
+        }
+        .await
+    }
+
+    fn on_interrupt(&self) {
+        let status = self.registers.read(PX_IS);
+        self.registers.write(PX_IS, status);
+        if status & IS_TASK_FILE_ERROR != 0 {
+            self.failed.store(true, Ordering::Release);
+        }
+        self.waker.wake();
+    }
+}
+
+impl Drop for AhciDisk {
+    fn drop(&mut self) {
+        self.registers.write(PX_IE, 0);
+        // If the port didn't stop, the HBA may still use its memory, which is then leaked.
+        if stop(self.registers)
+            && let Some(memory) = self.memory.take()
+        {
+            // SAFETY: the port stopped, so the HBA no longer reads or writes its memory.
+            unsafe {
+                drop(memory.command_list.reclaim());
+                drop(memory.command_table.reclaim());
+            }
+        }
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.identity.sectors
+    }
+
+    async fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_range(start, buffer.len(), SECTOR_SIZE, self.identity.sectors)?;
+        let mut data = self.buffer.lock().await;
+
+        let mut lba = start;
+        for chunk in buffer.chunks_mut(BUFFER_PAGES * PAGE_SIZE) {
+            self.transfer(&mut data, ATA_READ_DMA_EXT, lba, chunk.len())
+                .await?;
+            chunk.copy_from_slice(&idle(&mut data).as_slice()[..chunk.len()]);
+            lba += (chunk.len() / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    async fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_range(start, buffer.len(), SECTOR_SIZE, self.identity.sectors)?;
+        let mut data = self.buffer.lock().await;
+
+        let mut lba = start;
+        for chunk in buffer.chunks(BUFFER_PAGES * PAGE_SIZE) {
+            idle(&mut data).as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            self.transfer(&mut data, ATA_WRITE_DMA_EXT, lba, chunk.len())
+                .await?;
+            lba += (chunk.len() / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+}
+
+/// The data buffer of a disk between commands.
+fn idle(buffer: &mut Option<DmaBuffer>) -> &mut DmaBuffer {
+    buffer.as_mut().expect("a command is still running")
+}
+
+/// Waits until the disk is done with the command issued last, then gives its buffer back.
+struct Completion<'a> {
+    disk: &'a AhciDisk,
+    /// Where the buffer goes back to.
+    buffer: &'a mut Option<DmaBuffer>,
+    /// Only `None` once the command is done.
+    given: Option<DeviceBuffer>,
+}
+
+impl Completion<'_> {
+    fn finish(&mut self) {
+        if let Some(given) = self.given.take() {
+            // SAFETY: the command is done, or failed and the port was restarted, which drops it.
+            *self.buffer = Some(unsafe { given.reclaim() });
+        }
+    }
+}
+
+impl Future for Completion<'_> {
+    type Output = Result<(), BlockError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let result = self.disk.poll_command().or_else(|| {
+            self.disk.waker.register(cx.waker());
+            // The interrupt may have come in between.
+            self.disk.poll_command()
+        });
+
+        match result {
+            Some(result) => {
+                self.finish();
+                Poll::Ready(result)
+            }
+            None => {
+                if !INTERRUPTS.load(Ordering::Relaxed) {
+                    cx.waker().wake_by_ref();
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Completion<'_> {
+    /// Waits for a command whose task was dropped, so that the next command doesn't overwrite it while the
+    /// disk still works on it.
+    fn drop(&mut self) {
+        while self.given.is_some() && self.disk.poll_command().is_none() {
+            core::hint::spin_loop();
+        }
+        self.finish();
+    }
+}
+
+fn interrupt_handler() {
+    let Some(controller) = CONTROLLER.get() else {
+        return;
+    };
+    let pending = controller.hba.read(HBA_IS);
+    for disk in &controller.disks {
+        if pending & (1 << disk.port) != 0 {
+            disk.on_interrupt();
+        }
+    }
+    controller.hba.write(HBA_IS, pending);
+}
+
+pub static DRIVER: Driver = Driver {
+    name: "ahci",
+    priority: Priority::Normal,
+    bus: Bus::Pci {
+        matches: &[PciMatch::Class {
+            class: CLASS_STORAGE,
+            subclass: SUBCLASS_SATA,
+            prog_if: Some(PROG_IF_AHCI),
+        }],
+        probe,
+    },
+};
+
+fn probe(device: &pci::Device, claims: &mut Claims) -> Result<(), ProbeError> {
+    init(device, claims).map_err(ProbeError::new)?;
+    for disk in disks() {
+        println!(
+            "ahci: port {}: {}, {} sectors",
+            disk.port(),
+            disk.identity().model(),
+            disk.num_blocks()
+        );
+    }
+    Ok(())
+}
+
+/// Sets up `device`, the first controller only, and the disks attached to it. A port that fails is
+/// left out with a warning.
+fn init(device: &pci::Device, claims: &mut Claims) -> Result<(), AhciError> {
+    INIT.begin()?;
+
+    let Some(bar @ Bar::Memory { address, .. }) = device.bars[ABAR] else {
+        return Err(AhciError::NoRegisters);
+    };
+    claims.claim(bar.into())?;
+    let registers = memory::phys_to_virt(PhysAddr::new(address));
+    if !memory::is_mapped(registers) {
+        return Err(AhciError::NotMapped(PhysAddr::new(address)));
+    }
+    pci::enable(device.address);
+
+    let hba = Registers(registers);
+    hba.write(HBA_GHC, hba.read(HBA_GHC) | GHC_AHCI_ENABLE);
+    let wide_dma = hba.read(HBA_CAP) & CAP_64_BIT != 0;
+    let implemented = hba.read(HBA_PI);
+
+    let mut disks = Vec::new();
+    for port in (0..MAX_PORTS).filter(|port| implemented & (1 << port) != 0) {
+        match AhciDisk::new(hba, port, wide_dma) {
+            Ok(Some(disk)) => disks.push(disk),
+            Ok(None) => {}
+            Err(error) => println!("WARNING: ahci: port {} not set up: {:?}", port, error),
+        }
+    }
+
+    // The line stays masked until the disks are in place for the handler. A line already taken by another
+    // device is left alone, and commands are polled instead.
+    let irq = device.interrupt_line;
+    let interrupts =
+        claims.claim_irq(irq) && interrupts::set_irq_handler(irq, interrupt_handler).is_ok();
+    INTERRUPTS.store(interrupts, Ordering::Relaxed);
+    CONTROLLER.get_or_init(|| Controller { hba, disks });
+    if interrupts {
+        hba.write(HBA_IS, !0);
+        hba.write(HBA_GHC, hba.read(HBA_GHC) | GHC_INTERRUPT_ENABLE);
+        interrupts::unmask_irq(irq);
+    }
+
+    Ok(())
+}
+
+/// The disks `DRIVER` set up.
+pub fn disks() -> &'static [AhciDisk] {
+    CONTROLLER.get().map_or(&[], |controller| &controller.disks)
+}
+
+#[test_case]
+fn test_command_fis() {
+    let fis = command_fis(ATA_READ_DMA_EXT, 0x0605_0403_0201, 0x0807);
+
+    assert_eq!(
+        fis[..14],
+        [
+            0x27, 0x80, 0x25, 0, 0x01, 0x02, 0x03, 0x40, 0x04, 0x05, 0x06, 0, 0x07, 0x08
+        ]
+    );
+    assert!(fis[14..].iter().all(|&byte| byte == 0));
+}
@@ -12,6 +12,7 @@ use crate::allocator::bump::BumpAllocator;
 use crate::allocator::fixed_size_block::FixedSizeBlockAllocator;
 #[allow(unused_imports)]
 use crate::allocator::linked_list::LinkedListAllocator;
+use crate::init_state::InitState;
 
 pub mod bump;
 pub mod fixed_size_block;
@@ -20,10 +21,17 @@ pub mod linked_list;
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 
+static HEAP_INIT: InitState = InitState::new("heap");
+
+/// Maps the heap and hands it to the global allocator. Repeated calls leave the existing heap alone.
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
+    if HEAP_INIT.is_initialized() {
+        return Ok(());
+    }
+
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
         let heap_end = heap_start + HEAP_SIZE - 1u64;
@@ -45,13 +53,30 @@ pub fn init_heap(
         };
     }
 
-    unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    // Only marked once the mapping succeeded, so a failed attempt can be retried.
+    if HEAP_INIT.begin().is_ok() {
+        unsafe {
+            ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        }
     }
 
     Ok(())
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapUsage {
+    pub size: usize,
+    /// Bytes currently handed out, as requested by the callers.
+    pub allocated: usize,
+}
+
+pub fn heap_usage() -> HeapUsage {
+    HeapUsage {
+        size: HEAP_SIZE,
+        allocated: ALLOCATOR.lock().allocated(),
+    }
+}
+
 #[global_allocator]
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
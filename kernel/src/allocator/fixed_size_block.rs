@@ -22,6 +22,8 @@ struct ListNode {
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    /// Bytes requested by live allocations, not counting the rounding up to block sizes.
+    allocated: usize,
 }
 
 impl FixedSizeBlockAllocator {
@@ -32,6 +34,7 @@ impl FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             // FIXME: Use the allocator that we created.
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            allocated: 0,
         }
     }
 
@@ -41,6 +44,10 @@ impl FixedSizeBlockAllocator {
         }
     }
 
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
@@ -59,7 +66,7 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
 
-        match list_index(&layout) {
+        let ptr = match list_index(&layout) {
             Some(index) => match allocator.list_heads[index].take() {
                 Some(node) => {
                     allocator.list_heads[index] = node.next.take();
@@ -77,11 +84,18 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
             },
 
             None => allocator.fallback_alloc(layout),
+        };
+
+        if !ptr.is_null() {
+            allocator.allocated += layout.size();
         }
+
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.allocated -= layout.size();
 
         match list_index(&layout) {
             Some(index) => {
@@ -0,0 +1,137 @@
+//! Local APIC driver.
+//!
+//! Each CPU core has its own local APIC, which receives interrupts and also contains a timer. Unlike the PIT,
+//! the frequency of the APIC timer is not known in advance, so it has to be calibrated against the PIT.
+
+use conquer_once::spin::OnceCell;
+use core::ptr;
+use x86_64::{PhysAddr, VirtAddr, registers::model_specific::Msr};
+
+use crate::{cpu, interrupts::InterruptIndex, memory, time::pit};
+
+/// Holds the physical base address of the local APIC registers and the global enable flag.
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const REGISTER_ID: usize = 0x20;
+const REGISTER_EOI: usize = 0xB0;
+const REGISTER_SPURIOUS: usize = 0xF0;
+const REGISTER_LVT_TIMER: usize = 0x320;
+const REGISTER_TIMER_INITIAL_COUNT: usize = 0x380;
+const REGISTER_TIMER_CURRENT_COUNT: usize = 0x390;
+const REGISTER_TIMER_DIVIDE: usize = 0x3E0;
+
+/// Bit 8 of the spurious interrupt vector register enables the APIC in software.
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+/// Vector of the interrupts the APIC raises when an interrupt disappears before it could be delivered.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Divide the bus clock by 16 (the encoding is not a plain number).
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// How long the calibration waits on the PIT.
+const CALIBRATION_MICROS: u32 = 10_000;
+
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+
+struct LocalApic {
+    base: VirtAddr,
+    /// Timer ticks per second measured at calibration, with `TIMER_DIVIDE_BY_16`.
+    timer_frequency: u64,
+}
+
+impl LocalApic {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.base + register as u64).as_ptr::<u32>()) }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.base + register as u64).as_mut_ptr::<u32>(), value) }
+    }
+
+    /// Counts how many timer ticks elapse while the PIT waits for `CALIBRATION_MICROS`.
+    fn calibrate_timer(&self) -> u64 {
+        self.write(REGISTER_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(REGISTER_LVT_TIMER, LVT_MASKED);
+        self.write(REGISTER_TIMER_INITIAL_COUNT, u32::MAX);
+
+        pit::busy_wait_micros(CALIBRATION_MICROS);
+
+        let elapsed = u32::MAX - self.read(REGISTER_TIMER_CURRENT_COUNT);
+        self.write(REGISTER_TIMER_INITIAL_COUNT, 0);
+
+        elapsed as u64 * 1_000_000 / CALIBRATION_MICROS as u64
+    }
+}
+
+fn is_supported() -> bool {
+    // CPUID leaf 1, EDX bit 9 reports an on-chip APIC.
+    cpu::cpuid(1, 0).edx & (1 << 9) != 0
+}
+
+/// Enables the local APIC of the current CPU and calibrates its timer.
+///
+/// Returns `false` when the CPU has no APIC, in which case the PIT keeps being the tick source.
+/// Requires `memory::init` because the registers are accessed through the physical memory mapping.
+pub fn init() -> bool {
+    if !is_supported() {
+        return false;
+    }
+
+    let apic = LOCAL_APIC.get_or_init(|| {
+        let mut base_msr = Msr::new(IA32_APIC_BASE_MSR);
+        let base_value = unsafe { base_msr.read() } | APIC_BASE_ENABLE;
+        unsafe { base_msr.write(base_value) };
+
+        let mut apic = LocalApic {
+            base: memory::phys_to_virt(PhysAddr::new(base_value & 0xF_FFFF_F000)),
+            timer_frequency: 0,
+        };
+
+        apic.write(
+            REGISTER_SPURIOUS,
+            SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR),
+        );
+        apic.timer_frequency = apic.calibrate_timer();
+        apic
+    });
+
+    apic.timer_frequency > 0
+}
+
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.is_initialized()
+}
+
+pub fn id() -> u32 {
+    local_apic().read(REGISTER_ID) >> 24
+}
+
+/// Timer ticks per second, as measured at calibration.
+pub fn timer_frequency() -> u64 {
+    local_apic().timer_frequency
+}
+
+/// Starts the timer in periodic mode, raising `InterruptIndex::Timer` `hz` times per second.
+pub fn start_periodic_timer(hz: u32) {
+    let apic = local_apic();
+    let initial_count = (apic.timer_frequency / hz as u64).clamp(1, u32::MAX as u64);
+
+    apic.write(REGISTER_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    apic.write(
+        REGISTER_LVT_TIMER,
+        InterruptIndex::Timer.as_u8() as u32 | LVT_TIMER_PERIODIC,
+    );
+    apic.write(REGISTER_TIMER_INITIAL_COUNT, initial_count as u32);
+}
+
+/// Signals the end of an interrupt delivered by the local APIC.
+pub fn end_of_interrupt() {
+    local_apic().write(REGISTER_EOI, 0);
+}
+
+fn local_apic() -> &'static LocalApic {
+    LOCAL_APIC.try_get().expect("local APIC not initialized")
+}
@@ -0,0 +1,436 @@
+//! ATA disks on the legacy IDE channels, read and written by polled PIO.
+//!
+//! Each of the two channels has a block of I/O ports for its task file and one for its control
+//! register, and up to two drives, master and slave, of which one is selected at a time. Data moves
+//! through the data port a word at a time while the CPU polls the status register, so nothing needs
+//! interrupts, DMA memory or the heap: drives can be read early in boot, before anything else is up.
+//!
+//! This is the fallback for machines without AHCI or virtio, and a simple reference `BlockDevice`.
+
+use core::future::Future;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::{
+    storage::{BlockDevice, BlockError, check_range},
+    time::pit,
+};
+
+pub const SECTOR_SIZE: usize = 512;
+
+// Task file registers, as offsets from the channel's I/O base.
+const DATA: u16 = 0;
+const ERROR: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE: u16 = 6;
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_DRIVE_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+/// What a channel without drives reads as.
+const FLOATING_BUS: u8 = 0xff;
+
+/// Device control bit that keeps the drives from interrupting.
+const CONTROL_NO_INTERRUPTS: u8 = 1 << 1;
+const DRIVE_SLAVE: u8 = 1 << 4;
+const DRIVE_LBA: u8 = 1 << 6;
+/// Bits that must be set when selecting a drive with LBA28.
+const DRIVE_LBA28: u8 = 0xa0 | DRIVE_LBA;
+
+const ATA_READ_SECTORS: u8 = 0x20;
+const ATA_READ_SECTORS_EXT: u8 = 0x24;
+const ATA_WRITE_SECTORS: u8 = 0x30;
+const ATA_WRITE_SECTORS_EXT: u8 = 0x34;
+const ATA_FLUSH_CACHE: u8 = 0xe7;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+const ATA_IDENTIFY: u8 = 0xec;
+
+/// Sectors moved by a command, the most a sector count of 8 bits allows.
+const SECTORS_PER_COMMAND: usize = 256;
+/// The highest sector LBA28 reaches.
+const LBA28_LIMIT: u64 = 1 << 28;
+/// How long a drive is waited for, in milliseconds.
+const TIMEOUT_MILLIS: u32 = 1000;
+
+/// Serializes access to each channel, whose registers its two drives share.
+static CHANNEL_LOCKS: [Mutex<()>; 2] = [const { Mutex::new(()) }; 2];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Primary,
+    Secondary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    Master,
+    Slave,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaError {
+    NoDrive,
+    /// The drive is not an ATA disk, e.g. an ATAPI CD drive.
+    NotAta,
+    Timeout,
+    /// The drive failed the command, with this error register.
+    Device(u8),
+}
+
+impl Channel {
+    fn io_base(self) -> u16 {
+        match self {
+            Channel::Primary => 0x1f0,
+            Channel::Secondary => 0x170,
+        }
+    }
+
+    fn control(self) -> u16 {
+        match self {
+            Channel::Primary => 0x3f6,
+            Channel::Secondary => 0x376,
+        }
+    }
+
+    fn lock(self) -> &'static Mutex<()> {
+        &CHANNEL_LOCKS[self as usize]
+    }
+
+    fn read(self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io_base() + register).read() }
+    }
+
+    fn write(self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io_base() + register).write(value) }
+    }
+
+    /// Reads the status without acknowledging anything, four times: the selected drive takes 400ns to
+    /// show its status after a command or a selection.
+    fn settle(self) -> u8 {
+        let mut alternate_status = Port::<u8>::new(self.control());
+        let mut status = 0;
+        for _ in 0..4 {
+            status = unsafe { alternate_status.read() };
+        }
+        status
+    }
+
+    /// Waits until the drive is no longer busy, and returns its status.
+    fn wait_ready(self) -> Result<u8, AtaError> {
+        for _ in 0..TIMEOUT_MILLIS * 10 {
+            let status = self.read(STATUS);
+            if status & STATUS_BUSY == 0 {
+                return Ok(status);
+            }
+            pit::busy_wait_micros(100);
+        }
+        Err(AtaError::Timeout)
+    }
+
+    /// Waits until the drive has data or wants some.
+    fn wait_data(self) -> Result<(), AtaError> {
+        let status = self.wait_ready()?;
+        if status & (STATUS_ERROR | STATUS_DRIVE_FAULT) != 0 {
+            return Err(AtaError::Device(self.read(ERROR)));
+        }
+        if status & STATUS_DATA_REQUEST == 0 {
+            return Err(AtaError::Device(0));
+        }
+        Ok(())
+    }
+}
+
+/// What IDENTIFY DEVICE tells about an ATA disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Identity {
+    pub sectors: u64,
+    /// Whether the disk takes 48-bit sector numbers.
+    pub lba48: bool,
+    /// The model number, space padded ASCII.
+    model: [u8; 40],
+}
+
+impl Identity {
+    /// Reads the words IDENTIFY DEVICE returned.
+    pub fn parse(words: &[u16; 256]) -> Self {
+        let lba48 = words[83] & (1 << 10) != 0;
+        let sectors = if lba48 {
+            (0..4).fold(0, |sectors, i| {
+                sectors | u64::from(words[100 + i]) << (16 * i)
+            })
+        } else {
+            u64::from(words[60]) | u64::from(words[61]) << 16
+        };
+
+        // Each word holds two characters, the first in its high byte.
+        let mut model = [b' '; 40];
+        for (i, word) in words[27..47].iter().enumerate() {
+            model[2 * i..2 * i + 2].copy_from_slice(&word.to_be_bytes());
+        }
+
+        Identity {
+            sectors,
+            lba48,
+            model,
+        }
+    }
+
+    pub fn model(&self) -> &str {
+        core::str::from_utf8(&self.model).unwrap_or_default().trim()
+    }
+}
+
+/// An ATA disk on a legacy channel.
+#[derive(Debug, Clone, Copy)]
+pub struct AtaDrive {
+    channel: Channel,
+    position: Position,
+    identity: Identity,
+}
+
+impl AtaDrive {
+    /// Identifies the drive at `position` on `channel`.
+    pub fn identify(channel: Channel, position: Position) -> Result<Self, AtaError> {
+        let _channel = channel.lock().lock();
+        if channel.read(STATUS) == FLOATING_BUS {
+            return Err(AtaError::NoDrive);
+        }
+
+        unsafe { Port::<u8>::new(channel.control()).write(CONTROL_NO_INTERRUPTS) };
+        let drive = AtaDrive {
+            channel,
+            position,
+            identity: Identity::parse(&[0; 256]),
+        };
+        drive.select(0xa0);
+        for register in [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH] {
+            channel.write(register, 0);
+        }
+        channel.write(COMMAND, ATA_IDENTIFY);
+        if channel.settle() == 0 {
+            return Err(AtaError::NoDrive);
+        }
+        channel.wait_ready()?;
+        // ATAPI and SATA drives put their signature here instead.
+        if channel.read(LBA_MID) != 0 || channel.read(LBA_HIGH) != 0 {
+            return Err(AtaError::NotAta);
+        }
+        channel.wait_data()?;
+
+        let mut words = [0; 256];
+        let mut data = Port::<u16>::new(channel.io_base() + DATA);
+        for word in &mut words {
+            *word = unsafe { data.read() };
+        }
+
+        Ok(AtaDrive {
+            identity: Identity::parse(&words),
+            ..drive
+        })
+    }
+
+    /// The ATA disks on both channels.
+    pub fn probe() -> impl Iterator<Item = AtaDrive> {
+        [Channel::Primary, Channel::Secondary]
+            .into_iter()
+            .flat_map(|channel| {
+                [Position::Master, Position::Slave].map(|position| (channel, position))
+            })
+            .filter_map(|(channel, position)| Self::identify(channel, position).ok())
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// Selects the drive, with `bits` in the rest of the drive register.
+    fn select(&self, bits: u8) {
+        let slave = match self.position {
+            Position::Master => 0,
+            Position::Slave => DRIVE_SLAVE,
+        };
+        self.channel.write(DRIVE, bits | slave);
+        self.channel.settle();
+    }
+
+    /// Selects the drive and issues `command` on `count` sectors, at most `SECTORS_PER_COMMAND`, from
+    /// `lba`.
+    fn command(&self, command: u8, lba: u64, count: u16) -> Result<(), AtaError> {
+        let channel = self.channel;
+        channel.wait_ready()?;
+
+        let registers = [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH];
+        let lba = lba.to_le_bytes();
+        let [count_low, count_high] = count.to_le_bytes();
+        if self.identity.lba48 {
+            self.select(DRIVE_LBA);
+            // The high bytes go first, then the low ones over them.
+            for (register, byte) in registers
+                .into_iter()
+                .zip([count_high, lba[3], lba[4], lba[5]])
+            {
+                channel.write(register, byte);
+            }
+        } else {
+            // A count of 0 is 256 sectors.
+            self.select(DRIVE_LBA28 | (lba[3] & 0x0f));
+        }
+        for (register, byte) in registers
+            .into_iter()
+            .zip([count_low, lba[0], lba[1], lba[2]])
+        {
+            channel.write(register, byte);
+        }
+        channel.write(COMMAND, command);
+        channel.settle();
+        Ok(())
+    }
+
+    /// The sectors of `len` bytes from `start` on, as the first sector and count of runs one command can
+    /// move.
+    fn runs(&self, start: u64, len: usize) -> Result<impl Iterator<Item = (u64, u16)>, BlockError> {
+        let count = check_range(start, len, SECTOR_SIZE, self.identity.sectors)?;
+        if !self.identity.lba48 && start + count > LBA28_LIMIT {
+            return Err(BlockError::OutOfRange);
+        }
+        let end = start + count;
+        Ok((start..end)
+            .step_by(SECTORS_PER_COMMAND)
+            .map(move |first| (first, (end - first).min(SECTORS_PER_COMMAND as u64) as u16)))
+    }
+
+    /// Reads sectors into `buffer` from sector `start` on, without waiting for anything but the drive.
+    pub fn read_sectors(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let runs = self.runs(start, buffer.len())?;
+        let command = if self.identity.lba48 {
+            ATA_READ_SECTORS_EXT
+        } else {
+            ATA_READ_SECTORS
+        };
+        let _channel = self.channel.lock().lock();
+        let mut data = Port::<u16>::new(self.channel.io_base() + DATA);
+
+        let mut sectors = buffer.chunks_exact_mut(SECTOR_SIZE);
+        for (first, count) in runs {
+            self.command(command, first, count)
+                .map_err(|_| BlockError::Io)?;
+            for sector in sectors.by_ref().take(count.into()) {
+                self.channel.wait_data().map_err(|_| BlockError::Io)?;
+                for word in sector.chunks_exact_mut(2) {
+                    word.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `buffer` to sectors from sector `start` on, and flushes the drive's cache.
+    pub fn write_sectors(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        let runs = self.runs(start, buffer.len())?;
+        let (command, flush) = if self.identity.lba48 {
+            (ATA_WRITE_SECTORS_EXT, ATA_FLUSH_CACHE_EXT)
+        } else {
+            (ATA_WRITE_SECTORS, ATA_FLUSH_CACHE)
+        };
+        let _channel = self.channel.lock().lock();
+        let mut data = Port::<u16>::new(self.channel.io_base() + DATA);
+
+        let mut sectors = buffer.chunks_exact(SECTOR_SIZE);
+        for (first, count) in runs {
+            self.command(command, first, count)
+                .map_err(|_| BlockError::Io)?;
+            for sector in sectors.by_ref().take(count.into()) {
+                self.channel.wait_data().map_err(|_| BlockError::Io)?;
+                for word in sector.chunks_exact(2) {
+                    unsafe { data.write(u16::from_le_bytes([word[0], word[1]])) };
+                }
+            }
+        }
+
+        self.command(flush, 0, 0).map_err(|_| BlockError::Io)?;
+        match self.channel.wait_ready() {
+            Ok(status) if status & (STATUS_ERROR | STATUS_DRIVE_FAULT) == 0 => Ok(()),
+            _ => Err(BlockError::Io),
+        }
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.identity.sectors
+    }
+
+    fn read_blocks(
+        &self,
+        start: u64,
+        buffer: &mut [u8],
+    ) -> impl Future<Output = Result<(), BlockError>> {
+        core::future::ready(self.read_sectors(start, buffer))
+    }
+
+    fn write_blocks(
+        &self,
+        start: u64,
+        buffer: &[u8],
+    ) -> impl Future<Output = Result<(), BlockError>> {
+        core::future::ready(self.write_sectors(start, buffer))
+    }
+}
+
+/// IDENTIFY DEVICE words of an LBA48 disk of 0x1_0000_0002 sectors, named "QEMU HARDDISK".
+#[cfg(test)]
+const IDENTIFY: [u16; 256] = {
+    let mut words = [0x2020; 256];
+    let model = b"QEMU HARDDISK   ";
+    let mut i = 0;
+    while i < model.len() {
+        words[27 + i / 2] = (model[i] as u16) << 8 | model[i + 1] as u16;
+        i += 2;
+    }
+    words[83] = 1 << 10;
+    words[100] = 2;
+    words[101] = 0;
+    words[102] = 1;
+    words[103] = 0;
+    words
+};
+
+#[test_case]
+fn test_identity_parse() {
+    let identity = Identity::parse(&IDENTIFY);
+
+    assert!(identity.lba48);
+    assert_eq!(identity.sectors, 0x1_0000_0002);
+    assert_eq!(identity.model(), "QEMU HARDDISK");
+}
+
+#[test_case]
+fn test_identity_parse_lba28() {
+    let mut words = IDENTIFY;
+    words[83] = 0;
+    words[60] = 0x5678;
+    words[61] = 0x1234;
+
+    let identity = Identity::parse(&words);
+
+    assert!(!identity.lba48);
+    assert_eq!(identity.sectors, 0x1234_5678);
+}
@@ -0,0 +1,53 @@
+//! Walking the call stack through frame pointers.
+//!
+//! The kernel is built with frame pointers (see `.cargo/config.toml`), so every function pushes the
+//! caller's `rbp` next to its return address and points `rbp` at them. Code from the precompiled `core`
+//! doesn't, so a walk may stop early or, if such code reused `rbp`, wander off; every frame is checked to
+//! be mapped and above the previous one before it is read.
+
+use core::arch::asm;
+use x86_64::VirtAddr;
+
+use crate::memory;
+
+/// Most frames `walk` visits.
+pub const MAX_FRAMES: usize = 32;
+
+/// The frame pointer of the caller's frame.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    rbp
+}
+
+/// Calls `f` with the return address of each frame, starting at the frame `rbp` points to, until the
+/// chain ends, looks broken, or `MAX_FRAMES` frames were visited.
+pub fn walk(mut rbp: u64, mut f: impl FnMut(u64)) {
+    for _ in 0..MAX_FRAMES {
+        if rbp == 0 || !rbp.is_multiple_of(8) || !is_readable(rbp) || !is_readable(rbp + 8) {
+            return;
+        }
+
+        let (caller_rbp, return_address) = unsafe {
+            let frame = rbp as *const u64;
+            (frame.read_volatile(), frame.add(1).read_volatile())
+        };
+        if return_address == 0 {
+            return;
+        }
+        f(return_address);
+
+        // Stacks grow down, so callers' frames are always higher up.
+        if caller_rbp <= rbp {
+            return;
+        }
+        rbp = caller_rbp;
+    }
+}
+
+fn is_readable(address: u64) -> bool {
+    VirtAddr::try_new(address).is_ok_and(memory::is_mapped)
+}
@@ -0,0 +1,160 @@
+//! Virtual consoles: the screens of the framebuffer, each for one kind of output.
+//!
+//! Every console keeps its own text and cursor, and only the active one is drawn. The keyboard task
+//! switches between them on Alt+F1 to Alt+F4. `print!` writes to the `Log` console, the others are
+//! written with `console_print!`.
+//!
+//! Output to a console can also be mirrored to the serial port, so headless runs and CI logs see it
+//! without a screenshot. Only `Log` is mirrored by default. Mirroring pauses while a TUI tool has the
+//! serial terminal, and output goes only to the serial port until the framebuffer is set up.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+use pc_keyboard::KeyCode;
+
+use crate::{
+    framebuffer::{self, Color},
+    serial,
+};
+
+/// One bit per console, set if it is mirrored.
+static MIRRORED: AtomicU8 = AtomicU8::new(1 << Console::Log as u8);
+static MIRROR_PAUSED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    /// The kernel log and everything else printed with `print!`. Active at boot.
+    Log,
+    /// Interactive input, such as the echoed key presses.
+    Shell,
+    /// Diagnostics too noisy for the log.
+    Debug,
+    /// Free for anything else.
+    Scratch,
+}
+
+impl Console {
+    pub const ALL: [Console; framebuffer::SCREENS] = [
+        Console::Log,
+        Console::Shell,
+        Console::Debug,
+        Console::Scratch,
+    ];
+
+    /// The console switched to with Alt and `key`.
+    pub fn from_function_key(key: KeyCode) -> Option<Console> {
+        match key {
+            KeyCode::F1 => Some(Console::Log),
+            KeyCode::F2 => Some(Console::Shell),
+            KeyCode::F3 => Some(Console::Debug),
+            KeyCode::F4 => Some(Console::Scratch),
+            _ => None,
+        }
+    }
+
+    fn screen(self) -> usize {
+        self as usize
+    }
+
+    fn mirror_bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Sets whether output to `console` is also written to the serial port.
+pub fn set_mirrored(console: Console, mirrored: bool) {
+    if mirrored {
+        MIRRORED.fetch_or(console.mirror_bit(), Ordering::Relaxed);
+    } else {
+        MIRRORED.fetch_and(!console.mirror_bit(), Ordering::Relaxed);
+    }
+}
+
+pub fn is_mirrored(console: Console) -> bool {
+    MIRRORED.load(Ordering::Relaxed) & console.mirror_bit() != 0
+}
+
+/// Stops mirroring to the serial port while `paused`, for as long as something else draws there.
+pub fn pause_mirroring(paused: bool) {
+    MIRROR_PAUSED.store(paused, Ordering::Relaxed);
+}
+
+fn mirrors(console: Console) -> bool {
+    is_mirrored(console) && !MIRROR_PAUSED.load(Ordering::Relaxed)
+}
+
+/// Makes `console` the one drawn, redrawing the screen from its text.
+pub fn switch(console: Console) {
+    framebuffer::show_screen(console.screen());
+}
+
+/// The console being drawn.
+pub fn active() -> Console {
+    Console::ALL[framebuffer::shown_screen()]
+}
+
+/// Prints to `console` on the screen only, never mirrored. For output that goes to the serial port
+/// separately, like the kernel log.
+pub fn print_to_screen(console: Console, color: Option<Color>, args: fmt::Arguments) {
+    if !framebuffer::is_initialized() {
+        return;
+    }
+
+    match color {
+        Some(color) => framebuffer::print_colored_to(console.screen(), color, args),
+        None => framebuffer::print_to(console.screen(), args),
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(console: Console, args: fmt::Arguments) {
+    print_to_screen(console, None, args);
+    if mirrors(console) || !framebuffer::is_initialized() {
+        serial::_print(args);
+    }
+}
+
+#[doc(hidden)]
+pub fn _print_colored(console: Console, color: Color, args: fmt::Arguments) {
+    print_to_screen(console, Some(color), args);
+    if mirrors(console) || !framebuffer::is_initialized() {
+        serial::_print(args);
+    }
+}
+
+/// Prints to a console, e.g. `console_print!(Console::Debug, "{:?}", state)`.
+#[macro_export]
+macro_rules! console_print {
+    ($console:expr, $($arg:tt)*) => ($crate::console::_print($console, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! console_println {
+    ($console:expr) => ($crate::console_print!($console, "\n"));
+    ($console:expr, $($arg:tt)*) => ($crate::console_print!($console, "{}\n", format_args!($($arg)*)));
+}
+
+#[test_case]
+fn test_function_keys_map_to_every_console_in_order() {
+    let keys = [KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4];
+
+    for (key, console) in keys.into_iter().zip(Console::ALL) {
+        assert_eq!(Console::from_function_key(key), Some(console));
+        assert_eq!(Console::ALL[console.screen()], console);
+    }
+    assert_eq!(Console::from_function_key(KeyCode::F5), None);
+}
+
+#[test_case]
+fn test_only_the_log_is_mirrored_by_default() {
+    assert!(is_mirrored(Console::Log));
+    assert!(!is_mirrored(Console::Shell));
+
+    set_mirrored(Console::Debug, true);
+    assert!(is_mirrored(Console::Debug));
+    set_mirrored(Console::Debug, false);
+    assert!(!is_mirrored(Console::Debug));
+    assert!(is_mirrored(Console::Log));
+}
@@ -0,0 +1,25 @@
+//! Thin wrappers around CPU instructions that `x86_64` doesn't cover.
+
+use core::arch::x86_64::CpuidResult;
+
+/// Executes CPUID for the given leaf and subleaf.
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    // `__cpuid_count` is only safe on newer toolchains.
+    #[allow(unused_unsafe)]
+    unsafe {
+        core::arch::x86_64::__cpuid_count(leaf, subleaf)
+    }
+}
+
+/// Highest extended CPUID leaf (0x8000_0000 and above) supported by the CPU.
+pub fn max_extended_leaf() -> u32 {
+    cpuid(0x8000_0000, 0).eax
+}
+
+/// Reads the time stamp counter.
+pub fn rdtsc() -> u64 {
+    #[allow(unused_unsafe)]
+    unsafe {
+        core::arch::x86_64::_rdtsc()
+    }
+}
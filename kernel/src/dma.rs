@@ -0,0 +1,250 @@
+//! DMA buffers: memory that devices read and write on their own.
+//!
+//! A `DmaBuffer` is zeroed, physically contiguous memory, aligned to at least a page, which the CPU
+//! reaches through the physical memory mapping. No IOMMU is set up, so the bus address a device is given
+//! for it is its physical address.
+//!
+//! Who may touch the memory is in its type. The CPU reads and writes a `DmaBuffer`; `give` turns it into
+//! a `DeviceBuffer` to hand to a device, which only tells where the memory is. Once the device is done,
+//! which only its driver can know, `DeviceBuffer::reclaim` turns it back. A `DeviceBuffer` dropped while
+//! the device may still write to it is leaked rather than freed.
+//!
+//! Structures the CPU and a device use at the same time, like descriptor rings, have a protocol saying
+//! who owns each part. `DeviceBuffer::read` and `DeviceBuffer::write` reach them with volatile accesses.
+
+use core::{mem, ptr, slice};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{FrameDeallocator, PhysFrame},
+};
+
+use crate::memory::{self, GlobalFrameAllocator};
+
+pub const PAGE_SIZE: usize = 4096;
+/// The end of the memory devices with 32-bit addresses reach.
+const LIMIT_32BIT: u64 = 1 << 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    /// The buffer would have no bytes.
+    Empty,
+    /// The alignment isn't a power of two.
+    InvalidAlignment(usize),
+    OutOfMemory,
+    /// The memory found is above what the device reaches.
+    Unreachable,
+}
+
+/// Memory for DMA, which the CPU may use. See the module documentation.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    start: PhysFrame,
+    frames: u64,
+    len: usize,
+}
+
+impl DmaBuffer {
+    /// `len` bytes, aligned to a page.
+    pub fn new(len: usize) -> Result<Self, DmaError> {
+        Self::new_aligned(len, PAGE_SIZE)
+    }
+
+    /// `len` bytes aligned to `align`, a power of two. Alignments up to a page cost nothing; larger ones
+    /// take up to `align` more bytes while allocating.
+    pub fn new_aligned(len: usize, align: usize) -> Result<Self, DmaError> {
+        if !align.is_power_of_two() {
+            return Err(DmaError::InvalidAlignment(align));
+        }
+        if len == 0 {
+            return Err(DmaError::Empty);
+        }
+
+        let frames = len.div_ceil(PAGE_SIZE) as u64;
+        let align_frames = (align / PAGE_SIZE).max(1) as u64;
+        // Enough frames for an aligned run of `frames` to be among them. The others are freed.
+        let total = frames + align_frames - 1;
+        let first = memory::allocate_contiguous_frames(total).ok_or(DmaError::OutOfMemory)?;
+        let index = first.start_address().as_u64() / PAGE_SIZE as u64;
+        let start = first + (align_frames - index % align_frames) % align_frames;
+        for frame in
+            PhysFrame::range(first, start).chain(PhysFrame::range(start + frames, first + total))
+        {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+
+        Ok(DmaBuffer { start, frames, len })
+    }
+
+    /// `len` bytes aligned to a page, for a device that only reaches the first 4 GiB.
+    pub fn new_32bit(len: usize) -> Result<Self, DmaError> {
+        let buffer = Self::new(len)?;
+        let end = buffer.bus_address().as_u64() + buffer.frames * PAGE_SIZE as u64;
+        if end > LIMIT_32BIT {
+            return Err(DmaError::Unreachable);
+        }
+        Ok(buffer)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Always false, as buffers have at least a byte.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The address to give the device.
+    pub fn bus_address(&self) -> PhysAddr {
+        self.start.start_address()
+    }
+
+    /// Where the CPU reaches the buffer.
+    pub fn virt_address(&self) -> VirtAddr {
+        memory::phys_to_virt(self.bus_address())
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt_address().as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt_address().as_mut_ptr(), self.len) }
+    }
+
+    /// Reads a `T` at `offset`, which need not be aligned for it.
+    ///
+    /// # Panics
+    ///
+    /// If the `T` doesn't fit in the buffer.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + size_of::<T>() <= self.len, "read past the buffer");
+        unsafe {
+            (self.virt_address() + offset as u64)
+                .as_ptr::<T>()
+                .read_unaligned()
+        }
+    }
+
+    /// Writes `value` at `offset`, which need not be aligned for it.
+    ///
+    /// # Panics
+    ///
+    /// If the `T` doesn't fit in the buffer.
+    pub fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        assert!(offset + size_of::<T>() <= self.len, "write past the buffer");
+        unsafe {
+            (self.virt_address() + offset as u64)
+                .as_mut_ptr::<T>()
+                .write_unaligned(value)
+        }
+    }
+
+    /// Hands the buffer to a device, after which the CPU no longer touches it.
+    pub fn give(self) -> DeviceBuffer {
+        DeviceBuffer { buffer: Some(self) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        for frame in PhysFrame::range(self.start, self.start + self.frames) {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+/// Memory for DMA, which a device may be using. See the module documentation.
+#[derive(Debug)]
+#[must_use = "a buffer dropped while the device owns it is leaked"]
+pub struct DeviceBuffer {
+    /// Only `None` once reclaimed.
+    buffer: Option<DmaBuffer>,
+}
+
+impl DeviceBuffer {
+    fn buffer(&self) -> &DmaBuffer {
+        self.buffer.as_ref().expect("buffer reclaimed")
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer().len
+    }
+
+    /// Always false, as buffers have at least a byte.
+    pub fn is_empty(&self) -> bool {
+        self.buffer().is_empty()
+    }
+
+    pub fn bus_address(&self) -> PhysAddr {
+        self.buffer().bus_address()
+    }
+
+    /// Takes the buffer back for the CPU.
+    ///
+    /// # Safety
+    ///
+    /// The device must be done with the buffer: it must never read or write it again, unless given it
+    /// anew.
+    pub unsafe fn reclaim(mut self) -> DmaBuffer {
+        self.buffer.take().expect("buffer reclaimed")
+    }
+
+    /// Reads a `T` at `offset`, which must be aligned for it, as the device may be writing it.
+    ///
+    /// # Panics
+    ///
+    /// If the `T` doesn't fit in the buffer or `offset` isn't aligned.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        let address = self.checked(offset, size_of::<T>(), align_of::<T>());
+        unsafe { address.as_ptr::<T>().read_volatile() }
+    }
+
+    /// Writes `value` at `offset`, which must be aligned for it, as the device may be reading it.
+    /// Following the device's protocol, e.g. only writing what it doesn't own, is up to the caller.
+    ///
+    /// # Panics
+    ///
+    /// If the `T` doesn't fit in the buffer or `offset` isn't aligned.
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        let address = self.checked(offset, size_of::<T>(), align_of::<T>());
+        unsafe { address.as_mut_ptr::<T>().write_volatile(value) }
+    }
+
+    /// Copies the bytes at `offset` into `bytes`, e.g. a frame the device handed back. The device must not
+    /// be writing them.
+    ///
+    /// # Panics
+    ///
+    /// If the bytes don't fit in the buffer.
+    pub fn read_bytes(&self, offset: usize, bytes: &mut [u8]) {
+        let address = self.checked(offset, bytes.len(), 1);
+        unsafe { ptr::copy_nonoverlapping(address.as_ptr(), bytes.as_mut_ptr(), bytes.len()) }
+    }
+
+    /// Copies `bytes` to `offset`, e.g. a frame before handing it to the device. The device must not be
+    /// reading them.
+    ///
+    /// # Panics
+    ///
+    /// If the bytes don't fit in the buffer.
+    pub fn write_bytes(&self, offset: usize, bytes: &[u8]) {
+        let address = self.checked(offset, bytes.len(), 1);
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), address.as_mut_ptr(), bytes.len()) }
+    }
+
+    fn checked(&self, offset: usize, size: usize, align: usize) -> VirtAddr {
+        assert!(offset + size <= self.len(), "access past the buffer");
+        assert!(offset.is_multiple_of(align), "unaligned access");
+        self.buffer().virt_address() + offset as u64
+    }
+}
+
+impl Drop for DeviceBuffer {
+    /// Leaks the buffer, which the device may still write to.
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            mem::forget(buffer);
+        }
+    }
+}
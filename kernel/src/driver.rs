@@ -0,0 +1,439 @@
+//! The driver model: drivers say which devices they handle, and are bound to the devices found.
+//!
+//! A `Driver` matches PCI functions by vendor and device ID or by class, or devices in the ACPI namespace
+//! by hardware ID, or is a platform driver for a legacy device that is always there, like the PS/2
+//! controller. Drivers are `register`ed, then `probe_all` goes through the devices `pci::init` and
+//! `acpi::aml::init` found, and calls the probe function of the first driver matching each one.
+//!
+//! Drivers are probed by `Priority`, whatever bus their devices are on. Those that must set up their device
+//! before others, like the xHCI controller before the PS/2 one it may be emulating, are `Early`.
+//!
+//! While probing, a driver claims the resources it uses, its register ranges and IRQ lines, through
+//! `Claims`. A resource another driver holds is refused, so two drivers never drive the same registers.
+//! What a driver claimed is kept with its binding if the probe succeeds, and released if it fails.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use crate::{
+    acpi::aml::{self, Resource as AcpiResource},
+    pci, println,
+};
+
+static DRIVERS: Mutex<Vec<&'static Driver>> = Mutex::new(Vec::new());
+static BINDINGS: Mutex<Vec<Binding>> = Mutex::new(Vec::new());
+
+pub struct Driver {
+    pub name: &'static str,
+    pub priority: Priority,
+    pub bus: Bus,
+}
+
+/// When the devices of a driver are probed: those of every `Early` driver before any `Normal` one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Early,
+    Normal,
+}
+
+/// The devices a driver handles, and how it sets one up.
+pub enum Bus {
+    Pci {
+        matches: &'static [PciMatch],
+        probe: fn(&pci::Device, &mut Claims) -> Result<(), ProbeError>,
+    },
+    Acpi {
+        /// Hardware IDs, such as `PNP0303`.
+        ids: &'static [&'static str],
+        probe: fn(&AcpiDevice, &mut Claims) -> Result<(), ProbeError>,
+    },
+    /// A single device at fixed resources, which the probe finds out is there or not.
+    Platform {
+        probe: fn(&mut Claims) -> Result<(), ProbeError>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciMatch {
+    Id {
+        vendor_id: u16,
+        device_ids: &'static [u16],
+    },
+    /// Any function of the class, with this programming interface if `prog_if` isn't `None`.
+    Class {
+        class: u8,
+        subclass: u8,
+        prog_if: Option<u8>,
+    },
+}
+
+impl PciMatch {
+    pub fn matches(&self, device: &pci::Device) -> bool {
+        match *self {
+            PciMatch::Id {
+                vendor_id,
+                device_ids,
+            } => device.vendor_id == vendor_id && device_ids.contains(&device.device_id),
+            PciMatch::Class {
+                class,
+                subclass,
+                prog_if,
+            } => {
+                (device.class, device.subclass) == (class, subclass)
+                    && prog_if.is_none_or(|prog_if| prog_if == device.prog_if)
+            }
+        }
+    }
+}
+
+/// A device from the ACPI namespace, with a hardware ID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AcpiDevice {
+    pub path: String,
+    pub hid: String,
+    /// The current resources, from `_CRS`.
+    pub resources: Vec<AcpiResource>,
+}
+
+/// A bound device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceId {
+    Pci(pci::Address),
+    /// The path in the ACPI namespace.
+    Acpi(String),
+    /// The name of the platform driver.
+    Platform(&'static str),
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceId::Pci(address) => write!(f, "{}", address),
+            DeviceId::Acpi(path) => f.write_str(path),
+            DeviceId::Platform(name) => f.write_str(name),
+        }
+    }
+}
+
+/// Something a device decodes or raises, which only one driver may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Memory {
+        start: PhysAddr,
+        len: u64,
+    },
+    Io {
+        port: u16,
+        len: u32,
+    },
+    /// A PIC line (0-15).
+    Irq(u8),
+}
+
+impl Resource {
+    pub fn overlaps(&self, other: &Resource) -> bool {
+        fn ranges_overlap(a: u64, a_len: u64, b: u64, b_len: u64) -> bool {
+            a < b + b_len && b < a + a_len
+        }
+
+        match (*self, *other) {
+            (
+                Resource::Memory { start, len },
+                Resource::Memory {
+                    start: b,
+                    len: b_len,
+                },
+            ) => ranges_overlap(start.as_u64(), len, b.as_u64(), b_len),
+            (
+                Resource::Io { port, len },
+                Resource::Io {
+                    port: b,
+                    len: b_len,
+                },
+            ) => ranges_overlap(port.into(), len.into(), b.into(), b_len.into()),
+            (Resource::Irq(line), Resource::Irq(b)) => line == b,
+            _ => false,
+        }
+    }
+}
+
+impl From<pci::Bar> for Resource {
+    fn from(bar: pci::Bar) -> Self {
+        match bar {
+            pci::Bar::Memory { address, size, .. } => Resource::Memory {
+                start: PhysAddr::new(address),
+                len: size,
+            },
+            pci::Bar::Io { port, size } => Resource::Io { port, len: size },
+        }
+    }
+}
+
+/// A resource was already claimed, by driver `owner`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Busy {
+    pub resource: Resource,
+    pub owner: &'static str,
+}
+
+/// Why a probe failed, as the driver's own error.
+pub struct ProbeError(Box<dyn fmt::Debug + Send + Sync>);
+
+impl ProbeError {
+    pub fn new(error: impl fmt::Debug + Send + Sync + 'static) -> Self {
+        ProbeError(Box::new(error))
+    }
+}
+
+impl fmt::Debug for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The resources a driver claims while probing a device.
+pub struct Claims {
+    driver: &'static str,
+    resources: Vec<Resource>,
+}
+
+impl Claims {
+    /// Claims `resource`, unless a driver, this one included, already holds some of it.
+    pub fn claim(&mut self, resource: Resource) -> Result<(), Busy> {
+        let busy = |owner| Err(Busy { resource, owner });
+        if self.resources.iter().any(|held| held.overlaps(&resource)) {
+            return busy(self.driver);
+        }
+        for binding in BINDINGS.lock().iter() {
+            if binding
+                .resources
+                .iter()
+                .any(|held| held.overlaps(&resource))
+            {
+                return busy(binding.driver);
+            }
+        }
+        self.resources.push(resource);
+        Ok(())
+    }
+
+    /// Claims every BAR of `device`.
+    pub fn claim_bars(&mut self, device: &pci::Device) -> Result<(), Busy> {
+        for bar in device.bars.into_iter().flatten() {
+            self.claim(bar.into())?;
+        }
+        Ok(())
+    }
+
+    /// Claims the IRQ line `irq`, if it is a PIC line no other driver holds. Drivers that can poll
+    /// their device do so otherwise.
+    pub fn claim_irq(&mut self, irq: u8) -> bool {
+        irq < 16 && self.claim(Resource::Irq(irq)).is_ok()
+    }
+}
+
+/// A driver bound to a device, and the resources it holds.
+#[derive(Debug, Clone)]
+pub struct Binding {
+    pub driver: &'static str,
+    pub device: DeviceId,
+    pub resources: Vec<Resource>,
+}
+
+/// Adds `driver` to those `probe_all` tries. Drivers registered first are tried first among those of the
+/// same priority.
+pub fn register(driver: &'static Driver) {
+    let mut drivers = DRIVERS.lock();
+    if !drivers
+        .iter()
+        .any(|registered| core::ptr::eq(*registered, driver))
+    {
+        drivers.push(driver);
+    }
+}
+
+/// Probes every device found on the PCI bus and in the ACPI namespace, and every platform device, that
+/// isn't bound yet with the first driver matching it, a priority at a time. Failures are printed. Returns
+/// the number of devices bound.
+pub fn probe_all() -> usize {
+    // Copied, so that a probe may register drivers. The sort is stable, which keeps the registration order.
+    let mut drivers = DRIVERS.lock().clone();
+    drivers.sort_by_key(|driver| driver.priority);
+    drivers
+        .chunk_by(|a, b| a.priority == b.priority)
+        .map(probe_with)
+        .sum()
+}
+
+/// Probes the devices that aren't bound yet with the first of `drivers` matching each one.
+fn probe_with(drivers: &[&'static Driver]) -> usize {
+    let mut bound = 0;
+
+    for device in pci::devices() {
+        let id = DeviceId::Pci(device.address);
+        let driver = drivers.iter().find_map(|driver| match &driver.bus {
+            Bus::Pci { matches, probe } if matches.iter().any(|m| m.matches(device)) => {
+                Some((driver.name, probe))
+            }
+            _ => None,
+        });
+        if let Some((name, probe)) = driver
+            && bind(name, id, |claims| probe(device, claims))
+        {
+            bound += 1;
+        }
+    }
+
+    for device in acpi_devices() {
+        let id = DeviceId::Acpi(device.path.clone());
+        let driver = drivers.iter().find_map(|driver| match &driver.bus {
+            Bus::Acpi { ids, probe } if ids.contains(&device.hid.as_str()) => {
+                Some((driver.name, probe))
+            }
+            _ => None,
+        });
+        if let Some((name, probe)) = driver
+            && bind(name, id, |claims| probe(&device, claims))
+        {
+            bound += 1;
+        }
+    }
+
+    for driver in drivers {
+        if let Bus::Platform { probe } = &driver.bus
+            && bind(driver.name, DeviceId::Platform(driver.name), probe)
+        {
+            bound += 1;
+        }
+    }
+
+    bound
+}
+
+/// The devices with a hardware ID in the ACPI namespace, if it is loaded.
+fn acpi_devices() -> Vec<AcpiDevice> {
+    let Some(namespace) = aml::namespace() else {
+        return Vec::new();
+    };
+    namespace
+        .devices()
+        .filter_map(|device| {
+            Some(AcpiDevice {
+                path: String::from(device.path),
+                hid: device.hid?,
+                resources: device.resources,
+            })
+        })
+        .collect()
+}
+
+/// Runs `probe` for `driver` on `device`, unless it is bound already, and keeps the binding if it
+/// succeeds.
+fn bind(
+    driver: &'static str,
+    device: DeviceId,
+    probe: impl FnOnce(&mut Claims) -> Result<(), ProbeError>,
+) -> bool {
+    if BINDINGS
+        .lock()
+        .iter()
+        .any(|binding| binding.device == device)
+    {
+        return false;
+    }
+
+    let mut claims = Claims {
+        driver,
+        resources: Vec::new(),
+    };
+    match probe(&mut claims) {
+        Ok(()) => {
+            BINDINGS.lock().push(Binding {
+                driver,
+                device,
+                resources: claims.resources,
+            });
+            true
+        }
+        Err(error) => {
+            println!("WARNING: {} not bound to {}: {:?}", driver, device, error);
+            false
+        }
+    }
+}
+
+/// The devices bound so far, in the order they were.
+pub fn bindings() -> Vec<Binding> {
+    BINDINGS.lock().clone()
+}
+
+/// Lists the bound devices, one line each with the driver and the resources it holds.
+pub fn dump(output: &mut impl Write) -> fmt::Result {
+    for binding in BINDINGS.lock().iter() {
+        write!(output, "{} {}:", binding.device, binding.driver)?;
+        for resource in &binding.resources {
+            match resource {
+                Resource::Memory { start, len } => {
+                    write!(output, " mem {:#x}+{:#x}", start.as_u64(), len)?
+                }
+                Resource::Io { port, len } => write!(output, " io {:#x}+{:#x}", port, len)?,
+                Resource::Irq(line) => write!(output, " irq {}", line)?,
+            }
+        }
+        writeln!(output)?;
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_pci_match() {
+    let device = pci::Device {
+        address: pci::Address {
+            segment: 0,
+            bus: 0,
+            device: 3,
+            function: 0,
+        },
+        vendor_id: 0x8086,
+        device_id: 0x100e,
+        class: 0x02,
+        subclass: 0x00,
+        prog_if: 0x00,
+        revision: 3,
+        header_type: 0,
+        bars: [None; 6],
+        interrupt_line: 11,
+        interrupt_pin: 1,
+    };
+
+    let by_id = |device_ids| PciMatch::Id {
+        vendor_id: 0x8086,
+        device_ids,
+    };
+    assert!(by_id(&[0x100f, 0x100e]).matches(&device));
+    assert!(!by_id(&[0x100f]).matches(&device));
+
+    let by_class = |prog_if| PciMatch::Class {
+        class: 0x02,
+        subclass: 0x00,
+        prog_if,
+    };
+    assert!(by_class(None).matches(&device));
+    assert!(by_class(Some(0)).matches(&device));
+    assert!(!by_class(Some(1)).matches(&device));
+}
+
+#[test_case]
+fn test_resource_overlaps() {
+    let memory = |start, len| Resource::Memory {
+        start: PhysAddr::new(start),
+        len,
+    };
+    assert!(memory(0x1000, 0x1000).overlaps(&memory(0x1fff, 1)));
+    assert!(!memory(0x1000, 0x1000).overlaps(&memory(0x2000, 0x1000)));
+    assert!(Resource::Irq(11).overlaps(&Resource::Irq(11)));
+    assert!(!Resource::Irq(11).overlaps(&Resource::Io { port: 11, len: 1 }));
+    assert!(Resource::Io { port: 0x60, len: 5 }.overlaps(&Resource::Io { port: 0x64, len: 1 }));
+}
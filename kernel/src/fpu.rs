@@ -0,0 +1,281 @@
+//! x87 FPU and SSE state.
+//!
+//! The kernel is built for a soft-float target, so its own code never touches the FPU or SIMD registers:
+//! they only hold the state of the execution context that runs on top of it. Each context owns an
+//! `FpuState`, a save area for XSAVE (or FXSAVE on CPUs without it).
+//!
+//! Saving and restoring that area on every switch would be expensive, so it happens lazily. `switch_to`
+//! only records which state should be live and sets CR0.TS; the first FPU or SIMD instruction afterwards
+//! raises #NM (device not available), whose handler stores the registers into the previous owner's area
+//! and loads the new context's.
+
+use alloc::alloc::{Layout, alloc_zeroed, dealloc};
+use conquer_once::spin::OnceCell;
+use core::{
+    arch::asm,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicPtr, Ordering},
+};
+use x86_64::registers::{
+    control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    xcontrol::{XCr0, XCr0Flags},
+};
+
+use crate::cpu;
+
+const CPUID_XSAVE: u32 = 1 << 26;
+const CPUID_AVX: u32 = 1 << 28;
+
+/// Size of the legacy FXSAVE area, which is also the start of every XSAVE area.
+const FXSAVE_AREA_SIZE: usize = 512;
+/// XSAVE needs 64-byte alignment (FXSAVE only 16).
+const AREA_ALIGN: usize = 64;
+
+/// Default x87 control word: all exceptions masked, 64-bit precision, round to nearest.
+const DEFAULT_FCW: u16 = 0x037F;
+/// Default MXCSR: all SIMD exceptions masked, round to nearest.
+const DEFAULT_MXCSR: u32 = 0x1F80;
+const MXCSR_OFFSET: usize = 24;
+
+static CONFIG: OnceCell<Config> = OnceCell::uninit();
+/// The state whose values are currently in the registers.
+static OWNER: AtomicPtr<FpuState> = AtomicPtr::new(ptr::null_mut());
+/// The state of the running context, loaded on its first FPU use.
+static CURRENT: AtomicPtr<FpuState> = AtomicPtr::new(ptr::null_mut());
+
+#[derive(Debug, Clone, Copy)]
+struct Config {
+    /// State components saved by XSAVE, or `None` if only FXSAVE is available.
+    xsave_components: Option<u64>,
+    area_size: usize,
+}
+
+/// Enables the FPU and SSE, and XSAVE with AVX state when the CPU supports it.
+pub fn init() {
+    CONFIG.get_or_init(|| {
+        unsafe {
+            let mut cr0 = Cr0::read();
+            cr0.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            cr0.insert(Cr0Flags::MONITOR_COPROCESSOR);
+            Cr0::write(cr0);
+
+            Cr4::update(|cr4| cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+        }
+
+        let features = cpu::cpuid(1, 0).ecx;
+
+        if features & CPUID_XSAVE == 0 {
+            return Config {
+                xsave_components: None,
+                area_size: FXSAVE_AREA_SIZE,
+            };
+        }
+
+        let mut components = XCr0Flags::X87 | XCr0Flags::SSE;
+        if features & CPUID_AVX != 0 {
+            components |= XCr0Flags::AVX;
+        }
+
+        unsafe {
+            Cr4::update(|cr4| cr4.insert(Cr4Flags::OSXSAVE));
+            XCr0::write(components);
+        }
+
+        Config {
+            xsave_components: Some(components.bits()),
+            // Size of the area for the components enabled in XCR0.
+            area_size: cpu::cpuid(0xD, 0).ebx as usize,
+        }
+    });
+
+    unsafe { asm!("fninit", options(nomem, nostack)) };
+}
+
+fn config() -> Config {
+    *CONFIG.try_get().expect("fpu::init not called")
+}
+
+/// Saved FPU, SSE and (when enabled) AVX registers of one execution context.
+pub struct FpuState {
+    area: NonNull<u8>,
+}
+
+// The area is only accessed through the owning `FpuState` or by the #NM handler on this CPU.
+unsafe impl Send for FpuState {}
+
+impl FpuState {
+    /// A state with default control words and all registers cleared.
+    pub fn new() -> Self {
+        let layout = Self::layout();
+        let area = NonNull::new(unsafe { alloc_zeroed(layout) })
+            .unwrap_or_else(|| alloc::alloc::handle_alloc_error(layout));
+
+        unsafe {
+            // A zeroed XSAVE header marks every component as being in its initial state, but the legacy
+            // region is always read by FXRSTOR and partly by XRSTOR, so its control words must be valid.
+            area.as_ptr().cast::<u16>().write(DEFAULT_FCW);
+            area.as_ptr()
+                .add(MXCSR_OFFSET)
+                .cast::<u32>()
+                .write(DEFAULT_MXCSR);
+        }
+
+        FpuState { area }
+    }
+
+    /// A copy of this state, e.g. for a forked process. The registers are saved into this area first if
+    /// they are live, as the area is stale until then.
+    pub fn fork(&mut self) -> Self {
+        let this = self as *mut FpuState;
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            if OWNER.load(Ordering::Acquire) == this {
+                let task_switched = Cr0::read().contains(Cr0Flags::TASK_SWITCHED);
+                set_task_switched(false);
+                unsafe { self.save() };
+                set_task_switched(task_switched);
+            }
+        });
+
+        let copy = FpuState::new();
+        unsafe {
+            ptr::copy_nonoverlapping(self.area.as_ptr(), copy.area.as_ptr(), config().area_size)
+        };
+        copy
+    }
+
+    fn layout() -> Layout {
+        Layout::from_size_align(config().area_size, AREA_ALIGN).unwrap()
+    }
+
+    /// Stores the current registers into this area.
+    ///
+    /// # Safety
+    ///
+    /// CR0.TS must be clear, otherwise this raises #NM.
+    unsafe fn save(&mut self) {
+        let area = self.area.as_ptr();
+
+        unsafe {
+            match config().xsave_components {
+                Some(components) => asm!(
+                    "xsave64 [{}]",
+                    in(reg) area,
+                    in("eax") components as u32,
+                    in("edx") (components >> 32) as u32,
+                    options(nostack),
+                ),
+                None => asm!("fxsave64 [{}]", in(reg) area, options(nostack)),
+            }
+        }
+    }
+
+    /// Loads the registers from this area.
+    ///
+    /// # Safety
+    ///
+    /// CR0.TS must be clear, otherwise this raises #NM.
+    unsafe fn restore(&self) {
+        let area = self.area.as_ptr();
+
+        unsafe {
+            match config().xsave_components {
+                Some(components) => asm!(
+                    "xrstor64 [{}]",
+                    in(reg) area,
+                    in("eax") components as u32,
+                    in("edx") (components >> 32) as u32,
+                    options(nostack, readonly),
+                ),
+                None => asm!("fxrstor64 [{}]", in(reg) area, options(nostack, readonly)),
+            }
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        FpuState::new()
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        let this = self as *mut FpuState;
+
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let _ =
+                OWNER.compare_exchange(this, ptr::null_mut(), Ordering::AcqRel, Ordering::Acquire);
+            let _ = CURRENT.compare_exchange(
+                this,
+                ptr::null_mut(),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            );
+        });
+
+        unsafe { dealloc(self.area.as_ptr(), Self::layout()) };
+    }
+}
+
+fn set_task_switched(task_switched: bool) {
+    unsafe {
+        Cr0::update(|cr0| cr0.set(Cr0Flags::TASK_SWITCHED, task_switched));
+    }
+}
+
+/// Makes `state` the FPU state of the running context. Nothing is saved or loaded until the context
+/// actually uses the FPU. Meant to be called on every context switch.
+///
+/// # Safety
+///
+/// `state` must stay valid (and not move) while it is current or its registers are live, or be null for
+/// contexts that never use the FPU.
+pub unsafe fn switch_to(state: *mut FpuState) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        CURRENT.store(state, Ordering::Release);
+        set_task_switched(OWNER.load(Ordering::Acquire) != state);
+    });
+}
+
+/// Called by the #NM handler: hands the registers over to the running context.
+pub(crate) fn on_device_not_available() {
+    set_task_switched(false);
+
+    let current = CURRENT.load(Ordering::Acquire);
+    let owner = OWNER.swap(current, Ordering::AcqRel);
+
+    if owner == current {
+        return;
+    }
+
+    unsafe {
+        if let Some(owner) = owner.as_mut() {
+            owner.save();
+        }
+
+        match current.as_ref() {
+            Some(current) => current.restore(),
+            None => asm!("fninit", options(nomem, nostack)),
+        }
+    }
+}
+
+/// Lets kernel code (e.g. an interrupt handler) use FPU or SIMD instructions in `f` without corrupting the
+/// state of the interrupted context. Interrupts stay disabled while `f` runs.
+pub fn with_fpu<R>(f: impl FnOnce() -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        set_task_switched(false);
+
+        // Move the live registers to their owner, so `f` may clobber them.
+        if let Some(owner) = unsafe { OWNER.swap(ptr::null_mut(), Ordering::AcqRel).as_mut() } {
+            unsafe { owner.save() };
+        }
+
+        unsafe { asm!("fninit", options(nomem, nostack)) };
+        let result = f();
+
+        // Nobody owns the registers now, so the next FPU use by the context reloads its state.
+        set_task_switched(true);
+        result
+    })
+}
@@ -1,98 +1,875 @@
+//! Text output on the framebuffer set up by the bootloader.
+//!
+//! Once paging is up, `enable_back_buffer` moves drawing into a copy of the framebuffer in RAM: text is
+//! rendered and scrolled there, and each print then copies only the rectangle it changed to the real
+//! framebuffer, whose memory is uncached and slow to read and write. Before that, text is drawn to the
+//! framebuffer directly.
+//!
+//! Text is written to one of `SCREENS` screens, each with its own cursor, of which only the shown one is
+//! drawn. The text of the last few hundred lines of each is kept, which is what a screen is drawn from
+//! when it is shown with `show_screen`, and what `scroll_back` shows again once it scrolled off the top,
+//! until the next print returns to the bottom. `console` gives the screens a purpose, and `print!` writes
+//! to its log console, which is screen 0.
+//!
+//! A cursor marks where the shown screen's next character goes. `blink_cursor` blinks it.
+//!
+//! `show_splash` covers the screen with a picture during boot. Text is still written to the scrollback
+//! meanwhile, and drawn once `end_splash` hands the screen to the log console.
+//!
+//! Windows of `compositor` are stacked over the shown screen, and drawn without taking the writer's lock.
+//!
+//! A display driver can take over from the bootloader's framebuffer with `switch_framebuffer`, e.g. to
+//! change the resolution. If its display only shows what it is told changed, it gets every rectangle
+//! copied to the framebuffer.
+
 use bootloader_api::info::{FrameBuffer, FrameBufferInfo, PixelFormat};
-use core::fmt;
-use noto_sans_mono_bitmap::{RasterizedChar, get_raster};
+use core::{fmt, time::Duration};
+use noto_sans_mono_bitmap::{
+    FontWeight, RasterHeight, RasterizedChar, get_raster, get_raster_width,
+};
 use spin::Mutex;
+use x86_64::{
+    VirtAddr,
+    structures::paging::{
+        FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, mapper::MapToError,
+    },
+};
+
+use self::{picture::Picture, scrollback::Scrollback};
+use crate::{
+    init_state::{AlreadyInitialized, InitState},
+    task::timer,
+    userspace,
+};
 
-use crate::{framebuffer::font_constants::BACKUP_CHAR, userspace};
+pub mod capture;
+pub mod compositor;
+pub mod cursor;
+pub mod gfx;
+pub mod picture;
+mod scrollback;
+#[cfg(feature = "framebuffer-stats")]
+pub mod stats;
 
 pub static WRITER: Mutex<Option<Writer>> = Mutex::new(None);
+/// The scrollback of each screen. Only locked with `WRITER` held.
+static SCROLLBACKS: Mutex<[Scrollback; SCREENS]> =
+    Mutex::new([const { Scrollback::new() }; SCREENS]);
+
+/// Number of screens text can be written to.
+pub const SCREENS: usize = 4;
+/// How long the cursor stays on, and then off, when blinking.
+pub const CURSOR_BLINK_PERIOD: Duration = Duration::from_millis(500);
+/// Height of `CursorStyle::Underline`, in pixels.
+const UNDERLINE_HEIGHT: usize = 2;
 
 const LINE_SPACING: usize = 2;
 const LETTER_SPACING: usize = 0;
 const BORDER_PADDING: usize = 1;
+/// Drawn for characters the font doesn't have.
+const BACKUP_CHAR: char = '�';
+/// Columns from one tab stop to the next.
+const TAB_WIDTH: usize = 8;
+
+/// Columns `character` takes up on the screen. Control characters and the ones that only combine with
+/// their neighbours take none and aren't drawn. Wide characters, such as CJK ideographs and emoji, take
+/// two like in a terminal, and are drawn as `BACKUP_CHAR` followed by a blank since the font lacks them.
+fn char_columns(character: char) -> usize {
+    match character {
+        '\u{0}'..='\u{1f}'
+        | '\u{7f}'..='\u{9f}'
+        | '\u{300}'..='\u{36f}'
+        | '\u{200b}'..='\u{200f}'
+        | '\u{2060}'..='\u{2064}'
+        | '\u{fe00}'..='\u{fe0f}'
+        | '\u{feff}' => 0,
+        '\u{1100}'..='\u{115f}'
+        | '\u{2e80}'..='\u{303e}'
+        | '\u{3041}'..='\u{33ff}'
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{a000}'..='\u{a4cf}'
+        | '\u{ac00}'..='\u{d7a3}'
+        | '\u{f900}'..='\u{faff}'
+        | '\u{fe30}'..='\u{fe4f}'
+        | '\u{ff00}'..='\u{ff60}'
+        | '\u{ffe0}'..='\u{ffe6}'
+        | '\u{1f300}'..='\u{1f64f}'
+        | '\u{1f900}'..='\u{1f9ff}'
+        | '\u{20000}'..='\u{3fffd}' => 2,
+        _ => 1,
+    }
+}
+
+/// The size and weight text is drawn in, see `set_font`.
+#[derive(Debug, Clone, Copy)]
+pub struct Font {
+    pub height: RasterHeight,
+    pub weight: FontWeight,
+}
+
+impl Font {
+    pub const DEFAULT: Font = Font {
+        height: RasterHeight::Size16,
+        weight: FontWeight::Regular,
+    };
+
+    /// A font that stays readable on a framebuffer `width` pixels wide, for high resolutions.
+    pub fn for_width(width: usize) -> Font {
+        let height = match width {
+            3840.. => RasterHeight::Size32,
+            2560.. => RasterHeight::Size24,
+            1920.. => RasterHeight::Size20,
+            _ => RasterHeight::Size16,
+        };
 
-mod font_constants {
-    use noto_sans_mono_bitmap::{FontWeight, RasterHeight, get_raster_width};
+        Font {
+            height,
+            ..Font::DEFAULT
+        }
+    }
+
+    pub fn char_width(self) -> usize {
+        get_raster_width(self.weight, self.height)
+    }
+
+    pub fn char_height(self) -> usize {
+        self.height.val()
+    }
 
-    pub const CHAR_RASTER_HEIGHT: RasterHeight = RasterHeight::Size16;
+    /// Distance between the tops of two lines of text.
+    pub fn line_height(self) -> usize {
+        self.char_height() + LINE_SPACING
+    }
 
-    pub const CHAR_RASTER_WIDTH: usize = get_raster_width(FontWeight::Regular, CHAR_RASTER_HEIGHT);
+    fn raster(self, character: char) -> RasterizedChar {
+        let get = |character| get_raster(character, self.weight, self.height);
 
-    pub const BACKUP_CHAR: char = '�';
+        get(character).unwrap_or_else(|| get(BACKUP_CHAR).expect("Backup char not found"))
+    }
+}
 
-    pub const FONT_WEIGHT: FontWeight = FontWeight::Regular;
+/// A color as red, green and blue intensities, converted to the framebuffer's pixel format when drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
 }
 
-fn get_char_raster(character: char) -> RasterizedChar {
-    fn get(character: char) -> Option<RasterizedChar> {
-        get_raster(
-            character,
-            font_constants::FONT_WEIGHT,
-            font_constants::CHAR_RASTER_HEIGHT,
-        )
+impl Color {
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+    pub const WHITE: Color = Color::rgb(255, 255, 255);
+    pub const RED: Color = Color::rgb(255, 80, 80);
+    pub const GREEN: Color = Color::rgb(80, 255, 80);
+    pub const BLUE: Color = Color::rgb(100, 150, 255);
+    pub const YELLOW: Color = Color::rgb(255, 220, 0);
+    pub const CYAN: Color = Color::rgb(0, 220, 220);
+    pub const GREY: Color = Color::rgb(160, 160, 160);
+
+    /// The pale yellow text is drawn in unless `set_color` says otherwise.
+    pub const DEFAULT_FOREGROUND: Color = Color::rgb(255, 255, 127);
+    pub const DEFAULT_BACKGROUND: Color = Color::BLACK;
+
+    pub const fn rgb(red: u8, green: u8, blue: u8) -> Color {
+        Color { red, green, blue }
+    }
+
+    /// Mixes `self` into `background` by `alpha`, from 0 (only background) to 255 (only `self`).
+    fn blend(self, background: Color, alpha: u8) -> Color {
+        let mix = |foreground: u8, background: u8| {
+            let alpha = u16::from(alpha);
+            ((u16::from(foreground) * alpha + u16::from(background) * (255 - alpha)) / 255) as u8
+        };
+
+        Color {
+            red: mix(self.red, background.red),
+            green: mix(self.green, background.green),
+            blue: mix(self.blue, background.blue),
+        }
     }
 
-    get(character).unwrap_or_else(|| get(BACKUP_CHAR).expect("Backup char not found"))
+    /// Perceived brightness, for greyscale framebuffers.
+    fn luminance(self) -> u8 {
+        ((u16::from(self.red) * 77 + u16::from(self.green) * 150 + u16::from(self.blue) * 29) >> 8)
+            as u8
+    }
+
+    /// The bytes of a pixel of this color in `format`, or `None` if the format isn't supported.
+    fn to_pixel(self, format: PixelFormat) -> Option<[u8; 4]> {
+        match format {
+            PixelFormat::Rgb => Some([self.red, self.green, self.blue, 0]),
+            PixelFormat::Bgr => Some([self.blue, self.green, self.red, 0]),
+            PixelFormat::U8 => Some([if self.luminance() > 200 { 0xf } else { 0 }, 0, 0, 0]),
+            PixelFormat::Unknown {
+                red_position,
+                green_position,
+                blue_position,
+            } => {
+                let pixel = (u32::from(self.red) << red_position)
+                    | (u32::from(self.green) << green_position)
+                    | (u32::from(self.blue) << blue_position);
+                Some(pixel.to_le_bytes())
+            }
+            _ => None,
+        }
+    }
+
+    /// The color of a pixel stored as `bytes` in `format`, the inverse of `to_pixel`.
+    fn from_pixel(format: PixelFormat, bytes: &[u8]) -> Option<Color> {
+        let mut pixel = [0; 4];
+        let len = bytes.len().min(pixel.len());
+        pixel[..len].copy_from_slice(&bytes[..len]);
+
+        match format {
+            PixelFormat::Rgb => Some(Color::rgb(pixel[0], pixel[1], pixel[2])),
+            PixelFormat::Bgr => Some(Color::rgb(pixel[2], pixel[1], pixel[0])),
+            PixelFormat::U8 => Some(Color::rgb(pixel[0], pixel[0], pixel[0])),
+            PixelFormat::Unknown {
+                red_position,
+                green_position,
+                blue_position,
+            } => {
+                let pixel = u32::from_le_bytes(pixel);
+                let channel = |position: u8| (pixel >> position) as u8;
+                Some(Color::rgb(
+                    channel(red_position),
+                    channel(green_position),
+                    channel(blue_position),
+                ))
+            }
+            _ => None,
+        }
+    }
 }
 
-pub fn init(framebuffer: FrameBuffer) {
+static INIT: InitState = InitState::new("framebuffer");
+
+pub fn init(framebuffer: FrameBuffer) -> Result<(), AlreadyInitialized> {
+    INIT.begin()?;
+
     let mut writer = Writer {
+        font: Font::for_width(framebuffer.info().width),
         info: framebuffer.info(),
         buffer: framebuffer,
         x_position: 0,
         y_position: 0,
+        foreground: Color::DEFAULT_FOREGROUND,
+        background: Color::DEFAULT_BACKGROUND,
+        back_buffer: None,
+        dirty: None,
+        view_offset: 0,
+        shown: 0,
+        writing: 0,
+        cursors: [(BORDER_PADDING, BORDER_PADDING); SCREENS],
+        cursor_style: CursorStyle::Underline,
+        cursor_on: true,
+        cursor_drawn: None,
+        read_back: false,
+        pointer: cursor::Pointer::new(),
+        splash: false,
+        flush_scanout: None,
     };
     writer.clear();
 
-    let mut global_writer = WRITER.try_lock().unwrap();
-    assert!(global_writer.is_none(), "Global writer must be None");
+    *WRITER.lock() = Some(writer);
+    Ok(())
+}
+
+/// Whether `init` has been called, so printing won't panic.
+pub fn is_initialized() -> bool {
+    INIT.is_initialized()
+}
+
+/// Where the back buffer is mapped. It is as large as the framebuffer, far more than the heap holds.
+pub const BACK_BUFFER_START: usize = 0x_4444_8888_0000;
 
-    *global_writer = Some(writer);
+/// Maps a back buffer at `BACK_BUFFER_START` and draws into it from now on, see the module documentation.
+/// Does nothing if there is no framebuffer or it already has a back buffer. On failure, drawing keeps
+/// going to the framebuffer directly.
+pub fn enable_back_buffer(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        map_back_buffer(mapper, frame_allocator)
+    })
+}
+
+fn map_back_buffer(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let mut writer = WRITER.lock();
+    let Some(writer) = writer
+        .as_mut()
+        .filter(|writer| writer.back_buffer.is_none())
+    else {
+        return Ok(());
+    };
+
+    let len = writer.buffer.buffer().len();
+    let start = VirtAddr::new(BACK_BUFFER_START as u64);
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(start + (len as u64 - 1)),
+    );
+
+    for page in pages {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        }
+    }
+
+    let back_buffer = unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr::<u8>(), len) };
+    // Starts from what is on the screen, reading the framebuffer this once.
+    back_buffer.copy_from_slice(writer.buffer.buffer());
+    writer.back_buffer = Some(back_buffer);
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum SwitchError {
+    /// Drawing doesn't go through a back buffer, which the new framebuffer is drawn from.
+    NoBackBuffer,
+    /// The back buffer couldn't be mapped at the new size. Drawing stays on the old framebuffer.
+    Map(MapToError<Size4KiB>),
+}
+
+/// Draws on `buffer` from now on instead of the framebuffer drawn on so far, e.g. one of a display
+/// driver in another mode, redrawing the shown screen on it. `flush_scanout` is called with each
+/// rectangle copied to `buffer`, for displays that only show what they are told changed.
+pub fn switch_framebuffer(
+    buffer: FrameBuffer,
+    flush_scanout: Option<fn(gfx::Rect)>,
+) -> Result<(), SwitchError> {
+    if !INIT.is_initialized() {
+        return Err(SwitchError::NoBackBuffer);
+    }
+    let mut result = Ok(());
+    with_writer(|writer| result = writer.switch_framebuffer(buffer, flush_scanout));
+    result
+}
+
+/// A rectangle of pixels that changed since the last flush, with exclusive ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Dirty {
+    left: usize,
+    top: usize,
+    right: usize,
+    bottom: usize,
+}
+
+impl Dirty {
+    /// The pixels of `rect`, if it has any and it ends before `usize::MAX`.
+    fn from_rect(rect: gfx::Rect) -> Option<Dirty> {
+        if rect.width == 0 || rect.height == 0 {
+            return None;
+        }
+
+        Some(Dirty {
+            left: rect.x,
+            top: rect.y,
+            right: rect.x.checked_add(rect.width)?,
+            bottom: rect.y.checked_add(rect.height)?,
+        })
+    }
+
+    /// The same rectangle, moved right by `x` and down by `y`. Coordinates past `usize::MAX` stay there,
+    /// which is off every screen all the same.
+    fn offset(self, x: usize, y: usize) -> Dirty {
+        Dirty {
+            left: self.left.saturating_add(x),
+            top: self.top.saturating_add(y),
+            right: self.right.saturating_add(x),
+            bottom: self.bottom.saturating_add(y),
+        }
+    }
+
+    fn union(self, other: Dirty) -> Dirty {
+        Dirty {
+            left: self.left.min(other.left),
+            top: self.top.min(other.top),
+            right: self.right.max(other.right),
+            bottom: self.bottom.max(other.bottom),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorStyle {
+    /// Covers the whole character cell.
+    Block,
+    /// A bar under the character cell.
+    Underline,
+    Hidden,
 }
 
 pub struct Writer {
     buffer: FrameBuffer,
     info: FrameBufferInfo,
+    font: Font,
     x_position: usize,
     y_position: usize,
+    foreground: Color,
+    background: Color,
+    /// What is drawn, once `enable_back_buffer` was called.
+    back_buffer: Option<&'static mut [u8]>,
+    /// What changed in the back buffer since the last flush.
+    dirty: Option<Dirty>,
+    /// How many lines the view is scrolled back from the bottom.
+    view_offset: usize,
+    /// The screen that is drawn.
+    shown: usize,
+    /// The screen text is written to. `x_position` and `y_position` are its cursor.
+    writing: usize,
+    /// The cursors of the other screens, as `x_position` and `y_position`.
+    cursors: [(usize, usize); SCREENS],
+    cursor_style: CursorStyle,
+    /// Whether the cursor is in the visible phase of its blinking.
+    cursor_on: bool,
+    /// Where the cursor is drawn, if it is.
+    cursor_drawn: Option<(usize, usize)>,
+    /// See `set_read_back`.
+    read_back: bool,
+    pointer: cursor::Pointer,
+    /// Whether a splash picture covers the shown screen, see `show_splash`.
+    splash: bool,
+    /// Called with what `present` copied to the framebuffer, see `switch_framebuffer`.
+    flush_scanout: Option<fn(gfx::Rect)>,
 }
 
 impl Writer {
+    /// The shown screen, to draw on with `gfx`. Call `flush` to show what was drawn.
+    pub fn surface(&mut self) -> gfx::Surface<'_> {
+        self.surface_and_pointer().0
+    }
+
+    fn surface_and_pointer(&mut self) -> (gfx::Surface<'_>, &mut cursor::Pointer) {
+        let info = self.info;
+        let pixels = match &mut self.back_buffer {
+            Some(back_buffer) => &mut **back_buffer,
+            None => self.buffer.buffer_mut(),
+        };
+
+        (
+            gfx::Surface::tracking(pixels, info, &mut self.dirty),
+            &mut self.pointer,
+        )
+    }
+
+    /// Puts back what is under the mouse pointer, before drawing anything else.
+    fn hide_pointer(&mut self) {
+        let (mut surface, pointer) = self.surface_and_pointer();
+        pointer.hide(&mut surface);
+    }
+
+    /// Where drawing goes: the back buffer if there is one, otherwise the framebuffer.
+    fn pixels(&mut self) -> &mut [u8] {
+        match &mut self.back_buffer {
+            Some(back_buffer) => back_buffer,
+            None => self.buffer.buffer_mut(),
+        }
+    }
+
+    /// Records that a rectangle of the back buffer changed.
+    fn mark_dirty(&mut self, left: usize, top: usize, right: usize, bottom: usize) {
+        if self.back_buffer.is_none() {
+            return;
+        }
+
+        let area = Dirty {
+            left,
+            top,
+            right: right.min(self.width()),
+            bottom: bottom.min(self.height()),
+        };
+        self.dirty = Some(self.dirty.map_or(area, |dirty| dirty.union(area)));
+    }
+
+    fn mark_all_dirty(&mut self) {
+        self.mark_dirty(0, 0, self.width(), self.height());
+    }
+
+    /// Copies what changed in the back buffer to the framebuffer.
+    pub fn flush(&mut self) {
+        // The pointer is only drawn once everything under it is.
+        let (mut surface, pointer) = self.surface_and_pointer();
+        pointer.show(&mut surface);
+
+        if let Some(dirty) = self.dirty.take() {
+            self.present(dirty);
+        }
+
+        if self.read_back {
+            // SAFETY: reads a byte of the framebuffer, which stays mapped.
+            let _ = unsafe { core::ptr::read_volatile(self.buffer.buffer().as_ptr()) };
+        }
+    }
+
+    /// Copies `area` of the back buffer to the framebuffer, with the windows over it.
+    fn present(&mut self, area: Dirty) {
+        let Some(back_buffer) = &self.back_buffer else {
+            return;
+        };
+        let area = Dirty {
+            right: area.right.min(self.info.width),
+            bottom: area.bottom.min(self.info.height),
+            ..area
+        };
+        if area.left >= area.right || area.top >= area.bottom {
+            return;
+        }
+
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let front_buffer = self.buffer.buffer_mut();
+        for y in area.top..area.bottom {
+            let row = y * self.info.stride;
+            let start = (row + area.left) * bytes_per_pixel;
+            let end = (row + area.right) * bytes_per_pixel;
+
+            front_buffer[start..end].copy_from_slice(&back_buffer[start..end]);
+        }
+        compositor::overlay(front_buffer, &self.info, area);
+
+        if let Some(flush_scanout) = self.flush_scanout {
+            flush_scanout(gfx::Rect::new(
+                area.left,
+                area.top,
+                area.right - area.left,
+                area.bottom - area.top,
+            ));
+        }
+    }
+
+    /// See `switch_framebuffer`.
+    fn switch_framebuffer(
+        &mut self,
+        buffer: FrameBuffer,
+        flush_scanout: Option<fn(gfx::Rect)>,
+    ) -> Result<(), SwitchError> {
+        let Some(back_buffer) = self.back_buffer.take() else {
+            return Err(SwitchError::NoBackBuffer);
+        };
+        let start = VirtAddr::from_ptr(back_buffer.as_ptr());
+        let (previous_len, len) = (back_buffer.len(), buffer.info().byte_len);
+
+        if len != previous_len {
+            compositor::unmap_pixels(start, previous_len);
+            if let Err(error) = compositor::map_pixels(start, len) {
+                // The old size was just freed, so it should fit again.
+                if compositor::map_pixels(start, previous_len).is_ok() {
+                    self.back_buffer = Some(unsafe {
+                        core::slice::from_raw_parts_mut(start.as_mut_ptr(), previous_len)
+                    });
+                    self.redraw();
+                }
+                return Err(SwitchError::Map(error));
+            }
+        }
+
+        self.back_buffer =
+            Some(unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr(), len) });
+        self.info = buffer.info();
+        self.buffer = buffer;
+        self.flush_scanout = flush_scanout;
+        // Moves the cursors onto the rows there are now, and redraws everything.
+        self.set_font(self.font);
+        Ok(())
+    }
+
+    /// Whether each flush ends by reading the framebuffer back. On a framebuffer mapped uncached, the read
+    /// only completes once the writes before it have, so what was printed is on the screen before the
+    /// kernel goes on, e.g. to hang or reset. Off by default, since every read stalls the CPU.
+    pub fn set_read_back(&mut self, enabled: bool) {
+        self.read_back = enabled;
+    }
+
+    /// Sets the colors of the text written from now on.
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.foreground = foreground;
+        self.background = background;
+    }
+
+    /// The current text colors, as foreground and background.
+    pub fn color(&self) -> (Color, Color) {
+        (self.foreground, self.background)
+    }
+
+    /// Draws text in `font` from now on, redrawing the shown screen in it. Cursors stay on the same row
+    /// and column, or move up to the last row if there are fewer rows now.
+    pub fn set_font(&mut self, font: Font) {
+        let previous = self.font;
+        self.font = font;
+
+        let last_row = self.rows().saturating_sub(1);
+        let move_cursor = |(x_position, y_position): (usize, usize)| {
+            let column = (x_position - BORDER_PADDING) / previous.char_width();
+            let row = ((y_position - BORDER_PADDING) / previous.line_height()).min(last_row);
+
+            (
+                BORDER_PADDING + column * font.char_width(),
+                BORDER_PADDING + row * font.line_height(),
+            )
+        };
+        self.cursors = self.cursors.map(move_cursor);
+        (self.x_position, self.y_position) = move_cursor((self.x_position, self.y_position));
+
+        self.select_screen(self.shown);
+        self.view_offset = 0;
+        self.redraw();
+    }
+
+    pub fn font(&self) -> Font {
+        self.font
+    }
+
+    /// Number of characters that fit on a line in the current font.
+    pub fn columns(&self) -> usize {
+        (self.width() - BORDER_PADDING - 1) / self.font.char_width()
+    }
+
+    /// Number of lines that fit on the screen in the current font.
+    pub fn rows(&self) -> usize {
+        (self.height() - 2 * BORDER_PADDING) / self.font.line_height()
+    }
+
+    pub fn set_cursor_style(&mut self, style: CursorStyle) {
+        self.hide_cursor();
+        self.cursor_style = style;
+        self.show_cursor();
+    }
+
+    /// Turns the cursor on if it was off, and off if it was on.
+    pub fn blink_cursor(&mut self) {
+        self.cursor_on = !self.cursor_on;
+        if self.cursor_on {
+            self.show_cursor();
+        } else {
+            self.hide_cursor();
+        }
+    }
+
+    /// Draws the cursor at the shown screen's cursor position, if it is in view and on.
+    fn show_cursor(&mut self) {
+        if self.splash {
+            return;
+        }
+        let (x_position, y_position) = if self.is_drawing() {
+            (self.x_position, self.y_position)
+        } else {
+            self.cursors[self.shown]
+        };
+        let visible = self.cursor_on && self.cursor_style != CursorStyle::Hidden;
+
+        if visible && self.view_offset == 0 && self.cursor_drawn.is_none() {
+            self.fill_cursor(x_position, y_position, u8::MAX);
+            self.cursor_drawn = Some((x_position, y_position));
+        }
+    }
+
+    /// Erases the cursor, if it is drawn.
+    fn hide_cursor(&mut self) {
+        if let Some((x_position, y_position)) = self.cursor_drawn.take() {
+            self.fill_cursor(x_position, y_position, 0);
+        }
+    }
+
+    /// Fills the cursor's shape at a character cell, in the foreground color at full `intensity` and in
+    /// the background color at 0.
+    fn fill_cursor(&mut self, x_position: usize, y_position: usize, intensity: u8) {
+        let height = self.font.char_height();
+        let top = match self.cursor_style {
+            CursorStyle::Block => y_position,
+            CursorStyle::Underline => y_position + height - UNDERLINE_HEIGHT,
+            CursorStyle::Hidden => return,
+        };
+        let right = (x_position + self.font.char_width()).min(self.width());
+        let bottom = (y_position + height).min(self.height());
+
+        for y in top..bottom {
+            for x in x_position..right {
+                self.write_pixel(x, y, intensity);
+            }
+        }
+        self.mark_dirty(x_position, top, right, bottom);
+    }
+
+    /// Writes to `screen` from now on, drawing only if it is shown.
+    ///
+    /// # Panics
+    ///
+    /// If `screen` isn't less than `SCREENS`.
+    pub fn select_screen(&mut self, screen: usize) {
+        assert!(screen < SCREENS, "no screen {}", screen);
+
+        self.cursors[self.writing] = (self.x_position, self.y_position);
+        (self.x_position, self.y_position) = self.cursors[screen];
+        self.writing = screen;
+    }
+
+    /// Shows `screen` instead of the shown one, drawing its latest lines, and writes to it from now on.
+    pub fn show_screen(&mut self, screen: usize) {
+        self.select_screen(screen);
+        if screen != self.shown {
+            self.cursor_drawn = None;
+            self.shown = screen;
+            self.view_offset = 0;
+            self.redraw();
+        }
+    }
+
+    /// The screen that is drawn.
+    pub fn shown_screen(&self) -> usize {
+        self.shown
+    }
+
+    /// Whether text written now is drawn.
+    fn is_drawing(&self) -> bool {
+        self.writing == self.shown && !self.splash
+    }
+
+    /// Shows the lines `lines` further up in the scrollback of the shown screen, as far as it goes.
+    pub fn scroll_back(&mut self, lines: usize) {
+        self.select_screen(self.shown);
+        let kept = SCROLLBACKS.lock()[self.shown].len();
+        let top = kept.saturating_sub(self.rows_above_cursor() + 1);
+
+        self.scroll_to(self.view_offset.saturating_add(lines).min(top));
+    }
+
+    /// Shows the lines `lines` further down, up to the bottom where new text is written.
+    pub fn scroll_forward(&mut self, lines: usize) {
+        self.select_screen(self.shown);
+        self.scroll_to(self.view_offset.saturating_sub(lines));
+    }
+
+    fn scroll_to(&mut self, view_offset: usize) {
+        if view_offset != self.view_offset {
+            self.view_offset = view_offset;
+            self.redraw();
+        }
+    }
+
+    /// Draws the lines of the scrollback in view, with the line `view_offset` lines above the current one
+    /// on the cursor's row, in the current colors.
+    fn redraw(&mut self) {
+        let (x_position, y_position) = (self.x_position, self.y_position);
+        self.splash = false;
+        self.pixels().fill(0);
+        self.cursor_drawn = None;
+        self.pointer.forget();
+
+        let scrollbacks = SCROLLBACKS.lock();
+        for row in 0..=self.rows_above_cursor() {
+            let Some(text) = scrollbacks[self.shown].line(self.view_offset + row) else {
+                break;
+            };
+
+            self.x_position = BORDER_PADDING;
+            self.y_position = y_position - row * self.font.line_height();
+            for character in text.chars() {
+                if !self.fits(char_columns(character)) {
+                    break;
+                }
+                self.draw_char(character);
+            }
+        }
+        drop(scrollbacks);
+
+        (self.x_position, self.y_position) = (x_position, y_position);
+        self.mark_all_dirty();
+        self.show_cursor();
+    }
+
+    /// Number of rows of text above the one being written.
+    fn rows_above_cursor(&self) -> usize {
+        (self.y_position - BORDER_PADDING) / self.font.line_height()
+    }
+
     fn write_string(&mut self, str: &str) {
+        // Text is only written at the bottom.
+        if self.is_drawing() {
+            self.hide_pointer();
+            self.scroll_to(0);
+            self.hide_cursor();
+        }
+
         for character in str.chars() {
             self.write_char(character);
         }
+        self.show_cursor();
     }
 
     fn write_char(&mut self, character: char) {
         match character {
             '\n' => self.new_line(),
             '\r' => self.carriage_return(),
+            // Written as spaces, so the scrollback has no tabs. Tab stops don't carry over to the next line.
+            '\t' => {
+                let column = (self.x_position - BORDER_PADDING) / self.font.char_width();
+                for _ in column % TAB_WIDTH..TAB_WIDTH {
+                    if !self.fits(1) {
+                        break;
+                    }
+                    self.write_char(' ');
+                }
+            }
             character => {
-                let updated_x_position = self.x_position + font_constants::CHAR_RASTER_WIDTH;
+                let columns = char_columns(character);
+                if columns == 0 {
+                    return;
+                }
 
-                if updated_x_position >= self.width() {
+                // Wraps before the character rather than letting it run off the edge.
+                if !self.fits(columns) {
                     self.new_line();
                 }
 
-                let updated_y_position =
-                    self.y_position + font_constants::CHAR_RASTER_HEIGHT.val() + BORDER_PADDING;
-
-                while updated_y_position >= self.height() {
-                    self.shift_lines_up();
+                if self.is_drawing() {
+                    self.draw_char(character);
+                } else {
+                    self.x_position += columns * (self.font.char_width() + LETTER_SPACING);
                 }
-
-                self.write_rendered_char(get_char_raster(character));
+                SCROLLBACKS.lock()[self.writing].push(character);
             }
         }
     }
 
+    /// Whether a character `columns` columns wide fits on the line after the cursor.
+    fn fits(&self, columns: usize) -> bool {
+        self.x_position + columns * self.font.char_width() < self.width()
+    }
+
+    /// Draws `character`, which takes up at least one column, at the cursor and moves past it.
+    fn draw_char(&mut self, character: char) {
+        self.write_rendered_char(self.font.raster(character));
+        if char_columns(character) == 2 {
+            self.write_rendered_char(self.font.raster(' '));
+        }
+    }
+
     fn write_rendered_char(&mut self, rendered_char: RasterizedChar) {
+        #[cfg(feature = "framebuffer-stats")]
+        stats::count_glyph();
+
         for (y, row) in rendered_char.raster().iter().enumerate() {
             for (x, byte) in row.iter().enumerate() {
                 self.write_pixel(self.x_position + x, self.y_position + y, *byte);
             }
         }
+        self.mark_dirty(
+            self.x_position,
+            self.y_position,
+            self.x_position + rendered_char.width(),
+            self.y_position + rendered_char.height(),
+        );
 
         self.x_position += rendered_char.width() + LETTER_SPACING;
     }
@@ -100,49 +877,108 @@ impl Writer {
     fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
         let pixel_offset = y * self.info.stride + x;
 
-        let color = match self.info.pixel_format {
-            PixelFormat::Rgb => [intensity, intensity, intensity / 2, 0],
-            PixelFormat::Bgr => [intensity / 2, intensity, intensity, 0],
-            PixelFormat::U8 => [if intensity > 200 { 0xf } else { 0 }, 0, 0, 0],
-            other => {
-                self.info.pixel_format = PixelFormat::Rgb;
-                panic!("pixel format {:?} not supported", other);
-            }
+        let color = self.foreground.blend(self.background, intensity);
+        let Some(color) = color.to_pixel(self.info.pixel_format) else {
+            let other = self.info.pixel_format;
+            self.info.pixel_format = PixelFormat::Rgb;
+            panic!("pixel format {:?} not supported", other);
         };
 
         let bytes_per_pixel = self.info.bytes_per_pixel;
         let byte_offset = pixel_offset * bytes_per_pixel;
 
-        unsafe {
-            core::arch::asm!("mov r8, r9", in("r9") byte_offset);
-        }
-
-        self.buffer.buffer_mut()[byte_offset..(byte_offset + bytes_per_pixel)]
+        self.pixels()[byte_offset..(byte_offset + bytes_per_pixel)]
             .copy_from_slice(&color[..bytes_per_pixel]);
 
-        // let _ = unsafe { ptr::read_volatile(&self.buffer.buffer_mut()[byte_offset]) };
+        #[cfg(feature = "framebuffer-stats")]
+        stats::count_pixel();
     }
 
     fn new_line(&mut self) {
-        self.y_position += font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
+        self.y_position += self.font.line_height();
+        if self.y_position + self.font.line_height() + BORDER_PADDING > self.height() {
+            self.shift_lines_up();
+        }
+
         self.carriage_return();
+        SCROLLBACKS.lock()[self.writing].new_line();
     }
 
     fn carriage_return(&mut self) {
         self.x_position = BORDER_PADDING;
     }
 
+    /// Covers the shown screen with `picture`, centered on black, until `end_splash` or a redraw.
+    pub fn show_splash(&mut self, picture: &Picture) {
+        self.select_screen(self.shown);
+        self.hide_cursor();
+        self.pixels().fill(0);
+        self.mark_all_dirty();
+        self.pointer.forget();
+        self.splash = true;
+
+        let x = self.width().saturating_sub(picture.width) / 2;
+        let y = self.height().saturating_sub(picture.height) / 2;
+        let mut surface = self.surface();
+        let mut row = [Color::BLACK; 256];
+        for line in 0..picture.height {
+            let mut column = 0;
+            while column < picture.width {
+                let len = picture.read_row(column, line, &mut row);
+                let image = gfx::Image {
+                    width: len,
+                    height: 1,
+                    pixels: &row[..len],
+                };
+                surface.blit(&image, x + column, y + line);
+                column += len;
+            }
+        }
+    }
+
+    /// Replaces the splash picture, if it is shown, with the text written meanwhile.
+    pub fn end_splash(&mut self) {
+        if self.splash {
+            self.select_screen(self.shown);
+            self.redraw();
+        }
+    }
+
+    /// Clears the screen being written to.
     pub fn clear(&mut self) {
         self.x_position = BORDER_PADDING;
         self.y_position = BORDER_PADDING;
-        self.buffer.buffer_mut().fill(0);
+        if self.is_drawing() {
+            self.view_offset = 0;
+            self.pixels().fill(0);
+            self.mark_all_dirty();
+            self.pointer.forget();
+            self.cursor_drawn = None;
+            self.show_cursor();
+        }
+
+        // What was on the screen is now above it, in the scrollback.
+        let scrollback = &mut SCROLLBACKS.lock()[self.writing];
+        if scrollback.line(0).is_some_and(|line| !line.is_empty()) {
+            scrollback.new_line();
+        }
     }
 
+    /// Scrolls the screen up by one line, moving the cursor with it, and clears the rows freed below it.
     fn shift_lines_up(&mut self) {
-        let offset = self.info.stride * self.info.bytes_per_pixel * 8;
+        let row_len = self.info.stride * self.info.bytes_per_pixel;
+        let offset = row_len * self.font.line_height();
+        self.y_position -= self.font.line_height();
+        if !self.is_drawing() {
+            return;
+        }
+
+        let cursor = self.y_position * row_len;
+        let pixels = self.pixels();
+        pixels.copy_within(offset.., 0);
+        pixels[cursor..].fill(0);
 
-        self.buffer.buffer_mut().copy_within(offset.., 0);
-        self.y_position += 8;
+        self.mark_all_dirty();
     }
 
     fn width(&self) -> usize {
@@ -172,13 +1008,47 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
-#[doc(hidden)]
-pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
+/// Prints in the given foreground color, e.g. `print_colored!(Color::RED, "failed: {}", error)`. The
+/// colors set with `set_color` apply again afterwards.
+#[macro_export]
+macro_rules! print_colored {
+    ($color:expr, $($arg:tt)*) => ($crate::framebuffer::_print_colored($color, format_args!($($arg)*)));
+}
+
+/// Shows `lines` lines further up in the scrollback, see `Writer::scroll_back`.
+pub fn scroll_back(lines: usize) {
+    with_writer(|writer| {
+        writer.scroll_back(lines);
+        writer.flush();
+    });
+}
+
+/// Shows `lines` lines further down, see `Writer::scroll_forward`.
+pub fn scroll_forward(lines: usize) {
+    with_writer(|writer| {
+        writer.scroll_forward(lines);
+        writer.flush();
+    });
+}
+
+/// Sets the colors of the text printed from now on.
+pub fn set_color(foreground: Color, background: Color) {
+    with_writer(|writer| writer.set_color(foreground, background));
+}
+
+fn with_writer(f: impl FnOnce(&mut Writer)) {
     use x86_64::instructions::interrupts;
 
     let f = || {
-        WRITER.lock().as_mut().unwrap().write_fmt(args).unwrap();
+        #[cfg(feature = "framebuffer-stats")]
+        let _measurement = stats::Measurement::start();
+
+        let mut writer = WRITER.lock();
+        let writer = writer.as_mut().unwrap();
+        writer.hide_pointer();
+        f(writer);
+        // Draws the pointer again, even if `f` didn't draw anything.
+        writer.flush();
     };
 
     if !userspace::is_user_ring() {
@@ -188,6 +1058,134 @@ pub fn _print(args: fmt::Arguments) {
     }
 }
 
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    crate::console::_print(crate::console::Console::Log, args);
+}
+
+/// Writes to `screen`, which is only drawn if it is shown. See `Writer::select_screen`.
+pub fn print_to(screen: usize, args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    with_writer(|writer| {
+        writer.select_screen(screen);
+        writer.write_fmt(args).unwrap();
+        writer.flush();
+    });
+}
+
+/// The screen that is drawn.
+pub fn shown_screen() -> usize {
+    let mut shown = 0;
+    with_writer(|writer| shown = writer.shown_screen());
+    shown
+}
+
+/// Draws text in the given size and weight from now on, see `Writer::set_font`.
+pub fn set_font(height: RasterHeight, weight: FontWeight) {
+    with_writer(|writer| {
+        writer.set_font(Font { height, weight });
+        writer.flush();
+    });
+}
+
+pub fn set_cursor_style(style: CursorStyle) {
+    with_writer(|writer| {
+        writer.set_cursor_style(style);
+        writer.flush();
+    });
+}
+
+/// Executor task that blinks the cursor every `CURSOR_BLINK_PERIOD`.
+pub async fn blink_cursor() {
+    let mut interval = timer::interval(CURSOR_BLINK_PERIOD);
+
+    loop {
+        interval.tick().await;
+        with_writer(|writer| {
+            writer.blink_cursor();
+            writer.flush();
+        });
+    }
+}
+
+/// Shows the BMP or PPM picture in `data` on the shown screen until `end_splash`, see
+/// `Writer::show_splash`.
+pub fn show_splash(data: &[u8]) -> Result<(), picture::PictureError> {
+    let picture = Picture::parse(data)?;
+    with_writer(|writer| writer.show_splash(&picture));
+    Ok(())
+}
+
+/// Draws the text written since `show_splash` instead of the picture.
+pub fn end_splash() {
+    with_writer(|writer| writer.end_splash());
+}
+
+/// Shows `screen`, see `Writer::show_screen`.
+pub fn show_screen(screen: usize) {
+    with_writer(|writer| {
+        writer.show_screen(screen);
+        writer.flush();
+    });
+}
+
+#[doc(hidden)]
+pub fn _print_colored(foreground: Color, args: fmt::Arguments) {
+    crate::console::_print_colored(crate::console::Console::Log, foreground, args);
+}
+
+/// Writes to `screen` in the given foreground color, see `print_colored!`.
+pub fn print_colored_to(screen: usize, foreground: Color, args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    // Under one lock, so other output can't come out in this color.
+    with_writer(|writer| {
+        writer.select_screen(screen);
+        let (previous, background) = writer.color();
+        writer.set_color(foreground, background);
+        writer.write_fmt(args).unwrap();
+        writer.set_color(previous, background);
+        writer.flush();
+    });
+}
+
+#[test_case]
+fn test_colors_blend_into_the_background() {
+    assert_eq!(Color::WHITE.blend(Color::BLACK, 255), Color::WHITE);
+    assert_eq!(Color::WHITE.blend(Color::BLACK, 0), Color::BLACK);
+    assert_eq!(
+        Color::rgb(200, 100, 0).blend(Color::rgb(0, 100, 200), 128),
+        Color::rgb(100, 100, 99)
+    );
+}
+
+#[test_case]
+fn test_colors_follow_the_pixel_format() {
+    let color = Color::rgb(1, 2, 3);
+
+    assert_eq!(color.to_pixel(PixelFormat::Rgb), Some([1, 2, 3, 0]));
+    assert_eq!(color.to_pixel(PixelFormat::Bgr), Some([3, 2, 1, 0]));
+    assert_eq!(Color::WHITE.to_pixel(PixelFormat::U8), Some([0xf, 0, 0, 0]));
+    assert_eq!(Color::BLACK.to_pixel(PixelFormat::U8), Some([0, 0, 0, 0]));
+}
+
+#[test_case]
+fn test_char_columns() {
+    assert_eq!(char_columns('a'), 1);
+    assert_eq!(char_columns('�'), 1);
+    assert_eq!(char_columns('\x1b'), 0);
+    assert_eq!(char_columns('\u{301}'), 0);
+    assert_eq!(char_columns('\u{200b}'), 0);
+    assert_eq!(char_columns('中'), 2);
+    assert_eq!(char_columns('😀'), 2);
+}
+
+#[test_case]
+fn test_println_with_tabs_and_wide_characters() {
+    println!("a\tb\t\tc\u{301} 中文 \u{feff}end");
+}
+
 #[test_case]
 fn test_println_simple() {
     println!("test_println_simple output");
@@ -199,3 +1197,16 @@ fn test_println_many() {
         println!("test_println_many output");
     }
 }
+
+#[test_case]
+fn test_dirty_areas_dont_overflow() {
+    assert_eq!(
+        Dirty::from_rect(gfx::Rect::new(usize::MAX - 1, 0, 2, 1)),
+        None
+    );
+
+    let area = Dirty::from_rect(gfx::Rect::new(1, 2, 3, 4)).unwrap();
+    let moved = area.offset(usize::MAX, 0);
+    assert_eq!((moved.left, moved.right), (usize::MAX, usize::MAX));
+    assert_eq!((moved.top, moved.bottom), (2, 6));
+}
@@ -0,0 +1,98 @@
+//! Reading back what is on the shown screen, so tests can check what was drawn.
+//!
+//! `text_line` gives the text of a row as kept in the scrollback the screen is drawn from, and `crc32` a
+//! checksum of its pixels without the cursor. `dump_text_region` and `dump_crc32` write them to the
+//! serial port instead, where a script on the host can compare them with what it expects.
+
+use core::ops::Range;
+
+use super::{SCROLLBACKS, scrollback::LINE_LEN, with_writer};
+use crate::serial_println;
+
+/// Calls `f` with the text of the row `back` rows above the cursor on the shown screen, if there is one.
+/// `f` is called with the writer unlocked, so it may print.
+pub fn text_line<R>(back: usize, f: impl FnOnce(&str) -> R) -> Option<R> {
+    let mut text = [0; LINE_LEN];
+    let mut len = None;
+    with_writer(|writer| {
+        writer.select_screen(writer.shown);
+        if back <= writer.rows_above_cursor()
+            && let Some(line) = SCROLLBACKS.lock()[writer.shown].line(writer.view_offset + back)
+        {
+            text[..line.len()].copy_from_slice(line.as_bytes());
+            len = Some(line.len());
+        }
+    });
+    len.map(|len| f(core::str::from_utf8(&text[..len]).unwrap_or_default()))
+}
+
+/// Writes the text of `rows`, counted from the top of the shown screen, to the serial port, one line
+/// each, between a header and a footer.
+pub fn dump_text_region(rows: Range<usize>) {
+    with_writer(|writer| {
+        writer.select_screen(writer.shown);
+        let above = writer.rows_above_cursor();
+        let rows = rows.start..rows.end.min(writer.rows());
+
+        serial_println!("--- screen {} rows {:?} ---", writer.shown, rows);
+        let scrollbacks = SCROLLBACKS.lock();
+        for row in rows {
+            let text = above
+                .checked_sub(row)
+                .and_then(|back| scrollbacks[writer.shown].line(writer.view_offset + back))
+                .unwrap_or_default();
+            serial_println!("{:3}|{}", row, text);
+        }
+        serial_println!("--- end ---");
+    });
+}
+
+/// The CRC-32 of the pixels of the shown screen, row by row, in the framebuffer's pixel format.
+pub fn crc32() -> u32 {
+    let mut crc = !0;
+    with_writer(|writer| {
+        let cursor = writer.cursor_drawn;
+        writer.hide_cursor();
+
+        let info = writer.info;
+        let row_len = info.width * info.bytes_per_pixel;
+        let pixels = writer.pixels();
+        for y in 0..info.height {
+            let start = y * info.stride * info.bytes_per_pixel;
+            crc = crc32_update(crc, &pixels[start..start + row_len]);
+        }
+
+        if cursor.is_some() {
+            writer.show_cursor();
+        }
+    });
+    !crc
+}
+
+/// Writes `crc32` to the serial port.
+pub fn dump_crc32() {
+    serial_println!("framebuffer crc32: {:08x}", crc32());
+}
+
+/// Adds `data` to a CRC-32 (the one of zlib and Ethernet) computed so far, starting from `!0`. The
+/// result is inverted once all data was added.
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[test_case]
+fn test_crc32_check_value() {
+    assert_eq!(!crc32_update(!0, b"123456789"), 0xcbf4_3926);
+    assert_eq!(
+        !crc32_update(crc32_update(!0, b"1234"), b"56789"),
+        0xcbf4_3926
+    );
+}
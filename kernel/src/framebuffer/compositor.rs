@@ -0,0 +1,417 @@
+//! Windows: off-screen surfaces stacked over the console and composed onto the framebuffer.
+//!
+//! The console is the bottom layer, in the back buffer the writer draws into. Each `Window` has pixels of
+//! its own, mapped like the back buffer and in the framebuffer's pixel format, behind a lock of its own:
+//! drawing into a window never waits for the writer or for other windows. `run_compositor` copies what
+//! changed in windows to the framebuffer every `FRAME_PERIOD`, and every flush of the writer puts back
+//! the windows over what it copied, lowest `z` first.
+//!
+//! The compositor only reads a window it can lock right away. One that is being drawn into is composed
+//! again in the next frame.
+
+use bootloader_api::info::FrameBufferInfo;
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::{
+    VirtAddr,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame,
+        Size4KiB, mapper::MapToError,
+    },
+};
+
+use super::{
+    Dirty, INIT,
+    gfx::{Rect, Surface},
+    with_writer,
+};
+use crate::{
+    memory::{self, AddressSpace, GlobalFrameAllocator},
+    task::timer,
+};
+
+/// Windows that can be open at once.
+pub const MAX_WINDOWS: usize = 8;
+/// How often `run_compositor` composes the windows that changed.
+pub const FRAME_PERIOD: Duration = Duration::from_millis(16);
+
+/// Where the pixels of the first window are mapped, after the back buffer.
+const WINDOWS_START: u64 = 0x_4444_c000_0000;
+/// Virtual memory set aside for each window's pixels, enough for a 4K screen.
+const WINDOW_SLOT_SIZE: u64 = 64 * 1024 * 1024;
+
+static WINDOWS: [Mutex<Option<Layer>>; MAX_WINDOWS] = [const { Mutex::new(None) }; MAX_WINDOWS];
+/// Parts of the screen to compose in the next frame, besides what changed inside windows.
+static DAMAGE: Mutex<Option<Dirty>> = Mutex::new(None);
+
+#[derive(Debug)]
+pub enum WindowError {
+    /// There is no framebuffer, or no back buffer to compose the console from.
+    NoBackBuffer,
+    TooManyWindows,
+    /// The window has no pixels, more than fit in its slot, or more than a `usize` can count.
+    InvalidSize,
+    Map(MapToError<Size4KiB>),
+}
+
+struct Layer {
+    /// Where the window is on the screen, which it may extend past.
+    rect: Rect,
+    z: i32,
+    pixels: &'static mut [u8],
+    info: FrameBufferInfo,
+    /// What changed since the last frame, in the window's coordinates.
+    dirty: Option<Dirty>,
+}
+
+/// An open window. It is closed when dropped.
+#[derive(Debug)]
+pub struct Window {
+    slot: usize,
+}
+
+impl Window {
+    /// Opens a window at `rect` on the screen, above the windows with a lower `z`. It starts out black.
+    pub fn open(rect: Rect, z: i32) -> Result<Window, WindowError> {
+        if !INIT.is_initialized() {
+            return Err(WindowError::NoBackBuffer);
+        }
+        let mut screen = None;
+        with_writer(|writer| {
+            if writer.back_buffer.is_some() {
+                screen = Some(writer.info);
+            }
+        });
+        let screen = screen.ok_or(WindowError::NoBackBuffer)?;
+
+        // The window must also end on coordinates that can be added up.
+        let len = rect
+            .width
+            .checked_mul(rect.height)
+            .and_then(|pixels| pixels.checked_mul(screen.bytes_per_pixel))
+            .filter(|_| {
+                rect.x.checked_add(rect.width).is_some()
+                    && rect.y.checked_add(rect.height).is_some()
+            })
+            .ok_or(WindowError::InvalidSize)?;
+        if len == 0 || len as u64 > WINDOW_SLOT_SIZE {
+            return Err(WindowError::InvalidSize);
+        }
+
+        for (slot, layer) in WINDOWS.iter().enumerate() {
+            let mut layer = layer.lock();
+            if layer.is_some() {
+                continue;
+            }
+
+            let start = VirtAddr::new(WINDOWS_START + slot as u64 * WINDOW_SLOT_SIZE);
+            x86_64::instructions::interrupts::without_interrupts(|| map_pixels(start, len))
+                .map_err(WindowError::Map)?;
+            let pixels = unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr::<u8>(), len) };
+            pixels.fill(0);
+
+            *layer = Some(Layer {
+                rect,
+                z,
+                pixels,
+                info: FrameBufferInfo {
+                    byte_len: len,
+                    width: rect.width,
+                    height: rect.height,
+                    stride: rect.width,
+                    ..screen
+                },
+                dirty: None,
+            });
+            damage(rect);
+            return Ok(Window { slot });
+        }
+
+        Err(WindowError::TooManyWindows)
+    }
+
+    /// Calls `f` with the window's pixels, which are composed onto the screen in the next frame.
+    pub fn draw<R>(&self, f: impl FnOnce(&mut Surface) -> R) -> R {
+        let mut layer = WINDOWS[self.slot].lock();
+        let layer = layer.as_mut().expect("window closed");
+        f(&mut Surface::tracking(
+            layer.pixels,
+            layer.info,
+            &mut layer.dirty,
+        ))
+    }
+
+    /// Moves the top left corner of the window to `x`, `y`.
+    pub fn move_to(&self, x: usize, y: usize) {
+        self.update(|layer| {
+            layer.rect.x = x;
+            layer.rect.y = y;
+        });
+    }
+
+    /// Puts the window above the windows with a lower `z`, and below the ones with a higher one.
+    pub fn set_z(&self, z: i32) {
+        self.update(|layer| layer.z = z);
+    }
+
+    pub fn rect(&self) -> Rect {
+        WINDOWS[self.slot]
+            .lock()
+            .as_ref()
+            .expect("window closed")
+            .rect
+    }
+
+    /// The size and pixel format of the window's pixels, whose rows are `stride` pixels apart.
+    pub fn info(&self) -> FrameBufferInfo {
+        WINDOWS[self.slot]
+            .lock()
+            .as_ref()
+            .expect("window closed")
+            .info
+    }
+
+    /// The frames holding the window's pixels, in order, for sharing them with a process. They are freed
+    /// when the window is closed.
+    pub fn frames(&self) -> impl Iterator<Item = PhysFrame> {
+        let start = VirtAddr::new(WINDOWS_START + self.slot as u64 * WINDOW_SLOT_SIZE);
+        let len = self.info().byte_len;
+        let mapper = kernel_mapper();
+
+        pages(start, len).map(move |page| {
+            mapper
+                .translate_page(page)
+                .expect("window pixels aren't mapped")
+        })
+    }
+
+    /// Copies `rects` of the window, in its own coordinates, to the screen right away, over the console
+    /// and under the windows above it. For pixels written behind `draw`'s back, e.g. by a process the
+    /// window is shared with, which the compositor doesn't see change.
+    pub fn flush(&self, rects: &[Rect]) {
+        let window = self.rect();
+
+        with_writer(|writer| {
+            for &rect in rects {
+                if let Some(area) = Dirty::from_rect(clip(rect, window.width, window.height)) {
+                    writer.present(area.offset(window.x, window.y));
+                }
+            }
+        });
+    }
+
+    /// Changes the window, composing where it was and where it is in the next frame.
+    fn update(&self, f: impl FnOnce(&mut Layer)) {
+        let mut layer = WINDOWS[self.slot].lock();
+        let layer = layer.as_mut().expect("window closed");
+        damage(layer.rect);
+        f(layer);
+        damage(layer.rect);
+    }
+}
+
+impl Drop for Window {
+    fn drop(&mut self) {
+        let Some(layer) = WINDOWS[self.slot].lock().take() else {
+            return;
+        };
+        damage(layer.rect);
+
+        let start = VirtAddr::from_ptr(layer.pixels.as_ptr());
+        let len = layer.pixels.len();
+        x86_64::instructions::interrupts::without_interrupts(|| unmap_pixels(start, len));
+    }
+}
+
+/// The kernel's page tables, which every address space shares the windows' level 4 entry with.
+pub(super) fn kernel_mapper() -> OffsetPageTable<'static> {
+    let level_4_frame = AddressSpace::kernel().level_4_frame();
+    let level_4_table = memory::phys_to_virt(level_4_frame.start_address()).as_mut_ptr();
+
+    unsafe { OffsetPageTable::new(&mut *level_4_table, memory::physical_memory_offset()) }
+}
+
+fn pages(start: VirtAddr, len: usize) -> impl Iterator<Item = Page<Size4KiB>> {
+    Page::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(start + (len as u64 - 1)),
+    )
+}
+
+pub(super) fn map_pixels(start: VirtAddr, len: usize) -> Result<(), MapToError<Size4KiB>> {
+    let mut mapper = kernel_mapper();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    for (mapped, page) in pages(start, len).enumerate() {
+        let result = GlobalFrameAllocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)
+            .and_then(|frame| unsafe {
+                match mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) {
+                    Ok(flush) => {
+                        flush.flush();
+                        Ok(())
+                    }
+                    Err(error) => {
+                        GlobalFrameAllocator.deallocate_frame(frame);
+                        Err(error)
+                    }
+                }
+            });
+
+        if let Err(error) = result {
+            if mapped > 0 {
+                unmap_pixels(start, mapped * Page::<Size4KiB>::SIZE as usize);
+            }
+            return Err(error);
+        }
+    }
+
+    Ok(())
+}
+
+pub(super) fn unmap_pixels(start: VirtAddr, len: usize) {
+    let mut mapper = kernel_mapper();
+
+    for page in pages(start, len) {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+/// The part of `rect` inside a `width` by `height` window.
+fn clip(rect: Rect, width: usize, height: usize) -> Rect {
+    let x = rect.x.min(width);
+    let y = rect.y.min(height);
+    Rect::new(x, y, rect.width.min(width - x), rect.height.min(height - y))
+}
+
+/// Composes `rect` of the screen in the next frame.
+fn damage(rect: Rect) {
+    if let Some(area) = Dirty::from_rect(rect) {
+        damage_area(area);
+    }
+}
+
+/// Copies the windows over `area` of `front`, a framebuffer described by `info`, lowest `z` first.
+pub(super) fn overlay(front: &mut [u8], info: &FrameBufferInfo, area: Dirty) {
+    let mut order = [(0, 0); MAX_WINDOWS];
+    let mut count = 0;
+    for (slot, layer) in WINDOWS.iter().enumerate() {
+        match layer.try_lock() {
+            Some(layer) => {
+                if let Some(layer) = layer.as_ref() {
+                    order[count] = (layer.z, slot);
+                    count += 1;
+                }
+            }
+            // Being drawn into, or moved.
+            None => damage_area(area),
+        }
+    }
+    order[..count].sort_unstable();
+
+    for &(_, slot) in &order[..count] {
+        match WINDOWS[slot].try_lock() {
+            Some(layer) => {
+                if let Some(layer) = layer.as_ref() {
+                    copy_rect(front, info, layer.pixels, layer.rect, area);
+                }
+            }
+            None => damage_area(area),
+        }
+    }
+}
+
+fn damage_area(area: Dirty) {
+    let mut damage = DAMAGE.lock();
+    *damage = Some(damage.map_or(area, |damage| damage.union(area)));
+}
+
+/// Copies the part of `window`, the pixels of a window at `rect`, that is inside `area` to `front`.
+fn copy_rect(front: &mut [u8], info: &FrameBufferInfo, window: &[u8], rect: Rect, area: Dirty) {
+    let bytes_per_pixel = info.bytes_per_pixel;
+    let left = area.left.max(rect.x);
+    let top = area.top.max(rect.y);
+    let right = area.right.min(rect.x + rect.width).min(info.width);
+    let bottom = area.bottom.min(rect.y + rect.height).min(info.height);
+    if left >= right || top >= bottom {
+        return;
+    }
+
+    let len = (right - left) * bytes_per_pixel;
+    for y in top..bottom {
+        let from = ((y - rect.y) * rect.width + left - rect.x) * bytes_per_pixel;
+        let to = (y * info.stride + left) * bytes_per_pixel;
+        front[to..to + len].copy_from_slice(&window[from..from + len]);
+    }
+}
+
+/// Copies what changed since the last frame to the framebuffer: the insides of windows that were drawn
+/// into, and the parts of the screen windows were opened, moved or closed over.
+pub fn compose() {
+    let mut area = DAMAGE.lock().take();
+    for layer in &WINDOWS {
+        let Some(mut layer) = layer.try_lock() else {
+            continue;
+        };
+        let Some(layer) = layer.as_mut() else {
+            continue;
+        };
+        if let Some(dirty) = layer.dirty.take() {
+            let dirty = dirty.offset(layer.rect.x, layer.rect.y);
+            area = Some(area.map_or(dirty, |area| area.union(dirty)));
+        }
+    }
+
+    if let Some(area) = area {
+        with_writer(|writer| writer.present(area));
+    }
+}
+
+/// Composes the windows every `FRAME_PERIOD`.
+pub async fn run_compositor() {
+    let mut interval = timer::interval(FRAME_PERIOD);
+
+    loop {
+        interval.tick().await;
+        if INIT.is_initialized() {
+            compose();
+        }
+    }
+}
+
+#[test_case]
+fn test_copy_rect_is_clipped_to_the_window_and_area() {
+    const INFO: FrameBufferInfo = FrameBufferInfo {
+        byte_len: 6 * 4,
+        width: 6,
+        height: 4,
+        pixel_format: bootloader_api::info::PixelFormat::U8,
+        bytes_per_pixel: 1,
+        stride: 6,
+    };
+    let mut front = [0; 6 * 4];
+    let window = [1, 2, 3, 4, 5, 6];
+    let area = Dirty {
+        left: 0,
+        top: 0,
+        right: 5,
+        bottom: 4,
+    };
+
+    // A 3x2 window whose right column is outside `area`, and its bottom row off the screen.
+    copy_rect(&mut front, &INFO, &window, Rect::new(3, 3, 3, 2), area);
+
+    assert_eq!(front[3 * 6..], [0, 0, 0, 1, 2, 0]);
+    assert!(front[..3 * 6].iter().all(|&pixel| pixel == 0));
+}
+
+#[test_case]
+fn test_flushed_rects_are_clipped_to_the_window() {
+    assert_eq!(clip(Rect::new(2, 1, 10, 2), 6, 4), Rect::new(2, 1, 4, 2));
+    assert_eq!(clip(Rect::new(8, 5, 3, 3), 6, 4), Rect::new(6, 4, 0, 0));
+    assert_eq!(clip(Rect::new(0, 0, 6, 4), 6, 4), Rect::new(0, 0, 6, 4));
+}
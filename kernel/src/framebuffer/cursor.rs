@@ -0,0 +1,149 @@
+//! The mouse pointer, a sprite drawn over everything else on the screen.
+//!
+//! The pixels under the sprite are saved when it is drawn and put back before anything else draws, so
+//! text and graphics never have to know about it. The writer hides it whenever it is used and draws it
+//! again on `flush`, so only the rectangles it left and entered are copied to the framebuffer.
+//!
+//! There is no mouse driver yet: the pointer starts hidden, and whatever reads the mouse is meant to
+//! call `set_visible` and `move_to`.
+
+use super::{
+    Color,
+    gfx::{Bitmap, Rect, Surface},
+    with_writer,
+};
+
+pub const WIDTH: usize = 12;
+pub const HEIGHT: usize = 19;
+
+/// The arrow, with its tip at the top left. `#` is the outline, `.` the fill.
+const SPRITE: [&str; HEIGHT] = [
+    "#           ",
+    "##          ",
+    "#.#         ",
+    "#..#        ",
+    "#...#       ",
+    "#....#      ",
+    "#.....#     ",
+    "#......#    ",
+    "#.......#   ",
+    "#........#  ",
+    "#.........# ",
+    "#......#####",
+    "#...#..#    ",
+    "#..# #..#   ",
+    "#.#  #..#   ",
+    "##    #..#  ",
+    "#     #..#  ",
+    "       #..# ",
+    "       ###  ",
+];
+const ROW_LEN: usize = WIDTH.div_ceil(8);
+
+const OUTLINE: [u8; ROW_LEN * HEIGHT] = sprite_bits(b'#');
+const FILL: [u8; ROW_LEN * HEIGHT] = sprite_bits(b'.');
+
+/// The pixels of `SPRITE` marked with `mark`, as the bits of a `Bitmap`.
+const fn sprite_bits(mark: u8) -> [u8; ROW_LEN * HEIGHT] {
+    let mut bits = [0; ROW_LEN * HEIGHT];
+    let mut y = 0;
+
+    while y < HEIGHT {
+        let row = SPRITE[y].as_bytes();
+        let mut x = 0;
+        while x < WIDTH {
+            if row[x] == mark {
+                bits[y * ROW_LEN + x / 8] |= 0x80 >> (x % 8);
+            }
+            x += 1;
+        }
+        y += 1;
+    }
+
+    bits
+}
+
+pub(super) struct Pointer {
+    x: usize,
+    y: usize,
+    visible: bool,
+    /// Where the sprite is drawn, clipped to the screen, if it is.
+    drawn: Option<Rect>,
+    /// The pixels under the sprite, at up to 4 bytes each.
+    saved: [u8; WIDTH * HEIGHT * 4],
+}
+
+impl Pointer {
+    pub(super) const fn new() -> Self {
+        Pointer {
+            x: 0,
+            y: 0,
+            visible: false,
+            drawn: None,
+            saved: [0; WIDTH * HEIGHT * 4],
+        }
+    }
+
+    /// Draws the sprite if it should be seen and isn't drawn yet.
+    pub(super) fn show(&mut self, surface: &mut Surface) {
+        if !self.visible || self.drawn.is_some() {
+            return;
+        }
+        let Some(area) = surface.clip(Rect::new(self.x, self.y, WIDTH, HEIGHT)) else {
+            return;
+        };
+
+        surface.read_pixels(area, &mut self.saved);
+        for (bits, color) in [(&OUTLINE, Color::BLACK), (&FILL, Color::WHITE)] {
+            let bitmap = Bitmap {
+                width: WIDTH,
+                height: HEIGHT,
+                bits,
+            };
+            surface.draw_bitmap(&bitmap, self.x, self.y, color);
+        }
+        self.drawn = Some(area);
+    }
+
+    /// Puts back the pixels under the sprite, if it is drawn.
+    pub(super) fn hide(&mut self, surface: &mut Surface) {
+        if let Some(area) = self.drawn.take() {
+            surface.write_pixels(area, &self.saved);
+        }
+    }
+
+    /// Forgets the sprite without putting anything back, for when the screen under it was redrawn.
+    pub(super) fn forget(&mut self) {
+        self.drawn = None;
+    }
+}
+
+/// Moves the tip of the pointer to `x`, `y`. It may go off the right and bottom edges.
+pub fn move_to(x: usize, y: usize) {
+    with_writer(|writer| {
+        writer.pointer.x = x;
+        writer.pointer.y = y;
+    });
+}
+
+/// Shows or hides the pointer. It is hidden at boot.
+pub fn set_visible(visible: bool) {
+    with_writer(|writer| writer.pointer.visible = visible);
+}
+
+/// Where the tip of the pointer is.
+pub fn position() -> (usize, usize) {
+    let mut position = (0, 0);
+    with_writer(|writer| position = (writer.pointer.x, writer.pointer.y));
+    position
+}
+
+#[test_case]
+fn test_the_sprite_is_outlined() {
+    for (outline, fill) in OUTLINE.iter().zip(FILL) {
+        assert_eq!(outline & fill, 0);
+    }
+    // The tip and the left edge are outline.
+    assert_eq!(OUTLINE[0], 0x80);
+    assert!((0..HEIGHT - 2).all(|y| OUTLINE[y * ROW_LEN] & 0x80 != 0));
+}
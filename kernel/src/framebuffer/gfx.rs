@@ -0,0 +1,361 @@
+//! Drawing shapes and images, rather than text.
+//!
+//! Everything draws on a `Surface`, a rectangle of pixels in some `PixelFormat` that clips what is drawn
+//! to its bounds and converts colors to its format. `draw` lends out the shown screen as one, which
+//! draws into the back buffer when there is one, and flushes once afterwards. Drawings on the screen
+//! last until the text scrolls over them or the screen is redrawn, e.g. when switching consoles.
+
+use bootloader_api::info::FrameBufferInfo;
+#[cfg(test)]
+use bootloader_api::info::PixelFormat;
+
+use super::{Color, Dirty, with_writer};
+
+/// A rectangle of pixels, from its top left corner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub const fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
+/// Pixels of a picture, row by row.
+#[derive(Debug, Clone, Copy)]
+pub struct Image<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: &'a [Color],
+}
+
+/// A one-color picture with one bit per pixel, row by row. Every row starts at a new byte, and the most
+/// significant bit of a byte comes first. Clear bits are transparent.
+#[derive(Debug, Clone, Copy)]
+pub struct Bitmap<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub bits: &'a [u8],
+}
+
+impl Bitmap<'_> {
+    fn is_set(&self, x: usize, y: usize) -> bool {
+        let row_len = self.width.div_ceil(8);
+        self.bits
+            .get(y * row_len + x / 8)
+            .is_some_and(|byte| byte & (0x80 >> (x % 8)) != 0)
+    }
+}
+
+/// Pixels to draw on, laid out as `info` describes.
+pub struct Surface<'a> {
+    pixels: &'a mut [u8],
+    info: FrameBufferInfo,
+    /// Where the changes are recorded, for surfaces that are copied elsewhere afterwards.
+    dirty: Option<&'a mut Option<Dirty>>,
+}
+
+impl<'a> Surface<'a> {
+    /// A surface over `pixels`, which must hold `info.height` rows of `info.stride` pixels.
+    pub fn new(pixels: &'a mut [u8], info: FrameBufferInfo) -> Self {
+        assert!(
+            pixels.len() >= info.height * info.stride * info.bytes_per_pixel,
+            "surface smaller than its info says"
+        );
+
+        Surface {
+            pixels,
+            info,
+            dirty: None,
+        }
+    }
+
+    pub(super) fn tracking(
+        pixels: &'a mut [u8],
+        info: FrameBufferInfo,
+        dirty: &'a mut Option<Dirty>,
+    ) -> Self {
+        Surface {
+            dirty: Some(dirty),
+            ..Surface::new(pixels, info)
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.info.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.info.height
+    }
+
+    /// Fills `rect` with `color`.
+    pub fn fill_rect(&mut self, rect: Rect, color: Color) {
+        let Some(rect) = self.clip(rect) else {
+            return;
+        };
+        let Some(pixel) = self.pixel(color) else {
+            return;
+        };
+
+        for y in rect.y..rect.y + rect.height {
+            for x in rect.x..rect.x + rect.width {
+                self.put(x, y, &pixel);
+            }
+        }
+        self.mark_dirty(rect);
+    }
+
+    /// Draws a one pixel wide line from `from` to `to`, both included. Either end may be off the surface.
+    pub fn draw_line(&mut self, from: (isize, isize), to: (isize, isize), color: Color) {
+        let Some(pixel) = self.pixel(color) else {
+            return;
+        };
+
+        // Bresenham's algorithm, stepping one pixel along the longer axis each time.
+        let (mut x, mut y) = from;
+        let dx = (to.0 - x).abs();
+        let dy = -(to.1 - y).abs();
+        let step_x = if x < to.0 { 1 } else { -1 };
+        let step_y = if y < to.1 { 1 } else { -1 };
+        let mut error = dx + dy;
+
+        loop {
+            if let (Ok(column), Ok(row)) = (usize::try_from(x), usize::try_from(y))
+                && column < self.width()
+                && row < self.height()
+            {
+                self.put(column, row, &pixel);
+            }
+            if (x, y) == to {
+                break;
+            }
+
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+
+        let left = from.0.min(to.0).max(0) as usize;
+        let top = from.1.min(to.1).max(0) as usize;
+        let right = (from.0.max(to.0) + 1).max(0) as usize;
+        let bottom = (from.1.max(to.1) + 1).max(0) as usize;
+        self.mark_dirty(Rect::new(left, top, right - left, bottom - top));
+    }
+
+    /// Copies `image` with its top left corner at `x`, `y`.
+    pub fn blit(&mut self, image: &Image, x: usize, y: usize) {
+        let Some(rect) = self.clip(Rect::new(x, y, image.width, image.height)) else {
+            return;
+        };
+
+        for row in 0..rect.height {
+            for column in 0..rect.width {
+                let Some(&color) = image.pixels.get(row * image.width + column) else {
+                    return;
+                };
+                let Some(pixel) = self.pixel(color) else {
+                    return;
+                };
+                self.put(x + column, y + row, &pixel);
+            }
+        }
+        self.mark_dirty(rect);
+    }
+
+    /// Draws the set bits of `bitmap` in `color`, with its top left corner at `x`, `y`.
+    pub fn draw_bitmap(&mut self, bitmap: &Bitmap, x: usize, y: usize, color: Color) {
+        let Some(rect) = self.clip(Rect::new(x, y, bitmap.width, bitmap.height)) else {
+            return;
+        };
+        let Some(pixel) = self.pixel(color) else {
+            return;
+        };
+
+        for row in 0..rect.height {
+            for column in 0..rect.width {
+                if bitmap.is_set(column, row) {
+                    self.put(x + column, y + row, &pixel);
+                }
+            }
+        }
+        self.mark_dirty(rect);
+    }
+
+    /// The color of the pixel at `x`, `y`, if it is on the surface and its format is supported.
+    pub fn get(&self, x: usize, y: usize) -> Option<Color> {
+        if x >= self.width() || y >= self.height() {
+            return None;
+        }
+
+        let offset = (y * self.info.stride + x) * self.info.bytes_per_pixel;
+        let bytes = &self.pixels[offset..offset + self.info.bytes_per_pixel];
+        Color::from_pixel(self.info.pixel_format, bytes)
+    }
+
+    /// Copies the pixels in `rect`, which must be on the surface, into `out` row by row, in the surface's
+    /// format. `out` must hold `rect.width * rect.height` pixels.
+    pub fn read_pixels(&self, rect: Rect, out: &mut [u8]) {
+        let row_len = rect.width * self.info.bytes_per_pixel;
+
+        for row in 0..rect.height {
+            let start = ((rect.y + row) * self.info.stride + rect.x) * self.info.bytes_per_pixel;
+            out[row * row_len..(row + 1) * row_len]
+                .copy_from_slice(&self.pixels[start..start + row_len]);
+        }
+    }
+
+    /// Writes pixels read with `read_pixels` back into `rect`.
+    pub fn write_pixels(&mut self, rect: Rect, pixels: &[u8]) {
+        let row_len = rect.width * self.info.bytes_per_pixel;
+
+        for row in 0..rect.height {
+            let start = ((rect.y + row) * self.info.stride + rect.x) * self.info.bytes_per_pixel;
+            self.pixels[start..start + row_len]
+                .copy_from_slice(&pixels[row * row_len..(row + 1) * row_len]);
+        }
+        self.mark_dirty(rect);
+    }
+
+    /// The part of `rect` on the surface, if any.
+    pub fn clip(&self, rect: Rect) -> Option<Rect> {
+        let right = rect.x.saturating_add(rect.width).min(self.width());
+        let bottom = rect.y.saturating_add(rect.height).min(self.height());
+
+        (rect.x < right && rect.y < bottom)
+            .then(|| Rect::new(rect.x, rect.y, right - rect.x, bottom - rect.y))
+    }
+
+    fn pixel(&self, color: Color) -> Option<[u8; 4]> {
+        color.to_pixel(self.info.pixel_format)
+    }
+
+    /// Writes `pixel` at `x`, `y`, which must be on the surface.
+    fn put(&mut self, x: usize, y: usize, pixel: &[u8; 4]) {
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let offset = (y * self.info.stride + x) * bytes_per_pixel;
+        let len = bytes_per_pixel.min(pixel.len());
+
+        self.pixels[offset..offset + len].copy_from_slice(&pixel[..len]);
+    }
+
+    fn mark_dirty(&mut self, rect: Rect) {
+        let Some(dirty) = self.dirty.as_deref_mut() else {
+            return;
+        };
+
+        let area = Dirty {
+            left: rect.x.min(self.info.width),
+            top: rect.y.min(self.info.height),
+            right: (rect.x + rect.width).min(self.info.width),
+            bottom: (rect.y + rect.height).min(self.info.height),
+        };
+        *dirty = Some(dirty.map_or(area, |dirty| dirty.union(area)));
+    }
+}
+
+/// Runs `f` on the shown screen, and flushes what it drew.
+pub fn draw(f: impl FnOnce(&mut Surface)) {
+    with_writer(|writer| {
+        f(&mut writer.surface());
+        writer.flush();
+    });
+}
+
+#[cfg(test)]
+const WIDTH: usize = 8;
+#[cfg(test)]
+const HEIGHT: usize = 6;
+
+#[cfg(test)]
+fn info(pixel_format: PixelFormat) -> FrameBufferInfo {
+    FrameBufferInfo {
+        byte_len: WIDTH * HEIGHT * 4,
+        width: WIDTH,
+        height: HEIGHT,
+        pixel_format,
+        bytes_per_pixel: 4,
+        stride: WIDTH,
+    }
+}
+
+#[test_case]
+fn test_fill_rect_is_clipped_to_the_surface() {
+    let mut pixels = [0; WIDTH * HEIGHT * 4];
+    let mut surface = Surface::new(&mut pixels, info(PixelFormat::Rgb));
+
+    surface.fill_rect(Rect::new(6, 4, 10, 10), Color::RED);
+
+    assert_eq!(surface.get(6, 4), Some(Color::RED));
+    assert_eq!(surface.get(7, 5), Some(Color::RED));
+    assert_eq!(surface.get(5, 4), Some(Color::BLACK));
+    assert_eq!(surface.get(8, 4), None);
+}
+
+#[test_case]
+fn test_colors_are_converted_to_the_pixel_format() {
+    let mut pixels = [0; WIDTH * HEIGHT * 4];
+    let mut surface = Surface::new(&mut pixels, info(PixelFormat::Bgr));
+
+    surface.fill_rect(Rect::new(0, 0, 1, 1), Color::rgb(1, 2, 3));
+
+    assert_eq!(surface.get(0, 0), Some(Color::rgb(1, 2, 3)));
+    assert_eq!(pixels[..3], [3, 2, 1]);
+}
+
+#[test_case]
+fn test_draw_line_covers_both_ends() {
+    let mut pixels = [0; WIDTH * HEIGHT * 4];
+    let mut surface = Surface::new(&mut pixels, info(PixelFormat::Rgb));
+
+    surface.draw_line((-2, 0), (5, 5), Color::WHITE);
+    surface.draw_line((7, 0), (7, 0), Color::BLUE);
+
+    assert_eq!(surface.get(5, 5), Some(Color::WHITE));
+    assert_eq!(surface.get(7, 0), Some(Color::BLUE));
+    let lit = (0..HEIGHT)
+        .filter(|&y| (0..WIDTH).any(|x| surface.get(x, y) == Some(Color::WHITE)))
+        .count();
+    assert_eq!(lit, HEIGHT);
+}
+
+#[test_case]
+fn test_blit_and_bitmaps_land_where_asked() {
+    let mut pixels = [0; WIDTH * HEIGHT * 4];
+    let mut surface = Surface::new(&mut pixels, info(PixelFormat::Rgb));
+
+    let image = Image {
+        width: 2,
+        height: 1,
+        pixels: &[Color::RED, Color::GREEN],
+    };
+    surface.blit(&image, 7, 0);
+    assert_eq!(surface.get(7, 0), Some(Color::RED));
+
+    let bitmap = Bitmap {
+        width: 3,
+        height: 2,
+        bits: &[0b1010_0000, 0b0100_0000],
+    };
+    surface.draw_bitmap(&bitmap, 1, 2, Color::YELLOW);
+    assert_eq!(surface.get(1, 2), Some(Color::YELLOW));
+    assert_eq!(surface.get(2, 2), Some(Color::BLACK));
+    assert_eq!(surface.get(3, 2), Some(Color::YELLOW));
+    assert_eq!(surface.get(2, 3), Some(Color::YELLOW));
+}
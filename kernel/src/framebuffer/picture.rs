@@ -0,0 +1,284 @@
+//! Reading pictures from uncompressed BMP and binary PPM files.
+//!
+//! Pixels are read straight from the file, a row at a time, so nothing is allocated and pictures can be
+//! drawn before the heap is up. BMP files must be 24 or 32 bits per pixel without compression, and PPM
+//! files (`P6`) at most 255 per channel.
+
+use super::Color;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PictureError {
+    /// The file is neither a BMP nor a PPM file.
+    UnknownFormat,
+    /// The header is cut short or doesn't make sense.
+    InvalidHeader,
+    /// A BMP file with a bit depth or compression other than 24 or 32 bits uncompressed.
+    Unsupported,
+    /// The file ends before its last pixel.
+    Truncated,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Layout {
+    Ppm {
+        max_value: u8,
+    },
+    Bmp {
+        bytes_per_pixel: usize,
+        bottom_up: bool,
+    },
+}
+
+/// A picture in a BMP or PPM file.
+#[derive(Debug, Clone, Copy)]
+pub struct Picture<'a> {
+    pub width: usize,
+    pub height: usize,
+    layout: Layout,
+    /// The file's pixels.
+    pixels: &'a [u8],
+    /// Bytes from one row of `pixels` to the next.
+    stride: usize,
+}
+
+impl<'a> Picture<'a> {
+    /// Reads the header of the BMP or PPM file in `data`.
+    pub fn parse(data: &'a [u8]) -> Result<Self, PictureError> {
+        match data {
+            [b'B', b'M', ..] => Self::parse_bmp(data),
+            [b'P', b'6', ..] => Self::parse_ppm(data),
+            _ => Err(PictureError::UnknownFormat),
+        }
+    }
+
+    fn parse_bmp(data: &'a [u8]) -> Result<Self, PictureError> {
+        let u16_at = |offset: usize| {
+            data.get(offset..offset + 2)
+                .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+                .ok_or(PictureError::InvalidHeader)
+        };
+        let u32_at = |offset: usize| {
+            data.get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                .ok_or(PictureError::InvalidHeader)
+        };
+
+        let pixels_offset = u32_at(10)? as usize;
+        let width = u32_at(18)? as i32;
+        let height = u32_at(22)? as i32;
+        let bits_per_pixel = u16_at(28)?;
+        let compression = u32_at(30)?;
+
+        let bytes_per_pixel = match (bits_per_pixel, compression) {
+            (24, 0) => 3,
+            (32, 0) => 4,
+            _ => return Err(PictureError::Unsupported),
+        };
+        if width <= 0 || height == 0 {
+            return Err(PictureError::InvalidHeader);
+        }
+        let width = width as usize;
+        // Rows are stored bottom to top, unless the height is negative.
+        let bottom_up = height > 0;
+        let height = height.unsigned_abs() as usize;
+        // Rows are padded to a multiple of 4 bytes.
+        let stride = (width * bytes_per_pixel).next_multiple_of(4);
+
+        let pixels = data
+            .get(pixels_offset..)
+            .ok_or(PictureError::InvalidHeader)?;
+        Self::new(
+            width,
+            height,
+            Layout::Bmp {
+                bytes_per_pixel,
+                bottom_up,
+            },
+            pixels,
+            stride,
+        )
+    }
+
+    fn parse_ppm(data: &'a [u8]) -> Result<Self, PictureError> {
+        let mut position = 2;
+        let mut fields = [0usize; 3];
+
+        // Width, height and maximum value, as decimal numbers separated by whitespace and comments.
+        for field in &mut fields {
+            loop {
+                match data.get(position) {
+                    Some(byte) if byte.is_ascii_whitespace() => position += 1,
+                    Some(b'#') => {
+                        while data.get(position).is_some_and(|&byte| byte != b'\n') {
+                            position += 1;
+                        }
+                    }
+                    _ => break,
+                }
+            }
+
+            let start = position;
+            while data.get(position).is_some_and(u8::is_ascii_digit) {
+                *field = field
+                    .checked_mul(10)
+                    .and_then(|value| value.checked_add((data[position] - b'0') as usize))
+                    .ok_or(PictureError::InvalidHeader)?;
+                position += 1;
+            }
+            if position == start {
+                return Err(PictureError::InvalidHeader);
+            }
+        }
+
+        // A single whitespace character separates the header from the pixels.
+        if !data.get(position).is_some_and(u8::is_ascii_whitespace) {
+            return Err(PictureError::InvalidHeader);
+        }
+        let [width, height, max_value] = fields;
+        let max_value = match u8::try_from(max_value) {
+            Ok(0) | Err(_) => return Err(PictureError::Unsupported),
+            Ok(max_value) => max_value,
+        };
+
+        Self::new(
+            width,
+            height,
+            Layout::Ppm { max_value },
+            &data[position + 1..],
+            width * 3,
+        )
+    }
+
+    fn new(
+        width: usize,
+        height: usize,
+        layout: Layout,
+        pixels: &'a [u8],
+        stride: usize,
+    ) -> Result<Self, PictureError> {
+        if width == 0 || height == 0 {
+            return Err(PictureError::InvalidHeader);
+        }
+        let last_row_len = match layout {
+            Layout::Ppm { .. } => width * 3,
+            Layout::Bmp {
+                bytes_per_pixel, ..
+            } => width * bytes_per_pixel,
+        };
+        let len = stride
+            .checked_mul(height - 1)
+            .and_then(|len| len.checked_add(last_row_len))
+            .ok_or(PictureError::InvalidHeader)?;
+        if pixels.len() < len {
+            return Err(PictureError::Truncated);
+        }
+
+        Ok(Picture {
+            width,
+            height,
+            layout,
+            pixels,
+            stride,
+        })
+    }
+
+    /// The color of the pixel at `x`, `y`, from the top left corner.
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        assert!(x < self.width && y < self.height);
+
+        match self.layout {
+            Layout::Ppm { max_value } => {
+                let offset = y * self.stride + x * 3;
+                let scale =
+                    |value: u8| (value.min(max_value) as u16 * 255 / max_value as u16) as u8;
+                let [red, green, blue] = [0, 1, 2].map(|i| scale(self.pixels[offset + i]));
+                Color::rgb(red, green, blue)
+            }
+            Layout::Bmp {
+                bytes_per_pixel,
+                bottom_up,
+            } => {
+                let row = if bottom_up { self.height - 1 - y } else { y };
+                let offset = row * self.stride + x * bytes_per_pixel;
+                let bytes = &self.pixels[offset..offset + 3];
+                Color::rgb(bytes[2], bytes[1], bytes[0])
+            }
+        }
+    }
+
+    /// Fills `row` with the pixels of row `y` from column `x` on, as far as either goes.
+    pub fn read_row(&self, x: usize, y: usize, row: &mut [Color]) -> usize {
+        let len = row.len().min(self.width.saturating_sub(x));
+        for (column, color) in row[..len].iter_mut().enumerate() {
+            *color = self.pixel(x + column, y);
+        }
+        len
+    }
+}
+
+/// A 2x2 BMP, 24 bits per pixel, stored bottom up.
+#[cfg(test)]
+const BMP: [u8; 54 + 16] = {
+    let mut bmp = [0; 54 + 16];
+    bmp[0] = b'B';
+    bmp[1] = b'M';
+    bmp[10] = 54;
+    bmp[14] = 40;
+    bmp[18] = 2;
+    bmp[22] = 2;
+    bmp[26] = 1;
+    bmp[28] = 24;
+    // The bottom row, blue then green, padded to 8 bytes.
+    bmp[54] = 255;
+    bmp[54 + 4] = 255;
+    // The top row, red then white.
+    bmp[62 + 2] = 255;
+    let mut i = 65;
+    while i < 68 {
+        bmp[i] = 255;
+        i += 1;
+    }
+    bmp
+};
+
+#[test_case]
+fn test_bmp_rows_are_read_top_down() {
+    let picture = Picture::parse(&BMP).unwrap();
+
+    assert_eq!((picture.width, picture.height), (2, 2));
+    assert_eq!(picture.pixel(0, 0), Color::rgb(255, 0, 0));
+    assert_eq!(picture.pixel(1, 0), Color::WHITE);
+    assert_eq!(picture.pixel(0, 1), Color::rgb(0, 0, 255));
+    assert_eq!(picture.pixel(1, 1), Color::rgb(0, 255, 0));
+}
+
+#[test_case]
+fn test_truncated_bmp_is_rejected() {
+    assert_eq!(
+        Picture::parse(&BMP[..BMP.len() - 3]).err(),
+        Some(PictureError::Truncated)
+    );
+}
+
+#[test_case]
+fn test_ppm_header_may_have_comments_and_is_scaled() {
+    let ppm = b"P6\n# a comment\n2 1\n15\n\x0f\x00\x00\x00\x0f\x07";
+    let picture = Picture::parse(ppm).unwrap();
+
+    assert_eq!((picture.width, picture.height), (2, 1));
+    let mut row = [Color::BLACK; 4];
+    assert_eq!(picture.read_row(0, 0, &mut row), 2);
+    assert_eq!(row[..2], [Color::rgb(255, 0, 0), Color::rgb(0, 255, 119)]);
+}
+
+#[test_case]
+fn test_unknown_formats_are_rejected() {
+    assert_eq!(
+        Picture::parse(b"GIF89a").err(),
+        Some(PictureError::UnknownFormat)
+    );
+    assert_eq!(
+        Picture::parse(b"P6 2 1 65535\n").err(),
+        Some(PictureError::Unsupported)
+    );
+}
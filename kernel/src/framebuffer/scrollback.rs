@@ -0,0 +1,104 @@
+//! The text of the lines written to the screen, kept so that lines scrolled off the top can be shown
+//! again.
+//!
+//! Lines live in a ring in static memory, so recording never allocates and works before the heap is up.
+//! Only the text is kept: scrolled back lines are redrawn in the current colors.
+
+/// Lines kept, including the ones on the screen. Older ones are overwritten.
+pub const CAPACITY: usize = 256;
+/// Bytes of UTF-8 kept per line, more than fit across a 1920 pixel wide screen. The rest is dropped.
+pub const LINE_LEN: usize = 256;
+
+#[derive(Clone, Copy)]
+struct Line {
+    text: [u8; LINE_LEN],
+    len: usize,
+}
+
+impl Line {
+    const EMPTY: Line = Line {
+        text: [0; LINE_LEN],
+        len: 0,
+    };
+
+    fn text(&self) -> &str {
+        // Only whole characters are pushed.
+        core::str::from_utf8(&self.text[..self.len]).unwrap_or_default()
+    }
+}
+
+pub struct Scrollback {
+    lines: [Line; CAPACITY],
+    /// Number of the line being written. Line `n` is at `n % CAPACITY`.
+    current: usize,
+}
+
+impl Scrollback {
+    pub const fn new() -> Self {
+        Scrollback {
+            lines: [Line::EMPTY; CAPACITY],
+            current: 0,
+        }
+    }
+
+    /// Appends `character` to the current line, unless it is full.
+    pub fn push(&mut self, character: char) {
+        let line = &mut self.lines[self.current % CAPACITY];
+        let len = character.len_utf8();
+
+        if line.len + len <= LINE_LEN {
+            character.encode_utf8(&mut line.text[line.len..]);
+            line.len += len;
+        }
+    }
+
+    /// Starts a new, empty line, overwriting the oldest one if the ring is full.
+    pub fn new_line(&mut self) {
+        self.current += 1;
+        self.lines[self.current % CAPACITY] = Line::EMPTY;
+    }
+
+    /// Number of lines kept, including the current one.
+    pub fn len(&self) -> usize {
+        (self.current + 1).min(CAPACITY)
+    }
+
+    /// The text of the line `back` lines above the current one, which is line 0, if it is still kept.
+    pub fn line(&self, back: usize) -> Option<&str> {
+        (back < self.len()).then(|| self.lines[(self.current - back) % CAPACITY].text())
+    }
+}
+
+#[test_case]
+fn test_scrollback_keeps_the_latest_lines() {
+    static SCROLLBACK: spin::Mutex<Scrollback> = spin::Mutex::new(Scrollback::new());
+    let mut scrollback = SCROLLBACK.lock();
+    "ab".chars()
+        .for_each(|character| scrollback.push(character));
+    scrollback.new_line();
+    scrollback.push('é');
+
+    assert_eq!(scrollback.len(), 2);
+    assert_eq!(scrollback.line(0), Some("é"));
+    assert_eq!(scrollback.line(1), Some("ab"));
+    assert_eq!(scrollback.line(2), None);
+
+    for _ in 0..CAPACITY {
+        scrollback.new_line();
+    }
+    assert_eq!(scrollback.len(), CAPACITY);
+    assert_eq!(scrollback.line(CAPACITY - 1), Some(""));
+    assert_eq!(scrollback.line(CAPACITY), None);
+}
+
+#[test_case]
+fn test_scrollback_truncates_long_lines() {
+    static SCROLLBACK: spin::Mutex<Scrollback> = spin::Mutex::new(Scrollback::new());
+    let mut scrollback = SCROLLBACK.lock();
+    for _ in 0..LINE_LEN + 10 {
+        scrollback.push('x');
+    }
+    scrollback.push('é');
+
+    assert_eq!(scrollback.line(0).map(str::len), Some(LINE_LEN));
+}
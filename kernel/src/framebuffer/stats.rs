@@ -0,0 +1,63 @@
+//! Framebuffer performance counters, enabled with the `framebuffer-stats` feature.
+//!
+//! Counts the pixels written and glyphs rendered, and times every use of the writer with the TSC, which
+//! includes waiting for its lock. Together they show whether slow output comes from the amount drawn or
+//! from the cost per pixel, e.g. of drawing to the framebuffer without a back buffer.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::cpu;
+
+static PIXELS: AtomicU64 = AtomicU64::new(0);
+static GLYPHS: AtomicU64 = AtomicU64::new(0);
+static CYCLES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferStats {
+    pub pixels_written: u64,
+    pub glyphs_rendered: u64,
+    /// TSC cycles spent using the writer.
+    pub cycles: u64,
+}
+
+pub(super) fn count_pixel() {
+    PIXELS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(super) fn count_glyph() {
+    GLYPHS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Times a use of the writer from its creation until it is dropped.
+pub(super) struct Measurement {
+    start: u64,
+}
+
+impl Measurement {
+    pub(super) fn start() -> Self {
+        Measurement {
+            start: cpu::rdtsc(),
+        }
+    }
+}
+
+impl Drop for Measurement {
+    fn drop(&mut self) {
+        CYCLES.fetch_add(cpu::rdtsc().wrapping_sub(self.start), Ordering::Relaxed);
+    }
+}
+
+/// The counters since boot or the last `reset`.
+pub fn stats() -> FramebufferStats {
+    FramebufferStats {
+        pixels_written: PIXELS.load(Ordering::Relaxed),
+        glyphs_rendered: GLYPHS.load(Ordering::Relaxed),
+        cycles: CYCLES.load(Ordering::Relaxed),
+    }
+}
+
+pub fn reset() {
+    PIXELS.store(0, Ordering::Relaxed);
+    GLYPHS.store(0, Ordering::Relaxed);
+    CYCLES.store(0, Ordering::Relaxed);
+}
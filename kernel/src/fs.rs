@@ -0,0 +1,288 @@
+//! The virtual filesystem: filesystems mounted at paths, and the files in them.
+//!
+//! A filesystem implements `FileSystem`, in which files and directories are nodes it names with a
+//! `NodeId` of its choosing: an inode number, or where a directory entry is on the disk. `mount` puts it
+//! at an absolute path, and the functions here resolve paths through the mount with the longest matching
+//! prefix, then look up the rest of the path one name at a time.
+//!
+//! Everything is asynchronous, like the block devices filesystems sit on, so a task waiting for the disk
+//! leaves the CPU to the others. The methods of `FileSystem` return boxed futures so that filesystems of
+//! different types can be mounted side by side. A filesystem that can't be written only implements the
+//! reading methods; the others fail with `FsError::ReadOnly`.
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+use core::{future::Future, pin::Pin};
+use spin::Mutex;
+
+use crate::storage::BlockError;
+
+pub mod ext2;
+pub mod fat32;
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// Longest name a filesystem takes.
+pub const MAX_NAME: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    /// A directory that is removed still has entries.
+    NotEmpty,
+    /// The name is empty, too long or has characters the filesystem doesn't allow.
+    InvalidName,
+    /// A path isn't absolute.
+    InvalidPath,
+    NoSpace,
+    /// The file would be larger than the filesystem allows.
+    TooLarge,
+    ReadOnly,
+    /// The filesystem's structures on the disk don't make sense.
+    Corrupt,
+    /// The filesystem uses something the driver doesn't support.
+    Unsupported,
+    /// Something is mounted at the path already.
+    AlreadyMounted,
+    Device(BlockError),
+}
+
+impl From<BlockError> for FsError {
+    fn from(error: BlockError) -> Self {
+        match error {
+            BlockError::ReadOnly => FsError::ReadOnly,
+            error => FsError::Device(error),
+        }
+    }
+}
+
+/// A file or directory of a filesystem, as the filesystem names it.
+pub type NodeId = u64;
+
+/// What a `FileSystem` method returns.
+pub type FsFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, FsError>> + 'a>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: FileType,
+    /// In bytes. 0 for directories.
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub node: NodeId,
+    pub kind: FileType,
+    pub size: u64,
+}
+
+pub trait FileSystem: Send + Sync {
+    /// The filesystem's type, e.g. "fat32".
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> NodeId;
+
+    /// The node called `name` in directory `dir`.
+    fn lookup<'a>(&'a self, dir: NodeId, name: &'a str) -> FsFuture<'a, NodeId>;
+
+    fn metadata(&self, node: NodeId) -> FsFuture<'_, Metadata>;
+
+    /// The entries of `dir`, without `.` and `..`.
+    fn read_dir(&self, dir: NodeId) -> FsFuture<'_, Vec<DirEntry>>;
+
+    /// Reads from `offset` of the file into `buffer`, and returns how many bytes there were, fewer than
+    /// asked for at the end of the file.
+    fn read<'a>(&'a self, node: NodeId, offset: u64, buffer: &'a mut [u8]) -> FsFuture<'a, usize>;
+
+    /// Writes `data` at `offset` of the file, which grows to fit it. A gap between the end of the file
+    /// and `offset` reads as zeros.
+    fn write<'a>(&'a self, node: NodeId, offset: u64, data: &'a [u8]) -> FsFuture<'a, usize> {
+        let _ = (node, offset, data);
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+
+    /// Cuts the file to `len` bytes, or grows it with zeros.
+    fn truncate(&self, node: NodeId, len: u64) -> FsFuture<'_, ()> {
+        let _ = (node, len);
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+
+    /// Creates an empty file or directory called `name` in `dir`.
+    fn create<'a>(&'a self, dir: NodeId, name: &'a str, kind: FileType) -> FsFuture<'a, NodeId> {
+        let _ = (dir, name, kind);
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+
+    /// Removes the file or empty directory called `name` from `dir`.
+    fn remove<'a>(&'a self, dir: NodeId, name: &'a str) -> FsFuture<'a, ()> {
+        let _ = (dir, name);
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+}
+
+struct Mount {
+    /// The components of the path it is mounted at.
+    path: Vec<String>,
+    fs: Arc<dyn FileSystem>,
+}
+
+/// The components of absolute path `path`, with `.` and `..` resolved. `..` of the root is the root.
+pub fn components(path: &str) -> Result<Vec<&str>, FsError> {
+    let Some(path) = path.strip_prefix('/') else {
+        return Err(FsError::InvalidPath);
+    };
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+    Ok(components)
+}
+
+/// Mounts `fs` at `path`. The path doesn't need to exist; what was there is hidden until `unmount`.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let path: Vec<String> = components(path)?.into_iter().map(String::from).collect();
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(FsError::AlreadyMounted);
+    }
+    mounts.push(Mount { path, fs });
+    Ok(())
+}
+
+/// Takes the filesystem mounted at `path` away, and returns it.
+pub fn unmount(path: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    let path = components(path)?;
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|mount| mount.path == path)
+        .ok_or(FsError::NotFound)?;
+    Ok(mounts.remove(index).fs)
+}
+
+/// Where each filesystem is mounted, and its type, in the order they were.
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| {
+            let path = if mount.path.is_empty() {
+                String::from("/")
+            } else {
+                mount.path.iter().flat_map(|name| ["/", name]).collect()
+            };
+            (path, mount.fs.name())
+        })
+        .collect()
+}
+
+/// The filesystem `path` is on, and the node it names there.
+async fn resolve(path: &str) -> Result<(Arc<dyn FileSystem>, NodeId), FsError> {
+    resolve_components(&components(path)?).await
+}
+
+async fn resolve_components(components: &[&str]) -> Result<(Arc<dyn FileSystem>, NodeId), FsError> {
+    let (fs, depth) = {
+        let mounts = MOUNTS.lock();
+        let mount = mounts
+            .iter()
+            .filter(|mount| {
+                mount.path.len() <= components.len()
+                    && mount.path.iter().zip(components).all(|(a, b)| a == b)
+            })
+            .max_by_key(|mount| mount.path.len())
+            .ok_or(FsError::NotFound)?;
+        (mount.fs.clone(), mount.path.len())
+    };
+
+    let mut node = fs.root();
+    for name in &components[depth..] {
+        node = fs.lookup(node, name).await?;
+    }
+    Ok((fs, node))
+}
+
+/// The directory `path` is in, and its name there.
+async fn resolve_parent(path: &str) -> Result<(Arc<dyn FileSystem>, NodeId, String), FsError> {
+    let mut components = components(path)?;
+    let name = components.pop().ok_or(FsError::InvalidName)?;
+    let (fs, dir) = resolve_components(&components).await?;
+    Ok((fs, dir, String::from(name)))
+}
+
+pub async fn metadata(path: &str) -> Result<Metadata, FsError> {
+    let (fs, node) = resolve(path).await?;
+    fs.metadata(node).await
+}
+
+pub async fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let (fs, node) = resolve(path).await?;
+    fs.read_dir(node).await
+}
+
+/// Reads from `offset` of the file at `path`, see `FileSystem::read`.
+pub async fn read(path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+    let (fs, node) = resolve(path).await?;
+    fs.read(node, offset, buffer).await
+}
+
+/// The whole file at `path`.
+pub async fn read_to_end(path: &str) -> Result<Vec<u8>, FsError> {
+    let (fs, node) = resolve(path).await?;
+    let mut data = vec![0; fs.metadata(node).await?.size as usize];
+    let len = fs.read(node, 0, &mut data).await?;
+    data.truncate(len);
+    Ok(data)
+}
+
+/// Writes at `offset` of the existing file at `path`, see `FileSystem::write`.
+pub async fn write(path: &str, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+    let (fs, node) = resolve(path).await?;
+    fs.write(node, offset, data).await
+}
+
+/// Makes `data` the contents of the file at `path`, which is created if it doesn't exist.
+pub async fn write_file(path: &str, data: &[u8]) -> Result<(), FsError> {
+    let (fs, dir, name) = resolve_parent(path).await?;
+    let node = match fs.lookup(dir, &name).await {
+        Ok(node) => node,
+        Err(FsError::NotFound) => fs.create(dir, &name, FileType::File).await?,
+        Err(error) => return Err(error),
+    };
+    fs.truncate(node, 0).await?;
+    fs.write(node, 0, data).await?;
+    Ok(())
+}
+
+pub async fn truncate(path: &str, len: u64) -> Result<(), FsError> {
+    let (fs, node) = resolve(path).await?;
+    fs.truncate(node, len).await
+}
+
+/// Creates an empty file or directory at `path`, whose parent must exist.
+pub async fn create(path: &str, kind: FileType) -> Result<(), FsError> {
+    let (fs, dir, name) = resolve_parent(path).await?;
+    fs.create(dir, &name, kind).await?;
+    Ok(())
+}
+
+/// Removes the file or empty directory at `path`.
+pub async fn remove(path: &str) -> Result<(), FsError> {
+    let (fs, dir, name) = resolve_parent(path).await?;
+    fs.remove(dir, &name).await
+}
@@ -0,0 +1,501 @@
+//! ext2, the filesystem `mkfs.ext2` makes, read-only.
+//!
+//! The volume is divided into blocks of 1 KiB or more, and the blocks into groups. The superblock, at byte
+//! 1024, gives the sizes of both; the table of group descriptors follows it and says where each group's
+//! inode table is. Every file and directory has an inode with its type, size and the blocks its data is
+//! in: 12 directly, then a block of block numbers, a block of those, and one more level, each covering
+//! as many blocks as the one before times the numbers a block holds. A block number of 0 is a hole,
+//! which reads as zeros.
+//!
+//! A directory's data is a list of entries, each with an inode number and a name, which don't cross
+//! blocks. The root directory is inode 2. Nodes are named by their inode number.
+//!
+//! ext3 volumes are ext2 volumes with a journal, and can be read as long as the journal is empty. ext4
+//! ones store blocks in extents, which aren't supported.
+
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsFuture, Metadata, NodeId};
+use crate::storage::BlockDevice;
+
+const SUPERBLOCK_OFFSET: usize = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+/// Blocks are 1024 bytes shifted left by the superblock's log of the block size, up to 64 KiB.
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+
+/// Directory entries carry the type of their inode.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Groups' bitmaps and inode tables may be packed together, which only changes where they are.
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+/// Among those left out is the one set while an ext3 journal has changes that weren't applied yet.
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+
+const GROUP_DESCRIPTOR_SIZE: usize = 32;
+/// Inodes are this large in revision 0 volumes.
+const GOOD_OLD_INODE_SIZE: u32 = 128;
+
+const MODE_TYPE: u16 = 0xf000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_REGULAR: u16 = 0x8000;
+
+/// Block numbers in an inode: the direct ones, then the roots of the indirect trees.
+const DIRECT_BLOCKS: usize = 12;
+const INODE_BLOCKS: usize = DIRECT_BLOCKS + 3;
+
+const ROOT: NodeId = 2;
+
+/// The superblock, with what is needed to find inodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Superblock {
+    pub inodes: u32,
+    pub blocks: u32,
+    /// The block holding the superblock: 1 with 1 KiB blocks, 0 otherwise.
+    pub first_data_block: u32,
+    pub block_size: u32,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub inode_size: u32,
+    pub revision: u32,
+    pub feature_incompat: u32,
+}
+
+impl Superblock {
+    /// Reads the superblock from the 1024 bytes at byte 1024 of the volume. Fails with
+    /// `FsError::Unsupported` if it isn't an ext2 volume, or uses features that aren't supported.
+    pub fn parse(bytes: &[u8]) -> Result<Superblock, FsError> {
+        if bytes.len() < SUPERBLOCK_SIZE {
+            return Err(FsError::Unsupported);
+        }
+        let half = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let word =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        if half(56) != MAGIC {
+            return Err(FsError::Unsupported);
+        }
+
+        let revision = word(76);
+        let (inode_size, feature_incompat) = match revision {
+            0 => (GOOD_OLD_INODE_SIZE, 0),
+            _ => (u32::from(half(88)), word(96)),
+        };
+        if feature_incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(FsError::Unsupported);
+        }
+
+        let log_block_size = word(24);
+        if log_block_size > MAX_LOG_BLOCK_SIZE {
+            return Err(FsError::Corrupt);
+        }
+        let superblock = Superblock {
+            inodes: word(0),
+            blocks: word(4),
+            first_data_block: word(20),
+            block_size: 1024 << log_block_size,
+            blocks_per_group: word(32),
+            inodes_per_group: word(40),
+            inode_size,
+            revision,
+            feature_incompat,
+        };
+        if superblock.blocks_per_group == 0
+            || superblock.inodes_per_group == 0
+            || superblock.first_data_block >= superblock.blocks
+            || !superblock.inode_size.is_power_of_two()
+            || !(GOOD_OLD_INODE_SIZE..=superblock.block_size).contains(&superblock.inode_size)
+        {
+            return Err(FsError::Corrupt);
+        }
+        Ok(superblock)
+    }
+
+    pub fn groups(&self) -> u32 {
+        (self.blocks - self.first_data_block).div_ceil(self.blocks_per_group)
+    }
+}
+
+/// Where the `index`th block of a file is found when a block holds `per_block` block numbers: the slot
+/// of the inode's block numbers, how many levels of indirect blocks are under it, and the index among
+/// the blocks it covers.
+fn locate(index: u64, per_block: u64) -> Option<(usize, u32, u64)> {
+    if index < DIRECT_BLOCKS as u64 {
+        return Some((index as usize, 0, 0));
+    }
+    let mut rest = index - DIRECT_BLOCKS as u64;
+    let mut span = 1;
+    for depth in 1..=3 {
+        span *= per_block;
+        if rest < span {
+            return Some((DIRECT_BLOCKS + depth as usize - 1, depth, rest));
+        }
+        rest -= span;
+    }
+    None
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Inode {
+    mode: u16,
+    size: u64,
+    block: [u32; INODE_BLOCKS],
+}
+
+impl Inode {
+    fn parse(bytes: &[u8], revision: u32) -> Inode {
+        let word =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let mode = u16::from_le_bytes([bytes[0], bytes[1]]);
+        let mut size = u64::from(word(4));
+        // Revision 1 keeps the high half of regular files' sizes where revision 0 had directory ACLs.
+        if revision > 0 && mode & MODE_TYPE == MODE_REGULAR {
+            size |= u64::from(word(108)) << 32;
+        }
+        Inode {
+            mode,
+            size,
+            block: core::array::from_fn(|i| word(40 + 4 * i)),
+        }
+    }
+
+    fn kind(&self) -> FileType {
+        match self.mode & MODE_TYPE {
+            MODE_DIRECTORY => FileType::Directory,
+            _ => FileType::File,
+        }
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: self.kind(),
+            size: match self.kind() {
+                FileType::File => self.size,
+                FileType::Directory => 0,
+            },
+        }
+    }
+}
+
+/// An entry of a directory's data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Dirent {
+    inode: u32,
+    name: String,
+}
+
+/// The entries in a block of a directory, without `.` and `..`.
+fn parse_directory_block(block: &[u8], entries: &mut Vec<Dirent>) -> Result<(), FsError> {
+    let mut position = 0;
+    while position + 8 <= block.len() {
+        let bytes = &block[position..];
+        let inode = u32::from_le_bytes(bytes[..4].try_into().unwrap());
+        let record_len = usize::from(u16::from_le_bytes([bytes[4], bytes[5]]));
+        // Without the file type feature, the byte after the name length is its high byte, which names
+        // of up to 255 bytes leave 0.
+        let name_len = usize::from(bytes[6]);
+        if record_len < 8 || record_len > bytes.len() || 8 + name_len > record_len {
+            return Err(FsError::Corrupt);
+        }
+
+        let name = &bytes[8..8 + name_len];
+        // Unused entries have inode 0.
+        if inode != 0 && name != b"." && name != b".." {
+            entries.push(Dirent {
+                inode,
+                name: String::from_utf8_lossy(name).into_owned(),
+            });
+        }
+        position += record_len;
+    }
+    Ok(())
+}
+
+/// Finds the blocks of a file, keeping the indirect blocks last read at each level so that reading a
+/// file in order reads each of them once.
+struct BlockMap<'a, D> {
+    fs: &'a Ext2<D>,
+    inode: &'a Inode,
+    cached: [Option<(u32, Vec<u8>)>; 3],
+}
+
+impl<'a, D: BlockDevice> BlockMap<'a, D> {
+    fn new(fs: &'a Ext2<D>, inode: &'a Inode) -> Self {
+        BlockMap {
+            fs,
+            inode,
+            cached: [None, None, None],
+        }
+    }
+
+    /// The block holding the `index`th block of the file, 0 for a hole.
+    async fn get(&mut self, index: u64) -> Result<u32, FsError> {
+        let per_block = u64::from(self.fs.superblock.block_size / 4);
+        let (slot, depth, mut rest) = locate(index, per_block).ok_or(FsError::Corrupt)?;
+        let mut block = self.inode.block[slot];
+
+        for level in (0..depth).rev() {
+            if block == 0 {
+                return Ok(0);
+            }
+            let span = per_block.pow(level);
+            let cached = &mut self.cached[level as usize];
+            if cached.as_ref().is_none_or(|(number, _)| *number != block) {
+                let mut data = vec![0; self.fs.superblock.block_size as usize];
+                self.fs.read_blocks(block, &mut data).await?;
+                *cached = Some((block, data));
+            }
+            let (_, data) = cached.as_ref().unwrap();
+            let at = (rest / span) as usize * 4;
+            block = u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+            rest %= span;
+        }
+        Ok(block)
+    }
+}
+
+/// A mounted ext2 volume.
+pub struct Ext2<D> {
+    device: D,
+    superblock: Superblock,
+    /// Device blocks in a block of the volume.
+    blocks_per_block: u64,
+    /// The first block of each group's inode table.
+    inode_tables: Vec<u32>,
+}
+
+impl<D: BlockDevice> Ext2<D> {
+    /// Mounts the volume on `device`, which starts at its first block. Fails with
+    /// `FsError::Unsupported` if it isn't an ext2 volume.
+    pub async fn mount(device: D) -> Result<Self, FsError> {
+        let device_block_size = device.block_size();
+        let end = SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE;
+        let mut start = vec![0; end.div_ceil(device_block_size) * device_block_size];
+        device.read_blocks(0, &mut start).await?;
+        let superblock = Superblock::parse(&start[SUPERBLOCK_OFFSET..end])?;
+
+        let block_size = superblock.block_size as usize;
+        if !block_size.is_multiple_of(device_block_size) {
+            return Err(FsError::Unsupported);
+        }
+        let blocks_per_block = (block_size / device_block_size) as u64;
+        if u64::from(superblock.blocks) * blocks_per_block > device.num_blocks() {
+            return Err(FsError::Corrupt);
+        }
+
+        let mut fs = Ext2 {
+            device,
+            superblock,
+            blocks_per_block,
+            inode_tables: Vec::new(),
+        };
+
+        // The descriptors start in the block after the superblock's.
+        let groups = superblock.groups() as usize;
+        let table_len = (groups * GROUP_DESCRIPTOR_SIZE).div_ceil(block_size) * block_size;
+        let mut table = vec![0; table_len];
+        fs.read_blocks(superblock.first_data_block + 1, &mut table)
+            .await?;
+        fs.inode_tables = table
+            .chunks_exact(GROUP_DESCRIPTOR_SIZE)
+            .take(groups)
+            .map(|descriptor| u32::from_le_bytes(descriptor[8..12].try_into().unwrap()))
+            .collect();
+        if fs
+            .inode_tables
+            .iter()
+            .any(|&block| block >= superblock.blocks)
+        {
+            return Err(FsError::Corrupt);
+        }
+
+        if fs.inode(ROOT as u32).await?.kind() != FileType::Directory {
+            return Err(FsError::Corrupt);
+        }
+        Ok(fs)
+    }
+
+    pub fn superblock(&self) -> &Superblock {
+        &self.superblock
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Reads blocks of the volume from `block` into `buffer`, a whole number of them.
+    async fn read_blocks(&self, block: u32, buffer: &mut [u8]) -> Result<(), FsError> {
+        let count = (buffer.len() / self.superblock.block_size as usize) as u64;
+        if u64::from(block) + count > self.superblock.blocks.into() {
+            return Err(FsError::Corrupt);
+        }
+        Ok(self
+            .device
+            .read_blocks(u64::from(block) * self.blocks_per_block, buffer)
+            .await?)
+    }
+
+    async fn inode(&self, number: u32) -> Result<Inode, FsError> {
+        if number == 0 || number > self.superblock.inodes {
+            return Err(FsError::Corrupt);
+        }
+        let index = number - 1;
+        let group = (index / self.superblock.inodes_per_group) as usize;
+        let table = *self.inode_tables.get(group).ok_or(FsError::Corrupt)?;
+
+        let block_size = u64::from(self.superblock.block_size);
+        let byte = u64::from(index % self.superblock.inodes_per_group)
+            * u64::from(self.superblock.inode_size);
+        let mut data = vec![0; block_size as usize];
+        self.read_blocks(table + (byte / block_size) as u32, &mut data)
+            .await?;
+        let offset = (byte % block_size) as usize;
+        Ok(Inode::parse(
+            &data[offset..offset + self.superblock.inode_size as usize],
+            self.superblock.revision,
+        ))
+    }
+
+    fn node_inode(node: NodeId) -> Result<u32, FsError> {
+        u32::try_from(node).map_err(|_| FsError::NotFound)
+    }
+
+    /// The entries of directory `node`.
+    async fn entries(&self, node: NodeId) -> Result<Vec<Dirent>, FsError> {
+        let inode = self.inode(Self::node_inode(node)?).await?;
+        if inode.kind() != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        let block_size = self.superblock.block_size as usize;
+        let mut map = BlockMap::new(self, &inode);
+        let mut block = vec![0; block_size];
+        let mut entries = Vec::new();
+        for index in 0..inode.size.div_ceil(block_size as u64) {
+            match map.get(index).await? {
+                0 => continue,
+                number => self.read_blocks(number, &mut block).await?,
+            }
+            parse_directory_block(&block, &mut entries)?;
+        }
+        Ok(entries)
+    }
+}
+
+impl<D: BlockDevice + Send + Sync> FileSystem for Ext2<D> {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> NodeId {
+        ROOT
+    }
+
+    fn lookup<'a>(&'a self, dir: NodeId, name: &'a str) -> FsFuture<'a, NodeId> {
+        Box::pin(async move {
+            self.entries(dir)
+                .await?
+                .into_iter()
+                .find(|entry| entry.name == name)
+                .map(|entry| entry.inode.into())
+                .ok_or(FsError::NotFound)
+        })
+    }
+
+    fn metadata(&self, node: NodeId) -> FsFuture<'_, Metadata> {
+        Box::pin(async move { Ok(self.inode(Self::node_inode(node)?).await?.metadata()) })
+    }
+
+    fn read_dir(&self, dir: NodeId) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            let mut listing = Vec::new();
+            for entry in self.entries(dir).await? {
+                let metadata = self.inode(entry.inode).await?.metadata();
+                listing.push(DirEntry {
+                    name: entry.name,
+                    node: entry.inode.into(),
+                    kind: metadata.kind,
+                    size: metadata.size,
+                });
+            }
+            Ok(listing)
+        })
+    }
+
+    /// Reads a regular file. Other files that aren't directories, like symbolic links and devices, are
+    /// listed as files but can't be read.
+    fn read<'a>(&'a self, node: NodeId, offset: u64, buffer: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let inode = self.inode(Self::node_inode(node)?).await?;
+            match inode.mode & MODE_TYPE {
+                MODE_REGULAR => {}
+                MODE_DIRECTORY => return Err(FsError::IsADirectory),
+                _ => return Err(FsError::Unsupported),
+            }
+            if offset >= inode.size {
+                return Ok(0);
+            }
+            let len = (inode.size - offset).min(buffer.len() as u64) as usize;
+
+            let block_size = u64::from(self.superblock.block_size);
+            let mut map = BlockMap::new(self, &inode);
+            let mut block = vec![0; block_size as usize];
+            let mut done = 0;
+            while done < len {
+                let position = offset + done as u64;
+                let start = (position % block_size) as usize;
+                let part = (block_size as usize - start).min(len - done);
+                let out = &mut buffer[done..done + part];
+
+                match map.get(position / block_size).await? {
+                    0 => out.fill(0),
+                    number if part == block.len() => self.read_blocks(number, out).await?,
+                    number => {
+                        self.read_blocks(number, &mut block).await?;
+                        out.copy_from_slice(&block[start..start + part]);
+                    }
+                }
+                done += part;
+            }
+            Ok(len)
+        })
+    }
+}
+
+#[test_case]
+fn test_parse_superblock() {
+    let mut bytes = [0u8; SUPERBLOCK_SIZE];
+    bytes[0..4].copy_from_slice(&64u32.to_le_bytes());
+    bytes[4..8].copy_from_slice(&8192u32.to_le_bytes());
+    bytes[20..24].copy_from_slice(&1u32.to_le_bytes());
+    bytes[32..36].copy_from_slice(&8192u32.to_le_bytes());
+    bytes[40..44].copy_from_slice(&64u32.to_le_bytes());
+    bytes[56..58].copy_from_slice(&MAGIC.to_le_bytes());
+
+    // Revision 0 has fixed inodes and no features.
+    let superblock = Superblock::parse(&bytes).unwrap();
+    assert_eq!(superblock.block_size, 1024);
+    assert_eq!(superblock.inode_size, 128);
+    assert_eq!(superblock.groups(), 1);
+
+    bytes[24] = 2;
+    bytes[76] = 1;
+    bytes[88..90].copy_from_slice(&256u16.to_le_bytes());
+    bytes[96..100].copy_from_slice(&INCOMPAT_FILETYPE.to_le_bytes());
+    let superblock = Superblock::parse(&bytes).unwrap();
+    assert_eq!(superblock.block_size, 4096);
+    assert_eq!(superblock.inode_size, 256);
+
+    // Extents, from ext4.
+    bytes[96..100].copy_from_slice(&(INCOMPAT_FILETYPE | 0x40).to_le_bytes());
+    assert_eq!(Superblock::parse(&bytes), Err(FsError::Unsupported));
+}
+
+#[test_case]
+fn test_locate_blocks() {
+    assert_eq!(locate(0, 256), Some((0, 0, 0)));
+    assert_eq!(locate(11, 256), Some((11, 0, 0)));
+    assert_eq!(locate(12, 256), Some((12, 1, 0)));
+    assert_eq!(locate(12 + 255, 256), Some((12, 1, 255)));
+    assert_eq!(locate(12 + 256, 256), Some((13, 2, 0)));
+    assert_eq!(locate(12 + 256 + 65536, 256), Some((14, 3, 0)));
+    assert_eq!(locate(12 + 256 + 65536 + 256 * 65536, 256), None);
+}
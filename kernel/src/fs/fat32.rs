@@ -0,0 +1,1235 @@
+//! FAT32: the filesystem of USB sticks and memory cards, which every OS reads and writes.
+//!
+//! A volume starts with a boot sector describing its layout: reserved sectors, copies of the FAT (file
+//! allocation table), then the data area, split into clusters of a few sectors. The FAT has an entry per
+//! cluster holding the next cluster of the file it belongs to, an end of chain marker, or 0 if the
+//! cluster is free, so a file is a chain of clusters. A directory is a file of 32-byte entries, each with
+//! an 8.3 short name, attributes, the first cluster and the size of a file. Longer names are stored in
+//! the entries before the short one, 13 UTF-16 units each, with a checksum of the short name.
+//!
+//! Nodes are named by where their short entry is on the volume, in bytes, and the root directory, which
+//! has no entry, by 0. Every operation holds the same lock, so that nothing sees a change to the FAT or
+//! a directory half done. The free cluster count of the FSInfo sector is kept up to date for other
+//! systems, which trust it.
+
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsFuture, MAX_NAME, Metadata, NodeId};
+use crate::{rtc, storage::BlockDevice, task::sync::Mutex};
+
+const BOOT_SIGNATURE: u16 = 0xaa55;
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+/// What the FSInfo sector holds for a count or a cluster it doesn't know.
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+/// The bits of a FAT entry that are used; the top 4 are reserved.
+const CLUSTER_MASK: u32 = 0x0fff_ffff;
+/// Entries from this one on end a chain.
+const CLUSTER_END: u32 = 0x0fff_fff8;
+const FIRST_CLUSTER: u32 = 2;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_FREE: u8 = 0xe5;
+/// Stands for 0xe5 as the first byte of a short name, which would mark the entry free.
+const ENTRY_KANJI: u8 = 0x05;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// The attributes of a long name entry, which no short entry has.
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// Flags in the reserved byte of short entries, set by Windows NT for names that are all lowercase.
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXTENSION: u8 = 0x10;
+
+/// Set in the order of the long name entry holding the end of the name, which comes first.
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// Where the UTF-16 units of a long name entry are.
+const LONG_NAME_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const LONG_NAME_UNITS: usize = LONG_NAME_OFFSETS.len();
+
+/// Characters besides letters and digits allowed in short names.
+const SHORT_NAME_SPECIAL: &str = "$%'-_@~`!(){}^#&";
+/// Characters not allowed in long names either, besides control characters.
+const INVALID_CHARACTERS: &str = "\"*/:<>?\\|";
+
+const ROOT: NodeId = 0;
+
+/// The layout of a volume, from its boot sector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Volume {
+    pub bytes_per_sector: u32,
+    pub sectors_per_cluster: u32,
+    pub reserved_sectors: u32,
+    pub fats: u32,
+    pub sectors_per_fat: u32,
+    pub total_sectors: u32,
+    pub root_cluster: u32,
+    pub fsinfo_sector: Option<u32>,
+    /// The number of clusters in the data area.
+    pub clusters: u32,
+}
+
+impl Volume {
+    /// Reads the boot sector. Fails with `FsError::Unsupported` if it isn't a FAT32 volume.
+    pub fn parse(boot: &[u8]) -> Result<Volume, FsError> {
+        if boot.len() < 512 {
+            return Err(FsError::Unsupported);
+        }
+        let half = |offset: usize| u16::from_le_bytes([boot[offset], boot[offset + 1]]);
+        let word = |offset: usize| u32::from_le_bytes(boot[offset..offset + 4].try_into().unwrap());
+
+        let bytes_per_sector = u32::from(half(11));
+        let sectors_per_cluster = u32::from(boot[13]);
+        let reserved_sectors = u32::from(half(14));
+        let fats = u32::from(boot[16]);
+        // FAT12 and FAT16 have a fixed root directory and the size of their FAT here.
+        let fixed_root_entries = half(17);
+        let sectors_per_fat_16 = half(22);
+        if half(510) != BOOT_SIGNATURE
+            || !(512..=4096).contains(&bytes_per_sector)
+            || !bytes_per_sector.is_power_of_two()
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fats == 0
+            || fixed_root_entries != 0
+            || sectors_per_fat_16 != 0
+        {
+            return Err(FsError::Unsupported);
+        }
+        // Only version 0.0 exists.
+        if half(42) != 0 {
+            return Err(FsError::Unsupported);
+        }
+
+        let total_sectors = match half(19) {
+            0 => word(32),
+            sectors => sectors.into(),
+        };
+        let sectors_per_fat = word(36);
+        let root_cluster = word(44);
+        let fsinfo_sector = match half(48) {
+            0 | 0xffff => None,
+            sector => Some(sector.into()),
+        };
+
+        let data_start = u64::from(reserved_sectors) + u64::from(fats) * u64::from(sectors_per_fat);
+        if sectors_per_fat == 0 || data_start >= total_sectors.into() {
+            return Err(FsError::Corrupt);
+        }
+        let data_clusters =
+            (u64::from(total_sectors) - data_start) / u64::from(sectors_per_cluster);
+        // The FAT may have room for fewer clusters than the data area, and its first two entries are
+        // reserved.
+        let fat_clusters = u64::from(sectors_per_fat) * u64::from(bytes_per_sector) / 4 - 2;
+        let clusters = data_clusters.min(fat_clusters).min(CLUSTER_MASK.into()) as u32;
+
+        let volume = Volume {
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            fats,
+            sectors_per_fat,
+            total_sectors,
+            root_cluster,
+            fsinfo_sector,
+            clusters,
+        };
+        if !volume.is_data_cluster(root_cluster) {
+            return Err(FsError::Corrupt);
+        }
+        Ok(volume)
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        (self.bytes_per_sector * self.sectors_per_cluster) as usize
+    }
+
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..FIRST_CLUSTER + self.clusters).contains(&cluster)
+    }
+
+    /// The first sector of `cluster`.
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        let data_start = u64::from(self.reserved_sectors)
+            + u64::from(self.fats) * u64::from(self.sectors_per_fat);
+        data_start + u64::from(cluster - FIRST_CLUSTER) * u64::from(self.sectors_per_cluster)
+    }
+
+    /// The sector of FAT copy `fat` holding the entry of `cluster`, and where the entry is in it.
+    fn fat_sector(&self, fat: u32, cluster: u32) -> (u64, usize) {
+        let byte = u64::from(cluster) * 4;
+        let sector = u64::from(self.reserved_sectors)
+            + u64::from(fat) * u64::from(self.sectors_per_fat)
+            + byte / u64::from(self.bytes_per_sector);
+        (sector, (byte % u64::from(self.bytes_per_sector)) as usize)
+    }
+}
+
+/// A short directory entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    name: [u8; 11],
+    attributes: u8,
+    /// The lowercase flags.
+    case: u8,
+    first_cluster: u32,
+    size: u32,
+}
+
+impl Entry {
+    fn parse(bytes: &[u8]) -> Entry {
+        let half =
+            |offset: usize| u32::from(u16::from_le_bytes([bytes[offset], bytes[offset + 1]]));
+        Entry {
+            name: bytes[..11].try_into().unwrap(),
+            attributes: bytes[11],
+            case: bytes[12],
+            first_cluster: half(20) << 16 | half(26),
+            size: u32::from_le_bytes(bytes[28..32].try_into().unwrap()),
+        }
+    }
+
+    /// Writes the entry over `bytes`, keeping its timestamps.
+    fn write(&self, bytes: &mut [u8]) {
+        bytes[..11].copy_from_slice(&self.name);
+        bytes[11] = self.attributes;
+        bytes[12] = self.case;
+        bytes[20..22].copy_from_slice(&((self.first_cluster >> 16) as u16).to_le_bytes());
+        bytes[26..28].copy_from_slice(&(self.first_cluster as u16).to_le_bytes());
+        bytes[28..32].copy_from_slice(&self.size.to_le_bytes());
+    }
+
+    fn kind(&self) -> FileType {
+        if self.attributes & ATTR_DIRECTORY != 0 {
+            FileType::Directory
+        } else {
+            FileType::File
+        }
+    }
+
+    /// The short name as it is shown, e.g. `README.TXT`.
+    fn display_name(&self) -> String {
+        let mut name = self.name;
+        if name[0] == ENTRY_KANJI {
+            name[0] = ENTRY_FREE;
+        }
+        let part = |bytes: &[u8], lowercase: bool| {
+            bytes
+                .iter()
+                .rev()
+                .skip_while(|&&byte| byte == b' ')
+                .collect::<Vec<_>>()
+                .into_iter()
+                .rev()
+                .map(|&byte| match lowercase {
+                    true => char::from(byte.to_ascii_lowercase()),
+                    false => char::from(byte),
+                })
+                .collect::<String>()
+        };
+
+        let mut display = part(&name[..8], self.case & LOWERCASE_BASE != 0);
+        let extension = part(&name[8..], self.case & LOWERCASE_EXTENSION != 0);
+        if !extension.is_empty() {
+            display.push('.');
+            display.push_str(&extension);
+        }
+        display
+    }
+}
+
+/// Stamps a short entry with the current time, as created too if `created`.
+fn stamp(bytes: &mut [u8], created: bool) {
+    let now = rtc::read();
+    let date =
+        (now.year.saturating_sub(1980) << 9) | u16::from(now.month) << 5 | u16::from(now.day);
+    let time = u16::from(now.hour) << 11 | u16::from(now.minute) << 5 | u16::from(now.second / 2);
+    if created {
+        bytes[13] = (now.second % 2) * 100;
+        bytes[14..16].copy_from_slice(&time.to_le_bytes());
+        bytes[16..18].copy_from_slice(&date.to_le_bytes());
+    }
+    bytes[18..20].copy_from_slice(&date.to_le_bytes());
+    bytes[22..24].copy_from_slice(&time.to_le_bytes());
+    bytes[24..26].copy_from_slice(&date.to_le_bytes());
+}
+
+/// The checksum of a short name that its long name entries carry.
+fn checksum(name: &[u8; 11]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+fn is_short_name_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || SHORT_NAME_SPECIAL.contains(c)
+}
+
+/// `name` as a short name, if it is one as it is: an uppercase 8.3 name.
+fn short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    if !(1..=8).contains(&base.len())
+        || extension.len() > 3
+        || !base
+            .chars()
+            .chain(extension.chars())
+            .all(is_short_name_char)
+    {
+        return None;
+    }
+
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    Some(short)
+}
+
+/// The short name a long name gets before it is numbered: what is left of it in uppercase, without
+/// spaces and dots, and with other characters short names don't allow replaced with `_`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Basis {
+    base: [u8; 8],
+    base_len: usize,
+    extension: [u8; 3],
+}
+
+impl Basis {
+    fn new(name: &str) -> Basis {
+        let name = name.trim_start_matches('.');
+        let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+        let convert = |c: char| match c.to_ascii_uppercase() {
+            ' ' | '.' => None,
+            c if is_short_name_char(c) => Some(c as u8),
+            _ => Some(b'_'),
+        };
+
+        let mut basis = Basis {
+            base: [b' '; 8],
+            base_len: 0,
+            extension: [b' '; 3],
+        };
+        for (slot, byte) in basis.base.iter_mut().zip(base.chars().filter_map(convert)) {
+            *slot = byte;
+            basis.base_len += 1;
+        }
+        for (slot, byte) in basis
+            .extension
+            .iter_mut()
+            .zip(extension.chars().filter_map(convert))
+        {
+            *slot = byte;
+        }
+        if basis.base_len == 0 {
+            basis.base[0] = b'_';
+            basis.base_len = 1;
+        }
+        basis
+    }
+
+    /// The short name with `~number` after as much of the base as fits, e.g. `LONGFI~1TXT`.
+    fn numbered(&self, number: u32) -> [u8; 11] {
+        let mut digits = [0; 10];
+        let mut count = 0;
+        let mut rest = number;
+        loop {
+            digits[count] = b'0' + (rest % 10) as u8;
+            count += 1;
+            rest /= 10;
+            if rest == 0 {
+                break;
+            }
+        }
+
+        let keep = self.base_len.min(8 - 1 - count);
+        let mut name = [b' '; 11];
+        name[..keep].copy_from_slice(&self.base[..keep]);
+        name[keep] = b'~';
+        for (slot, digit) in name[keep + 1..]
+            .iter_mut()
+            .zip(digits[..count].iter().rev())
+        {
+            *slot = *digit;
+        }
+        name[8..].copy_from_slice(&self.extension);
+        name
+    }
+}
+
+/// Whether a file may be called `name`.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name.encode_utf16().count() <= MAX_NAME
+        && !name.ends_with(['.', ' '])
+        && !name
+            .chars()
+            .any(|c| c < ' ' || INVALID_CHARACTERS.contains(c))
+}
+
+/// The long name entries for `name`, in the order they are on the disk.
+fn long_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY_SIZE]> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LONG_NAME_UNITS);
+
+    (1..=count)
+        .rev()
+        .map(|order| {
+            let mut entry = [0; ENTRY_SIZE];
+            entry[0] = order as u8 | if order == count { LAST_LONG_ENTRY } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                let index = (order - 1) * LONG_NAME_UNITS + i;
+                // The name ends with a null unit if it doesn't fill the entry, and 0xffff pads the rest.
+                let unit = match index.cmp(&units.len()) {
+                    core::cmp::Ordering::Less => units[index],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xffff,
+                };
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+/// A file or directory found in a directory.
+#[derive(Debug, Clone)]
+struct Found {
+    name: String,
+    entry: Entry,
+    /// The slot of the first long name entry, or of the short entry if there are none.
+    first_slot: usize,
+    /// The slot of the short entry.
+    slot: usize,
+}
+
+impl Found {
+    /// Whether `name` is its long or its short name, ignoring the case of ASCII letters like Windows.
+    fn is_called(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.entry.display_name().eq_ignore_ascii_case(name)
+    }
+}
+
+/// The files and directories in the slots of a directory, without `.`, `..` and volume labels.
+fn parse_directory(data: &[u8]) -> Vec<Found> {
+    struct LongName {
+        units: Vec<u16>,
+        checksum: u8,
+        /// The order of the entry expected next; 0 once all were seen.
+        next: u8,
+        first_slot: usize,
+    }
+
+    let mut found = Vec::new();
+    let mut long: Option<LongName> = None;
+
+    for (slot, bytes) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        match bytes[0] {
+            ENTRY_END => break,
+            ENTRY_FREE => {
+                long = None;
+                continue;
+            }
+            _ => {}
+        }
+
+        if bytes[11] & 0x3f == ATTR_LONG_NAME {
+            let order = bytes[0] & 0x1f;
+            let units = |long: &mut LongName| {
+                let start = usize::from(order - 1) * LONG_NAME_UNITS;
+                for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                    long.units[start + i] = u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+                }
+                long.next = order - 1;
+            };
+            if bytes[0] & LAST_LONG_ENTRY != 0 && (1..=20).contains(&order) {
+                let mut started = LongName {
+                    units: vec![0xffff; usize::from(order) * LONG_NAME_UNITS],
+                    checksum: bytes[13],
+                    next: order,
+                    first_slot: slot,
+                };
+                units(&mut started);
+                long = Some(started);
+            } else if let Some(current) = &mut long
+                && order != 0
+                && order == current.next
+                && bytes[13] == current.checksum
+            {
+                units(current);
+            } else {
+                long = None;
+            }
+            continue;
+        }
+
+        let entry = Entry::parse(bytes);
+        let long = long.take();
+        if entry.attributes & ATTR_VOLUME_ID != 0 || entry.name[0] == b'.' {
+            continue;
+        }
+        let (name, first_slot) = match long {
+            Some(long) if long.next == 0 && long.checksum == checksum(&entry.name) => {
+                let end = long.units.iter().position(|&unit| unit == 0);
+                let units = &long.units[..end.unwrap_or(long.units.len())];
+                let name = char::decode_utf16(units.iter().copied())
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect();
+                (name, long.first_slot)
+            }
+            _ => (entry.display_name(), slot),
+        };
+        found.push(Found {
+            name,
+            entry,
+            first_slot,
+            slot,
+        });
+    }
+    found
+}
+
+/// What is known about the free clusters.
+struct Allocation {
+    /// From the FSInfo sector, if it has a count.
+    free: Option<u32>,
+    /// Where the search for a free cluster starts.
+    next: u32,
+    /// Whether the FSInfo sector needs to be written.
+    dirty: bool,
+}
+
+/// A directory read into memory.
+struct Directory {
+    clusters: Vec<u32>,
+    data: Vec<u8>,
+}
+
+/// A mounted FAT32 volume.
+pub struct Fat32<D> {
+    device: D,
+    volume: Volume,
+    blocks_per_sector: u64,
+    allocation: Mutex<Allocation>,
+}
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Mounts the volume on `device`, which starts at its first block. Fails with
+    /// `FsError::Unsupported` if it isn't a FAT32 volume.
+    pub async fn mount(device: D) -> Result<Self, FsError> {
+        let block_size = device.block_size();
+        let mut boot = vec![0; 512usize.div_ceil(block_size) * block_size];
+        device.read_blocks(0, &mut boot).await?;
+        let volume = Volume::parse(&boot)?;
+
+        if !(volume.bytes_per_sector as usize).is_multiple_of(block_size) {
+            return Err(FsError::Unsupported);
+        }
+        let blocks_per_sector = (volume.bytes_per_sector as usize / block_size) as u64;
+        if u64::from(volume.total_sectors) * blocks_per_sector > device.num_blocks() {
+            return Err(FsError::Corrupt);
+        }
+
+        let fat = Fat32 {
+            device,
+            volume,
+            blocks_per_sector,
+            allocation: Mutex::new(Allocation {
+                free: None,
+                next: FIRST_CLUSTER,
+                dirty: false,
+            }),
+        };
+        if let Some((free, next)) = fat.read_fsinfo().await? {
+            let mut allocation = fat.allocation.lock().await;
+            allocation.free = (free <= volume.clusters).then_some(free);
+            if volume.is_data_cluster(next) {
+                allocation.next = next;
+            }
+        }
+        Ok(fat)
+    }
+
+    pub fn volume(&self) -> &Volume {
+        &self.volume
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// The number of free clusters, if the FSInfo sector has it.
+    pub async fn free_clusters(&self) -> Option<u32> {
+        self.allocation.lock().await.free
+    }
+
+    fn sector_size(&self) -> usize {
+        self.volume.bytes_per_sector as usize
+    }
+
+    async fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        Ok(self
+            .device
+            .read_blocks(sector * self.blocks_per_sector, buffer)
+            .await?)
+    }
+
+    async fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), FsError> {
+        Ok(self
+            .device
+            .write_blocks(sector * self.blocks_per_sector, buffer)
+            .await?)
+    }
+
+    /// The free cluster count and the next free cluster from the FSInfo sector, if it is valid.
+    async fn read_fsinfo(&self) -> Result<Option<(u32, u32)>, FsError> {
+        let Some(sector) = self.volume.fsinfo_sector else {
+            return Ok(None);
+        };
+        let mut info = vec![0; self.sector_size()];
+        self.read_sectors(sector.into(), &mut info).await?;
+        let word = |offset: usize| u32::from_le_bytes(info[offset..offset + 4].try_into().unwrap());
+        if word(0) != FSINFO_LEAD_SIGNATURE || word(484) != FSINFO_STRUCT_SIGNATURE {
+            return Ok(None);
+        }
+        Ok(Some((word(488), word(492))))
+    }
+
+    /// Writes the free cluster count to the FSInfo sector, if it changed.
+    async fn sync_fsinfo(&self, allocation: &mut Allocation) -> Result<(), FsError> {
+        let Some(sector) = self.volume.fsinfo_sector else {
+            return Ok(());
+        };
+        if !allocation.dirty || self.read_fsinfo().await?.is_none() {
+            return Ok(());
+        }
+
+        let mut info = vec![0; self.sector_size()];
+        self.read_sectors(sector.into(), &mut info).await?;
+        let free = allocation.free.unwrap_or(FSINFO_UNKNOWN);
+        info[488..492].copy_from_slice(&free.to_le_bytes());
+        info[492..496].copy_from_slice(&allocation.next.to_le_bytes());
+        self.write_sectors(sector.into(), &info).await?;
+        allocation.dirty = false;
+        Ok(())
+    }
+
+    /// Sets the FAT entry of `cluster` in every copy of the FAT.
+    async fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let mut buffer = vec![0; self.sector_size()];
+        for fat in 0..self.volume.fats {
+            let (sector, offset) = self.volume.fat_sector(fat, cluster);
+            self.read_sectors(sector, &mut buffer).await?;
+            let entry = &mut buffer[offset..offset + 4];
+            let reserved = u32::from_le_bytes((*entry).try_into().unwrap()) & !CLUSTER_MASK;
+            entry.copy_from_slice(&(reserved | value & CLUSTER_MASK).to_le_bytes());
+            self.write_sectors(sector, &buffer).await?;
+        }
+        Ok(())
+    }
+
+    /// The clusters of the chain starting at `first`, none if it is 0.
+    async fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut clusters = Vec::new();
+        if first == 0 {
+            return Ok(clusters);
+        }
+
+        let mut buffer = vec![0; self.sector_size()];
+        let mut loaded = None;
+        let mut cluster = first;
+        loop {
+            // A chain longer than the volume loops.
+            if !self.volume.is_data_cluster(cluster)
+                || clusters.len() >= self.volume.clusters as usize
+            {
+                return Err(FsError::Corrupt);
+            }
+            clusters.push(cluster);
+
+            let (sector, offset) = self.volume.fat_sector(0, cluster);
+            if loaded != Some(sector) {
+                self.read_sectors(sector, &mut buffer).await?;
+                loaded = Some(sector);
+            }
+            let next = u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap());
+            match next & CLUSTER_MASK {
+                next if next >= CLUSTER_END => return Ok(clusters),
+                next => cluster = next,
+            }
+        }
+    }
+
+    /// Finds a free cluster, zeroes it and ends a chain with it, after `previous` if there is one.
+    async fn allocate(
+        &self,
+        allocation: &mut Allocation,
+        previous: Option<u32>,
+    ) -> Result<u32, FsError> {
+        let mut buffer = vec![0; self.sector_size()];
+        let mut loaded = None;
+        let mut found = None;
+        for i in 0..self.volume.clusters {
+            let cluster =
+                FIRST_CLUSTER + (allocation.next - FIRST_CLUSTER + i) % self.volume.clusters;
+            let (sector, offset) = self.volume.fat_sector(0, cluster);
+            if loaded != Some(sector) {
+                self.read_sectors(sector, &mut buffer).await?;
+                loaded = Some(sector);
+            }
+            if u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap()) & CLUSTER_MASK
+                == 0
+            {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(FsError::NoSpace)?;
+
+        let zeros = vec![0; self.volume.cluster_size()];
+        self.write_sectors(self.volume.cluster_sector(cluster), &zeros)
+            .await?;
+        self.set_fat_entry(cluster, CLUSTER_MASK).await?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster).await?;
+        }
+
+        allocation.next = if self.volume.is_data_cluster(cluster + 1) {
+            cluster + 1
+        } else {
+            FIRST_CLUSTER
+        };
+        allocation.free = allocation.free.map(|free| free.saturating_sub(1));
+        allocation.dirty = true;
+        Ok(cluster)
+    }
+
+    /// Marks `clusters` free.
+    async fn free(&self, allocation: &mut Allocation, clusters: &[u32]) -> Result<(), FsError> {
+        for &cluster in clusters {
+            self.set_fat_entry(cluster, 0).await?;
+        }
+        allocation.free = allocation
+            .free
+            .map(|free| (free + clusters.len() as u32).min(self.volume.clusters));
+        allocation.dirty = true;
+        Ok(())
+    }
+
+    /// Calls `f` with the sector and the range in it of each part of the `len` bytes at `offset` of the
+    /// data in `clusters`, and where the part is in those bytes.
+    fn sectors(
+        &self,
+        clusters: &[u32],
+        offset: u64,
+        len: usize,
+    ) -> impl Iterator<Item = (u64, core::ops::Range<usize>, usize)> + use<'_, D> {
+        let cluster_size = self.volume.cluster_size() as u64;
+        let sector_size = self.sector_size();
+        let clusters = clusters.to_vec();
+        let mut done = 0;
+
+        core::iter::from_fn(move || {
+            if done >= len {
+                return None;
+            }
+            let position = offset + done as u64;
+            let cluster = clusters[(position / cluster_size) as usize];
+            let within = position % cluster_size;
+            let sector = self.volume.cluster_sector(cluster) + within / sector_size as u64;
+            let start = (within % sector_size as u64) as usize;
+            let part = (sector_size - start).min(len - done);
+
+            let item = (sector, start..start + part, done);
+            done += part;
+            Some(item)
+        })
+    }
+
+    /// Reads `buffer.len()` bytes at `offset` of the data in `clusters`.
+    async fn read_data(
+        &self,
+        clusters: &[u32],
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<(), FsError> {
+        let mut sector_buffer = vec![0; self.sector_size()];
+        for (sector, range, at) in self.sectors(clusters, offset, buffer.len()) {
+            let part = &mut buffer[at..at + range.len()];
+            if range.len() == sector_buffer.len() {
+                self.read_sectors(sector, part).await?;
+            } else {
+                self.read_sectors(sector, &mut sector_buffer).await?;
+                part.copy_from_slice(&sector_buffer[range]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at `offset` of the data in `clusters`.
+    async fn write_data(&self, clusters: &[u32], offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let mut sector_buffer = vec![0; self.sector_size()];
+        for (sector, range, at) in self.sectors(clusters, offset, data.len()) {
+            let part = &data[at..at + range.len()];
+            if range.len() == sector_buffer.len() {
+                self.write_sectors(sector, part).await?;
+            } else {
+                self.read_sectors(sector, &mut sector_buffer).await?;
+                sector_buffer[range].copy_from_slice(part);
+                self.write_sectors(sector, &sector_buffer).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `len` zeros at `offset` of the data in `clusters`.
+    async fn zero_data(&self, clusters: &[u32], offset: u64, len: u64) -> Result<(), FsError> {
+        let zeros = vec![0; self.volume.cluster_size()];
+        let mut done = 0;
+        while done < len {
+            let part = (len - done).min(zeros.len() as u64);
+            self.write_data(clusters, offset + done, &zeros[..part as usize])
+                .await?;
+            done += part;
+        }
+        Ok(())
+    }
+
+    /// The short entry of `node`, made up for the root directory.
+    async fn entry(&self, node: NodeId) -> Result<Entry, FsError> {
+        if node == ROOT {
+            return Ok(Entry {
+                name: [b' '; 11],
+                attributes: ATTR_DIRECTORY,
+                case: 0,
+                first_cluster: self.volume.root_cluster,
+                size: 0,
+            });
+        }
+        let mut buffer = vec![0; self.sector_size()];
+        let (sector, offset) = self.entry_location(node);
+        self.read_sectors(sector, &mut buffer).await?;
+        Ok(Entry::parse(&buffer[offset..offset + ENTRY_SIZE]))
+    }
+
+    fn entry_location(&self, node: NodeId) -> (u64, usize) {
+        let sector_size = self.sector_size() as u64;
+        (node / sector_size, (node % sector_size) as usize)
+    }
+
+    /// Writes `entry` as the short entry of `node`, stamped with the time of the change.
+    async fn update_entry(&self, node: NodeId, entry: &Entry) -> Result<(), FsError> {
+        let mut buffer = vec![0; self.sector_size()];
+        let (sector, offset) = self.entry_location(node);
+        self.read_sectors(sector, &mut buffer).await?;
+        let bytes = &mut buffer[offset..offset + ENTRY_SIZE];
+        entry.write(bytes);
+        stamp(bytes, false);
+        self.write_sectors(sector, &buffer).await
+    }
+
+    /// The first cluster of directory `node`.
+    async fn directory_cluster(&self, node: NodeId) -> Result<u32, FsError> {
+        let entry = self.entry(node).await?;
+        if entry.kind() != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        // `..` entries name the root directory with 0.
+        Ok(match entry.first_cluster {
+            0 => self.volume.root_cluster,
+            cluster => cluster,
+        })
+    }
+
+    async fn read_directory(&self, first: u32) -> Result<Directory, FsError> {
+        let clusters = self.chain(first).await?;
+        let mut data = vec![0; clusters.len() * self.volume.cluster_size()];
+        self.read_data(&clusters, 0, &mut data).await?;
+        Ok(Directory { clusters, data })
+    }
+
+    /// Where slot `slot` of `directory` is on the volume, which names the node whose short entry it is.
+    fn slot_node(&self, directory: &Directory, slot: usize) -> NodeId {
+        let per_cluster = self.volume.cluster_size() / ENTRY_SIZE;
+        let cluster = directory.clusters[slot / per_cluster];
+        self.volume.cluster_sector(cluster) * self.sector_size() as u64
+            + ((slot % per_cluster) * ENTRY_SIZE) as u64
+    }
+
+    /// Writes slots `slots` of `directory` from its data.
+    async fn write_slots(
+        &self,
+        directory: &Directory,
+        slots: core::ops::Range<usize>,
+    ) -> Result<(), FsError> {
+        let bytes = slots.start * ENTRY_SIZE..slots.end * ENTRY_SIZE;
+        self.write_data(
+            &directory.clusters,
+            bytes.start as u64,
+            &directory.data[bytes],
+        )
+        .await
+    }
+
+    async fn find(&self, dir: NodeId, name: &str) -> Result<(Directory, Found), FsError> {
+        let directory = self
+            .read_directory(self.directory_cluster(dir).await?)
+            .await?;
+        let found = parse_directory(&directory.data)
+            .into_iter()
+            .find(|found| found.is_called(name))
+            .ok_or(FsError::NotFound)?;
+        Ok((directory, found))
+    }
+
+    async fn write_locked(
+        &self,
+        allocation: &mut Allocation,
+        node: NodeId,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, FsError> {
+        let mut entry = self.entry(node).await?;
+        if node == ROOT || entry.kind() == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let end = offset + data.len() as u64;
+        if end > u32::MAX.into() {
+            return Err(FsError::TooLarge);
+        }
+
+        let mut clusters = self.chain(entry.first_cluster).await?;
+        let needed = end.div_ceil(self.volume.cluster_size() as u64) as usize;
+        while clusters.len() < needed {
+            let cluster = self.allocate(allocation, clusters.last().copied()).await?;
+            clusters.push(cluster);
+        }
+        if let Some(&first) = clusters.first() {
+            entry.first_cluster = first;
+        }
+
+        let size = u64::from(entry.size);
+        if offset > size {
+            self.zero_data(&clusters, size, offset - size).await?;
+        }
+        self.write_data(&clusters, offset, data).await?;
+
+        entry.size = size.max(end) as u32;
+        self.update_entry(node, &entry).await?;
+        self.sync_fsinfo(allocation).await?;
+        Ok(data.len())
+    }
+
+    async fn truncate_locked(
+        &self,
+        allocation: &mut Allocation,
+        node: NodeId,
+        len: u64,
+    ) -> Result<(), FsError> {
+        let mut entry = self.entry(node).await?;
+        if node == ROOT || entry.kind() == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        if len > u64::from(entry.size) {
+            return self
+                .write_locked(allocation, node, len, &[])
+                .await
+                .map(|_| ());
+        }
+
+        let clusters = self.chain(entry.first_cluster).await?;
+        let keep = (len.div_ceil(self.volume.cluster_size() as u64) as usize).min(clusters.len());
+        if keep < clusters.len() {
+            match keep {
+                0 => entry.first_cluster = 0,
+                keep => self.set_fat_entry(clusters[keep - 1], CLUSTER_MASK).await?,
+            }
+            self.free(allocation, &clusters[keep..]).await?;
+        }
+        entry.size = len as u32;
+        self.update_entry(node, &entry).await?;
+        self.sync_fsinfo(allocation).await
+    }
+
+    async fn create_locked(
+        &self,
+        allocation: &mut Allocation,
+        dir: NodeId,
+        name: &str,
+        kind: FileType,
+    ) -> Result<NodeId, FsError> {
+        if !is_valid_name(name) {
+            return Err(FsError::InvalidName);
+        }
+        let dir_cluster = self.directory_cluster(dir).await?;
+        let mut directory = self.read_directory(dir_cluster).await?;
+        let existing = parse_directory(&directory.data);
+        if existing.iter().any(|found| found.is_called(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        // A name that is a short name as it is needs no long one.
+        let taken = |short: &[u8; 11]| existing.iter().any(|found| &found.entry.name == short);
+        let (short, long) = match short_name(name) {
+            Some(short) if !taken(&short) => (short, Vec::new()),
+            _ => {
+                let basis = Basis::new(name);
+                let short = (1..=999_999)
+                    .map(|number| basis.numbered(number))
+                    .find(|short| !taken(short))
+                    .ok_or(FsError::NoSpace)?;
+                (short, long_entries(name, checksum(&short)))
+            }
+        };
+
+        // Free slots for the long entries and the short one, in a row, growing the directory if there
+        // aren't.
+        let needed = long.len() + 1;
+        let first_slot = loop {
+            let mut run = 0;
+            let free = directory.data.chunks_exact(ENTRY_SIZE).position(|slot| {
+                run = if matches!(slot[0], ENTRY_FREE | ENTRY_END) {
+                    run + 1
+                } else {
+                    0
+                };
+                run == needed
+            });
+            if let Some(last) = free {
+                break last + 1 - needed;
+            }
+            let cluster = self
+                .allocate(allocation, directory.clusters.last().copied())
+                .await?;
+            directory.clusters.push(cluster);
+            directory
+                .data
+                .resize(directory.data.len() + self.volume.cluster_size(), 0);
+        };
+
+        let mut entry = Entry {
+            name: short,
+            attributes: match kind {
+                FileType::File => ATTR_ARCHIVE,
+                FileType::Directory => ATTR_DIRECTORY,
+            },
+            case: 0,
+            first_cluster: 0,
+            size: 0,
+        };
+        if kind == FileType::Directory {
+            entry.first_cluster = self.allocate(allocation, None).await?;
+            let mut dots = [0; 2 * ENTRY_SIZE];
+            let parent = if dir_cluster == self.volume.root_cluster {
+                0
+            } else {
+                dir_cluster
+            };
+            for (bytes, (name, cluster)) in dots.chunks_exact_mut(ENTRY_SIZE).zip([
+                (*b".          ", entry.first_cluster),
+                (*b"..         ", parent),
+            ]) {
+                let dot = Entry {
+                    name,
+                    first_cluster: cluster,
+                    ..entry
+                };
+                dot.write(bytes);
+                stamp(bytes, true);
+            }
+            self.write_data(&[entry.first_cluster], 0, &dots).await?;
+        }
+
+        let slot = first_slot + long.len();
+        for (i, bytes) in long.iter().enumerate() {
+            let at = (first_slot + i) * ENTRY_SIZE;
+            directory.data[at..at + ENTRY_SIZE].copy_from_slice(bytes);
+        }
+        let bytes = &mut directory.data[slot * ENTRY_SIZE..(slot + 1) * ENTRY_SIZE];
+        bytes.fill(0);
+        entry.write(bytes);
+        stamp(bytes, true);
+        self.write_slots(&directory, first_slot..slot + 1).await?;
+        self.sync_fsinfo(allocation).await?;
+        Ok(self.slot_node(&directory, slot))
+    }
+
+    async fn remove_locked(
+        &self,
+        allocation: &mut Allocation,
+        dir: NodeId,
+        name: &str,
+    ) -> Result<(), FsError> {
+        let (mut directory, found) = self.find(dir, name).await?;
+        let clusters = self.chain(found.entry.first_cluster).await?;
+        if found.entry.kind() == FileType::Directory {
+            let contents = self.read_directory(found.entry.first_cluster).await?;
+            if !parse_directory(&contents.data).is_empty() {
+                return Err(FsError::NotEmpty);
+            }
+        }
+
+        for slot in found.first_slot..=found.slot {
+            directory.data[slot * ENTRY_SIZE] = ENTRY_FREE;
+        }
+        self.write_slots(&directory, found.first_slot..found.slot + 1)
+            .await?;
+        self.free(allocation, &clusters).await?;
+        self.sync_fsinfo(allocation).await
+    }
+}
+
+impl<D: BlockDevice + Send + Sync> FileSystem for Fat32<D> {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> NodeId {
+        ROOT
+    }
+
+    fn lookup<'a>(&'a self, dir: NodeId, name: &'a str) -> FsFuture<'a, NodeId> {
+        Box::pin(async move {
+            let _allocation = self.allocation.lock().await;
+            let (directory, found) = self.find(dir, name).await?;
+            Ok(self.slot_node(&directory, found.slot))
+        })
+    }
+
+    fn metadata(&self, node: NodeId) -> FsFuture<'_, Metadata> {
+        Box::pin(async move {
+            let _allocation = self.allocation.lock().await;
+            let entry = self.entry(node).await?;
+            Ok(Metadata {
+                kind: entry.kind(),
+                size: match entry.kind() {
+                    FileType::File => entry.size.into(),
+                    FileType::Directory => 0,
+                },
+            })
+        })
+    }
+
+    fn read_dir(&self, dir: NodeId) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            let _allocation = self.allocation.lock().await;
+            let directory = self
+                .read_directory(self.directory_cluster(dir).await?)
+                .await?;
+            Ok(parse_directory(&directory.data)
+                .into_iter()
+                .map(|found| DirEntry {
+                    node: self.slot_node(&directory, found.slot),
+                    kind: found.entry.kind(),
+                    size: match found.entry.kind() {
+                        FileType::File => found.entry.size.into(),
+                        FileType::Directory => 0,
+                    },
+                    name: found.name,
+                })
+                .collect())
+        })
+    }
+
+    fn read<'a>(&'a self, node: NodeId, offset: u64, buffer: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let _allocation = self.allocation.lock().await;
+            let entry = self.entry(node).await?;
+            if node == ROOT || entry.kind() == FileType::Directory {
+                return Err(FsError::IsADirectory);
+            }
+            let size = u64::from(entry.size);
+            if offset >= size {
+                return Ok(0);
+            }
+            let len = (size - offset).min(buffer.len() as u64) as usize;
+
+            let clusters = self.chain(entry.first_cluster).await?;
+            if (clusters.len() * self.volume.cluster_size()) < size as usize {
+                return Err(FsError::Corrupt);
+            }
+            self.read_data(&clusters, offset, &mut buffer[..len])
+                .await?;
+            Ok(len)
+        })
+    }
+
+    fn write<'a>(&'a self, node: NodeId, offset: u64, data: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let mut allocation = self.allocation.lock().await;
+            self.write_locked(&mut allocation, node, offset, data).await
+        })
+    }
+
+    fn truncate(&self, node: NodeId, len: u64) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            let mut allocation = self.allocation.lock().await;
+            self.truncate_locked(&mut allocation, node, len).await
+        })
+    }
+
+    fn create<'a>(&'a self, dir: NodeId, name: &'a str, kind: FileType) -> FsFuture<'a, NodeId> {
+        Box::pin(async move {
+            let mut allocation = self.allocation.lock().await;
+            self.create_locked(&mut allocation, dir, name, kind).await
+        })
+    }
+
+    fn remove<'a>(&'a self, dir: NodeId, name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            let mut allocation = self.allocation.lock().await;
+            self.remove_locked(&mut allocation, dir, name).await
+        })
+    }
+}
+
+#[test_case]
+fn test_parse_volume() {
+    let mut boot = [0u8; 512];
+    boot[11..13].copy_from_slice(&512u16.to_le_bytes());
+    boot[13] = 1;
+    boot[14..16].copy_from_slice(&32u16.to_le_bytes());
+    boot[16] = 2;
+    boot[32..36].copy_from_slice(&4096u32.to_le_bytes());
+    boot[36..40].copy_from_slice(&32u32.to_le_bytes());
+    boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+    boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+    boot[510..512].copy_from_slice(&BOOT_SIGNATURE.to_le_bytes());
+
+    let volume = Volume::parse(&boot).unwrap();
+    assert_eq!(volume.clusters, 4096 - 32 - 2 * 32);
+    assert_eq!(volume.fsinfo_sector, Some(1));
+    assert_eq!(volume.cluster_sector(2), 96);
+    assert_eq!(volume.fat_sector(1, 200), (32 + 32 + 1, 288));
+
+    // A FAT16 volume has the size of its FAT in the old field.
+    boot[22] = 1;
+    assert_eq!(Volume::parse(&boot), Err(FsError::Unsupported));
+}
+
+#[test_case]
+fn test_short_names() {
+    assert_eq!(short_name("README.TXT"), Some(*b"README  TXT"));
+    assert_eq!(short_name("KERNEL"), Some(*b"KERNEL     "));
+    assert_eq!(short_name("readme.txt"), None);
+    assert_eq!(short_name("A.B.C"), None);
+    assert_eq!(short_name("TOOLONGNAME"), None);
+
+    assert_eq!(checksum(b"README  TXT"), 0x73);
+    assert_eq!(
+        Basis::new("Long file name.txt").numbered(1),
+        *b"LONGFI~1TXT"
+    );
+    assert_eq!(Basis::new(".hidden").numbered(12), *b"HIDDE~12   ");
+    assert_eq!(Basis::new("a+b.tar.gz").numbered(3), *b"A_BTAR~3GZ ");
+
+    assert!(is_valid_name("Long file name.txt"));
+    assert!(!is_valid_name("a:b"));
+    assert!(!is_valid_name("trailing."));
+    assert!(!is_valid_name(".."));
+}
@@ -1,3 +1,9 @@
+//! Segmentation: the GDT with the kernel and user segments, and the TSS.
+//!
+//! Long mode ignores segment bases and limits, so segments only set the privilege level. The TSS holds
+//! RSP0, the stack the CPU switches to on interrupts and exceptions from ring 3, and the interrupt stack
+//! table for handlers that need a known-good stack whatever the CPU was doing.
+
 use lazy_static::lazy_static;
 use x86_64::VirtAddr;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
@@ -5,50 +11,37 @@ use x86_64::structures::tss::TaskStateSegment;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 pub const GENERIC_PROTECTION_FAULT_IST_INDEX: u16 = 1;
-
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-
-        tss.interrupt_stack_table[GENERIC_PROTECTION_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-
-        tss.privilege_stack_table[0] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-
-        tss
-    };
+pub const PAGE_FAULT_IST_INDEX: u16 = 2;
+pub const NMI_IST_INDEX: u16 = 3;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 4;
+pub const DEBUG_IST_INDEX: u16 = 5;
+
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+/// Reserves a separate static stack on each use and evaluates to its end, since stacks grow downwards.
+macro_rules! static_stack {
+    ($size:expr) => {{
+        const STACK_SIZE: usize = $size;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(&raw const STACK);
+        stack_start + STACK_SIZE
+    }};
 }
 
+/// One per CPU; only the boot CPU is brought up. Mutable because RSP0 changes with every process switch,
+/// see `set_kernel_stack`.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
 lazy_static! {
-    pub static ref GDT: (GlobalDescriptorTable, Selectors) = {
+    static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
         let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
-        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        // SYSRET loads the user SS and CS from consecutive entries, data first.
         let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss()));
 
         (
             gdt,
@@ -63,6 +56,8 @@ lazy_static! {
     };
 }
 
+/// Selectors of the GDT entries. The user ones carry RPL 3.
+#[derive(Debug, Clone, Copy)]
 pub struct Selectors {
     pub kernel_code_selector: SegmentSelector,
     pub kernel_data_selector: SegmentSelector,
@@ -71,20 +66,56 @@ pub struct Selectors {
     pub tss_selector: SegmentSelector,
 }
 
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+pub fn tss() -> &'static TaskStateSegment {
+    unsafe { &*core::ptr::addr_of!(TSS) }
+}
+
+/// Sets RSP0, the stack the CPU switches to when an interrupt or exception arrives in ring 3, e.g. to the
+/// kernel stack of the process about to run.
+pub fn set_kernel_stack(top: VirtAddr) {
+    // The CPU only reads RSP0 on a transition from ring 3, which can't happen while the kernel writes it.
+    unsafe { TSS.privilege_stack_table[0] = top.align_down(16u64) };
+}
+
+/// Gives the TSS its stacks. Must run before the TSS is loaded.
+fn init_tss() {
+    let tss = unsafe { &mut *core::ptr::addr_of_mut!(TSS) };
+
+    // Faults that may happen with a broken stack (overflows hitting the guard page), or at any moment
+    // (NMI, machine check), get known-good stacks from the interrupt stack table.
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = static_stack!(IST_STACK_SIZE);
+    tss.interrupt_stack_table[GENERIC_PROTECTION_FAULT_IST_INDEX as usize] =
+        static_stack!(IST_STACK_SIZE);
+    tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = static_stack!(IST_STACK_SIZE);
+    tss.interrupt_stack_table[NMI_IST_INDEX as usize] = static_stack!(IST_STACK_SIZE);
+    tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = static_stack!(IST_STACK_SIZE);
+    // A single step of a syscall instruction traps on the user stack, see `interrupts::debug`.
+    tss.interrupt_stack_table[DEBUG_IST_INDEX as usize] = static_stack!(IST_STACK_SIZE);
+
+    // Used until the first process runs.
+    tss.privilege_stack_table[0] = static_stack!(4096 * 5);
+}
+
 pub fn init() {
     use x86_64::instructions::segmentation::{CS, DS, ES, FS, GS, SS, Segment};
     use x86_64::instructions::tables::load_tss;
 
+    init_tss();
     GDT.0.load();
 
+    let selectors = selectors();
     unsafe {
-        CS::set_reg(GDT.1.kernel_code_selector);
-        DS::set_reg(GDT.1.kernel_data_selector);
-        ES::set_reg(GDT.1.kernel_data_selector);
-        FS::set_reg(GDT.1.kernel_data_selector);
-        SS::set_reg(GDT.1.kernel_data_selector);
-        GS::set_reg(GDT.1.kernel_data_selector);
-
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(selectors.kernel_code_selector);
+        DS::set_reg(selectors.kernel_data_selector);
+        ES::set_reg(selectors.kernel_data_selector);
+        FS::set_reg(selectors.kernel_data_selector);
+        SS::set_reg(selectors.kernel_data_selector);
+        GS::set_reg(selectors.kernel_data_selector);
+
+        load_tss(selectors.tss_selector);
     }
 }
@@ -0,0 +1,59 @@
+//! Tracking of which subsystems have been initialized.
+//!
+//! Integration tests and the kernel entry point share the same init paths, so running one twice must not
+//! silently reinitialize hardware or reset global state. Each init function owns an `InitState` and either
+//! returns early or reports `AlreadyInitialized` on a repeated call.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlreadyInitialized {
+    pub subsystem: &'static str,
+}
+
+impl fmt::Display for AlreadyInitialized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is already initialized", self.subsystem)
+    }
+}
+
+pub struct InitState {
+    subsystem: &'static str,
+    initialized: AtomicBool,
+}
+
+impl InitState {
+    pub const fn new(subsystem: &'static str) -> Self {
+        InitState {
+            subsystem,
+            initialized: AtomicBool::new(false),
+        }
+    }
+
+    /// Marks the subsystem as initialized. Fails if it already was, so only the first caller proceeds.
+    pub fn begin(&self) -> Result<(), AlreadyInitialized> {
+        self.initialized
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .map(|_| ())
+            .map_err(|_| AlreadyInitialized {
+                subsystem: self.subsystem,
+            })
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.initialized.load(Ordering::Acquire)
+    }
+}
+
+#[test_case]
+fn test_only_first_begin_succeeds() {
+    let state = InitState::new("test");
+
+    assert!(!state.is_initialized());
+    assert_eq!(state.begin(), Ok(()));
+    assert!(state.is_initialized());
+    assert_eq!(state.begin(), Err(AlreadyInitialized { subsystem: "test" }));
+}
@@ -0,0 +1,218 @@
+//! The initial ramdisk, a tar archive loaded next to the kernel by the bootloader.
+//!
+//! The build packs the repository's `initrd/` directory into it, so it is where user programs and data
+//! files come from until there is a disk. Only the parts of the ustar format the build produces are
+//! understood: regular files with paths shorter than 100 bytes.
+
+use conquer_once::spin::OnceCell;
+use core::{slice, str};
+
+const BLOCK_SIZE: usize = 512;
+const NAME: core::ops::Range<usize> = 0..100;
+const SIZE: core::ops::Range<usize> = 124..136;
+const CHECKSUM: core::ops::Range<usize> = 148..156;
+const TYPE_FLAG: usize = 156;
+const MAGIC: core::ops::Range<usize> = 257..262;
+
+static INITRD: OnceCell<Initrd<'static>> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitrdError {
+    /// The header at this offset isn't a valid ustar header.
+    InvalidHeader(usize),
+    /// The file whose header is at this offset extends past the end of the archive.
+    Truncated(usize),
+    AlreadyInitialized,
+}
+
+/// A file in the archive.
+#[derive(Debug, Clone, Copy)]
+pub struct File<'a> {
+    pub name: &'a str,
+    pub data: &'a [u8],
+}
+
+/// A validated tar archive.
+#[derive(Debug, Clone, Copy)]
+pub struct Initrd<'a> {
+    archive: &'a [u8],
+}
+
+impl<'a> Initrd<'a> {
+    /// Checks every header of the archive.
+    pub fn new(archive: &'a [u8]) -> Result<Self, InitrdError> {
+        let initrd = Initrd { archive };
+        for entry in initrd.entries() {
+            entry?;
+        }
+
+        Ok(initrd)
+    }
+
+    /// Regular files in the archive, in archive order.
+    pub fn files(&self) -> impl Iterator<Item = File<'a>> + use<'a> {
+        self.entries()
+            .filter_map(Result::ok)
+            .filter_map(|(type_flag, file)| matches!(type_flag, b'0' | 0).then_some(file))
+    }
+
+    /// Finds a file by its path inside the archive, e.g. `bin/init`. A leading `./` or `/` is ignored.
+    pub fn find(&self, name: &str) -> Option<&'a [u8]> {
+        let name = normalize(name);
+        self.files()
+            .find(|file| normalize(file.name) == name)
+            .map(|file| file.data)
+    }
+
+    fn entries(&self) -> impl Iterator<Item = Result<(u8, File<'a>), InitrdError>> + use<'a> {
+        let archive = self.archive;
+        let mut offset = 0;
+
+        core::iter::from_fn(move || {
+            let header = archive.get(offset..offset + BLOCK_SIZE)?;
+
+            // The archive ends with zeroed blocks.
+            if header.iter().all(|&byte| byte == 0) {
+                return None;
+            }
+
+            let entry = parse_entry(archive, offset, header);
+            offset = match &entry {
+                Ok((_, file)) => offset + BLOCK_SIZE + file.data.len().next_multiple_of(BLOCK_SIZE),
+                Err(_) => archive.len(),
+            };
+
+            Some(entry)
+        })
+    }
+}
+
+fn parse_entry<'a>(
+    archive: &'a [u8],
+    offset: usize,
+    header: &'a [u8],
+) -> Result<(u8, File<'a>), InitrdError> {
+    let invalid = InitrdError::InvalidHeader(offset);
+
+    if header[MAGIC] != *b"ustar" {
+        return Err(invalid);
+    }
+
+    // The checksum is the sum of the header bytes, with the checksum field itself counted as spaces.
+    let field_sum: u32 = header[CHECKSUM].iter().map(|&byte| byte as u32).sum();
+    let sum = header.iter().map(|&byte| byte as u32).sum::<u32>() - field_sum
+        + CHECKSUM.len() as u32 * b' ' as u32;
+    if parse_octal(&header[CHECKSUM]) != Some(sum as usize) {
+        return Err(invalid);
+    }
+
+    let name = &header[NAME];
+    let name_len = name
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(name.len());
+    let name = str::from_utf8(&name[..name_len]).map_err(|_| invalid)?;
+    let size = parse_octal(&header[SIZE]).ok_or(invalid)?;
+
+    let start = offset + BLOCK_SIZE;
+    let data = archive
+        .get(start..start + size)
+        .ok_or(InitrdError::Truncated(offset))?;
+
+    Ok((header[TYPE_FLAG], File { name, data }))
+}
+
+/// Numeric fields are octal ASCII, padded with NULs or spaces.
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let mut digits = field
+        .iter()
+        .skip_while(|&&byte| byte == b' ')
+        .take_while(|&&byte| byte != 0 && byte != b' ');
+
+    digits.try_fold(0usize, |value, &digit| match digit {
+        b'0'..=b'7' => value.checked_mul(8)?.checked_add((digit - b'0') as usize),
+        _ => None,
+    })
+}
+
+fn normalize(name: &str) -> &str {
+    name.trim_start_matches("./").trim_start_matches('/')
+}
+
+/// Validates the ramdisk the bootloader loaded at `address`. Does nothing if there is none.
+pub fn init(address: Option<u64>, len: u64) -> Result<(), InitrdError> {
+    let Some(address) = address else {
+        return Ok(());
+    };
+
+    // The bootloader maps the ramdisk into the kernel's address space.
+    let archive = unsafe { slice::from_raw_parts(address as *const u8, len as usize) };
+    let initrd = Initrd::new(archive)?;
+
+    INITRD
+        .try_init_once(|| initrd)
+        .map_err(|_| InitrdError::AlreadyInitialized)
+}
+
+/// The ramdisk, if the bootloader loaded one.
+pub fn get() -> Option<&'static Initrd<'static>> {
+    INITRD.get()
+}
+
+/// Shorthand for finding a file in the ramdisk.
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    get()?.find(name)
+}
+
+/// Writes a ustar header and the file data at `offset`, returning the offset of the next header.
+#[cfg(test)]
+fn write_test_file(archive: &mut [u8], offset: usize, name: &str, data: &[u8]) -> usize {
+    fn write_octal(field: &mut [u8], mut value: usize) {
+        // Zero-padded digits followed by a NUL.
+        let digits = field.len() - 1;
+        for byte in field[..digits].iter_mut().rev() {
+            *byte = b'0' + (value % 8) as u8;
+            value /= 8;
+        }
+        field[digits] = 0;
+    }
+
+    let header = &mut archive[offset..offset + BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[SIZE], data.len());
+    header[TYPE_FLAG] = b'0';
+    header[MAGIC].copy_from_slice(b"ustar");
+    header[CHECKSUM].fill(b' ');
+
+    let sum = header.iter().map(|&byte| byte as usize).sum();
+    write_octal(&mut header[CHECKSUM], sum);
+
+    let start = offset + BLOCK_SIZE;
+    archive[start..start + data.len()].copy_from_slice(data);
+    start + data.len().next_multiple_of(BLOCK_SIZE)
+}
+
+#[test_case]
+fn test_files_are_found_by_name() {
+    let mut archive = [0u8; BLOCK_SIZE * 6];
+    let offset = write_test_file(&mut archive, 0, "README", b"hello");
+    write_test_file(&mut archive, offset, "./bin/init", &[0x7F; 600]);
+
+    let initrd = Initrd::new(&archive).unwrap();
+    assert_eq!(initrd.files().count(), 2);
+    assert_eq!(initrd.find("README"), Some(&b"hello"[..]));
+    assert_eq!(initrd.find("/bin/init").map(<[u8]>::len), Some(600));
+    assert_eq!(initrd.find("missing"), None);
+}
+
+#[test_case]
+fn test_corrupted_archive_is_rejected() {
+    let mut archive = [0u8; BLOCK_SIZE * 3];
+    write_test_file(&mut archive, 0, "README", b"hello");
+    archive[0] = b'X';
+
+    assert_eq!(
+        Initrd::new(&archive).err(),
+        Some(InitrdError::InvalidHeader(0))
+    );
+}
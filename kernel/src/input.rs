@@ -0,0 +1,239 @@
+//! Input devices, and the bus their events go out on.
+//!
+//! Drivers register their devices with `register_device` and `emit` what happens to them as `Event`s,
+//! in a form that doesn't depend on the hardware. Whatever wants input, such as the console or the
+//! tools, `subscribe`s and gets every event from every device, and so does the process holding the input
+//! `ring`. Events are never emitted by interrupt handlers, which hand raw bytes to the driver's task, or
+//! to deferred work when there is no task to take them.
+
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use futures_util::stream::Stream;
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+
+pub mod ring;
+
+/// Events queued for a subscriber that hasn't caught up, beyond which the oldest are dropped.
+const MAX_QUEUED: usize = 128;
+
+/// The bit for each button in `MouseReport::buttons`.
+const BUTTONS: [(u8, Button); 3] = [
+    (1 << 0, Button::Left),
+    (1 << 1, Button::Right),
+    (1 << 2, Button::Middle),
+];
+
+static BUS: Mutex<Bus> = Mutex::new(Bus {
+    devices: Vec::new(),
+    subscribers: Vec::new(),
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DeviceId(u16);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Keyboard,
+    Mouse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    pub id: DeviceId,
+    pub name: &'static str,
+    pub kind: DeviceKind,
+}
+
+/// Which modifier keys are held, either of the pair for each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Left,
+    Right,
+    Middle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// `key` is what the layout makes of `code`, if anything.
+    KeyPress {
+        code: KeyCode,
+        key: Option<DecodedKey>,
+        modifiers: Modifiers,
+    },
+    KeyRelease {
+        code: KeyCode,
+    },
+    /// Relative movement, with `dy` growing downwards like screen coordinates.
+    MouseMove {
+        dx: i32,
+        dy: i32,
+    },
+    Button {
+        button: Button,
+        pressed: bool,
+    },
+    /// Wheel movement, positive when scrolled towards the user.
+    Scroll {
+        delta: i32,
+    },
+}
+
+/// What a mouse reports at once, as mice of every kind do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseReport {
+    /// Movement, with `dy` growing downwards like `Event::MouseMove`.
+    pub dx: i32,
+    pub dy: i32,
+    /// Bit 0 for the left button, 1 for the right and 2 for the middle one.
+    pub buttons: u8,
+    /// Like `Event::Scroll`.
+    pub scroll: i32,
+}
+
+/// An event, and the device it came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub device: DeviceId,
+    pub event: Event,
+}
+
+struct Subscriber {
+    events: VecDeque<InputEvent>,
+    waker: Option<Waker>,
+}
+
+struct Bus {
+    devices: Vec<Device>,
+    /// Indexed by `Subscription::index`, `None` once dropped.
+    subscribers: Vec<Option<Subscriber>>,
+}
+
+/// Registers a device whose events will be emitted as coming from the returned ID.
+pub fn register_device(name: &'static str, kind: DeviceKind) -> DeviceId {
+    let mut bus = BUS.lock();
+    let id = DeviceId(bus.devices.len() as u16);
+    bus.devices.push(Device { id, name, kind });
+    id
+}
+
+/// The registered devices, in the order they were registered.
+pub fn devices() -> Vec<Device> {
+    BUS.lock().devices.clone()
+}
+
+/// Sends `event` to every subscriber.
+pub fn emit(device: DeviceId, event: Event) {
+    let event = InputEvent { device, event };
+
+    for subscriber in BUS.lock().subscribers.iter_mut().flatten() {
+        if subscriber.events.len() == MAX_QUEUED {
+            subscriber.events.pop_front();
+        }
+        subscriber.events.push_back(event);
+
+        if let Some(waker) = subscriber.waker.take() {
+            waker.wake();
+        }
+    }
+
+    ring::push(&event);
+}
+
+/// Emits what changed with `report`, given the buttons held before it.
+pub fn emit_mouse(device: DeviceId, buttons: u8, report: MouseReport) {
+    if report.dx != 0 || report.dy != 0 {
+        emit(
+            device,
+            Event::MouseMove {
+                dx: report.dx,
+                dy: report.dy,
+            },
+        );
+    }
+    for (bit, button) in BUTTONS {
+        if (buttons ^ report.buttons) & bit != 0 {
+            let pressed = report.buttons & bit != 0;
+            emit(device, Event::Button { button, pressed });
+        }
+    }
+    if report.scroll != 0 {
+        emit(
+            device,
+            Event::Scroll {
+                delta: report.scroll,
+            },
+        );
+    }
+}
+
+/// Receives every event emitted from now on, until the subscription is dropped.
+pub fn subscribe() -> Subscription {
+    let mut bus = BUS.lock();
+    let subscriber = Subscriber {
+        events: VecDeque::new(),
+        waker: None,
+    };
+
+    let index = match bus.subscribers.iter().position(Option::is_none) {
+        Some(index) => {
+            bus.subscribers[index] = Some(subscriber);
+            index
+        }
+        None => {
+            bus.subscribers.push(Some(subscriber));
+            bus.subscribers.len() - 1
+        }
+    };
+    Subscription { index }
+}
+
+/// Events for one subscriber, from `subscribe`.
+pub struct Subscription {
+    index: usize,
+}
+
+impl Subscription {
+    /// The next event, if one is queued.
+    pub fn try_next(&mut self) -> Option<InputEvent> {
+        BUS.lock().subscribers[self.index]
+            .as_mut()
+            .and_then(|subscriber| subscriber.events.pop_front())
+    }
+}
+
+impl Stream for Subscription {
+    type Item = InputEvent;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<InputEvent>> {
+        let mut bus = BUS.lock();
+        let Some(subscriber) = bus.subscribers[self.index].as_mut() else {
+            return Poll::Ready(None);
+        };
+
+        // The waker is stored under the same lock `emit` pushes under, so no wakeup is lost.
+        match subscriber.events.pop_front() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => {
+                subscriber.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        BUS.lock().subscribers[self.index] = None;
+    }
+}
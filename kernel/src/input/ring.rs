@@ -0,0 +1,223 @@
+//! The input ring: every input event, delivered to a user process through a page it shares with the
+//! kernel, see `libsys::input::InputRing`.
+//!
+//! A process that draws the screen itself wants input as soon as it happens, without a syscall per
+//! event. `attach` maps the ring into it, and `input::emit` copies every event into the ring and rings
+//! the doorbell: it wakes the futex waiters on the ring's `head`. That doesn't need the executor, which
+//! doesn't run while processes do.
+//!
+//! The ring sees every key typed, so only a privileged process, one the kernel started itself, may take
+//! it. It goes to the first one that asks, which holds it until it exits or execs.
+
+use core::{mem::offset_of, sync::atomic::Ordering};
+use libsys::input::{self as abi, InputRing, RingEvent};
+use pc_keyboard::DecodedKey;
+use spin::Mutex;
+use x86_64::{
+    VirtAddr,
+    structures::paging::{Page, PhysFrame},
+};
+
+use super::{Button, Event, InputEvent, Modifiers};
+use crate::{
+    memory::phys_to_virt,
+    process::{self, HeapError, MMAP_TOP, Pid, futex},
+};
+
+/// Where the ring is mapped, in the gap between anonymous mappings and the stack.
+pub const RING_ADDRESS: VirtAddr = MMAP_TOP;
+
+static HOLDER: Mutex<Option<Holder>> = Mutex::new(None);
+
+struct Holder {
+    pid: Pid,
+    frame: PhysFrame,
+    /// Level 4 table of the holder's address space, which identifies its futexes.
+    space: PhysFrame,
+}
+
+impl Holder {
+    fn ring(&self) -> &InputRing {
+        // The frame stays mapped in the holder until `release`, which takes the holder lock.
+        unsafe { &*phys_to_virt(self.frame.start_address()).as_ptr() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RingError {
+    /// Another process holds the ring.
+    Busy,
+    /// The process isn't privileged, see the module documentation.
+    NotPermitted,
+    NoProcess,
+    OutOfMemory,
+}
+
+/// Maps the ring into the current process, empty, and makes it the holder. Returns where it is mapped.
+pub fn attach() -> Result<VirtAddr, RingError> {
+    let pid = process::current().ok_or(RingError::NoProcess)?;
+    if !process::with_process(pid, |process| process.is_privileged()).ok_or(RingError::NoProcess)? {
+        return Err(RingError::NotPermitted);
+    }
+
+    let mut holder = HOLDER.lock();
+    if holder.as_ref().is_some_and(|holder| holder.pid != pid) {
+        return Err(RingError::Busy);
+    }
+
+    let (frame, space) = process::with_process(pid, |process| {
+        let frame = process.map_shared(Page::containing_address(RING_ADDRESS))?;
+        let space = process
+            .address_space()
+            .expect("current process has exited")
+            .level_4_frame();
+        Ok::<_, HeapError>((frame, space))
+    })
+    .ok_or(RingError::NoProcess)?
+    .map_err(|_| RingError::OutOfMemory)?;
+
+    let ring = holder.insert(Holder { pid, frame, space }).ring();
+    // A page inherited from a parent that held the ring still has its events in it.
+    ring.head.store(0, Ordering::Relaxed);
+    ring.tail.store(0, Ordering::Relaxed);
+    ring.dropped.store(0, Ordering::Relaxed);
+    Ok(RING_ADDRESS)
+}
+
+/// Takes the ring away from `pid`, if it holds it. Called before its address space goes away.
+pub fn release(pid: Pid) {
+    let mut holder = HOLDER.lock();
+    if holder.as_ref().is_some_and(|holder| holder.pid == pid) {
+        *holder = None;
+    }
+}
+
+/// Copies `event` into the ring, if a process holds it, and wakes the processes waiting on it.
+pub(super) fn push(event: &InputEvent) {
+    let space = {
+        let holder = HOLDER.lock();
+        let Some(holder) = holder.as_ref() else {
+            return;
+        };
+        holder.ring().push(encode(event));
+        holder.space
+    };
+
+    // Waking takes the process table, so the holder lock is released first.
+    let head = RING_ADDRESS + offset_of!(InputRing, head) as u64;
+    let _ = futex::wake_waiters_in(space, head.as_u64(), u64::MAX);
+}
+
+/// `event` as the ring holds it.
+pub fn encode(event: &InputEvent) -> RingEvent {
+    let device = event.device.0;
+
+    match event.event {
+        Event::KeyPress {
+            code,
+            key,
+            modifiers,
+        } => RingEvent {
+            kind: abi::KEY_PRESS,
+            modifiers: encode_modifiers(modifiers),
+            device,
+            code: code as u32,
+            x: match key {
+                Some(DecodedKey::Unicode(character)) => character as i32,
+                _ => -1,
+            },
+            y: 0,
+        },
+        Event::KeyRelease { code } => RingEvent {
+            kind: abi::KEY_RELEASE,
+            device,
+            code: code as u32,
+            ..RingEvent::default()
+        },
+        Event::MouseMove { dx, dy } => RingEvent {
+            kind: abi::MOUSE_MOVE,
+            device,
+            x: dx,
+            y: dy,
+            ..RingEvent::default()
+        },
+        Event::Button { button, pressed } => RingEvent {
+            kind: abi::BUTTON,
+            device,
+            code: match button {
+                Button::Left => 0,
+                Button::Right => 1,
+                Button::Middle => 2,
+            },
+            x: i32::from(pressed),
+            ..RingEvent::default()
+        },
+        Event::Scroll { delta } => RingEvent {
+            kind: abi::SCROLL,
+            device,
+            x: delta,
+            ..RingEvent::default()
+        },
+    }
+}
+
+fn encode_modifiers(modifiers: Modifiers) -> u8 {
+    let mut bits = 0;
+    if modifiers.shift {
+        bits |= abi::MOD_SHIFT;
+    }
+    if modifiers.ctrl {
+        bits |= abi::MOD_CTRL;
+    }
+    if modifiers.alt {
+        bits |= abi::MOD_ALT;
+    }
+    bits
+}
+
+#[test_case]
+fn test_events_are_encoded_for_the_ring() {
+    use pc_keyboard::KeyCode;
+
+    let device = super::DeviceId(3);
+    let press = InputEvent {
+        device,
+        event: Event::KeyPress {
+            code: KeyCode::A,
+            key: Some(DecodedKey::Unicode('A')),
+            modifiers: Modifiers {
+                shift: true,
+                ..Modifiers::default()
+            },
+        },
+    };
+    let encoded = encode(&press);
+    assert_eq!(encoded.kind, abi::KEY_PRESS);
+    assert_eq!(encoded.device, 3);
+    assert_eq!(encoded.modifiers, abi::MOD_SHIFT);
+    assert_eq!(encoded.code, KeyCode::A as u32);
+    assert_eq!(encoded.x, 'A' as i32);
+
+    let button = InputEvent {
+        device,
+        event: Event::Button {
+            button: Button::Middle,
+            pressed: true,
+        },
+    };
+    assert_eq!(
+        encode(&button),
+        RingEvent {
+            kind: abi::BUTTON,
+            device: 3,
+            code: 2,
+            x: 1,
+            ..RingEvent::default()
+        }
+    );
+}
+
+#[test_case]
+fn test_the_ring_needs_a_process() {
+    assert_eq!(attach(), Err(RingError::NoProcess));
+}
@@ -1,9 +1,27 @@
-use crate::{gdt, print, println};
+use crate::{apic, fpu, framebuffer, gdt, lockup, memory, println, process, serial, time};
+use core::{
+    arch::naked_asm,
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin::Mutex;
+use x86_64::VirtAddr;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
+pub mod debug;
+pub mod deferred;
+#[cfg(feature = "irq-latency")]
+pub mod latency;
+pub mod stats;
+pub mod unhandled;
+
+use debug::GeneralRegisters;
+use unhandled::unhandled_interrupt_handler;
+
+pub use deferred::defer;
+
 /// Primary PIC
 pub const PIC_1_OFFSET: u8 = 32;
 /// Secondary PIC
@@ -17,10 +35,14 @@ pub static PICS: Mutex<ChainedPics> =
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    /// IRQ4, COM1.
+    Serial = PIC_1_OFFSET + 4,
+    /// IRQ8. Raised by the RTC, or by the HPET one-shot comparator once legacy routing is on.
+    Cmos = PIC_2_OFFSET,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub(crate) fn as_u8(self) -> u8 {
         self as u8
     }
 
@@ -32,10 +54,30 @@ impl InterruptIndex {
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        // Everything below overrides the catch-all for the vectors it handles.
+        x86_64::set_general_handler!(&mut idt, unhandled_interrupt_handler);
+
+        idt.breakpoint.set_handler_fn(debug::breakpoint_handler);
+        idt.device_not_available
+            .set_handler_fn(device_not_available_handler);
 
         unsafe {
+            idt.debug
+                .set_handler_addr(VirtAddr::new(debug::debug_entry as *const () as u64))
+                .set_stack_index(gdt::DEBUG_IST_INDEX);
+
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+
+            idt.non_maskable_interrupt
+                .set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+
+            idt.machine_check
+                .set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
@@ -45,8 +87,13 @@ lazy_static! {
                 .set_stack_index(gdt::GENERIC_PROTECTION_FAULT_IST_INDEX);
         }
 
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+        unsafe {
+            idt[InterruptIndex::Timer.as_usize()]
+                .set_handler_addr(VirtAddr::new(timer_entry as *const () as u64));
+        }
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial.as_usize()].set_handler_fn(serial_interrupt_handler);
+        idt[InterruptIndex::Cmos.as_usize()].set_handler_fn(cmos_interrupt_handler);
         idt
     };
 }
@@ -55,32 +102,253 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// Stops the PIC from delivering the given IRQ line (0-15).
+pub fn mask_irq(irq: u8) {
+    let mut pics = PICS.lock();
+
+    unsafe {
+        let mut masks = pics.read_masks();
+        masks[usize::from(irq / 8)] |= 1 << (irq % 8);
+        pics.write_masks(masks[0], masks[1]);
+    }
+}
+
+/// Lets the PIC deliver the given IRQ line (0-15), including the cascade line for the secondary PIC.
+pub fn unmask_irq(irq: u8) {
+    let mut pics = PICS.lock();
+
+    unsafe {
+        let mut masks = pics.read_masks();
+        masks[usize::from(irq / 8)] &= !(1 << (irq % 8));
+
+        if irq >= 8 {
+            masks[0] &= !(1 << 2);
+        }
+
+        pics.write_masks(masks[0], masks[1]);
+    }
+}
+
+/// Handlers of PIC lines without an IDT entry of their own, as `fn()` pointers, or 0. See `set_irq_handler`.
+static IRQ_HANDLERS: [AtomicUsize; 16] = [const { AtomicUsize::new(0) }; 16];
+
+/// The PIC line (0-15) already has a handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqInUse(pub u8);
+
+/// Whether the PIC line `irq` has an IDT entry of its own, whose handler never looks at `IRQ_HANDLERS`.
+fn has_own_entry(irq: u8) -> bool {
+    [
+        InterruptIndex::Timer,
+        InterruptIndex::Keyboard,
+        InterruptIndex::Serial,
+        InterruptIndex::Cmos,
+    ]
+    .iter()
+    .any(|index| index.as_u8() == PIC_1_OFFSET + irq)
+}
+
+/// Calls `handler` for every interrupt on the PIC line `irq` (0-15), e.g. a line a PCI device is routed
+/// to. The end of the interrupt is signaled after `handler` returns. The line stays masked until
+/// `unmask_irq`. Lines with an IDT entry of their own (0, 1, 4 and 8) are always in use.
+pub fn set_irq_handler(irq: u8, handler: fn()) -> Result<(), IrqInUse> {
+    if has_own_entry(irq) {
+        return Err(IrqInUse(irq));
+    }
+    IRQ_HANDLERS[usize::from(irq)]
+        .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map(|_| ())
+        .map_err(|_| IrqInUse(irq))
+}
+
+/// The handler passed to `set_irq_handler` for the PIC line `irq`.
+fn irq_handler(irq: u8) -> Option<fn()> {
+    let handler = IRQ_HANDLERS.get(usize::from(irq))?.load(Ordering::Acquire);
+    // SAFETY: only `fn()` pointers are stored.
+    (handler != 0).then(|| unsafe { core::mem::transmute::<usize, fn()>(handler) })
+}
+
+/// Handlers running on the CPU, counting the ones they interrupted.
+static HANDLER_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Bookkeeping shared by every handler, kept until the handler returns.
+struct HandlerGuard {
+    #[cfg(feature = "irq-latency")]
+    _latency: latency::Measurement,
+}
+
+/// Counts the interrupt and, with the `irq-latency` feature, times the handler until the guard is dropped.
+fn enter_handler(vector: u8) -> HandlerGuard {
+    stats::record(vector);
+    HANDLER_DEPTH.fetch_add(1, Ordering::Relaxed);
+
+    HandlerGuard {
+        #[cfg(feature = "irq-latency")]
+        _latency: latency::Measurement::start(vector),
+    }
+}
+
+impl Drop for HandlerGuard {
+    fn drop(&mut self) {
+        HANDLER_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether the CPU is running an interrupt or exception handler.
+pub fn in_handler() -> bool {
+    HANDLER_DEPTH.load(Ordering::Relaxed) > 0
+}
+
 extern "x86-interrupt" fn page_fault_handler(
     _stack_frame: InterruptStackFrame,
-    _error_code: PageFaultErrorCode,
+    error_code: PageFaultErrorCode,
 ) {
+    use x86_64::registers::control::Cr2;
+
+    let handler = enter_handler(14);
+
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        // Anonymous memory of user processes only gets a frame once it is touched.
+        if process::handle_page_fault(Cr2::read()) {
+            return;
+        }
+
+        // The next process doesn't come back here.
+        drop(handler);
+        process::signal::terminate_current(process::signal::Signal::SEGV);
+    }
+
     loop {}
 }
 
-// Interruptions use a specific calling convention.
-extern "x86-interrupt" fn breakpoint_handler(_stack_frame: InterruptStackFrame) {
-    loop {}
+extern "x86-interrupt" fn device_not_available_handler(_stack_frame: InterruptStackFrame) {
+    let _handler = enter_handler(7);
+    fpu::on_device_not_available();
+}
+
+/// An NMI can arrive while the interrupted code holds the output locks, even with interrupts disabled, so
+/// they are only tried: the report is skipped on whichever output is busy.
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    let _handler = enter_handler(2);
+
+    if let Some(mut writer) = framebuffer::WRITER.try_lock()
+        && let Some(writer) = writer.as_mut()
+    {
+        let _ = writeln!(writer, "NMI\n{:#?}", stack_frame);
+        writer.flush();
+    }
+    if let Some(mut serial) = serial::SERIAL1.try_lock() {
+        let _ = writeln!(serial, "NMI\n{:#?}", stack_frame);
+    }
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    let _handler = enter_handler(18);
+    dump_fault_state("MACHINE CHECK", &stack_frame, None);
+    panic!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame)
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) -> ! {
+    let _handler = enter_handler(8);
+    dump_fault_state("DOUBLE FAULT", &stack_frame, Some(error_code));
     panic!(
         "EXCEPTION: DOUBLE FAULT\n{:#?} {:#?}",
         stack_frame, error_code
     )
 }
 
+/// How many words from the top of the faulting stack are dumped.
+const FAULT_STACK_DUMP_WORDS: usize = 16;
+
+/// Writes everything useful for post-mortem debugging to the serial port.
+///
+/// The framebuffer may be what broke, and serial output survives the machine resetting, so the dump goes
+/// there before the panic handler tries the screen. Unrecoverable faults never return to whoever holds the
+/// serial lock, so it's taken by force.
+fn dump_fault_state(name: &str, stack_frame: &InterruptStackFrame, error_code: Option<u64>) {
+    use x86_64::registers::control::{Cr2, Cr3};
+    use x86_64::registers::segmentation::{CS, SS, Segment};
+
+    let mut serial = serial::SERIAL1.try_lock().unwrap_or_else(|| {
+        unsafe { serial::SERIAL1.force_unlock() };
+        serial::SERIAL1.lock()
+    });
+
+    let _ = writeln!(serial, "EXCEPTION: {}", name);
+    if let Some(error_code) = error_code {
+        let _ = writeln!(serial, "  error code: {:#x}", error_code);
+    }
+    let _ = writeln!(
+        serial,
+        "  rip: {:#x}\n  rsp: {:#x}\n  rflags: {:#x}\n  cs: {:#x}  ss: {:#x}",
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.stack_pointer.as_u64(),
+        stack_frame.cpu_flags,
+        stack_frame.code_segment,
+        stack_frame.stack_segment,
+    );
+
+    // CR2 still holds the address of the last page fault, which is usually what led to a double fault.
+    let (level_4_frame, cr3_flags) = Cr3::read();
+    let _ = writeln!(
+        serial,
+        "  cr2: {:#x}\n  cr3: {:#x} ({:?})",
+        Cr2::read_raw(),
+        level_4_frame.start_address().as_u64(),
+        cr3_flags,
+    );
+
+    let gdtr = x86_64::instructions::tables::sgdt();
+    let _ = writeln!(
+        serial,
+        "  gdt: base {:#x} limit {:#x}\n  current cs: {:#x}  ss: {:#x}",
+        gdtr.base.as_u64(),
+        gdtr.limit,
+        CS::get_reg().0,
+        SS::get_reg().0,
+    );
+
+    let tss = gdt::tss();
+    let privilege_stack = tss.privilege_stack_table[0];
+    let _ = writeln!(serial, "  tss rsp0: {:#x}", privilege_stack.as_u64());
+    let interrupt_stacks = tss.interrupt_stack_table;
+    for (index, stack) in interrupt_stacks.iter().enumerate() {
+        if !stack.is_null() {
+            let _ = writeln!(serial, "  tss ist{}: {:#x}", index, stack.as_u64());
+        }
+    }
+
+    // A stack overflow into the guard page is the classic cause of a double fault; reading the stack would
+    // then fault again, so only dump the words that are actually mapped.
+    let _ = writeln!(serial, "  stack:");
+    let stack = stack_frame.stack_pointer;
+    for index in 0..FAULT_STACK_DUMP_WORDS {
+        let address = stack + (index * 8) as u64;
+
+        if !memory::is_mapped(address) {
+            let _ = writeln!(serial, "    [rsp+{:#04x}] <unmapped>", index * 8);
+            break;
+        }
+
+        let word = unsafe { address.as_ptr::<u64>().read_volatile() };
+        let _ = writeln!(serial, "    [rsp+{:#04x}] {:#018x}", index * 8, word);
+    }
+}
+
 extern "x86-interrupt" fn generic_protection_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    let handler = enter_handler(13);
+
+    if stack_frame.code_segment & 0b11 == 3 {
+        drop(handler);
+        process::signal::terminate_current(process::signal::Signal::SEGV);
+    }
+
     println!(
         "EXCEPTION: GENERIC PROTECTION FAULT {:#?} {:#?}",
         stack_frame, error_code
@@ -88,26 +356,116 @@ extern "x86-interrupt" fn generic_protection_fault_handler(
     loop {}
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+/// What `timer_entry` leaves on the stack.
+#[repr(C)]
+struct TimerFrame {
+    registers: GeneralRegisters,
+    stack_frame: InterruptStackFrame,
+}
+
+/// Entry point of the timer interrupt, which saves the general purpose registers like `debug_entry` so
+/// that a user process can be preempted.
+#[unsafe(naked)]
+unsafe extern "C" fn timer_entry() {
+    naked_asm!(
+        "push r15",
+        "push r14",
+        "push r13",
+        "push r12",
+        "push r11",
+        "push r10",
+        "push r9",
+        "push r8",
+        "push rbp",
+        "push rdi",
+        "push rsi",
+        "push rdx",
+        "push rcx",
+        "push rbx",
+        "push rax",
+        "mov rdi, rsp",
+        "cld",
+        "call {handler}",
+        "pop rax",
+        "pop rbx",
+        "pop rcx",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop rbp",
+        "pop r8",
+        "pop r9",
+        "pop r10",
+        "pop r11",
+        "pop r12",
+        "pop r13",
+        "pop r14",
+        "pop r15",
+        "iretq",
+        handler = sym timer_handler,
+    );
+}
+
+extern "C" fn timer_handler(frame: &mut TimerFrame) {
+    {
+        let _handler = enter_handler(InterruptIndex::Timer.as_u8());
+        time::on_tick();
+        crate::task::timer::on_tick();
+        lockup::check(&frame.stack_frame, frame.registers.rbp);
+
+        match time::tick_source() {
+            time::TickSource::Apic => apic::end_of_interrupt(),
+            time::TickSource::Pit => unsafe {
+                PICS.lock()
+                    .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+            },
+        }
+    }
+
+    // Outside of the handler proper, since a preempted process doesn't come back here.
+    if frame.stack_frame.code_segment & 0b11 == 3 {
+        process::sched::on_tick(&frame.registers, &frame.stack_frame);
+    }
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _handler = enter_handler(InterruptIndex::Keyboard.as_u8());
+    crate::ps2::on_keyboard_interrupt();
 
     unsafe {
         PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _handler = enter_handler(InterruptIndex::Serial.as_u8());
+    crate::serial::on_interrupt();
 
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Serial.as_u8());
+    }
+}
 
-    crate::task::keyboard::add_scancode(scancode);
+/// IRQ8 has a single source at a time: the HPET takes it over from the RTC when legacy routing is turned
+/// on. Each handler checks whether it is its own.
+extern "x86-interrupt" fn cmos_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _handler = enter_handler(InterruptIndex::Cmos.as_u8());
+    time::hpet::on_interrupt();
+    crate::rtc::on_interrupt();
 
     unsafe {
         PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+            .notify_end_of_interrupt(InterruptIndex::Cmos.as_u8());
+    }
+}
+
+#[test_case]
+fn test_set_irq_handler_rejects_lines_with_own_entry() {
+    fn handler() {}
+    for irq in [0, 1, 4, 8] {
+        assert_eq!(set_irq_handler(irq, handler), Err(IrqInUse(irq)));
     }
 }
 
@@ -0,0 +1,176 @@
+//! Breakpoint (#BP) and debug (#DB) exceptions.
+//!
+//! By default both exceptions are reported and execution resumes. A debugger (for example a future GDB
+//! stub) can take them over with `set_hook`. Single steps of traced user processes go to
+//! `process::trace` first.
+//!
+//! #DB enters through `debug_entry`, which saves the general purpose registers for the tracer, on its own
+//! interrupt stack: a `syscall` executed with the trap flag set may trap on the first instruction of the
+//! syscall entry, which still runs on the user stack.
+
+use core::arch::naked_asm;
+use spin::Mutex;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::{println, process::trace, syscall};
+
+/// Trap flag in RFLAGS: the CPU raises #DB after every instruction while it is set.
+pub const RFLAGS_TRAP_FLAG: u64 = 1 << 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugEvent {
+    /// An `int3` was executed. The instruction pointer already points past it.
+    Breakpoint,
+    /// Single-step, hardware breakpoint or watchpoint.
+    Debug,
+}
+
+/// Returns `true` if the event was handled and the default report should be skipped.
+///
+/// The hook may modify the stack frame, e.g. to set or clear the trap flag.
+pub type DebugHook = fn(DebugEvent, &mut InterruptStackFrame) -> bool;
+
+static HOOK: Mutex<Option<DebugHook>> = Mutex::new(None);
+
+/// Installs a hook that sees every #BP and #DB before the default handling. Returns the previous hook.
+pub fn set_hook(hook: Option<DebugHook>) -> Option<DebugHook> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        core::mem::replace(&mut *HOOK.lock(), hook)
+    })
+}
+
+fn run_hook(event: DebugEvent, stack_frame: &mut InterruptStackFrame) -> bool {
+    let hook = *HOOK.lock();
+    hook.is_some_and(|hook| hook(event, stack_frame))
+}
+
+/// General purpose registers of the interrupted code, as saved by `debug_entry`.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct GeneralRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+/// What `debug_entry` leaves on the stack.
+#[repr(C)]
+struct DebugFrame {
+    registers: GeneralRegisters,
+    stack_frame: InterruptStackFrame,
+}
+
+/// Entry point of #DB, set with `set_handler_addr`.
+#[unsafe(naked)]
+pub(super) unsafe extern "C" fn debug_entry() {
+    naked_asm!(
+        // The CPU pushed 5 words onto a 16-byte aligned stack, so 15 more leave it aligned for the call.
+        "push r15",
+        "push r14",
+        "push r13",
+        "push r12",
+        "push r11",
+        "push r10",
+        "push r9",
+        "push r8",
+        "push rbp",
+        "push rdi",
+        "push rsi",
+        "push rdx",
+        "push rcx",
+        "push rbx",
+        "push rax",
+        "mov rdi, rsp",
+        "cld",
+        "call {handler}",
+        "pop rax",
+        "pop rbx",
+        "pop rcx",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop rbp",
+        "pop r8",
+        "pop r9",
+        "pop r10",
+        "pop r11",
+        "pop r12",
+        "pop r13",
+        "pop r14",
+        "pop r15",
+        "iretq",
+        handler = sym debug_handler,
+    );
+}
+
+// Interruptions use a specific calling convention.
+pub(super) extern "x86-interrupt" fn breakpoint_handler(mut stack_frame: InterruptStackFrame) {
+    let _handler = super::enter_handler(3);
+
+    if run_hook(DebugEvent::Breakpoint, &mut stack_frame) {
+        return;
+    }
+
+    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+}
+
+extern "C" fn debug_handler(frame: &mut DebugFrame) {
+    let _handler = super::enter_handler(1);
+    let stack_frame = &mut frame.stack_frame;
+
+    if stack_frame.code_segment & 0b11 == 3 {
+        if trace::on_single_step(&frame.registers, stack_frame) {
+            return;
+        }
+    } else if stack_frame.instruction_pointer == syscall::entry_address() {
+        // A traced process made a syscall; the entry masks the trap flag itself.
+        return;
+    }
+
+    if run_hook(DebugEvent::Debug, stack_frame) {
+        return;
+    }
+
+    println!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
+
+    // Nobody asked for single-stepping, so stop it instead of trapping after every instruction.
+    if stack_frame.cpu_flags & RFLAGS_TRAP_FLAG != 0 {
+        unsafe {
+            stack_frame
+                .as_mut()
+                .update(|frame| frame.cpu_flags &= !RFLAGS_TRAP_FLAG);
+        }
+    }
+}
+
+#[test_case]
+fn test_breakpoint_hook_intercepts_int3() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static HITS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_breakpoints(event: DebugEvent, _stack_frame: &mut InterruptStackFrame) -> bool {
+        if event == DebugEvent::Breakpoint {
+            HITS.fetch_add(1, Ordering::Relaxed);
+        }
+        true
+    }
+
+    let previous = set_hook(Some(count_breakpoints));
+    x86_64::instructions::interrupts::int3();
+    set_hook(previous);
+
+    assert_eq!(HITS.load(Ordering::Relaxed), 1);
+}
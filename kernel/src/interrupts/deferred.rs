@@ -0,0 +1,106 @@
+//! Deferred work ("bottom halves").
+//!
+//! Interrupt handlers run with interrupts disabled, so anything slow they do delays every other interrupt.
+//! Instead, a handler should only capture the data it needs and `defer` the rest, which then runs later
+//! with interrupts enabled from `run_deferred_work`.
+//!
+//! Deferring must not allocate, since the allocator lock could be held by the interrupted code. That's why
+//! a work item is a plain function pointer plus one argument, stored in a queue allocated up front.
+
+use conquer_once::spin::OnceCell;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+
+use crate::queue::{Queue, QueueId};
+
+static WORK_QUEUE: OnceCell<Queue<Work>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+#[derive(Debug, Clone, Copy)]
+struct Work {
+    function: fn(usize),
+    argument: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeferError {
+    /// `init` hasn't been called yet (it needs the heap).
+    Uninitialized,
+    /// The queue is full and its overflow behavior dropped the work.
+    QueueFull,
+}
+
+/// Allocates the work queue. Must be called after the heap is initialized. Repeated calls do nothing.
+pub fn init() {
+    WORK_QUEUE.init_once(|| Queue::new(QueueId::DeferredWork));
+}
+
+/// Queues `function(argument)` to run later with interrupts enabled. Safe to call from interrupt handlers.
+pub fn defer(function: fn(usize), argument: usize) -> Result<(), DeferError> {
+    let queue = WORK_QUEUE
+        .try_get()
+        .map_err(|_| DeferError::Uninitialized)?;
+
+    queue
+        .push(Work { function, argument })
+        .map_err(|_| DeferError::QueueFull)?;
+    WAKER.wake();
+
+    Ok(())
+}
+
+/// Runs all queued work and returns how many items ran.
+pub fn run_pending() -> usize {
+    let Ok(queue) = WORK_QUEUE.try_get() else {
+        return 0;
+    };
+
+    let mut count = 0;
+
+    while let Some(work) = queue.pop() {
+        (work.function)(work.argument);
+        count += 1;
+    }
+
+    count
+}
+
+/// Resolves once there is queued work.
+struct PendingWork;
+
+impl Future for PendingWork {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        let queue = WORK_QUEUE
+            .try_get()
+            .expect("deferred work queue not initialized");
+
+        if !queue.is_empty() {
+            return Poll::Ready(());
+        }
+
+        // Register before checking again, otherwise a `defer` between the check and the registration would
+        // be missed.
+        WAKER.register(context.waker());
+
+        if queue.is_empty() {
+            Poll::Pending
+        } else {
+            WAKER.take();
+            Poll::Ready(())
+        }
+    }
+}
+
+/// Executor task that runs deferred work as it arrives.
+pub async fn run_deferred_work() {
+    loop {
+        PendingWork.await;
+        run_pending();
+    }
+}
@@ -0,0 +1,122 @@
+//! Interrupt handler latency measurement, enabled with the `irq-latency` feature.
+//!
+//! Every handler is timed with the TSC from entry to exit, and the minimum, average and maximum are kept
+//! per vector. Long handlers delay every other interrupt, so this shows whether locks taken from interrupt
+//! context (the framebuffer writer, the allocator) are held for too long.
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{cpu, time::tsc};
+
+struct VectorLatency {
+    samples: AtomicU64,
+    total_cycles: AtomicU64,
+    min_cycles: AtomicU64,
+    max_cycles: AtomicU64,
+}
+
+impl VectorLatency {
+    const fn new() -> Self {
+        VectorLatency {
+            samples: AtomicU64::new(0),
+            total_cycles: AtomicU64::new(0),
+            min_cycles: AtomicU64::new(u64::MAX),
+            max_cycles: AtomicU64::new(0),
+        }
+    }
+}
+
+static LATENCIES: [VectorLatency; 256] = [const { VectorLatency::new() }; 256];
+
+/// Times a handler from its creation until it is dropped.
+pub struct Measurement {
+    vector: u8,
+    start: u64,
+}
+
+impl Measurement {
+    pub fn start(vector: u8) -> Self {
+        Measurement {
+            vector,
+            start: cpu::rdtsc(),
+        }
+    }
+}
+
+impl Drop for Measurement {
+    fn drop(&mut self) {
+        let cycles = cpu::rdtsc().wrapping_sub(self.start);
+        let latency = &LATENCIES[usize::from(self.vector)];
+
+        latency.samples.fetch_add(1, Ordering::Relaxed);
+        latency.total_cycles.fetch_add(cycles, Ordering::Relaxed);
+        latency.min_cycles.fetch_min(cycles, Ordering::Relaxed);
+        latency.max_cycles.fetch_max(cycles, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub vector: u8,
+    pub samples: u64,
+    pub min_cycles: u64,
+    pub avg_cycles: u64,
+    pub max_cycles: u64,
+}
+
+/// Summaries for every vector whose handler ran at least once since the last `reset`.
+pub fn summaries() -> impl Iterator<Item = LatencySummary> {
+    (0..=u8::MAX).filter_map(|vector| {
+        let latency = &LATENCIES[usize::from(vector)];
+        let samples = latency.samples.load(Ordering::Relaxed);
+
+        (samples > 0).then(|| LatencySummary {
+            vector,
+            samples,
+            min_cycles: latency.min_cycles.load(Ordering::Relaxed),
+            avg_cycles: latency.total_cycles.load(Ordering::Relaxed) / samples,
+            max_cycles: latency.max_cycles.load(Ordering::Relaxed),
+        })
+    })
+}
+
+pub fn reset() {
+    for latency in &LATENCIES {
+        latency.samples.store(0, Ordering::Relaxed);
+        latency.total_cycles.store(0, Ordering::Relaxed);
+        latency.min_cycles.store(u64::MAX, Ordering::Relaxed);
+        latency.max_cycles.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Writes one line per vector, in nanoseconds when the TSC frequency is known and in cycles otherwise.
+pub fn dump(output: &mut impl Write) -> fmt::Result {
+    let frequency = tsc::frequency();
+    let unit = if frequency > 0 { "ns" } else { "cycles" };
+    let scale = |cycles: u64| {
+        if frequency > 0 {
+            (cycles as u128 * 1_000_000_000 / frequency as u128) as u64
+        } else {
+            cycles
+        }
+    };
+
+    writeln!(output, "vector samples min avg max ({})", unit)?;
+
+    for summary in summaries() {
+        writeln!(
+            output,
+            "{} {} {} {} {}",
+            summary.vector,
+            summary.samples,
+            scale(summary.min_cycles),
+            scale(summary.avg_cycles),
+            scale(summary.max_cycles),
+        )?;
+    }
+
+    Ok(())
+}
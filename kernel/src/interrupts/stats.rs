@@ -0,0 +1,68 @@
+//! Per-vector interrupt counters and the owners of each vector.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::InterruptIndex;
+use crate::apic;
+
+static COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// Vectors with a dedicated handler and who they belong to.
+const OWNERS: &[(u8, &str)] = &[
+    (1, "debug"),
+    (2, "nmi"),
+    (3, "breakpoint"),
+    (7, "device not available"),
+    (8, "double fault"),
+    (13, "general protection fault"),
+    (14, "page fault"),
+    (18, "machine check"),
+    (InterruptIndex::Timer as u8, "timer"),
+    (InterruptIndex::Keyboard as u8, "keyboard"),
+    (InterruptIndex::Cmos as u8, "rtc/hpet"),
+    (apic::SPURIOUS_VECTOR, "apic spurious"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VectorInfo {
+    pub vector: u8,
+    /// `None` for vectors that only reached the catch-all handler.
+    pub owner: Option<&'static str>,
+    pub count: u64,
+}
+
+/// Counts one interrupt on `vector`. Called at the start of every handler.
+pub(crate) fn record(vector: u8) {
+    COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn count(vector: u8) -> u64 {
+    COUNTS[usize::from(vector)].load(Ordering::Relaxed)
+}
+
+/// Lists the vectors that have a handler or have been raised at least once, in vector order.
+pub fn vectors() -> impl Iterator<Item = VectorInfo> {
+    (0..=u8::MAX).filter_map(|vector| {
+        let owner = OWNERS
+            .iter()
+            .find(|&&(owned, _)| owned == vector)
+            .map(|&(_, owner)| owner);
+        let count = count(vector);
+
+        (owner.is_some() || count > 0).then_some(VectorInfo {
+            vector,
+            owner,
+            count,
+        })
+    })
+}
+
+#[test_case]
+fn test_breakpoint_is_counted() {
+    let before = count(3);
+    x86_64::instructions::interrupts::int3();
+
+    let breakpoint = vectors().find(|info| info.vector == 3).unwrap();
+    assert_eq!(breakpoint.owner, Some("breakpoint"));
+    assert_eq!(breakpoint.count, before + 1);
+}
@@ -0,0 +1,121 @@
+//! Catch-all handling for vectors without a dedicated handler, and for spurious interrupts.
+//!
+//! Without a handler, any stray interrupt would escalate to a double fault. Instead every unused IDT entry
+//! points to `unhandled_interrupt_handler`, which reports the vector and acknowledges it when needed.
+//!
+//! The PIC raises a spurious IRQ7 (or IRQ15 on the secondary PIC) when an interrupt line drops before the
+//! CPU acknowledges it. Spurious interrupts must not be acknowledged with an EOI, except that the primary
+//! PIC still expects one for the cascade line when the secondary PIC raised it. The local APIC has its own
+//! spurious vector, which never needs an EOI either.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{instructions::port::Port, structures::idt::InterruptStackFrame};
+
+use super::{PIC_1_OFFSET, PIC_2_OFFSET, PICS};
+use crate::{
+    apic, println,
+    process::signal::{self, Signal},
+};
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xA0;
+/// OCW3 command selecting the in-service register for the next read of the command port.
+const PIC_READ_ISR: u8 = 0x0B;
+const PIC_EOI: u8 = 0x20;
+
+/// The lowest priority line of each PIC, used for its spurious interrupts.
+const PIC_SPURIOUS_LINE: u8 = 7;
+
+static SPURIOUS_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+static UNHANDLED_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of spurious interrupts ignored since boot, from both the PIC and the local APIC.
+pub fn spurious_count() -> u64 {
+    SPURIOUS_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// Number of interrupts on vectors without a handler since boot.
+pub fn unhandled_count() -> u64 {
+    UNHANDLED_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+fn in_service_register(command_port: u16) -> u8 {
+    let mut port = Port::<u8>::new(command_port);
+
+    unsafe {
+        port.write(PIC_READ_ISR);
+        port.read()
+    }
+}
+
+/// A spurious PIC interrupt arrives on the lowest priority line without that line being in service.
+fn is_spurious_pic_interrupt(vector: u8) -> bool {
+    let (command_port, offset) = if vector < PIC_2_OFFSET {
+        (PIC_1_COMMAND, PIC_1_OFFSET)
+    } else {
+        (PIC_2_COMMAND, PIC_2_OFFSET)
+    };
+
+    vector - offset == PIC_SPURIOUS_LINE
+        && in_service_register(command_port) & (1 << PIC_SPURIOUS_LINE) == 0
+}
+
+pub(super) fn unhandled_interrupt_handler(
+    stack_frame: InterruptStackFrame,
+    vector: u8,
+    error_code: Option<u64>,
+) {
+    let handler = super::enter_handler(vector);
+
+    // Returning from a fault re-executes the faulting instruction, so an unhandled exception can't be
+    // recovered from. When user code raised it, only its process has to go.
+    if vector < PIC_1_OFFSET && stack_frame.code_segment & 0b11 == 3 {
+        drop(handler);
+        signal::terminate_current(Signal::for_exception(vector));
+    }
+    if vector < PIC_1_OFFSET {
+        panic!(
+            "EXCEPTION: unhandled exception {} (error code {:?})\n{:#?}",
+            vector, error_code, stack_frame
+        );
+    }
+
+    if vector == apic::SPURIOUS_VECTOR {
+        SPURIOUS_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let from_pic = (PIC_1_OFFSET..PIC_2_OFFSET + 8).contains(&vector);
+
+    if from_pic && is_spurious_pic_interrupt(vector) {
+        SPURIOUS_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+
+        // The primary PIC did deliver the cascade line, so it still needs its EOI.
+        if vector >= PIC_2_OFFSET {
+            unsafe { Port::<u8>::new(PIC_1_COMMAND).write(PIC_EOI) };
+        }
+        return;
+    }
+
+    if from_pic && let Some(handler) = super::irq_handler(vector - PIC_1_OFFSET) {
+        handler();
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
+        return;
+    }
+
+    UNHANDLED_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    println!("unhandled interrupt {}\n{:#?}", vector, stack_frame);
+
+    if from_pic {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
+    } else if apic::is_enabled() {
+        apic::end_of_interrupt();
+    }
+}
+
+#[test_case]
+fn test_unhandled_vector_returns() {
+    let before = unhandled_count();
+    unsafe { core::arch::asm!("int 0x64") };
+    assert_eq!(unhandled_count(), before + 1);
+}
@@ -0,0 +1,307 @@
+//! The kernel log: a ring of timestamped records from the kernel and from user processes, read back like
+//! `dmesg`.
+//!
+//! Every record is also echoed to the serial port and the screen as it is logged, so kernel events and
+//! what user programs log about them show up in one stream, in order. The ring lives in static memory
+//! and keeps the last `CAPACITY` records; older ones are overwritten.
+//!
+//! User processes log with the `log` syscall. Their records are always tagged with their PID, and the
+//! number of records per second is limited by `Resource::LogRate`.
+//!
+//! Once a writable disk is mounted, `file::run` appends the log to a file on it.
+
+use core::{fmt, time::Duration};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::{
+    console::{self, Console},
+    framebuffer::Color,
+    process::Pid,
+    serial_print, time,
+};
+
+pub mod file;
+
+pub const CAPACITY: usize = 128;
+/// Longer messages are truncated.
+pub const MAX_MESSAGE_LEN: usize = 120;
+
+static LOG: Mutex<Ring> = Mutex::new(Ring {
+    records: [Record::EMPTY; CAPACITY],
+    next_sequence: 0,
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warning,
+    Info,
+    Debug,
+}
+
+impl Level {
+    /// The level with the given `libsys::log` number.
+    pub fn from_number(number: u64) -> Option<Self> {
+        match number {
+            libsys::log::ERROR => Some(Level::Error),
+            libsys::log::WARNING => Some(Level::Warning),
+            libsys::log::INFO => Some(Level::Info),
+            libsys::log::DEBUG => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    /// The color records of this level are shown in on the screen, if not the default one.
+    pub fn color(self) -> Option<Color> {
+        match self {
+            Level::Error => Some(Color::RED),
+            Level::Warning => Some(Color::YELLOW),
+            Level::Info => None,
+            Level::Debug => Some(Color::GREY),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        })
+    }
+}
+
+/// Who logged a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Kernel,
+    Process(Pid),
+}
+
+#[derive(Clone, Copy)]
+pub struct Record {
+    sequence: u64,
+    /// Time since boot.
+    timestamp: Duration,
+    level: Level,
+    source: Source,
+    text: [u8; MAX_MESSAGE_LEN],
+    len: usize,
+}
+
+impl Record {
+    const EMPTY: Record = Record {
+        sequence: 0,
+        timestamp: Duration::ZERO,
+        level: Level::Info,
+        source: Source::Kernel,
+        text: [0; MAX_MESSAGE_LEN],
+        len: 0,
+    };
+
+    /// Position of the record in the log, counting from 0 since boot.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn timestamp(&self) -> Duration {
+        self.timestamp
+    }
+
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    pub fn source(&self) -> Source {
+        self.source
+    }
+
+    pub fn text(&self) -> &str {
+        // Only whole characters are ever copied in, see `fmt::Write for Record`.
+        core::str::from_utf8(&self.text[..self.len]).unwrap_or_default()
+    }
+}
+
+/// Appends to the text, dropping whatever doesn't fit without splitting a character.
+impl fmt::Write for Record {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = MAX_MESSAGE_LEN - self.len;
+        let mut end = s.len().min(room);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        self.text[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Record")
+            .field("sequence", &self.sequence)
+            .field("timestamp", &self.timestamp)
+            .field("level", &self.level)
+            .field("source", &self.source)
+            .field("text", &self.text())
+            .finish()
+    }
+}
+
+/// `[   12.345678] pid 3 info: text`, like `dmesg`.
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{:>5}.{:06}] ",
+            self.timestamp.as_secs(),
+            self.timestamp.subsec_micros()
+        )?;
+
+        match self.source {
+            Source::Kernel => write!(f, "kernel")?,
+            Source::Process(pid) => write!(f, "pid {}", pid)?,
+        }
+
+        write!(f, " {}: {}", self.level, self.text())
+    }
+}
+
+struct Ring {
+    records: [Record; CAPACITY],
+    next_sequence: u64,
+}
+
+/// Appends a record and echoes it to the console, returning its sequence number. Must not be called from
+/// interrupt handlers, since echoing takes the console locks.
+pub fn log(level: Level, source: Source, args: fmt::Arguments) -> u64 {
+    use core::fmt::Write;
+
+    let mut record = Record {
+        timestamp: Duration::from_nanos(time::uptime_nanos()),
+        level,
+        source,
+        ..Record::EMPTY
+    };
+    let _ = record.write_fmt(args);
+
+    let record = interrupts::without_interrupts(|| {
+        let mut ring = LOG.lock();
+        record.sequence = ring.next_sequence;
+        ring.records[record.sequence as usize % CAPACITY] = record;
+        ring.next_sequence += 1;
+        record
+    });
+
+    serial_print!("{}\n", record);
+    console::print_to_screen(
+        Console::Log,
+        record.level.color(),
+        format_args!("{}\n", record),
+    );
+
+    record.sequence
+}
+
+/// Calls `f` on every record still in the ring with a sequence number of at least `since`, oldest first.
+/// Returns the sequence number the next record will get, to continue from later.
+///
+/// The log stays locked meanwhile, so `f` must not log.
+pub fn read(since: u64, mut f: impl FnMut(&Record)) -> u64 {
+    interrupts::without_interrupts(|| {
+        let ring = LOG.lock();
+
+        let oldest = ring.next_sequence.saturating_sub(CAPACITY as u64);
+        for sequence in since.max(oldest)..ring.next_sequence {
+            f(&ring.records[sequence as usize % CAPACITY]);
+        }
+
+        ring.next_sequence
+    })
+}
+
+/// Logs a kernel record at the given level, e.g. `klog!(Info, "disk ready")`.
+#[macro_export]
+macro_rules! klog {
+    ($level:ident, $($arg:tt)*) => {
+        $crate::kmsg::log(
+            $crate::kmsg::Level::$level,
+            $crate::kmsg::Source::Kernel,
+            format_args!($($arg)*),
+        )
+    };
+}
+
+/// Counts a process's records per one-second window, to enforce `Resource::LogRate`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimiter {
+    window_start: u64,
+    count: u64,
+    suppressed: u64,
+}
+
+impl RateLimiter {
+    const WINDOW_NANOS: u64 = 1_000_000_000;
+
+    /// Whether one more record fits in `per_second` at `now_nanos`. If so, returns how many records were
+    /// suppressed since the last one that got through, so that can be logged too.
+    pub fn admit(&mut self, now_nanos: u64, per_second: u64) -> Option<u64> {
+        if now_nanos.saturating_sub(self.window_start) >= Self::WINDOW_NANOS {
+            self.window_start = now_nanos;
+            self.count = 0;
+        }
+
+        if self.count >= per_second {
+            self.suppressed += 1;
+            return None;
+        }
+
+        self.count += 1;
+        Some(core::mem::take(&mut self.suppressed))
+    }
+}
+
+#[test_case]
+fn test_rate_limiter_resets_every_second() {
+    let mut limiter = RateLimiter::default();
+
+    assert_eq!(limiter.admit(0, 2), Some(0));
+    assert_eq!(limiter.admit(10, 2), Some(0));
+    assert_eq!(limiter.admit(20, 2), None);
+    assert_eq!(limiter.admit(30, 2), None);
+    assert_eq!(limiter.admit(1_000_000_000, 2), Some(2));
+}
+
+#[test_case]
+fn test_long_messages_are_truncated_on_char_boundaries() {
+    use core::fmt::Write;
+
+    let mut record = Record::EMPTY;
+    for _ in 0..MAX_MESSAGE_LEN {
+        let _ = record.write_str("é");
+    }
+
+    assert_eq!(record.text().chars().count(), MAX_MESSAGE_LEN / 2);
+    assert_eq!(record.len, MAX_MESSAGE_LEN);
+}
+
+#[test_case]
+fn test_records_are_read_back_in_order() {
+    let first = klog!(Info, "first {}", 1);
+    log(Level::Warning, Source::Kernel, format_args!("second"));
+
+    let mut count = 0;
+    let next = read(first, |record| {
+        let expected = [(Level::Info, "first 1"), (Level::Warning, "second")][count];
+        assert_eq!((record.level(), record.text()), expected);
+        assert_eq!(record.source(), Source::Kernel);
+        count += 1;
+    });
+
+    assert_eq!(count, 2);
+    assert_eq!(next, first + 2);
+}
@@ -0,0 +1,108 @@
+//! The kernel log, appended to a file on a writable filesystem so that it outlives the run.
+//!
+//! `run` appends the records logged since its last look every `PERIOD`, a line each with the sequence
+//! number in front. Opening the file appends a boot marker, since sequence numbers start over every
+//! boot, and records the ring overwrote before they could be written leave a marker saying how many are
+//! missing.
+//!
+//! The file is only ever appended to, a batch of lines per write. Filesystems write through to the disk
+//! and write the data before the size in the directory entry, so a run that ends in a hang or a triple
+//! fault leaves the file ending with the last batch written, in whole lines.
+
+use alloc::string::String;
+use core::{fmt::Write, time::Duration};
+
+use crate::{
+    fs::{self, FileType, FsError},
+    klog,
+    task::timer,
+};
+
+/// How often `run` appends what was logged.
+pub const PERIOD: Duration = Duration::from_secs(1);
+
+/// The line `LogFile::open` appends first.
+pub const BOOT_MARKER: &str = "-- boot --\n";
+
+/// A log file open for appending.
+pub struct LogFile {
+    path: String,
+    /// The size of the file, where the next batch goes.
+    offset: u64,
+    /// The sequence number of the first record not appended yet.
+    next: u64,
+}
+
+impl LogFile {
+    /// Opens the file at `path`, creating it if it doesn't exist, and appends a boot marker. The first
+    /// `flush` appends every record still in the ring.
+    pub async fn open(path: &str) -> Result<Self, FsError> {
+        match fs::create(path, FileType::File).await {
+            Ok(()) | Err(FsError::AlreadyExists) => {}
+            Err(error) => return Err(error),
+        }
+        let metadata = fs::metadata(path).await?;
+        if metadata.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+
+        let mut file = LogFile {
+            path: String::from(path),
+            offset: metadata.size,
+            next: 0,
+        };
+        file.append(BOOT_MARKER).await?;
+        Ok(file)
+    }
+
+    /// Appends the records logged since the last call, and returns how many there were.
+    pub async fn flush(&mut self) -> Result<usize, FsError> {
+        let mut batch = String::new();
+        let mut count = 0;
+        let mut expected = self.next;
+        let next = super::read(self.next, |record| {
+            if record.sequence() > expected {
+                let lost = record.sequence() - expected;
+                let _ = writeln!(batch, "-- {} records lost --", lost);
+            }
+            let _ = writeln!(batch, "{} {}", record.sequence(), record);
+            expected = record.sequence() + 1;
+            count += 1;
+        });
+
+        self.append(&batch).await?;
+        self.next = next;
+        Ok(count)
+    }
+
+    async fn append(&mut self, text: &str) -> Result<(), FsError> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        fs::write(&self.path, self.offset, text.as_bytes()).await?;
+        self.offset += text.len() as u64;
+        Ok(())
+    }
+}
+
+/// Appends the kernel log to the file at `path` every `PERIOD`, until writing fails. Returns right away
+/// if the filesystem is read-only.
+pub async fn run(path: &str) {
+    let mut file = match LogFile::open(path).await {
+        Ok(file) => file,
+        Err(FsError::ReadOnly) => return,
+        Err(error) => {
+            klog!(Warning, "kmsg: {} not opened: {:?}", path, error);
+            return;
+        }
+    };
+
+    let mut interval = timer::interval(PERIOD);
+    loop {
+        if let Err(error) = file.flush().await {
+            klog!(Warning, "kmsg: stopped writing {}: {:?}", path, error);
+            return;
+        }
+        interval.tick().await;
+    }
+}
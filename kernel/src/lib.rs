@@ -9,14 +9,47 @@ use core::panic::PanicInfo;
 
 extern crate alloc;
 
+pub mod acpi;
+pub mod ahci;
 pub mod allocator;
+pub mod apic;
+pub mod ata;
+pub mod backtrace;
+pub mod console;
+pub mod cpu;
+pub mod dma;
+pub mod driver;
+pub mod fpu;
 pub mod framebuffer;
+pub mod fs;
 pub mod gdt;
+pub mod init_state;
+pub mod initrd;
+pub mod input;
 pub mod interrupts;
+pub mod kmsg;
+pub mod lockup;
 pub mod memory;
+pub mod mitigations;
+pub mod net;
+pub mod panic_screen;
+pub mod pci;
+pub mod process;
+pub mod ps2;
+pub mod queue;
+pub mod rtc;
 pub mod serial;
+pub mod sound;
+pub mod storage;
+pub mod sync;
+pub mod syscall;
 pub mod task;
+pub mod thread;
+pub mod time;
+pub mod tui;
+pub mod usb;
 pub mod userspace;
+pub mod virtio;
 
 pub trait Testable {
     fn run(&self);
@@ -34,7 +67,7 @@ where
 }
 
 pub fn test_runner(tests: &[&dyn Testable]) {
-    println!("Running {} tests", tests.len());
+    serial_println!("Running {} tests", tests.len());
 
     for test in tests {
         test.run();
@@ -44,20 +77,32 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    task::catch::recover(info);
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
 
+static INIT: init_state::InitState = init_state::InitState::new("kernel");
+
+/// Sets up the GDT, interrupts and the PIT. Repeated calls do nothing.
 pub fn init() {
+    if INIT.begin().is_err() {
+        return;
+    }
+
     interrupts::init_idt();
     gdt::init();
+    syscall::init();
+    fpu::init();
+    process::tls::init();
+    mitigations::init();
     unsafe {
         interrupts::PICS.lock().initialize();
 
-        // FIXME: Set up APIC.
         // This is used for enabling timer and keyboard interrupts.
+        // The timer moves to the local APIC in `time::init`, which masks IRQ0 again.
         // 0xFC = PIC1 and 0xFF = PIC2
         // Bit 1 means that IRQ is disabled. Bit 0 means that IRQ is enabled.
         //
@@ -73,6 +118,7 @@ pub fn init() {
         // 0xFF = 1111 1111 (All IRQs is disabled)
         interrupts::PICS.lock().write_masks(0xFC, 0xFF);
     };
+    time::init_pit();
     x86_64::instructions::interrupts::enable();
 }
 
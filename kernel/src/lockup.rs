@@ -0,0 +1,111 @@
+//! Soft lockup detector.
+//!
+//! Code that makes progress regularly calls `touch` (the executor does it for every poll and every time it
+//! goes idle, processes on every switch and syscall). The timer interrupt then checks whether anything
+//! touched the detector recently; if the kernel was interrupted in ring 0 and nothing made progress for
+//! longer than the threshold, something is stuck in a loop, and a report with the interrupted context is
+//! printed.
+//!
+//! Only code that runs with interrupts enabled can be caught, since the check runs from the timer
+//! interrupt. The report is printed with `try_lock`, because the stuck code may hold the output locks.
+
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::{backtrace, framebuffer, serial, time};
+
+pub const DEFAULT_THRESHOLD: Duration = Duration::from_secs(10);
+
+static PROGRESS: AtomicU64 = AtomicU64::new(0);
+static LAST_SEEN_PROGRESS: AtomicU64 = AtomicU64::new(0);
+static LAST_PROGRESS_NANOS: AtomicU64 = AtomicU64::new(0);
+static THRESHOLD_NANOS: AtomicU64 = AtomicU64::new(DEFAULT_THRESHOLD.as_nanos() as u64);
+/// Only one report per stall.
+static REPORTED: AtomicBool = AtomicBool::new(false);
+/// Set while the CPU is halted waiting for work, which looks like no progress but isn't a lockup.
+static IDLE: AtomicBool = AtomicBool::new(false);
+
+/// Marks that the kernel is making progress.
+pub fn touch() {
+    PROGRESS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Runs `f` (typically a `hlt`) without it counting as a stall.
+pub fn idle<R>(f: impl FnOnce() -> R) -> R {
+    IDLE.store(true, Ordering::Relaxed);
+    let result = f();
+    IDLE.store(false, Ordering::Relaxed);
+    touch();
+    result
+}
+
+pub fn set_threshold(threshold: Duration) {
+    THRESHOLD_NANOS.store(threshold.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Called from the timer interrupt with the context it interrupted, including its frame pointer.
+pub(crate) fn check(stack_frame: &InterruptStackFrame, rbp: u64) {
+    let now = time::monotonic_nanos();
+    let progress = PROGRESS.load(Ordering::Relaxed);
+
+    if IDLE.load(Ordering::Relaxed) {
+        LAST_PROGRESS_NANOS.store(now, Ordering::Relaxed);
+        return;
+    }
+
+    if progress != LAST_SEEN_PROGRESS.swap(progress, Ordering::Relaxed) {
+        LAST_PROGRESS_NANOS.store(now, Ordering::Relaxed);
+        REPORTED.store(false, Ordering::Relaxed);
+        return;
+    }
+
+    // Userspace spinning is not a kernel lockup.
+    let interrupted_ring = stack_frame.code_segment & 0b11;
+    let stalled_for = now.saturating_sub(LAST_PROGRESS_NANOS.load(Ordering::Relaxed));
+
+    if interrupted_ring == 0
+        && stalled_for > THRESHOLD_NANOS.load(Ordering::Relaxed)
+        && !REPORTED.swap(true, Ordering::Relaxed)
+    {
+        report(stack_frame, rbp, Duration::from_nanos(stalled_for));
+    }
+}
+
+fn report(stack_frame: &InterruptStackFrame, rbp: u64, stalled_for: Duration) {
+    let mut writer = framebuffer::WRITER.try_lock();
+    let mut serial = serial::SERIAL1.try_lock();
+
+    let mut emit = |args: core::fmt::Arguments| {
+        if let Some(writer) = writer.as_mut().and_then(|writer| writer.as_mut()) {
+            let _ = writer.write_fmt(args);
+            writer.flush();
+        }
+        if let Some(serial) = serial.as_mut() {
+            let _ = serial.write_fmt(args);
+        }
+    };
+
+    emit(format_args!(
+        "WARNING: soft lockup - no progress for {}s\n  rip: {:#x}\n  rsp: {:#x}\n",
+        stalled_for.as_secs(),
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.stack_pointer.as_u64(),
+    ));
+
+    emit(format_args!("  backtrace:\n"));
+    let mut frames = 0;
+    backtrace::walk(rbp, |return_address| {
+        emit(format_args!(
+            "    #{:<2} {:#018x}\n",
+            frames, return_address
+        ));
+        frames += 1;
+    });
+    if frames == 0 {
+        emit(format_args!("    (no frames)\n"));
+    }
+}
@@ -4,7 +4,7 @@
 #![test_runner(kernel::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
-use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
+use alloc::{boxed::Box, rc::Rc, sync::Arc, vec, vec::Vec};
 use bootloader_api::{
     BootInfo,
     config::{BootloaderConfig, Mapping},
@@ -14,8 +14,13 @@ use core::panic::PanicInfo;
 #[cfg(userspace)]
 use kernel::userspace;
 use kernel::{
-    framebuffer, println,
-    task::{Task, executor::Executor, keyboard},
+    framebuffer, println, serial,
+    task::{
+        self, Priority, Task,
+        executor::Executor,
+        watchdog::{self, StallAction},
+    },
+    tui::{self, file_browser::FileBrowser, memory_browser::MemoryBrowser, monitor::Monitor},
 };
 
 extern crate alloc;
@@ -29,19 +34,83 @@ pub static BOOTLOADER_CONFIG: BootloaderConfig = {
 entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
 
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
-    use kernel::allocator;
     use kernel::memory::{self, BootInfoFrameAllocator};
-    use x86_64::VirtAddr;
+    use kernel::{
+        acpi, ahci, allocator, ata, driver, initrd, interrupts, net, pci, ps2,
+        storage::BlockDevice, usb, virtio,
+    };
+    use x86_64::{PhysAddr, VirtAddr};
 
-    framebuffer::init(boot_info.framebuffer.take().unwrap());
+    framebuffer::init(boot_info.framebuffer.take().unwrap())
+        .expect("framebuffer already initialized");
+    let splash = framebuffer::show_splash(include_bytes!("../assets/logo.ppm"));
     kernel::init();
+    if let Err(error) = splash {
+        println!("WARNING: no splash: {:?}", error);
+    }
+    println!("mitigations: {}", kernel::mitigations::active());
 
     let physical_memory_offset =
         VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
     let mut mapper = unsafe { memory::init(physical_memory_offset) };
+
+    if let Some(rsdp_address) = boot_info.rsdp_addr.into_option() {
+        acpi::init(PhysAddr::new(rsdp_address)).expect("invalid ACPI tables");
+    }
+    kernel::time::init();
+
+    #[cfg(feature = "memtest")]
+    memory::memtest::run(&boot_info.memory_regions);
+
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("failed to init heap");
+    if let Err(error) = framebuffer::enable_back_buffer(&mut mapper, &mut frame_allocator) {
+        println!(
+            "framebuffer: no back buffer ({:?}), drawing directly",
+            error
+        );
+    }
+    memory::install_frame_allocator(frame_allocator);
+    interrupts::deferred::init();
+
+    if let Err(error) = acpi::aml::init() {
+        println!("WARNING: AML namespace not loaded: {:?}", error);
+    }
+    match pci::init() {
+        Ok(functions) => println!("pci: {} functions", functions),
+        Err(error) => println!("WARNING: PCI scanned through I/O ports only: {:?}", error),
+    }
+    for driver in [
+        &usb::xhci::DRIVER,
+        &ps2::DRIVER,
+        &virtio::blk::DRIVER,
+        &virtio::gpu::DRIVER,
+        &ahci::DRIVER,
+        &net::e1000::DRIVER,
+    ] {
+        driver::register(driver);
+    }
+    println!("driver: {} devices bound", driver::probe_all());
+    if virtio::blk::device().is_none() && ahci::disks().is_empty() {
+        for drive in ata::AtaDrive::probe() {
+            println!(
+                "ata: {:?} {:?}: {}, {} sectors",
+                drive.channel(),
+                drive.position(),
+                drive.identity().model(),
+                drive.num_blocks()
+            );
+        }
+    }
+
+    match initrd::init(boot_info.ramdisk_addr.into_option(), boot_info.ramdisk_len) {
+        Ok(()) => {
+            let files = initrd::get().map_or(0, |initrd| initrd.files().count());
+            println!("initrd: {} files", files);
+        }
+        Err(error) => println!("WARNING: initrd not loaded: {:?}", error),
+    }
 
     let heap_value = Box::new(42);
     println!("heap_value at {:p}", heap_value);
@@ -69,18 +138,104 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     #[cfg(not(test))]
     #[cfg(userspace)]
     unsafe {
-        userspace::jump_to_userspace(physical_memory_offset);
+        userspace::jump_to_userspace();
     }
 
     let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn_task(Task::new_named("example", example_task()));
+    executor.spawn_task(
+        Task::new_named("keyboard", task::keyboard::run_keyboard()).with_priority(Priority::High),
+    );
+    executor.spawn_task(
+        Task::new_named("keys", tui::input::route_keys()).with_priority(Priority::High),
+    );
+    // F1, F2 and F3, in this order.
+    executor.spawn_task(Task::new_named(
+        "monitor",
+        tui::run("monitor", Monitor::new()),
+    ));
+    executor.spawn_task(Task::new_named(
+        "memory",
+        tui::run("memory", MemoryBrowser::new()),
+    ));
+    executor.spawn_task(Task::new_named(
+        "files",
+        tui::run("files", FileBrowser::new()),
+    ));
+    executor.spawn_task(Task::new_named(
+        "deferred",
+        interrupts::deferred::run_deferred_work(),
+    ));
+    executor.spawn_task(Task::new_named("timers", task::timer::run_timers()));
+    executor.spawn_task(Task::new_named("mount", mount_disk()));
+    executor.spawn_task(Task::new_named("serial", serial::echo_input()));
+    executor.spawn_task(Task::new_named("cursor", framebuffer::blink_cursor()));
+    if let Some(controller) = ps2::controller() {
+        executor.spawn_task(Task::new_named("ps2", ps2::watch_hotplug()));
+        if controller.mouse {
+            executor.spawn_task(Task::new_named("mouse", ps2::mouse::run_mouse()));
+        }
+    }
+    if let Some(xhci) = usb::xhci::take()
+        && xhci.devices().iter().any(|device| device.boot.is_some())
+    {
+        executor.spawn_task(Task::new_named("usb", usb::xhci::run(xhci)));
+    }
+    executor.spawn_task(Task::new_named(
+        "compositor",
+        framebuffer::compositor::run_compositor(),
+    ));
+    executor.spawn_task(
+        Task::new_named("watchdog", watchdog::run_watchdog(StallAction::Log))
+            .with_priority(Priority::High),
+    );
+    // The log console takes over from the splash once the kernel is up.
+    framebuffer::end_splash();
     executor.run();
 
     #[cfg(test)]
     test_main();
 }
 
+/// Mounts the FAT32 or ext2 volume on the first disk at `/disk`, if it has one.
+async fn mount_disk() {
+    use kernel::{ahci, fs, kmsg, virtio};
+
+    let volume = if let Some(disk) = virtio::blk::device() {
+        open_volume(disk).await
+    } else if let Some(disk) = ahci::disks().first() {
+        open_volume(disk).await
+    } else {
+        return;
+    };
+    match volume.and_then(|volume| {
+        let name = volume.name();
+        fs::mount("/disk", volume).map(|()| name)
+    }) {
+        Ok(name) => println!("fs: {} mounted at /disk", name),
+        Err(fs::FsError::Unsupported) => return,
+        Err(error) => {
+            println!("WARNING: disk not mounted: {:?}", error);
+            return;
+        }
+    }
+    // This task keeps the log on the disk from then on.
+    kmsg::file::run("/disk/kernel.log").await;
+}
+
+/// The filesystem on `disk`, of whichever supported type it is.
+async fn open_volume<D>(disk: D) -> Result<Arc<dyn kernel::fs::FileSystem>, kernel::fs::FsError>
+where
+    D: kernel::storage::BlockDevice + Copy + Send + Sync + 'static,
+{
+    use kernel::fs::{FsError, ext2::Ext2, fat32::Fat32};
+
+    match Fat32::mount(disk).await {
+        Err(FsError::Unsupported) => Ok(Arc::new(Ext2::mount(disk).await?)),
+        fat => Ok(Arc::new(fat?)),
+    }
+}
+
 async fn async_number() -> u32 {
     42
 }
@@ -93,7 +248,10 @@ async fn example_task() {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    let rbp = kernel::backtrace::frame_pointer();
+    kernel::task::catch::recover(info);
+    kernel::panic_screen::show(info, rbp);
+    kernel::sound::panic_beep();
 
     kernel::hlt_loop();
 }
@@ -1,15 +1,55 @@
 use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+use conquer_once::spin::OnceCell;
 use core::panic;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
 use x86_64::{
     PhysAddr, VirtAddr,
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB,
     },
 };
 
 use crate::println;
 
+pub mod address_space;
+pub mod dump;
+#[cfg(feature = "memtest")]
+pub mod memtest;
+
+pub use address_space::AddressSpace;
+
+/// Virtual address where the bootloader mapped the whole physical memory.
+///
+/// The bootloader always maps at least the first 4 GiB, so MMIO regions such as the local APIC are also
+/// reachable through this offset.
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+/// The memory map reported by the bootloader, kept for introspection.
+static MEMORY_REGIONS: OnceCell<&'static [MemoryRegion]> = OnceCell::uninit();
+/// Frames handed out by `BootInfoFrameAllocator` and not freed since.
+static ALLOCATED_FRAMES: AtomicU64 = AtomicU64::new(0);
+/// The allocator behind `GlobalFrameAllocator`, installed once boot no longer needs it directly.
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+/// Head of the list of freed frames. Each free frame stores the address of the next one in its first
+/// 8 bytes (0 ends the list), so the list needs no memory of its own.
+static FREE_FRAMES: Mutex<Option<PhysFrame>> = Mutex::new(None);
+/// Start addresses of frames that must never be allocated, e.g. because they failed the memory test.
+static QUARANTINE: [AtomicU64; MAX_QUARANTINED] = [const { AtomicU64::new(0) }; MAX_QUARANTINED];
+static QUARANTINED: AtomicUsize = AtomicUsize::new(0);
+
+const MAX_QUARANTINED: usize = 256;
+
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    // Calling this again only creates another view of the same page tables, as long as the offset agrees.
+    let offset = *PHYSICAL_MEMORY_OFFSET.get_or_init(|| physical_memory_offset);
+    assert_eq!(
+        offset, physical_memory_offset,
+        "memory::init called again with a different offset"
+    );
+
+    address_space::set_kernel(x86_64::registers::control::Cr3::read().0);
+
     unsafe {
         let level_4_table = active_level_4_table(physical_memory_offset);
         OffsetPageTable::new(level_4_table, physical_memory_offset)
@@ -28,6 +68,200 @@ pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static
     unsafe { &mut *page_table_ptr }
 }
 
+pub fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET
+        .try_get()
+        .expect("physical memory offset not initialized")
+}
+
+/// Returns the virtual address through which a physical address can be accessed.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+/// Whether `addr` is mapped in the active page table. Returns `false` before `init`.
+///
+/// Meant for diagnostics that must not fault, such as dumping a possibly overflowed stack.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    use x86_64::structures::paging::Translate;
+
+    let Ok(&offset) = PHYSICAL_MEMORY_OFFSET.try_get() else {
+        return false;
+    };
+
+    let page_table = unsafe { OffsetPageTable::new(active_level_4_table(offset), offset) };
+    page_table.translate_addr(addr).is_some()
+}
+
+/// The flags of the page `addr` is in, in the active page tables, or `None` if it isn't mapped.
+pub fn page_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    use x86_64::structures::paging::{Translate, mapper::TranslateResult};
+
+    let &offset = PHYSICAL_MEMORY_OFFSET.try_get().ok()?;
+    let page_table = unsafe { OffsetPageTable::new(active_level_4_table(offset), offset) };
+    match page_table.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => Some(flags),
+        _ => None,
+    }
+}
+
+/// The bootloader's memory map. Empty until a `BootInfoFrameAllocator` is created.
+pub fn regions() -> &'static [MemoryRegion] {
+    MEMORY_REGIONS.get().copied().unwrap_or(&[])
+}
+
+/// Bytes of memory the bootloader reported as usable.
+pub fn usable_bytes() -> u64 {
+    regions()
+        .iter()
+        .filter(|region| region.kind == MemoryRegionKind::Usable)
+        .map(|region| region.end - region.start)
+        .sum()
+}
+
+/// Number of physical frames in use.
+pub fn allocated_frames() -> u64 {
+    ALLOCATED_FRAMES.load(Ordering::Relaxed)
+}
+
+/// Keeps `frame` from ever being allocated. Returns `false` if the quarantine is full.
+///
+/// Only possible before the first allocation, since `BootInfoFrameAllocator` counts the frames it has
+/// handed out by position.
+pub fn quarantine(frame: PhysFrame) -> bool {
+    assert_eq!(
+        ALLOCATED_FRAMES.load(Ordering::Relaxed),
+        0,
+        "frames quarantined after allocation started"
+    );
+
+    let index = QUARANTINED.load(Ordering::Relaxed);
+    if index == MAX_QUARANTINED {
+        return false;
+    }
+
+    QUARANTINE[index].store(frame.start_address().as_u64(), Ordering::Relaxed);
+    QUARANTINED.store(index + 1, Ordering::Relaxed);
+    true
+}
+
+pub fn is_quarantined(frame: PhysFrame) -> bool {
+    QUARANTINE[..QUARANTINED.load(Ordering::Relaxed)]
+        .iter()
+        .any(|address| address.load(Ordering::Relaxed) == frame.start_address().as_u64())
+}
+
+/// Number of frames in quarantine.
+pub fn quarantined_frames() -> usize {
+    QUARANTINED.load(Ordering::Relaxed)
+}
+
+/// Hands `allocator` over to `GlobalFrameAllocator`, for allocations after boot.
+pub fn install_frame_allocator(allocator: BootInfoFrameAllocator) {
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+}
+
+/// Allocates frames from the allocator passed to `install_frame_allocator`, reusing freed frames first.
+#[derive(Debug, Clone, Copy)]
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let mut free = FREE_FRAMES.lock();
+
+        if let Some(frame) = *free {
+            let next = unsafe { phys_to_virt(frame.start_address()).as_ptr::<u64>().read() };
+            *free = (next != 0).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+
+            ALLOCATED_FRAMES.fetch_add(1, Ordering::Relaxed);
+            return Some(frame);
+        }
+        drop(free);
+
+        FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let mut free = FREE_FRAMES.lock();
+        let next = free.map_or(0, |next| next.start_address().as_u64());
+
+        unsafe {
+            phys_to_virt(frame.start_address())
+                .as_mut_ptr::<u64>()
+                .write(next)
+        };
+        *free = Some(frame);
+        ALLOCATED_FRAMES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Allocates a frame and fills it with zeros.
+pub fn allocate_zeroed_frame() -> Option<PhysFrame> {
+    let frame = GlobalFrameAllocator.allocate_frame()?;
+    unsafe {
+        phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, 4096);
+    }
+
+    Some(frame)
+}
+
+/// Allocates `count` zeroed frames that follow each other in physical memory, e.g. for a device that
+/// reads a buffer larger than a page, and returns the first one.
+///
+/// Frames are taken one at a time until enough of them are adjacent; the ones that aren't part of the
+/// result are freed afterwards. It gives up after `MAX_CONTIGUOUS_RUNS` runs of adjacent frames, so it
+/// may fail while enough memory is free when memory is fragmented.
+pub fn allocate_contiguous_frames(count: u64) -> Option<PhysFrame> {
+    const MAX_CONTIGUOUS_RUNS: usize = 64;
+    if count == 0 {
+        return None;
+    }
+
+    let mut abandoned = [None::<(PhysFrame, u64)>; MAX_CONTIGUOUS_RUNS];
+    let mut run: Option<(PhysFrame, u64)> = None;
+    let mut found = None;
+
+    for slot in &mut abandoned {
+        while let Some(frame) = GlobalFrameAllocator.allocate_frame() {
+            match run {
+                Some((start, len)) if start + len == frame => run = Some((start, len + 1)),
+                // The frame before `start`, from the free list which hands out frames in reverse.
+                Some((start, len)) if frame + 1 == start => run = Some((frame, len + 1)),
+                Some(_) => {
+                    *slot = run.replace((frame, 1));
+                    break;
+                }
+                None => run = Some((frame, 1)),
+            }
+            if run.is_some_and(|(_, len)| len == count) {
+                found = run.take();
+                break;
+            }
+        }
+        if found.is_some() || slot.is_none() {
+            break;
+        }
+    }
+
+    for (start, len) in abandoned.into_iter().chain([run]).flatten() {
+        for frame in PhysFrame::range(start, start + len) {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+
+    let (start, len) = found?;
+    unsafe {
+        phys_to_virt(start.start_address())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, len as usize * 4096);
+    }
+    Some(start)
+}
+
 pub fn create_example_mapping(
     page: Page,
     mapper: &mut OffsetPageTable,
@@ -58,6 +292,8 @@ pub struct BootInfoFrameAllocator {
 
 impl BootInfoFrameAllocator {
     pub unsafe fn init(memory_map: &'static [MemoryRegion]) -> Self {
+        let _ = MEMORY_REGIONS.try_init_once(|| memory_map);
+
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
@@ -70,7 +306,9 @@ impl BootInfoFrameAllocator {
         let addr_ranges = usable_regions.map(|r| r.start..r.end);
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
 
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+        frame_addresses
+            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+            .filter(|&frame| !is_quarantined(frame))
     }
 }
 
@@ -80,6 +318,8 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
 
         if frame.is_none() {
             println!("EMPTY: {:#?}", self.next);
+        } else {
+            ALLOCATED_FRAMES.fetch_add(1, Ordering::Relaxed);
         }
 
         self.next += 1;
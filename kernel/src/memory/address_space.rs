@@ -0,0 +1,622 @@
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use x86_64::{
+    VirtAddr,
+    registers::control::Cr3,
+    structures::paging::{
+        FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+        Size4KiB, Translate, mapper::MapToError, page::PageRange, page_table::PageTableLevel,
+    },
+};
+
+use super::{GlobalFrameAllocator, allocate_zeroed_frame, phys_to_virt, physical_memory_offset};
+
+/// Start of the user part of every address space, covered by a single level 4 entry.
+pub const USER_START: VirtAddr = VirtAddr::new_truncate(0x0000_0080_0000_0000);
+/// End (exclusive) of the user part.
+pub const USER_END: VirtAddr = VirtAddr::new_truncate(0x0000_0100_0000_0000);
+
+const USER_LEVEL_4_INDEX: usize = 1;
+/// Marks user pages mapped to a frame the address space doesn't own, see `map_borrowed`.
+const BORROWED: PageTableFlags = PageTableFlags::BIT_9;
+
+/// The page tables set up by the bootloader, which every address space shares.
+static KERNEL_LEVEL_4_FRAME: OnceCell<PhysFrame> = OnceCell::uninit();
+
+pub(super) fn set_kernel(level_4_frame: PhysFrame) {
+    KERNEL_LEVEL_4_FRAME.get_or_init(|| level_4_frame);
+}
+
+/// Pages that are mapped to a zeroed frame the first time they are touched, see
+/// `AddressSpace::handle_fault`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnonymousRegion {
+    pub pages: PageRange,
+    pub flags: PageTableFlags,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// Part of the range is outside `USER_START..USER_END`.
+    OutsideUserPart,
+    /// The range overlaps an existing anonymous region.
+    Overlaps,
+}
+
+/// The pages in both `a` and `b`, possibly none.
+fn intersection(a: PageRange, b: PageRange) -> PageRange {
+    Page::range(a.start.max(b.start), a.end.min(b.end))
+}
+
+/// A set of page tables, identified by the frame of its level 4 table.
+///
+/// User address spaces map the kernel exactly like the kernel's own page tables, by sharing all level 4
+/// entries except the one for `USER_START..USER_END`, whose tables and frames belong to the address space
+/// and are freed with it. Kernel mappings added later in level 4 entries that were empty when the address
+/// space was created won't show up in it. None of the kernel is user-accessible, in its own tables or in
+/// the shared entries, except where `share_kernel_code` replaces them with copies.
+///
+/// Besides the pages mapped right away with `map_user`, a user address space can have anonymous regions,
+/// whose pages only get a frame once they are touched, and pages borrowed from the kernel with
+/// `map_borrowed`, whose frames it doesn't own.
+#[derive(Debug, PartialEq, Eq)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame<Size4KiB>,
+    /// Whether this is a user address space, owning its user part.
+    owned: bool,
+    /// Sorted by address and never overlapping.
+    anonymous: Vec<AnonymousRegion>,
+    /// The copy of the level 3 table that maps the kernel image, see `share_kernel_code`.
+    kernel_code: Option<PhysFrame>,
+}
+
+impl AddressSpace {
+    /// The address space in CR3.
+    pub fn current() -> Self {
+        let (level_4_frame, _) = Cr3::read();
+        AddressSpace {
+            level_4_frame,
+            owned: false,
+            anonymous: Vec::new(),
+            kernel_code: None,
+        }
+    }
+
+    /// The kernel's own page tables. Requires `memory::init`.
+    pub fn kernel() -> Self {
+        AddressSpace {
+            level_4_frame: *KERNEL_LEVEL_4_FRAME
+                .try_get()
+                .expect("memory::init not called"),
+            owned: false,
+            anonymous: Vec::new(),
+            kernel_code: None,
+        }
+    }
+
+    /// A new address space with the kernel mapped and nothing in the user part. Returns `None` if no
+    /// frame is left for its level 4 table.
+    pub fn new_user() -> Option<Self> {
+        let kernel = Self::kernel();
+        assert!(
+            kernel.level_4_table()[USER_LEVEL_4_INDEX].is_unused(),
+            "the kernel uses the user part of the address space"
+        );
+
+        let level_4_frame = allocate_zeroed_frame()?;
+        let space = AddressSpace {
+            level_4_frame,
+            owned: true,
+            anonymous: Vec::new(),
+            kernel_code: None,
+        };
+
+        let table = unsafe { space.level_4_table_mut() };
+        for (index, entry) in kernel.level_4_table().iter().enumerate() {
+            if index != USER_LEVEL_4_INDEX {
+                table[index] = entry.clone();
+            }
+        }
+
+        Some(space)
+    }
+
+    /// Lets ring 3 read and run the kernel's code and read-only data, for programs built into the kernel
+    /// (see `process::spawn_user`). The level 4 entry of the kernel image is replaced by copies of its
+    /// tables in which the pages mapped read-only are user-accessible; the kernel's own tables, and its
+    /// writable data, stay out of reach of ring 3.
+    pub fn share_kernel_code(&mut self) -> Result<(), MapToError<Size4KiB>> {
+        assert!(self.owned, "not a user address space");
+        if self.kernel_code.is_some() {
+            return Ok(());
+        }
+
+        let index = kernel_image_index();
+        let kernel = Self::kernel();
+        let kernel_entry = &kernel.level_4_table()[index];
+        let kernel_table = kernel_entry
+            .frame()
+            .expect("the kernel image isn't mapped with 4 KiB tables");
+        let copy = copy_user_readable(kernel_table, PageTableLevel::Three)
+            .ok_or(MapToError::FrameAllocationFailed)?;
+
+        let flags = kernel_entry.flags() | PageTableFlags::USER_ACCESSIBLE;
+        unsafe { self.level_4_table_mut()[index].set_addr(copy.start_address(), flags) };
+        self.kernel_code = Some(copy);
+        Ok(())
+    }
+
+    /// Whether ring 3 can reach `address` in this address space, which takes every level of the page
+    /// tables to allow it.
+    pub fn is_user_accessible(&self, address: VirtAddr) -> bool {
+        let mut table = self.level_4_table();
+        let mut level = PageTableLevel::Four;
+
+        loop {
+            let entry = &table[address.page_table_index(level)];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE) {
+                return false;
+            }
+
+            match (level.next_lower_level(), entry.frame()) {
+                (Some(lower), Ok(frame)) => {
+                    table = unsafe { &*phys_to_virt(frame.start_address()).as_ptr() };
+                    level = lower;
+                }
+                // A huge page, or a page of the level 1 table.
+                _ => return true,
+            }
+        }
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame<Size4KiB> {
+        self.level_4_frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Switches to this address space, unless it is already active (which would needlessly flush the TLB).
+    ///
+    /// # Safety
+    ///
+    /// The kernel, including the running code and stack, must be mapped identically in this address space.
+    pub unsafe fn activate(&self) {
+        if self.is_active() {
+            return;
+        }
+
+        let (_, flags) = Cr3::read();
+        unsafe { Cr3::write(self.level_4_frame, flags) };
+    }
+
+    fn level_4_table(&self) -> &PageTable {
+        unsafe { &*phys_to_virt(self.level_4_frame.start_address()).as_ptr() }
+    }
+
+    /// # Safety
+    ///
+    /// No other reference to the table may exist.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn level_4_table_mut(&self) -> &mut PageTable {
+        unsafe { &mut *phys_to_virt(self.level_4_frame.start_address()).as_mut_ptr() }
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe { OffsetPageTable::new(self.level_4_table_mut(), physical_memory_offset()) }
+    }
+
+    /// Maps `page`, which must be in the user part, to a new zeroed frame. The page is always
+    /// user-accessible; `flags` adds e.g. `WRITABLE`.
+    pub fn map_user(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<PhysFrame, MapToError<Size4KiB>> {
+        assert!(self.owned, "not a user address space");
+        assert!(
+            (USER_START..USER_END).contains(&page.start_address()),
+            "page outside the user part"
+        );
+
+        let frame = allocate_zeroed_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+        let result = unsafe {
+            self.mapper().map_to_with_table_flags(
+                page,
+                frame,
+                flags,
+                PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::USER_ACCESSIBLE,
+                &mut GlobalFrameAllocator,
+            )
+        };
+
+        match result {
+            // The TLB can't hold a translation for a page that wasn't mapped, so there is nothing to flush.
+            Ok(flush) => {
+                flush.ignore();
+                Ok(frame)
+            }
+            Err(error) => {
+                unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                Err(error)
+            }
+        }
+    }
+
+    /// Maps `page`, which must be in the user part, to `frame`, which stays owned by the caller: the address
+    /// space never frees it, and `fork` leaves the page out of the copy. The frame must outlive the mapping,
+    /// until `unmap_borrowed` or until the address space is dropped.
+    pub fn map_borrowed(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(self.owned, "not a user address space");
+        assert!(
+            (USER_START..USER_END).contains(&page.start_address()),
+            "page outside the user part"
+        );
+
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | BORROWED;
+        let flush = unsafe {
+            self.mapper().map_to_with_table_flags(
+                page,
+                frame,
+                flags,
+                PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::USER_ACCESSIBLE,
+                &mut GlobalFrameAllocator,
+            )?
+        };
+        // Like in `map_user`, the page wasn't mapped before.
+        flush.ignore();
+        Ok(())
+    }
+
+    /// Unmaps a page mapped with `map_borrowed`, leaving its frame to its owner. Does nothing if it isn't
+    /// mapped that way.
+    pub fn unmap_borrowed(&mut self, page: Page) {
+        if !self
+            .translate(page)
+            .is_some_and(|(_, flags)| flags.contains(BORROWED))
+        {
+            return;
+        }
+
+        let active = self.is_active();
+        if let Ok((_, flush)) = self.mapper().unmap(page) {
+            if active {
+                flush.flush();
+            } else {
+                flush.ignore();
+            }
+        }
+    }
+
+    /// Unmaps `page`, which must be in the user part, and frees its frame. Does nothing if it isn't mapped.
+    fn unmap_user(&mut self, page: Page) {
+        let active = self.is_active();
+
+        if let Ok((frame, flush)) = self.mapper().unmap(page) {
+            if active {
+                flush.flush();
+            } else {
+                flush.ignore();
+            }
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+
+    /// Adds an anonymous region covering `pages`, whose pages are mapped with `flags` (made
+    /// user-accessible, like `map_user`) once they are touched. The caller must keep the range clear of
+    /// pages mapped some other way, such as a loaded program.
+    pub fn add_anonymous(
+        &mut self,
+        pages: PageRange,
+        flags: PageTableFlags,
+    ) -> Result<(), RegionError> {
+        assert!(self.owned, "not a user address space");
+
+        if pages.is_empty() {
+            return Ok(());
+        }
+        if pages.start.start_address() < USER_START || pages.end.start_address() > USER_END {
+            return Err(RegionError::OutsideUserPart);
+        }
+        if self
+            .anonymous
+            .iter()
+            .any(|region| !intersection(region.pages, pages).is_empty())
+        {
+            return Err(RegionError::Overlaps);
+        }
+
+        let index = self
+            .anonymous
+            .partition_point(|region| region.pages.start < pages.start);
+        let mut region = AnonymousRegion { pages, flags };
+
+        // Regions that continue each other are merged, so a heap grown in small steps stays one region.
+        if let Some(next) = self.anonymous.get(index)
+            && next.pages.start == pages.end
+            && next.flags == flags
+        {
+            region.pages.end = next.pages.end;
+            self.anonymous.remove(index);
+        }
+        if let Some(previous) = index.checked_sub(1).map(|index| &mut self.anonymous[index])
+            && previous.pages.end == pages.start
+            && previous.flags == flags
+        {
+            previous.pages.end = region.pages.end;
+            return Ok(());
+        }
+
+        self.anonymous.insert(index, region);
+        Ok(())
+    }
+
+    /// Takes `pages` out of the anonymous regions, splitting them where needed, and frees the frames of
+    /// the pages that were touched.
+    pub fn remove_anonymous(&mut self, pages: PageRange) {
+        let mut kept = Vec::with_capacity(self.anonymous.len() + 1);
+
+        for region in core::mem::take(&mut self.anonymous) {
+            let removed = intersection(region.pages, pages);
+            if removed.is_empty() {
+                kept.push(region);
+                continue;
+            }
+
+            let before = Page::range(region.pages.start, removed.start);
+            let after = Page::range(removed.end, region.pages.end);
+            for pages in [before, after] {
+                if !pages.is_empty() {
+                    kept.push(AnonymousRegion { pages, ..region });
+                }
+            }
+
+            for page in removed {
+                self.unmap_user(page);
+            }
+        }
+
+        self.anonymous = kept;
+    }
+
+    pub fn anonymous_regions(&self) -> &[AnonymousRegion] {
+        &self.anonymous
+    }
+
+    /// Pages in anonymous regions, touched or not.
+    pub fn anonymous_pages(&self) -> u64 {
+        self.anonymous
+            .iter()
+            .map(|region| region.pages.end - region.pages.start)
+            .sum()
+    }
+
+    /// The highest `count` pages in `within` that are outside every anonymous region.
+    pub fn find_free(&self, count: u64, within: PageRange) -> Option<PageRange> {
+        let mut end = within.end;
+
+        for region in self.anonymous.iter().rev() {
+            if region.pages.start >= end {
+                continue;
+            }
+
+            let start = region.pages.end.max(within.start);
+            if start < end && end - start >= count {
+                return Some(Page::range(end - count, end));
+            }
+
+            end = region.pages.start;
+            if end <= within.start {
+                return None;
+            }
+        }
+
+        (within.start < end && end - within.start >= count).then(|| Page::range(end - count, end))
+    }
+
+    /// Resolves a page fault at `address` by mapping a zeroed frame, if the address is in an anonymous
+    /// region and its page hasn't been touched yet. Returns whether the access can be retried; it can't
+    /// when the page is already mapped (the fault was a protection violation) or no frame is left.
+    pub fn handle_fault(&mut self, address: VirtAddr) -> bool {
+        let page = Page::containing_address(address);
+
+        let Some(region) = self
+            .anonymous
+            .iter()
+            .find(|region| region.pages.start <= page && page < region.pages.end)
+            .copied()
+        else {
+            return false;
+        };
+
+        self.translate(page).is_none() && self.map_user(page, region.flags).is_ok()
+    }
+
+    /// The frame `page` is mapped to, with its flags.
+    pub fn translate(&mut self, page: Page) -> Option<(PhysFrame, PageTableFlags)> {
+        use x86_64::structures::paging::mapper::TranslateResult;
+
+        match self.mapper().translate(page.start_address()) {
+            TranslateResult::Mapped { frame, flags, .. } => {
+                Some((PhysFrame::containing_address(frame.start_address()), flags))
+            }
+            _ => None,
+        }
+    }
+
+    /// Mapped pages in the user part, with their frames and flags.
+    fn user_pages(&self, mut f: impl FnMut(Page, PhysFrame, PageTableFlags)) {
+        let level_4_entry = &self.level_4_table()[USER_LEVEL_4_INDEX];
+        walk(
+            level_4_entry.frame().ok(),
+            PageTableLevel::Three,
+            USER_START,
+            &mut f,
+        );
+    }
+
+    /// A copy of this address space whose user part has the same contents in new frames, for `fork`.
+    /// Anonymous pages that weren't touched yet stay that way in the copy.
+    pub fn fork(&self) -> Result<AddressSpace, MapToError<Size4KiB>> {
+        let mut child = AddressSpace::new_user().ok_or(MapToError::FrameAllocationFailed)?;
+        child.anonymous = self.anonymous.clone();
+        if self.kernel_code.is_some() {
+            child.share_kernel_code()?;
+        }
+        let mut result = Ok(());
+
+        self.user_pages(|page, frame, flags| {
+            if result.is_err() || flags.contains(BORROWED) {
+                return;
+            }
+
+            result = child.map_user(page, flags).map(|copy| unsafe {
+                core::ptr::copy_nonoverlapping(
+                    phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                    phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                    4096,
+                );
+            });
+        });
+
+        result.map(|()| child)
+    }
+}
+
+/// Calls `f` for every page mapped below the table in `frame`, which is at `level` and covers the
+/// addresses from `start`.
+fn walk(
+    frame: Option<PhysFrame>,
+    level: PageTableLevel,
+    start: VirtAddr,
+    f: &mut impl FnMut(Page, PhysFrame, PageTableFlags),
+) {
+    let Some(frame) = frame else {
+        return;
+    };
+    let table: &PageTable = unsafe { &*phys_to_virt(frame.start_address()).as_ptr() };
+
+    for (index, entry) in table.iter().enumerate() {
+        let Ok(entry_frame) = entry.frame() else {
+            continue;
+        };
+        let address = start + index as u64 * level.entry_address_space_alignment();
+
+        match level.next_lower_level() {
+            Some(lower) => walk(Some(entry_frame), lower, address, f),
+            None => f(
+                Page::containing_address(address),
+                entry_frame,
+                entry.flags(),
+            ),
+        }
+    }
+}
+
+/// The level 4 index of the kernel image, which the bootloader gives a level 4 entry of its own.
+fn kernel_image_index() -> usize {
+    usize::from(VirtAddr::from_ptr(kernel_image_index as *const ()).p4_index())
+}
+
+/// A copy of the table in `frame`, at `level`, and of the tables below it, in which the pages mapped
+/// read-only are user-accessible, like the tables leading to them. The pages themselves aren't copied.
+/// Huge pages are left out of reach of ring 3. Returns `None` if no frame is left.
+fn copy_user_readable(frame: PhysFrame, level: PageTableLevel) -> Option<PhysFrame> {
+    let table: &PageTable = unsafe { &*phys_to_virt(frame.start_address()).as_ptr() };
+    let copy_frame = allocate_zeroed_frame()?;
+    let copy: &mut PageTable =
+        unsafe { &mut *phys_to_virt(copy_frame.start_address()).as_mut_ptr() };
+
+    for (entry, copied) in table.iter().zip(copy.iter_mut()) {
+        let flags = entry.flags();
+        let Ok(entry_frame) = entry.frame() else {
+            *copied = entry.clone();
+            continue;
+        };
+
+        match level.next_lower_level() {
+            Some(lower) => {
+                let Some(lower_copy) = copy_user_readable(entry_frame, lower) else {
+                    free_table_copies(copy_frame, level);
+                    return None;
+                };
+                copied.set_addr(
+                    lower_copy.start_address(),
+                    flags | PageTableFlags::USER_ACCESSIBLE,
+                );
+            }
+            None if flags.contains(PageTableFlags::WRITABLE) => *copied = entry.clone(),
+            None => copied.set_addr(
+                entry_frame.start_address(),
+                flags | PageTableFlags::USER_ACCESSIBLE,
+            ),
+        }
+    }
+
+    Some(copy_frame)
+}
+
+/// Frees a table made by `copy_user_readable` and the copies below it, but not the frames they map,
+/// which belong to the kernel.
+fn free_table_copies(frame: PhysFrame, level: PageTableLevel) {
+    let table: &PageTable = unsafe { &*phys_to_virt(frame.start_address()).as_ptr() };
+
+    if let Some(lower) = level.next_lower_level() {
+        for entry in table.iter() {
+            if let Ok(entry_frame) = entry.frame() {
+                free_table_copies(entry_frame, lower);
+            }
+        }
+    }
+
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+}
+
+/// Frees the page tables below the table in `frame` (at `level`), the frames they map except borrowed
+/// ones, and the table itself.
+fn free_tables(frame: PhysFrame, level: PageTableLevel) {
+    let table: &PageTable = unsafe { &*phys_to_virt(frame.start_address()).as_ptr() };
+
+    for entry in table.iter() {
+        if let Ok(entry_frame) = entry.frame() {
+            match level.next_lower_level() {
+                Some(lower) => free_tables(entry_frame, lower),
+                None if entry.flags().contains(BORROWED) => {}
+                None => unsafe { GlobalFrameAllocator.deallocate_frame(entry_frame) },
+            }
+        }
+    }
+
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
+
+        assert!(!self.is_active(), "dropping the active address space");
+
+        if let Ok(frame) = self.level_4_table()[USER_LEVEL_4_INDEX].frame() {
+            free_tables(frame, PageTableLevel::Three);
+        }
+        if let Some(frame) = self.kernel_code {
+            free_table_copies(frame, PageTableLevel::Three);
+        }
+        unsafe { GlobalFrameAllocator.deallocate_frame(self.level_4_frame) };
+    }
+}
@@ -0,0 +1,109 @@
+//! Hex dumps of kernel memory for debugging.
+//!
+//! Every page of the range is checked against the active page table before anything is read, so a bad
+//! address produces an error instead of a page fault.
+
+use core::fmt::{self, Write};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{is_mapped, phys_to_virt};
+
+const BYTES_PER_LINE: u64 = 16;
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpError {
+    /// The range wraps around the end of the address space.
+    InvalidRange,
+    /// The first address in the range that isn't mapped.
+    Unmapped(VirtAddr),
+    Write,
+}
+
+impl From<fmt::Error> for DumpError {
+    fn from(_: fmt::Error) -> Self {
+        DumpError::Write
+    }
+}
+
+/// Checks that every page touched by `[start, start + len)` is mapped.
+fn validate(start: VirtAddr, len: u64) -> Result<(), DumpError> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    let end = start
+        .as_u64()
+        .checked_add(len - 1)
+        .and_then(|end| VirtAddr::try_new(end).ok())
+        .ok_or(DumpError::InvalidRange)?;
+
+    let mut page = start.align_down(PAGE_SIZE);
+    while page <= end {
+        if !is_mapped(page) {
+            return Err(DumpError::Unmapped(page.max(start)));
+        }
+        page += PAGE_SIZE;
+    }
+
+    Ok(())
+}
+
+/// Writes `len` bytes starting at `start` as hex and ASCII, 16 bytes per line.
+pub fn hexdump(output: &mut impl Write, start: VirtAddr, len: u64) -> Result<(), DumpError> {
+    validate(start, len)?;
+    write_lines(output, start, start.as_u64(), len)
+}
+
+/// Like `hexdump`, but for physical memory, which is read through the physical memory mapping.
+///
+/// Reading device memory can have side effects, so this is best limited to RAM.
+pub fn pmemdump(output: &mut impl Write, start: PhysAddr, len: u64) -> Result<(), DumpError> {
+    let virtual_start = phys_to_virt(start);
+    validate(virtual_start, len)?;
+    write_lines(output, virtual_start, start.as_u64(), len)
+}
+
+/// Dumps memory that has already been validated, labelling each line with `label_start` plus its offset.
+fn write_lines(
+    output: &mut impl Write,
+    start: VirtAddr,
+    label_start: u64,
+    len: u64,
+) -> Result<(), DumpError> {
+    let mut offset = 0;
+
+    while offset < len {
+        let line_len = (len - offset).min(BYTES_PER_LINE) as usize;
+        let mut line = [0u8; BYTES_PER_LINE as usize];
+
+        for (index, byte) in line[..line_len].iter_mut().enumerate() {
+            let address = start + offset + index as u64;
+            *byte = unsafe { address.as_ptr::<u8>().read_volatile() };
+        }
+
+        write!(output, "{:016x} ", label_start + offset)?;
+        for (index, byte) in line.iter().enumerate() {
+            if index < line_len {
+                write!(output, " {:02x}", byte)?;
+            } else {
+                write!(output, "   ")?;
+            }
+        }
+
+        write!(output, "  |")?;
+        for &byte in &line[..line_len] {
+            let printable = if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            };
+            output.write_char(printable)?;
+        }
+        writeln!(output, "|")?;
+
+        offset += line_len as u64;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,209 @@
+//! Boot-time memory test, built in with the `memtest` feature.
+//!
+//! Flaky RAM shows up as corruption that is impossible to track down, so on machines of doubtful health
+//! every usable frame can be tested before the frame allocator hands any of them out. Frames that fail
+//! are reported and quarantined, so they are never allocated.
+//!
+//! Each frame goes through three patterns:
+//!
+//! - walking ones on the first word, which catches data lines that are stuck or shorted together;
+//! - every word holding its own address, which catches address lines that alias other locations;
+//! - pseudo-random values, which catch cells that only fail with certain neighbours.
+//!
+//! The frame is zeroed afterwards. Testing takes a while on large machines, which is why it is opt-in.
+
+use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+use core::fmt;
+use x86_64::{PhysAddr, structures::paging::PhysFrame};
+
+use super::{phys_to_virt, quarantine};
+use crate::println;
+
+const WORDS_PER_FRAME: usize = 4096 / 8;
+/// Frames between progress reports.
+const PROGRESS_INTERVAL: u64 = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    WalkingOnes,
+    AddressInAddress,
+    Random,
+}
+
+/// A word that didn't read back what was written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub address: PhysAddr,
+    pub pattern: Pattern,
+    pub expected: u64,
+    pub found: u64,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}: {:?} wrote {:#018x}, read {:#018x} (bits {:#018x} differ)",
+            self.address.as_u64(),
+            self.pattern,
+            self.expected,
+            self.found,
+            self.expected ^ self.found
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Report {
+    pub tested: u64,
+    pub bad: u64,
+    /// Bad frames that couldn't be quarantined because the quarantine was full.
+    pub not_quarantined: u64,
+}
+
+/// xorshift64, seeded per block so a fault can be reproduced.
+fn random_words(seed: u64) -> impl Iterator<Item = u64> {
+    let mut state = seed | 1;
+
+    core::iter::repeat_with(move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    })
+}
+
+/// Runs the patterns over `words`, which live at physical address `base`, and zeroes them. Returns the
+/// first fault.
+pub fn test_words(words: &mut [u64], base: PhysAddr) -> Result<(), Fault> {
+    let fault = |index: usize, pattern, expected, found| Fault {
+        address: base + index as u64 * 8,
+        pattern,
+        expected,
+        found,
+    };
+    let len = words.len();
+    let words = words.as_mut_ptr();
+
+    // Volatile accesses, so the compiler can't assume a read returns what was just written.
+    let write = |index: usize, value: u64| unsafe { words.add(index).write_volatile(value) };
+    let read = |index: usize| unsafe { words.add(index).read_volatile() };
+
+    if len > 0 {
+        for bit in 0..u64::BITS {
+            let value = 1 << bit;
+            write(0, value);
+
+            let found = read(0);
+            if found != value {
+                return Err(fault(0, Pattern::WalkingOnes, value, found));
+            }
+        }
+    }
+
+    let address = |index: usize| base.as_u64() + index as u64 * 8;
+    for index in 0..len {
+        write(index, address(index));
+    }
+    for index in 0..len {
+        let found = read(index);
+        if found != address(index) {
+            return Err(fault(
+                index,
+                Pattern::AddressInAddress,
+                address(index),
+                found,
+            ));
+        }
+    }
+
+    for (index, value) in random_words(base.as_u64()).take(len).enumerate() {
+        write(index, value);
+    }
+    for (index, value) in random_words(base.as_u64()).take(len).enumerate() {
+        let found = read(index);
+        if found != value {
+            return Err(fault(index, Pattern::Random, value, found));
+        }
+    }
+
+    for index in 0..len {
+        write(index, 0);
+    }
+
+    Ok(())
+}
+
+/// Tests every usable frame in `regions` and quarantines the bad ones. Must run after `memory::init` and
+/// before any frame is allocated.
+pub fn run(regions: &[MemoryRegion]) -> Report {
+    let mut report = Report::default();
+
+    let frames = regions
+        .iter()
+        .filter(|region| region.kind == MemoryRegionKind::Usable)
+        .flat_map(|region| (region.start..region.end).step_by(4096))
+        .map(|address| PhysFrame::containing_address(PhysAddr::new(address)));
+
+    println!("memtest: testing usable memory");
+
+    for frame in frames {
+        let words = unsafe {
+            core::slice::from_raw_parts_mut(
+                phys_to_virt(frame.start_address()).as_mut_ptr::<u64>(),
+                WORDS_PER_FRAME,
+            )
+        };
+
+        if let Err(fault) = test_words(words, frame.start_address()) {
+            println!("memtest: bad frame at {}", fault);
+            report.bad += 1;
+
+            if !quarantine(frame) {
+                report.not_quarantined += 1;
+            }
+        }
+
+        report.tested += 1;
+        if report.tested % PROGRESS_INTERVAL == 0 {
+            println!(
+                "memtest: {} MiB tested",
+                report.tested * 4096 / (1024 * 1024)
+            );
+        }
+    }
+
+    println!(
+        "memtest: {} frames tested, {} bad",
+        report.tested, report.bad
+    );
+    if report.not_quarantined > 0 {
+        println!(
+            "WARNING: memtest: {} bad frames couldn't be quarantined and may be allocated",
+            report.not_quarantined
+        );
+    }
+
+    report
+}
+
+#[test_case]
+fn test_good_memory_passes_and_is_zeroed() {
+    let mut words = [0xFFFF_FFFF_FFFF_FFFFu64; 64];
+
+    assert_eq!(test_words(&mut words, PhysAddr::new(0x10_0000)), Ok(()));
+    assert!(words.iter().all(|&word| word == 0));
+}
+
+#[test_case]
+fn test_random_pattern_depends_on_the_address() {
+    let first: [u64; 4] = core::array::from_fn({
+        let mut words = random_words(0x1000);
+        move |_| words.next().unwrap()
+    });
+    let second = random_words(0x2000).next().unwrap();
+
+    assert_ne!(first[0], second);
+    assert_ne!(first[0], first[1]);
+    assert_eq!(random_words(0x1000).next(), Some(first[0]));
+}
@@ -0,0 +1,143 @@
+//! Mitigations for speculative execution attacks.
+//!
+//! CPUs that are affected expose controls through MSRs, advertised by CPUID leaf 7:
+//! - IA32_SPEC_CTRL: IBRS keeps less privileged code from steering indirect branch prediction, STIBP
+//!   does the same between sibling hyperthreads, SSBD disables speculative store bypass.
+//! - IA32_PRED_CMD: IBPB flushes the indirect branch predictors, meant for switches between untrusted
+//!   contexts.
+//!
+//! Everything the CPU supports is turned on by `init`, unless the kernel is built with the
+//! `no-mitigations` feature.
+//!
+//! User address spaces keep ring 3 away from the kernel: only programs built into the kernel reach its
+//! code and read-only data, through their own copies of the tables that map them (see
+//! `AddressSpace::share_kernel_code`), and nothing else of the kernel is user-accessible. The rest of the
+//! kernel is still mapped in them for ring 0, though, and Meltdown reads such mappings speculatively.
+//! Mitigating it would take switching page tables on every entry into the kernel: it is only reported.
+
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use x86_64::registers::model_specific::Msr;
+
+use crate::cpu;
+
+const CPUID_SPEC_CTRL: u32 = 1 << 26;
+const CPUID_STIBP: u32 = 1 << 27;
+const CPUID_ARCH_CAPABILITIES: u32 = 1 << 29;
+const CPUID_SSBD: u32 = 1 << 31;
+
+const IA32_SPEC_CTRL: u32 = 0x48;
+const IA32_PRED_CMD: u32 = 0x49;
+const IA32_ARCH_CAPABILITIES: u32 = 0x10A;
+
+const SPEC_CTRL_IBRS: u64 = 1 << 0;
+const SPEC_CTRL_STIBP: u64 = 1 << 1;
+const SPEC_CTRL_SSBD: u64 = 1 << 2;
+const PRED_CMD_IBPB: u64 = 1 << 0;
+/// The CPU isn't affected by rogue data cache loads (Meltdown).
+const ARCH_CAPABILITIES_RDCL_NO: u64 = 1 << 0;
+
+static ACTIVE: OnceCell<Mitigations> = OnceCell::uninit();
+
+/// Mitigations in effect.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Mitigations {
+    pub ibrs: bool,
+    pub stibp: bool,
+    pub ssbd: bool,
+    /// IBPB is issued by `on_context_switch`.
+    pub ibpb: bool,
+    /// The CPU is affected by Meltdown, which stays unmitigated, see the module documentation.
+    pub meltdown_vulnerable: bool,
+}
+
+impl fmt::Display for Mitigations {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+
+        write!(
+            f,
+            "ibrs: {}, stibp: {}, ssbd: {}, ibpb: {}, meltdown: {}",
+            on_off(self.ibrs),
+            on_off(self.stibp),
+            on_off(self.ssbd),
+            on_off(self.ibpb),
+            if self.meltdown_vulnerable {
+                "vulnerable"
+            } else {
+                "not affected"
+            }
+        )
+    }
+}
+
+/// Enables every mitigation the CPU supports.
+pub fn init() {
+    ACTIVE.get_or_init(|| {
+        let features = if cpu::cpuid(0, 0).eax >= 7 {
+            cpu::cpuid(7, 0).edx
+        } else {
+            0
+        };
+
+        let arch_capabilities = if features & CPUID_ARCH_CAPABILITIES != 0 {
+            unsafe { Msr::new(IA32_ARCH_CAPABILITIES).read() }
+        } else {
+            0
+        };
+
+        let mut mitigations = Mitigations {
+            meltdown_vulnerable: arch_capabilities & ARCH_CAPABILITIES_RDCL_NO == 0,
+            ..Mitigations::default()
+        };
+
+        if cfg!(feature = "no-mitigations") {
+            return mitigations;
+        }
+
+        let mut spec_ctrl = 0;
+        if features & CPUID_SPEC_CTRL != 0 {
+            spec_ctrl |= SPEC_CTRL_IBRS;
+            mitigations.ibrs = true;
+            mitigations.ibpb = true;
+        }
+        if features & CPUID_STIBP != 0 {
+            spec_ctrl |= SPEC_CTRL_STIBP;
+            mitigations.stibp = true;
+        }
+        if features & CPUID_SSBD != 0 {
+            spec_ctrl |= SPEC_CTRL_SSBD;
+            mitigations.ssbd = true;
+        }
+
+        if spec_ctrl != 0 {
+            unsafe { Msr::new(IA32_SPEC_CTRL).write(spec_ctrl) };
+        }
+
+        mitigations
+    });
+}
+
+/// The mitigations enabled by `init`, all off before it ran.
+pub fn active() -> Mitigations {
+    ACTIVE.get().copied().unwrap_or_default()
+}
+
+/// Flushes the branch predictors when switching to a different user context, so it can't influence the
+/// speculation of the next one.
+pub fn on_context_switch() {
+    if active().ibpb {
+        unsafe { Msr::new(IA32_PRED_CMD).write(PRED_CMD_IBPB) };
+    }
+}
+
+#[test_case]
+fn test_only_supported_mitigations_are_active() {
+    let features = cpu::cpuid(7, 0).edx;
+    let mitigations = active();
+
+    assert!(!mitigations.ibrs || features & CPUID_SPEC_CTRL != 0);
+    assert!(!mitigations.stibp || features & CPUID_STIBP != 0);
+    assert!(!mitigations.ssbd || features & CPUID_SSBD != 0);
+    on_context_switch();
+}
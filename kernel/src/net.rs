@@ -0,0 +1,65 @@
+//! Network devices: Ethernet cards that send and receive whole frames.
+//!
+//! Drivers implement `Device`. Frames are Ethernet frames without the frame check sequence, which the
+//! card adds and strips itself. Sending returns once the card has the frame; receiving waits for the
+//! next frame to arrive.
+
+use core::{fmt, future::Future};
+
+pub mod e1000;
+
+/// The largest frame without a VLAN tag: a 14 byte header and 1500 bytes of payload.
+pub const MAX_FRAME: usize = 1514;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// The frame is longer than `MAX_FRAME`.
+    FrameTooLarge,
+    /// The buffer is too small for the frame received, which is dropped.
+    BufferTooSmall(usize),
+}
+
+pub trait Device {
+    fn mac(&self) -> MacAddress;
+
+    /// Whether a cable is plugged in and the link negotiated.
+    fn link_up(&self) -> bool;
+
+    /// Queues `frame` to be sent.
+    fn transmit(&self, frame: &[u8]) -> impl Future<Output = Result<(), NetError>>;
+
+    /// Waits for a frame, copies it into `buffer` and returns its length.
+    fn receive(&self, buffer: &mut [u8]) -> impl Future<Output = Result<usize, NetError>>;
+}
+
+impl<D: Device> Device for &D {
+    fn mac(&self) -> MacAddress {
+        (**self).mac()
+    }
+
+    fn link_up(&self) -> bool {
+        (**self).link_up()
+    }
+
+    fn transmit(&self, frame: &[u8]) -> impl Future<Output = Result<(), NetError>> {
+        (**self).transmit(frame)
+    }
+
+    fn receive(&self, buffer: &mut [u8]) -> impl Future<Output = Result<usize, NetError>> {
+        (**self).receive(buffer)
+    }
+}
@@ -0,0 +1,527 @@
+//! The Intel 82540EM gigabit Ethernet controller, the e1000 that QEMU emulates, and its close relatives.
+//!
+//! The registers are in BAR 0. Frames go through two rings of descriptors in memory, one to receive and
+//! one to transmit, each descriptor pointing at a `BUFFER_SIZE` buffer. The card owns the descriptors
+//! from the ring's head to its tail: it fills receive buffers and hands them back by setting their done
+//! bit, and the driver gives them to the card again by moving the tail past them. Transmitting goes the
+//! other way round. The MAC address is read from the EEPROM.
+//!
+//! Tasks waiting for a frame, or for room in the transmit ring, sleep until the card interrupts. Without
+//! an interrupt line, they poll the card each time the executor runs them.
+
+use conquer_once::spin::OnceCell;
+use core::{
+    future::poll_fn,
+    mem::offset_of,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Poll,
+};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{Device, MAX_FRAME, MacAddress, NetError};
+use crate::{
+    dma::{DeviceBuffer, DmaBuffer},
+    driver::{Bus, Busy, Claims, Driver, PciMatch, Priority, ProbeError},
+    init_state::{AlreadyInitialized, InitState},
+    interrupts, memory,
+    pci::{self, Bar},
+    println,
+    time::pit,
+};
+
+const VENDOR_INTEL: u16 = 0x8086;
+/// The 82540EM, and the 82545EM which works the same way.
+const DEVICE_IDS: [u16; 2] = [0x100e, 0x100f];
+
+const REG_CTRL: u64 = 0x0000;
+const REG_STATUS: u64 = 0x0008;
+const REG_EERD: u64 = 0x0014;
+const REG_ICR: u64 = 0x00c0;
+const REG_IMS: u64 = 0x00d0;
+const REG_IMC: u64 = 0x00d8;
+const REG_RCTL: u64 = 0x0100;
+const REG_TCTL: u64 = 0x0400;
+const REG_TIPG: u64 = 0x0410;
+const REG_RDBAL: u64 = 0x2800;
+const REG_RDBAH: u64 = 0x2804;
+const REG_RDLEN: u64 = 0x2808;
+const REG_RDH: u64 = 0x2810;
+const REG_RDT: u64 = 0x2818;
+const REG_TDBAL: u64 = 0x3800;
+const REG_TDBAH: u64 = 0x3804;
+const REG_TDLEN: u64 = 0x3808;
+const REG_TDH: u64 = 0x3810;
+const REG_TDT: u64 = 0x3818;
+/// The multicast table, of `MTA_ENTRIES` registers.
+const REG_MTA: u64 = 0x5200;
+const MTA_ENTRIES: u64 = 128;
+const REG_RAL: u64 = 0x5400;
+const REG_RAH: u64 = 0x5404;
+
+const CTRL_AUTO_SPEED: u32 = 1 << 5;
+const CTRL_SET_LINK_UP: u32 = 1 << 6;
+const CTRL_RESET: u32 = 1 << 26;
+const STATUS_LINK_UP: u32 = 1 << 1;
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+/// The address being valid, in the high half of a receive address.
+const RAH_VALID: u32 = 1 << 31;
+
+/// A transmit descriptor was written back.
+const INT_TXDW: u32 = 1 << 0;
+/// The free receive descriptors ran low.
+const INT_RXDMT0: u32 = 1 << 4;
+/// A frame was dropped for lack of receive descriptors.
+const INT_RX_OVERRUN: u32 = 1 << 6;
+const INT_RX_TIMER: u32 = 1 << 7;
+const INT_RX: u32 = INT_RXDMT0 | INT_RX_OVERRUN | INT_RX_TIMER;
+
+const RCTL_ENABLE: u32 = 1 << 1;
+const RCTL_BROADCAST: u32 = 1 << 15;
+/// Strip the CRC, which `Device` frames don't have. Buffer size bits left clear select 2048 bytes.
+const RCTL_STRIP_CRC: u32 = 1 << 26;
+const TCTL_ENABLE: u32 = 1 << 1;
+const TCTL_PAD_SHORT: u32 = 1 << 3;
+const TCTL_COLLISION_THRESHOLD: u32 = 0x0f << 4;
+const TCTL_COLLISION_DISTANCE: u32 = 0x40 << 12;
+/// The inter-packet gap the manual gives for copper.
+const TIPG_COPPER: u32 = 10 | (8 << 10) | (6 << 20);
+
+const RX_DONE: u8 = 1 << 0;
+const RX_END_OF_PACKET: u8 = 1 << 1;
+const TX_END_OF_PACKET: u8 = 1 << 0;
+const TX_INSERT_CRC: u8 = 1 << 1;
+const TX_REPORT_STATUS: u8 = 1 << 3;
+
+/// Descriptors in each ring, which fill one page.
+const RING_SIZE: usize = 32;
+const DESCRIPTOR_SIZE: usize = 16;
+const BUFFER_SIZE: usize = 2048;
+/// How long the reset and the EEPROM are waited for, in milliseconds.
+const TIMEOUT_MILLIS: u32 = 100;
+
+static INIT: InitState = InitState::new("e1000");
+static DEVICE: OnceCell<E1000> = OnceCell::uninit();
+/// Whether the card interrupts, rather than waiting tasks polling it.
+static INTERRUPTS: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum E1000Error {
+    /// The card has no memory BAR 0.
+    NoRegisters,
+    /// The registers aren't in the physical memory mapping.
+    NotMapped(PhysAddr),
+    /// The card didn't come out of reset.
+    ResetTimeout,
+    OutOfMemory,
+    Busy(Busy),
+    AlreadyInitialized(AlreadyInitialized),
+}
+
+impl From<Busy> for E1000Error {
+    fn from(error: Busy) -> Self {
+        E1000Error::Busy(error)
+    }
+}
+
+impl From<AlreadyInitialized> for E1000Error {
+    fn from(error: AlreadyInitialized) -> Self {
+        E1000Error::AlreadyInitialized(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Registers(VirtAddr);
+
+impl Registers {
+    fn read(self, offset: u64) -> u32 {
+        unsafe { (self.0 + offset).as_ptr::<u32>().read_volatile() }
+    }
+
+    fn write(self, offset: u64, value: u32) {
+        unsafe { (self.0 + offset).as_mut_ptr::<u32>().write_volatile(value) }
+    }
+
+    /// Waits until `done` returns true for register `offset`, and returns its last value if it did in
+    /// time.
+    fn wait(self, offset: u64, done: impl Fn(u32) -> bool) -> Option<u32> {
+        for _ in 0..TIMEOUT_MILLIS {
+            let value = self.read(offset);
+            if done(value) {
+                return Some(value);
+            }
+            pit::busy_wait_micros(1000);
+        }
+        None
+    }
+}
+
+#[repr(C)]
+struct RxDescriptor {
+    address: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[repr(C)]
+struct TxDescriptor {
+    address: u64,
+    length: u16,
+    checksum_offset: u8,
+    command: u8,
+    status: u8,
+    checksum_start: u8,
+    special: u16,
+}
+
+/// The MAC address from the first three EEPROM words, which hold it in little endian.
+fn mac_from_words(words: [u16; 3]) -> MacAddress {
+    let [a, b] = words[0].to_le_bytes();
+    let [c, d] = words[1].to_le_bytes();
+    let [e, f] = words[2].to_le_bytes();
+    MacAddress([a, b, c, d, e, f])
+}
+
+pub struct E1000 {
+    address: pci::Address,
+    registers: Registers,
+    rx_ring: DeviceBuffer,
+    tx_ring: DeviceBuffer,
+    /// The receive buffers, then the transmit buffers.
+    buffers: DeviceBuffer,
+    /// The next receive descriptor the card will fill.
+    rx_next: Mutex<usize>,
+    /// The next transmit descriptor to fill, the ring's tail.
+    tx_tail: Mutex<usize>,
+    rx_waker: AtomicWaker,
+    tx_waker: AtomicWaker,
+    mac: MacAddress,
+}
+
+impl E1000 {
+    pub fn address(&self) -> pci::Address {
+        self.address
+    }
+
+    /// Where `field` of descriptor `index` is in its ring.
+    fn descriptor(index: usize, field: usize) -> usize {
+        index * DESCRIPTOR_SIZE + field
+    }
+
+    /// Where buffer `index` of the receive ring, or of the transmit ring if `tx`, is in `buffers`.
+    fn buffer(tx: bool, index: usize) -> usize {
+        let buffer = if tx { RING_SIZE + index } else { index };
+        buffer * BUFFER_SIZE
+    }
+
+    /// Reads word `address` of the EEPROM.
+    fn read_eeprom(&self, address: u8) -> Option<u16> {
+        self.registers
+            .write(REG_EERD, (u32::from(address) << 8) | EERD_START);
+        let value = self
+            .registers
+            .wait(REG_EERD, |value| value & EERD_DONE != 0)?;
+        Some((value >> 16) as u16)
+    }
+
+    fn read_mac(&self) -> MacAddress {
+        let words = [0, 1, 2].map(|address| self.read_eeprom(address));
+        if let [Some(a), Some(b), Some(c)] = words {
+            return mac_from_words([a, b, c]);
+        }
+
+        // Without an EEPROM, the firmware may have set the receive address itself.
+        let low = self.registers.read(REG_RAL).to_le_bytes();
+        let high = self.registers.read(REG_RAH).to_le_bytes();
+        MacAddress([low[0], low[1], low[2], low[3], high[0], high[1]])
+    }
+
+    fn reset(&self) -> Result<(), E1000Error> {
+        self.registers.write(REG_IMC, !0);
+        self.registers
+            .write(REG_CTRL, self.registers.read(REG_CTRL) | CTRL_RESET);
+        // The registers can't be read for a moment after the reset starts.
+        pit::busy_wait_micros(1000);
+        self.registers
+            .wait(REG_CTRL, |value| value & CTRL_RESET == 0)
+            .ok_or(E1000Error::ResetTimeout)?;
+
+        // The reset enables interrupts again.
+        self.registers.write(REG_IMC, !0);
+        self.registers.read(REG_ICR);
+        self.registers.write(
+            REG_CTRL,
+            self.registers.read(REG_CTRL) | CTRL_SET_LINK_UP | CTRL_AUTO_SPEED,
+        );
+        Ok(())
+    }
+
+    fn set_up_rings(&self) {
+        let [a, b, c, d, e, f] = self.mac.0;
+        self.registers
+            .write(REG_RAL, u32::from_le_bytes([a, b, c, d]));
+        self.registers
+            .write(REG_RAH, u32::from(u16::from_le_bytes([e, f])) | RAH_VALID);
+        for entry in 0..MTA_ENTRIES {
+            self.registers.write(REG_MTA + entry * 4, 0);
+        }
+
+        let buffers = self.buffers.bus_address();
+        for index in 0..RING_SIZE {
+            self.rx_ring.write(
+                Self::descriptor(index, offset_of!(RxDescriptor, address)),
+                (buffers + Self::buffer(false, index) as u64).as_u64(),
+            );
+            self.tx_ring.write(
+                Self::descriptor(index, offset_of!(TxDescriptor, address)),
+                (buffers + Self::buffer(true, index) as u64).as_u64(),
+            );
+        }
+        let ring_bytes = (RING_SIZE * DESCRIPTOR_SIZE) as u32;
+
+        let rx_ring = self.rx_ring.bus_address().as_u64();
+        self.registers.write(REG_RDBAL, rx_ring as u32);
+        self.registers.write(REG_RDBAH, (rx_ring >> 32) as u32);
+        self.registers.write(REG_RDLEN, ring_bytes);
+        self.registers.write(REG_RDH, 0);
+        // Every descriptor but the last is the card's. The tail can't reach the head, which would mean
+        // the ring is empty.
+        self.registers.write(REG_RDT, (RING_SIZE - 1) as u32);
+        self.registers
+            .write(REG_RCTL, RCTL_ENABLE | RCTL_BROADCAST | RCTL_STRIP_CRC);
+
+        let tx_ring = self.tx_ring.bus_address().as_u64();
+        self.registers.write(REG_TDBAL, tx_ring as u32);
+        self.registers.write(REG_TDBAH, (tx_ring >> 32) as u32);
+        self.registers.write(REG_TDLEN, ring_bytes);
+        self.registers.write(REG_TDH, 0);
+        self.registers.write(REG_TDT, 0);
+        self.registers.write(
+            REG_TCTL,
+            TCTL_ENABLE | TCTL_PAD_SHORT | TCTL_COLLISION_THRESHOLD | TCTL_COLLISION_DISTANCE,
+        );
+        self.registers.write(REG_TIPG, TIPG_COPPER);
+    }
+
+    /// Copies the next frame received into `buffer`, if there is one. Frames with errors are dropped.
+    fn try_receive(&self, buffer: &mut [u8]) -> Option<Result<usize, NetError>> {
+        let mut next = self.rx_next.lock();
+
+        loop {
+            let field = |field| Self::descriptor(*next, field);
+            let status: u8 = self.rx_ring.read(field(offset_of!(RxDescriptor, status)));
+            if status & RX_DONE == 0 {
+                return None;
+            }
+
+            let length: u16 = self.rx_ring.read(field(offset_of!(RxDescriptor, length)));
+            let length = usize::from(length);
+            let errors: u8 = self.rx_ring.read(field(offset_of!(RxDescriptor, errors)));
+            // Buffers are larger than any frame without a VLAN tag, so a frame never spans several.
+            let result = if status & RX_END_OF_PACKET == 0 || errors != 0 {
+                None
+            } else if length > buffer.len() {
+                Some(Err(NetError::BufferTooSmall(length)))
+            } else {
+                self.buffers
+                    .read_bytes(Self::buffer(false, *next), &mut buffer[..length]);
+                Some(Ok(length))
+            };
+
+            self.rx_ring
+                .write(field(offset_of!(RxDescriptor, status)), 0u8);
+            self.registers.write(REG_RDT, *next as u32);
+            *next = (*next + 1) % RING_SIZE;
+
+            if result.is_some() {
+                return result;
+            }
+        }
+    }
+
+    /// Queues `frame`, if there is room in the ring.
+    fn try_transmit(&self, frame: &[u8]) -> Option<()> {
+        let mut tail = self.tx_tail.lock();
+        let next = (*tail + 1) % RING_SIZE;
+        // Filling the last free descriptor would make the ring look empty.
+        if next == self.registers.read(REG_TDH) as usize {
+            return None;
+        }
+
+        // The head went past the descriptor, so the card is done with its buffer.
+        self.buffers.write_bytes(Self::buffer(true, *tail), frame);
+        let field = |field| Self::descriptor(*tail, field);
+        self.tx_ring
+            .write(field(offset_of!(TxDescriptor, length)), frame.len() as u16);
+        self.tx_ring
+            .write(field(offset_of!(TxDescriptor, status)), 0u8);
+        self.tx_ring.write(
+            field(offset_of!(TxDescriptor, command)),
+            TX_END_OF_PACKET | TX_INSERT_CRC | TX_REPORT_STATUS,
+        );
+        self.registers.write(REG_TDT, next as u32);
+        *tail = next;
+        Some(())
+    }
+}
+
+impl Device for E1000 {
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        self.registers.read(REG_STATUS) & STATUS_LINK_UP != 0
+    }
+
+    async fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME {
+            return Err(NetError::FrameTooLarge);
+        }
+
+        poll_fn(|context| {
+            let sent = self.try_transmit(frame).or_else(|| {
+                self.tx_waker.register(context.waker());
+                // The card may have interrupted in between.
+                self.try_transmit(frame)
+            });
+            match sent {
+                Some(()) => Poll::Ready(Ok(())),
+                None => {
+                    if !INTERRUPTS.load(Ordering::Relaxed) {
+                        context.waker().wake_by_ref();
+                    }
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    async fn receive(&self, buffer: &mut [u8]) -> Result<usize, NetError> {
+        poll_fn(|context| {
+            let received = self.try_receive(buffer).or_else(|| {
+                self.rx_waker.register(context.waker());
+                self.try_receive(buffer)
+            });
+            match received {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    if !INTERRUPTS.load(Ordering::Relaxed) {
+                        context.waker().wake_by_ref();
+                    }
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
+fn interrupt_handler() {
+    let Some(device) = DEVICE.get() else {
+        return;
+    };
+    // Reading the cause acknowledges it.
+    let cause = device.registers.read(REG_ICR);
+    if cause & INT_RX != 0 {
+        device.rx_waker.wake();
+    }
+    if cause & INT_TXDW != 0 {
+        device.tx_waker.wake();
+    }
+}
+
+pub static DRIVER: Driver = Driver {
+    name: "e1000",
+    priority: Priority::Normal,
+    bus: Bus::Pci {
+        matches: &[PciMatch::Id {
+            vendor_id: VENDOR_INTEL,
+            device_ids: &DEVICE_IDS,
+        }],
+        probe,
+    },
+};
+
+fn probe(device: &pci::Device, claims: &mut Claims) -> Result<(), ProbeError> {
+    let nic = init(device, claims).map_err(ProbeError::new)?;
+    println!(
+        "e1000: {}, {}, link {}",
+        nic.address(),
+        nic.mac(),
+        if nic.link_up() { "up" } else { "down" }
+    );
+    Ok(())
+}
+
+/// Sets up `device`, the first card only.
+fn init(device: &pci::Device, claims: &mut Claims) -> Result<&'static E1000, E1000Error> {
+    INIT.begin()?;
+
+    let Some(bar @ Bar::Memory { address, .. }) = device.bars[0] else {
+        return Err(E1000Error::NoRegisters);
+    };
+    claims.claim(bar.into())?;
+    let registers = memory::phys_to_virt(PhysAddr::new(address));
+    if !memory::is_mapped(registers) {
+        return Err(E1000Error::NotMapped(PhysAddr::new(address)));
+    }
+    pci::enable(device.address);
+
+    let ring = || DmaBuffer::new(RING_SIZE * DESCRIPTOR_SIZE);
+    let (Ok(rx_ring), Ok(tx_ring), Ok(buffers)) =
+        (ring(), ring(), DmaBuffer::new(2 * RING_SIZE * BUFFER_SIZE))
+    else {
+        return Err(E1000Error::OutOfMemory);
+    };
+
+    // The card only uses the rings once they are set up, but that can't be undone short of a reset, so
+    // they are the card's from now on.
+    let mut nic = E1000 {
+        address: device.address,
+        registers: Registers(registers),
+        rx_ring: rx_ring.give(),
+        tx_ring: tx_ring.give(),
+        buffers: buffers.give(),
+        rx_next: Mutex::new(0),
+        tx_tail: Mutex::new(0),
+        rx_waker: AtomicWaker::new(),
+        tx_waker: AtomicWaker::new(),
+        mac: MacAddress([0; 6]),
+    };
+    nic.reset()?;
+    nic.mac = nic.read_mac();
+    nic.set_up_rings();
+
+    // A line already taken by another device is left alone, and the card is polled instead.
+    let irq = device.interrupt_line;
+    let interrupts =
+        claims.claim_irq(irq) && interrupts::set_irq_handler(irq, interrupt_handler).is_ok();
+    INTERRUPTS.store(interrupts, Ordering::Relaxed);
+    let nic = DEVICE.get_or_init(|| nic);
+    if interrupts {
+        nic.registers.write(REG_IMS, INT_RX | INT_TXDW);
+        interrupts::unmask_irq(irq);
+    }
+
+    Ok(nic)
+}
+
+/// The card `DRIVER` set up.
+pub fn device() -> Option<&'static E1000> {
+    DEVICE.get()
+}
+
+#[test_case]
+fn test_mac_from_words() {
+    assert_eq!(
+        mac_from_words([0x5452, 0x1200, 0x5634]),
+        MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56])
+    );
+}
@@ -0,0 +1,88 @@
+//! The screen shown when the kernel panics.
+//!
+//! It takes over the shown screen with the panic message, the registers at the time of the panic and a
+//! backtrace, on a background no other output uses, and writes the same to the serial port. The panic
+//! may have happened while printing, so the output locks are taken by force: whoever held them never
+//! runs again.
+
+use core::{fmt::Write, panic::PanicInfo};
+use x86_64::registers::{
+    control::{Cr0, Cr2, Cr3, Cr4},
+    rflags,
+};
+
+use crate::{
+    backtrace,
+    framebuffer::{self, Color, CursorStyle, gfx::Rect},
+    serial,
+};
+
+pub const BACKGROUND: Color = Color::rgb(96, 0, 0);
+const HEADING: Color = Color::YELLOW;
+const TEXT: Color = Color::WHITE;
+
+/// Shows `info` and the state of the CPU. Called by the panic handler, with the panicking code's frame
+/// pointer.
+pub fn show(info: &PanicInfo, rbp: u64) {
+    x86_64::instructions::interrupts::disable();
+
+    let mut writer = framebuffer::WRITER.try_lock().unwrap_or_else(|| {
+        unsafe { framebuffer::WRITER.force_unlock() };
+        framebuffer::WRITER.lock()
+    });
+    let mut serial = serial::SERIAL1.try_lock().unwrap_or_else(|| {
+        unsafe { serial::SERIAL1.force_unlock() };
+        serial::SERIAL1.lock()
+    });
+
+    // The writer may have been stopped halfway through anything, so its state is reset first.
+    if let Some(writer) = writer.as_mut() {
+        writer.end_splash();
+        writer.select_screen(writer.shown_screen());
+        writer.set_cursor_style(CursorStyle::Hidden);
+        writer.clear();
+
+        let mut surface = writer.surface();
+        let screen = Rect::new(0, 0, surface.width(), surface.height());
+        surface.fill_rect(screen, BACKGROUND);
+    }
+
+    let mut emit = |color: Color, args: core::fmt::Arguments| {
+        if let Some(writer) = writer.as_mut() {
+            writer.set_color(color, BACKGROUND);
+            let _ = writer.write_fmt(args);
+            writer.flush();
+        }
+        let _ = serial.write_fmt(args);
+    };
+
+    emit(HEADING, format_args!("KERNEL PANIC\n\n"));
+    emit(TEXT, format_args!("{}\n\n", info));
+
+    emit(HEADING, format_args!("registers:\n"));
+    emit(
+        TEXT,
+        format_args!(
+            "  rbp: {:#018x}  rflags: {:#x}\n  cr0: {:#x}  cr2: {:#x}\n  cr3: {:#x}  cr4: {:#x}\n\n",
+            rbp,
+            rflags::read_raw(),
+            Cr0::read_raw(),
+            Cr2::read_raw(),
+            Cr3::read().0.start_address().as_u64(),
+            Cr4::read_raw(),
+        ),
+    );
+
+    emit(HEADING, format_args!("backtrace:\n"));
+    let mut frames = 0;
+    backtrace::walk(rbp, |return_address| {
+        emit(
+            TEXT,
+            format_args!("  #{:<2} {:#018x}\n", frames, return_address),
+        );
+        frames += 1;
+    });
+    if frames == 0 {
+        emit(TEXT, format_args!("  (no frames)\n"));
+    }
+}
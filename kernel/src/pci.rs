@@ -0,0 +1,576 @@
+//! PCI devices, found by scanning configuration space.
+//!
+//! Every PCI function has 256 bytes (4 KiB on PCI Express) of configuration space, with its vendor, device
+//! and class in the first bytes and its BARs (base address registers) after them. It is reached through
+//! the memory mapped ECAM (Enhanced Configuration Access Mechanism) regions the ACPI MCFG table lists,
+//! or otherwise through the legacy I/O ports 0xCF8 and 0xCFC, which only reach segment 0.
+//!
+//! `init` scans every bus once and keeps what it finds, which `devices` returns and `dump` lists like
+//! `lspci`. Drivers find their device there and read the rest of its configuration space with
+//! `read_config`.
+
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr, instructions::port::Port};
+
+use crate::{
+    acpi,
+    init_state::{AlreadyInitialized, InitState},
+    memory,
+};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+const VENDOR_ID: u16 = 0x00;
+const COMMAND: u16 = 0x04;
+const CLASS: u16 = 0x08;
+const HEADER_TYPE: u16 = 0x0e;
+const BAR0: u16 = 0x10;
+const CAPABILITIES: u16 = 0x34;
+const INTERRUPT_LINE: u16 = 0x3c;
+
+/// Command register bits that make the function respond to I/O and memory accesses.
+const COMMAND_DECODE: u32 = 0b11;
+/// Command register bit that lets the function access memory itself, for DMA.
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+/// Status register bit set when the function has a capability list.
+const STATUS_CAPABILITIES: u32 = 1 << 4;
+/// Most capabilities followed, in case a broken list loops.
+const MAX_CAPABILITIES: usize = 48;
+/// Read from the vendor ID of a function that doesn't exist.
+const NO_VENDOR: u16 = 0xffff;
+/// Offset of the first allocation in the MCFG table, after its header and 8 reserved bytes.
+const MCFG_ENTRIES: usize = 44;
+const MCFG_ENTRY_LEN: usize = 16;
+
+static INIT: InitState = InitState::new("pci");
+static DEVICES: OnceCell<Vec<Device>> = OnceCell::uninit();
+static ECAM: OnceCell<Vec<EcamRegion>> = OnceCell::uninit();
+/// Serializes the two port accesses of a legacy configuration access.
+static PORTS: Mutex<()> = Mutex::new(());
+
+/// Where a function is: its segment, bus, device and function number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// A region of memory mapped configuration space, for the buses `start_bus..=end_bus` of a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct EcamRegion {
+    base: PhysAddr,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+impl EcamRegion {
+    /// The physical address of `offset` in the configuration space of `address`, if this region has it.
+    fn config_address(&self, address: Address, offset: u16) -> Option<PhysAddr> {
+        let in_region = address.segment == self.segment
+            && (self.start_bus..=self.end_bus).contains(&address.bus);
+
+        in_region.then(|| {
+            let bus = (address.bus - self.start_bus) as u64;
+            self.base
+                + (bus << 20
+                    | (address.device as u64) << 15
+                    | (address.function as u64) << 12
+                    | offset as u64)
+        })
+    }
+}
+
+/// A base address register: where the function decodes memory or I/O accesses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        /// Whether the BAR takes up two registers, the second one holding the upper half of `address`.
+        wide: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    /// The layout of the rest of the configuration space, without the multi-function bit: 0 for devices,
+    /// 1 for PCI-to-PCI bridges.
+    pub header_type: u8,
+    /// The BARs, by register. The register after a 64-bit BAR is `None`.
+    pub bars: [Option<Bar>; 6],
+    pub interrupt_line: u8,
+    /// 1 to 4 for INTA# to INTD#, 0 for none.
+    pub interrupt_pin: u8,
+}
+
+impl Device {
+    /// A description of the class, e.g. "Ethernet controller".
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciError {
+    /// The MCFG table lists an ECAM region outside the physical memory mapping.
+    EcamNotMapped(PhysAddr),
+    AlreadyInitialized(AlreadyInitialized),
+}
+
+impl From<AlreadyInitialized> for PciError {
+    fn from(error: AlreadyInitialized) -> Self {
+        PciError::AlreadyInitialized(error)
+    }
+}
+
+/// Finds the ECAM regions and scans every bus for functions. Returns the number of functions found.
+/// Requires the heap and, for ECAM, `acpi::init`.
+pub fn init() -> Result<usize, PciError> {
+    INIT.begin()?;
+
+    let regions = ecam_regions();
+    if let Some(region) = regions
+        .iter()
+        .find(|region| !memory::is_mapped(memory::phys_to_virt(region.base)))
+    {
+        ECAM.init_once(Vec::new);
+        DEVICES.init_once(|| scan(&[0]));
+        return Err(PciError::EcamNotMapped(region.base));
+    }
+
+    let mut segments: Vec<u16> = regions.iter().map(|region| region.segment).collect();
+    segments.sort_unstable();
+    segments.dedup();
+    if segments.is_empty() {
+        segments.push(0);
+    }
+    ECAM.init_once(|| regions);
+
+    let devices = DEVICES.get_or_init(|| scan(&segments));
+    Ok(devices.len())
+}
+
+/// The regions listed in the MCFG table, if there is one.
+fn ecam_regions() -> Vec<EcamRegion> {
+    let Some(mcfg) = acpi::find_table(b"MCFG") else {
+        return Vec::new();
+    };
+
+    acpi::table_bytes(mcfg)[MCFG_ENTRIES..]
+        .chunks_exact(MCFG_ENTRY_LEN)
+        .map(|entry| EcamRegion {
+            base: PhysAddr::new(u64::from_le_bytes(entry[..8].try_into().unwrap())),
+            segment: u16::from_le_bytes([entry[8], entry[9]]),
+            start_bus: entry[10],
+            end_bus: entry[11],
+        })
+        .collect()
+}
+
+fn scan(segments: &[u16]) -> Vec<Device> {
+    let mut devices = Vec::new();
+
+    for &segment in segments {
+        for bus in 0..=255 {
+            for device in 0..32 {
+                let address = Address {
+                    segment,
+                    bus,
+                    device,
+                    function: 0,
+                };
+                let Some(first) = probe(address) else {
+                    continue;
+                };
+                let multi_function = read_config(address, HEADER_TYPE) >> 16 & 0x80 != 0;
+                devices.push(first);
+
+                if multi_function {
+                    devices.extend((1..8).filter_map(|function| {
+                        probe(Address {
+                            function,
+                            ..address
+                        })
+                    }));
+                }
+            }
+        }
+    }
+
+    devices
+}
+
+/// Reads the function at `address`, if there is one.
+fn probe(address: Address) -> Option<Device> {
+    let ids = read_config(address, VENDOR_ID);
+    let vendor_id = ids as u16;
+    if vendor_id == NO_VENDOR {
+        return None;
+    }
+
+    let class = read_config(address, CLASS);
+    let header_type = (read_config(address, HEADER_TYPE) >> 16) as u8 & 0x7f;
+    let interrupt = read_config(address, INTERRUPT_LINE);
+
+    // Bridges only have two BARs, and other header types none.
+    let bar_count = match header_type {
+        0 => 6,
+        1 => 2,
+        _ => 0,
+    };
+
+    Some(Device {
+        address,
+        vendor_id,
+        device_id: (ids >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        revision: class as u8,
+        header_type,
+        bars: read_bars(address, bar_count),
+        interrupt_line: interrupt as u8,
+        interrupt_pin: (interrupt >> 8) as u8,
+    })
+}
+
+/// Reads the first `count` BARs of `address`, sizing each by writing all ones to it and reading back which
+/// bits stuck. Decoding is turned off meanwhile, so the function doesn't respond at the addresses written.
+fn read_bars(address: Address, count: usize) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let command = read_config(address, COMMAND);
+    write_config(address, COMMAND, command & !COMMAND_DECODE);
+
+    let mut index = 0;
+    while index < count {
+        let offset = BAR0 + 4 * index as u16;
+        let low = read_config(address, offset);
+        let low_mask = size_mask(address, offset, low);
+
+        let wide = low & 0b111 == 0b100 && index + 1 < count;
+        let (high, high_mask) = if wide {
+            let high = read_config(address, offset + 4);
+            (high, size_mask(address, offset + 4, high))
+        } else {
+            (0, u32::MAX)
+        };
+
+        bars[index] = decode_bar(low, low_mask, high, high_mask);
+        index += if wide { 2 } else { 1 };
+    }
+
+    write_config(address, COMMAND, command);
+    bars
+}
+
+/// Writes all ones to the register at `offset`, holding `value`, and reads back which bits stuck.
+fn size_mask(address: Address, offset: u16, value: u32) -> u32 {
+    write_config(address, offset, u32::MAX);
+    let mask = read_config(address, offset);
+    write_config(address, offset, value);
+    mask
+}
+
+/// Decodes a BAR from its registers and what they read after writing all ones. For a 32-bit BAR, `high`
+/// is 0 and `high_mask` all ones.
+fn decode_bar(low: u32, low_mask: u32, high: u32, high_mask: u32) -> Option<Bar> {
+    if low & 1 == 1 {
+        let mask = low_mask & !0b11 | 0xffff_0000;
+        if mask == 0xffff_0000 {
+            return None;
+        }
+        return Some(Bar::Io {
+            port: (low & !0b11) as u16,
+            size: (!mask).wrapping_add(1),
+        });
+    }
+
+    let mask = (high_mask as u64) << 32 | (low_mask & !0xf) as u64;
+    if mask == 0 || mask == 0xffff_ffff_0000_0000 {
+        return None;
+    }
+    Some(Bar::Memory {
+        address: (high as u64) << 32 | (low & !0xf) as u64,
+        size: (!mask).wrapping_add(1),
+        prefetchable: low & 0b1000 != 0,
+        wide: low & 0b110 == 0b100,
+    })
+}
+
+/// Reads the 32-bit register at `offset`, rounded down to a multiple of 4, of the configuration space of
+/// `address`. Reads all ones if there is no such function.
+pub fn read_config(address: Address, offset: u16) -> u32 {
+    let offset = offset & !0b11;
+
+    if let Some(config) = ecam_address(address, offset) {
+        return unsafe { config.as_ptr::<u32>().read_volatile() };
+    }
+    if address.segment != 0 || offset >= 0x100 {
+        return u32::MAX;
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _ports = PORTS.lock();
+        unsafe {
+            Port::new(CONFIG_ADDRESS).write(legacy_address(address, offset));
+            Port::new(CONFIG_DATA).read()
+        }
+    })
+}
+
+/// Writes the 32-bit register at `offset`, rounded down to a multiple of 4, of the configuration space of
+/// `address`.
+pub fn write_config(address: Address, offset: u16, value: u32) {
+    let offset = offset & !0b11;
+
+    if let Some(config) = ecam_address(address, offset) {
+        unsafe { config.as_mut_ptr::<u32>().write_volatile(value) };
+        return;
+    }
+    if address.segment != 0 || offset >= 0x100 {
+        return;
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _ports = PORTS.lock();
+        unsafe {
+            Port::new(CONFIG_ADDRESS).write(legacy_address(address, offset));
+            Port::new(CONFIG_DATA).write(value);
+        }
+    });
+}
+
+fn ecam_address(address: Address, offset: u16) -> Option<VirtAddr> {
+    ECAM.get()?
+        .iter()
+        .find_map(|region| region.config_address(address, offset))
+        .map(memory::phys_to_virt)
+}
+
+/// Lets the function at `address` respond to I/O and memory accesses, and access memory itself.
+pub fn enable(address: Address) {
+    let command = read_config(address, COMMAND);
+    write_config(
+        address,
+        COMMAND,
+        command | COMMAND_DECODE | COMMAND_BUS_MASTER,
+    );
+}
+
+/// The capabilities of the function at `address`, as their ID and offset in its configuration space.
+pub fn capabilities(address: Address) -> impl Iterator<Item = (u8, u16)> {
+    // The status register is the upper half of the command register's dword.
+    let status = read_config(address, COMMAND) >> 16;
+    let mut next = if status & STATUS_CAPABILITIES != 0 {
+        (read_config(address, CAPABILITIES) & 0xfc) as u16
+    } else {
+        0
+    };
+
+    (0..MAX_CAPABILITIES).map_while(move |_| {
+        let offset = next;
+        if offset == 0 {
+            return None;
+        }
+        let header = read_config(address, offset);
+        next = (header >> 8) as u16 & 0xfc;
+        Some((header as u8, offset))
+    })
+}
+
+/// The value for `CONFIG_ADDRESS` that selects `offset` of `address`.
+fn legacy_address(address: Address, offset: u16) -> u32 {
+    1 << 31
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | offset as u32
+}
+
+/// The functions found by `init`, by address. Empty before it.
+pub fn devices() -> &'static [Device] {
+    DEVICES.get().map_or(&[], Vec::as_slice)
+}
+
+/// The first function with this vendor and device ID.
+pub fn find(vendor_id: u16, device_id: u16) -> Option<&'static Device> {
+    devices()
+        .iter()
+        .find(|device| device.vendor_id == vendor_id && device.device_id == device_id)
+}
+
+/// Lists the functions found by `init` like `lspci`, one line each followed by their BARs.
+pub fn dump(output: &mut impl Write) -> fmt::Result {
+    for device in devices() {
+        writeln!(
+            output,
+            "{} {} [{:02x}{:02x}]: {:04x}:{:04x} (rev {:02x})",
+            device.address,
+            device.class_name(),
+            device.class,
+            device.subclass,
+            device.vendor_id,
+            device.device_id,
+            device.revision
+        )?;
+
+        for (index, bar) in device.bars.iter().enumerate() {
+            match bar {
+                Some(Bar::Memory {
+                    address,
+                    size,
+                    prefetchable,
+                    wide,
+                }) => writeln!(
+                    output,
+                    "    BAR{}: memory at {:#x} ({}-bit, {}prefetchable) [size={:#x}]",
+                    index,
+                    address,
+                    if *wide { 64 } else { 32 },
+                    if *prefetchable { "" } else { "non-" },
+                    size
+                )?,
+                Some(Bar::Io { port, size }) => writeln!(
+                    output,
+                    "    BAR{}: I/O ports at {:#x} [size={:#x}]",
+                    index, port, size
+                )?,
+                None => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A description of a class code, as far as the common ones go.
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x00, _) => "Unclassified device",
+        (0x01, 0x00) => "SCSI storage controller",
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, 0x03) => "Audio device",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x09, _) => "Input device controller",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus",
+        (0x0c, _) => "Serial bus controller",
+        _ => "Unknown device",
+    }
+}
+
+#[test_case]
+fn test_ecam_address_of_a_function() {
+    let region = EcamRegion {
+        base: PhysAddr::new(0xb000_0000),
+        segment: 0,
+        start_bus: 0,
+        end_bus: 0xff,
+    };
+    let address = Address {
+        segment: 0,
+        bus: 1,
+        device: 2,
+        function: 3,
+    };
+
+    assert_eq!(
+        region.config_address(address, 0x10),
+        Some(PhysAddr::new(0xb011_3010))
+    );
+    assert_eq!(
+        region.config_address(
+            Address {
+                segment: 1,
+                ..address
+            },
+            0
+        ),
+        None
+    );
+}
+
+#[test_case]
+fn test_legacy_address_of_a_function() {
+    let address = Address {
+        segment: 0,
+        bus: 0,
+        device: 0x1f,
+        function: 2,
+    };
+
+    assert_eq!(legacy_address(address, 0x3c), 0x8000_fa3c);
+}
+
+#[test_case]
+fn test_bars_are_decoded_with_their_size() {
+    assert_eq!(
+        decode_bar(0xfebf_0000, 0xffff_f000, 0, u32::MAX),
+        Some(Bar::Memory {
+            address: 0xfebf_0000,
+            size: 0x1000,
+            prefetchable: false,
+            wide: false,
+        })
+    );
+    assert_eq!(
+        decode_bar(0xc000_000c, 0xffe0_000c, 0x1, 0xffff_ffff),
+        Some(Bar::Memory {
+            address: 0x1_c000_0000,
+            size: 0x20_0000,
+            prefetchable: true,
+            wide: true,
+        })
+    );
+    assert_eq!(
+        decode_bar(0xc041, 0xffff_ffe1, 0, u32::MAX),
+        Some(Bar::Io {
+            port: 0xc040,
+            size: 0x20,
+        })
+    );
+    assert_eq!(decode_bar(0, 0, 0, u32::MAX), None);
+}
@@ -0,0 +1,952 @@
+//! Processes and the process table.
+//!
+//! A process is a user program together with everything the kernel keeps for it: its address space, the
+//! kernel stack its syscalls run on, the saved user registers and its scheduling state. Every process
+//! lives in a global table keyed by its PID until its parent reaps it.
+//!
+//! A process keeps the CPU until it blocks or exits inside a syscall, or until the timer preempts it, and
+//! then `schedule` enters the next ready process as chosen by `sched`. A blocked process is resumed by
+//! restarting the syscall it blocked in, which checks again whether it can complete.
+
+use alloc::{boxed::Box, collections::BTreeMap, vec, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::Mutex;
+use x86_64::{
+    VirtAddr,
+    registers::rflags::RFlags,
+    structures::{
+        idt::InterruptStackFrame,
+        paging::{FrameDeallocator, Page, PageTableFlags, PhysFrame, page::PageRange},
+    },
+};
+
+use crate::{
+    fpu::{self, FpuState},
+    framebuffer::compositor::Window,
+    gdt, input,
+    interrupts::debug::GeneralRegisters,
+    kmsg::RateLimiter,
+    lockup,
+    memory::{
+        self, AddressSpace, GlobalFrameAllocator,
+        address_space::{USER_END, USER_START},
+        phys_to_virt,
+    },
+    mitigations, syscall, userspace,
+};
+
+pub mod elf;
+pub mod fd;
+pub mod futex;
+pub mod pipe;
+pub mod rlimit;
+pub mod sched;
+pub mod signal;
+pub mod sleep;
+pub mod tls;
+pub mod trace;
+
+use elf::ElfError;
+pub use fd::{FdTable, File};
+use rlimit::{LimitExceeded, Limits, Resource};
+use sched::Priority;
+use signal::Signals;
+use tls::SegmentBases;
+
+const KERNEL_STACK_SIZE: usize = 4096 * 4;
+/// Processes that can exist at the same time, zombies included. Each one takes a little of the kernel
+/// heap, so a fork bomb runs into this instead of exhausting it.
+pub const MAX_PROCESSES: usize = 16;
+const USER_STACK_SIZE: u64 = 4096 * 4;
+/// User stacks end at the top of the user part of the address space.
+pub const USER_STACK_TOP: VirtAddr = USER_END;
+/// Where the heap starts for processes without a program image, see `spawn_user`.
+const USER_HEAP_START: VirtAddr = USER_START;
+/// Anonymous mappings go top-down below this, and the heap can't grow past it. The gap above keeps
+/// them away from the stack.
+pub const MMAP_TOP: VirtAddr = VirtAddr::new_truncate(USER_END.as_u64() - 0x4000_0000);
+/// Where the window shared with a process is mapped, in the gap above anonymous mappings, see
+/// `Process::attach_surface`.
+pub const SURFACE_ADDRESS: VirtAddr = VirtAddr::new_truncate(MMAP_TOP.as_u64() + 0x1000_0000);
+const HEAP_FLAGS: PageTableFlags = PageTableFlags::WRITABLE.union(PageTableFlags::NO_EXECUTE);
+
+static PROCESSES: Mutex<BTreeMap<Pid, Process>> = Mutex::new(BTreeMap::new());
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+/// PID of the process on the CPU, 0 while the kernel runs on its own.
+static CURRENT: AtomicU64 = AtomicU64::new(0);
+/// PID of the process that ran last, to tell when the CPU switches to a different one.
+static LAST_RUN: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The process with the given number, if it is a valid PID.
+    pub fn from_u64(pid: u64) -> Option<Self> {
+        (pid != 0).then_some(Pid(pid))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Can run, waiting for the CPU.
+    Ready,
+    Running,
+    /// Waiting for an event, such as a child exiting.
+    Blocked,
+    /// Exited, kept until its parent collects the exit code.
+    Zombie {
+        exit_code: i32,
+    },
+}
+
+/// User registers of a process that isn't running.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Context {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+}
+
+impl Context {
+    /// Registers for starting a program at `entry` with the stack at `stack`.
+    pub fn start(entry: VirtAddr, stack: VirtAddr) -> Self {
+        Context {
+            rip: entry.as_u64(),
+            rsp: stack.as_u64(),
+            // Bit 1 is reserved and always set.
+            rflags: RFlags::INTERRUPT_FLAG.bits() | 0x2,
+            ..Context::default()
+        }
+    }
+
+    /// The registers of user code interrupted with `stack_frame`, given the general purpose registers
+    /// saved by the interrupt's entry.
+    pub(crate) fn interrupted(
+        registers: &GeneralRegisters,
+        stack_frame: &InterruptStackFrame,
+    ) -> Self {
+        Context {
+            rax: registers.rax,
+            rbx: registers.rbx,
+            rcx: registers.rcx,
+            rdx: registers.rdx,
+            rsi: registers.rsi,
+            rdi: registers.rdi,
+            rbp: registers.rbp,
+            r8: registers.r8,
+            r9: registers.r9,
+            r10: registers.r10,
+            r11: registers.r11,
+            r12: registers.r12,
+            r13: registers.r13,
+            r14: registers.r14,
+            r15: registers.r15,
+            rip: stack_frame.instruction_pointer.as_u64(),
+            rsp: stack_frame.stack_pointer.as_u64(),
+            rflags: stack_frame.cpu_flags,
+        }
+    }
+}
+
+/// A stack on the kernel heap.
+pub struct Stack {
+    memory: Box<[u8]>,
+}
+
+impl Stack {
+    pub(crate) fn new(size: usize) -> Self {
+        Stack {
+            memory: vec![0; size].into_boxed_slice(),
+        }
+    }
+
+    /// The initial stack pointer, 16-byte aligned as the ABI expects.
+    pub fn top(&self) -> VirtAddr {
+        (VirtAddr::from_ptr(self.memory.as_ptr()) + self.memory.len() as u64).align_down(16u64)
+    }
+}
+
+/// The stack a process's syscalls and interrupts run on, in frames of its own rather than on the kernel
+/// heap, which is too small to hold one per process.
+pub struct KernelStack {
+    start: PhysFrame,
+}
+
+impl KernelStack {
+    const FRAMES: u64 = (KERNEL_STACK_SIZE / 4096) as u64;
+
+    /// `None` if there aren't enough adjacent free frames.
+    fn new() -> Option<Self> {
+        memory::allocate_contiguous_frames(Self::FRAMES).map(|start| KernelStack { start })
+    }
+
+    /// The initial stack pointer, 16-byte aligned as the ABI expects.
+    pub fn top(&self) -> VirtAddr {
+        phys_to_virt(self.start.start_address()) + KERNEL_STACK_SIZE as u64
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        for frame in PhysFrame::range(self.start, self.start + Self::FRAMES) {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    LimitExceeded(Resource),
+    /// There are already `MAX_PROCESSES` processes.
+    TooManyProcesses,
+    OutOfMemory,
+    InvalidImage(ElfError),
+}
+
+impl From<LimitExceeded> for SpawnError {
+    fn from(error: LimitExceeded) -> Self {
+        SpawnError::LimitExceeded(error.resource)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SurfaceError {
+    /// The process already has a window.
+    Busy,
+    OutOfMemory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapError {
+    /// The process would go over its `Heap` limit.
+    LimitExceeded,
+    /// The range runs into another mapping or out of the space left for the heap.
+    NoRoom,
+}
+
+impl From<ElfError> for SpawnError {
+    fn from(error: ElfError) -> Self {
+        match error {
+            ElfError::OutOfMemory => SpawnError::OutOfMemory,
+            error => SpawnError::InvalidImage(error),
+        }
+    }
+}
+
+pub struct Process {
+    pid: Pid,
+    /// `None` for processes started by the kernel itself, or whose parent exited.
+    parent: Option<Pid>,
+    state: State,
+    /// Freed as soon as the process exits.
+    address_space: Option<AddressSpace>,
+    /// Kept until the process is reaped, since it exits while running on it.
+    kernel_stack: KernelStack,
+    context: Context,
+    limits: Limits,
+    log_limiter: RateLimiter,
+    /// Start of the heap, where the break can't go below.
+    heap_start: VirtAddr,
+    /// End of the heap, moved with `brk`. Pages up to it are reserved as an anonymous region.
+    brk: VirtAddr,
+    /// Set while the process is single-stepped, see `trace`.
+    trace: Option<trace::Trace>,
+    signals: Signals,
+    /// Closed when the process exits.
+    files: FdTable,
+    /// Only up to date while the process is off the CPU, see `tls`.
+    segment_bases: SegmentBases,
+    /// Boxed so that its address stays valid for `fpu` while the table changes.
+    fpu: Box<FpuState>,
+    priority: Priority,
+    /// Time spent in ring 3, charged a tick at a time by `sched::on_tick`.
+    cpu_nanos: u64,
+    /// Tick a sleeping process wakes up at, see `sleep`.
+    sleeping_until: Option<u64>,
+    /// Closed once the address space it is mapped in is gone.
+    surface: Option<Window>,
+    /// Set for processes the kernel started itself rather than forked, which may take devices such as
+    /// the input ring. Not inherited.
+    privileged: bool,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn parent(&self) -> Option<Pid> {
+        self.parent
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// `None` once the process has exited.
+    pub fn address_space(&self) -> Option<&AddressSpace> {
+        self.address_space.as_ref()
+    }
+
+    pub fn kernel_stack(&self) -> &KernelStack {
+        &self.kernel_stack
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn limits_mut(&mut self) -> &mut Limits {
+        &mut self.limits
+    }
+
+    /// Tracks the records logged against `Resource::LogRate`.
+    pub fn log_limiter_mut(&mut self) -> &mut RateLimiter {
+        &mut self.log_limiter
+    }
+
+    pub fn signals(&self) -> &Signals {
+        &self.signals
+    }
+
+    /// The FS and GS bases as of the last time the process left the CPU.
+    pub fn segment_bases(&self) -> SegmentBases {
+        self.segment_bases
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub fn is_privileged(&self) -> bool {
+        self.privileged
+    }
+
+    pub fn files(&self) -> &FdTable {
+        &self.files
+    }
+
+    /// Opens `file` at the lowest free descriptor, within the `OpenFiles` limit.
+    pub fn open_file(&mut self, file: File) -> Result<u64, fd::FdError> {
+        let limit = self.limits.get(Resource::OpenFiles).soft;
+        self.files.insert(file, limit)
+    }
+
+    pub fn close_file(&mut self, fd: u64) -> Result<File, fd::FdError> {
+        self.files.close(fd)
+    }
+
+    pub fn is_traced(&self) -> bool {
+        self.trace.is_some()
+    }
+
+    pub fn heap_start(&self) -> VirtAddr {
+        self.heap_start
+    }
+
+    /// The current end of the heap.
+    pub fn brk(&self) -> VirtAddr {
+        self.brk
+    }
+
+    fn user_address_space(&mut self) -> &mut AddressSpace {
+        self.address_space.as_mut().expect("process has exited")
+    }
+
+    /// Checks that `pages` more anonymous pages are within the `Heap` limit.
+    fn check_heap(&mut self, pages: u64) -> Result<(), HeapError> {
+        // More bytes than a u64 holds wouldn't fit in the address space either.
+        let total = self
+            .user_address_space()
+            .anonymous_pages()
+            .checked_add(pages)
+            .and_then(|total| total.checked_mul(4096))
+            .ok_or(HeapError::NoRoom)?;
+        self.limits
+            .check(Resource::Heap, total)
+            .map_err(|_| HeapError::LimitExceeded)
+    }
+
+    /// Moves the end of the heap to `end`, reserving or freeing the pages in between. Pages are only
+    /// backed by memory once they are touched.
+    pub fn set_brk(&mut self, end: VirtAddr) -> Result<(), HeapError> {
+        if end < self.heap_start || end > MMAP_TOP {
+            return Err(HeapError::NoRoom);
+        }
+
+        let old = Page::containing_address(self.brk.align_up(4096u64));
+        let new = Page::containing_address(end.align_up(4096u64));
+
+        if new > old {
+            self.check_heap(new - old)?;
+            self.user_address_space()
+                .add_anonymous(Page::range(old, new), HEAP_FLAGS)
+                .map_err(|_| HeapError::NoRoom)?;
+        } else {
+            self.user_address_space()
+                .remove_anonymous(Page::range(new, old));
+        }
+
+        self.brk = end;
+        Ok(())
+    }
+
+    /// Reserves `count` pages of anonymous memory above the heap, mapped with `flags` once touched, and
+    /// returns where they start.
+    pub fn map_anonymous(
+        &mut self,
+        count: u64,
+        flags: PageTableFlags,
+    ) -> Result<VirtAddr, HeapError> {
+        self.check_heap(count)?;
+
+        let within = Page::range(
+            Page::containing_address(self.brk.align_up(4096u64)),
+            Page::containing_address(MMAP_TOP),
+        );
+        let space = self.user_address_space();
+        let pages = space.find_free(count, within).ok_or(HeapError::NoRoom)?;
+
+        space
+            .add_anonymous(pages, flags)
+            .map_err(|_| HeapError::NoRoom)?;
+        Ok(pages.start.start_address())
+    }
+
+    /// Maps `page` to a zeroed frame for memory the kernel shares with the process, such as the input
+    /// ring, and returns the frame. A page that is already mapped, e.g. because it was inherited from a
+    /// parent that shared it, keeps its frame. The page must be outside the heap and anonymous mappings.
+    pub fn map_shared(&mut self, page: Page) -> Result<PhysFrame, HeapError> {
+        let space = self.user_address_space();
+        if let Some((frame, _)) = space.translate(page) {
+            return Ok(frame);
+        }
+
+        space
+            .map_user(page, HEAP_FLAGS)
+            .map_err(|_| HeapError::NoRoom)
+    }
+
+    /// The window shared with the process, see `attach_surface`.
+    pub fn surface(&self) -> Option<&Window> {
+        self.surface.as_ref()
+    }
+
+    /// Maps the pixels of `window` at `SURFACE_ADDRESS` for the process to draw into, and keeps the
+    /// window open until the process exits or execs. A process has one window at most.
+    pub fn attach_surface(&mut self, window: Window) -> Result<(), SurfaceError> {
+        if self.surface.is_some() {
+            return Err(SurfaceError::Busy);
+        }
+
+        let start = Page::containing_address(SURFACE_ADDRESS);
+        let space = self.user_address_space();
+        for (index, frame) in window.frames().enumerate() {
+            if space
+                .map_borrowed(start + index as u64, frame, HEAP_FLAGS)
+                .is_err()
+            {
+                for page in Page::range(start, start + index as u64) {
+                    space.unmap_borrowed(page);
+                }
+                return Err(SurfaceError::OutOfMemory);
+            }
+        }
+
+        self.surface = Some(window);
+        Ok(())
+    }
+
+    /// Drops anonymous memory, freeing what was touched. Pages that aren't anonymous are left alone.
+    pub fn unmap_anonymous(&mut self, pages: PageRange) {
+        self.user_address_space().remove_anonymous(pages);
+    }
+}
+
+/// A user address space with a stack mapped below `USER_STACK_TOP`.
+fn new_address_space() -> Result<AddressSpace, SpawnError> {
+    let mut space = AddressSpace::new_user().ok_or(SpawnError::OutOfMemory)?;
+
+    let pages = Page::range(
+        Page::containing_address(USER_STACK_TOP - USER_STACK_SIZE),
+        Page::containing_address(USER_STACK_TOP),
+    );
+    for page in pages {
+        space
+            .map_user(page, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+            .map_err(|_| SpawnError::OutOfMemory)?;
+    }
+
+    Ok(space)
+}
+
+/// Adds a ready process to the table, whose heap starts and currently ends at the addresses in `heap`.
+/// When called on behalf of a process, the new process becomes its child and inherits its limits, signal
+/// actions, open files and nice value, and creating it must not exceed the parent's `Children` limit.
+/// There can't be more than `MAX_PROCESSES` processes in all.
+fn add_process(
+    address_space: AddressSpace,
+    context: Context,
+    (heap_start, brk): (VirtAddr, VirtAddr),
+) -> Result<Pid, SpawnError> {
+    let parent = current();
+    let mut processes = PROCESSES.lock();
+    if processes.len() >= MAX_PROCESSES {
+        return Err(SpawnError::TooManyProcesses);
+    }
+
+    let (limits, signals, files, nice) = match parent.and_then(|parent| processes.get(&parent)) {
+        Some(parent) => {
+            let children = processes
+                .values()
+                .filter(|process| process.parent == Some(parent.pid))
+                .count();
+            parent
+                .limits
+                .check(Resource::Children, children as u64 + 1)?;
+
+            (
+                parent.limits,
+                parent.signals.fork(),
+                parent.files.clone(),
+                parent.priority.nice(),
+            )
+        }
+        None => (
+            Limits::default(),
+            Signals::default(),
+            FdTable::standard(),
+            0,
+        ),
+    };
+
+    let kernel_stack = KernelStack::new().ok_or(SpawnError::OutOfMemory)?;
+    let process = Process {
+        pid: Pid::new(),
+        parent,
+        state: State::Ready,
+        address_space: Some(address_space),
+        kernel_stack,
+        context,
+        limits,
+        log_limiter: RateLimiter::default(),
+        heap_start,
+        brk,
+        trace: None,
+        signals,
+        files,
+        segment_bases: SegmentBases::default(),
+        fpu: Box::new(FpuState::new()),
+        priority: Priority::new(nice),
+        cpu_nanos: 0,
+        sleeping_until: None,
+        surface: None,
+        privileged: parent.is_none(),
+    };
+
+    let (pid, level) = (process.pid, process.priority.level());
+    processes.insert(pid, process);
+    sched::push(pid, level);
+    Ok(pid)
+}
+
+/// Creates a ready process that runs `entry`, a function of the kernel, in ring 3. Its address space
+/// shares the kernel's code and read-only data with it, see `AddressSpace::share_kernel_code`; only the
+/// stack is private to the process.
+pub fn spawn_user(entry: fn()) -> Result<Pid, SpawnError> {
+    let mut address_space = new_address_space()?;
+    address_space
+        .share_kernel_code()
+        .map_err(|_| SpawnError::OutOfMemory)?;
+    // Functions expect the stack to be misaligned by the return address a call would have pushed.
+    let context = Context::start(
+        VirtAddr::from_ptr(entry as *const ()),
+        USER_STACK_TOP - 8u64,
+    );
+
+    add_process(address_space, context, (USER_HEAP_START, USER_HEAP_START))
+}
+
+/// Loads an ELF executable into a new address space. Also returns where its heap starts, right above the
+/// program.
+fn load_image(image: &[u8]) -> Result<(AddressSpace, Context, VirtAddr), SpawnError> {
+    let mut address_space = new_address_space()?;
+    let loaded = elf::load(image, &mut address_space)?;
+
+    Ok((
+        address_space,
+        Context::start(loaded.entry, USER_STACK_TOP),
+        loaded.end.align_up(4096u64),
+    ))
+}
+
+/// Creates a ready process running the ELF executable `image`.
+pub fn spawn_elf(image: &[u8]) -> Result<Pid, SpawnError> {
+    let (address_space, context, heap_start) = load_image(image)?;
+    add_process(address_space, context, (heap_start, heap_start))
+}
+
+/// Creates a child of the current process with a copy of its address space, which starts with the
+/// registers in `context`.
+pub fn fork(context: Context) -> Result<Pid, SpawnError> {
+    let pid = current().expect("fork outside of a process");
+
+    let (address_space, heap, fpu) = with_process(pid, |process| {
+        let space = process
+            .address_space
+            .as_ref()
+            .expect("running process has exited");
+        let heap = (process.heap_start, process.brk);
+        let space = space.fork().map_err(|_| SpawnError::OutOfMemory)?;
+        Ok::<_, SpawnError>((space, heap, Box::new(process.fpu.fork())))
+    })
+    .expect("current process missing from the table")?;
+
+    let child = add_process(address_space, context, heap)?;
+    // The parent is on the CPU, so its bases are the ones there.
+    with_process(child, |child| {
+        child.segment_bases = SegmentBases::read();
+        child.fpu = fpu;
+    });
+    Ok(child)
+}
+
+/// Replaces the program of the current process with the ELF executable `image`, returning the registers
+/// it starts with. The old address space is gone when this succeeds.
+pub fn exec(image: &[u8]) -> Result<Context, SpawnError> {
+    let pid = current().expect("exec outside of a process");
+    let (address_space, context, heap_start) = load_image(image)?;
+
+    // The ring lives in the address space that is about to go away.
+    input::ring::release(pid);
+
+    let old = with_process(pid, |process| {
+        unsafe { address_space.activate() };
+        process.heap_start = heap_start;
+        process.brk = heap_start;
+        process.signals.exec();
+        process.segment_bases = SegmentBases::default();
+        process.segment_bases.load();
+        (
+            process.address_space.replace(address_space),
+            process.surface.take(),
+        )
+    })
+    .expect("current process missing from the table");
+
+    // The window's pixels stay mapped until the old address space is gone.
+    drop(old);
+    Ok(context)
+}
+
+/// Runs `f` on the process with the given PID, if it exists.
+pub fn with_process<R>(pid: Pid, f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    PROCESSES.lock().get_mut(&pid).map(f)
+}
+
+/// PIDs and states of every process in the table.
+pub fn list() -> Vec<(Pid, State)> {
+    PROCESSES
+        .lock()
+        .values()
+        .map(|process| (process.pid, process.state))
+        .collect()
+}
+
+/// The process on the CPU, `None` while the kernel runs on its own.
+pub fn current() -> Option<Pid> {
+    Pid::from_u64(CURRENT.load(Ordering::Relaxed))
+}
+
+/// Resolves a page fault of the current process at `address`, see `AddressSpace::handle_fault`. Returns
+/// whether the faulting access can be retried.
+pub fn handle_page_fault(address: VirtAddr) -> bool {
+    let Some(pid) = current() else {
+        return false;
+    };
+
+    with_process(pid, |process| {
+        process
+            .address_space
+            .as_mut()
+            .is_some_and(|space| space.handle_fault(address))
+    })
+    .unwrap_or(false)
+}
+
+/// Turns the current process into a zombie holding `exit_code`, frees its address space and closes its
+/// files. Its children are orphaned, and its parent is woken up in case it waits for it. The caller must
+/// `schedule` next.
+pub fn exit_current(exit_code: i32) {
+    let Some(pid) = current() else {
+        return;
+    };
+
+    // The address space can't be freed while it is active.
+    unsafe { AddressSpace::kernel().activate() };
+
+    let (address_space, files, surface, parent) = {
+        let mut processes = PROCESSES.lock();
+
+        for child in processes.values_mut() {
+            if child.parent == Some(pid) {
+                child.parent = None;
+            }
+        }
+
+        let process = processes
+            .get_mut(&pid)
+            .expect("current process missing from the table");
+        process.state = State::Zombie { exit_code };
+        (
+            process.address_space.take(),
+            core::mem::take(&mut process.files),
+            process.surface.take(),
+            process.parent,
+        )
+    };
+
+    input::ring::release(pid);
+    drop(address_space);
+    drop(surface);
+    drop(files);
+    futex::remove_waiter(pid);
+    sleep::remove_sleeper(pid);
+    CURRENT.store(0, Ordering::Relaxed);
+
+    if let Some(parent) = parent {
+        wake(parent);
+    }
+}
+
+/// Outcome of `wait`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitStatus {
+    /// The child exited with the given code and was removed from the table.
+    Reaped(Pid, i32),
+    /// There are matching children, but none of them has exited yet.
+    Running,
+    /// The process has no matching children.
+    NoChildren,
+}
+
+/// Reaps an exited child of `parent`: the one with the given PID, or any if `child` is `None`.
+pub fn wait(parent: Pid, child: Option<Pid>) -> WaitStatus {
+    let mut processes = PROCESSES.lock();
+
+    let mut has_children = false;
+    let mut zombie = None;
+
+    for process in processes.values() {
+        if process.parent != Some(parent) || child.is_some_and(|child| process.pid != child) {
+            continue;
+        }
+
+        has_children = true;
+        if let State::Zombie { exit_code } = process.state {
+            zombie = Some((process.pid, exit_code));
+            break;
+        }
+    }
+
+    match zombie {
+        Some((pid, exit_code)) => {
+            processes.remove(&pid);
+            WaitStatus::Reaped(pid, exit_code)
+        }
+        None if has_children => WaitStatus::Running,
+        None => WaitStatus::NoChildren,
+    }
+}
+
+/// Removes a zombie from the table and returns its exit code.
+pub fn reap(pid: Pid) -> Option<i32> {
+    let mut processes = PROCESSES.lock();
+
+    match processes.get(&pid)?.state {
+        State::Zombie { exit_code } => {
+            processes.remove(&pid);
+            Some(exit_code)
+        }
+        _ => None,
+    }
+}
+
+/// Makes a blocked process ready again. Returns whether it was blocked.
+pub fn wake(pid: Pid) -> bool {
+    let woken = with_process(pid, |process| {
+        if process.state != State::Blocked {
+            return None;
+        }
+
+        process.state = State::Ready;
+        process.priority.on_wake();
+        Some(process.priority.level())
+    });
+
+    match woken {
+        Some(Some(level)) => {
+            sched::push(pid, level);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Blocks the current process until it is woken up, then resumes it with the registers in `context`.
+/// Typically the context restarts the syscall that blocked.
+pub fn block_current(context: Context) -> ! {
+    let pid = current().expect("block outside of a process");
+
+    with_process(pid, |process| {
+        process.context = context;
+        process.state = State::Blocked;
+    });
+    CURRENT.store(0, Ordering::Relaxed);
+
+    schedule();
+}
+
+/// Puts the current process back among the ready ones, to resume with the registers in `context`, and
+/// runs the next process. Used when the timer preempts it.
+pub fn preempt_current(context: Context) -> ! {
+    let pid = current().expect("preempt outside of a process");
+
+    let level = with_process(pid, |process| {
+        process.context = context;
+        process.state = State::Ready;
+        process.priority.level()
+    })
+    .expect("current process missing from the table");
+    CURRENT.store(0, Ordering::Relaxed);
+    sched::push(pid, level);
+
+    schedule();
+}
+
+/// Runs the next ready process, waiting with interrupts enabled until there is one.
+pub fn schedule() -> ! {
+    use x86_64::instructions::interrupts;
+
+    loop {
+        // The executor doesn't run while processes do, so work deferred by interrupt handlers, such as
+        // decoding keys, runs between them.
+        interrupts::enable();
+        crate::interrupts::deferred::run_pending();
+        interrupts::disable();
+
+        let next = interrupts::without_interrupts(|| {
+            sleep::wake_sleepers();
+            sched::pop()
+        });
+
+        if let Some(pid) = next {
+            unsafe { run(pid) };
+        }
+
+        lockup::idle(interrupts::enable_and_hlt);
+        interrupts::disable();
+    }
+}
+
+/// Switches to the process and enters it in ring 3.
+///
+/// # Safety
+///
+/// The process must be ready.
+pub unsafe fn run(pid: Pid) -> ! {
+    // Time spent in ring 3 is progress too, and is only seen by the detector between switches and syscalls.
+    lockup::touch();
+    sched::remove(pid);
+
+    let previous = LAST_RUN.swap(pid.0, Ordering::Relaxed);
+    let switched = previous != pid.0;
+    if switched && let Some(previous) = Pid::from_u64(previous) {
+        // It may have changed its bases from user mode since it last set them with a syscall.
+        with_process(previous, |process| {
+            process.segment_bases = SegmentBases::read();
+        });
+    }
+
+    let (context, woken_early) = with_process(pid, |process| {
+        assert_eq!(process.state, State::Ready, "process {} can't run", pid);
+        process.state = State::Running;
+
+        let address_space = process
+            .address_space
+            .as_ref()
+            .expect("ready process has exited");
+        unsafe { address_space.activate() };
+        // Interrupts from ring 3 and syscalls both enter the kernel on the process's own stack.
+        let kernel_stack = process.kernel_stack.top();
+        gdt::set_kernel_stack(kernel_stack);
+        syscall::set_kernel_stack(kernel_stack);
+        if switched {
+            process.segment_bases.load();
+        }
+        unsafe { fpu::switch_to(&mut *process.fpu) };
+        // A sleep cut short, e.g. by a signal, is over.
+        let woken_early = process.sleeping_until.take().is_some();
+
+        // Children inherit the trap flag along with the registers, but not the tracing.
+        let mut context = process.context;
+        if process.trace.is_some() {
+            context.rflags |= RFlags::TRAP_FLAG.bits();
+        } else {
+            context.rflags &= !RFlags::TRAP_FLAG.bits();
+        }
+        (context, woken_early)
+    })
+    .expect("no such process");
+
+    // The wheel is only taken outside of the process table, see `sleep::wake_sleepers`.
+    if woken_early {
+        sleep::remove_sleeper(pid);
+    }
+
+    if switched {
+        mitigations::on_context_switch();
+    }
+
+    CURRENT.store(pid.0, Ordering::Relaxed);
+    let context = signal::deliver(context);
+    unsafe { userspace::enter(&context) }
+}
@@ -0,0 +1,215 @@
+//! Loader for static ELF64 executables.
+//!
+//! Only what a statically linked x86_64 program needs is supported: the `PT_LOAD` segments are copied
+//! into a user address space, zero-filled up to their memory size, and execution starts at the entry
+//! point. Relocations, interpreters and TLS segments aren't.
+
+use core::ops::Range;
+use x86_64::{
+    VirtAddr,
+    structures::paging::{Page, PageTableFlags},
+};
+
+use crate::memory::{
+    self, AddressSpace,
+    address_space::{USER_END, USER_START},
+};
+
+const ELF_MAGIC: [u8; 4] = *b"\x7FELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3E;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PF_W: u32 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// Not an ELF file, or not one for this machine.
+    NotAnExecutable,
+    /// A header or segment points outside the file.
+    Truncated,
+    /// A segment would be loaded outside the user part of the address space.
+    BadAddress,
+    OutOfMemory,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = bytes.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = bytes.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, ElfError> {
+    let bytes = bytes.get(offset..offset + 8).ok_or(ElfError::Truncated)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// A loadable segment.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    virtual_address: u64,
+    file_range: (usize, usize),
+    memory_size: u64,
+    writable: bool,
+}
+
+impl Segment {
+    fn file_bytes<'a>(&self, image: &'a [u8]) -> Result<&'a [u8], ElfError> {
+        let (offset, size) = self.file_range;
+        let end = offset.checked_add(size).ok_or(ElfError::Truncated)?;
+        image.get(offset..end).ok_or(ElfError::Truncated)
+    }
+
+    fn memory_range(&self) -> Result<Range<VirtAddr>, ElfError> {
+        let end = self
+            .virtual_address
+            .checked_add(self.memory_size)
+            .ok_or(ElfError::BadAddress)?;
+        let range = VirtAddr::try_new(self.virtual_address).map_err(|_| ElfError::BadAddress)?
+            ..VirtAddr::try_new(end).map_err(|_| ElfError::BadAddress)?;
+
+        if range.start < USER_START || range.end > USER_END {
+            return Err(ElfError::BadAddress);
+        }
+
+        Ok(range)
+    }
+}
+
+/// Checks the ELF header and returns the entry point and the loadable segments.
+fn parse(
+    image: &[u8],
+) -> Result<
+    (
+        VirtAddr,
+        impl Iterator<Item = Result<Segment, ElfError>> + '_,
+    ),
+    ElfError,
+> {
+    if image.len() < HEADER_SIZE
+        || image[0..4] != ELF_MAGIC
+        || image[4] != CLASS_64
+        || image[5] != DATA_LITTLE_ENDIAN
+        || read_u16(image, 16)? != TYPE_EXECUTABLE
+        || read_u16(image, 18)? != MACHINE_X86_64
+    {
+        return Err(ElfError::NotAnExecutable);
+    }
+
+    let entry = VirtAddr::try_new(read_u64(image, 24)?).map_err(|_| ElfError::BadAddress)?;
+    let program_headers = read_u64(image, 32)? as usize;
+    let entry_size = read_u16(image, 54)? as usize;
+    let count = read_u16(image, 56)? as usize;
+
+    if entry_size < PROGRAM_HEADER_SIZE {
+        return Err(ElfError::NotAnExecutable);
+    }
+
+    let segments = (0..count).filter_map(move |index| {
+        let header = program_headers + index * entry_size;
+
+        let segment = (|| {
+            if read_u32(image, header)? != PT_LOAD {
+                return Ok(None);
+            }
+
+            Ok(Some(Segment {
+                writable: read_u32(image, header + 4)? & PF_W != 0,
+                file_range: (
+                    read_u64(image, header + 8)? as usize,
+                    read_u64(image, header + 32)? as usize,
+                ),
+                virtual_address: read_u64(image, header + 16)?,
+                memory_size: read_u64(image, header + 40)?,
+            }))
+        })();
+
+        segment.transpose()
+    });
+
+    Ok((entry, segments))
+}
+
+/// Where a program was loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Loaded {
+    pub entry: VirtAddr,
+    /// End of the highest segment, above which the heap can go.
+    pub end: VirtAddr,
+}
+
+/// Loads the segments of `image` into `space`.
+pub fn load(image: &[u8], space: &mut AddressSpace) -> Result<Loaded, ElfError> {
+    let (entry, segments) = parse(image)?;
+
+    if !(USER_START..USER_END).contains(&entry) {
+        return Err(ElfError::BadAddress);
+    }
+
+    let mut end = USER_START;
+
+    for segment in segments {
+        let segment = segment?;
+        let data = segment.file_bytes(image)?;
+        let range = segment.memory_range()?;
+
+        if data.len() as u64 > segment.memory_size {
+            return Err(ElfError::Truncated);
+        }
+        if range.start == range.end {
+            continue;
+        }
+        end = end.max(range.end);
+
+        let flags = if segment.writable {
+            PageTableFlags::WRITABLE
+        } else {
+            PageTableFlags::empty()
+        };
+
+        let pages = Page::range_inclusive(
+            Page::containing_address(range.start),
+            Page::containing_address(range.end - 1u64),
+        );
+
+        for page in pages {
+            // Segments may share a page at their boundaries.
+            let frame = match space.translate(page) {
+                Some((frame, _)) => frame,
+                None => space
+                    .map_user(page, flags)
+                    .map_err(|_| ElfError::OutOfMemory)?,
+            };
+
+            // Copy the part of the file that lands in this page; the rest stays zeroed.
+            let page_start = page.start_address().as_u64();
+            let file_start = segment.virtual_address.max(page_start);
+            let file_end = (segment.virtual_address + data.len() as u64).min(page_start + 4096);
+
+            if file_start < file_end {
+                let source = &data[(file_start - segment.virtual_address) as usize
+                    ..(file_end - segment.virtual_address) as usize];
+                let destination =
+                    memory::phys_to_virt(frame.start_address()) + (file_start - page_start);
+
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        source.as_ptr(),
+                        destination.as_mut_ptr::<u8>(),
+                        source.len(),
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(Loaded { entry, end })
+}
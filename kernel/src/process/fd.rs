@@ -0,0 +1,246 @@
+//! File descriptors: small integers a process uses to refer to the kernel's file objects.
+//!
+//! Every process has an `FdTable`. Descriptors are handed out lowest first, like on Unix, and a child
+//! starts with copies of its parent's, which refer to the same files and so share their read positions.
+//! Programs started by the kernel get the standard descriptors: the serial port as standard input and the
+//! console as standard output and error. Pipes, see `pipe`, are files too.
+
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+use super::pipe::{PipeReader, PipeWriter};
+use crate::{initrd, print, serial::SERIAL1, sync::WaitQueue};
+
+/// Base of the COM1 registers, see `serial`.
+const COM1: u16 = 0x3F8;
+/// Line status register bit set while a received byte is waiting.
+const DATA_READY: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileError {
+    /// The file can't be read, e.g. the console.
+    NotReadable,
+    /// The file can't be written, e.g. a file of the initrd.
+    NotWritable,
+    /// Nothing to read yet, or no room to write.
+    WouldBlock,
+    /// A write to a pipe whose read ends are all closed.
+    BrokenPipe,
+}
+
+/// A file object a descriptor refers to.
+#[derive(Debug, Clone)]
+pub enum File {
+    /// The framebuffer console. Write-only.
+    Console,
+    /// COM1. Reads return what has been received so far.
+    Serial,
+    /// A file of the initrd. Read-only.
+    Initrd(Arc<InitrdFile>),
+    /// The read end of a pipe.
+    PipeReader(Arc<PipeReader>),
+    /// The write end of a pipe.
+    PipeWriter(Arc<PipeWriter>),
+}
+
+/// An open file of the initrd, with the read position shared by every descriptor that refers to it.
+#[derive(Debug)]
+pub struct InitrdFile {
+    data: &'static [u8],
+    offset: Mutex<usize>,
+}
+
+impl File {
+    /// Opens the file at `path`: `/dev/console`, `/dev/serial`, or a file of the initrd.
+    pub fn open(path: &str) -> Option<File> {
+        match path {
+            "/dev/console" => Some(File::Console),
+            "/dev/serial" => Some(File::Serial),
+            path => initrd::find(path).map(|data| {
+                File::Initrd(Arc::new(InitrdFile {
+                    data,
+                    offset: Mutex::new(0),
+                }))
+            }),
+        }
+    }
+
+    /// Whether the file can be written at all.
+    pub fn is_writable(&self) -> bool {
+        !matches!(self, File::Initrd(_) | File::PipeReader(_))
+    }
+
+    /// Where a process waits for a read or write that would block to be able to make progress. Files
+    /// without one, like the serial port, aren't waited for.
+    pub fn waiters(&self, write: bool) -> Option<&WaitQueue> {
+        match (self, write) {
+            (File::PipeReader(reader), false) => Some(reader.waiters()),
+            (File::PipeWriter(writer), true) => Some(writer.waiters()),
+            _ => None,
+        }
+    }
+
+    /// Reads into `buffer`, returning the number of bytes read, 0 at the end of the file.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, FileError> {
+        match self {
+            File::Console | File::PipeWriter(_) => Err(FileError::NotReadable),
+            File::Serial => read_serial(buffer),
+            File::Initrd(file) => {
+                let mut offset = file.offset.lock();
+                let remaining = file.data.get(*offset..).unwrap_or_default();
+                let len = remaining.len().min(buffer.len());
+
+                buffer[..len].copy_from_slice(&remaining[..len]);
+                *offset += len;
+                Ok(len)
+            }
+            File::PipeReader(reader) => reader.read(buffer),
+        }
+    }
+
+    /// Writes `bytes`, returning the number of bytes written.
+    pub fn write(&self, bytes: &[u8]) -> Result<usize, FileError> {
+        match self {
+            File::Console => {
+                for chunk in bytes.utf8_chunks() {
+                    print!("{}", chunk.valid());
+
+                    if !chunk.invalid().is_empty() {
+                        print!("{}", char::REPLACEMENT_CHARACTER);
+                    }
+                }
+                Ok(bytes.len())
+            }
+            File::Serial => {
+                interrupts::without_interrupts(|| {
+                    let mut serial = SERIAL1.lock();
+                    for &byte in bytes {
+                        serial.send_raw(byte);
+                    }
+                });
+                Ok(bytes.len())
+            }
+            File::Initrd(_) | File::PipeReader(_) => Err(FileError::NotWritable),
+            File::PipeWriter(writer) => writer.write(bytes),
+        }
+    }
+}
+
+/// Takes the bytes COM1 has received, without waiting for more.
+fn read_serial(buffer: &mut [u8]) -> Result<usize, FileError> {
+    let mut data = Port::<u8>::new(COM1);
+    let mut line_status = Port::<u8>::new(COM1 + 5);
+
+    // Holding the lock keeps `serial::xmodem` from taking the same bytes.
+    let len = interrupts::without_interrupts(|| {
+        let _serial = SERIAL1.lock();
+        let mut len = 0;
+
+        while len < buffer.len() && unsafe { line_status.read() } & DATA_READY != 0 {
+            buffer[len] = unsafe { data.read() };
+            len += 1;
+        }
+        len
+    });
+
+    if len == 0 && !buffer.is_empty() {
+        return Err(FileError::WouldBlock);
+    }
+    Ok(len)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdError {
+    /// The descriptor isn't open.
+    BadDescriptor,
+    /// Opening another file would exceed the `OpenFiles` limit.
+    TooManyOpen,
+}
+
+/// The open files of a process, indexed by descriptor.
+#[derive(Debug, Clone, Default)]
+pub struct FdTable {
+    files: Vec<Option<File>>,
+}
+
+impl FdTable {
+    /// A table without any open files.
+    pub const fn new() -> Self {
+        FdTable { files: Vec::new() }
+    }
+
+    /// Standard input, output and error, for programs started by the kernel.
+    pub fn standard() -> Self {
+        let mut table = FdTable::new();
+        for fd in [libsys::STDIN, libsys::STDOUT, libsys::STDERR] {
+            let file = standard_file(fd).expect("standard descriptor without a file");
+            table.files.push(Some(file));
+        }
+        table
+    }
+
+    /// Opens `file` at the lowest free descriptor, which must stay below `limit`.
+    pub fn insert(&mut self, file: File, limit: u64) -> Result<u64, FdError> {
+        let fd = self
+            .files
+            .iter()
+            .position(Option::is_none)
+            .unwrap_or(self.files.len());
+
+        if fd as u64 >= limit {
+            return Err(FdError::TooManyOpen);
+        }
+
+        if fd == self.files.len() {
+            self.files.push(Some(file));
+        } else {
+            self.files[fd] = Some(file);
+        }
+        Ok(fd as u64)
+    }
+
+    /// The file open at `fd`.
+    pub fn get(&self, fd: u64) -> Option<&File> {
+        let fd = usize::try_from(fd).ok()?;
+        self.files.get(fd)?.as_ref()
+    }
+
+    /// Closes `fd`, returning its file.
+    pub fn close(&mut self, fd: u64) -> Result<File, FdError> {
+        let file = usize::try_from(fd)
+            .ok()
+            .and_then(|fd| self.files.get_mut(fd))
+            .and_then(Option::take)
+            .ok_or(FdError::BadDescriptor)?;
+
+        // Trailing free slots aren't needed to keep the other descriptors where they are.
+        while let Some(None) = self.files.last() {
+            self.files.pop();
+        }
+        Ok(file)
+    }
+
+    /// Number of open descriptors.
+    pub fn open_count(&self) -> usize {
+        self.files.iter().flatten().count()
+    }
+}
+
+/// What the standard descriptors refer to. Also used for syscalls made by the kernel itself, which has no
+/// descriptor table.
+pub fn standard_file(fd: u64) -> Option<File> {
+    match fd {
+        libsys::STDIN => Some(File::Serial),
+        libsys::STDOUT | libsys::STDERR => Some(File::Console),
+        _ => None,
+    }
+}
+
+#[test_case]
+fn test_console_is_write_only() {
+    assert_eq!(File::Console.read(&mut [0; 4]), Err(FileError::NotReadable));
+    assert_eq!(File::Console.write(b""), Ok(0));
+    assert!(matches!(standard_file(libsys::STDERR), Some(File::Console)));
+    assert!(standard_file(3).is_none());
+}
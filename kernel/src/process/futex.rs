@@ -0,0 +1,130 @@
+//! Futexes: wait queues keyed on user addresses, for locks that only enter the kernel when contended.
+//!
+//! User code keeps the state of a lock in a 32-bit word and only makes the `futex` syscall to sleep while
+//! the word holds a value meaning "locked", or to wake sleepers after changing it. The kernel never
+//! interprets the word: waiting compares it with the value the caller expects, so a change made right
+//! before the caller goes to sleep isn't missed, and waking only looks at who waits on the address.
+//!
+//! A futex is identified by the address space and the virtual address of the word, so processes sharing
+//! an address space would share its futexes. Waiters are kept in a fixed hash table of queues. A waiter
+//! may be woken without a matching wake, e.g. by a signal, so callers must check the word again.
+
+use alloc::collections::VecDeque;
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+
+use super::{Pid, current, wake, with_process};
+
+const BUCKETS: usize = 64;
+
+static TABLE: [Mutex<VecDeque<Waiter>>; BUCKETS] = [const { Mutex::new(VecDeque::new()) }; BUCKETS];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FutexError {
+    NoProcess,
+    /// The address isn't aligned to the 32-bit word.
+    Unaligned,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Key {
+    /// Physical address of the level 4 page table, which identifies the address space.
+    space: u64,
+    address: u64,
+}
+
+impl Key {
+    /// The futex at `address` in the address space of the current process.
+    fn current(address: u64) -> Result<Self, FutexError> {
+        if !address.is_multiple_of(4) {
+            return Err(FutexError::Unaligned);
+        }
+
+        let pid = current().ok_or(FutexError::NoProcess)?;
+        let space = with_process(pid, |process| {
+            process
+                .address_space()
+                .map(|space| space.level_4_frame().start_address().as_u64())
+        })
+        .flatten()
+        .ok_or(FutexError::NoProcess)?;
+
+        Ok(Key { space, address })
+    }
+
+    fn bucket(&self) -> &'static Mutex<VecDeque<Waiter>> {
+        // Fibonacci hashing of the word index; page tables are page-aligned, so their low bits are zero.
+        let hash = (self.address >> 2 ^ self.space >> 12).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        &TABLE[(hash >> (64 - BUCKETS.trailing_zeros())) as usize]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Waiter {
+    key: Key,
+    pid: Pid,
+}
+
+/// Queues the current process as a waiter on the futex at `address`. The caller blocks it next, without
+/// enabling interrupts in between so that no wake is missed.
+pub fn add_waiter(address: u64) -> Result<(), FutexError> {
+    let key = Key::current(address)?;
+    let pid = current().ok_or(FutexError::NoProcess)?;
+
+    let mut waiters = key.bucket().lock();
+    // A process left behind by a wake that didn't come from the futex must not be queued twice.
+    waiters.retain(|waiter| waiter.pid != pid);
+    waiters.push_back(Waiter { key, pid });
+    Ok(())
+}
+
+/// Wakes up to `count` processes waiting on the futex at `address`, the longest waiting first. Returns
+/// how many were woken.
+pub fn wake_waiters(address: u64, count: u64) -> Result<u64, FutexError> {
+    Ok(wake_key(Key::current(address)?, count))
+}
+
+/// Like `wake_waiters`, for the futex at `address` in the address space whose level 4 table is `space`
+/// rather than the current one. Lets the kernel ring a doorbell in memory it shares with a process.
+pub fn wake_waiters_in(space: PhysFrame, address: u64, count: u64) -> Result<u64, FutexError> {
+    if !address.is_multiple_of(4) {
+        return Err(FutexError::Unaligned);
+    }
+
+    let space = space.start_address().as_u64();
+    Ok(wake_key(Key { space, address }, count))
+}
+
+fn wake_key(key: Key, count: u64) -> u64 {
+    let mut woken = 0;
+
+    while woken < count {
+        let mut waiters = key.bucket().lock();
+        let Some(index) = waiters.iter().position(|waiter| waiter.key == key) else {
+            break;
+        };
+        let waiter = waiters.remove(index).expect("waiter vanished");
+        drop(waiters);
+
+        // Waiters woken meanwhile by something else, such as a signal, don't count.
+        if wake(waiter.pid) {
+            woken += 1;
+        }
+    }
+
+    woken
+}
+
+/// Forgets the futexes `pid` waits on, once it exits.
+pub(super) fn remove_waiter(pid: Pid) {
+    for bucket in &TABLE {
+        bucket.lock().retain(|waiter| waiter.pid != pid);
+    }
+}
+
+#[test_case]
+fn test_futexes_need_an_aligned_address_and_a_process() {
+    assert_eq!(add_waiter(0x1002), Err(FutexError::Unaligned));
+    assert_eq!(add_waiter(0x1000), Err(FutexError::NoProcess));
+    assert_eq!(wake_waiters(0x1000, 1), Err(FutexError::NoProcess));
+}
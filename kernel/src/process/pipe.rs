@@ -0,0 +1,174 @@
+//! Pipes: one-way byte channels between processes.
+//!
+//! A pipe is a ring buffer with a read end and a write end. Each end is a `File`, so any number of
+//! descriptors can refer to it, e.g. in a parent and the children it forks. A read of an empty pipe and a
+//! write to a full one can't complete yet: the syscall blocks the process on the pipe's wait queue and is
+//! restarted when the other end makes progress. Once every write end is closed, reads return what is
+//! left and then 0, the end of the file; once every read end is closed, writes fail with a broken pipe.
+
+use alloc::{boxed::Box, sync::Arc, vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use spin::Mutex;
+
+use super::fd::FileError;
+use crate::sync::WaitQueue;
+
+/// Bytes a pipe holds before writers have to wait for a reader.
+pub const PIPE_SIZE: usize = 4096;
+
+struct RingBuffer {
+    bytes: Box<[u8]>,
+    /// Index of the oldest byte.
+    start: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    fn new(capacity: usize) -> Self {
+        RingBuffer {
+            bytes: vec![0; capacity].into_boxed_slice(),
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Appends as much of `bytes` as fits, returning how much that was.
+    fn push(&mut self, bytes: &[u8]) -> usize {
+        let capacity = self.bytes.len();
+        let count = bytes.len().min(capacity - self.len);
+
+        for (index, &byte) in bytes[..count].iter().enumerate() {
+            self.bytes[(self.start + self.len + index) % capacity] = byte;
+        }
+        self.len += count;
+        count
+    }
+
+    /// Takes the oldest bytes into `buffer`, returning how many there were.
+    fn pop(&mut self, buffer: &mut [u8]) -> usize {
+        let capacity = self.bytes.len();
+        let count = buffer.len().min(self.len);
+
+        for (index, byte) in buffer[..count].iter_mut().enumerate() {
+            *byte = self.bytes[(self.start + index) % capacity];
+        }
+        self.start = (self.start + count) % capacity;
+        self.len -= count;
+        count
+    }
+}
+
+/// Both wait queues are always woken as a whole: a process woken by a signal meanwhile leaves a stale
+/// entry behind, which would swallow a `wake_one`.
+struct Pipe {
+    buffer: Mutex<RingBuffer>,
+    /// Set once every read end is closed.
+    read_closed: AtomicBool,
+    /// Set once every write end is closed.
+    write_closed: AtomicBool,
+    /// Processes waiting for bytes to read.
+    readers: WaitQueue,
+    /// Processes waiting for room to write.
+    writers: WaitQueue,
+}
+
+impl fmt::Debug for Pipe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pipe")
+            .field("len", &self.buffer.lock().len)
+            .field("read_closed", &self.read_closed.load(Ordering::Relaxed))
+            .field("write_closed", &self.write_closed.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+/// The read end of a pipe. Closed when the last `File` referring to it is dropped.
+#[derive(Debug)]
+pub struct PipeReader {
+    pipe: Arc<Pipe>,
+}
+
+/// The write end of a pipe. Closed when the last `File` referring to it is dropped.
+#[derive(Debug)]
+pub struct PipeWriter {
+    pipe: Arc<Pipe>,
+}
+
+/// Creates a pipe and returns its two ends.
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        buffer: Mutex::new(RingBuffer::new(PIPE_SIZE)),
+        read_closed: AtomicBool::new(false),
+        write_closed: AtomicBool::new(false),
+        readers: WaitQueue::new(),
+        writers: WaitQueue::new(),
+    });
+
+    (PipeReader { pipe: pipe.clone() }, PipeWriter { pipe })
+}
+
+impl PipeReader {
+    /// Reads what has been written so far, up to `buffer.len()` bytes. Returns 0 once the pipe is empty
+    /// and every write end is closed.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, FileError> {
+        let read = self.pipe.buffer.lock().pop(buffer);
+
+        if read == 0 && !buffer.is_empty() && !self.pipe.write_closed.load(Ordering::Acquire) {
+            return Err(FileError::WouldBlock);
+        }
+
+        if read > 0 {
+            self.pipe.writers.wake_all();
+        }
+        Ok(read)
+    }
+
+    /// Where readers wait for the pipe to have something to read.
+    pub fn waiters(&self) -> &WaitQueue {
+        &self.pipe.readers
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.pipe.read_closed.store(true, Ordering::Release);
+        // Blocked writers find out that nobody will read what they write.
+        self.pipe.writers.wake_all();
+    }
+}
+
+impl PipeWriter {
+    /// Writes as much of `bytes` as there is room for, returning how much that was.
+    pub fn write(&self, bytes: &[u8]) -> Result<usize, FileError> {
+        if self.pipe.read_closed.load(Ordering::Acquire) {
+            return Err(FileError::BrokenPipe);
+        }
+
+        let written = self.pipe.buffer.lock().push(bytes);
+
+        if written == 0 && !bytes.is_empty() {
+            return Err(FileError::WouldBlock);
+        }
+
+        if written > 0 {
+            self.pipe.readers.wake_all();
+        }
+        Ok(written)
+    }
+
+    /// Where writers wait for the pipe to have room.
+    pub fn waiters(&self) -> &WaitQueue {
+        &self.pipe.writers
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.pipe.write_closed.store(true, Ordering::Release);
+        // Blocked readers get to the end of the file.
+        self.pipe.readers.wake_all();
+    }
+}
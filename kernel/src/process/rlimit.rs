@@ -0,0 +1,102 @@
+//! Per-process resource limits.
+//!
+//! Every limit has a soft value, which is what gets enforced, and a hard value, the most a process may
+//! raise its soft value to. Hard values can only be lowered. Children start with their parent's limits.
+
+pub use libsys::rlimit::{INFINITY, Rlimit};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    /// Bytes of user heap.
+    Heap,
+    OpenFiles,
+    /// CPU time in milliseconds. Past the soft value a process gets `SIGXCPU` every second, past the
+    /// hard value `SIGKILL`.
+    CpuTime,
+    /// Children that exist at the same time, zombies included.
+    Children,
+    /// Kernel log records per second. A process with a limit of 0 can't log at all.
+    LogRate,
+}
+
+impl Resource {
+    /// The resource with the given `libsys::rlimit` number.
+    pub fn from_number(number: u64) -> Option<Self> {
+        match number {
+            libsys::rlimit::HEAP => Some(Resource::Heap),
+            libsys::rlimit::OPEN_FILES => Some(Resource::OpenFiles),
+            libsys::rlimit::CPU_TIME => Some(Resource::CpuTime),
+            libsys::rlimit::CHILDREN => Some(Resource::Children),
+            libsys::rlimit::LOG_RATE => Some(Resource::LogRate),
+            _ => None,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitError {
+    /// The soft value is above the hard value.
+    SoftAboveHard,
+    /// Hard values can't be raised.
+    RaisesHardLimit,
+}
+
+/// Returned when an operation would take a process over its soft limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimitExceeded {
+    pub resource: Resource,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    limits: [Rlimit; 5],
+}
+
+impl Limits {
+    pub fn get(&self, resource: Resource) -> Rlimit {
+        self.limits[resource.index()]
+    }
+
+    pub fn set(&mut self, resource: Resource, limit: Rlimit) -> Result<(), LimitError> {
+        if limit.soft > limit.hard {
+            return Err(LimitError::SoftAboveHard);
+        }
+        if limit.hard > self.get(resource).hard {
+            return Err(LimitError::RaisesHardLimit);
+        }
+
+        self.limits[resource.index()] = limit;
+        Ok(())
+    }
+
+    /// Checks that `amount` of `resource` is within the soft limit.
+    pub fn check(&self, resource: Resource, amount: u64) -> Result<(), LimitExceeded> {
+        if amount > self.get(resource).soft {
+            return Err(LimitExceeded { resource });
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Limits {
+    /// Generous enough for any well-behaved program, low enough that a runaway one (e.g. a fork bomb)
+    /// can't take the kernel down.
+    fn default() -> Self {
+        let limit = |soft, hard| Rlimit { soft, hard };
+
+        Limits {
+            limits: [
+                limit(16 * 1024 * 1024, INFINITY),
+                limit(64, 1024),
+                limit(INFINITY, INFINITY),
+                limit(16, 256),
+                limit(10, 100),
+            ],
+        }
+    }
+}
@@ -0,0 +1,339 @@
+//! Scheduling policy: a multi-level feedback queue.
+//!
+//! Ready processes wait in one of `LEVELS` queues, and the CPU goes to the oldest process of the highest
+//! non-empty one. A process's nice value sets the highest level it can be at, five nice values per level,
+//! so that processes with the default nice value of 0 start in the middle. Each level down gets a time
+//! slice twice as long as the one above.
+//!
+//! Timer ticks that interrupt ring 3 are charged to the running process. One that uses up its slice is
+//! computing rather than waiting for anything, so it moves down a level and, if another process is
+//! ready, is preempted. A process that blocks reading input is interactive: when it is woken it goes back
+//! to its highest level, so it gets the CPU ahead of the busy ones as soon as its input arrives. A tick
+//! also preempts the running process for one that became ready at a higher level, such as a sleeper whose
+//! deadline passed. Every `BOOST_INTERVAL_TICKS` every process is moved back up, so busy processes can't
+//! starve at the bottom.
+//!
+//! The ticks charged to a process are also its CPU time, which is held to its `CpuTime` limit: past the
+//! soft value it gets `SIGXCPU` every second, and past the hard value `SIGKILL`.
+//!
+//! The slice at the highest level is a setting, `set_slice_ticks`, which user programs change with the
+//! `sysctl` syscall. The `sched-latency` feature runs a smoke test at boot that shows what it does to the
+//! wakeup latency of an interactive process.
+
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::{
+    Context, PROCESSES, Pid, current, preempt_current,
+    rlimit::{Resource, Rlimit},
+    signal::{self, Signal},
+    sleep, with_process,
+};
+use crate::{interrupts::debug::GeneralRegisters, time};
+
+pub const LEVELS: usize = 8;
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+/// Nice values that share a highest level.
+const NICE_PER_LEVEL: i8 = 5;
+/// Length of a slice at a process's highest level, until changed with `set_slice_ticks`.
+pub const DEFAULT_SLICE_TICKS: u32 = 2;
+/// Longest slice `set_slice_ticks` takes, a second at the default tick rate.
+pub const MAX_SLICE_TICKS: u32 = 100;
+/// Ticks between moving every process back to its highest level, a second at the default tick rate.
+const BOOST_INTERVAL_TICKS: u64 = 100;
+
+static SLICE_TICKS: AtomicU32 = AtomicU32::new(DEFAULT_SLICE_TICKS);
+
+/// Ready processes, by level.
+static READY: Mutex<[VecDeque<Pid>; LEVELS]> = Mutex::new([const { VecDeque::new() }; LEVELS]);
+
+/// Where a process stands with the scheduler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Priority {
+    nice: i8,
+    level: u8,
+    /// Ticks left in the current slice.
+    ticks_left: u32,
+    /// Set while the process is blocked reading input, see `waits_for_input`.
+    waiting_for_input: bool,
+}
+
+impl Priority {
+    /// A process with the given nice value at its highest level.
+    pub fn new(nice: i8) -> Self {
+        let mut priority = Priority {
+            nice: nice.clamp(NICE_MIN, NICE_MAX),
+            level: 0,
+            ticks_left: 0,
+            waiting_for_input: false,
+        };
+        priority.boost();
+        priority
+    }
+
+    pub fn nice(&self) -> i8 {
+        self.nice
+    }
+
+    /// The queue the process waits in, 0 being the first to run.
+    pub fn level(&self) -> usize {
+        usize::from(self.level)
+    }
+
+    /// The highest level the nice value allows.
+    pub fn top_level(&self) -> usize {
+        ((self.nice - NICE_MIN) / NICE_PER_LEVEL) as usize
+    }
+
+    /// Changes the nice value, moving the process to the new highest level if it is now above it, or up
+    /// to it if the process asked for more priority.
+    pub fn set_nice(&mut self, nice: i8) {
+        let raised = nice < self.nice;
+        self.nice = nice.clamp(NICE_MIN, NICE_MAX);
+
+        if raised || self.level() < self.top_level() {
+            self.boost();
+        }
+    }
+
+    /// Moves the process back to its highest level with a fresh slice.
+    fn boost(&mut self) {
+        self.level = self.top_level() as u8;
+        self.ticks_left = self.slice_ticks();
+    }
+
+    fn slice_ticks(&self) -> u32 {
+        slice_ticks() << (self.level() - self.top_level())
+    }
+
+    /// Charges one tick to the process. Returns whether that used up its slice, in which case it is
+    /// moved down a level with a new slice.
+    fn charge(&mut self) -> bool {
+        self.ticks_left = self.ticks_left.saturating_sub(1);
+        if self.ticks_left > 0 {
+            return false;
+        }
+
+        self.level = (self.level() + 1).min(LEVELS - 1) as u8;
+        self.ticks_left = self.slice_ticks();
+        true
+    }
+
+    /// Called when the process is woken up.
+    pub(super) fn on_wake(&mut self) {
+        if core::mem::take(&mut self.waiting_for_input) {
+            self.boost();
+        }
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::new(0)
+    }
+}
+
+/// Length of a slice at a process's highest level.
+pub fn slice_ticks() -> u32 {
+    SLICE_TICKS.load(Ordering::Relaxed)
+}
+
+/// Sets the length of a slice at a process's highest level, from 1 to `MAX_SLICE_TICKS` ticks. Running
+/// processes get it with their next slice.
+pub fn set_slice_ticks(ticks: u32) -> Result<(), InvalidSlice> {
+    if !(1..=MAX_SLICE_TICKS).contains(&ticks) {
+        return Err(InvalidSlice(ticks));
+    }
+    SLICE_TICKS.store(ticks, Ordering::Relaxed);
+    Ok(())
+}
+
+/// A slice length `set_slice_ticks` doesn't take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidSlice(pub u32);
+
+/// Queues a ready process at `level`.
+pub(super) fn push(pid: Pid, level: usize) {
+    READY.lock()[level.min(LEVELS - 1)].push_back(pid);
+}
+
+/// Takes the next process to run.
+pub(super) fn pop() -> Option<Pid> {
+    READY.lock().iter_mut().find_map(VecDeque::pop_front)
+}
+
+/// Takes `pid` out of the ready queues.
+pub(super) fn remove(pid: Pid) {
+    for level in READY.lock().iter_mut() {
+        level.retain(|&ready| ready != pid);
+    }
+}
+
+/// Ready processes at each level.
+pub fn ready_counts() -> [usize; LEVELS] {
+    let ready = READY.lock();
+    core::array::from_fn(|level| ready[level].len())
+}
+
+/// Marks the current process, which is about to block, as waiting for input, so it is boosted when it
+/// is woken up.
+pub fn waits_for_input() {
+    if let Some(pid) = current() {
+        with_process(pid, |process| process.priority.waiting_for_input = true);
+    }
+}
+
+/// Adds `increment` to the nice value of the current process, returning the new one.
+pub fn nice(increment: i64) -> Option<i8> {
+    let pid = current()?;
+    with_process(pid, |process| {
+        let nice = (i64::from(process.priority.nice) + increment)
+            .clamp(i64::from(NICE_MIN), i64::from(NICE_MAX));
+        process.priority.set_nice(nice as i8);
+        process.priority.nice
+    })
+}
+
+/// Moves every process back to its highest level.
+fn boost_all() {
+    let mut processes = PROCESSES.lock();
+    for process in processes.values_mut() {
+        process.priority.boost();
+    }
+
+    let mut ready = READY.lock();
+    let levels = core::mem::replace(&mut *ready, [const { VecDeque::new() }; LEVELS]);
+    for pid in levels.into_iter().flatten() {
+        let level = processes
+            .get(&pid)
+            .map_or(0, |process| process.priority.level());
+        ready[level].push_back(pid);
+    }
+}
+
+/// The signal for a process whose CPU time went from `before` to `after` milliseconds under `limit`, see
+/// the module documentation.
+fn cpu_limit_signal(limit: Rlimit, before: u64, after: u64) -> Option<Signal> {
+    if after > limit.hard {
+        Some(Signal::KILL)
+    } else if after > limit.soft
+        && (before <= limit.soft || (before - limit.soft) / 1000 != (after - limit.soft) / 1000)
+    {
+        Some(Signal::XCPU)
+    } else {
+        None
+    }
+}
+
+/// Charges a timer tick that interrupted ring 3 to the running process, whose registers are in
+/// `registers` and `stack_frame`, and wakes the sleepers that are due. The process is preempted if it
+/// used up its slice and another one is ready, or if one at a higher level is, in which case this
+/// doesn't return. It is also preempted if the tick took it over a CPU time limit, to get the signal
+/// as it resumes. The interrupt must have been acknowledged already.
+pub(crate) fn on_tick(registers: &GeneralRegisters, stack_frame: &InterruptStackFrame) {
+    let Some(pid) = current() else {
+        return;
+    };
+
+    if time::ticks().is_multiple_of(BOOST_INTERVAL_TICKS) {
+        boost_all();
+    }
+
+    sleep::wake_sleepers();
+
+    let Some((expired, level, signal)) = with_process(pid, |process| {
+        let before = process.cpu_nanos / 1_000_000;
+        process.cpu_nanos += time::nanos_per_tick();
+        let limit = process.limits.get(Resource::CpuTime);
+        let signal = cpu_limit_signal(limit, before, process.cpu_nanos / 1_000_000);
+
+        (process.priority.charge(), process.priority.level(), signal)
+    }) else {
+        return;
+    };
+
+    if let Some(signal) = signal {
+        let _ = signal::send(pid, signal);
+        preempt_current(Context::interrupted(registers, stack_frame));
+    }
+
+    let first_ready = READY.lock().iter().position(|level| !level.is_empty());
+    if first_ready.is_some_and(|first| expired || first < level) {
+        preempt_current(Context::interrupted(registers, stack_frame));
+    }
+}
+
+#[test_case]
+fn test_nice_sets_the_highest_level() {
+    assert_eq!(Priority::new(NICE_MIN).level(), 0);
+    assert_eq!(Priority::new(0).level(), 4);
+    assert_eq!(Priority::new(NICE_MAX).level(), LEVELS - 1);
+    assert_eq!(Priority::new(100).nice(), NICE_MAX);
+}
+
+#[test_case]
+fn test_busy_processes_sink_and_get_longer_slices() {
+    let mut priority = Priority::new(0);
+
+    assert!(!priority.charge());
+    assert!(priority.charge());
+    assert_eq!(priority.level(), 5);
+    assert_eq!(priority.ticks_left, 2 * slice_ticks());
+
+    for _ in 0..100 {
+        priority.charge();
+    }
+    assert_eq!(priority.level(), LEVELS - 1);
+
+    priority.on_wake();
+    assert_eq!(priority.level(), LEVELS - 1);
+
+    priority.waiting_for_input = true;
+    priority.on_wake();
+    assert_eq!(priority.level(), 4);
+    assert!(!priority.waiting_for_input);
+}
+
+#[test_case]
+fn test_slice_length_is_a_setting() {
+    assert_eq!(set_slice_ticks(0), Err(InvalidSlice(0)));
+    assert_eq!(
+        set_slice_ticks(MAX_SLICE_TICKS + 1),
+        Err(InvalidSlice(MAX_SLICE_TICKS + 1))
+    );
+
+    set_slice_ticks(5).unwrap();
+    let mut priority = Priority::new(0);
+    assert_eq!(priority.ticks_left, 5);
+    for _ in 0..5 {
+        priority.charge();
+    }
+    assert_eq!(priority.ticks_left, 10);
+
+    set_slice_ticks(DEFAULT_SLICE_TICKS).unwrap();
+}
+
+#[test_case]
+fn test_cpu_time_limits_signal_once_a_second() {
+    let limit = Rlimit {
+        soft: 1000,
+        hard: 3000,
+    };
+
+    assert_eq!(cpu_limit_signal(limit, 990, 1000), None);
+    assert_eq!(cpu_limit_signal(limit, 1000, 1010), Some(Signal::XCPU));
+    assert_eq!(cpu_limit_signal(limit, 1010, 1020), None);
+    assert_eq!(cpu_limit_signal(limit, 1980, 1990), None);
+    assert_eq!(cpu_limit_signal(limit, 1990, 2000), Some(Signal::XCPU));
+    assert_eq!(cpu_limit_signal(limit, 2000, 2010), None);
+    assert_eq!(cpu_limit_signal(limit, 3000, 3010), Some(Signal::KILL));
+
+    let unlimited = Rlimit {
+        soft: super::rlimit::INFINITY,
+        hard: super::rlimit::INFINITY,
+    };
+    assert_eq!(cpu_limit_signal(unlimited, u64::MAX - 10, u64::MAX), None);
+}
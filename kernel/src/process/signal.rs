@@ -0,0 +1,281 @@
+//! Signals: asynchronous notifications to processes.
+//!
+//! Every process has a mask of pending signals and an action per signal. Signals are delivered when the
+//! process is about to return to ring 3, either from a syscall or when it is scheduled, so a process
+//! blocked in a syscall is woken up to receive one. The default action of every signal is to terminate
+//! the process, which then exits with code `128 + signal` like in a shell.
+//!
+//! A signal with a user handler is delivered by saving the registers the process would have resumed with
+//! on its stack and entering the handler with the signal number as its argument. The handler returns into
+//! a restorer given by the program (`libsys` passes its own), which calls `sigreturn` to resume with the
+//! saved registers.
+//!
+//! Faults in user code, such as a page fault outside any mapping, terminate the process with the
+//! matching signal right away, even if it has a handler.
+
+use libsys::signal::{SIGFPE, SIGILL, SIGKILL, SIGPIPE, SIGSEGV, SIGTRAP, SIGXCPU};
+use x86_64::{VirtAddr, registers::rflags::RFlags};
+
+use super::{Context, Pid, State, current, exit_current, schedule, wake, with_process};
+use crate::{klog, syscall};
+
+/// Signals are numbered from 1 to `COUNT - 1`.
+pub const COUNT: usize = 32;
+/// Bytes below the stack pointer that the handler must not overwrite, the red zone of the System V ABI.
+const RED_ZONE: u64 = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Signal(u8);
+
+impl Signal {
+    pub const KILL: Signal = Signal(SIGKILL as u8);
+    pub const SEGV: Signal = Signal(SIGSEGV as u8);
+    pub const PIPE: Signal = Signal(SIGPIPE as u8);
+    pub const XCPU: Signal = Signal(SIGXCPU as u8);
+
+    /// The signal with the given `libsys::signal` number.
+    pub fn from_number(number: u64) -> Option<Self> {
+        (1..COUNT as u64)
+            .contains(&number)
+            .then_some(Signal(number as u8))
+    }
+
+    /// The signal for a CPU exception raised by user code.
+    pub fn for_exception(vector: u8) -> Self {
+        let number = match vector {
+            // Divide error, x87 and SIMD floating point errors.
+            0 | 16 | 19 => SIGFPE,
+            1 | 3 => SIGTRAP,
+            6 => SIGILL,
+            _ => SIGSEGV,
+        };
+        Signal(number as u8)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        u64::from(self.0)
+    }
+
+    fn bit(self) -> u64 {
+        1 << self.0
+    }
+
+    /// Whether the action can be changed. `SIGKILL` always terminates.
+    fn is_catchable(self) -> bool {
+        self != Signal::KILL
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Action {
+    /// Terminate the process.
+    #[default]
+    Default,
+    Ignore,
+    /// Run `handler(signal)` in user mode, returning into `restorer`.
+    Handler {
+        handler: u64,
+        restorer: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalError {
+    NoSuchProcess,
+    /// The action of `SIGKILL` can't be changed.
+    Uncatchable,
+}
+
+/// Pending signals and actions of a process.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Signals {
+    pending: u64,
+    actions: [Action; COUNT],
+}
+
+impl Signals {
+    /// What a child starts with: the same actions and nothing pending.
+    pub fn fork(&self) -> Self {
+        Signals {
+            pending: 0,
+            actions: self.actions,
+        }
+    }
+
+    /// Handlers belong to the old program, so `exec` resets them. Ignored signals stay ignored.
+    pub fn exec(&mut self) {
+        for action in &mut self.actions {
+            if let Action::Handler { .. } = action {
+                *action = Action::Default;
+            }
+        }
+    }
+
+    pub fn is_pending(&self, signal: Signal) -> bool {
+        self.pending & signal.bit() != 0
+    }
+
+    pub fn action(&self, signal: Signal) -> Action {
+        self.actions[usize::from(signal.0)]
+    }
+
+    /// Takes the lowest pending signal that isn't ignored, dropping ignored ones on the way.
+    fn take(&mut self) -> Option<(Signal, Action)> {
+        while self.pending != 0 {
+            let signal = Signal(self.pending.trailing_zeros() as u8);
+            self.pending &= !signal.bit();
+
+            match self.action(signal) {
+                Action::Ignore if signal.is_catchable() => continue,
+                Action::Ignore => return Some((signal, Action::Default)),
+                action => return Some((signal, action)),
+            }
+        }
+
+        None
+    }
+}
+
+/// Makes `signal` pending for `pid` and wakes it up if it is blocked, so the signal gets delivered.
+pub fn send(pid: Pid, signal: Signal) -> Result<(), SignalError> {
+    let state = with_process(pid, |process| {
+        if !matches!(process.state, State::Zombie { .. }) {
+            process.signals.pending |= signal.bit();
+        }
+        process.state
+    })
+    .ok_or(SignalError::NoSuchProcess)?;
+
+    match state {
+        State::Zombie { .. } => Err(SignalError::NoSuchProcess),
+        State::Blocked => {
+            wake(pid);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Sets the action of `signal` for `pid`, returning the previous one.
+pub fn set_action(pid: Pid, signal: Signal, action: Action) -> Result<Action, SignalError> {
+    if !signal.is_catchable() {
+        return Err(SignalError::Uncatchable);
+    }
+
+    with_process(pid, |process| {
+        core::mem::replace(&mut process.signals.actions[usize::from(signal.0)], action)
+    })
+    .ok_or(SignalError::NoSuchProcess)
+}
+
+/// Whether the current process has signals to deliver.
+pub fn has_pending() -> bool {
+    current()
+        .and_then(|pid| with_process(pid, |process| process.signals.pending != 0))
+        .unwrap_or(false)
+}
+
+/// Terminates the current process because of `signal`.
+pub fn terminate_current(signal: Signal) -> ! {
+    if let Some(pid) = current() {
+        klog!(Warning, "process {} killed by signal {}", pid, signal.0);
+    }
+
+    exit_current(128 + i32::from(signal.0));
+    schedule();
+}
+
+/// Delivers the pending signals of the current process, which is about to return to ring 3 with the
+/// registers in `context`. Returns the registers to return with instead, which enter a handler if there
+/// is one; doesn't return if a signal terminates the process.
+pub fn deliver(mut context: Context) -> Context {
+    let Some(pid) = current() else {
+        return context;
+    };
+
+    while let Some((signal, action)) = with_process(pid, |process| process.signals.take()).flatten()
+    {
+        let Action::Handler { handler, restorer } = action else {
+            terminate_current(signal);
+        };
+
+        match enter_handler(&context, signal, handler, restorer) {
+            Some(handler_context) => context = handler_context,
+            None => terminate_current(Signal::SEGV),
+        }
+    }
+
+    context
+}
+
+/// Saves `context` on the user stack and returns the registers that run `handler`. Returns `None` if the
+/// stack can't hold the saved registers.
+fn enter_handler(
+    context: &Context,
+    signal: Signal,
+    handler: u64,
+    restorer: u64,
+) -> Option<Context> {
+    let saved = context
+        .rsp
+        .checked_sub(RED_ZONE + size_of::<Context>() as u64)?
+        & !0xF;
+    let return_address = saved - 8;
+
+    syscall::write_user(saved, *context).ok()?;
+    syscall::write_user(return_address, restorer).ok()?;
+
+    let mut handler_context = Context::start(
+        VirtAddr::try_new(handler).ok()?,
+        VirtAddr::new(return_address),
+    );
+    handler_context.rdi = signal.as_u64();
+    Some(handler_context)
+}
+
+/// The registers saved by `enter_handler` at `stack`, where the restorer calls `sigreturn`, made safe to
+/// return to: privileged flags are dropped and the instruction pointer must be canonical.
+pub fn saved_context(stack: u64) -> Option<Context> {
+    const USER_FLAGS: RFlags = RFlags::CARRY_FLAG
+        .union(RFlags::PARITY_FLAG)
+        .union(RFlags::AUXILIARY_CARRY_FLAG)
+        .union(RFlags::ZERO_FLAG)
+        .union(RFlags::SIGN_FLAG)
+        .union(RFlags::DIRECTION_FLAG)
+        .union(RFlags::OVERFLOW_FLAG)
+        .union(RFlags::ALIGNMENT_CHECK)
+        .union(RFlags::ID);
+
+    let mut context: Context = syscall::read_user(stack).ok()?;
+    VirtAddr::try_new(context.rip).ok()?;
+
+    // Interrupts stay enabled, and bit 1 is reserved and always set.
+    context.rflags = (context.rflags & USER_FLAGS.bits()) | RFlags::INTERRUPT_FLAG.bits() | 0x2;
+    Some(context)
+}
+
+#[test_case]
+fn test_ignored_signals_are_dropped_except_sigkill() {
+    let mut signals = Signals::default();
+    signals.actions[SIGSEGV as usize] = Action::Ignore;
+    signals.actions[SIGKILL as usize] = Action::Ignore;
+    signals.pending = Signal::SEGV.bit() | Signal::KILL.bit();
+
+    assert_eq!(signals.take(), Some((Signal::KILL, Action::Default)));
+    assert_eq!(signals.take(), None);
+}
+
+#[test_case]
+fn test_signals_are_taken_lowest_first() {
+    let handler = Action::Handler {
+        handler: 0x1000,
+        restorer: 0x2000,
+    };
+    let mut signals = Signals::default();
+    signals.actions[SIGILL as usize] = handler;
+    signals.pending = Signal::SEGV.bit() | Signal(SIGILL as u8).bit();
+
+    assert_eq!(signals.take(), Some((Signal(SIGILL as u8), handler)));
+    assert_eq!(signals.take(), Some((Signal::SEGV, Action::Default)));
+    assert_eq!(signals.fork().pending, 0);
+}
@@ -0,0 +1,64 @@
+//! Sleeping processes, parked on a timer wheel until their deadline tick.
+//!
+//! The wheel is advanced on timer ticks that interrupt user code, see `sched::on_tick`, and by `schedule`
+//! whenever a tick wakes up the idle CPU. Those are the places where no kernel code can be holding the
+//! process table, which waking a process needs. A sleeper resumes as if its syscall returned 0, unless
+//! something else wakes it first, e.g. a signal, in which case it gets the registers it blocked with.
+
+use spin::Mutex;
+
+use super::{Pid, State, current, wake, with_process};
+use crate::time::{self, wheel::TimerWheel};
+
+static SLEEPERS: Mutex<TimerWheel<Pid>> = Mutex::new(TimerWheel::new(0));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepError {
+    NoProcess,
+}
+
+/// Parks the current process until the tick `deadline`. The caller blocks it next, without enabling
+/// interrupts in between.
+pub fn add_sleeper(deadline: u64) -> Result<(), SleepError> {
+    let pid = current().ok_or(SleepError::NoProcess)?;
+
+    with_process(pid, |process| process.sleeping_until = Some(deadline))
+        .ok_or(SleepError::NoProcess)?;
+    SLEEPERS.lock().insert(deadline, pid);
+    Ok(())
+}
+
+/// Number of processes on the wheel.
+pub fn sleeper_count() -> usize {
+    SLEEPERS.lock().len()
+}
+
+/// Takes `pid` off the wheel, once it was woken before its deadline or exited. Deadlines that never
+/// come, like those of sleeps that saturated, would keep it there for good otherwise.
+pub(super) fn remove_sleeper(pid: Pid) {
+    SLEEPERS.lock().remove(|&sleeper| sleeper == pid);
+}
+
+/// Wakes the sleepers whose deadline has passed.
+pub(super) fn wake_sleepers() {
+    let now = time::ticks();
+
+    SLEEPERS.lock().expire(now, |pid| {
+        // The process may have been woken early and be blocked on something else by now.
+        let due = with_process(pid, |process| {
+            let due = process.state == State::Blocked
+                && process
+                    .sleeping_until
+                    .is_some_and(|deadline| deadline <= now);
+            if due {
+                process.sleeping_until = None;
+                process.context.rax = 0;
+            }
+            due
+        });
+
+        if due == Some(true) {
+            wake(pid);
+        }
+    });
+}
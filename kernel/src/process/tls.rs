@@ -0,0 +1,122 @@
+//! FS and GS bases of user processes, which thread-local storage is built on.
+//!
+//! Compilers address thread-local variables relative to the FS base (and some runtimes use GS), so every
+//! process has its own pair of bases, set with the `arch_prctl` syscall. When the CPU supports FSGSBASE,
+//! user code may also change them itself with `wrfsbase`/`wrgsbase`, so the bases in the MSRs are the
+//! authoritative ones while a process is on the CPU: they are saved into the process when the CPU switches
+//! to another one, and loaded from it when it is switched back to.
+//!
+//! The kernel doesn't use either base itself. The syscall entry's `swapgs` goes through
+//! IA32_KERNEL_GS_BASE, which user code can't change.
+
+use x86_64::{
+    VirtAddr,
+    registers::{
+        control::{Cr4, Cr4Flags},
+        model_specific::{FsBase, GsBase},
+    },
+};
+
+use super::{current, with_process};
+use crate::{cpu, memory::address_space::USER_END};
+
+/// CPUID leaf 7 EBX bit for the FSGSBASE instructions.
+const CPUID_FSGSBASE: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    Fs,
+    Gs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsError {
+    NoProcess,
+    /// The base isn't an address in the user part of the address space.
+    InvalidBase,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SegmentBases {
+    pub fs: u64,
+    pub gs: u64,
+}
+
+impl SegmentBases {
+    /// The bases on the CPU.
+    pub fn read() -> Self {
+        SegmentBases {
+            fs: FsBase::read().as_u64(),
+            gs: GsBase::read().as_u64(),
+        }
+    }
+
+    /// Puts the bases on the CPU.
+    pub fn load(&self) {
+        FsBase::write(VirtAddr::new_truncate(self.fs));
+        GsBase::write(VirtAddr::new_truncate(self.gs));
+    }
+
+    pub fn get(&self, segment: Segment) -> u64 {
+        match segment {
+            Segment::Fs => self.fs,
+            Segment::Gs => self.gs,
+        }
+    }
+
+    fn set(&mut self, segment: Segment, base: u64) {
+        match segment {
+            Segment::Fs => self.fs = base,
+            Segment::Gs => self.gs = base,
+        }
+    }
+}
+
+/// Lets user code read and write the bases itself when the CPU supports it.
+pub fn init() {
+    if cpu::cpuid(0, 0).eax >= 7 && cpu::cpuid(7, 0).ebx & CPUID_FSGSBASE != 0 {
+        unsafe { Cr4::update(|cr4| cr4.insert(Cr4Flags::FSGSBASE)) };
+    }
+}
+
+/// Whether user code can use `rdfsbase`, `wrfsbase` and their GS counterparts.
+pub fn has_fsgsbase() -> bool {
+    Cr4::read().contains(Cr4Flags::FSGSBASE)
+}
+
+/// Sets a base of the current process.
+pub fn set(segment: Segment, base: u64) -> Result<(), TlsError> {
+    let pid = current().ok_or(TlsError::NoProcess)?;
+    if VirtAddr::try_new(base).is_err() || base > USER_END.as_u64() {
+        return Err(TlsError::InvalidBase);
+    }
+
+    with_process(pid, |process| {
+        // The other base may have been changed from user mode since it was saved.
+        let mut bases = SegmentBases::read();
+        bases.set(segment, base);
+        process.segment_bases = bases;
+        bases.load();
+    })
+    .ok_or(TlsError::NoProcess)
+}
+
+/// A base of the current process.
+pub fn get(segment: Segment) -> Result<u64, TlsError> {
+    current().ok_or(TlsError::NoProcess)?;
+    Ok(SegmentBases::read().get(segment))
+}
+
+#[test_case]
+fn test_bases_are_loaded_and_read_back() {
+    let saved = SegmentBases::read();
+
+    let bases = SegmentBases {
+        fs: 0x7000_0000,
+        gs: 0x7000_1000,
+    };
+    bases.load();
+    assert_eq!(SegmentBases::read(), bases);
+
+    saved.load();
+}
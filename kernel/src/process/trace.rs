@@ -0,0 +1,348 @@
+//! Instruction-level tracing of user processes.
+//!
+//! A traced process runs with the trap flag set, so the CPU raises #DB after every instruction it
+//! executes. Each instruction inside the traced RIP range is recorded into a ring with the registers it
+//! changed, to be read back with `read` like the kernel log. Instructions outside the range trap too but
+//! aren't recorded, so a traced process is slow as a whole.
+//!
+//! The trap flag is masked while the kernel handles a syscall and only takes effect again one instruction
+//! after returning, so a `syscall` and the instruction after it are recorded as one step.
+
+use core::{fmt, ops::Range};
+use spin::Mutex;
+use x86_64::{VirtAddr, instructions::interrupts, structures::idt::InterruptStackFrame};
+
+use super::{Context, Pid, current, with_process};
+use crate::interrupts::debug::GeneralRegisters;
+
+pub const CAPACITY: usize = 256;
+/// Registers recorded per step. Few instructions change more; their steps are marked as truncated.
+pub const MAX_CHANGES: usize = 6;
+
+static TRACE: Mutex<Ring> = Mutex::new(Ring {
+    steps: [Step::EMPTY; CAPACITY],
+    next_sequence: 0,
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Rax,
+    Rbx,
+    Rcx,
+    Rdx,
+    Rsi,
+    Rdi,
+    Rbp,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+    Rsp,
+    Rflags,
+}
+
+impl Register {
+    /// Every register except RIP, which is recorded on its own.
+    pub const ALL: [Register; 17] = [
+        Register::Rax,
+        Register::Rbx,
+        Register::Rcx,
+        Register::Rdx,
+        Register::Rsi,
+        Register::Rdi,
+        Register::Rbp,
+        Register::R8,
+        Register::R9,
+        Register::R10,
+        Register::R11,
+        Register::R12,
+        Register::R13,
+        Register::R14,
+        Register::R15,
+        Register::Rsp,
+        Register::Rflags,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Register::Rax => "rax",
+            Register::Rbx => "rbx",
+            Register::Rcx => "rcx",
+            Register::Rdx => "rdx",
+            Register::Rsi => "rsi",
+            Register::Rdi => "rdi",
+            Register::Rbp => "rbp",
+            Register::R8 => "r8",
+            Register::R9 => "r9",
+            Register::R10 => "r10",
+            Register::R11 => "r11",
+            Register::R12 => "r12",
+            Register::R13 => "r13",
+            Register::R14 => "r14",
+            Register::R15 => "r15",
+            Register::Rsp => "rsp",
+            Register::Rflags => "rflags",
+        }
+    }
+
+    fn value(self, context: &Context) -> u64 {
+        match self {
+            Register::Rax => context.rax,
+            Register::Rbx => context.rbx,
+            Register::Rcx => context.rcx,
+            Register::Rdx => context.rdx,
+            Register::Rsi => context.rsi,
+            Register::Rdi => context.rdi,
+            Register::Rbp => context.rbp,
+            Register::R8 => context.r8,
+            Register::R9 => context.r9,
+            Register::R10 => context.r10,
+            Register::R11 => context.r11,
+            Register::R12 => context.r12,
+            Register::R13 => context.r13,
+            Register::R14 => context.r14,
+            Register::R15 => context.r15,
+            Register::Rsp => context.rsp,
+            Register::Rflags => context.rflags,
+        }
+    }
+}
+
+/// One traced instruction.
+#[derive(Debug, Clone, Copy)]
+pub struct Step {
+    sequence: u64,
+    pid: Pid,
+    rip: u64,
+    changes: [(Register, u64); MAX_CHANGES],
+    len: usize,
+    truncated: bool,
+}
+
+impl Step {
+    const EMPTY: Step = Step {
+        sequence: 0,
+        pid: Pid(0),
+        rip: 0,
+        changes: [(Register::Rax, 0); MAX_CHANGES],
+        len: 0,
+        truncated: false,
+    };
+
+    /// The instruction at `before.rip`, which left the registers in `after`.
+    pub fn new(pid: Pid, before: &Context, after: &Context) -> Self {
+        let mut step = Step {
+            pid,
+            rip: before.rip,
+            ..Step::EMPTY
+        };
+
+        for register in Register::ALL {
+            let value = register.value(after);
+            if value == register.value(before) {
+                continue;
+            }
+
+            if step.len == MAX_CHANGES {
+                step.truncated = true;
+                break;
+            }
+            step.changes[step.len] = (register, value);
+            step.len += 1;
+        }
+
+        step
+    }
+
+    /// Position of the step in the trace, counting from 0 since boot.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// Address of the instruction.
+    pub fn rip(&self) -> u64 {
+        self.rip
+    }
+
+    /// Registers the instruction changed, with their new values.
+    pub fn changes(&self) -> &[(Register, u64)] {
+        &self.changes[..self.len]
+    }
+
+    /// Whether the instruction changed more registers than were recorded.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+/// `pid 3 0x401000: rax=0x1 rflags=0x346`.
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pid {} {:#x}:", self.pid, self.rip)?;
+
+        for (register, value) in self.changes() {
+            write!(f, " {}={:#x}", register.name(), value)?;
+        }
+        if self.truncated {
+            write!(f, " ...")?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Ring {
+    steps: [Step; CAPACITY],
+    next_sequence: u64,
+}
+
+/// Tracing state of a process.
+#[derive(Debug, Clone)]
+pub struct Trace {
+    range: Range<u64>,
+    /// Registers at the previous trap, before the instruction that caused the current one.
+    last: Option<Context>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceError {
+    NoSuchProcess,
+    /// Only a process itself and its parent may trace it.
+    NotPermitted,
+}
+
+/// Starts tracing the instructions of `pid` in `range`, on behalf of `tracer`, or changes the range if
+/// it is already traced. The process runs with the trap flag from the next time it enters ring 3.
+pub fn start(tracer: Pid, pid: Pid, range: Range<VirtAddr>) -> Result<(), TraceError> {
+    with_process(pid, |process| {
+        if pid != tracer && process.parent != Some(tracer) {
+            return Err(TraceError::NotPermitted);
+        }
+
+        process.trace = Some(Trace {
+            range: range.start.as_u64()..range.end.as_u64(),
+            last: None,
+        });
+        Ok(())
+    })
+    .unwrap_or(Err(TraceError::NoSuchProcess))
+}
+
+/// Stops tracing `pid`. Its recorded steps stay in the ring.
+pub fn stop(tracer: Pid, pid: Pid) -> Result<(), TraceError> {
+    with_process(pid, |process| {
+        if pid != tracer && process.parent != Some(tracer) {
+            return Err(TraceError::NotPermitted);
+        }
+
+        process.trace = None;
+        Ok(())
+    })
+    .unwrap_or(Err(TraceError::NoSuchProcess))
+}
+
+/// Handles a single-step trap from ring 3, recording the instruction that caused it if the current process
+/// is traced and the instruction is in its range. Returns whether the process is traced, in which case
+/// the trap flag must stay set.
+pub(crate) fn on_single_step(
+    registers: &GeneralRegisters,
+    stack_frame: &InterruptStackFrame,
+) -> bool {
+    let Some(pid) = current() else {
+        return false;
+    };
+
+    let now = Context::interrupted(registers, stack_frame);
+
+    with_process(pid, |process| {
+        let Some(trace) = process.trace.as_mut() else {
+            return false;
+        };
+
+        if let Some(last) = trace.last
+            && trace.range.contains(&last.rip)
+        {
+            record(Step::new(pid, &last, &now));
+        }
+        trace.last = Some(now);
+        true
+    })
+    .unwrap_or(false)
+}
+
+fn record(mut step: Step) {
+    interrupts::without_interrupts(|| {
+        let mut ring = TRACE.lock();
+        step.sequence = ring.next_sequence;
+        ring.steps[step.sequence as usize % CAPACITY] = step;
+        ring.next_sequence += 1;
+    });
+}
+
+/// Calls `f` on every step still in the ring with a sequence number of at least `since`, oldest first.
+/// Returns the sequence number the next step will get, to continue from later.
+pub fn read(since: u64, mut f: impl FnMut(&Step)) -> u64 {
+    interrupts::without_interrupts(|| {
+        let ring = TRACE.lock();
+
+        let oldest = ring.next_sequence.saturating_sub(CAPACITY as u64);
+        for sequence in since.max(oldest)..ring.next_sequence {
+            f(&ring.steps[sequence as usize % CAPACITY]);
+        }
+
+        ring.next_sequence
+    })
+}
+
+#[test_case]
+fn test_steps_record_changed_registers() {
+    let before = Context {
+        rip: 0x1000,
+        rax: 1,
+        rsp: 0x8000,
+        ..Context::default()
+    };
+    let after = Context {
+        rip: 0x1003,
+        rax: 2,
+        rsp: 0x7FF8,
+        ..before
+    };
+
+    let step = Step::new(Pid(1), &before, &after);
+
+    assert_eq!(step.rip(), 0x1000);
+    assert_eq!(
+        step.changes(),
+        &[(Register::Rax, 2), (Register::Rsp, 0x7FF8)]
+    );
+    assert!(!step.is_truncated());
+}
+
+#[test_case]
+fn test_steps_changing_many_registers_are_truncated() {
+    let before = Context::default();
+    let after = Context {
+        rax: 1,
+        rbx: 1,
+        rcx: 1,
+        rdx: 1,
+        rsi: 1,
+        rdi: 1,
+        rbp: 1,
+        ..before
+    };
+
+    let step = Step::new(Pid(1), &before, &after);
+
+    assert_eq!(step.changes().len(), MAX_CHANGES);
+    assert!(step.is_truncated());
+}
@@ -0,0 +1,368 @@
+//! The i8042 PS/2 controller, and the devices plugged into its two ports.
+//!
+//! Firmware may leave the controller in any state, so `DRIVER` sets it up from scratch: it disables both
+//! ports, runs the controller's and the ports' self-tests, resets the devices and only then enables the
+//! interrupts of the ports that work. Until then, the keyboard interrupt handler still works, but only
+//! reads a byte when the controller has one from the first port.
+//!
+//! `KeyDecoder` reads scancode set 1. The keyboard is switched to set 2, which the controller translates
+//! into set 1. A keyboard that refuses is asked which set it speaks, and translation is turned off if
+//! that is set 1 already.
+//!
+//! A mouse on the second port is set up by `DRIVER` too, and read by `mouse::run_mouse`.
+//!
+//! PS/2 has no signal for a device being unplugged, so `watch_hotplug` sends the keyboard an echo every
+//! `HOTPLUG_PERIOD`. A keyboard that stops answering is taken as unplugged, and one that answers again
+//! as plugged back in, which gets scanning enabled again.
+
+use conquer_once::spin::OnceCell;
+use core::{
+    sync::atomic::{AtomicBool, AtomicU16, Ordering},
+    time::Duration,
+};
+use x86_64::instructions::port::Port;
+
+use crate::{
+    driver::{Bus, Busy, Claims, Driver, Priority, ProbeError, Resource},
+    println,
+    task::timer,
+    time::pit,
+};
+
+pub mod mouse;
+
+const DATA_PORT: u16 = 0x60;
+/// The status register when read, the command register when written.
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// The byte in the output buffer came from the second port.
+const STATUS_SECOND_PORT: u8 = 1 << 5;
+
+const CONTROLLER_READ_CONFIG: u8 = 0x20;
+const CONTROLLER_WRITE_CONFIG: u8 = 0x60;
+const CONTROLLER_DISABLE_SECOND: u8 = 0xa7;
+const CONTROLLER_ENABLE_SECOND: u8 = 0xa8;
+const CONTROLLER_TEST_SECOND: u8 = 0xa9;
+const CONTROLLER_SELF_TEST: u8 = 0xaa;
+const CONTROLLER_TEST_FIRST: u8 = 0xab;
+const CONTROLLER_DISABLE_FIRST: u8 = 0xad;
+const CONTROLLER_ENABLE_FIRST: u8 = 0xae;
+/// Sends the next byte written to the data port to the second port.
+const CONTROLLER_WRITE_SECOND: u8 = 0xd4;
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+const CONFIG_FIRST_INTERRUPT: u8 = 1 << 0;
+const CONFIG_SECOND_INTERRUPT: u8 = 1 << 1;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const DEVICE_SCANCODE_SET: u8 = 0xf0;
+const DEVICE_ENABLE_SCANNING: u8 = 0xf4;
+const DEVICE_ECHO: u8 = 0xee;
+const DEVICE_RESET: u8 = 0xff;
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+const RESET_PASSED: u8 = 0xaa;
+/// The first byte of the reply to a scancode set query, when translated from set 2.
+const TRANSLATED_SET_1: u8 = 0x43;
+
+/// How often `watch_hotplug` checks for the keyboard.
+pub const HOTPLUG_PERIOD: Duration = Duration::from_secs(1);
+/// How long a device is waited for, in units of 100us.
+const TIMEOUT: u32 = 10_000;
+
+/// Set while a task waits for the keyboard to answer a command, so that the interrupt handler hands its
+/// answer over instead of taking it for a scancode.
+static AWAITING_REPLY: AtomicBool = AtomicBool::new(false);
+/// The answer, with bit 8 set once there is one.
+static REPLY: AtomicU16 = AtomicU16::new(0);
+static KEYBOARD_CONNECTED: AtomicBool = AtomicBool::new(false);
+static CONTROLLER: OnceCell<Controller> = OnceCell::uninit();
+
+/// The keyboard's IRQ line, whose handler is in `interrupts`.
+const KEYBOARD_IRQ: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// Nothing answers at the controller's ports.
+    NoController,
+    /// The controller's self-test failed with this result.
+    SelfTestFailed(u8),
+    /// The controller or a device didn't answer in time.
+    Timeout,
+    /// The device answered a command with this instead of an acknowledgement.
+    Rejected(u8),
+    /// Another driver holds the ports or the keyboard's IRQ line.
+    Busy(Busy),
+}
+
+impl From<Busy> for Ps2Error {
+    fn from(error: Busy) -> Self {
+        Ps2Error::Busy(error)
+    }
+}
+
+/// What `DRIVER` found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Controller {
+    /// Whether the controller has a second port, for a mouse.
+    pub dual_channel: bool,
+    pub keyboard: bool,
+    /// Whether a mouse was set up on the second port.
+    pub mouse: bool,
+    pub scroll_wheel: bool,
+    /// Whether the controller translates scancode set 2 into set 1.
+    pub translation: bool,
+}
+
+fn status() -> u8 {
+    unsafe { Port::<u8>::new(COMMAND_PORT).read() }
+}
+
+fn read_data() -> u8 {
+    unsafe { Port::<u8>::new(DATA_PORT).read() }
+}
+
+/// Waits with `pit` until `done` returns true.
+fn wait(done: impl Fn() -> bool) -> Result<(), Ps2Error> {
+    for _ in 0..TIMEOUT {
+        if done() {
+            return Ok(());
+        }
+        pit::busy_wait_micros(100);
+    }
+    Err(Ps2Error::Timeout)
+}
+
+fn write_command(command: u8) -> Result<(), Ps2Error> {
+    wait(|| status() & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(COMMAND_PORT).write(command) };
+    Ok(())
+}
+
+fn write_data(byte: u8) -> Result<(), Ps2Error> {
+    wait(|| status() & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(DATA_PORT).write(byte) };
+    Ok(())
+}
+
+fn read_polled() -> Result<u8, Ps2Error> {
+    wait(|| status() & STATUS_OUTPUT_FULL != 0)?;
+    Ok(read_data())
+}
+
+/// Sends `command` to the controller, and returns its answer.
+fn controller_query(command: u8) -> Result<u8, Ps2Error> {
+    write_command(command)?;
+    read_polled()
+}
+
+/// Sends `byte` to the device on the first or second port and waits for its acknowledgement, while the
+/// port's interrupt is disabled. Resent up to three times when the device asks for it.
+fn send_polled(second: bool, byte: u8) -> Result<(), Ps2Error> {
+    for _ in 0..3 {
+        if second {
+            write_command(CONTROLLER_WRITE_SECOND)?;
+        }
+        write_data(byte)?;
+        match read_polled()? {
+            ACK => return Ok(()),
+            RESEND => continue,
+            other => return Err(Ps2Error::Rejected(other)),
+        }
+    }
+    Err(Ps2Error::Rejected(RESEND))
+}
+
+/// Resets the device on a port, and returns whether one passed its self-test.
+fn reset_device(second: bool) -> bool {
+    send_polled(second, DEVICE_RESET).is_ok() && read_polled() == Ok(RESET_PASSED)
+}
+
+/// Switches the keyboard to scancode set 2, and returns whether the controller must translate what it
+/// sends, i.e. whether it doesn't speak set 1 already.
+fn negotiate_scancode_set() -> Result<bool, Ps2Error> {
+    if send_polled(false, DEVICE_SCANCODE_SET).is_ok() && send_polled(false, 2).is_ok() {
+        return Ok(true);
+    }
+
+    // Ask which set it speaks instead. Translation is off while `init` runs, but some controllers
+    // translate the answer anyway.
+    send_polled(false, DEVICE_SCANCODE_SET)?;
+    send_polled(false, 0)?;
+    Ok(!matches!(read_polled()?, 1 | TRANSLATED_SET_1))
+}
+
+pub static DRIVER: Driver = Driver {
+    name: "ps2",
+    priority: Priority::Normal,
+    bus: Bus::Platform { probe },
+};
+
+fn probe(claims: &mut Claims) -> Result<(), ProbeError> {
+    let controller = init(claims).map_err(ProbeError::new)?;
+    println!("ps2: {:?}", controller);
+    CONTROLLER.init_once(|| controller);
+    Ok(())
+}
+
+/// What `DRIVER` found, if it set up the controller.
+pub fn controller() -> Option<Controller> {
+    CONTROLLER.get().copied()
+}
+
+/// Sets up the controller and its devices, and enables the interrupts of the ports that work.
+fn init(claims: &mut Claims) -> Result<Controller, Ps2Error> {
+    for port in [DATA_PORT, COMMAND_PORT] {
+        claims.claim(Resource::Io { port, len: 1 })?;
+    }
+    claims.claim(Resource::Irq(KEYBOARD_IRQ))?;
+    if status() == 0xff {
+        return Err(Ps2Error::NoController);
+    }
+
+    write_command(CONTROLLER_DISABLE_FIRST)?;
+    write_command(CONTROLLER_DISABLE_SECOND)?;
+    // Whatever arrived before is stale.
+    while status() & STATUS_OUTPUT_FULL != 0 {
+        read_data();
+    }
+
+    let mut config = controller_query(CONTROLLER_READ_CONFIG)?;
+    config &= !(CONFIG_FIRST_INTERRUPT | CONFIG_SECOND_INTERRUPT | CONFIG_TRANSLATION);
+    write_command(CONTROLLER_WRITE_CONFIG)?;
+    write_data(config)?;
+
+    match controller_query(CONTROLLER_SELF_TEST)? {
+        SELF_TEST_PASSED => {}
+        result => return Err(Ps2Error::SelfTestFailed(result)),
+    }
+    // The self-test may have reset the configuration.
+    write_command(CONTROLLER_WRITE_CONFIG)?;
+    write_data(config)?;
+
+    // The second port's clock only turns on when enabled if there is a second port.
+    write_command(CONTROLLER_ENABLE_SECOND)?;
+    let dual_channel =
+        controller_query(CONTROLLER_READ_CONFIG)? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+    write_command(CONTROLLER_DISABLE_SECOND)?;
+
+    let first_works = controller_query(CONTROLLER_TEST_FIRST)? == PORT_TEST_PASSED;
+    let second_works =
+        dual_channel && controller_query(CONTROLLER_TEST_SECOND)? == PORT_TEST_PASSED;
+
+    let mut controller = Controller {
+        dual_channel,
+        keyboard: false,
+        mouse: false,
+        scroll_wheel: false,
+        translation: false,
+    };
+    if first_works {
+        write_command(CONTROLLER_ENABLE_FIRST)?;
+        controller.keyboard = reset_device(false);
+    }
+    // A mouse is no use without its line.
+    if second_works && claims.claim_irq(mouse::IRQ) {
+        write_command(CONTROLLER_ENABLE_SECOND)?;
+        if reset_device(true)
+            && let Ok(scroll_wheel) = mouse::setup()
+        {
+            controller.mouse = true;
+            controller.scroll_wheel = scroll_wheel;
+            config |= CONFIG_SECOND_INTERRUPT;
+        }
+    }
+    if controller.keyboard {
+        controller.translation = negotiate_scancode_set()?;
+        send_polled(false, DEVICE_ENABLE_SCANNING)?;
+        config |= CONFIG_FIRST_INTERRUPT;
+    }
+    if controller.translation {
+        config |= CONFIG_TRANSLATION;
+    }
+    write_command(CONTROLLER_WRITE_CONFIG)?;
+    write_data(config)?;
+
+    KEYBOARD_CONNECTED.store(controller.keyboard, Ordering::Relaxed);
+    Ok(controller)
+}
+
+/// Called by the keyboard interrupt handler: reads the byte the keyboard sent, if there is one.
+pub(crate) fn on_keyboard_interrupt() {
+    let status = status();
+    if status & STATUS_OUTPUT_FULL == 0 || status & STATUS_SECOND_PORT != 0 {
+        return;
+    }
+    let byte = read_data();
+
+    if AWAITING_REPLY.load(Ordering::Acquire) && matches!(byte, ACK | RESEND | DEVICE_ECHO) {
+        REPLY.store(0x100 | u16::from(byte), Ordering::Release);
+        return;
+    }
+    crate::task::keyboard::add_scancode(byte);
+}
+
+/// Sends `byte` to the keyboard once its interrupt is enabled, and waits for an answer, which the
+/// interrupt handler hands over.
+async fn send(byte: u8) -> Result<u8, Ps2Error> {
+    REPLY.store(0, Ordering::Relaxed);
+    AWAITING_REPLY.store(true, Ordering::Release);
+    let result = match write_data(byte) {
+        Ok(()) => {
+            let reply = timer::timeout(Duration::from_millis(100), async {
+                loop {
+                    let reply = REPLY.load(Ordering::Acquire);
+                    if reply != 0 {
+                        return reply as u8;
+                    }
+                    timer::sleep(Duration::from_millis(1)).await;
+                }
+            });
+            reply.await.map_err(|_| Ps2Error::Timeout)
+        }
+        Err(error) => Err(error),
+    };
+    AWAITING_REPLY.store(false, Ordering::Release);
+    result
+}
+
+pub fn keyboard_connected() -> bool {
+    KEYBOARD_CONNECTED.load(Ordering::Relaxed)
+}
+
+/// Checks every `HOTPLUG_PERIOD` whether the keyboard is still there, and sets up a keyboard that was
+/// plugged back in.
+pub async fn watch_hotplug() {
+    let mut interval = timer::interval(HOTPLUG_PERIOD);
+
+    loop {
+        interval.tick().await;
+        let answers = send(DEVICE_ECHO).await == Ok(DEVICE_ECHO);
+
+        match (keyboard_connected(), answers) {
+            (true, false) => {
+                KEYBOARD_CONNECTED.store(false, Ordering::Relaxed);
+                println!("ps2: keyboard disconnected");
+            }
+            (false, true) => {
+                // A keyboard comes back with its defaults: set 2, which the controller still
+                // translates, and scanning enabled, which doesn't hurt to make sure of.
+                let enabled = send(DEVICE_ENABLE_SCANNING).await == Ok(ACK);
+                KEYBOARD_CONNECTED.store(true, Ordering::Relaxed);
+                println!(
+                    "ps2: keyboard connected{}",
+                    if enabled {
+                        ""
+                    } else {
+                        ", but scanning not enabled"
+                    }
+                );
+            }
+            _ => {}
+        }
+    }
+}
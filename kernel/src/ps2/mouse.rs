@@ -0,0 +1,170 @@
+//! The mouse on the second port, with or without a scroll wheel.
+//!
+//! The mouse sends packets of three bytes, or four with a scroll wheel, which the interrupt handler
+//! queues as they come and `run_mouse` puts back together. Bytes lost to a full queue leave the packets
+//! out of step, which `PacketDecoder` notices from the bit every first byte has set.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use futures_util::stream::StreamExt;
+
+use super::{
+    DEVICE_ENABLE_SCANNING, Ps2Error, STATUS_OUTPUT_FULL, STATUS_SECOND_PORT, read_data,
+    read_polled, send_polled, status,
+};
+use crate::{
+    input::{self, DeviceKind, MouseReport},
+    interrupts,
+    queue::QueueId,
+    task::irq_stream::IrqStream,
+};
+
+/// The PIC line the second port interrupts on.
+pub const IRQ: u8 = 12;
+
+const SET_DEFAULTS: u8 = 0xf6;
+const SET_SAMPLE_RATE: u8 = 0xf3;
+const GET_ID: u8 = 0xf2;
+/// The ID of a mouse that switched to four byte packets for its wheel.
+const ID_SCROLL_WHEEL: u8 = 3;
+/// The sample rates that, set in this order, make a mouse with a wheel switch to four byte packets.
+const SCROLL_WHEEL_KNOCK: [u8; 3] = [200, 100, 80];
+
+/// Set in every first byte of a packet.
+const ALWAYS_SET: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+static BYTES: IrqStream<u8> = IrqStream::new("mouse bytes", QueueId::MouseBytes);
+static SCROLL_WHEEL: AtomicBool = AtomicBool::new(false);
+
+/// Sets up the mouse, its interrupt still disabled by the controller, and returns whether it has a
+/// scroll wheel.
+pub(super) fn setup() -> Result<bool, Ps2Error> {
+    // The ID the mouse sends after its reset.
+    while status() & STATUS_OUTPUT_FULL != 0 {
+        read_data();
+    }
+
+    send_polled(true, SET_DEFAULTS)?;
+    for rate in SCROLL_WHEEL_KNOCK {
+        send_polled(true, SET_SAMPLE_RATE)?;
+        send_polled(true, rate)?;
+    }
+    send_polled(true, GET_ID)?;
+    let scroll_wheel = read_polled()? == ID_SCROLL_WHEEL;
+    send_polled(true, DEVICE_ENABLE_SCANNING)?;
+
+    SCROLL_WHEEL.store(scroll_wheel, Ordering::Relaxed);
+    Ok(scroll_wheel)
+}
+
+fn on_interrupt() {
+    let status = status();
+    if status & STATUS_OUTPUT_FULL == 0 || status & STATUS_SECOND_PORT == 0 {
+        return;
+    }
+    // A packet missing a byte is dropped by the decoder anyway.
+    let _ = BYTES.push(read_data());
+}
+
+/// Puts packets back together from their bytes.
+pub struct PacketDecoder {
+    bytes: [u8; 4],
+    received: usize,
+    scroll_wheel: bool,
+}
+
+impl PacketDecoder {
+    pub const fn new(scroll_wheel: bool) -> Self {
+        PacketDecoder {
+            bytes: [0; 4],
+            received: 0,
+            scroll_wheel,
+        }
+    }
+
+    /// Returns the packet completed by `byte`, if any.
+    pub fn push(&mut self, byte: u8) -> Option<MouseReport> {
+        if self.received == 0 && byte & ALWAYS_SET == 0 {
+            // Out of step: this can't be a first byte.
+            return None;
+        }
+        self.bytes[self.received] = byte;
+        self.received += 1;
+
+        let len = if self.scroll_wheel { 4 } else { 3 };
+        if self.received < len {
+            return None;
+        }
+        self.received = 0;
+
+        let [flags, x, y, z] = self.bytes;
+        let movement = |value: u8, negative: bool, overflow: bool| match overflow {
+            true => 0,
+            false => i32::from(value) - if negative { 256 } else { 0 },
+        };
+        Some(MouseReport {
+            dx: movement(x, flags & X_SIGN != 0, flags & X_OVERFLOW != 0),
+            dy: -movement(y, flags & Y_SIGN != 0, flags & Y_OVERFLOW != 0),
+            buttons: flags & 0b111,
+            // The wheel's movement is in the low four bits, as a signed number.
+            scroll: match self.scroll_wheel {
+                true => i32::from(((z << 4) as i8) >> 4),
+                false => 0,
+            },
+        })
+    }
+}
+
+/// The mouse driver: reads the packets and emits them as input events.
+pub async fn run_mouse() {
+    let mut bytes = BYTES.take().expect("mouse bytes already taken");
+    interrupts::set_irq_handler(IRQ, on_interrupt).expect("IRQ 12 already in use");
+    interrupts::unmask_irq(IRQ);
+
+    let device = input::register_device("PS/2 mouse", DeviceKind::Mouse);
+    let mut decoder = PacketDecoder::new(SCROLL_WHEEL.load(Ordering::Relaxed));
+    let mut buttons = 0;
+
+    while let Some(byte) = bytes.next().await {
+        if let Some(report) = decoder.push(byte) {
+            input::emit_mouse(device, buttons, report);
+            buttons = report.buttons;
+        }
+    }
+}
+
+#[test_case]
+fn test_packets_are_decoded() {
+    let mut decoder = PacketDecoder::new(false);
+    // Left button held, moving right by 5 and down by 3, with a stray byte before.
+    let packet = [0x02, 0x29, 0x05, 0xfd].map(|byte| decoder.push(byte));
+    assert_eq!(
+        packet,
+        [
+            None,
+            None,
+            None,
+            Some(MouseReport {
+                dx: 5,
+                dy: 3,
+                buttons: 1,
+                scroll: 0,
+            }),
+        ]
+    );
+
+    let mut decoder = PacketDecoder::new(true);
+    let packet = [0x18, 0xff, 0x00, 0x0f].map(|byte| decoder.push(byte));
+    assert_eq!(
+        packet[3],
+        Some(MouseReport {
+            dx: -1,
+            dy: 0,
+            buttons: 0,
+            scroll: -1,
+        })
+    );
+}
@@ -0,0 +1,267 @@
+//! Bounded kernel queues, sized and accounted for in one place.
+//!
+//! Every fixed-size queue in the kernel has an entry in a central registry, which holds its capacity,
+//! what happens when it is full and how often that happened. A full queue is then visible in `stats`
+//! instead of silently losing input.
+//!
+//! Capacities are read when a queue is created, so changing one only affects queues created later.
+//! Overflow behavior can be changed at any time.
+
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicU8, AtomicU64, AtomicUsize, Ordering};
+use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// What a push does when the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Overflow {
+    /// Discards the oldest entry to make room.
+    DropOldest,
+    /// Rejects the new entry.
+    DropNewest,
+    /// Keeps the new entry in a growable overflow buffer. Pushing with interrupts disabled can't
+    /// allocate, since the interrupted code might hold the allocator lock. In that case only the buffer's
+    /// spare capacity is used, and the entry is dropped once that runs out.
+    Grow,
+}
+
+impl Overflow {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Overflow::DropOldest,
+            1 => Overflow::DropNewest,
+            _ => Overflow::Grow,
+        }
+    }
+}
+
+/// The registered queues.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueId {
+    /// Keyboard scancodes, pushed by the keyboard interrupt handler.
+    Scancodes,
+    /// Executor tasks ready to be polled.
+    Tasks,
+    /// Work deferred by interrupt handlers.
+    DeferredWork,
+    /// Bytes received on COM1, pushed by its interrupt handler.
+    SerialInput,
+    /// Bytes from the PS/2 mouse, pushed by its interrupt handler.
+    MouseBytes,
+}
+
+impl QueueId {
+    pub const ALL: [QueueId; 5] = [
+        QueueId::Scancodes,
+        QueueId::Tasks,
+        QueueId::DeferredWork,
+        QueueId::SerialInput,
+        QueueId::MouseBytes,
+    ];
+
+    fn entry(self) -> &'static Entry {
+        &REGISTRY[self as usize]
+    }
+}
+
+struct Entry {
+    name: &'static str,
+    /// Whether entries can't be dropped without breaking the consumer, leaving `Overflow::Grow` as the
+    /// only option.
+    lossless: bool,
+    capacity: AtomicUsize,
+    overflow: AtomicU8,
+    /// Pushes that found the queue full.
+    overflows: AtomicU64,
+    /// Entries lost to overflows.
+    dropped: AtomicU64,
+    /// Most entries held at once.
+    high_water: AtomicUsize,
+}
+
+impl Entry {
+    const fn new(name: &'static str, capacity: usize, overflow: Overflow, lossless: bool) -> Self {
+        Entry {
+            name,
+            lossless,
+            capacity: AtomicUsize::new(capacity),
+            overflow: AtomicU8::new(overflow as u8),
+            overflows: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            high_water: AtomicUsize::new(0),
+        }
+    }
+
+    fn overflow(&self) -> Overflow {
+        Overflow::from_u8(self.overflow.load(Ordering::Relaxed))
+    }
+}
+
+/// Indexed by `QueueId`.
+static REGISTRY: [Entry; 5] = [
+    Entry::new("scancodes", 100, Overflow::DropNewest, false),
+    // A lost wakeup would leave its task asleep forever.
+    Entry::new("tasks", 100, Overflow::Grow, true),
+    Entry::new("deferred work", 64, Overflow::DropNewest, false),
+    Entry::new("serial input", 256, Overflow::DropNewest, false),
+    Entry::new("mouse bytes", 256, Overflow::DropNewest, false),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    /// The queue's entries can't be dropped, so it only supports `Overflow::Grow`.
+    Lossless,
+    /// A queue needs room for at least one entry.
+    ZeroCapacity,
+}
+
+/// Sets the capacity of queues created from now on.
+pub fn set_capacity(id: QueueId, capacity: usize) -> Result<(), QueueError> {
+    if capacity == 0 {
+        return Err(QueueError::ZeroCapacity);
+    }
+
+    id.entry().capacity.store(capacity, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Changes what happens when the queue is full, for existing queues too.
+pub fn set_overflow(id: QueueId, overflow: Overflow) -> Result<(), QueueError> {
+    let entry = id.entry();
+
+    if entry.lossless && overflow != Overflow::Grow {
+        return Err(QueueError::Lossless);
+    }
+
+    entry.overflow.store(overflow as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueStats {
+    pub id: QueueId,
+    pub name: &'static str,
+    pub capacity: usize,
+    pub overflow: Overflow,
+    pub overflows: u64,
+    pub dropped: u64,
+    pub high_water: usize,
+}
+
+pub fn stats(id: QueueId) -> QueueStats {
+    let entry = id.entry();
+
+    QueueStats {
+        id,
+        name: entry.name,
+        capacity: entry.capacity.load(Ordering::Relaxed),
+        overflow: entry.overflow(),
+        overflows: entry.overflows.load(Ordering::Relaxed),
+        dropped: entry.dropped.load(Ordering::Relaxed),
+        high_water: entry.high_water.load(Ordering::Relaxed),
+    }
+}
+
+/// Stats of every registered queue.
+pub fn all_stats() -> impl Iterator<Item = QueueStats> {
+    QueueId::ALL.into_iter().map(stats)
+}
+
+/// A lock-free ring of the registered capacity, plus the overflow buffer used by `Overflow::Grow`.
+///
+/// Safe to push from interrupt handlers. Creating one allocates.
+pub struct Queue<T> {
+    id: QueueId,
+    ring: ArrayQueue<T>,
+    /// Entries that didn't fit in `ring`, newer than all of the ring's. Only locked with interrupts
+    /// disabled, so a handler can't interrupt its holder.
+    spill: Mutex<VecDeque<T>>,
+}
+
+impl<T> Queue<T> {
+    pub fn new(id: QueueId) -> Self {
+        Queue {
+            id,
+            ring: ArrayQueue::new(id.entry().capacity.load(Ordering::Relaxed)),
+            spill: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn id(&self) -> QueueId {
+        self.id
+    }
+
+    /// Capacity of the ring, not counting the overflow buffer.
+    pub fn capacity(&self) -> usize {
+        self.ring.capacity()
+    }
+
+    /// Pushes `value`, applying the queue's overflow behavior if it is full. Returns the value back if it
+    /// was dropped.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let entry = self.id.entry();
+
+        let result = match entry.overflow() {
+            Overflow::DropNewest => self.ring.push(value).inspect_err(|_| {
+                entry.overflows.fetch_add(1, Ordering::Relaxed);
+                entry.dropped.fetch_add(1, Ordering::Relaxed);
+            }),
+            Overflow::DropOldest => {
+                if self.ring.force_push(value).is_some() {
+                    entry.overflows.fetch_add(1, Ordering::Relaxed);
+                    entry.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(())
+            }
+            Overflow::Grow => self.push_or_spill(value),
+        };
+
+        entry.high_water.fetch_max(self.len(), Ordering::Relaxed);
+        result
+    }
+
+    fn push_or_spill(&self, value: T) -> Result<(), T> {
+        let entry = self.id.entry();
+        let can_allocate = interrupts::are_enabled();
+
+        interrupts::without_interrupts(|| {
+            let mut spill = self.spill.lock();
+
+            // Once entries spilled, new ones have to queue up behind them to stay in order.
+            let value = if spill.is_empty() {
+                match self.ring.push(value) {
+                    Ok(()) => return Ok(()),
+                    Err(value) => value,
+                }
+            } else {
+                value
+            };
+
+            entry.overflows.fetch_add(1, Ordering::Relaxed);
+
+            if !can_allocate && spill.len() == spill.capacity() {
+                entry.dropped.fetch_add(1, Ordering::Relaxed);
+                return Err(value);
+            }
+
+            spill.push_back(value);
+            Ok(())
+        })
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        self.ring
+            .pop()
+            .or_else(|| interrupts::without_interrupts(|| self.spill.lock().pop_front()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.ring.len() + interrupts::without_interrupts(|| self.spill.lock().len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
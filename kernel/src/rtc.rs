@@ -0,0 +1,428 @@
+//! Real-time clock in the CMOS.
+//!
+//! The RTC keeps the date and time while the machine is off. Its registers are read through an index port
+//! (0x70) and a data port (0x71), and may hold values either in binary or in BCD depending on status
+//! register B.
+//!
+//! The RTC can also interrupt on IRQ 8, at a periodic rate from 2 to 8192 Hz and when its time matches an
+//! alarm. `ticks` and `alarm` enable these, and take them back on drop. The handler must read status C
+//! for the RTC to interrupt again, and the index port is shared with every other register access, so those
+//! are made with interrupts disabled.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::{stream::Stream, task::AtomicWaker};
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use crate::{
+    acpi::{self, SdtHeader},
+    init_state::InitState,
+    interrupts, time,
+};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+/// The PIC line the RTC interrupts on.
+pub const IRQ: u8 = 8;
+
+const REGISTER_SECONDS: u8 = 0x00;
+const REGISTER_ALARM_SECONDS: u8 = 0x01;
+const REGISTER_MINUTES: u8 = 0x02;
+const REGISTER_ALARM_MINUTES: u8 = 0x03;
+const REGISTER_HOURS: u8 = 0x04;
+const REGISTER_ALARM_HOURS: u8 = 0x05;
+const REGISTER_DAY: u8 = 0x07;
+const REGISTER_MONTH: u8 = 0x08;
+const REGISTER_YEAR: u8 = 0x09;
+const REGISTER_STATUS_A: u8 = 0x0A;
+const REGISTER_STATUS_B: u8 = 0x0B;
+/// Says which interrupts fired, and is cleared by reading it.
+const REGISTER_STATUS_C: u8 = 0x0C;
+
+/// Set in status A while the RTC is updating its registers, during which they must not be read.
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// The low bits of status A select the periodic rate: 32768 Hz shifted right by one less than it.
+const STATUS_A_RATE: u8 = 0x0F;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_ALARM_INTERRUPT: u8 = 1 << 5;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const STATUS_C_ALARM: u8 = 1 << 5;
+const STATUS_C_PERIODIC: u8 = 1 << 6;
+/// In 12-hour mode, the highest bit of the hours register marks PM.
+const HOURS_PM: u8 = 1 << 7;
+
+/// Offset of the century register index inside the FADT.
+const FADT_CENTURY_OFFSET: usize = 108;
+
+static IRQ_STATE: InitState = InitState::new("RTC interrupt");
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+static TICKS_TAKEN: AtomicBool = AtomicBool::new(false);
+static TICKS_WAKER: AtomicWaker = AtomicWaker::new();
+static ALARM_TAKEN: AtomicBool = AtomicBool::new(false);
+static ALARM_FIRED: AtomicBool = AtomicBool::new(false);
+static ALARM_WAKER: AtomicWaker = AtomicWaker::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    /// The periodic rate must be a power of two from 2 to 8192 Hz.
+    InvalidRate(u32),
+    InvalidTime,
+    /// Someone else has the periodic interrupt or the alarm.
+    Busy,
+    /// IRQ 8 is routed to the HPET instead.
+    IrqInUse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00 UTC, assuming the RTC runs in UTC.
+    pub fn unix_timestamp(&self) -> u64 {
+        // Howard Hinnant's `days_from_civil`: shifts the year to start in March so that the leap day is the
+        // last day of the year.
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        (days * 86400) as u64
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    without_interrupts(|| unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(register);
+        Port::<u8>::new(CMOS_DATA).read()
+    })
+}
+
+fn write_register(register: u8, value: u8) {
+    without_interrupts(|| unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(register);
+        Port::<u8>::new(CMOS_DATA).write(value);
+    })
+}
+
+/// Sets and clears bits of status B, without an interrupt in between.
+fn update_status_b(set: u8, clear: u8) {
+    without_interrupts(|| {
+        let status = read_register(REGISTER_STATUS_B);
+        write_register(REGISTER_STATUS_B, (status | set) & !clear);
+    })
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+fn binary_to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// The status A rate for a periodic interrupt at `hz`.
+fn rate_for(hz: u32) -> Option<u8> {
+    (hz.is_power_of_two() && (2..=8192).contains(&hz)).then(|| (16 - hz.trailing_zeros()) as u8)
+}
+
+/// The hours register for `hour` (0-23) in the format status B selects.
+fn encode_hour(hour: u8, status_b: u8) -> u8 {
+    let (hour, pm) = match status_b & STATUS_B_24_HOUR != 0 {
+        true => (hour, false),
+        false => (
+            if hour.is_multiple_of(12) {
+                12
+            } else {
+                hour % 12
+            },
+            hour >= 12,
+        ),
+    };
+    let hour = match status_b & STATUS_B_BINARY != 0 {
+        true => hour,
+        false => binary_to_bcd(hour),
+    };
+    if pm { hour | HOURS_PM } else { hour }
+}
+
+/// The FADT may name a CMOS register holding the century; zero means there is none.
+fn century_register() -> Option<u8> {
+    let fadt = acpi::find_table(b"FACP")?;
+    let header: SdtHeader = unsafe { acpi::read_table(fadt) };
+
+    if (header.length as usize) <= FADT_CENTURY_OFFSET {
+        return None;
+    }
+
+    let register: [u8; FADT_CENTURY_OFFSET + 1] = unsafe { acpi::read_table(fadt) };
+    Some(register[FADT_CENTURY_OFFSET]).filter(|&register| register != 0)
+}
+
+fn read_raw(century_register: Option<u8>) -> [u8; 7] {
+    while read_register(REGISTER_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+
+    [
+        read_register(REGISTER_SECONDS),
+        read_register(REGISTER_MINUTES),
+        read_register(REGISTER_HOURS),
+        read_register(REGISTER_DAY),
+        read_register(REGISTER_MONTH),
+        read_register(REGISTER_YEAR),
+        century_register.map_or(0, read_register),
+    ]
+}
+
+/// Reads the current date and time.
+pub fn read() -> DateTime {
+    let century_register = century_register();
+
+    // An update may still start between the check and the reads, so read until two results agree.
+    let mut raw = read_raw(century_register);
+    loop {
+        let again = read_raw(century_register);
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    let status_b = read_register(REGISTER_STATUS_B);
+    let pm = raw[2] & HOURS_PM != 0;
+    raw[2] &= !HOURS_PM;
+
+    if status_b & STATUS_B_BINARY == 0 {
+        raw = raw.map(bcd_to_binary);
+    }
+
+    let [second, minute, mut hour, day, month, year, century] = raw;
+
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight and 12 PM is noon.
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let full_year = if century != 0 {
+        century as u16 * 100 + year as u16
+    } else {
+        // Without a century register, assume the 21st century.
+        2000 + year as u16
+    };
+
+    DateTime {
+        year: full_year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
+
+/// Called by the IRQ 8 handler.
+pub(crate) fn on_interrupt() {
+    if !IRQ_STATE.is_initialized() || time::hpet::routes_irq8() {
+        return;
+    }
+    let status = read_register(REGISTER_STATUS_C);
+
+    if status & STATUS_C_PERIODIC != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+        TICKS_WAKER.wake();
+    }
+    if status & STATUS_C_ALARM != 0 {
+        ALARM_FIRED.store(true, Ordering::Release);
+        ALARM_WAKER.wake();
+    }
+}
+
+/// Unmasks IRQ 8 the first time it is needed. Its IDT entry calls `on_interrupt` from then on.
+fn enable_irq() -> Result<(), RtcError> {
+    if time::hpet::routes_irq8() {
+        return Err(RtcError::IrqInUse);
+    }
+    if IRQ_STATE.begin().is_ok() {
+        // Whatever fired before would keep the RTC from interrupting.
+        read_register(REGISTER_STATUS_C);
+        interrupts::unmask_irq(IRQ);
+    }
+    Ok(())
+}
+
+/// Periodic interrupts counted since boot, while some `Ticks` were enabled.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// Starts the periodic interrupt at `hz`, and returns the stream of its ticks. There can only be one
+/// at a time; dropping it stops the interrupt.
+pub fn ticks(hz: u32) -> Result<Ticks, RtcError> {
+    let rate = rate_for(hz).ok_or(RtcError::InvalidRate(hz))?;
+    if TICKS_TAKEN.swap(true, Ordering::Acquire) {
+        return Err(RtcError::Busy);
+    }
+    if let Err(error) = enable_irq() {
+        TICKS_TAKEN.store(false, Ordering::Release);
+        return Err(error);
+    }
+
+    without_interrupts(|| {
+        let status = read_register(REGISTER_STATUS_A);
+        write_register(REGISTER_STATUS_A, (status & !STATUS_A_RATE) | rate);
+        update_status_b(STATUS_B_PERIODIC_INTERRUPT, 0);
+    });
+    Ok(Ticks {
+        seen: periodic_ticks(),
+    })
+}
+
+/// The periodic interrupt, from `ticks`. Yields how many ticks passed since the last item, which is more
+/// than one if the consumer fell behind.
+pub struct Ticks {
+    seen: u64,
+}
+
+impl Stream for Ticks {
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u64>> {
+        TICKS_WAKER.register(context.waker());
+
+        let now = periodic_ticks();
+        if now == self.seen {
+            return Poll::Pending;
+        }
+        let elapsed = now - self.seen;
+        self.seen = now;
+        Poll::Ready(Some(elapsed))
+    }
+}
+
+impl Drop for Ticks {
+    fn drop(&mut self) {
+        update_status_b(0, STATUS_B_PERIODIC_INTERRUPT);
+        TICKS_TAKEN.store(false, Ordering::Release);
+    }
+}
+
+/// Resolves the next time the RTC reads `hour:minute:second`, in the RTC's time zone. There can only be
+/// one alarm at a time; dropping it disarms it.
+pub fn alarm(hour: u8, minute: u8, second: u8) -> Result<Alarm, RtcError> {
+    if hour > 23 || minute > 59 || second > 59 {
+        return Err(RtcError::InvalidTime);
+    }
+    if ALARM_TAKEN.swap(true, Ordering::Acquire) {
+        return Err(RtcError::Busy);
+    }
+    if let Err(error) = enable_irq() {
+        ALARM_TAKEN.store(false, Ordering::Release);
+        return Err(error);
+    }
+
+    without_interrupts(|| {
+        let status_b = read_register(REGISTER_STATUS_B);
+        let encode = |value| match status_b & STATUS_B_BINARY != 0 {
+            true => value,
+            false => binary_to_bcd(value),
+        };
+        write_register(REGISTER_ALARM_SECONDS, encode(second));
+        write_register(REGISTER_ALARM_MINUTES, encode(minute));
+        write_register(REGISTER_ALARM_HOURS, encode_hour(hour, status_b));
+        ALARM_FIRED.store(false, Ordering::Relaxed);
+        update_status_b(STATUS_B_ALARM_INTERRUPT, 0);
+    });
+    Ok(Alarm { _private: () })
+}
+
+/// An armed alarm, from `alarm`.
+pub struct Alarm {
+    _private: (),
+}
+
+impl Future for Alarm {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        ALARM_WAKER.register(context.waker());
+
+        match ALARM_FIRED.load(Ordering::Acquire) {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Alarm {
+    fn drop(&mut self) {
+        update_status_b(0, STATUS_B_ALARM_INTERRUPT);
+        ALARM_TAKEN.store(false, Ordering::Release);
+    }
+}
+
+#[test_case]
+fn test_periodic_rate() {
+    assert_eq!(rate_for(1024), Some(6));
+    assert_eq!(rate_for(8192), Some(3));
+    assert_eq!(rate_for(2), Some(15));
+    assert_eq!(rate_for(1), None);
+    assert_eq!(rate_for(100), None);
+    assert_eq!(rate_for(16384), None);
+}
+
+#[test_case]
+fn test_encode_hour() {
+    let bcd_24_hour = STATUS_B_24_HOUR;
+    assert_eq!(encode_hour(23, bcd_24_hour), 0x23);
+    assert_eq!(encode_hour(23, bcd_24_hour | STATUS_B_BINARY), 23);
+    // Midnight and noon are both 12 in 12-hour mode.
+    assert_eq!(encode_hour(0, 0), 0x12);
+    assert_eq!(encode_hour(12, 0), 0x12 | HOURS_PM);
+    assert_eq!(encode_hour(13, STATUS_B_BINARY), 1 | HOURS_PM);
+}
+
+#[test_case]
+fn test_unix_timestamp() {
+    let epoch = DateTime {
+        year: 1970,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+    assert_eq!(epoch.unix_timestamp(), 0);
+
+    let leap_day = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 12,
+        minute: 30,
+        second: 15,
+    };
+    assert_eq!(leap_day.unix_timestamp(), 1_709_209_815);
+}
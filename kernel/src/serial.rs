@@ -1,6 +1,38 @@
+//! COM1, a 16550 UART, for logging and a headless console.
+//!
+//! Output is written synchronously through `SERIAL1`. Input arrives by IRQ 4, whose handler moves every
+//! received byte into a queue that `SerialInput` reads as a stream. The IRQ stays masked until that
+//! stream is taken, and while an XMODEM transfer polls the port itself.
+
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_util::stream::{Stream, StreamExt};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
+use x86_64::instructions::port::{Port, PortReadOnly};
+
+use crate::{
+    console::Console,
+    console_print,
+    init_state::AlreadyInitialized,
+    interrupts,
+    queue::QueueId,
+    task::irq_stream::{IrqEvents, IrqStream},
+};
+
+pub mod xmodem;
+
+/// The PIC line COM1 interrupts on.
+pub const IRQ: u8 = 4;
+const DATA_PORT: u16 = 0x3F8;
+const LINE_STATUS_PORT: u16 = DATA_PORT + 5;
+/// Line status bit set while a received byte waits in the data register.
+const DATA_READY: u8 = 1 << 0;
+
+static INPUT: IrqStream<u8> = IrqStream::new("serial input", QueueId::SerialInput);
 
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
@@ -42,3 +74,67 @@ macro_rules! serial_println {
         $crate::serial_print!(concat!($fmt, "\n"), $($arg)*)
     };
 }
+
+/// Whether a byte was received and not read yet.
+fn data_ready() -> bool {
+    unsafe { PortReadOnly::<u8>::new(LINE_STATUS_PORT).read() & DATA_READY != 0 }
+}
+
+/// Called by the COM1 interrupt handler. Reads every byte waiting in the UART's FIFO, which also clears
+/// the interrupt. Bytes that don't fit in the queue are dropped and counted in its stats.
+pub(crate) fn on_interrupt() {
+    while data_ready() {
+        let byte = unsafe { Port::<u8>::new(DATA_PORT).read() };
+        let _ = INPUT.push(byte);
+    }
+}
+
+/// The bytes received on COM1.
+pub struct SerialInput {
+    bytes: IrqEvents<u8>,
+}
+
+impl SerialInput {
+    /// Creates the only consumer of the bytes received on COM1, and starts receiving them.
+    pub fn new() -> Result<Self, AlreadyInitialized> {
+        let bytes = INPUT.take()?;
+        // Nothing arrives before the port is initialized.
+        lazy_static::initialize(&SERIAL1);
+        interrupts::unmask_irq(IRQ);
+
+        Ok(SerialInput { bytes })
+    }
+}
+
+impl Stream for SerialInput {
+    type Item = u8;
+
+    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u8>> {
+        Pin::new(&mut self.bytes).poll_next(context)
+    }
+}
+
+/// Whether `SerialInput` was created, so IRQ 4 should be unmasked.
+pub(crate) fn is_receiving() -> bool {
+    INPUT.is_taken()
+}
+
+/// Echoes what is typed on the serial console to the shell console, and back to the terminal, which
+/// doesn't show what is typed otherwise. Terminals send a carriage return for Enter.
+pub async fn echo_input() {
+    let mut input = SerialInput::new().expect("serial input already taken");
+
+    while let Some(byte) = input.next().await {
+        match byte {
+            b'\r' | b'\n' => {
+                serial_print!("\r\n");
+                console_print!(Console::Shell, "\n");
+            }
+            byte => {
+                let character = char::from(byte);
+                serial_print!("{}", character);
+                console_print!(Console::Shell, "{}", character);
+            }
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! XMODEM receiver, to push files to a running system over the serial port.
+//!
+//! The sender transfers the file in numbered blocks of 128 bytes (SOH) or, with XMODEM-1K, 1024 bytes
+//! (STX), each acknowledged (ACK) or rejected (NAK) by the receiver. The receiver starts the transfer by
+//! sending 'C' to ask for CRC-16 checksums, and falls back to NAK (8-bit sums) for senders that don't
+//! answer. EOT ends the transfer and two CANs abort it.
+//!
+//! XMODEM has no notion of file size: the last block is padded with SUB (0x1A) bytes, which are returned
+//! as part of the data.
+
+use alloc::vec::Vec;
+use core::time::Duration;
+use spin::MutexGuard;
+use uart_16550::SerialPort;
+use x86_64::instructions::port::{Port, PortReadOnly};
+
+use super::SERIAL1;
+use crate::{interrupts, time::Instant};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const CRC_REQUEST: u8 = b'C';
+
+/// Blocks rejected in a row before giving up.
+const MAX_ERRORS: usize = 10;
+/// Times 'C' is sent before falling back to 8-bit checksums, and NAK before giving up.
+const START_ATTEMPTS: usize = 3;
+const START_TIMEOUT: Duration = Duration::from_secs(3);
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(10);
+const BYTE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A byte-oriented connection to the sender.
+pub trait Link {
+    /// Waits at most `timeout` for the next byte.
+    fn read_byte(&mut self, timeout: Duration) -> Option<u8>;
+    fn write_byte(&mut self, byte: u8);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XmodemError {
+    /// The sender never started the transfer, or stopped answering.
+    Timeout,
+    /// The sender aborted the transfer.
+    Cancelled,
+    /// Too many blocks in a row had bad checksums or were cut short.
+    TooManyErrors,
+    /// The sender skipped a block, so the file can't be put back together.
+    OutOfSequence,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Checksum {
+    Crc16,
+    Sum,
+}
+
+impl Checksum {
+    fn len(self) -> usize {
+        match self {
+            Checksum::Crc16 => 2,
+            Checksum::Sum => 1,
+        }
+    }
+
+    fn verify(self, data: &[u8], expected: &[u8]) -> bool {
+        match self {
+            Checksum::Crc16 => crc16(data).to_be_bytes() == expected,
+            Checksum::Sum => {
+                data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == expected[0]
+            }
+        }
+    }
+}
+
+/// CRC-16/XMODEM: polynomial 0x1021, initial value 0.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+enum Block {
+    Data { number: u8, data: Vec<u8> },
+    End,
+}
+
+/// Receives a whole file. `progress` is called with the number of bytes received after every block.
+pub fn receive(
+    link: &mut impl Link,
+    mut progress: impl FnMut(usize),
+) -> Result<Vec<u8>, XmodemError> {
+    let (checksum, mut header) = start(link)?;
+
+    let mut file = Vec::new();
+    let mut expected: u8 = 1;
+    let mut errors = 0;
+
+    loop {
+        match read_block(link, header, checksum) {
+            Ok(Block::End) => {
+                link.write_byte(ACK);
+                return Ok(file);
+            }
+            Ok(Block::Data { number, data }) if number == expected => {
+                file.extend_from_slice(&data);
+                expected = expected.wrapping_add(1);
+                errors = 0;
+                link.write_byte(ACK);
+                progress(file.len());
+            }
+            // Our ACK for the previous block got lost, so the sender repeated it.
+            Ok(Block::Data { number, .. }) if number == expected.wrapping_sub(1) => {
+                link.write_byte(ACK);
+            }
+            Ok(Block::Data { .. }) => {
+                cancel(link);
+                return Err(XmodemError::OutOfSequence);
+            }
+            Err(BlockError::Cancelled) => return Err(XmodemError::Cancelled),
+            Err(BlockError::Corrupted) => {
+                errors += 1;
+                if errors >= MAX_ERRORS {
+                    cancel(link);
+                    return Err(XmodemError::TooManyErrors);
+                }
+
+                purge(link);
+                link.write_byte(NAK);
+            }
+        }
+
+        header = link.read_byte(BLOCK_TIMEOUT).ok_or(XmodemError::Timeout)?;
+    }
+}
+
+/// Asks the sender to start, preferring CRC-16. Returns the checksum in use and the first header byte.
+fn start(link: &mut impl Link) -> Result<(Checksum, u8), XmodemError> {
+    for (checksum, request) in [(Checksum::Crc16, CRC_REQUEST), (Checksum::Sum, NAK)] {
+        for _ in 0..START_ATTEMPTS {
+            link.write_byte(request);
+
+            if let Some(header) = link.read_byte(START_TIMEOUT) {
+                return Ok((checksum, header));
+            }
+        }
+    }
+
+    Err(XmodemError::Timeout)
+}
+
+enum BlockError {
+    Cancelled,
+    /// Bad header, checksum or a block cut short; the sender should repeat it.
+    Corrupted,
+}
+
+fn read_block(link: &mut impl Link, header: u8, checksum: Checksum) -> Result<Block, BlockError> {
+    let size = match header {
+        SOH => 128,
+        STX => 1024,
+        EOT => return Ok(Block::End),
+        CAN => {
+            // A single CAN may be line noise.
+            return match link.read_byte(BYTE_TIMEOUT) {
+                Some(CAN) => Err(BlockError::Cancelled),
+                _ => Err(BlockError::Corrupted),
+            };
+        }
+        _ => return Err(BlockError::Corrupted),
+    };
+
+    let mut read = |len: usize| -> Result<Vec<u8>, BlockError> {
+        (0..len)
+            .map(|_| link.read_byte(BYTE_TIMEOUT).ok_or(BlockError::Corrupted))
+            .collect()
+    };
+
+    let numbers = read(2)?;
+    let data = read(size)?;
+    let sum = read(checksum.len())?;
+
+    if numbers[0] != !numbers[1] || !checksum.verify(&data, &sum) {
+        return Err(BlockError::Corrupted);
+    }
+
+    Ok(Block::Data {
+        number: numbers[0],
+        data,
+    })
+}
+
+/// Drops whatever is left of a bad block, so the NAK isn't mistaken for a reply to a partial block.
+fn purge(link: &mut impl Link) {
+    while link.read_byte(BYTE_TIMEOUT).is_some() {}
+}
+
+fn cancel(link: &mut impl Link) {
+    link.write_byte(CAN);
+    link.write_byte(CAN);
+}
+
+/// COM1 as an XMODEM link. The port stays locked while the link exists, so other serial output can't
+/// corrupt the transfer.
+pub struct SerialLink {
+    port: MutexGuard<'static, SerialPort>,
+    data: Port<u8>,
+    line_status: PortReadOnly<u8>,
+}
+
+impl SerialLink {
+    const DATA_PORT: u16 = 0x3F8;
+    const LINE_STATUS_PORT: u16 = Self::DATA_PORT + 5;
+    const DATA_READY: u8 = 1 << 0;
+
+    pub fn new() -> Self {
+        // The link reads the port itself, so received bytes mustn't go to `SerialInput`.
+        interrupts::mask_irq(super::IRQ);
+
+        SerialLink {
+            port: SERIAL1.lock(),
+            data: Port::new(Self::DATA_PORT),
+            line_status: PortReadOnly::new(Self::LINE_STATUS_PORT),
+        }
+    }
+}
+
+impl Drop for SerialLink {
+    fn drop(&mut self) {
+        if super::is_receiving() {
+            interrupts::unmask_irq(super::IRQ);
+            // Bytes that arrived in between wouldn't raise the IRQ again.
+            x86_64::instructions::interrupts::without_interrupts(super::on_interrupt);
+        }
+    }
+}
+
+impl Default for SerialLink {
+    fn default() -> Self {
+        SerialLink::new()
+    }
+}
+
+impl Link for SerialLink {
+    fn read_byte(&mut self, timeout: Duration) -> Option<u8> {
+        let start = Instant::now();
+
+        loop {
+            if unsafe { self.line_status.read() } & Self::DATA_READY != 0 {
+                return Some(unsafe { self.data.read() });
+            }
+
+            if start.elapsed() >= timeout {
+                return None;
+            }
+
+            core::hint::spin_loop();
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.port.send_raw(byte);
+    }
+}
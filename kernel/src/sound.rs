@@ -0,0 +1,97 @@
+//! The PC speaker, a square wave from PIT channel 2.
+//!
+//! `beep` sleeps through the tone and is what tasks use; beeps from several tasks take turns.
+//! `beep_blocking` busy-waits instead, for when interrupts may be off, such as `panic_beep` on the
+//! panic path, where a headless machine has no other way to tell.
+
+use core::time::Duration;
+
+use crate::{
+    task::{sync::Mutex, timer},
+    time::pit,
+};
+
+/// Roughly the range of human hearing, and well within what the PIT can produce.
+pub const MIN_FREQUENCY: u32 = 20;
+pub const MAX_FREQUENCY: u32 = 20_000;
+
+/// The longest wait `pit::busy_wait_micros` allows is a bit above this.
+const BUSY_WAIT_STEP_MICROS: u32 = 50_000;
+
+/// Held by whoever is beeping.
+static SPEAKER: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoundError {
+    /// Outside `MIN_FREQUENCY..=MAX_FREQUENCY`.
+    InvalidFrequency(u32),
+}
+
+fn check_frequency(freq: u32) -> Result<(), SoundError> {
+    match (MIN_FREQUENCY..=MAX_FREQUENCY).contains(&freq) {
+        true => Ok(()),
+        false => Err(SoundError::InvalidFrequency(freq)),
+    }
+}
+
+/// Stops the tone when dropped, so that a cancelled beep doesn't go on forever.
+struct Tone;
+
+impl Tone {
+    fn play(freq: u32) -> Self {
+        pit::play_tone(freq);
+        Tone
+    }
+}
+
+impl Drop for Tone {
+    fn drop(&mut self) {
+        pit::stop_tone();
+    }
+}
+
+/// Plays `freq` Hz for `duration`, after any beep already playing.
+pub async fn beep(freq: u32, duration: Duration) -> Result<(), SoundError> {
+    check_frequency(freq)?;
+    let _speaker = SPEAKER.lock().await;
+
+    let _tone = Tone::play(freq);
+    timer::sleep(duration).await;
+    Ok(())
+}
+
+/// Plays `freq` Hz for `duration`, busy-waiting. Cuts whatever `beep` is playing short.
+pub fn beep_blocking(freq: u32, duration: Duration) -> Result<(), SoundError> {
+    check_frequency(freq)?;
+
+    let _tone = Tone::play(freq);
+    let mut micros = duration.as_micros();
+    while micros > 0 {
+        let step = micros.min(BUSY_WAIT_STEP_MICROS.into());
+        pit::busy_wait_micros(step as u32);
+        micros -= step;
+    }
+    Ok(())
+}
+
+/// Two short, low beeps, for the panic handler.
+pub fn panic_beep() {
+    // The panic may have interrupted code that held the PIT.
+    unsafe { pit::force_unlock() };
+
+    for _ in 0..2 {
+        let _ = beep_blocking(440, Duration::from_millis(150));
+        pit::busy_wait_micros(BUSY_WAIT_STEP_MICROS);
+    }
+}
+
+#[test_case]
+fn test_frequency_range() {
+    assert_eq!(check_frequency(440), Ok(()));
+    assert_eq!(check_frequency(MAX_FREQUENCY), Ok(()));
+    assert_eq!(check_frequency(10), Err(SoundError::InvalidFrequency(10)));
+    assert_eq!(
+        check_frequency(30_000),
+        Err(SoundError::InvalidFrequency(30_000))
+    );
+}
@@ -0,0 +1,106 @@
+//! Block storage: devices that are read and written in whole blocks.
+//!
+//! Drivers implement `BlockDevice`. Reads and writes are asynchronous, so a task waiting for the disk
+//! leaves the CPU to the others. A `RequestQueue` puts a device behind a task that serves requests one
+//! at a time, in the order they came in, and can be cloned into every task that uses the device.
+//! `RamDisk` keeps its blocks on the heap, for filesystems to be tested without a disk.
+
+use core::future::Future;
+
+pub mod queue;
+pub mod ramdisk;
+
+pub use queue::{Request, RequestQueue};
+pub use ramdisk::RamDisk;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The blocks asked for go past the end of the device.
+    OutOfRange,
+    /// The buffer isn't a whole number of blocks.
+    InvalidLength,
+    ReadOnly,
+    /// The device failed to read or write.
+    Io,
+    /// The device doesn't support the request.
+    Unsupported,
+    /// The task serving a `RequestQueue` is gone.
+    QueueClosed,
+}
+
+pub trait BlockDevice {
+    /// The size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    fn num_blocks(&self) -> u64;
+
+    /// Reads blocks into `buffer`, from block `start` on.
+    fn read_blocks(
+        &self,
+        start: u64,
+        buffer: &mut [u8],
+    ) -> impl Future<Output = Result<(), BlockError>>;
+
+    /// Writes `buffer` to blocks from block `start` on.
+    fn write_blocks(
+        &self,
+        start: u64,
+        buffer: &[u8],
+    ) -> impl Future<Output = Result<(), BlockError>>;
+}
+
+impl<D: BlockDevice> BlockDevice for &D {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (**self).num_blocks()
+    }
+
+    fn read_blocks(
+        &self,
+        start: u64,
+        buffer: &mut [u8],
+    ) -> impl Future<Output = Result<(), BlockError>> {
+        (**self).read_blocks(start, buffer)
+    }
+
+    fn write_blocks(
+        &self,
+        start: u64,
+        buffer: &[u8],
+    ) -> impl Future<Output = Result<(), BlockError>> {
+        (**self).write_blocks(start, buffer)
+    }
+}
+
+/// The number of blocks of `block_size` in `len` bytes starting at block `start` on a device with
+/// `num_blocks`, if they fit.
+pub fn check_range(
+    start: u64,
+    len: usize,
+    block_size: usize,
+    num_blocks: u64,
+) -> Result<u64, BlockError> {
+    if !len.is_multiple_of(block_size) {
+        return Err(BlockError::InvalidLength);
+    }
+    let count = (len / block_size) as u64;
+    match start.checked_add(count) {
+        Some(end) if end <= num_blocks => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+#[test_case]
+fn test_check_range() {
+    assert_eq!(check_range(0, 1024, 512, 4), Ok(2));
+    assert_eq!(check_range(2, 1024, 512, 4), Ok(2));
+    assert_eq!(check_range(3, 1024, 512, 4), Err(BlockError::OutOfRange));
+    assert_eq!(
+        check_range(u64::MAX, 512, 512, 4),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(check_range(0, 100, 512, 4), Err(BlockError::InvalidLength));
+}
@@ -0,0 +1,116 @@
+//! Requests to a block device, queued for a task that serves them in order.
+//!
+//! `RequestQueue::new` takes the device and returns the queue with the future that serves it, which the
+//! caller spawns. Requests carry their data with them, so they don't borrow from the task that made
+//! them. Once the queue is full, submitting waits for room.
+
+use alloc::{vec, vec::Vec};
+use core::future::Future;
+
+use super::{BlockDevice, BlockError, check_range};
+use crate::task::channel::{mpsc, oneshot};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Reads `count` blocks from block `start` on.
+    Read { start: u64, count: u64 },
+    /// Writes `data` to blocks from block `start` on.
+    Write { start: u64, data: Vec<u8> },
+}
+
+type Reply = oneshot::Sender<Result<Vec<u8>, BlockError>>;
+
+/// A handle to the queue of a block device. Clones submit to the same queue.
+#[derive(Clone)]
+pub struct RequestQueue {
+    sender: mpsc::Sender<(Request, Reply)>,
+    block_size: usize,
+    num_blocks: u64,
+}
+
+impl RequestQueue {
+    /// A queue of up to `depth` requests to `device`, and the future that serves them until every
+    /// handle is dropped.
+    pub fn new<D: BlockDevice>(device: D, depth: usize) -> (Self, impl Future<Output = ()>) {
+        let (sender, receiver) = mpsc::channel(depth);
+        let queue = RequestQueue {
+            sender,
+            block_size: device.block_size(),
+            num_blocks: device.num_blocks(),
+        };
+
+        (queue, serve(device, receiver))
+    }
+
+    /// Queues `request` and waits until it was served. Reads return the blocks read, writes hand their
+    /// data back.
+    pub async fn submit(&self, request: Request) -> Result<Vec<u8>, BlockError> {
+        let (reply, result) = oneshot::channel();
+        self.sender
+            .send((request, reply))
+            .await
+            .map_err(|_| BlockError::QueueClosed)?;
+
+        result.await.map_err(|_| BlockError::QueueClosed)?
+    }
+
+    /// Requests waiting to be served.
+    pub fn pending(&self) -> usize {
+        self.sender.len()
+    }
+}
+
+impl BlockDevice for RequestQueue {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    async fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let count = check_range(start, buffer.len(), self.block_size, self.num_blocks)?;
+        let data = self.submit(Request::Read { start, count }).await?;
+        buffer.copy_from_slice(&data);
+        Ok(())
+    }
+
+    async fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_range(start, buffer.len(), self.block_size, self.num_blocks)?;
+        self.submit(Request::Write {
+            start,
+            data: buffer.to_vec(),
+        })
+        .await
+        .map(drop)
+    }
+}
+
+async fn serve<D: BlockDevice>(device: D, mut requests: mpsc::Receiver<(Request, Reply)>) {
+    while let Some((request, reply)) = requests.recv().await {
+        let result = match request {
+            Request::Read { start, count } => {
+                let len = usize::try_from(count)
+                    .ok()
+                    .and_then(|count| count.checked_mul(device.block_size()));
+                match len {
+                    Some(len) => {
+                        let mut buffer = vec![0; len];
+                        device
+                            .read_blocks(start, &mut buffer)
+                            .await
+                            .map(|()| buffer)
+                    }
+                    None => Err(BlockError::OutOfRange),
+                }
+            }
+            Request::Write { start, data } => {
+                device.write_blocks(start, &data).await.map(|()| data)
+            }
+        };
+
+        // The task that submitted the request may be gone.
+        let _ = reply.send(result);
+    }
+}
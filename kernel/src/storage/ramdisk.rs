@@ -0,0 +1,75 @@
+//! A block device in memory.
+
+use alloc::{vec, vec::Vec};
+use spin::Mutex;
+
+use super::{BlockDevice, BlockError, check_range};
+
+#[derive(Debug)]
+pub struct RamDisk {
+    data: Mutex<Vec<u8>>,
+    block_size: usize,
+    num_blocks: u64,
+    read_only: bool,
+}
+
+impl RamDisk {
+    /// A disk of `num_blocks` zeroed blocks.
+    pub fn new(block_size: usize, num_blocks: u64) -> Self {
+        Self::from_bytes(vec![0; block_size * num_blocks as usize], block_size)
+    }
+
+    /// A disk holding `data`, without its last partial block if it has one.
+    pub fn from_bytes(mut data: Vec<u8>, block_size: usize) -> Self {
+        assert!(block_size > 0, "blocks must not be empty");
+        let num_blocks = data.len() / block_size;
+        data.truncate(num_blocks * block_size);
+
+        RamDisk {
+            data: Mutex::new(data),
+            block_size,
+            num_blocks: num_blocks as u64,
+            read_only: false,
+        }
+    }
+
+    /// Makes writes fail with `BlockError::ReadOnly`.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data.into_inner()
+    }
+
+    fn byte_range(&self, start: u64, len: usize) -> Result<core::ops::Range<usize>, BlockError> {
+        check_range(start, len, self.block_size, self.num_blocks)?;
+        let offset = start as usize * self.block_size;
+        Ok(offset..offset + len)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    async fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let range = self.byte_range(start, buffer.len())?;
+        buffer.copy_from_slice(&self.data.lock()[range]);
+        Ok(())
+    }
+
+    async fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        let range = self.byte_range(start, buffer.len())?;
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        self.data.lock()[range].copy_from_slice(buffer);
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+//! Blocking synchronization primitives.
+//!
+//! Unlike the spinlocks used elsewhere, these put a waiting kernel thread to sleep until whoever holds
+//! the resource releases it, so the CPU goes to the threads that can make progress. They must not be
+//! used from interrupt handlers, which can't sleep.
+//!
+//! Processes can't sleep in the middle of a syscall: a syscall that has to wait registers the process
+//! with `WaitQueue::block_process`, which restarts the syscall once the process is woken up.
+
+use alloc::collections::VecDeque;
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use x86_64::instructions::interrupts;
+
+use crate::{
+    process::{self, Context, Pid},
+    thread::{self, ThreadId},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Waiter {
+    Thread(ThreadId),
+    Process(Pid),
+}
+
+impl Waiter {
+    fn wake(self) {
+        match self {
+            Waiter::Thread(id) => thread::wake(id),
+            Waiter::Process(pid) => {
+                process::wake(pid);
+            }
+        }
+    }
+}
+
+/// Threads and processes waiting for an event, woken in the order they started waiting.
+pub struct WaitQueue {
+    waiters: spin::Mutex<VecDeque<Waiter>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: spin::Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Sleeps until `condition` holds. It is checked with interrupts disabled, so an interrupt handler
+    /// waking the queue in between can't be missed.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        while !interrupts::without_interrupts(|| {
+            if condition() {
+                return true;
+            }
+
+            self.waiters
+                .lock()
+                .push_back(Waiter::Thread(thread::current()));
+            thread::block_current();
+            false
+        }) {}
+    }
+
+    /// Blocks the current process until the queue is woken, then resumes it with the registers in
+    /// `context`, which typically restart the syscall that is waiting.
+    pub fn block_process(&self, context: Context) -> ! {
+        self.add_current_process();
+        process::block_current(context);
+    }
+
+    /// Queues the current process without blocking it yet, for callers that have to let go of what they
+    /// hold first, since `process::block_current` doesn't return. Leaves interrupts disabled, and they
+    /// must stay so until the process blocks.
+    pub fn add_current_process(&self) {
+        let pid = process::current().expect("block outside of a process");
+
+        interrupts::disable();
+        self.waiters.lock().push_back(Waiter::Process(pid));
+    }
+
+    /// Wakes the longest waiter. Returns whether there was one.
+    pub fn wake_one(&self) -> bool {
+        let waiter = interrupts::without_interrupts(|| self.waiters.lock().pop_front());
+        waiter.map(Waiter::wake).is_some()
+    }
+
+    /// Wakes every waiter and returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let waiters = interrupts::without_interrupts(|| core::mem::take(&mut *self.waiters.lock()));
+        let count = waiters.len();
+
+        waiters.into_iter().for_each(Waiter::wake);
+        count
+    }
+
+    pub fn len(&self) -> usize {
+        interrupts::without_interrupts(|| self.waiters.lock().len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A mutual exclusion lock whose waiters sleep instead of spinning.
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Locks the mutex, sleeping while another thread holds it.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.waiters.wait_until(|| self.acquire());
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.acquire().then_some(MutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    fn acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("value", &*guard).finish(),
+            None => f.write_str("Mutex { <locked> }"),
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
+
+/// A counting semaphore whose waiters sleep until a permit is released.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Takes a permit, sleeping until one is available.
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Returns a permit and wakes a waiter.
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
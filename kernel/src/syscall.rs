@@ -0,0 +1,394 @@
+//! System call entry through SYSCALL/SYSRET.
+//!
+//! SYSCALL jumps to the address in IA32_LSTAR with CS and SS taken from IA32_STAR, the user RIP in RCX and
+//! RFLAGS in R11, but it doesn't switch stacks. The entry stub uses `swapgs` to reach the per-CPU area
+//! (whose address lives in IA32_KERNEL_GS_BASE), saves the user stack pointer there and switches to the
+//! kernel stack before calling into Rust.
+//!
+//! Register convention: the syscall number goes in RAX and up to six arguments in RDI, RSI, RDX, R10, R8
+//! and R9 (RCX is taken by SYSCALL itself). The result comes back in RAX; every other register except RCX
+//! and R11 is preserved. The numbers themselves are defined by `libsys`, which user programs link against.
+
+use core::{arch::naked_asm, mem::offset_of};
+use libsys::{errno, number};
+use x86_64::{
+    VirtAddr,
+    registers::{
+        model_specific::{Efer, EferFlags, KernelGsBase, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::paging::PageTableFlags,
+};
+
+use crate::{
+    gdt, lockup,
+    memory::{
+        self,
+        address_space::{USER_END, USER_START},
+    },
+    process::{Context, signal},
+};
+
+mod gfx;
+mod io;
+mod mm;
+mod process;
+mod time;
+
+/// Per-CPU data reached through GS after `swapgs`.
+#[repr(C)]
+struct PerCpu {
+    /// Top of the stack syscalls run on.
+    kernel_stack: u64,
+    /// Scratch slot for the user stack pointer while switching stacks.
+    user_stack: u64,
+}
+
+// Only one CPU is brought up, so there is a single per-CPU area.
+static mut PER_CPU: PerCpu = PerCpu {
+    kernel_stack: 0,
+    user_stack: 0,
+};
+
+/// User registers saved by the entry stub, in the order it pushes them (the last push comes first). All of
+/// them are restored on return, so handlers may change them, e.g. to start a new program.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub number: u64,
+    pub args: [u64; 6],
+    pub rbx: u64,
+    pub rbp: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+/// Length of the `syscall` instruction.
+const SYSCALL_INSTRUCTION_LEN: u64 = 2;
+
+impl SyscallFrame {
+    /// The registers the caller sees after the syscall returns `result`.
+    pub fn context(&self, result: i64) -> Context {
+        let [rdi, rsi, rdx, r10, r8, r9] = self.args;
+
+        Context {
+            rax: result as u64,
+            rbx: self.rbx,
+            // SYSCALL itself overwrites these two.
+            rcx: self.rip,
+            rdx,
+            rsi,
+            rdi,
+            rbp: self.rbp,
+            r8,
+            r9,
+            r10,
+            r11: self.rflags,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rip: self.rip,
+            rsp: self.rsp,
+            rflags: self.rflags,
+        }
+    }
+
+    /// The registers that make the caller issue this syscall again, for syscalls that block.
+    pub fn restart_context(&self) -> Context {
+        Context {
+            rip: self.rip - SYSCALL_INSTRUCTION_LEN,
+            ..self.context(self.number as i64)
+        }
+    }
+
+    /// Makes the syscall return into `context` instead of the caller.
+    pub fn set_context(&mut self, context: &Context) {
+        *self = SyscallFrame {
+            number: context.rax,
+            args: [
+                context.rdi,
+                context.rsi,
+                context.rdx,
+                context.r10,
+                context.r8,
+                context.r9,
+            ],
+            rbx: context.rbx,
+            rbp: context.rbp,
+            r12: context.r12,
+            r13: context.r13,
+            r14: context.r14,
+            r15: context.r15,
+            rflags: context.rflags,
+            rip: context.rip,
+            rsp: context.rsp,
+        };
+    }
+}
+
+/// Handles one syscall. The return value is placed in RAX; errors are negated error numbers.
+pub type SyscallHandler = fn(&mut SyscallFrame) -> i64;
+
+const SYSCALL_COUNT: usize = 31;
+
+/// Handlers indexed by syscall number.
+static SYSCALL_TABLE: [Option<SyscallHandler>; SYSCALL_COUNT] = {
+    let mut table: [Option<SyscallHandler>; SYSCALL_COUNT] = [None; SYSCALL_COUNT];
+    table[number::WRITE as usize] = Some(io::sys_write);
+    table[number::EXIT as usize] = Some(process::sys_exit);
+    table[number::GETPID as usize] = Some(process::sys_getpid);
+    table[number::SETRLIMIT as usize] = Some(process::sys_setrlimit);
+    table[number::GETRLIMIT as usize] = Some(process::sys_getrlimit);
+    table[number::FORK as usize] = Some(process::sys_fork);
+    table[number::EXECVE as usize] = Some(process::sys_execve);
+    table[number::WAIT as usize] = Some(process::sys_wait);
+    table[number::LOG as usize] = Some(io::sys_log);
+    table[number::BRK as usize] = Some(mm::sys_brk);
+    table[number::MMAP as usize] = Some(mm::sys_mmap);
+    table[number::MUNMAP as usize] = Some(mm::sys_munmap);
+    table[number::TRACE as usize] = Some(process::sys_trace);
+    table[number::KILL as usize] = Some(process::sys_kill);
+    table[number::SIGACTION as usize] = Some(process::sys_sigaction);
+    table[number::SIGRETURN as usize] = Some(process::sys_sigreturn);
+    table[number::READ as usize] = Some(io::sys_read);
+    table[number::OPEN as usize] = Some(io::sys_open);
+    table[number::CLOSE as usize] = Some(io::sys_close);
+    table[number::WRITEV as usize] = Some(io::sys_writev);
+    table[number::SUBMIT as usize] = Some(io::sys_submit);
+    table[number::ARCH_PRCTL as usize] = Some(process::sys_arch_prctl);
+    table[number::PIPE as usize] = Some(io::sys_pipe);
+    table[number::NICE as usize] = Some(process::sys_nice);
+    table[number::FUTEX as usize] = Some(process::sys_futex);
+    table[number::CLOCK_GETTIME as usize] = Some(time::sys_clock_gettime);
+    table[number::NANOSLEEP as usize] = Some(time::sys_nanosleep);
+    table[number::SYSCTL as usize] = Some(process::sys_sysctl);
+    table[number::INPUT_RING as usize] = Some(mm::sys_input_ring);
+    table[number::SURFACE_OPEN as usize] = Some(gfx::sys_surface_open);
+    table[number::SURFACE_FLUSH as usize] = Some(gfx::sys_surface_flush);
+    table
+};
+
+/// Enables SYSCALL and programs its MSRs. Requires `gdt::init`.
+pub fn init() {
+    let selectors = gdt::selectors();
+
+    // The kernel stack used for interrupts from ring 3 is also the syscall stack, aligned so that the
+    // stub's pushes leave the stack 16-byte aligned for the call into Rust.
+    let kernel_stack = gdt::tss().privilege_stack_table[0].as_u64() & !0xF;
+
+    unsafe {
+        PER_CPU.kernel_stack = kernel_stack;
+        KernelGsBase::write(VirtAddr::from_ptr(&raw const PER_CPU));
+
+        Star::write(
+            selectors.user_code_selector,
+            selectors.user_data_selector,
+            selectors.kernel_code_selector,
+            selectors.kernel_data_selector,
+        )
+        .expect("GDT layout is incompatible with SYSRET");
+        LStar::write(entry_address());
+        // Interrupts stay off until the stack has been switched; single-stepping and the direction flag
+        // must not leak into the kernel either.
+        SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG);
+
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+/// Address of the first instruction the CPU runs on `syscall`.
+pub(crate) fn entry_address() -> VirtAddr {
+    VirtAddr::from_ptr(syscall_entry as *const ())
+}
+
+/// Sets the stack syscalls switch to, e.g. the kernel stack of the process about to run.
+pub(crate) fn set_kernel_stack(top: VirtAddr) {
+    // Interrupts are off inside syscalls, so the entry stub can't observe a half-written value.
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        PER_CPU.kernel_stack = top.as_u64() & !0xF;
+    });
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn syscall_entry() {
+    naked_asm!(
+        // Swap to the kernel GS only for as long as the stack switch needs it, since nothing else in the
+        // kernel uses GS yet.
+        "swapgs",
+        "mov gs:[{user_stack}], rsp",
+        "mov rsp, gs:[{kernel_stack}]",
+        "push qword ptr gs:[{user_stack}]",
+        "swapgs",
+        // Build a `SyscallFrame`: 16 pushes keep the aligned stack aligned for the call.
+        "push rcx",
+        "push r11",
+        "push r15",
+        "push r14",
+        "push r13",
+        "push r12",
+        "push rbp",
+        "push rbx",
+        "push r9",
+        "push r8",
+        "push r10",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rax",
+        "mov rdi, rsp",
+        "call {dispatch}",
+        // RAX now holds the result, so the saved number is dropped instead of restored.
+        "add rsp, 8",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop r10",
+        "pop r8",
+        "pop r9",
+        "pop rbx",
+        "pop rbp",
+        "pop r12",
+        "pop r13",
+        "pop r14",
+        "pop r15",
+        "pop r11",
+        "pop rcx",
+        "pop rsp",
+        "sysretq",
+        user_stack = const offset_of!(PerCpu, user_stack),
+        kernel_stack = const offset_of!(PerCpu, kernel_stack),
+        dispatch = sym dispatch,
+    );
+}
+
+/// Runs the syscall in `frame` for the current process, as the entry stub does, and returns its result.
+pub extern "C" fn dispatch(frame: &mut SyscallFrame) -> i64 {
+    lockup::touch();
+
+    let handler = usize::try_from(frame.number)
+        .ok()
+        .and_then(|number| SYSCALL_TABLE.get(number).copied().flatten());
+
+    let result = match handler {
+        Some(handler) => handler(frame),
+        None => -errno::ENOSYS,
+    };
+
+    // Signals that arrived meanwhile, or that the syscall sent to the caller, are delivered on the way out.
+    if signal::has_pending() {
+        let context = signal::deliver(frame.context(result));
+        frame.set_context(&context);
+        return context.rax as i64;
+    }
+
+    result
+}
+
+/// Checks that the `len` bytes at `address` are in the user part of the caller's memory and mapped, and
+/// writable if the kernel is going to `write` them, or returns `EFAULT`. Anonymous pages the caller hasn't
+/// touched yet are mapped now, as if it had touched them. The kernel can't write read-only pages either
+/// (CR0.WP is set), and the fault it would take in ring 0 isn't recoverable.
+fn check_user_range(address: u64, len: u64, write: bool) -> Result<VirtAddr, i64> {
+    if len == 0 {
+        return VirtAddr::try_new(address).map_err(|_| -errno::EFAULT);
+    }
+
+    let end = address.checked_add(len - 1).ok_or(-errno::EFAULT)?;
+    if address < USER_START.as_u64() || end >= USER_END.as_u64() {
+        return Err(-errno::EFAULT);
+    }
+    let (start, end) = (VirtAddr::new(address), VirtAddr::new(end));
+
+    let mut pages = (start.align_down(4096u64).as_u64()..=end.as_u64()).step_by(4096);
+    let mut required = PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    if !pages.all(|page| {
+        let page = VirtAddr::new(page);
+        let flags = memory::page_flags(page).or_else(|| {
+            crate::process::handle_page_fault(page)
+                .then(|| memory::page_flags(page))
+                .flatten()
+        });
+        flags.is_some_and(|flags| flags.contains(required))
+    }) {
+        return Err(-errno::EFAULT);
+    }
+
+    Ok(start)
+}
+
+/// The `len` bytes at `address` in the caller's memory, or `EFAULT` if any of them isn't mapped.
+fn user_bytes<'a>(address: u64, len: u64) -> Result<&'a [u8], i64> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+
+    let start = check_user_range(address, len, false)?;
+    Ok(unsafe { core::slice::from_raw_parts(start.as_ptr(), len as usize) })
+}
+
+/// Like `user_bytes`, for buffers the kernel writes into.
+fn user_bytes_mut<'a>(address: u64, len: u64) -> Result<&'a mut [u8], i64> {
+    if len == 0 {
+        return Ok(&mut []);
+    }
+
+    let start = check_user_range(address, len, true)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr(), len as usize) })
+}
+
+/// Reads a `T` from the caller's memory.
+pub(crate) fn read_user<T: Copy>(address: u64) -> Result<T, i64> {
+    let start = check_user_range(address, core::mem::size_of::<T>() as u64, false)?;
+    Ok(unsafe { core::ptr::read_unaligned(start.as_ptr()) })
+}
+
+/// Writes a `T` into the caller's memory.
+pub(crate) fn write_user<T: Copy>(address: u64, value: T) -> Result<(), i64> {
+    let start = check_user_range(address, core::mem::size_of::<T>() as u64, true)?;
+    unsafe { core::ptr::write_unaligned(start.as_mut_ptr(), value) };
+    Ok(())
+}
+
+#[test_case]
+fn test_unknown_syscall_is_enosys() {
+    let mut frame = SyscallFrame {
+        number: 1000,
+        ..SyscallFrame::default()
+    };
+
+    assert_eq!(dispatch(&mut frame), -errno::ENOSYS);
+}
+
+#[test_case]
+fn test_arch_prctl_needs_a_known_code_and_a_process() {
+    let mut frame = SyscallFrame {
+        number: number::ARCH_PRCTL,
+        args: [0, 0x1000, 0, 0, 0, 0],
+        ..SyscallFrame::default()
+    };
+
+    assert_eq!(dispatch(&mut frame), -errno::EINVAL);
+
+    frame.args[0] = libsys::prctl::ARCH_SET_FS;
+    assert_eq!(dispatch(&mut frame), -errno::ESRCH);
+}
+
+#[test_case]
+fn test_nice_needs_a_process() {
+    let mut frame = SyscallFrame {
+        number: number::NICE,
+        args: [5, 0, 0, 0, 0, 0],
+        ..SyscallFrame::default()
+    };
+
+    assert_eq!(dispatch(&mut frame), -errno::ESRCH);
+}
@@ -0,0 +1,107 @@
+use bootloader_api::info::PixelFormat;
+use libsys::{
+    errno,
+    gfx::{self, MAX_DAMAGE_RECTS, SurfaceInfo},
+};
+
+use super::{SyscallFrame, read_user, write_user};
+use crate::{
+    framebuffer::{
+        compositor::{Window, WindowError},
+        gfx::Rect,
+    },
+    process::{self, SURFACE_ADDRESS, SurfaceError},
+};
+
+/// Where windows shared with processes go in the stack of windows.
+const SURFACE_Z: i32 = 1;
+
+fn rect(rect: gfx::Rect) -> Rect {
+    Rect::new(
+        rect.x as usize,
+        rect.y as usize,
+        rect.width as usize,
+        rect.height as usize,
+    )
+}
+
+/// `surface_open(rect, info)`: opens a window at the `gfx::Rect` at `rect` and shares it with the caller.
+/// Writes the layout of its pixels to `info` and returns where they are mapped.
+pub(super) fn sys_surface_open(frame: &mut SyscallFrame) -> i64 {
+    let [rect_address, info_address, ..] = frame.args;
+
+    let requested: gfx::Rect = match read_user(rect_address) {
+        Ok(rect) => rect,
+        Err(error) => return error,
+    };
+    let Some(pid) = process::current() else {
+        return -errno::ESRCH;
+    };
+    if process::with_process(pid, |process| process.surface().is_some()).unwrap_or(false) {
+        return -errno::EBUSY;
+    }
+
+    let window = match Window::open(rect(requested), SURFACE_Z) {
+        Ok(window) => window,
+        Err(WindowError::NoBackBuffer) => return -errno::ENODEV,
+        Err(WindowError::TooManyWindows) => return -errno::EBUSY,
+        Err(WindowError::InvalidSize) => return -errno::EINVAL,
+        Err(WindowError::Map(_)) => return -errno::ENOMEM,
+    };
+
+    let pixels = window.info();
+    let info = SurfaceInfo {
+        byte_len: pixels.byte_len as u64,
+        width: pixels.width as u32,
+        height: pixels.height as u32,
+        stride: pixels.stride as u32,
+        bytes_per_pixel: pixels.bytes_per_pixel as u32,
+        format: match pixels.pixel_format {
+            PixelFormat::Rgb => gfx::FORMAT_RGB,
+            PixelFormat::Bgr => gfx::FORMAT_BGR,
+            PixelFormat::U8 => gfx::FORMAT_U8,
+            _ => gfx::FORMAT_UNKNOWN,
+        },
+    };
+    if let Err(error) = write_user(info_address, info) {
+        return error;
+    }
+
+    match process::with_process(pid, |process| process.attach_surface(window)) {
+        Some(Ok(())) => SURFACE_ADDRESS.as_u64() as i64,
+        Some(Err(SurfaceError::Busy)) => -errno::EBUSY,
+        Some(Err(SurfaceError::OutOfMemory)) => -errno::ENOMEM,
+        None => -errno::ESRCH,
+    }
+}
+
+/// `surface_flush(rects, count)`: shows the `count` `gfx::Rect`s at `rects` of the caller's window.
+pub(super) fn sys_surface_flush(frame: &mut SyscallFrame) -> i64 {
+    let [address, count, ..] = frame.args;
+
+    if count > MAX_DAMAGE_RECTS as u64 {
+        return -errno::EINVAL;
+    }
+
+    let mut rects = [Rect::new(0, 0, 0, 0); MAX_DAMAGE_RECTS];
+    for (index, slot) in rects[..count as usize].iter_mut().enumerate() {
+        let offset = (index * size_of::<gfx::Rect>()) as u64;
+        match read_user(address.wrapping_add(offset)) {
+            Ok(requested) => *slot = rect(requested),
+            Err(error) => return error,
+        }
+    }
+
+    let Some(pid) = process::current() else {
+        return -errno::ESRCH;
+    };
+
+    process::with_process(pid, |process| match process.surface() {
+        Some(window) => {
+            window.flush(&rects[..count as usize]);
+            0
+        }
+        None => -errno::ENODEV,
+    })
+    .unwrap_or(-errno::ESRCH)
+}
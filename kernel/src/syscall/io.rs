@@ -0,0 +1,359 @@
+use alloc::sync::Arc;
+use core::mem::offset_of;
+use libsys::{
+    errno, fcntl,
+    uio::{IOV_MAX, IoVec, OP_READ, OP_WRITE, Submission},
+};
+
+use super::{SyscallFrame, read_user, user_bytes, user_bytes_mut, write_user};
+use crate::{
+    kmsg::{self, Level, Source},
+    process::{
+        self, File,
+        fd::{self, FdError, FileError},
+        pipe,
+        rlimit::Resource,
+        sched,
+        signal::{self, Signal},
+    },
+    time,
+};
+
+/// Outcome of a read or write.
+enum Transfer {
+    /// The syscall's result.
+    Done(i64),
+    /// The file isn't ready, see `block_on`.
+    WouldBlock(File),
+}
+
+/// The file the caller has open at `fd`. The kernel itself has no descriptor table, so its syscalls see
+/// the standard descriptors.
+fn file(fd: u64) -> Result<File, i64> {
+    let file = match process::current() {
+        Some(pid) => process::with_process(pid, |process| process.files().get(fd).cloned())
+            .ok_or(-errno::ESRCH)?,
+        None => fd::standard_file(fd),
+    };
+
+    file.ok_or(-errno::EBADF)
+}
+
+fn file_errno(error: FileError) -> i64 {
+    match error {
+        FileError::NotReadable | FileError::NotWritable => -errno::EBADF,
+        FileError::WouldBlock => -errno::EAGAIN,
+        FileError::BrokenPipe => -errno::EPIPE,
+    }
+}
+
+/// Like `file_errno`, for writes: writing to a broken pipe also sends `SIGPIPE` to the caller.
+fn write_errno(error: FileError) -> i64 {
+    if error == FileError::BrokenPipe
+        && let Some(pid) = process::current()
+    {
+        let _ = signal::send(pid, Signal::PIPE);
+    }
+
+    file_errno(error)
+}
+
+/// Blocks the caller until `file` may be ready for the read or write it would block on, then makes the
+/// syscall in `frame` again. A caller waiting to read is treated as interactive, see `sched`. Files
+/// nobody would wake the caller for, like the serial port, and callers that aren't processes get `EAGAIN`
+/// instead.
+fn block_on(file: File, write: bool, frame: &SyscallFrame) -> i64 {
+    let Some(waiters) = file.waiters(write) else {
+        return -errno::EAGAIN;
+    };
+    if process::current().is_none() {
+        return -errno::EAGAIN;
+    }
+
+    if !write {
+        sched::waits_for_input();
+    }
+    waiters.add_current_process();
+    // `block_current` doesn't return, so nothing may be held when it is called. The caller's descriptor
+    // table keeps the file alive, and with interrupts disabled nothing else runs until the process is
+    // blocked.
+    drop(file);
+    process::block_current(frame.restart_context());
+}
+
+/// `read(fd, buffer, len)`: reads from an open file into the caller's buffer. Blocks while a pipe has
+/// nothing to read.
+pub(super) fn sys_read(frame: &mut SyscallFrame) -> i64 {
+    let [fd, buffer, len, ..] = frame.args;
+
+    match read(fd, buffer, len) {
+        Transfer::Done(result) => result,
+        Transfer::WouldBlock(file) => block_on(file, false, frame),
+    }
+}
+
+fn read(fd: u64, buffer: u64, len: u64) -> Transfer {
+    let file = match file(fd) {
+        Ok(file) => file,
+        Err(error) => return Transfer::Done(error),
+    };
+    let buffer = match user_bytes_mut(buffer, len) {
+        Ok(buffer) => buffer,
+        Err(error) => return Transfer::Done(error),
+    };
+
+    match file.read(buffer) {
+        Ok(read) => Transfer::Done(read as i64),
+        Err(FileError::WouldBlock) => Transfer::WouldBlock(file),
+        Err(error) => Transfer::Done(file_errno(error)),
+    }
+}
+
+/// `write(fd, buffer, len)`: writes the caller's buffer to an open file. Blocks while a pipe has no room,
+/// and otherwise writes as much as there is room for.
+pub(super) fn sys_write(frame: &mut SyscallFrame) -> i64 {
+    let [fd, buffer, len, ..] = frame.args;
+
+    match write(fd, buffer, len) {
+        Transfer::Done(result) => result,
+        Transfer::WouldBlock(file) => block_on(file, true, frame),
+    }
+}
+
+fn write(fd: u64, buffer: u64, len: u64) -> Transfer {
+    let file = match file(fd) {
+        Ok(file) => file,
+        Err(error) => return Transfer::Done(error),
+    };
+    let bytes = match user_bytes(buffer, len) {
+        Ok(bytes) => bytes,
+        Err(error) => return Transfer::Done(error),
+    };
+
+    match file.write(bytes) {
+        Ok(written) => Transfer::Done(written as i64),
+        Err(FileError::WouldBlock) => Transfer::WouldBlock(file),
+        Err(error) => Transfer::Done(write_errno(error)),
+    }
+}
+
+/// `writev(fd, buffers, count)`: writes `count` `IoVec` buffers one after the other, stopping at the first
+/// partial write. Errors are only reported if nothing was written, and it only blocks if nothing could be
+/// written yet.
+pub(super) fn sys_writev(frame: &mut SyscallFrame) -> i64 {
+    let [fd, buffers, count, ..] = frame.args;
+
+    if count > IOV_MAX as u64 {
+        return -errno::EINVAL;
+    }
+    let file = match file(fd) {
+        Ok(file) => file,
+        Err(error) => return error,
+    };
+
+    let mut total = 0;
+    for index in 0..count {
+        let address = buffers + index * size_of::<IoVec>() as u64;
+        let written = read_user::<IoVec>(address).and_then(|buffer| {
+            let bytes = user_bytes(buffer.base, buffer.len)?;
+            Ok((file.write(bytes), bytes.len()))
+        });
+
+        match written {
+            Ok((Ok(written), len)) => {
+                total += written as i64;
+                if written < len {
+                    break;
+                }
+            }
+            Ok((Err(FileError::WouldBlock), _)) if total == 0 => {
+                return block_on(file, true, frame);
+            }
+            Ok((Err(error), _)) if total == 0 => return write_errno(error),
+            Err(error) if total == 0 => return error,
+            Ok((Err(_), _)) | Err(_) => break,
+        }
+    }
+
+    total
+}
+
+/// `submit(entries, count)`: runs `count` `Submission` entries in order, storing the result of each in
+/// the entry. Returns the number of entries run, which is all of them unless one can't be accessed.
+/// Entries never block: one that would gets `EAGAIN`.
+pub(super) fn sys_submit(frame: &mut SyscallFrame) -> i64 {
+    let [entries, count, ..] = frame.args;
+
+    if count > IOV_MAX as u64 {
+        return -errno::EINVAL;
+    }
+
+    for index in 0..count {
+        let address = entries + index * size_of::<Submission>() as u64;
+        let entry = match read_user::<Submission>(address) {
+            Ok(entry) => entry,
+            Err(error) if index == 0 => return error,
+            Err(_) => return index as i64,
+        };
+
+        let transfer = match entry.op {
+            OP_READ => read(entry.fd, entry.address, entry.len),
+            OP_WRITE => write(entry.fd, entry.address, entry.len),
+            _ => Transfer::Done(-errno::EINVAL),
+        };
+        let result = match transfer {
+            Transfer::Done(result) => result,
+            Transfer::WouldBlock(_) => -errno::EAGAIN,
+        };
+
+        let result_address = address + offset_of!(Submission, result) as u64;
+        if let Err(error) = write_user(result_address, result) {
+            return if index == 0 { error } else { index as i64 };
+        }
+    }
+
+    count as i64
+}
+
+/// `open(path, len, mode)`: opens a file, returning the lowest free descriptor.
+pub(super) fn sys_open(frame: &mut SyscallFrame) -> i64 {
+    let [path, len, mode, ..] = frame.args;
+
+    let Some(pid) = process::current() else {
+        return -errno::ESRCH;
+    };
+    let path = match user_bytes(path, len) {
+        Ok(path) => path,
+        Err(error) => return error,
+    };
+    let Ok(path) = core::str::from_utf8(path) else {
+        return -errno::EINVAL;
+    };
+    let write = match mode {
+        fcntl::O_RDONLY => false,
+        fcntl::O_WRONLY | fcntl::O_RDWR => true,
+        _ => return -errno::EINVAL,
+    };
+
+    let Some(file) = File::open(path) else {
+        return -errno::ENOENT;
+    };
+    if write && !file.is_writable() {
+        return -errno::EROFS;
+    }
+
+    match process::with_process(pid, |process| process.open_file(file)) {
+        Some(Ok(fd)) => fd as i64,
+        Some(Err(FdError::TooManyOpen)) => -errno::EMFILE,
+        Some(Err(FdError::BadDescriptor)) => -errno::EBADF,
+        None => -errno::ESRCH,
+    }
+}
+
+/// `close(fd)`: closes an open descriptor.
+pub(super) fn sys_close(frame: &mut SyscallFrame) -> i64 {
+    let [fd, ..] = frame.args;
+
+    let Some(pid) = process::current() else {
+        return -errno::ESRCH;
+    };
+
+    // The file is dropped outside the process table lock.
+    match process::with_process(pid, |process| process.close_file(fd)) {
+        Some(Ok(_file)) => 0,
+        Some(Err(_)) => -errno::EBADF,
+        None => -errno::ESRCH,
+    }
+}
+
+/// `pipe(*mut [u64; 2])`: creates a pipe and stores the descriptors of its read and write ends.
+pub(super) fn sys_pipe(frame: &mut SyscallFrame) -> i64 {
+    let [fds, ..] = frame.args;
+
+    let Some(pid) = process::current() else {
+        return -errno::ESRCH;
+    };
+
+    let (reader, writer) = pipe::pipe();
+    let reader = File::PipeReader(Arc::new(reader));
+    let writer = File::PipeWriter(Arc::new(writer));
+
+    // Nobody waits on the new pipe yet, so its ends can be dropped under the process table lock.
+    let opened = process::with_process(pid, |process| {
+        let read_fd = process.open_file(reader)?;
+        match process.open_file(writer) {
+            Ok(write_fd) => Ok([read_fd, write_fd]),
+            Err(error) => {
+                let _ = process.close_file(read_fd);
+                Err(error)
+            }
+        }
+    });
+
+    let descriptors = match opened {
+        Some(Ok(opened)) => opened,
+        Some(Err(FdError::TooManyOpen)) => return -errno::EMFILE,
+        Some(Err(FdError::BadDescriptor)) => return -errno::EBADF,
+        None => return -errno::ESRCH,
+    };
+
+    match write_user(fds, descriptors) {
+        Ok(()) => 0,
+        Err(error) => {
+            // Closed outside the process table lock, like in `sys_close`.
+            let closed =
+                process::with_process(pid, |process| descriptors.map(|fd| process.close_file(fd)));
+            drop(closed);
+            error
+        }
+    }
+}
+
+/// `log(level, message, len)`: appends a record tagged with the caller's PID to the kernel log.
+pub(super) fn sys_log(frame: &mut SyscallFrame) -> i64 {
+    let [level, message, len, ..] = frame.args;
+
+    let Some(level) = Level::from_number(level) else {
+        return -errno::EINVAL;
+    };
+    let message = match user_bytes(message, len) {
+        Ok(message) => message,
+        Err(error) => return error,
+    };
+    let Ok(message) = core::str::from_utf8(message) else {
+        return -errno::EINVAL;
+    };
+    let Some(pid) = process::current() else {
+        return -errno::ESRCH;
+    };
+
+    let admitted = process::with_process(pid, |process| {
+        let per_second = process.limits().get(Resource::LogRate).soft;
+        if per_second == 0 {
+            return Err(-errno::EPERM);
+        }
+
+        process
+            .log_limiter_mut()
+            .admit(time::uptime_nanos(), per_second)
+            .ok_or(-errno::EAGAIN)
+    });
+
+    let suppressed = match admitted {
+        Some(Ok(suppressed)) => suppressed,
+        Some(Err(error)) => return error,
+        None => return -errno::ESRCH,
+    };
+
+    let source = Source::Process(pid);
+    if suppressed > 0 {
+        kmsg::log(
+            Level::Warning,
+            source,
+            format_args!("{} records suppressed", suppressed),
+        );
+    }
+    kmsg::log(level, source, format_args!("{}", message));
+
+    0
+}
@@ -0,0 +1,108 @@
+use libsys::{
+    errno,
+    mman::{MAP_ANONYMOUS, MAP_PRIVATE, PROT_EXEC, PROT_READ, PROT_WRITE},
+};
+use x86_64::{
+    VirtAddr,
+    structures::paging::{Page, PageTableFlags},
+};
+
+use super::SyscallFrame;
+use crate::{
+    input::ring::{self, RingError},
+    memory::address_space::{USER_END, USER_START},
+    process::{self, HeapError},
+};
+
+/// `brk(address)`: moves the end of the heap and returns the new end, or the old one if it can't move
+/// there. An address of 0 only queries the end.
+pub(super) fn sys_brk(frame: &mut SyscallFrame) -> i64 {
+    let address = frame.args[0];
+
+    let Some(pid) = process::current() else {
+        return -errno::ESRCH;
+    };
+
+    process::with_process(pid, |process| {
+        if let Ok(end) = VirtAddr::try_new(address)
+            && address != 0
+        {
+            let _ = process.set_brk(end);
+        }
+
+        process.brk().as_u64() as i64
+    })
+    .unwrap_or(-errno::ESRCH)
+}
+
+/// `mmap(address, len, prot, flags, fd, offset)`: maps zeroed memory and returns its address. Only
+/// private anonymous mappings are supported; `address`, `fd` and `offset` are ignored.
+pub(super) fn sys_mmap(frame: &mut SyscallFrame) -> i64 {
+    let [_, len, prot, flags, ..] = frame.args;
+
+    if flags & MAP_ANONYMOUS == 0 {
+        // Files can't be mapped yet.
+        return -errno::ENODEV;
+    }
+    if flags != MAP_PRIVATE | MAP_ANONYMOUS
+        || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0
+        || len == 0
+    {
+        return -errno::EINVAL;
+    }
+
+    let mut page_flags = PageTableFlags::empty();
+    if prot & PROT_WRITE != 0 {
+        page_flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        page_flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    let Some(pid) = process::current() else {
+        return -errno::ESRCH;
+    };
+
+    match process::with_process(pid, |process| {
+        process.map_anonymous(len.div_ceil(4096), page_flags)
+    }) {
+        Some(Ok(address)) => address.as_u64() as i64,
+        Some(Err(HeapError::LimitExceeded | HeapError::NoRoom)) => -errno::ENOMEM,
+        None => -errno::ESRCH,
+    }
+}
+
+/// `input_ring()`: maps the input ring into the caller and returns its address, see `input::ring`.
+pub(super) fn sys_input_ring(_frame: &mut SyscallFrame) -> i64 {
+    match ring::attach() {
+        Ok(address) => address.as_u64() as i64,
+        Err(RingError::Busy) => -errno::EBUSY,
+        Err(RingError::NotPermitted) => -errno::EPERM,
+        Err(RingError::NoProcess) => -errno::ESRCH,
+        Err(RingError::OutOfMemory) => -errno::ENOMEM,
+    }
+}
+
+/// `munmap(address, len)`: unmaps the anonymous pages in the range. `address` must be page-aligned.
+pub(super) fn sys_munmap(frame: &mut SyscallFrame) -> i64 {
+    let [address, len, ..] = frame.args;
+
+    let end = address.checked_add(len.next_multiple_of(4096));
+    let (Some(end), true) = (end, address.is_multiple_of(4096) && len > 0) else {
+        return -errno::EINVAL;
+    };
+    if address < USER_START.as_u64() || end > USER_END.as_u64() {
+        return -errno::EINVAL;
+    }
+
+    let Some(pid) = process::current() else {
+        return -errno::ESRCH;
+    };
+
+    let pages = Page::range(
+        Page::containing_address(VirtAddr::new(address)),
+        Page::containing_address(VirtAddr::new(end)),
+    );
+    process::with_process(pid, |process| process.unmap_anonymous(pages))
+        .map_or(-errno::ESRCH, |()| 0)
+}
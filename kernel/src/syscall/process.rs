@@ -0,0 +1,358 @@
+use libsys::errno;
+use x86_64::{VirtAddr, registers::rflags::RFlags};
+
+use super::{SyscallFrame, read_user, user_bytes, write_user};
+use crate::{
+    initrd, klog,
+    process::{
+        self, Pid, SpawnError, WaitStatus,
+        futex::{self, FutexError},
+        rlimit::{LimitError, Resource, Rlimit},
+        sched,
+        signal::{self, Action, Signal, SignalError},
+        tls::{self, Segment, TlsError},
+        trace::{self, TraceError},
+    },
+    serial_println,
+};
+
+/// Added to nice values returned by `nice`, which are in -20..=19.
+const NICE_OFFSET: i64 = 20;
+
+fn spawn_errno(error: SpawnError) -> i64 {
+    match error {
+        SpawnError::LimitExceeded(_) | SpawnError::TooManyProcesses => -errno::EAGAIN,
+        SpawnError::OutOfMemory => -errno::ENOMEM,
+        SpawnError::InvalidImage(_) => -errno::ENOEXEC,
+    }
+}
+
+/// `exit(code)`: frees the address space and leaves a zombie for the parent to reap.
+pub(super) fn sys_exit(frame: &mut SyscallFrame) -> i64 {
+    let code = frame.args[0] as i32;
+
+    if let Some(pid) = process::current() {
+        klog!(Info, "process {} exited with code {}", pid, code);
+    }
+    process::exit_current(code);
+    process::schedule();
+}
+
+/// `getpid()`.
+pub(super) fn sys_getpid(_frame: &mut SyscallFrame) -> i64 {
+    process::current().map_or(0, |pid| pid.as_u64() as i64)
+}
+
+/// `setrlimit(resource, *const Rlimit)`.
+pub(super) fn sys_setrlimit(frame: &mut SyscallFrame) -> i64 {
+    let [resource, limit, ..] = frame.args;
+
+    let Some(resource) = Resource::from_number(resource) else {
+        return -errno::EINVAL;
+    };
+    let limit: Rlimit = match read_user(limit) {
+        Ok(limit) => limit,
+        Err(error) => return error,
+    };
+    let Some(pid) = process::current() else {
+        return -errno::ESRCH;
+    };
+
+    match process::with_process(pid, |process| process.limits_mut().set(resource, limit)) {
+        Some(Ok(())) => 0,
+        Some(Err(LimitError::SoftAboveHard)) => -errno::EINVAL,
+        Some(Err(LimitError::RaisesHardLimit)) => -errno::EPERM,
+        None => -errno::ESRCH,
+    }
+}
+
+/// `getrlimit(resource, *mut Rlimit)`.
+pub(super) fn sys_getrlimit(frame: &mut SyscallFrame) -> i64 {
+    let [resource, limit, ..] = frame.args;
+
+    let Some(resource) = Resource::from_number(resource) else {
+        return -errno::EINVAL;
+    };
+    let Some(current) = process::current()
+        .and_then(|pid| process::with_process(pid, |process| process.limits().get(resource)))
+    else {
+        return -errno::ESRCH;
+    };
+
+    match write_user(limit, current) {
+        Ok(()) => 0,
+        Err(error) => error,
+    }
+}
+
+/// `fork()`: the child resumes from the same syscall with a result of 0.
+pub(super) fn sys_fork(frame: &mut SyscallFrame) -> i64 {
+    if process::current().is_none() {
+        return -errno::ESRCH;
+    }
+
+    match process::fork(frame.context(0)) {
+        Ok(child) => child.as_u64() as i64,
+        Err(error) => spawn_errno(error),
+    }
+}
+
+/// `execve(path, len)`: runs the executable at `path` in the initrd. Arguments and environment aren't
+/// supported yet.
+pub(super) fn sys_execve(frame: &mut SyscallFrame) -> i64 {
+    let [path, len, ..] = frame.args;
+
+    if process::current().is_none() {
+        return -errno::ESRCH;
+    }
+
+    let path = match user_bytes(path, len) {
+        Ok(path) => path,
+        Err(error) => return error,
+    };
+    let Ok(path) = core::str::from_utf8(path) else {
+        return -errno::EINVAL;
+    };
+    let Some(image) = initrd::find(path) else {
+        return -errno::ENOENT;
+    };
+
+    match process::exec(image) {
+        Ok(context) => {
+            frame.set_context(&context);
+            // Returned in RAX, which the new program doesn't expect anything in.
+            0
+        }
+        Err(error) => spawn_errno(error),
+    }
+}
+
+/// `wait(pid, *mut i32)`: reaps the child `pid`, or any child if `pid` is 0, blocking until it exits.
+/// Returns the child's PID and stores its exit code unless the pointer is null.
+pub(super) fn sys_wait(frame: &mut SyscallFrame) -> i64 {
+    let [child, exit_code, ..] = frame.args;
+
+    let Some(pid) = process::current() else {
+        return -errno::ESRCH;
+    };
+
+    match process::wait(pid, Pid::from_u64(child)) {
+        WaitStatus::Reaped(child, code) => {
+            if exit_code != 0
+                && let Err(error) = write_user(exit_code, code)
+            {
+                return error;
+            }
+
+            child.as_u64() as i64
+        }
+        WaitStatus::Running => process::block_current(frame.restart_context()),
+        WaitStatus::NoChildren => -errno::ECHILD,
+    }
+}
+
+/// `trace(pid, start, end)`: single-steps the process `pid` (the caller if 0), which must be the caller or
+/// its child, recording the instructions in `start..end`. An empty range stops tracing and writes the
+/// recorded steps of the process to the serial port.
+pub(super) fn sys_trace(frame: &mut SyscallFrame) -> i64 {
+    let [pid, start, end, ..] = frame.args;
+
+    let Some(caller) = process::current() else {
+        return -errno::ESRCH;
+    };
+    let pid = Pid::from_u64(pid).unwrap_or(caller);
+
+    let result = if start < end {
+        let (Ok(start), Ok(end)) = (VirtAddr::try_new(start), VirtAddr::try_new(end)) else {
+            return -errno::EINVAL;
+        };
+        trace::start(caller, pid, start..end)
+    } else {
+        trace::stop(caller, pid).inspect(|()| {
+            trace::read(0, |step| {
+                if step.pid() == pid {
+                    serial_println!("{}", step);
+                }
+            });
+        })
+    };
+
+    match result {
+        Ok(()) => {
+            // The caller returns through SYSRET, which takes RFLAGS from the frame instead of the process.
+            if pid == caller {
+                if start < end {
+                    frame.rflags |= RFlags::TRAP_FLAG.bits();
+                } else {
+                    frame.rflags &= !RFlags::TRAP_FLAG.bits();
+                }
+            }
+            0
+        }
+        Err(TraceError::NoSuchProcess) => -errno::ESRCH,
+        Err(TraceError::NotPermitted) => -errno::EPERM,
+    }
+}
+
+/// `kill(pid, signal)`: sends `signal` to the process `pid`. A signal of 0 only checks that the process
+/// exists.
+pub(super) fn sys_kill(frame: &mut SyscallFrame) -> i64 {
+    let [pid, number, ..] = frame.args;
+
+    let Some(pid) = Pid::from_u64(pid) else {
+        return -errno::ESRCH;
+    };
+    if number == 0 {
+        let alive = process::with_process(pid, |process| {
+            !matches!(process.state(), process::State::Zombie { .. })
+        });
+        return if alive == Some(true) {
+            0
+        } else {
+            -errno::ESRCH
+        };
+    }
+    let Some(signal) = Signal::from_number(number) else {
+        return -errno::EINVAL;
+    };
+
+    match signal::send(pid, signal) {
+        Ok(()) => 0,
+        Err(_) => -errno::ESRCH,
+    }
+}
+
+/// `sigaction(signal, handler, restorer)`: sets what `signal` does to the caller. The handler is
+/// `SIG_DFL`, `SIG_IGN` or the address of a function taking the signal number, which returns into
+/// `restorer`; the restorer must call `sigreturn`.
+pub(super) fn sys_sigaction(frame: &mut SyscallFrame) -> i64 {
+    let [number, handler, restorer, ..] = frame.args;
+
+    let Some(signal) = Signal::from_number(number) else {
+        return -errno::EINVAL;
+    };
+    let action = match handler {
+        libsys::signal::SIG_DFL => Action::Default,
+        libsys::signal::SIG_IGN => Action::Ignore,
+        handler => Action::Handler { handler, restorer },
+    };
+    let Some(pid) = process::current() else {
+        return -errno::ESRCH;
+    };
+
+    match signal::set_action(pid, signal, action) {
+        Ok(_) => 0,
+        Err(SignalError::Uncatchable) => -errno::EINVAL,
+        Err(SignalError::NoSuchProcess) => -errno::ESRCH,
+    }
+}
+
+/// `sigreturn()`: called by the restorer once a signal handler returns, resumes the caller with the
+/// registers it had before the signal.
+pub(super) fn sys_sigreturn(frame: &mut SyscallFrame) -> i64 {
+    if process::current().is_none() {
+        return -errno::ESRCH;
+    }
+
+    let Some(context) = signal::saved_context(frame.rsp) else {
+        signal::terminate_current(Signal::SEGV);
+    };
+
+    frame.set_context(&context);
+    context.rax as i64
+}
+
+/// `arch_prctl(code, address)`: sets the FS or GS base to `address`, or stores it at `address`.
+pub(super) fn sys_arch_prctl(frame: &mut SyscallFrame) -> i64 {
+    use libsys::prctl::{ARCH_GET_FS, ARCH_GET_GS, ARCH_SET_FS, ARCH_SET_GS};
+
+    let [code, address, ..] = frame.args;
+
+    let segment = match code {
+        ARCH_SET_FS | ARCH_GET_FS => Segment::Fs,
+        ARCH_SET_GS | ARCH_GET_GS => Segment::Gs,
+        _ => return -errno::EINVAL,
+    };
+
+    let result = match code {
+        ARCH_SET_FS | ARCH_SET_GS => tls::set(segment, address),
+        _ => match tls::get(segment) {
+            Ok(base) => return write_user(address, base).map_or_else(|error| error, |()| 0),
+            Err(error) => Err(error),
+        },
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(TlsError::NoProcess) => -errno::ESRCH,
+        Err(TlsError::InvalidBase) => -errno::EPERM,
+    }
+}
+
+/// `nice(increment)`: adds `increment` to the caller's nice value, clamped to -20..=19. Returns the new
+/// nice value plus 20, so that it can't be mistaken for an error.
+pub(super) fn sys_nice(frame: &mut SyscallFrame) -> i64 {
+    let increment = frame.args[0] as i64;
+
+    match sched::nice(increment) {
+        Some(nice) => i64::from(nice) + NICE_OFFSET,
+        None => -errno::ESRCH,
+    }
+}
+
+/// `sysctl(name, set, value)`: returns the kernel setting `name`, after changing it to `value` if `set`
+/// isn't 0.
+pub(super) fn sys_sysctl(frame: &mut SyscallFrame) -> i64 {
+    let [name, set, value, ..] = frame.args;
+
+    match name {
+        libsys::sysctl::SCHED_SLICE_TICKS => {
+            let old = sched::slice_ticks();
+            if set != 0 {
+                let Ok(ticks) = u32::try_from(value) else {
+                    return -errno::EINVAL;
+                };
+                if sched::set_slice_ticks(ticks).is_err() {
+                    return -errno::EINVAL;
+                }
+            }
+            i64::from(old)
+        }
+        _ => -errno::EINVAL,
+    }
+}
+
+/// `futex(address, op, value)`: with `FUTEX_WAIT`, blocks until woken if the word at `address` still
+/// holds `value`, and fails with `EAGAIN` otherwise. With `FUTEX_WAKE`, wakes up to `value` waiters and
+/// returns how many were woken.
+pub(super) fn sys_futex(frame: &mut SyscallFrame) -> i64 {
+    use libsys::futex::{FUTEX_WAIT, FUTEX_WAKE};
+
+    let [address, op, value, ..] = frame.args;
+
+    let result = match op {
+        FUTEX_WAIT => {
+            let word: u32 = match read_user(address) {
+                Ok(word) => word,
+                Err(error) => return error,
+            };
+            if u64::from(word) != value {
+                return -errno::EAGAIN;
+            }
+
+            match futex::add_waiter(address) {
+                // Woken waiters return 0 rather than restarting, which would compare the word again.
+                Ok(()) => process::block_current(frame.context(0)),
+                Err(error) => Err(error),
+            }
+        }
+        FUTEX_WAKE => futex::wake_waiters(address, value).map(|woken| woken as i64),
+        _ => return -errno::EINVAL,
+    };
+
+    match result {
+        Ok(woken) => woken,
+        Err(FutexError::NoProcess) => -errno::ESRCH,
+        Err(FutexError::Unaligned) => -errno::EINVAL,
+    }
+}
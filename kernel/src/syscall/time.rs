@@ -0,0 +1,45 @@
+use libsys::errno;
+
+use super::{SyscallFrame, read_user, write_user};
+use crate::{
+    process::{
+        self,
+        sleep::{self, SleepError},
+    },
+    time::{self, ClockError, ClockId, Timespec},
+};
+
+/// `clock_gettime(clock, timespec)`: writes the time of `clock` to `timespec`.
+pub(super) fn sys_clock_gettime(frame: &mut SyscallFrame) -> i64 {
+    let [clock, address, ..] = frame.args;
+
+    let timespec = match ClockId::try_from(clock).and_then(time::clock_gettime) {
+        Ok(timespec) => timespec,
+        Err(ClockError::InvalidClock) => return -errno::EINVAL,
+        Err(ClockError::WallClockUnset) => return -errno::ENODEV,
+    };
+
+    write_user(address, timespec).map_or_else(|error| error, |()| 0)
+}
+
+/// `nanosleep(duration)`: blocks the caller for at least the `Timespec` at `duration`. Returns 0 once it
+/// has passed and `EINTR` if the process is woken earlier.
+pub(super) fn sys_nanosleep(frame: &mut SyscallFrame) -> i64 {
+    let timespec: Timespec = match read_user(frame.args[0]) {
+        Ok(timespec) => timespec,
+        Err(error) => return error,
+    };
+    let Some(duration) = timespec.to_duration() else {
+        return -errno::EINVAL;
+    };
+
+    if duration.is_zero() {
+        return 0;
+    }
+
+    match sleep::add_sleeper(time::ticks().saturating_add(time::ticks_for(duration))) {
+        // The sleeper sets RAX to 0 when it wakes the process at its deadline.
+        Ok(()) => process::block_current(frame.context(-errno::EINTR)),
+        Err(SleepError::NoProcess) => -errno::ESRCH,
+    }
+}
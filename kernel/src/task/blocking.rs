@@ -0,0 +1,92 @@
+//! Running blocking operations on kernel threads, see `spawn_blocking`.
+//!
+//! Jobs wait in a queue for a pool of worker threads, which are spawned as jobs come in, up to
+//! `MAX_WORKERS`, and then sleep while there is nothing to do. Threads are scheduled cooperatively, so a
+//! job only lets the executor run again when it blocks, yields or returns: one that polls a device
+//! should call `thread::yield_now` between polls. A job that panics still takes down the kernel.
+
+use alloc::{boxed::Box, collections::VecDeque};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use spin::Mutex;
+
+use super::channel::oneshot;
+use crate::{sync::WaitQueue, thread};
+
+/// Most worker threads there will be. More jobs than that wait for a worker to finish.
+pub const MAX_WORKERS: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+static JOBS: Mutex<VecDeque<Job>> = Mutex::new(VecDeque::new());
+/// Idle workers wait here for jobs.
+static JOBS_QUEUED: WaitQueue = WaitQueue::new();
+static WORKERS: AtomicUsize = AtomicUsize::new(0);
+static IDLE_WORKERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs `function` on a worker thread and returns a future that resolves to its result, so tasks can
+/// wait for synchronous operations, like PIO disk reads, without keeping the executor from running.
+///
+/// Allocates, so it can't be called from interrupt handlers.
+pub fn spawn_blocking<T: Send + 'static>(
+    function: impl FnOnce() -> T + Send + 'static,
+) -> BlockingHandle<T> {
+    let (sender, receiver) = oneshot::channel();
+    JOBS.lock().push_back(Box::new(move || {
+        // Nobody waits for the result if the handle was dropped.
+        let _ = sender.send(function());
+    }));
+
+    if IDLE_WORKERS.load(Ordering::Acquire) == 0 && WORKERS.load(Ordering::Relaxed) < MAX_WORKERS {
+        WORKERS.fetch_add(1, Ordering::Relaxed);
+        thread::spawn(work);
+    } else {
+        JOBS_QUEUED.wake_one();
+    }
+
+    BlockingHandle { receiver }
+}
+
+/// Number of worker threads spawned so far.
+pub fn worker_count() -> usize {
+    WORKERS.load(Ordering::Relaxed)
+}
+
+/// Entry point of the worker threads, which run jobs until the kernel stops.
+fn work() {
+    loop {
+        let mut job = None;
+
+        IDLE_WORKERS.fetch_add(1, Ordering::Release);
+        JOBS_QUEUED.wait_until(|| {
+            job = JOBS.lock().pop_front();
+            job.is_some()
+        });
+        IDLE_WORKERS.fetch_sub(1, Ordering::Release);
+
+        if let Some(job) = job {
+            job();
+        }
+    }
+}
+
+/// Resolves to the result of a function passed to `spawn_blocking`. Dropping it doesn't stop the
+/// function, whose result is then dropped.
+pub struct BlockingHandle<T> {
+    receiver: oneshot::Receiver<T>,
+}
+
+impl<T> Future for BlockingHandle<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<T> {
+        Pin::new(&mut self.receiver)
+            .poll(context)
+            // Jobs are never dropped without running, and a panicking one halts the kernel.
+            .map(|result| result.expect("blocking job dropped"))
+    }
+}
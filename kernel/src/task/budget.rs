@@ -0,0 +1,77 @@
+//! Cooperative scheduling: `yield_now` and the poll budget.
+//!
+//! Tasks are only switched when they return `Pending`, so a task that loops over a channel that always
+//! has values ready would keep the executor forever. To prevent that, the `Executor` gives each poll a
+//! budget of operations. Futures that can be ready again and again, like `mpsc::Receiver`, spend a unit
+//! each time they are ready, and once the budget is spent they return `Pending` instead and wake their
+//! task, which puts it at the back of the queue behind the other woken tasks.
+//!
+//! Outside of an executor's poll, e.g. under the `SimpleExecutor`, the budget is unlimited.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Context, Poll},
+};
+
+/// Operations a poll may do before its task is made to yield, unless the executor is told otherwise.
+pub const DEFAULT_BUDGET: u32 = 128;
+
+/// Means no budget applies.
+const UNLIMITED: u32 = u32::MAX;
+
+/// What is left of the budget of the poll in progress. Tasks only run on the boot thread, so a single
+/// counter serves every executor.
+static REMAINING: AtomicU32 = AtomicU32::new(UNLIMITED);
+
+/// Runs `poll` with `budget` operations to spend, or without limit if it is `None`.
+pub(crate) fn with_budget<R>(budget: Option<u32>, poll: impl FnOnce() -> R) -> R {
+    let outer = REMAINING.swap(budget.unwrap_or(UNLIMITED), Ordering::Relaxed);
+    let result = poll();
+    REMAINING.store(outer, Ordering::Relaxed);
+    result
+}
+
+/// Spends a unit of the budget. Once it is spent, wakes the task and returns `Pending`, which the caller
+/// returns too, instead of being ready.
+pub fn poll_proceed(context: &mut Context) -> Poll<()> {
+    let remaining = REMAINING.load(Ordering::Relaxed);
+
+    match remaining {
+        UNLIMITED => Poll::Ready(()),
+        0 => {
+            context.waker().wake_by_ref();
+            Poll::Pending
+        }
+        _ => {
+            REMAINING.store(remaining - 1, Ordering::Relaxed);
+            Poll::Ready(())
+        }
+    }
+}
+
+/// Returns `Pending` once, letting the other woken tasks run before the current one continues.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// See `yield_now`.
+#[derive(Debug)]
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+
+        self.yielded = true;
+        context.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
@@ -0,0 +1,217 @@
+//! Recovering from panics in tasks.
+//!
+//! The kernel can't unwind, so a panic normally ends in the panic handler, which halts. `catch_panic`
+//! runs a function with a way back instead: it saves the callee-saved registers and the stack pointer
+//! before calling it, and if the function panics, the panic handler calls `recover`, which records the
+//! message and restores them, so that `catch_panic` returns an error as if the function had returned.
+//!
+//! This is best effort. The frames between the two are abandoned without running any destructors: what
+//! they owned is leaked, and locks they held stay locked, so a panic while holding the allocator or a
+//! lock the rest of the kernel needs still brings it down later. Panics in interrupt handlers, and on
+//! another kernel thread than the one that called `catch_panic`, are never recovered from.
+
+use core::{
+    arch::naked_asm,
+    fmt::{self, Write},
+    panic::PanicInfo,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+use x86_64::instructions::interrupts;
+
+use crate::{interrupts::in_handler, thread};
+
+/// Bytes of a panic message that are kept; the rest is cut off.
+const MESSAGE_SIZE: usize = 112;
+
+/// The innermost `catch_panic` in progress, if any.
+static CATCHER: AtomicPtr<Catcher> = AtomicPtr::new(ptr::null_mut());
+
+/// The message of a caught panic, with its location.
+///
+/// Formatted into a fixed buffer, since the allocator may be what panicked.
+#[derive(Clone)]
+pub struct PanicMessage {
+    bytes: [u8; MESSAGE_SIZE],
+    len: usize,
+}
+
+impl PanicMessage {
+    fn new() -> Self {
+        PanicMessage {
+            bytes: [0; MESSAGE_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Truncation only happens at character boundaries, see `write_str`.
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for PanicMessage {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let mut end = text.len().min(MESSAGE_SIZE - self.len);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        self.bytes[self.len..self.len + end].copy_from_slice(&text.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+impl fmt::Display for PanicMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for PanicMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+struct Catcher {
+    /// Stack pointer saved by `call_catching`.
+    rsp: u64,
+    thread: thread::ThreadId,
+    /// The `catch_panic` this one is nested in.
+    outer: *mut Catcher,
+    message: PanicMessage,
+}
+
+/// Runs `function`, returning its result, or the panic message if it panicked.
+pub fn catch_panic<R>(function: impl FnOnce() -> R) -> Result<R, PanicMessage> {
+    let mut function = Some(function);
+    let mut result = None;
+    let mut call = || result = function.take().map(|function| function());
+
+    let mut catcher = Catcher {
+        rsp: 0,
+        thread: thread::current(),
+        outer: CATCHER.load(Ordering::Acquire),
+        message: PanicMessage::new(),
+    };
+    let interrupts_enabled = interrupts::are_enabled();
+
+    CATCHER.store(&raw mut catcher, Ordering::Release);
+    let panicked = unsafe {
+        call_catching(
+            &raw mut catcher.rsp,
+            trampoline(&call),
+            (&raw mut call).cast(),
+        )
+    };
+    CATCHER.store(catcher.outer, Ordering::Release);
+
+    if panicked {
+        // The panic may have struck while interrupts were disabled for a moment.
+        if interrupts_enabled {
+            interrupts::enable();
+        }
+        return Err(catcher.message);
+    }
+
+    Ok(result.expect("caught function didn't run"))
+}
+
+/// Called by the panic handler. Returns if no `catch_panic` can take the panic, and otherwise continues
+/// in the innermost one.
+pub fn recover(info: &PanicInfo) {
+    let catcher = CATCHER.load(Ordering::Acquire);
+    if catcher.is_null() || in_handler() {
+        return;
+    }
+
+    // Only reached from the panic handler on the thread that set the catcher, so nothing else uses it.
+    let catcher = unsafe { &mut *catcher };
+    if catcher.thread != thread::current() {
+        return;
+    }
+
+    // A panic while recovering goes to the outer catcher, if any.
+    CATCHER.store(catcher.outer, Ordering::Release);
+    let _ = write!(catcher.message, "{}", info.message());
+    if let Some(location) = info.location() {
+        let _ = write!(catcher.message, " at {}", location);
+    }
+
+    unsafe { resume(catcher.rsp) }
+}
+
+/// A function that calls a closure like `closure` through a pointer to it.
+fn trampoline<F: FnMut()>(_closure: &F) -> extern "C" fn(*mut ()) {
+    extern "C" fn call<F: FnMut()>(closure: *mut ()) {
+        unsafe { (*closure.cast::<F>())() }
+    }
+    call::<F>
+}
+
+/// Saves the callee-saved registers and the stack pointer to `saved_rsp`, then calls `function(data)`.
+/// Returns false once it returns, or true when `resume` continues here instead.
+#[unsafe(naked)]
+unsafe extern "C" fn call_catching(
+    saved_rsp: *mut u64,
+    function: extern "C" fn(*mut ()),
+    data: *mut (),
+) -> bool {
+    naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        // Realigns the stack to 16 bytes for the call.
+        "sub rsp, 8",
+        "mov [rdi], rsp",
+        "mov rdi, rdx",
+        "call rsi",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "xor eax, eax",
+        "ret",
+    );
+}
+
+/// Returns from the `call_catching` that saved `saved_rsp`, abandoning everything it called.
+#[unsafe(naked)]
+unsafe extern "C" fn resume(saved_rsp: u64) -> ! {
+    naked_asm!(
+        "mov rsp, rdi",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "mov eax, 1",
+        "ret",
+    );
+}
+
+#[test_case]
+fn test_catch_panic_returns_the_result() {
+    assert_eq!(catch_panic(|| 6 * 7).ok(), Some(42));
+}
+
+#[test_case]
+fn test_panic_messages_are_truncated_at_character_boundaries() {
+    let mut message = PanicMessage::new();
+    for _ in 0..MESSAGE_SIZE {
+        let _ = message.write_str("é");
+    }
+
+    assert_eq!(message.len, MESSAGE_SIZE);
+    assert!(message.as_str().chars().all(|character| character == 'é'));
+}
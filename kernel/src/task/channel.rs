@@ -0,0 +1,7 @@
+//! Channels for passing values between tasks, and into tasks from interrupt handlers.
+//!
+//! Receivers are futures or streams that register their task's waker, so a task waiting on a channel
+//! sleeps until a value arrives or every sender is gone.
+
+pub mod mpsc;
+pub mod oneshot;
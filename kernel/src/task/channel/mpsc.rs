@@ -0,0 +1,192 @@
+//! Bounded channels with any number of senders and a single receiver.
+//!
+//! Values wait in a ring allocated with the channel, so `try_send` never allocates and is safe to call
+//! from interrupt handlers, which makes a channel an event source for tasks. Tasks can `send` instead,
+//! which waits for room. The receiver is a `Stream` that ends once every sender is dropped and the values
+//! left in the ring were received. Each value received or sent by a task spends a unit of its poll's
+//! budget, see `budget`.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    future::poll_fn,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{ready, stream::Stream, task::AtomicWaker};
+use spin::Mutex;
+
+use crate::task::budget;
+
+struct Shared<T> {
+    ring: ArrayQueue<T>,
+    senders: AtomicUsize,
+    receiver_closed: AtomicBool,
+    receiver: AtomicWaker,
+    /// Tasks waiting in `send` for room. Never locked by interrupt handlers, which only use `try_send`.
+    waiting_senders: Mutex<Vec<Waker>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel has no room; the value is handed back.
+    Full(T),
+    /// The receiver was dropped; the value is handed back.
+    Closed(T),
+}
+
+/// The receiver was dropped; the value is handed back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Creates a channel that holds up to `capacity` values, which must not be zero.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        ring: ArrayQueue::new(capacity),
+        senders: AtomicUsize::new(1),
+        receiver_closed: AtomicBool::new(false),
+        receiver: AtomicWaker::new(),
+        waiting_senders: Mutex::new(Vec::new()),
+    });
+
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Sends `value` if there is room for it. Safe to call from interrupt handlers.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if self.shared.receiver_closed.load(Ordering::Acquire) {
+            return Err(TrySendError::Closed(value));
+        }
+
+        self.shared.ring.push(value).map_err(TrySendError::Full)?;
+        self.shared.receiver.wake();
+        Ok(())
+    }
+
+    /// Sends `value`, waiting for room if the channel is full.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = Some(value);
+
+        poll_fn(|context| {
+            ready!(budget::poll_proceed(context));
+
+            // The second attempt is made after registering, in case the receiver made room in between.
+            for registered in [false, true] {
+                if registered {
+                    self.shared
+                        .waiting_senders
+                        .lock()
+                        .push(context.waker().clone());
+                }
+
+                match self.try_send(value.take().expect("send polled after completion")) {
+                    Ok(()) => return Poll::Ready(Ok(())),
+                    Err(TrySendError::Closed(rejected)) => {
+                        return Poll::Ready(Err(SendError(rejected)));
+                    }
+                    Err(TrySendError::Full(rejected)) => value = Some(rejected),
+                }
+            }
+
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Number of values waiting to be received.
+    pub fn len(&self) -> usize {
+        self.shared.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shared.ring.is_empty()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // The last sender ends the stream.
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.receiver.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Takes the oldest value, if there is one.
+    pub fn try_recv(&mut self) -> Option<T> {
+        let value = self.shared.ring.pop()?;
+        self.wake_senders();
+        Some(value)
+    }
+
+    /// Waits for the next value. Returns `None` once every sender is dropped and no values are left.
+    pub async fn recv(&mut self) -> Option<T> {
+        futures_util::StreamExt::next(self).await
+    }
+
+    fn wake_senders(&self) {
+        let waiting = core::mem::take(&mut *self.shared.waiting_senders.lock());
+        for waker in waiting {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<T>> {
+        ready!(budget::poll_proceed(context));
+
+        if let Some(value) = self.try_recv() {
+            return Poll::Ready(Some(value));
+        }
+
+        // Register before checking again, otherwise a send between the check and the registration would be
+        // missed.
+        self.shared.receiver.register(context.waker());
+        // Read before the ring: the last sender's values are in the ring before it is gone.
+        let closed = self.shared.senders.load(Ordering::Acquire) == 0;
+
+        if let Some(value) = self.try_recv() {
+            self.shared.receiver.take();
+            Poll::Ready(Some(value))
+        } else if closed {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_closed.store(true, Ordering::Release);
+        self.wake_senders();
+    }
+}
@@ -0,0 +1,95 @@
+//! Channels that carry a single value from one sender to one receiver.
+//!
+//! The receiver is a future, so a task can await a value produced by another task. If the sender is
+//! dropped without sending, the receiver resolves to `Canceled` instead of waiting forever.
+
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+struct Shared<T> {
+    value: Mutex<Option<T>>,
+    /// Set once the sender is gone, whether it sent the value or not.
+    closed: AtomicBool,
+    /// Set once the receiver is gone, so sending is pointless.
+    received: AtomicBool,
+    waker: AtomicWaker,
+}
+
+/// The sender was dropped without sending a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Canceled;
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        value: Mutex::new(None),
+        closed: AtomicBool::new(false),
+        received: AtomicBool::new(false),
+        waker: AtomicWaker::new(),
+    });
+
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, handing it back if the receiver was dropped.
+    pub fn send(self, value: T) -> Result<(), T> {
+        if self.shared.received.load(Ordering::Acquire) {
+            return Err(value);
+        }
+
+        *self.shared.value.lock() = Some(value);
+        // Dropping `self` wakes the receiver.
+        Ok(())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+        self.shared.waker.wake();
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, Canceled>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let shared = &self.shared;
+
+        // Register before checking, otherwise a send between the check and the registration would be
+        // missed.
+        shared.waker.register(context.waker());
+
+        if !shared.closed.load(Ordering::Acquire) {
+            return Poll::Pending;
+        }
+
+        Poll::Ready(shared.value.lock().take().ok_or(Canceled))
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.received.store(true, Ordering::Release);
+    }
+}
@@ -0,0 +1,187 @@
+//! Combinators for waiting on several futures at once, e.g. a scancode or a timer.
+//!
+//! They poll the futures they combine in place, without allocating or spawning, so they are cheap
+//! enough for driver tasks. Every one polls its futures in argument order, so the first future wins
+//! when both are ready.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// One of two values, or one of two futures.
+///
+/// As a future, it completes with the output of whichever variant it holds, which lets a function
+/// return one of two different futures with the same output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+impl<A, B> Future for Either<A, B>
+where
+    A: Future,
+    B: Future<Output = A::Output>,
+{
+    type Output = A::Output;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<A::Output> {
+        // SAFETY: the variant's future is never moved out of the pinned `Either`.
+        unsafe {
+            match self.get_unchecked_mut() {
+                Either::Left(future) => Pin::new_unchecked(future).poll(context),
+                Either::Right(future) => Pin::new_unchecked(future).poll(context),
+            }
+        }
+    }
+}
+
+/// Waits for the first of two futures, see `select2`.
+#[derive(Debug)]
+pub struct Select2<A, B> {
+    first: A,
+    second: B,
+}
+
+/// Resolves to the output of whichever future completes first, and drops the other one with it.
+pub fn select2<A: Future, B: Future>(first: A, second: B) -> Select2<A, B> {
+    Select2 { first, second }
+}
+
+impl<A: Future, B: Future> Future for Select2<A, B> {
+    type Output = Either<A::Output, B::Output>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        // SAFETY: neither future is ever moved out of the pinned `Select2`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let first = unsafe { Pin::new_unchecked(&mut this.first) };
+        let second = unsafe { Pin::new_unchecked(&mut this.second) };
+
+        if let Poll::Ready(output) = first.poll(context) {
+            return Poll::Ready(Either::Left(output));
+        }
+        second.poll(context).map(Either::Right)
+    }
+}
+
+/// Like `select2`, for two futures with the same output, which it resolves to.
+pub async fn race<T>(first: impl Future<Output = T>, second: impl Future<Output = T>) -> T {
+    match select2(first, second).await {
+        Either::Left(output) | Either::Right(output) => output,
+    }
+}
+
+/// A future of a `Join`, and then its output.
+enum MaybeDone<F: Future> {
+    Pending(F),
+    Done(F::Output),
+    Taken,
+}
+
+impl<F: Future> MaybeDone<F> {
+    /// Polls the future if it is still pending. Returns whether there is an output now.
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> bool {
+        // SAFETY: the future is only dropped in place, by overwriting it with its output.
+        let this = unsafe { self.get_unchecked_mut() };
+
+        if let MaybeDone::Pending(future) = this {
+            match unsafe { Pin::new_unchecked(future) }.poll(context) {
+                Poll::Ready(output) => *this = MaybeDone::Done(output),
+                Poll::Pending => return false,
+            }
+        }
+        true
+    }
+
+    fn take(self: Pin<&mut Self>) -> F::Output {
+        // SAFETY: only an output is moved out, never the future.
+        let this = unsafe { self.get_unchecked_mut() };
+
+        match core::mem::replace(this, MaybeDone::Taken) {
+            MaybeDone::Done(output) => output,
+            _ => panic!("join output taken before it was ready"),
+        }
+    }
+}
+
+/// Waits for two futures, see `join`.
+pub struct Join<A: Future, B: Future> {
+    first: MaybeDone<A>,
+    second: MaybeDone<B>,
+}
+
+/// Resolves to the outputs of both futures once both have completed. The futures make progress
+/// concurrently, on the task that awaits the join.
+pub fn join<A: Future, B: Future>(first: A, second: B) -> Join<A, B> {
+    Join {
+        first: MaybeDone::Pending(first),
+        second: MaybeDone::Pending(second),
+    }
+}
+
+impl<A: Future, B: Future> Future for Join<A, B> {
+    type Output = (A::Output, B::Output);
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        // SAFETY: the fields are never moved out of the pinned `Join`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut first = unsafe { Pin::new_unchecked(&mut this.first) };
+        let mut second = unsafe { Pin::new_unchecked(&mut this.second) };
+
+        // Both are polled even if the first is still pending, so that both register their wakers.
+        let first_done = first.as_mut().poll(context);
+        let second_done = second.as_mut().poll(context);
+
+        if first_done && second_done {
+            Poll::Ready((first.take(), second.take()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+fn poll_once<F: Future>(future: Pin<&mut F>) -> Poll<F::Output> {
+    future.poll(&mut Context::from_waker(core::task::Waker::noop()))
+}
+
+#[test_case]
+fn test_select2_prefers_the_first_ready_future() {
+    use core::future::{pending, ready};
+
+    let mut both = core::pin::pin!(select2(ready(1), ready("two")));
+    assert_eq!(poll_once(both.as_mut()), Poll::Ready(Either::Left(1)));
+
+    let mut second = core::pin::pin!(select2(pending::<u8>(), ready("two")));
+    assert_eq!(
+        poll_once(second.as_mut()),
+        Poll::Ready(Either::Right("two"))
+    );
+
+    let mut neither = core::pin::pin!(select2(pending::<u8>(), pending::<u8>()));
+    assert_eq!(poll_once(neither.as_mut()), Poll::Pending);
+}
+
+#[test_case]
+fn test_join_waits_for_both_futures() {
+    use core::future::{pending, ready};
+
+    let mut both = core::pin::pin!(join(ready(1), ready('b')));
+    assert_eq!(poll_once(both.as_mut()), Poll::Ready((1, 'b')));
+
+    let mut one = core::pin::pin!(join(ready(1), pending::<char>()));
+    assert_eq!(poll_once(one.as_mut()), Poll::Pending);
+}
+
+#[test_case]
+fn test_race_and_either_resolve_to_the_shared_output() {
+    use core::future::{pending, ready};
+
+    let mut raced = core::pin::pin!(race(pending::<u8>(), ready(7)));
+    assert_eq!(poll_once(raced.as_mut()), Poll::Ready(7));
+
+    let mut either = core::pin::pin!(Either::<_, core::future::Pending<u8>>::Left(ready(3)));
+    assert_eq!(poll_once(either.as_mut()), Poll::Ready(3));
+}
@@ -1,76 +1,504 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
-use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
+use super::{
+    Priority, Task, TaskId,
+    budget::{self, DEFAULT_BUDGET},
+    catch::catch_panic,
+    channel::oneshot::{self, Canceled},
+};
+use crate::{
+    interrupts::in_handler,
+    lockup,
+    queue::{Queue, QueueId},
+    serial_println, thread,
+};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    rc::Rc,
+    sync::Arc,
+    task::Wake,
+    vec::Vec,
+};
+use core::{
+    cell::RefCell,
+    future::{Future, poll_fn},
+    pin::{Pin, pin},
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
 
+/// Futures handed to a `Spawner`, waiting for the executor to turn them into tasks.
+type Injected = Mutex<
+    VecDeque<(
+        Priority,
+        Pin<Box<dyn Future<Output = ()> + Send>>,
+        AbortHandle,
+    )>,
+>;
+
+/// Runs tasks as they are woken, on one or more workers.
+///
+/// New tasks wait in a shared injector until a worker takes them. From then on the task belongs to that
+/// worker, and waits in the worker's local queue whenever it is woken. Every queue is split by `Priority`.
+/// A worker polls the oldest task of the highest priority it can find: in the injector first, so new tasks
+/// don't wait behind busy ones, then in its own queue. If both are empty it steals half of the tasks of
+/// that priority queued on another worker, which then belong to it. Only the boot CPU is brought up, so the
+/// workers take turns on it, polling a task each.
+///
+/// The queues' capacity and overflow behavior are those of `QueueId::Tasks`. They grow when full, but
+/// can't while interrupts are disabled, so a wake from an interrupt handler can find no room. Such a wake
+/// isn't lost: the waker flags it, and the executor queues the task again on its next pass.
+///
+/// A task that panics is removed and its `JoinHandle` resolves to `JoinError`, while the other tasks keep
+/// running. See `catch` for what the panic leaves behind.
+///
+/// At most `DEFAULT_TASK_LIMIT` tasks, or what `set_task_limit` sets, exist at a time, counting those
+/// waiting in a `Spawner`, so that a loop spawning tasks fails to spawn instead of exhausting the heap.
+/// The waker cache holds an entry per task, so it is bounded as well.
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    /// Of each task, to fail its `JoinHandle` if it panics.
+    abort_handles: BTreeMap<TaskId, AbortHandle>,
+    run_queues: Arc<RunQueues>,
+    /// Indexed like `RunQueues::local`.
+    workers: Vec<WorkerStats>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
+    injected: Arc<Injected>,
+    /// Set when a waker found the task queue full, see `TaskWaker::lost`.
+    lost_wakes: Arc<AtomicBool>,
+    /// Of each poll, see `budget`.
+    budget: Option<u32>,
+    slots: Arc<TaskSlots>,
+}
+
+/// Tasks an executor holds at a time unless `Executor::set_task_limit` says otherwise.
+pub const DEFAULT_TASK_LIMIT: usize = 1024;
+
+/// `TaskWaker::worker` of a task that waits in the injector.
+const INJECTOR: usize = usize::MAX;
+
+/// A queue of woken tasks per `Priority`, indexed by it.
+type Queues = [Queue<TaskId>; Priority::ALL.len()];
+
+/// Where woken tasks wait to be polled, see `Executor`.
+struct RunQueues {
+    injector: Queues,
+    /// Indexed by worker.
+    local: Vec<Queues>,
+}
+
+impl RunQueues {
+    fn new(workers: usize) -> Self {
+        let queues = || Priority::ALL.map(|_| Queue::new(QueueId::Tasks));
+
+        RunQueues {
+            injector: queues(),
+            local: (0..workers).map(|_| queues()).collect(),
+        }
+    }
+
+    /// The queue of `worker`, or of the injector if it is `INJECTOR`, for tasks of `priority`.
+    fn queue(&self, worker: usize, priority: Priority) -> &Queue<TaskId> {
+        match worker {
+            INJECTOR => &self.injector[priority as usize],
+            worker => &self.local[worker][priority as usize],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.local
+            .iter()
+            .chain([&self.injector])
+            .flatten()
+            .all(Queue::is_empty)
+    }
+}
+
+/// What a worker did so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WorkerStats {
+    pub polls: u64,
+    /// Times it stole tasks from another worker.
+    pub steals: u64,
+}
+
+/// The executor has as many tasks as its limit allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    TooManyTasks,
+}
+
+/// Counts the tasks of an executor, including futures waiting in its spawners, against its limit.
+struct TaskSlots {
+    used: AtomicUsize,
+    limit: AtomicUsize,
+}
+
+impl TaskSlots {
+    fn take(&self) -> Result<(), SpawnError> {
+        let limit = self.limit.load(Ordering::Relaxed);
+
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                (used < limit).then_some(used + 1)
+            })
+            .map(|_| ())
+            .map_err(|_| SpawnError::TooManyTasks)
+    }
+
+    fn release(&self) {
+        self.used.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl Executor {
+    /// An executor with a single worker.
     pub fn new() -> Self {
+        Self::with_workers(1)
+    }
+
+    /// An executor with `workers` workers, at least one.
+    pub fn with_workers(workers: usize) -> Self {
+        assert!(workers > 0, "an executor needs a worker");
+
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            abort_handles: BTreeMap::new(),
+            run_queues: Arc::new(RunQueues::new(workers)),
+            workers: alloc::vec![WorkerStats::default(); workers],
             waker_cache: BTreeMap::new(),
+            injected: Arc::new(Mutex::new(VecDeque::new())),
+            lost_wakes: Arc::new(AtomicBool::new(false)),
+            budget: Some(DEFAULT_BUDGET),
+            slots: Arc::new(TaskSlots {
+                used: AtomicUsize::new(0),
+                limit: AtomicUsize::new(DEFAULT_TASK_LIMIT),
+            }),
         }
     }
 
-    /// Spawns a new task.
+    /// Sets how many tasks may exist at a time, or lifts the limit if `limit` is `None`. Tasks over a
+    /// new, lower limit keep running, but no more can be spawned until enough of them complete.
+    pub fn set_task_limit(&mut self, limit: Option<usize>) {
+        self.slots
+            .limit
+            .store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    /// Sets how many operations a poll may do before its task is made to yield, or lifts the limit if
+    /// `budget` is `None`. See `budget`.
+    pub fn set_budget(&mut self, budget: Option<u32>) {
+        self.budget = budget;
+    }
+
+    /// Spawns `future` as a new task, returning a handle that resolves to its output.
     ///
     /// Because we are making a mutable loan from the executor, we can no longer execute `spawn` after the `run`
-    /// method starts executing, plus `run` implements an infinite loop with a divergent return.
-    /// One solution is to create a custom `Spawner` type that shares a queue with the `Executor`, a queue shared with it,
-    /// or its own queue that is synchronized by the `Executor`.
+    /// method starts executing, plus `run` implements an infinite loop with a divergent return. Code that
+    /// spawns later, such as running tasks, uses a `Spawner` instead.
     ///
     /// Remember that Rust doesn't allow having two mutable borrows at the same time, except for reborrowing.
-    pub fn spawn(&mut self, task: Task) {
+    ///
+    /// Panics if the task limit is reached, see `try_spawn`.
+    pub fn spawn<T: 'static>(
+        &mut self,
+        future: impl Future<Output = T> + 'static,
+    ) -> JoinHandle<T> {
+        self.spawn_with_priority(future, Priority::Normal)
+    }
+
+    /// Like `spawn`, for a task of the given priority.
+    pub fn spawn_with_priority<T: 'static>(
+        &mut self,
+        future: impl Future<Output = T> + 'static,
+        priority: Priority,
+    ) -> JoinHandle<T> {
+        self.try_spawn_with_priority(future, priority)
+            .expect("task limit reached")
+    }
+
+    /// Spawns a task built with `Task::new` or `Task::new_named`, keeping its name and priority.
+    pub fn spawn_task(&mut self, task: Task) -> JoinHandle<()> {
+        self.try_spawn_task(task).expect("task limit reached")
+    }
+
+    /// Like `spawn`, but fails instead of panicking if the executor has as many tasks as its limit allows.
+    pub fn try_spawn<T: 'static>(
+        &mut self,
+        future: impl Future<Output = T> + 'static,
+    ) -> Result<JoinHandle<T>, SpawnError> {
+        self.try_spawn_with_priority(future, Priority::Normal)
+    }
+
+    /// Like `try_spawn`, for a task of the given priority.
+    pub fn try_spawn_with_priority<T: 'static>(
+        &mut self,
+        future: impl Future<Output = T> + 'static,
+        priority: Priority,
+    ) -> Result<JoinHandle<T>, SpawnError> {
+        self.slots.take()?;
+
+        let (task, handle) = joinable(future);
+        self.insert(
+            Task::new(task).with_priority(priority),
+            handle.abort_handle(),
+        );
+        Ok(handle)
+    }
+
+    /// Like `spawn_task`, but fails instead of panicking if the task limit is reached.
+    pub fn try_spawn_task(&mut self, task: Task) -> Result<JoinHandle<()>, SpawnError> {
+        self.slots.take()?;
+
+        let (future, handle) = joinable(task.future);
+        self.insert(
+            Task {
+                future: Box::pin(future),
+                ..task
+            },
+            handle.abort_handle(),
+        );
+        Ok(handle)
+    }
+
+    /// A handle that spawns tasks on this executor, also while it runs.
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            injected: self.injected.clone(),
+            slots: self.slots.clone(),
+        }
+    }
+
+    /// Adds `task` and queues it for its first poll, through its waker like any later wake.
+    fn insert(&mut self, task: Task, abort: AbortHandle) {
         let task_id = task.id;
+        let priority = task.priority;
 
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
+        self.abort_handles.insert(task_id, abort);
+
+        let task_waker = TaskWaker::new(
+            task_id,
+            priority,
+            self.run_queues.clone(),
+            self.lost_wakes.clone(),
+        );
+        task_waker.wake_task(WakeReason::Spawned);
+        self.waker_cache.insert(task_id, task_waker);
+    }
+
+    /// Turns the futures handed to spawners into queued tasks.
+    fn insert_injected(&mut self) {
+        // Spawners may be used by interrupt handlers, which must not find the lock taken.
+        while let Some((priority, future, abort)) =
+            interrupts::without_interrupts(|| self.injected.lock().pop_front())
+        {
+            self.insert(Task::from_pinned(future).with_priority(priority), abort);
+        }
+    }
+
+    /// Queues the tasks whose wakes found the task queue full.
+    fn requeue_lost_wakes(&self) {
+        if !self.lost_wakes.swap(false, Ordering::AcqRel) {
+            return;
+        }
+
+        for task_waker in self.waker_cache.values() {
+            if task_waker.lost.swap(false, Ordering::AcqRel) {
+                task_waker.push();
+            }
+        }
+    }
+
+    /// The next task for `worker` to poll, see `Executor`.
+    fn next_task(&mut self, worker: usize) -> Option<TaskId> {
+        let run_queues = self.run_queues.clone();
+
+        for priority in Priority::ALL {
+            if let Some(task_id) = run_queues.queue(INJECTOR, priority).pop() {
+                self.give(task_id, worker);
+                return Some(task_id);
+            }
+            if let Some(task_id) = run_queues.queue(worker, priority).pop() {
+                return Some(task_id);
+            }
+            if let Some(task_id) = self.steal(worker, priority) {
+                return Some(task_id);
+            }
+        }
+
+        None
+    }
+
+    /// Moves half of the tasks of `priority` queued on the next worker after `worker` that has any to
+    /// `worker`. Returns the oldest of them, for `worker` to poll, and queues the others on it.
+    fn steal(&mut self, worker: usize, priority: Priority) -> Option<TaskId> {
+        let run_queues = self.run_queues.clone();
+        let count = run_queues.local.len();
+
+        for victim in (1..count).map(|offset| (worker + offset) % count) {
+            let queue = run_queues.queue(victim, priority);
+            let mut stolen = (0..queue.len().div_ceil(2)).map_while(|_| queue.pop());
+            let Some(first) = stolen.next() else {
+                continue;
+            };
+
+            self.give(first, worker);
+            for task_id in stolen {
+                self.give(task_id, worker);
+                // Still marked as queued, so it is pushed again by hand.
+                if let Some(task_waker) = self.waker_cache.get(&task_id) {
+                    task_waker.push();
+                }
+            }
+            self.workers[worker].steals += 1;
+            return Some(first);
+        }
 
-        self.task_queue.push(task_id).expect("queue full");
+        None
+    }
+
+    /// Makes `task_id` belong to `worker`, so it is queued there when woken.
+    fn give(&self, task_id: TaskId, worker: usize) {
+        if let Some(task_waker) = self.waker_cache.get(&task_id) {
+            task_waker.worker.store(worker, Ordering::Release);
+        }
     }
 
     fn run_ready_tasks(&mut self) {
+        loop {
+            self.insert_injected();
+            self.requeue_lost_wakes();
+
+            let mut polled = false;
+            for worker in 0..self.workers.len() {
+                if let Some(task_id) = self.next_task(worker) {
+                    self.poll_task(task_id);
+                    self.workers[worker].polls += 1;
+                    polled = true;
+                }
+            }
+            if !polled {
+                break;
+            }
+        }
+    }
+
+    fn poll_task(&mut self, task_id: TaskId) {
         // Destructuring is necessary because in the closure below we attempt to perform a full borrow of
         // self in order to obtain the waker_cache.
         let Self {
             tasks,
-            task_queue,
+            abort_handles,
             waker_cache,
+            budget,
+            slots,
+            ..
         } = self;
 
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                // Task no longer exists.
-                None => continue,
-            };
+        lockup::touch();
 
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+        let (Some(task), Some(task_waker)) = (tasks.get_mut(&task_id), waker_cache.get(&task_id))
+        else {
+            // Task no longer exists.
+            return;
+        };
 
-            let mut context = Context::from_waker(waker);
+        // Cleared before polling, so a wake that happens during the poll queues the task again.
+        task_waker.queued.store(false, Ordering::Release);
+        task_waker.polling.store(true, Ordering::Relaxed);
 
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // If the task is complete, remove it and its curly waker. There's no reason to keep them,
-                    // since the task is finished.
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
+        let waker = Waker::from(task_waker.clone());
+        let mut context = Context::from_waker(&waker);
+
+        // Outside `catch_panic`, which doesn't return through the closure if the task panics.
+        let result = budget::with_budget(*budget, || catch_panic(|| task.poll(&mut context)));
+        task_waker.polling.store(false, Ordering::Relaxed);
+
+        match result {
+            Ok(Poll::Ready(())) => {
+                // If the task is complete, remove it and its curly waker. There's no reason to keep them,
+                // since the task is finished.
+                tasks.remove(&task_id);
+                abort_handles.remove(&task_id);
+                waker_cache.remove(&task_id);
+                slots.release();
+            }
+
+            Ok(Poll::Pending) => {}
+
+            Err(message) => {
+                serial_println!("task {} panicked: {}", task_id.0, message);
 
-                Poll::Pending => {}
+                // The future stopped halfway through a poll, so dropping it could drop values that were
+                // already moved or dropped. It is leaked instead.
+                if let Some(task) = tasks.remove(&task_id) {
+                    core::mem::forget(task);
+                }
+                if let Some(abort) = abort_handles.remove(&task_id) {
+                    abort.poison();
+                }
+                waker_cache.remove(&task_id);
+                slots.release();
             }
         }
     }
 
+    /// Polls tasks until the task queue is empty, then returns instead of sleeping.
+    ///
+    /// Useful for tests, where the executor must hand control back once there is nothing left to do.
+    pub fn run_until_idle(&mut self) {
+        self.run_ready_tasks();
+    }
+
+    /// Number of tasks that were spawned and haven't completed yet.
+    pub fn task_count(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Describes every task that hasn't completed yet, in spawn order.
+    pub fn tasks(&self) -> impl Iterator<Item = TaskInfo> + '_ {
+        self.tasks.iter().map(|(&task_id, task)| {
+            let queued = self
+                .waker_cache
+                .get(&task_id)
+                .is_some_and(|waker| waker.queued.load(Ordering::Acquire));
+
+            TaskInfo {
+                id: task_id.0,
+                priority: task.priority,
+                state: if queued {
+                    TaskState::Queued
+                } else {
+                    TaskState::Waiting
+                },
+            }
+        })
+    }
+
+    /// What each worker did so far, in worker order.
+    pub fn worker_stats(&self) -> impl Iterator<Item = WorkerStats> + '_ {
+        self.workers.iter().copied()
+    }
+
+    /// Poll statistics of every task that hasn't completed yet, in spawn order.
+    pub fn stats(&self) -> impl Iterator<Item = TaskStats> + '_ {
+        self.tasks.iter().map(|(&task_id, task)| TaskStats {
+            id: task_id.0,
+            name: task.name,
+            polls: task.polls,
+            poll_cycles: task.poll_cycles,
+            last_wake: self
+                .waker_cache
+                .get(&task_id)
+                .and_then(|waker| WakeReason::from_u8(waker.last_wake.load(Ordering::Relaxed))),
+        })
+    }
+
     /// The executor spins.
     ///
     /// Because the keyboard task, for example, prevents the tasks map from being empty, a loop with a
@@ -78,21 +506,28 @@ impl Executor {
     pub fn run(&mut self) -> ! {
         loop {
             self.run_ready_tasks();
+            // The executor runs on the boot thread, so kernel threads only get the CPU when it yields.
+            thread::yield_now();
             self.sleep_if_idle();
         }
     }
 
-    /// It puts the CPU into sleep mode when there are no tasks in the task queue, preventing the CPU from becoming busy.
+    /// It puts the CPU into sleep mode when there are no tasks in the task queue and no kernel thread is ready, preventing the CPU from becoming busy.
     fn sleep_if_idle(&self) {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         interrupts::disable(); // Prevent race conditions
         // Between run_ready_tasks and sleep_if_idle, an interruption may occur and the queue may not become empty, hence the new check.
-        if self.task_queue.is_empty() {
+        if self.run_queues.is_empty()
+            && self.injected.lock().is_empty()
+            && !self.lost_wakes.load(Ordering::Acquire)
+            && !thread::has_ready()
+        {
             // We disabled interrupts earlier because if an interrupt happens here, we'll lose the wakeup.
             // After verifying that there are indeed no tasks in the queue, we re-enable interrupts and activate
             // the hlt instruction to enter sleep mode. This is all done atomically.
-            enable_and_hlt();
+            // A halted CPU is idle, not stuck.
+            lockup::idle(enable_and_hlt);
         } else {
             // This means that after run_ready_tasks a new task was added by an interrupt, so we re-enable interrupts
             // and re-enter the loop.
@@ -101,27 +536,321 @@ impl Executor {
     }
 }
 
-/// The waker's job is to push the waken task ID to the task_queue.
+/// Runs `future` to completion on an executor of its own, e.g. in a test.
+///
+/// Panics if the future is still pending once nothing is left to poll, as no interrupt wakes this
+/// executor's tasks.
+pub fn block_on<T: 'static>(future: impl Future<Output = T> + 'static) -> T {
+    let mut executor = Executor::new();
+    let result = Rc::new(RefCell::new(None));
+    let output = result.clone();
+    executor.spawn(async move { *output.borrow_mut() = Some(future.await) });
+    executor.run_until_idle();
+
+    result.take().expect("future didn't complete")
+}
+
+/// Spawns tasks on an `Executor`, including after it started running. Clones share the executor.
+///
+/// Spawned futures wait in a queue until the executor next looks for ready tasks, which it does before
+/// sleeping and after polling each task. Spawning allocates, so interrupt handlers shouldn't spawn
+/// directly but `defer` a function that does.
+#[derive(Clone)]
+pub struct Spawner {
+    injected: Arc<Injected>,
+    slots: Arc<TaskSlots>,
+}
+
+impl Spawner {
+    /// Spawns `future` as a new task, returning a handle that resolves to its output.
+    ///
+    /// Panics if the executor's task limit is reached, see `try_spawn`.
+    pub fn spawn<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + Send + 'static,
+    ) -> JoinHandle<T> {
+        self.spawn_with_priority(future, Priority::Normal)
+    }
+
+    /// Like `spawn`, for a task of the given priority.
+    pub fn spawn_with_priority<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + Send + 'static,
+        priority: Priority,
+    ) -> JoinHandle<T> {
+        self.try_spawn_with_priority(future, priority)
+            .expect("task limit reached")
+    }
+
+    /// Like `spawn`, but fails instead of panicking if the executor has as many tasks as its limit allows.
+    pub fn try_spawn<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + Send + 'static,
+    ) -> Result<JoinHandle<T>, SpawnError> {
+        self.try_spawn_with_priority(future, Priority::Normal)
+    }
+
+    /// Like `try_spawn`, for a task of the given priority.
+    pub fn try_spawn_with_priority<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + Send + 'static,
+        priority: Priority,
+    ) -> Result<JoinHandle<T>, SpawnError> {
+        self.slots.take()?;
+
+        let (task, handle) = joinable(future);
+        let task: Pin<Box<dyn Future<Output = ()> + Send>> = Box::pin(task);
+
+        let abort = handle.abort_handle();
+        interrupts::without_interrupts(|| self.injected.lock().push_back((priority, task, abort)));
+        Ok(handle)
+    }
+}
+
+/// Wraps `future` into the future of a task that sends its output to the returned handle, and that
+/// completes early if the handle's task is aborted.
+fn joinable<T>(future: impl Future<Output = T>) -> (impl Future<Output = ()>, JoinHandle<T>) {
+    let (sender, receiver) = oneshot::channel();
+    let abort = AbortHandle {
+        state: Arc::new(AbortState {
+            aborted: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+            panicked: AtomicBool::new(false),
+            join_waker: AtomicWaker::new(),
+        }),
+    };
+    let state = abort.state.clone();
+
+    let task = async move {
+        let mut future = pin!(future);
+        let output = poll_fn(|context| {
+            // Registered before checking, so an abort in between still wakes the task.
+            state.waker.register(context.waker());
+            if state.aborted.load(Ordering::Acquire) {
+                return Poll::Ready(None);
+            }
+            future.as_mut().poll(context).map(Some)
+        })
+        .await;
+
+        // Nobody waits for the output if the handle was dropped, and an aborted task has none.
+        if let Some(output) = output {
+            let _ = sender.send(output);
+        }
+    };
+
+    (task, JoinHandle { receiver, abort })
+}
+
+/// Resolves to the output of a spawned task. Dropping it lets the task run on without anyone waiting
+/// for it.
+pub struct JoinHandle<T> {
+    receiver: oneshot::Receiver<T>,
+    abort: AbortHandle,
+}
+
+impl<T> JoinHandle<T> {
+    /// A handle that can abort the task without waiting for it.
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone()
+    }
+
+    /// Aborts the task, see `AbortHandle::abort`.
+    pub fn abort(&self) {
+        self.abort.abort();
+    }
+}
+
+/// The task was dropped before completing, because it was aborted, it panicked or its executor was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JoinError;
+
+struct AbortState {
+    aborted: AtomicBool,
+    /// The waker of the task, to have it polled once it is aborted.
+    waker: AtomicWaker,
+    /// Set when the task panicked. Its future was leaked with the sender of its output, so the
+    /// `JoinHandle` can't wait for that to be dropped.
+    panicked: AtomicBool,
+    /// The waker of the task waiting on the `JoinHandle`.
+    join_waker: AtomicWaker,
+}
+
+/// Stops a spawned task. Clones refer to the same task.
+#[derive(Clone)]
+pub struct AbortHandle {
+    state: Arc<AbortState>,
+}
+
+impl AbortHandle {
+    /// Stops the task. It isn't polled again: the next time the executor gets to it, it completes without
+    /// output, which drops its future and removes it from the executor. Aborting a completed task does
+    /// nothing.
+    pub fn abort(&self) {
+        self.state.aborted.store(true, Ordering::Release);
+        self.state.waker.wake();
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.state.aborted.load(Ordering::Acquire)
+    }
+
+    /// Fails the `JoinHandle` of a task that panicked.
+    fn poison(&self) {
+        self.state.panicked.store(true, Ordering::Release);
+        self.state.join_waker.wake();
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        if let Poll::Ready(result) = Pin::new(&mut self.receiver).poll(context) {
+            return Poll::Ready(result.map_err(|Canceled| JoinError));
+        }
+
+        // Registered before checking, so a panic in between still wakes the waiting task.
+        let state = &self.abort.state;
+        state.join_waker.register(context.waker());
+        if state.panicked.load(Ordering::Acquire) {
+            return Poll::Ready(Err(JoinError));
+        }
+
+        Poll::Pending
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Waiting in the task queue to be polled.
+    Queued,
+    /// Polled and returned `Pending`; it runs again once woken.
+    Waiting,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub priority: Priority,
+    pub state: TaskState,
+}
+
+/// Polls and run time of a task, to find tasks that spin or never wake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskStats {
+    pub id: u64,
+    pub name: Option<&'static str>,
+    pub polls: u64,
+    /// Time spent polling the task, in TSC cycles.
+    pub poll_cycles: u64,
+    /// `None` if the task was never woken, which only happens to tasks that haven't been polled yet.
+    pub last_wake: Option<WakeReason>,
+}
+
+/// Where the last wake of a task came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum WakeReason {
+    /// The task was spawned, which queues it for its first poll.
+    Spawned = 1,
+    /// An interrupt handler, e.g. through an `AtomicWaker`.
+    Interrupt,
+    /// The task itself while being polled, e.g. with `yield_now` or once its budget was spent.
+    Itself,
+    /// Another task, or a kernel thread.
+    Other,
+}
+
+impl WakeReason {
+    fn from_u8(value: u8) -> Option<WakeReason> {
+        [
+            WakeReason::Spawned,
+            WakeReason::Interrupt,
+            WakeReason::Itself,
+            WakeReason::Other,
+        ]
+        .into_iter()
+        .find(|&reason| reason as u8 == value)
+    }
+}
+
+/// The waker's job is to push the waken task ID to the queue of the worker it belongs to.
 /// Next, the `Executor` polls for the new task.
 struct TaskWaker {
     task_id: TaskId,
-    // Ownership of task_queue is shared between wakers and executors through the Arc wrapper type,
+    priority: Priority,
+    // Ownership of the run queues is shared between wakers and executors through the Arc wrapper type,
     // which is based on reference counting.
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    run_queues: Arc<RunQueues>,
+    /// The worker the task belongs to, or `INJECTOR` until one takes it.
+    worker: AtomicUsize,
+    /// Set while the task ID sits in the queue, so that many wakes before the next poll collapse into a
+    /// single queue entry instead of filling up the queue with duplicates.
+    queued: AtomicBool,
+    /// Set when the task was woken but the queue had no room for it. The executor pushes it again.
+    lost: AtomicBool,
+    /// The executor's flag for any lost wake, so it doesn't have to check every waker on each pass.
+    lost_wakes: Arc<AtomicBool>,
+    /// Set while the executor polls the task, to tell when it wakes itself.
+    polling: AtomicBool,
+    /// A `WakeReason`, or 0 before the first wake.
+    last_wake: AtomicU8,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+    fn new(
+        task_id: TaskId,
+        priority: Priority,
+        run_queues: Arc<RunQueues>,
+        lost_wakes: Arc<AtomicBool>,
+    ) -> Arc<TaskWaker> {
         // The Waker type supports conversions using the From trait when the type in question implements the Wake trait.
         // This is because we are wrapping a type that implements the Wake trait, where this trait uses the Arc smart pointer.
-        Waker::from(Arc::new(TaskWaker {
+        Arc::new(TaskWaker {
             task_id,
-            task_queue,
-        }))
+            priority,
+            run_queues,
+            worker: AtomicUsize::new(INJECTOR),
+            queued: AtomicBool::new(false),
+            lost: AtomicBool::new(false),
+            lost_wakes,
+            polling: AtomicBool::new(false),
+            last_wake: AtomicU8::new(0),
+        })
     }
 
-    fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+    fn wake_task(&self, reason: WakeReason) {
+        self.last_wake.store(reason as u8, Ordering::Relaxed);
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        self.push();
+    }
+
+    fn wake_from_waker(&self) {
+        let reason = if in_handler() {
+            WakeReason::Interrupt
+        } else if self.polling.load(Ordering::Relaxed) {
+            WakeReason::Itself
+        } else {
+            WakeReason::Other
+        };
+        self.wake_task(reason);
+    }
+
+    fn push(&self) {
+        let worker = self.worker.load(Ordering::Acquire);
+        if self
+            .run_queues
+            .queue(worker, self.priority)
+            .push(self.task_id)
+            .is_err()
+        {
+            self.lost.store(true, Ordering::Release);
+            self.lost_wakes.store(true, Ordering::Release);
+        }
     }
 }
 
@@ -132,7 +861,7 @@ impl TaskWaker {
 impl Wake for TaskWaker {
     // Since this captures ownership, it increases the number of references in Arc.
     fn wake(self: Arc<Self>) {
-        self.wake_task();
+        self.wake_from_waker();
     }
 
     // Implementing this method is optional because not all data types support waking by reference.
@@ -140,6 +869,6 @@ impl Wake for TaskWaker {
     // the reference count, for example.
     fn wake_by_ref(self: &Arc<Self>) {
         // Since our type only requires one &self reference, this is easy to resolve.
-        self.wake_task();
+        self.wake_from_waker();
     }
 }
@@ -0,0 +1,119 @@
+//! Streams of events pushed by interrupt handlers.
+//!
+//! An `IrqStream` is meant to be a static: the interrupt handler pushes into it, and a task takes its
+//! only consumer, an `IrqEvents`, and reads the events as a `Stream`. Events wait in a kernel `Queue`,
+//! which is allocated when the consumer is taken, so that the handler never allocates. Until then,
+//! pushes fail, since nobody would read the events.
+
+use conquer_once::spin::OnceCell;
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_util::{stream::Stream, task::AtomicWaker};
+
+use crate::{
+    init_state::AlreadyInitialized,
+    queue::{Queue, QueueId},
+};
+
+pub struct IrqStream<T> {
+    /// Names the stream in `AlreadyInitialized`.
+    name: &'static str,
+    queue_id: QueueId,
+    queue: OnceCell<Queue<T>>,
+    /// The waker of the consumer, woken by `push`.
+    waker: AtomicWaker,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushError<T> {
+    /// The queue is full and its overflow policy rejected the event, which is handed back.
+    Full(T),
+    /// Nobody took the consumer yet; the event is handed back.
+    NoConsumer(T),
+}
+
+impl<T> IrqStream<T> {
+    /// A stream whose events wait in a queue registered as `queue_id`.
+    pub const fn new(name: &'static str, queue_id: QueueId) -> Self {
+        IrqStream {
+            name,
+            queue_id,
+            queue: OnceCell::uninit(),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// Queues `event` and wakes the consumer. Never allocates or blocks, so it is safe to call from
+    /// interrupt handlers.
+    pub fn push(&self, event: T) -> Result<(), PushError<T>> {
+        let Ok(queue) = self.queue.try_get() else {
+            return Err(PushError::NoConsumer(event));
+        };
+
+        queue.push(event).map_err(PushError::Full)?;
+        self.waker.wake();
+        Ok(())
+    }
+
+    /// Creates the only consumer of the stream. Allocates its queue, so it must not be called from
+    /// interrupt handlers.
+    pub fn take(&'static self) -> Result<IrqEvents<T>, AlreadyInitialized> {
+        self.queue
+            .try_init_once(|| Queue::new(self.queue_id))
+            .map_err(|_| AlreadyInitialized {
+                subsystem: self.name,
+            })?;
+
+        Ok(IrqEvents { stream: self })
+    }
+
+    /// Whether the consumer was taken.
+    pub fn is_taken(&self) -> bool {
+        self.queue.is_initialized()
+    }
+}
+
+/// The consumer of an `IrqStream`. The stream never ends.
+pub struct IrqEvents<T: 'static> {
+    stream: &'static IrqStream<T>,
+}
+
+impl<T> IrqEvents<T> {
+    /// Takes the oldest event, if there is one.
+    pub fn try_next(&mut self) -> Option<T> {
+        self.queue().pop()
+    }
+
+    fn queue(&self) -> &'static Queue<T> {
+        self.stream
+            .queue
+            .try_get()
+            .expect("queue is allocated before the consumer exists")
+    }
+}
+
+impl<T> Stream for IrqEvents<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<T>> {
+        let queue = self.queue();
+
+        // Fast path, which doesn't touch the waker.
+        if let Some(event) = queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+
+        // Register before checking again, otherwise an event pushed in between would be missed.
+        self.stream.waker.register(context.waker());
+
+        match queue.pop() {
+            Some(event) => {
+                self.stream.waker.take();
+                Poll::Ready(Some(event))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
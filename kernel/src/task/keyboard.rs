@@ -1,95 +1,321 @@
-use conquer_once::spin::OnceCell; // Allows for the single initialization of static variables.
+use alloc::vec::Vec;
 use core::{
     pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
     task::{Context, Poll},
 };
-use crossbeam_queue::ArrayQueue; // Allows for a fixed-size queue without locks.
 use futures_util::stream::{Stream, StreamExt};
-use futures_util::task::AtomicWaker;
-use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1, layouts};
+use pc_keyboard::{
+    DecodedKey, EventDecoder, HandleControl, KeyCode, KeyEvent, KeyState, ScancodeSet, ScancodeSet1,
+};
+use spin::Mutex;
+
+use super::irq_stream::{IrqEvents, IrqStream, PushError};
+use crate::{
+    console::{self, Console},
+    console_print,
+    init_state::AlreadyInitialized,
+    input::{self, DeviceId, DeviceKind, Event, Modifiers},
+    interrupts, println,
+    queue::QueueId,
+};
 
-use crate::{print, println};
+pub mod layout;
 
-// We use `OnceCell` because `ArrayQueue::new` performs heap allocation, which is not allowed with static variables.
-// We don't use `lazy-static` because we need to ensure predictable queue initialization.
-// Otherwise, it could be initialized in interrupt handlers, which can lead to heap allocation.
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+pub use layout::Layout;
 
-// Store the `Waker` using `AtomicWaker`. We cannot use a field in `ScancodeStream` because it needs to be visible from `add_scancode`.
-// `poll_next` as a consumer stores the wake.
-// `add_scancode` as a producer triggers the wake.
-static WAKER: AtomicWaker = AtomicWaker::new();
+// The queue is only allocated once the `ScancodeStream` is created, never by the interrupt handler.
+static SCANCODES: IrqStream<u8> = IrqStream::new("scancode stream", QueueId::Scancodes);
 
+/// While set, scancodes coming from the PS/2 IRQ are ignored so that only replayed input reaches the stream.
+static REPLAY_MODE: AtomicBool = AtomicBool::new(false);
+
+/// The layout and remaps keyboard drivers decode with, picked up by `KeyDecoder::follow_settings`
+/// whenever `SETTINGS_VERSION` changes.
+static SETTINGS: Mutex<Settings> = Mutex::new(Settings {
+    layout: Layout::Us,
+    remaps: Vec::new(),
+});
+static SETTINGS_VERSION: AtomicU64 = AtomicU64::new(1);
+
+struct Settings {
+    layout: Layout,
+    remaps: Vec<(KeyCode, KeyCode)>,
+}
+
+/// Called by the keyboard interrupt handler.
 pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
-            println!("WARNING: scancode queue full; dropping keyboard input");
-        } else {
-            WAKER.wake();
+    if REPLAY_MODE.load(Ordering::Relaxed) {
+        return;
+    }
+
+    push_scancode(scancode);
+}
+
+/// Replaces the PS/2 IRQ source with a scripted scancode sequence (scancode set 1).
+///
+/// Meant for tests: the scancodes are delivered exactly as if they had been read from port 0x60, and any
+/// real keyboard input is dropped from now on so it can't interleave with the script.
+pub fn replay_scancodes(scancodes: &[u8]) {
+    REPLAY_MODE.store(true, Ordering::Relaxed);
+
+    for &scancode in scancodes {
+        push_scancode(scancode);
+    }
+}
+
+fn push_scancode(scancode: u8) {
+    // Printing takes the framebuffer lock, which the interrupted code may be holding, so the warnings are
+    // deferred instead of printed from here.
+    match SCANCODES.push(scancode) {
+        Ok(()) => {}
+        Err(PushError::Full(_)) => {
+            let _ = interrupts::defer(warn_queue_full, 0);
+        }
+        // Without the keyboard task, as while processes run instead of the executor, deferred work
+        // decodes the scancode instead.
+        Err(PushError::NoConsumer(scancode)) => {
+            let _ = interrupts::defer(decode_deferred, usize::from(scancode));
         }
-    } else {
-        println!("WARNING: scancode queue uninitialized");
     }
 }
 
+/// Decodes a scancode the keyboard task wasn't there to take and emits it, like `run_keyboard` would.
+fn decode_deferred(scancode: usize) {
+    static DECODER: Mutex<Option<(DeviceId, KeyDecoder)>> = Mutex::new(None);
+
+    let mut decoder = DECODER.lock();
+    let (device, decoder) = decoder.get_or_insert_with(|| {
+        let device = input::register_device("PS/2 keyboard", DeviceKind::Keyboard);
+        (device, KeyDecoder::new())
+    });
+    decoder.follow_settings();
+    if let Some(event) = decoder.feed(scancode as u8) {
+        input::emit(*device, event);
+    }
+}
+
+fn warn_queue_full(_: usize) {
+    println!("WARNING: scancode queue full; dropping keyboard input");
+}
+
 pub struct ScancodeStream {
-    /// Prevents the struct from being constructed outside the module.
-    _private: (),
+    scancodes: IrqEvents<u8>,
 }
 
 impl ScancodeStream {
-    pub fn new() -> Self {
-        SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
-
-        ScancodeStream { _private: () }
+    /// Creates the only consumer of the scancode queue.
+    pub fn new() -> Result<Self, AlreadyInitialized> {
+        Ok(ScancodeStream {
+            scancodes: SCANCODES.take()?,
+        })
     }
 }
 
 impl Stream for ScancodeStream {
     type Item = u8;
 
-    fn poll_next(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<u8>> {
-        let queue = SCANCODE_QUEUE
-            .try_get()
-            .expect("scancode queue not initialized");
+    fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<u8>> {
+        Pin::new(&mut self.scancodes).poll_next(ctx)
+    }
+}
+
+/// Turns raw scancodes into keys, keeping track of modifier state across calls.
+pub struct KeyDecoder {
+    scancodes: ScancodeSet1,
+    events: EventDecoder<Layout>,
+    layout: Layout,
+    /// Keys that act as another, applied before anything else, so that a key remapped to a modifier
+    /// works as one.
+    remaps: Vec<(KeyCode, KeyCode)>,
+    /// Which modifiers are held, which `pc_keyboard` tracks but doesn't tell.
+    modifiers: Modifiers,
+    /// The `SETTINGS_VERSION` last applied by `follow_settings`.
+    settings_version: u64,
+}
+
+impl KeyDecoder {
+    /// A decoder for the US layout, without remaps.
+    pub fn new() -> Self {
+        KeyDecoder {
+            scancodes: ScancodeSet1::new(),
+            events: EventDecoder::new(Layout::Us, HandleControl::Ignore),
+            layout: Layout::Us,
+            remaps: Vec::new(),
+            modifiers: Modifiers::default(),
+            settings_version: 0,
+        }
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+        self.events.change_layout(layout);
+    }
+
+    /// Makes `from` act as `to`, or as itself again if they are the same.
+    pub fn remap(&mut self, from: KeyCode, to: KeyCode) {
+        self.remaps.retain(|&(key, _)| key != from);
+        if from != to {
+            self.remaps.push((from, to));
+        }
+    }
+
+    /// Drops every remap.
+    pub fn clear_remaps(&mut self) {
+        self.remaps.clear();
+    }
+
+    /// Returns `None` for scancodes that don't complete a key press, such as releases and prefixes.
+    pub fn decode(&mut self, scancode: u8) -> Option<DecodedKey> {
+        match self.feed(scancode) {
+            Some(Event::KeyPress { key, .. }) => key,
+            _ => None,
+        }
+    }
 
-        // Prevent initialization of WAKER. This is a fast path.
-        if let Some(scancode) = queue.pop() {
-            return Poll::Ready(Some(scancode));
+    /// Takes the layout and remaps set with `keyboard::set_layout` and `keyboard::remap`, if they
+    /// changed since the last call.
+    pub fn follow_settings(&mut self) {
+        let version = SETTINGS_VERSION.load(Ordering::Acquire);
+        if version == self.settings_version {
+            return;
         }
+        self.settings_version = version;
 
-        WAKER.register(&ctx.waker());
+        let settings = SETTINGS.lock();
+        self.set_layout(settings.layout);
+        self.clear_remaps();
+        for &(from, to) in &settings.remaps {
+            self.remap(from, to);
+        }
+    }
 
-        match queue.pop() {
-            Some(scancode) => {
-                WAKER.take();
-                Poll::Ready(Some(scancode))
+    /// The key press or release completed by `scancode`, if any.
+    pub fn feed(&mut self, scancode: u8) -> Option<Event> {
+        let key_event = self.scancodes.advance_state(scancode).ok()??;
+        self.process(key_event)
+    }
+
+    /// The key press or release for `key_event`, from a keyboard that doesn't send scancodes.
+    pub fn process(&mut self, mut key_event: KeyEvent) -> Option<Event> {
+        if let Some(&(_, to)) = self
+            .remaps
+            .iter()
+            .find(|&&(from, _)| from == key_event.code)
+        {
+            key_event.code = to;
+        }
+        let code = key_event.code;
+        self.track_modifiers(&key_event);
+
+        match key_event.state {
+            KeyState::Up => {
+                self.events.process_keyevent(key_event);
+                Some(Event::KeyRelease { code })
             }
-            None => Poll::Pending,
+            _ => Some(Event::KeyPress {
+                code,
+                key: self.events.process_keyevent(key_event),
+                modifiers: self.modifiers,
+            }),
+        }
+    }
+
+    /// Whether either Alt key is held.
+    pub fn alt_held(&self) -> bool {
+        self.modifiers.alt
+    }
+
+    fn track_modifiers(&mut self, event: &KeyEvent) {
+        let held = event.state != KeyState::Up;
+        match event.code {
+            KeyCode::LShift | KeyCode::RShift => self.modifiers.shift = held,
+            KeyCode::LControl | KeyCode::RControl => self.modifiers.ctrl = held,
+            KeyCode::LAlt | KeyCode::RAltGr => self.modifiers.alt = held,
+            _ => {}
         }
     }
 }
 
-pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(
-        ScancodeSet1::new(),
-        layouts::Us104Key,
-        HandleControl::Ignore,
-    );
-
-    // It repeatedly obtains a scancode from the stream.
+impl Default for KeyDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Switches every keyboard to `layout`.
+pub fn set_layout(layout: Layout) {
+    SETTINGS.lock().layout = layout;
+    SETTINGS_VERSION.fetch_add(1, Ordering::Release);
+}
+
+/// The layout keyboards decode with.
+pub fn layout() -> Layout {
+    SETTINGS.lock().layout
+}
+
+/// Makes `from` act as `to` on every keyboard, or as itself again if they are the same. For example,
+/// `remap(KeyCode::CapsLock, KeyCode::LControl)`.
+pub fn remap(from: KeyCode, to: KeyCode) {
+    let mut settings = SETTINGS.lock();
+    settings.remaps.retain(|&(key, _)| key != from);
+    if from != to {
+        settings.remaps.push((from, to));
+    }
+    SETTINGS_VERSION.fetch_add(1, Ordering::Release);
+}
+
+/// The remaps set with `remap`, as `(from, to)` pairs.
+pub fn remaps() -> Vec<(KeyCode, KeyCode)> {
+    SETTINGS.lock().remaps.clone()
+}
+
+/// The keyboard driver: decodes the scancodes and emits them as input events.
+pub async fn run_keyboard() {
+    let mut scancodes = ScancodeStream::new().expect("scancode stream already taken");
+    let device = input::register_device("PS/2 keyboard", DeviceKind::Keyboard);
+    let mut decoder = KeyDecoder::new();
+
     // `.next` is obtained by the `StreamExt` trait, which returns a future that resolves to the next element in the stream.
     while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
-                }
-            }
+        decoder.follow_settings();
+        if let Some(event) = decoder.feed(scancode) {
+            input::emit(device, event);
+        }
+    }
+}
+
+/// Echoes key presses to the shell console, and switches consoles with Alt+F1 to Alt+F4.
+pub async fn print_keypresses() {
+    let mut events = input::subscribe();
+
+    while let Some(input) = events.next().await {
+        if let Some(console) = console_switch(&input.event) {
+            console::switch(console);
+        } else if let Event::KeyPress { key: Some(key), .. } = input.event {
+            echo(key);
         }
     }
 }
+
+/// The console to switch to if `event` is an Alt+F1 to Alt+F4 press.
+pub fn console_switch(event: &Event) -> Option<Console> {
+    match *event {
+        Event::KeyPress {
+            code, modifiers, ..
+        } if modifiers.alt => Console::from_function_key(code),
+        _ => None,
+    }
+}
+
+/// Prints a key press to the shell console.
+pub fn echo(key: DecodedKey) {
+    match key {
+        DecodedKey::Unicode(character) => console_print!(Console::Shell, "{}", character),
+        DecodedKey::RawKey(key) => console_print!(Console::Shell, "{:?}", key),
+    }
+}
@@ -0,0 +1,122 @@
+//! The keyboard layouts that can be chosen at runtime.
+//!
+//! `pc_keyboard` has no Brazilian layout, so ABNT2 is mapped here: it only differs from US in the
+//! punctuation keys and AltGr, and falls back to `Us104Key` for the rest.
+
+use pc_keyboard::{
+    DecodedKey, HandleControl, KeyCode, KeyboardLayout, Modifiers,
+    layouts::{De105Key, Dvorak104Key, Us104Key},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Layout {
+    #[default]
+    Us,
+    /// Brazilian ABNT2, with the extra `/?` key next to the right shift.
+    Abnt2,
+    De,
+    Dvorak,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 4] = [Layout::Us, Layout::Abnt2, Layout::De, Layout::Dvorak];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Abnt2 => "abnt2",
+            Layout::De => "de",
+            Layout::Dvorak => "dvorak",
+        }
+    }
+
+    /// The layout called `name`, as returned by `name`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|layout| layout.name() == name)
+    }
+}
+
+impl KeyboardLayout for Layout {
+    fn map_keycode(
+        &self,
+        keycode: KeyCode,
+        modifiers: &Modifiers,
+        handle_ctrl: HandleControl,
+    ) -> DecodedKey {
+        match self {
+            Layout::Us => Us104Key.map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Abnt2 => match abnt2(keycode, modifiers) {
+                Some(character) => DecodedKey::Unicode(character),
+                None => Us104Key.map_keycode(keycode, modifiers, handle_ctrl),
+            },
+            Layout::De => De105Key.map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Dvorak => Dvorak104Key.map_keycode(keycode, modifiers, handle_ctrl),
+        }
+    }
+}
+
+/// The character of the keys where ABNT2 differs from US. Dead keys type their accent as is.
+fn abnt2(keycode: KeyCode, modifiers: &Modifiers) -> Option<char> {
+    if modifiers.alt_gr {
+        return match keycode {
+            KeyCode::Key1 => Some('¹'),
+            KeyCode::Key2 => Some('²'),
+            KeyCode::Key3 => Some('³'),
+            KeyCode::Key4 => Some('£'),
+            KeyCode::Key5 => Some('¢'),
+            KeyCode::Key6 => Some('¬'),
+            KeyCode::OemPlus => Some('§'),
+            KeyCode::Q => Some('/'),
+            KeyCode::W => Some('?'),
+            KeyCode::E => Some('°'),
+            KeyCode::Oem6 => Some('ª'),
+            KeyCode::Oem7 => Some('º'),
+            _ => None,
+        };
+    }
+
+    let (normal, shifted) = match keycode {
+        // Left of 1.
+        KeyCode::Oem8 => ('\'', '"'),
+        KeyCode::Key6 => ('6', '¨'),
+        // Right of P, then right of that.
+        KeyCode::Oem4 => ('´', '`'),
+        KeyCode::Oem6 => ('[', '{'),
+        // Right of L, then right of that.
+        KeyCode::Oem1 => ('ç', 'Ç'),
+        KeyCode::Oem3 => ('~', '^'),
+        // Left of Enter, on the home row.
+        KeyCode::Oem7 => (']', '}'),
+        // Left of Z.
+        KeyCode::Oem5 => ('\\', '|'),
+        // Right of the period, then the extra key next to the right shift.
+        KeyCode::Oem2 => (';', ':'),
+        KeyCode::Oem12 => ('/', '?'),
+        _ => return None,
+    };
+
+    let shift = match keycode {
+        KeyCode::Oem1 => modifiers.is_caps(),
+        _ => modifiers.is_shifted(),
+    };
+    Some(if shift { shifted } else { normal })
+}
+
+#[test_case]
+fn test_abnt2_layout() {
+    let map = |keycode, shift| {
+        let modifiers = Modifiers {
+            lshift: shift,
+            ..Modifiers::default()
+        };
+        Layout::Abnt2.map_keycode(keycode, &modifiers, HandleControl::Ignore)
+    };
+
+    assert_eq!(map(KeyCode::Oem1, false), DecodedKey::Unicode('ç'));
+    assert_eq!(map(KeyCode::Oem12, false), DecodedKey::Unicode('/'));
+    assert_eq!(map(KeyCode::Oem12, true), DecodedKey::Unicode('?'));
+    assert_eq!(map(KeyCode::Oem2, true), DecodedKey::Unicode(':'));
+    // Not one of the keys that differ from US.
+    assert_eq!(map(KeyCode::A, true), DecodedKey::Unicode('A'));
+    assert_eq!(Layout::from_name("abnt2"), Some(Layout::Abnt2));
+}
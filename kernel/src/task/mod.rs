@@ -3,9 +3,24 @@ use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::{Context, Poll};
 use core::{future::Future, pin::Pin};
 
+use crate::cpu;
+
+pub mod blocking;
+pub mod budget;
+pub mod catch;
+pub mod channel;
+pub mod combinators;
 pub mod executor;
+pub mod irq_stream;
 pub mod keyboard;
 pub mod simple_executor;
+pub mod sync;
+pub mod timer;
+pub mod watchdog;
+
+pub use blocking::spawn_blocking;
+pub use budget::yield_now;
+pub use executor::block_on;
 
 pub struct Task {
     /// Unique task ID.
@@ -15,6 +30,26 @@ pub struct Task {
     /// We store `Future` as a pinned `Box`, preventing it from being moved in memory and invalidating pointers
     /// to the self-referential structures of the state machine generated by the compiler in async functions.
     future: Pin<Box<dyn Future<Output = ()>>>,
+    priority: Priority,
+    /// Shown in statistics, to tell tasks apart.
+    name: Option<&'static str>,
+    polls: u64,
+    /// Time spent in `poll`, in TSC cycles.
+    poll_cycles: u64,
+}
+
+/// Priority classes of tasks. The executor runs every woken task of a higher class before any of a lower
+/// one, so tasks that handle input stay responsive while bulk work is runnable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+}
+
+impl Priority {
+    /// In the order they run.
+    pub const ALL: [Priority; 2] = [Priority::High, Priority::Normal];
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -35,13 +70,57 @@ impl Task {
     ///
     /// Because `Task` must be maintained indefinitely, we use the lifetime `'static` property on `Task`.
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task::from_pinned(Box::pin(future))
+    }
+
+    /// Create a new task with a name that shows up in the executor's statistics.
+    pub fn new_named(name: &'static str, future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            name: Some(name),
+            ..Task::new(future)
+        }
+    }
+
+    fn from_pinned(future: Pin<Box<dyn Future<Output = ()>>>) -> Task {
         Task {
             id: TaskId::new(),
-            future: Box::pin(future),
+            future,
+            priority: Priority::Normal,
+            name: None,
+            polls: 0,
+            poll_cycles: 0,
         }
     }
 
+    pub fn with_priority(mut self, priority: Priority) -> Task {
+        self.priority = priority;
+        self
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    /// Number of times the task was polled.
+    pub fn polls(&self) -> u64 {
+        self.polls
+    }
+
+    /// Time spent polling the task, in TSC cycles.
+    pub fn poll_cycles(&self) -> u64 {
+        self.poll_cycles
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        let start = cpu::rdtsc();
+        let result = self.future.as_mut().poll(context);
+
+        self.polls += 1;
+        self.poll_cycles += cpu::rdtsc().wrapping_sub(start);
+        result
     }
 }
@@ -0,0 +1,323 @@
+//! Locks for sharing state between tasks.
+//!
+//! Locking returns a future: a task that finds the lock taken registers its waker and returns `Pending`,
+//! so the executor runs other tasks, and the one holding the lock, until it is released. Unlike with
+//! `spin::Mutex`, a guard can be held across an `.await` and interrupts stay enabled while waiting.
+//!
+//! The locks aren't fair: a released lock goes to whoever asks next, which may not be the task that was
+//! woken. They must not be used from interrupt handlers, which can't wait.
+
+use alloc::collections::VecDeque;
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+/// Tasks waiting for a lock, woken in the order they started waiting.
+struct Waiters {
+    wakers: spin::Mutex<VecDeque<Waker>>,
+}
+
+impl Waiters {
+    const fn new() -> Self {
+        Waiters {
+            wakers: spin::Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn register(&self, waker: &Waker) {
+        let mut wakers = self.wakers.lock();
+        if !wakers.iter().any(|queued| queued.will_wake(waker)) {
+            wakers.push_back(waker.clone());
+        }
+    }
+
+    fn wake_one(&self) {
+        // Popped before waking, since waking may poll the waiter on some executors and it locks again.
+        let waker = self.wakers.lock().pop_front();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    fn wake_all(&self) {
+        let wakers = core::mem::take(&mut *self.wakers.lock());
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+/// Completes once `try_acquire` succeeds.
+struct Acquire<'a, F: Fn() -> bool> {
+    waiters: &'a Waiters,
+    try_acquire: F,
+    /// Set while registered as a waiter.
+    waiting: bool,
+}
+
+impl<F: Fn() -> bool> Future for Acquire<'_, F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if (self.try_acquire)() {
+            self.waiting = false;
+            return Poll::Ready(());
+        }
+
+        // Register before trying again, otherwise a release in between would be missed.
+        self.waiters.register(context.waker());
+        if (self.try_acquire)() {
+            self.waiting = false;
+            return Poll::Ready(());
+        }
+
+        self.waiting = true;
+        Poll::Pending
+    }
+}
+
+impl<F: Fn() -> bool> Unpin for Acquire<'_, F> {}
+
+impl<F: Fn() -> bool> Drop for Acquire<'_, F> {
+    fn drop(&mut self) {
+        // This waiter may have been woken for a release it won't take, so another one gets the chance.
+        if self.waiting {
+            self.waiters.wake_one();
+        }
+    }
+}
+
+/// A mutual exclusion lock for tasks.
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: Waiters,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: Waiters::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Locks the mutex, waiting while another task holds it.
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        Acquire {
+            waiters: &self.waiters,
+            try_acquire: || self.acquire(),
+            waiting: false,
+        }
+        .await;
+
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.acquire().then_some(MutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    fn acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("value", &*guard).finish(),
+            None => f.write_str("Mutex { <locked> }"),
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
+
+/// `RwLock::state` while a writer holds the lock. Otherwise the state is the number of readers.
+const WRITER: usize = usize::MAX;
+
+/// A lock for tasks that lets many readers or a single writer in.
+///
+/// Readers are let in whenever no writer holds the lock, so a steady stream of readers can keep a
+/// writer waiting.
+pub struct RwLock<T> {
+    state: AtomicUsize,
+    waiters: Waiters,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: AtomicUsize::new(0),
+            waiters: Waiters::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Locks for reading, waiting while a writer holds the lock.
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        Acquire {
+            waiters: &self.waiters,
+            try_acquire: || self.acquire_read(),
+            waiting: false,
+        }
+        .await;
+
+        RwLockReadGuard { lock: self }
+    }
+
+    /// Locks for writing, waiting while anyone else holds the lock.
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        Acquire {
+            waiters: &self.waiters,
+            try_acquire: || self.acquire_write(),
+            waiting: false,
+        }
+        .await;
+
+        RwLockWriteGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.acquire_read()
+            .then_some(RwLockReadGuard { lock: self })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.acquire_write()
+            .then_some(RwLockWriteGuard { lock: self })
+    }
+
+    /// Number of readers holding the lock.
+    pub fn reader_count(&self) -> usize {
+        match self.state.load(Ordering::Relaxed) {
+            WRITER => 0,
+            readers => readers,
+        }
+    }
+
+    pub fn is_write_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) == WRITER
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    fn acquire_read(&self) -> bool {
+        self.state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |readers| {
+                (readers < WRITER - 1).then_some(readers + 1)
+            })
+            .is_ok()
+    }
+
+    fn acquire_write(&self) -> bool {
+        self.state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f.debug_struct("RwLock").field("value", &*guard).finish(),
+            None => f.write_str("RwLock { <locked> }"),
+        }
+    }
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // Only writers wait while readers hold the lock, and one of them can take it once the last
+        // reader is gone.
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.lock.waiters.wake_one();
+        }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        // Any number of waiting readers can go on together.
+        self.lock.waiters.wake_all();
+    }
+}
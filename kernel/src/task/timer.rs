@@ -0,0 +1,288 @@
+//! Timers for async code: `sleep`, `interval` and `timeout`.
+//!
+//! Waiting tasks register their waker on a timer wheel under the tick they wait for. The wheel is advanced
+//! by `run_timers`, a task like `run_deferred_work` that must be spawned for timers to fire at all. The
+//! timer interrupt only wakes that task while some timer is pending, so that the wakers of waiting tasks
+//! are never dropped in the interrupt handler, where dropping the last reference to one would free memory.
+//!
+//! Deadlines are in timer ticks, so timers have the resolution of a tick and fire at least as late as
+//! asked. A timer that is dropped before firing, e.g. the sleep of a `timeout` whose future completed,
+//! takes itself off the wheel.
+
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use futures_util::{stream::Stream, task::AtomicWaker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::time::{self, wheel::TimerWheel};
+
+/// Only locked with interrupts disabled, like the queues the interrupt handlers push to.
+static WHEEL: Mutex<TimerWheel<Timer>> = Mutex::new(TimerWheel::new(0));
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+/// Set while the wheel holds timers, so the interrupt handler knows to wake `run_timers`.
+static ARMED: AtomicBool = AtomicBool::new(false);
+static DRIVER: AtomicWaker = AtomicWaker::new();
+
+/// Called by the timer interrupt handler.
+pub(crate) fn on_tick() {
+    if ARMED.load(Ordering::Acquire) {
+        DRIVER.wake();
+    }
+}
+
+#[derive(Debug)]
+struct Timer {
+    /// Tells apart the timers of different `Sleep`s, whose wakers may be the same.
+    id: u64,
+    waker: Waker,
+}
+
+/// A timer on the wheel, see `register`.
+#[derive(Debug)]
+struct Registration {
+    id: u64,
+    /// What the wheel filed the timer under.
+    deadline: u64,
+    waker: Waker,
+}
+
+fn register(deadline: u64, waker: &Waker) -> Registration {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let timer = Timer {
+        id,
+        waker: waker.clone(),
+    };
+
+    let deadline = interrupts::without_interrupts(|| {
+        let deadline = WHEEL.lock().insert(deadline, timer);
+        ARMED.store(true, Ordering::Release);
+        deadline
+    });
+
+    Registration {
+        id,
+        deadline,
+        waker: waker.clone(),
+    }
+}
+
+/// Takes the timer off the wheel, unless it already fired.
+fn deregister(registration: Registration) {
+    let removed = interrupts::without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        let removed = wheel.remove_at(registration.deadline, |timer| timer.id == registration.id);
+        ARMED.store(!wheel.is_empty(), Ordering::Release);
+        removed
+    });
+    // Like the wakers of fired timers, dropped outside of the lock.
+    drop(removed);
+}
+
+/// Wakes the tasks whose timers are due and returns how many there were.
+pub fn fire_expired() -> usize {
+    let mut expired = Vec::new();
+
+    interrupts::without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        wheel.expire(time::ticks(), |timer| expired.push(timer.waker));
+        ARMED.store(!wheel.is_empty(), Ordering::Release);
+    });
+
+    // Waking pushes to the task queue, which can only grow with interrupts enabled.
+    let count = expired.len();
+    for waker in expired {
+        waker.wake();
+    }
+    count
+}
+
+/// Number of timers on the wheel.
+pub fn pending_count() -> usize {
+    interrupts::without_interrupts(|| WHEEL.lock().len())
+}
+
+/// Executor task that fires timers as they become due.
+pub async fn run_timers() {
+    loop {
+        TimersDue.await;
+        fire_expired();
+    }
+}
+
+/// Resolves once a tick passed while some timer was pending.
+struct TimersDue;
+
+impl Future for TimersDue {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        // Register before checking, otherwise a tick between the check and the registration would be missed.
+        DRIVER.register(context.waker());
+
+        let due = interrupts::without_interrupts(|| {
+            let wheel = WHEEL.lock();
+            !wheel.is_empty() && time::ticks() > wheel.now()
+        });
+
+        if due {
+            DRIVER.take();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Completes at a given tick, see `sleep`.
+#[derive(Debug)]
+pub struct Sleep {
+    deadline: u64,
+    /// The timer on the wheel, so that polling again with the same waker doesn't add another one.
+    registered: Option<Registration>,
+}
+
+/// Completes once at least `duration` has passed.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(time::ticks().saturating_add(time::ticks_for(duration)))
+}
+
+/// Completes once the tick count reaches `deadline`.
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep {
+        deadline,
+        registered: None,
+    }
+}
+
+impl Sleep {
+    /// The tick the sleep completes at.
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if time::ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        let waker = context.waker();
+        if !self
+            .registered
+            .as_ref()
+            .is_some_and(|registered| registered.waker.will_wake(waker))
+        {
+            if let Some(old) = self.registered.take() {
+                deregister(old);
+            }
+            self.registered = Some(register(self.deadline, waker));
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(registration) = self.registered.take() {
+            deregister(registration);
+        }
+    }
+}
+
+/// Yields once per period, see `interval`.
+#[derive(Debug)]
+pub struct Interval {
+    period_ticks: u64,
+    sleep: Sleep,
+}
+
+/// A stream that yields every `period`, starting one period from now. A period is at least one tick.
+///
+/// Periods missed because the task didn't poll in time are skipped rather than yielded in a burst, so the
+/// interval keeps its phase.
+pub fn interval(period: Duration) -> Interval {
+    // `ticks_for` counts the tick in progress as none of the period, which an interval doesn't need.
+    let period_ticks = (time::ticks_for(period) - 1).max(1);
+
+    Interval {
+        period_ticks,
+        sleep: sleep_until(time::ticks().saturating_add(period_ticks)),
+    }
+}
+
+impl Interval {
+    /// Completes at the next period.
+    pub async fn tick(&mut self) {
+        futures_util::StreamExt::next(self).await;
+    }
+}
+
+impl Stream for Interval {
+    type Item = ();
+
+    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<()>> {
+        if Pin::new(&mut self.sleep).poll(context).is_pending() {
+            return Poll::Pending;
+        }
+
+        let now = time::ticks();
+        let missed = (now - self.sleep.deadline) / self.period_ticks;
+        let deadline = self.sleep.deadline + (missed + 1) * self.period_ticks;
+        self.sleep = sleep_until(deadline);
+
+        Poll::Ready(Some(()))
+    }
+}
+
+/// The future of a `timeout` didn't complete in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Runs `future` for at most `duration`, see `timeout`.
+#[derive(Debug)]
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+/// Resolves to the output of `future`, or to `Elapsed` if it doesn't complete within `duration`.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        // SAFETY: `future` is never moved out of the pinned `Timeout`, and `sleep` is `Unpin`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        if let Poll::Ready(output) = future.poll(context) {
+            return Poll::Ready(Ok(output));
+        }
+
+        Pin::new(&mut this.sleep)
+            .poll(context)
+            .map(|()| Err(Elapsed))
+    }
+}
+
+#[test_case]
+fn test_sleep_saturates_far_deadlines() {
+    assert_eq!(sleep(Duration::MAX).deadline(), u64::MAX);
+}
@@ -0,0 +1,142 @@
+//! Watchdog for tasks that stop making progress.
+//!
+//! A task that should do something regularly, like a driver task handling its device's events, calls
+//! `feed` with its name each time it does. `run_watchdog`, a high-priority task, checks every heartbeat
+//! periodically and reports the ones that weren't fed within their timeout, which turns a silent hang into
+//! a message naming what hung. Unlike the lockup detector, which catches code that never returns to the
+//! executor, this catches tasks that wait forever while the others run on.
+//!
+//! Heartbeats are kept in a map, so `feed` allocates the first time and can't be called from interrupt
+//! handlers.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::time::Duration;
+use spin::Mutex;
+
+use super::timer;
+use crate::{
+    console::{self, Console},
+    serial_println, time,
+};
+
+/// Timeout of heartbeats that are fed without being set up by `watch`.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often `run_watchdog` checks the heartbeats.
+pub const CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+static HEARTBEATS: Mutex<BTreeMap<&'static str, Heartbeat>> = Mutex::new(BTreeMap::new());
+
+struct Heartbeat {
+    last_fed_nanos: u64,
+    timeout: Duration,
+    /// Only one report per stall.
+    reported: bool,
+}
+
+/// A heartbeat that wasn't fed in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stall {
+    pub name: &'static str,
+    pub stalled_for: Duration,
+    pub timeout: Duration,
+}
+
+/// What `run_watchdog` does about a stall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallAction {
+    Log,
+    /// Reports the stall and halts, like a panic would. Panicking in the watchdog task would only end the
+    /// task, since the executor catches panics.
+    Halt,
+}
+
+/// Starts watching `name`, which stalls if it isn't fed for longer than `timeout`. Counts as a feed.
+pub fn watch(name: &'static str, timeout: Duration) {
+    HEARTBEATS.lock().insert(
+        name,
+        Heartbeat {
+            last_fed_nanos: time::monotonic_nanos(),
+            timeout,
+            reported: false,
+        },
+    );
+}
+
+/// Stops watching `name`, e.g. because its task completed.
+pub fn unwatch(name: &'static str) {
+    HEARTBEATS.lock().remove(name);
+}
+
+/// Records that `name` made progress, and starts watching it with `DEFAULT_TIMEOUT` if it wasn't yet.
+pub fn feed(name: &'static str) {
+    let now = time::monotonic_nanos();
+    let mut heartbeats = HEARTBEATS.lock();
+
+    match heartbeats.get_mut(name) {
+        Some(heartbeat) => {
+            heartbeat.last_fed_nanos = now;
+            heartbeat.reported = false;
+        }
+        None => {
+            heartbeats.insert(
+                name,
+                Heartbeat {
+                    last_fed_nanos: now,
+                    timeout: DEFAULT_TIMEOUT,
+                    reported: false,
+                },
+            );
+        }
+    }
+}
+
+/// Returns the heartbeats that stalled since they were last fed or checked. A stall is only returned
+/// once, until the heartbeat is fed again.
+pub fn check() -> Vec<Stall> {
+    let now = time::monotonic_nanos();
+    let mut stalls = Vec::new();
+
+    for (&name, heartbeat) in HEARTBEATS.lock().iter_mut() {
+        let stalled_for = Duration::from_nanos(now.saturating_sub(heartbeat.last_fed_nanos));
+
+        if stalled_for > heartbeat.timeout && !heartbeat.reported {
+            heartbeat.reported = true;
+            stalls.push(Stall {
+                name,
+                stalled_for,
+                timeout: heartbeat.timeout,
+            });
+        }
+    }
+
+    stalls
+}
+
+/// Executor task that checks the heartbeats every `CHECK_PERIOD` and reports stalls, halting on the first
+/// one if `action` says so. Should be spawned with a high priority, so busy tasks don't delay it.
+pub async fn run_watchdog(action: StallAction) {
+    let mut interval = timer::interval(CHECK_PERIOD);
+
+    loop {
+        interval.tick().await;
+
+        for stall in check() {
+            serial_println!(
+                "watchdog: {} not fed for {:?} (timeout {:?})",
+                stall.name,
+                stall.stalled_for,
+                stall.timeout
+            );
+            // The details already went to the serial port.
+            console::print_to_screen(
+                Console::Log,
+                None,
+                format_args!("watchdog: {} stalled\n", stall.name),
+            );
+
+            if action == StallAction::Halt {
+                crate::hlt_loop();
+            }
+        }
+    }
+}
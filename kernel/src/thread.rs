@@ -0,0 +1,290 @@
+//! Kernel threads.
+//!
+//! A kernel thread runs a function in ring 0 on its own stack, for long-running kernel work that fits
+//! neither the async executor nor an interrupt handler. Threads are scheduled cooperatively: a thread
+//! keeps the CPU until it yields, blocks or returns, and then the next ready thread continues in FIFO
+//! order. The code that booted the kernel, which goes on to run the executor, is thread 0 and yields like
+//! any other thread.
+//!
+//! User processes are scheduled separately by `process`. Once the kernel has entered user mode, kernel
+//! threads don't get the CPU anymore.
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    vec::Vec,
+};
+use core::{
+    arch::naked_asm,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::{lockup, process::Stack};
+
+const STACK_SIZE: usize = 4096 * 4;
+
+/// Threads that haven't returned yet.
+static THREADS: Mutex<BTreeMap<ThreadId, Thread>> = Mutex::new(BTreeMap::new());
+/// Ready threads, in the order they will run.
+static READY: Mutex<VecDeque<ThreadId>> = Mutex::new(VecDeque::new());
+/// Stacks of threads that returned. A thread can't free the stack it runs on, so the next thread does.
+static FINISHED: Mutex<Vec<Stack>> = Mutex::new(Vec::new());
+static CURRENT: AtomicU64 = AtomicU64::new(0);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    /// The thread that booted the kernel.
+    pub const BOOT: ThreadId = ThreadId(0);
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    /// Sleeping until `wake` is called on it.
+    Blocked,
+}
+
+struct Thread {
+    state: State,
+    /// `None` for the boot thread, which runs on the bootloader's stack.
+    stack: Option<Stack>,
+    /// Stack pointer while the thread is switched out. Boxed so that its address stays valid while
+    /// `switch` writes it, even if the table changes in between.
+    saved_rsp: Box<u64>,
+}
+
+impl Thread {
+    fn boot() -> Self {
+        Thread {
+            state: State::Running,
+            stack: None,
+            saved_rsp: Box::new(0),
+        }
+    }
+}
+
+/// Creates a ready thread that runs `entry`. It runs once the current thread yields or blocks.
+pub fn spawn(entry: fn()) -> ThreadId {
+    let id = ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let stack = Stack::new(STACK_SIZE);
+
+    // The initial stack looks like `switch` left it: callee-saved registers, then the return address,
+    // which starts the thread with `entry` in RBX.
+    let top = stack.top().as_mut_ptr::<u64>();
+    let initial = [
+        0,
+        0,
+        0,
+        0,
+        entry as usize as u64,
+        0,
+        thread_start as *const () as u64,
+    ];
+    let saved_rsp = unsafe {
+        let rsp = top.sub(initial.len());
+        rsp.copy_from_nonoverlapping(initial.as_ptr(), initial.len());
+        rsp
+    };
+
+    let thread = Thread {
+        state: State::Ready,
+        stack: Some(stack),
+        saved_rsp: Box::new(saved_rsp as u64),
+    };
+
+    interrupts::without_interrupts(|| {
+        THREADS.lock().insert(id, thread);
+        READY.lock().push_back(id);
+    });
+
+    id
+}
+
+/// The thread on the CPU.
+pub fn current() -> ThreadId {
+    ThreadId(CURRENT.load(Ordering::Relaxed))
+}
+
+/// The thread's state, or `None` if it has returned.
+pub fn state(id: ThreadId) -> Option<State> {
+    interrupts::without_interrupts(|| {
+        let threads = THREADS.lock();
+
+        match threads.get(&id) {
+            Some(thread) => Some(thread.state),
+            // The boot thread is only added to the table the first time it switches out.
+            None => (id == ThreadId::BOOT).then_some(State::Running),
+        }
+    })
+}
+
+/// Whether a thread is waiting for the CPU.
+pub fn has_ready() -> bool {
+    interrupts::without_interrupts(|| !READY.lock().is_empty())
+}
+
+/// Lets the other ready threads run, returning once it's this thread's turn again.
+pub fn yield_now() {
+    reschedule(State::Ready);
+}
+
+/// Puts the current thread to sleep until another thread or an interrupt handler calls `wake` on it.
+///
+/// To wait for a condition without missing the wakeup, check the condition and block with interrupts
+/// disabled; interrupts are then enabled only once the thread is switched out.
+pub fn block_current() {
+    reschedule(State::Blocked);
+}
+
+/// Makes a blocked thread ready again. Safe to call from interrupt handlers.
+pub fn wake(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+
+        if let Some(thread) = threads.get_mut(&id)
+            && thread.state == State::Blocked
+        {
+            thread.state = State::Ready;
+            READY.lock().push_back(id);
+        }
+    });
+}
+
+fn reschedule(state: State) {
+    let interrupts_enabled = interrupts::are_enabled();
+    interrupts::disable();
+
+    let current = current();
+    {
+        let mut threads = THREADS.lock();
+        let thread = threads.entry(current).or_insert_with(Thread::boot);
+        thread.state = state;
+
+        if state == State::Ready {
+            READY.lock().push_back(current);
+        }
+    }
+
+    let next = next_ready();
+    if next == current {
+        THREADS.lock().get_mut(&current).unwrap().state = State::Running;
+    } else {
+        let saved_rsp: *mut u64 = &mut *THREADS.lock().get_mut(&current).unwrap().saved_rsp;
+        unsafe { switch_to(saved_rsp, next) };
+        free_finished();
+    }
+
+    if interrupts_enabled {
+        interrupts::enable();
+    }
+}
+
+/// Takes the next ready thread, halting until an interrupt wakes one if there is none. Called with
+/// interrupts disabled.
+fn next_ready() -> ThreadId {
+    loop {
+        if let Some(next) = READY.lock().pop_front() {
+            return next;
+        }
+
+        lockup::idle(interrupts::enable_and_hlt);
+        interrupts::disable();
+    }
+}
+
+/// Saves the stack pointer to `saved_rsp` and continues `next`, returning once the current thread is
+/// switched back in. Called with interrupts disabled and no locks held.
+unsafe fn switch_to(saved_rsp: *mut u64, next: ThreadId) {
+    let next_rsp = {
+        let mut threads = THREADS.lock();
+        let thread = threads.get_mut(&next).expect("ready thread missing");
+        thread.state = State::Running;
+        *thread.saved_rsp
+    };
+
+    CURRENT.store(next.0, Ordering::Relaxed);
+    unsafe { switch(saved_rsp, next_rsp) };
+}
+
+/// Pushes the callee-saved registers, stores the stack pointer to `saved_rsp`, then loads `next_rsp`
+/// and pops the registers saved there. Everything else was saved by the caller.
+#[unsafe(naked)]
+unsafe extern "C" fn switch(saved_rsp: *mut u64, next_rsp: u64) {
+    naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    );
+}
+
+/// Where `switch` first returns to in a new thread, with the entry point in RBX.
+#[unsafe(naked)]
+unsafe extern "C" fn thread_start() {
+    naked_asm!(
+        "mov rdi, rbx",
+        "call {thread_main}",
+        "ud2",
+        thread_main = sym thread_main,
+    );
+}
+
+extern "C" fn thread_main(entry: *const ()) -> ! {
+    // Passed as a raw pointer since `fn()` has no C representation; `spawn` put a `fn()` there.
+    let entry: fn() = unsafe { core::mem::transmute(entry) };
+
+    free_finished();
+    interrupts::enable();
+
+    entry();
+    exit();
+}
+
+/// Ends the current thread. Its stack is freed once another thread runs.
+fn exit() -> ! {
+    interrupts::disable();
+
+    let thread = THREADS
+        .lock()
+        .remove(&current())
+        .expect("current thread missing");
+    FINISHED.lock().extend(thread.stack);
+
+    // Nothing resumes this thread, so where its stack pointer ends up doesn't matter.
+    let mut discarded = 0;
+    unsafe { switch_to(&mut discarded, next_ready()) };
+    unreachable!("finished thread resumed");
+}
+
+fn free_finished() {
+    let stacks = core::mem::take(&mut *FINISHED.lock());
+    drop(stacks);
+}
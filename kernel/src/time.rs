@@ -0,0 +1,286 @@
+//! Kernel timekeeping.
+//!
+//! The timer interrupt is the heartbeat of the kernel. It starts out driven by the PIT and is moved to the
+//! local APIC timer by `init` once memory is set up. Everything that needs the passage of time (scheduling,
+//! async timers) should be built on top of the tick exposed here instead of reprogramming a timer itself.
+
+use core::{
+    sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{apic, interrupts, rtc};
+
+pub mod hpet;
+pub mod pit;
+pub mod tsc;
+pub mod wheel;
+
+pub const DEFAULT_TICK_HZ: u32 = 100;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TICK_HZ: AtomicU32 = AtomicU32::new(DEFAULT_TICK_HZ);
+/// Accumulated per tick so the uptime stays correct across `set_tick_hz` calls.
+static UPTIME_NANOS: AtomicU64 = AtomicU64::new(0);
+static NANOS_PER_TICK: AtomicU64 = AtomicU64::new(NANOS_PER_SECOND / DEFAULT_TICK_HZ as u64);
+static TICK_SOURCE: AtomicU8 = AtomicU8::new(TickSource::Pit as u8);
+/// Unix time (in nanoseconds) at the moment the kernel booted.
+static BOOT_EPOCH_NANOS: AtomicU64 = AtomicU64::new(0);
+static WALL_CLOCK_SET: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TickSource {
+    Pit,
+    Apic,
+}
+
+/// Starts ticking with the PIT, which needs no memory setup. Called from `kernel::init`.
+pub(crate) fn init_pit() {
+    pit::set_frequency(DEFAULT_TICK_HZ);
+}
+
+/// Sets up the clock sources and reads the wall clock from the RTC.
+///
+/// The HPET and the TSC (if invariant) take over the monotonic clock, and the tick moves from the PIT to
+/// the calibrated local APIC timer, if there is one.
+/// Must be called after `memory::init`, and after `acpi::init` for the HPET to be found.
+pub fn init() {
+    hpet::init(monotonic_nanos());
+    tsc::init(monotonic_nanos);
+    set_wall_clock(rtc::read().unix_timestamp() * NANOS_PER_SECOND);
+
+    if !apic::init() {
+        return;
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // Stop the PIT from raising IRQ0, otherwise the timer vector would fire from both sources.
+        interrupts::mask_irq(0);
+        TICK_SOURCE.store(TickSource::Apic as u8, Ordering::Relaxed);
+        apic::start_periodic_timer(tick_hz());
+    });
+}
+
+/// Changes how many times per second the timer interrupt fires.
+pub fn set_tick_hz(hz: u32) {
+    assert!(hz > 0, "tick frequency must be positive");
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        match tick_source() {
+            TickSource::Pit => pit::set_frequency(hz),
+            TickSource::Apic => apic::start_periodic_timer(hz),
+        }
+
+        TICK_HZ.store(hz, Ordering::Relaxed);
+        NANOS_PER_TICK.store(NANOS_PER_SECOND / hz as u64, Ordering::Relaxed);
+    });
+}
+
+pub fn tick_hz() -> u32 {
+    TICK_HZ.load(Ordering::Relaxed)
+}
+
+pub fn nanos_per_tick() -> u64 {
+    NANOS_PER_TICK.load(Ordering::Relaxed)
+}
+
+pub fn tick_source() -> TickSource {
+    match TICK_SOURCE.load(Ordering::Relaxed) {
+        0 => TickSource::Pit,
+        _ => TickSource::Apic,
+    }
+}
+
+/// Number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Time since boot with the resolution of one tick.
+pub fn uptime_nanos() -> u64 {
+    UPTIME_NANOS.load(Ordering::Relaxed)
+}
+
+/// Ticks after which at least `duration` has passed. The tick in progress counts as none of it.
+pub fn ticks_for(duration: Duration) -> u64 {
+    let nanos_per_tick = u128::from(NANOS_PER_TICK.load(Ordering::Relaxed));
+    let ticks = duration.as_nanos().div_ceil(nanos_per_tick) + 1;
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
+
+/// Called by the timer interrupt handler.
+pub(crate) fn on_tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    UPTIME_NANOS.fetch_add(NANOS_PER_TICK.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// Nanoseconds since boot from the best available clock source.
+///
+/// An invariant TSC is preferred since it is the cheapest to read, then the HPET, and only then the tick
+/// count, whose resolution is only one tick.
+pub fn monotonic_nanos() -> u64 {
+    if tsc::is_enabled() {
+        tsc::nanos()
+    } else if hpet::is_enabled() {
+        hpet::nanos()
+    } else {
+        uptime_nanos()
+    }
+}
+
+/// A point in time on the monotonic clock, which never goes backwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant {
+            nanos: monotonic_nanos(),
+        }
+    }
+
+    /// Saturates to zero if `earlier` is actually later than `self`.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+
+        Some(Instant {
+            nanos: self.nanos.checked_add(nanos)?,
+        })
+    }
+
+    /// Time since boot.
+    pub fn as_duration(&self) -> Duration {
+        Duration::from_nanos(self.nanos)
+    }
+}
+
+/// Clock identifiers accepted by `clock_gettime`, numbered like POSIX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum ClockId {
+    Realtime = 0,
+    Monotonic = 1,
+}
+
+impl TryFrom<u64> for ClockId {
+    type Error = ClockError;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ClockId::Realtime),
+            1 => Ok(ClockId::Monotonic),
+            _ => Err(ClockError::InvalidClock),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockError {
+    InvalidClock,
+    /// The wall clock has not been read from any source yet.
+    WallClockUnset,
+}
+
+/// Layout shared with userspace, like `struct timespec`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(C)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl Timespec {
+    pub fn from_nanos(nanos: u64) -> Self {
+        Timespec {
+            tv_sec: (nanos / NANOS_PER_SECOND) as i64,
+            tv_nsec: (nanos % NANOS_PER_SECOND) as i64,
+        }
+    }
+
+    /// The duration, if this is a valid one: not negative, with nanoseconds below a second.
+    pub fn to_duration(self) -> Option<Duration> {
+        let seconds = u64::try_from(self.tv_sec).ok()?;
+        let nanos = u32::try_from(self.tv_nsec)
+            .ok()
+            .filter(|&nanos| u64::from(nanos) < NANOS_PER_SECOND)?;
+
+        Some(Duration::new(seconds, nanos))
+    }
+}
+
+impl From<Timespec> for Duration {
+    fn from(timespec: Timespec) -> Self {
+        Duration::new(timespec.tv_sec as u64, timespec.tv_nsec as u32)
+    }
+}
+
+/// Sets the current wall-clock time, as nanoseconds since the Unix epoch.
+pub fn set_wall_clock(unix_nanos: u64) {
+    BOOT_EPOCH_NANOS.store(
+        unix_nanos.saturating_sub(monotonic_nanos()),
+        Ordering::Relaxed,
+    );
+    WALL_CLOCK_SET.store(true, Ordering::Release);
+}
+
+/// Kernel side of the `clock_gettime` syscall.
+pub fn clock_gettime(clock: ClockId) -> Result<Timespec, ClockError> {
+    match clock {
+        ClockId::Monotonic => Ok(Timespec::from_nanos(monotonic_nanos())),
+        ClockId::Realtime => {
+            if !WALL_CLOCK_SET.load(Ordering::Acquire) {
+                return Err(ClockError::WallClockUnset);
+            }
+
+            let boot_epoch = BOOT_EPOCH_NANOS.load(Ordering::Relaxed);
+            Ok(Timespec::from_nanos(boot_epoch + monotonic_nanos()))
+        }
+    }
+}
+
+#[test_case]
+fn test_timespec_from_nanos() {
+    let timespec = Timespec::from_nanos(3 * NANOS_PER_SECOND + 250);
+
+    assert_eq!(timespec.tv_sec, 3);
+    assert_eq!(timespec.tv_nsec, 250);
+    assert_eq!(Duration::from(timespec), Duration::new(3, 250));
+    assert_eq!(timespec.to_duration(), Some(Duration::new(3, 250)));
+}
+
+#[test_case]
+fn test_invalid_timespecs_have_no_duration() {
+    let negative = Timespec {
+        tv_sec: -1,
+        tv_nsec: 0,
+    };
+    let too_many_nanos = Timespec {
+        tv_sec: 0,
+        tv_nsec: NANOS_PER_SECOND as i64,
+    };
+
+    assert_eq!(negative.to_duration(), None);
+    assert_eq!(too_many_nanos.to_duration(), None);
+}
+
+#[test_case]
+fn test_sleeps_last_at_least_their_duration() {
+    let tick = Duration::from_nanos(NANOS_PER_TICK.load(Ordering::Relaxed));
+
+    assert_eq!(ticks_for(Duration::ZERO), 1);
+    assert_eq!(ticks_for(tick), 2);
+    assert_eq!(ticks_for(tick + Duration::from_nanos(1)), 3);
+}
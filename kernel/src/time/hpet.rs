@@ -0,0 +1,199 @@
+//! HPET (High Precision Event Timer) driver.
+//!
+//! The HPET has a free-running main counter with a fixed period (reported in femtoseconds) and a few
+//! comparators that raise an interrupt when the counter reaches a given value. Its location is described
+//! by the ACPI "HPET" table.
+
+use conquer_once::spin::OnceCell;
+use core::{ptr, time::Duration};
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{
+    acpi::{self, GenericAddress, SdtHeader},
+    interrupts, memory,
+    time::{self, TickSource},
+};
+
+const REGISTER_CAPABILITIES: usize = 0x000;
+const REGISTER_CONFIG: usize = 0x010;
+const REGISTER_MAIN_COUNTER: usize = 0x0F0;
+
+const CAPABILITY_64_BIT_COUNTER: u64 = 1 << 13;
+const CAPABILITY_LEGACY_ROUTE: u64 = 1 << 15;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+/// Routes timer 0 to IRQ0 and timer 1 to IRQ8, taking these lines over from the PIT and the RTC.
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+
+/// The comparator used for one-shot interrupts. With legacy routing it raises IRQ8.
+const ONESHOT_TIMER: usize = 1;
+
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+static ONESHOT_CALLBACK: Mutex<Option<fn()>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct HpetTable {
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+struct Hpet {
+    base: VirtAddr,
+    /// Main counter period in femtoseconds.
+    period: u64,
+    legacy_route: bool,
+    /// Monotonic time when the counter was started, so switching to the HPET doesn't make time jump back.
+    base_nanos: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    NotPresent,
+    /// The comparator interrupt can only be routed through IRQ8, which requires the tick to come from the
+    /// local APIC since legacy routing also disconnects the PIT.
+    RoutingUnavailable,
+}
+
+impl Hpet {
+    fn read(&self, register: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.base + register as u64).as_ptr::<u64>()) }
+    }
+
+    fn write(&self, register: usize, value: u64) {
+        unsafe { ptr::write_volatile((self.base + register as u64).as_mut_ptr::<u64>(), value) }
+    }
+
+    fn timer_config_register(timer: usize) -> usize {
+        0x100 + 0x20 * timer
+    }
+
+    fn timer_comparator_register(timer: usize) -> usize {
+        0x108 + 0x20 * timer
+    }
+}
+
+/// Finds the HPET through ACPI and starts its main counter from zero.
+///
+/// Only HPETs with a 64-bit main counter are used, so `nanos` never has to deal with wraparound.
+/// `now_nanos` is the current monotonic time, which the HPET clock continues from.
+pub fn init(now_nanos: u64) -> bool {
+    let table_address = match acpi::find_table(b"HPET") {
+        Some(address) => address,
+        None => return false,
+    };
+
+    let hpet = HPET.get_or_init(|| {
+        let table: HpetTable = unsafe { acpi::read_table(table_address) };
+        let base_address = table.base_address.address;
+
+        let mut hpet = Hpet {
+            base: memory::phys_to_virt(PhysAddr::new(base_address)),
+            period: 0,
+            legacy_route: false,
+            base_nanos: now_nanos,
+        };
+
+        let capabilities = hpet.read(REGISTER_CAPABILITIES);
+
+        if capabilities & CAPABILITY_64_BIT_COUNTER == 0 {
+            return hpet;
+        }
+
+        hpet.period = capabilities >> 32;
+        hpet.legacy_route = capabilities & CAPABILITY_LEGACY_ROUTE != 0;
+
+        // The counter may only be written while it is halted.
+        let config = hpet.read(REGISTER_CONFIG) & !CONFIG_ENABLE;
+        hpet.write(REGISTER_CONFIG, config);
+        hpet.write(REGISTER_MAIN_COUNTER, 0);
+        hpet.write(REGISTER_CONFIG, config | CONFIG_ENABLE);
+        hpet
+    });
+
+    hpet.period != 0
+}
+
+pub fn is_enabled() -> bool {
+    HPET.get().is_some_and(|hpet| hpet.period != 0)
+}
+
+/// Whether legacy routing is on, in which case IRQ8 comes from the HPET and no longer from the RTC.
+pub fn routes_irq8() -> bool {
+    hpet().is_ok_and(|hpet| hpet.read(REGISTER_CONFIG) & CONFIG_LEGACY_ROUTE != 0)
+}
+
+fn hpet() -> Result<&'static Hpet, HpetError> {
+    HPET.get()
+        .filter(|hpet| hpet.period != 0)
+        .ok_or(HpetError::NotPresent)
+}
+
+/// Monotonic nanoseconds since boot. Returns zero if there is no HPET.
+pub fn nanos() -> u64 {
+    match hpet() {
+        Ok(hpet) => {
+            let counter = hpet.read(REGISTER_MAIN_COUNTER) as u128;
+            hpet.base_nanos + (counter * hpet.period as u128 / FEMTOS_PER_NANO) as u64
+        }
+        Err(_) => 0,
+    }
+}
+
+/// Calls `callback` from interrupt context once `after` has elapsed.
+///
+/// Only one one-shot can be pending; arming again replaces the previous one.
+pub fn arm_oneshot(after: Duration, callback: fn()) -> Result<(), HpetError> {
+    let hpet = hpet()?;
+
+    if !hpet.legacy_route || time::tick_source() != TickSource::Apic {
+        return Err(HpetError::RoutingUnavailable);
+    }
+
+    let delta = (after.as_nanos() * FEMTOS_PER_NANO / hpet.period as u128).max(1) as u64;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *ONESHOT_CALLBACK.lock() = Some(callback);
+
+        let config = hpet.read(REGISTER_CONFIG);
+        if config & CONFIG_LEGACY_ROUTE == 0 {
+            hpet.write(REGISTER_CONFIG, config | CONFIG_LEGACY_ROUTE);
+            interrupts::unmask_irq(8);
+        }
+
+        let comparator = hpet.read(REGISTER_MAIN_COUNTER).wrapping_add(delta);
+        hpet.write(Hpet::timer_comparator_register(ONESHOT_TIMER), comparator);
+
+        let timer_config = Hpet::timer_config_register(ONESHOT_TIMER);
+        // Edge-triggered, non-periodic.
+        hpet.write(timer_config, TIMER_INTERRUPT_ENABLE);
+    });
+
+    Ok(())
+}
+
+/// Called by the IRQ8 handler.
+pub(crate) fn on_interrupt() {
+    if !routes_irq8() {
+        return;
+    }
+
+    if let Ok(hpet) = hpet() {
+        hpet.write(Hpet::timer_config_register(ONESHOT_TIMER), 0);
+    }
+
+    // Taken before the call, so that the callback may arm the comparator again.
+    let callback = ONESHOT_CALLBACK.lock().take();
+    if let Some(callback) = callback {
+        callback();
+    }
+}
@@ -0,0 +1,137 @@
+use core::sync::atomic::{AtomicU16, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// The PIT (Programmable Interval Timer) is driven by a fixed oscillator of ~1.193182 MHz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+/// The reload value is a 16-bit counter, so this is the slowest rate the PIT can produce.
+pub const MIN_FREQUENCY: u32 = BASE_FREQUENCY / 0xFFFF + 1;
+
+const CHANNEL_0_DATA: u16 = 0x40;
+const CHANNEL_2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Port B of the keyboard controller. Bit 0 gates channel 2, bit 1 connects it to the speaker and bit 5
+/// reflects the channel 2 output.
+const PORT_B: u16 = 0x61;
+
+/// Port B bits that gate channel 2 and connect it to the speaker.
+const SPEAKER: u8 = 0b11;
+
+static PIT: Mutex<()> = Mutex::new(());
+/// The channel 2 divisor of the tone the speaker plays, or 0 while it is silent. `busy_wait_micros`
+/// borrows channel 2, and puts the tone back after.
+static TONE_DIVISOR: AtomicU16 = AtomicU16::new(0);
+
+/// Programs channel 0 (wired to IRQ0) as a rate generator firing `hz` times per second.
+pub fn set_frequency(hz: u32) {
+    assert!(
+        (MIN_FREQUENCY..=BASE_FREQUENCY).contains(&hz),
+        "PIT frequency {} Hz out of range",
+        hz
+    );
+
+    let divisor = (BASE_FREQUENCY / hz) as u16;
+    let _guard = PIT.lock();
+
+    unsafe {
+        // Channel 0, access mode lobyte/hibyte, mode 3 (square wave generator), binary.
+        Port::<u8>::new(COMMAND).write(0b0011_0110);
+        let mut data = Port::<u8>::new(CHANNEL_0_DATA);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+}
+
+/// Busy-waits for `micros` microseconds using channel 2, which is not connected to any IRQ.
+///
+/// This is used to calibrate other timers, so it must not depend on interrupts being delivered.
+/// The counter is 16 bits wide, so at most ~54ms can be waited at once.
+pub fn busy_wait_micros(micros: u32) {
+    let count = (BASE_FREQUENCY as u64 * micros as u64 / 1_000_000) as u32;
+    assert!(
+        count > 0 && count <= 0xFFFF,
+        "PIT wait of {}us out of range",
+        micros
+    );
+
+    let _guard = PIT.lock();
+
+    unsafe {
+        let mut port_b = Port::<u8>::new(PORT_B);
+
+        // Disconnect the speaker and hold the gate low while the counter is loaded.
+        let value = port_b.read() & !SPEAKER;
+        port_b.write(value);
+
+        // Channel 2, access mode lobyte/hibyte, mode 0 (interrupt on terminal count), binary.
+        // In mode 0 the output goes high once the counter reaches zero.
+        Port::<u8>::new(COMMAND).write(0b1011_0000);
+        let mut data = Port::<u8>::new(CHANNEL_2_DATA);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        // Raising the gate starts the countdown.
+        port_b.write(value | 1);
+
+        while port_b.read() & 0b10_0000 == 0 {
+            core::hint::spin_loop();
+        }
+
+        match TONE_DIVISOR.load(Ordering::Relaxed) {
+            0 => port_b.write(value),
+            divisor => program_tone(divisor),
+        }
+    }
+}
+
+/// Makes the speaker play a square wave at `hz`, until `stop_tone`.
+pub fn play_tone(hz: u32) {
+    assert!(
+        (MIN_FREQUENCY..=BASE_FREQUENCY).contains(&hz),
+        "tone of {} Hz out of range",
+        hz
+    );
+
+    let divisor = (BASE_FREQUENCY / hz) as u16;
+    let _guard = PIT.lock();
+    TONE_DIVISOR.store(divisor, Ordering::Relaxed);
+    unsafe { program_tone(divisor) };
+}
+
+pub fn stop_tone() {
+    let _guard = PIT.lock();
+    TONE_DIVISOR.store(0, Ordering::Relaxed);
+
+    unsafe {
+        let mut port_b = Port::<u8>::new(PORT_B);
+        let value = port_b.read();
+        port_b.write(value & !SPEAKER);
+    }
+}
+
+/// Releases the lock on the PIT, which code interrupted by a panic may hold.
+///
+/// # Safety
+///
+/// Only for the panic path, once nothing else runs.
+pub(crate) unsafe fn force_unlock() {
+    if PIT.try_lock().is_none() {
+        unsafe { PIT.force_unlock() };
+    }
+}
+
+/// Must be called with `PIT` locked.
+unsafe fn program_tone(divisor: u16) {
+    unsafe {
+        // Channel 2, access mode lobyte/hibyte, mode 3 (square wave generator), binary.
+        Port::<u8>::new(COMMAND).write(0b1011_0110);
+        let mut data = Port::<u8>::new(CHANNEL_2_DATA);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+
+        let mut port_b = Port::<u8>::new(PORT_B);
+        let value = port_b.read();
+        port_b.write(value | SPEAKER);
+    }
+}
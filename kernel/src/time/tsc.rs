@@ -0,0 +1,92 @@
+//! Time stamp counter.
+//!
+//! The TSC is the cheapest clock to read (a single instruction), but it is only usable for timekeeping if
+//! it is invariant, i.e. it ticks at a constant rate regardless of frequency scaling and sleep states.
+
+use conquer_once::spin::OnceCell;
+
+use crate::{cpu, time::hpet, time::pit};
+
+/// How long the calibration measures the TSC.
+const CALIBRATION_MICROS: u64 = 10_000;
+
+static TSC: OnceCell<Calibration> = OnceCell::uninit();
+
+struct Calibration {
+    /// TSC ticks per second.
+    frequency: u64,
+    /// TSC value and monotonic time sampled together once the calibration finished; later readings are
+    /// relative to these.
+    base_tsc: u64,
+    base_nanos: u64,
+}
+
+/// CPUID leaf 0x8000_0007, EDX bit 8.
+pub fn is_invariant() -> bool {
+    cpu::max_extended_leaf() >= 0x8000_0007 && cpu::cpuid(0x8000_0007, 0).edx & (1 << 8) != 0
+}
+
+/// Measures the TSC frequency against the HPET, or the PIT if there is no HPET.
+fn measure_frequency() -> u64 {
+    if hpet::is_enabled() {
+        let start_nanos = hpet::nanos();
+        let start = cpu::rdtsc();
+
+        while hpet::nanos() - start_nanos < CALIBRATION_MICROS * 1000 {
+            core::hint::spin_loop();
+        }
+
+        let elapsed_nanos = hpet::nanos() - start_nanos;
+        let elapsed = cpu::rdtsc() - start;
+
+        (elapsed as u128 * 1_000_000_000 / elapsed_nanos as u128) as u64
+    } else {
+        let start = cpu::rdtsc();
+        pit::busy_wait_micros(CALIBRATION_MICROS as u32);
+        let elapsed = cpu::rdtsc() - start;
+
+        elapsed * 1_000_000 / CALIBRATION_MICROS
+    }
+}
+
+/// Calibrates the TSC if it is invariant. `now_nanos` reads the monotonic clock used until now, which the
+/// TSC clock continues from so that time doesn't jump when switching clock sources. It is read after the
+/// calibration, right next to the TSC, since the calibration itself takes a while.
+pub fn init(now_nanos: impl FnOnce() -> u64) -> bool {
+    if !is_invariant() {
+        return false;
+    }
+
+    TSC.get_or_init(|| {
+        let frequency = measure_frequency();
+        let base_nanos = now_nanos();
+
+        Calibration {
+            frequency,
+            base_tsc: cpu::rdtsc(),
+            base_nanos,
+        }
+    })
+    .frequency
+        > 0
+}
+
+pub fn is_enabled() -> bool {
+    TSC.get().is_some_and(|tsc| tsc.frequency > 0)
+}
+
+/// TSC ticks per second, or zero if the TSC is not used.
+pub fn frequency() -> u64 {
+    TSC.get().map_or(0, |tsc| tsc.frequency)
+}
+
+/// Monotonic nanoseconds since boot. Only meaningful if `is_enabled`.
+pub fn nanos() -> u64 {
+    match TSC.get() {
+        Some(tsc) => {
+            let elapsed = cpu::rdtsc().wrapping_sub(tsc.base_tsc) as u128;
+            tsc.base_nanos + (elapsed * 1_000_000_000 / tsc.frequency as u128) as u64
+        }
+        None => 0,
+    }
+}
@@ -0,0 +1,97 @@
+//! A hashed timer wheel with a resolution of one tick.
+//!
+//! Entries are kept in the slot of their deadline tick modulo `SLOTS`, so inserting is O(1) and expiring
+//! only looks at the slots of the ticks that passed. Deadlines more than `SLOTS` ticks away share a slot
+//! with nearer ones and just stay in it for more rounds of the wheel.
+
+use alloc::vec::Vec;
+
+const SLOTS: usize = 64;
+
+pub struct TimerWheel<T> {
+    slots: [Vec<(u64, T)>; SLOTS],
+    /// The last tick that was expired.
+    now: u64,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    /// A wheel whose current tick is `now`.
+    pub const fn new(now: u64) -> Self {
+        TimerWheel {
+            slots: [const { Vec::new() }; SLOTS],
+            now,
+            len: 0,
+        }
+    }
+
+    /// Adds `item` to expire at the tick `deadline`, or at the next expiry if that tick has passed. Returns
+    /// the deadline it is filed under, for `remove_at`.
+    pub fn insert(&mut self, deadline: u64, item: T) -> u64 {
+        let deadline = deadline.max(self.now + 1);
+        self.slots[deadline as usize % SLOTS].push((deadline, item));
+        self.len += 1;
+        deadline
+    }
+
+    /// Advances the wheel to the tick `now`, passing every entry whose deadline has been reached to
+    /// `expired`.
+    pub fn expire(&mut self, now: u64, mut expired: impl FnMut(T)) {
+        if now <= self.now {
+            return;
+        }
+
+        // Past a full turn every slot has been reached, and each is checked only once.
+        let first = self.now + 1;
+        let ticks = (now - self.now).min(SLOTS as u64);
+        self.now = now;
+
+        for tick in first..first + ticks {
+            let slot = &mut self.slots[tick as usize % SLOTS];
+            let mut index = 0;
+
+            while index < slot.len() {
+                if slot[index].0 <= now {
+                    let (_, item) = slot.swap_remove(index);
+                    self.len -= 1;
+                    expired(item);
+                } else {
+                    index += 1;
+                }
+            }
+        }
+    }
+
+    /// Removes every entry for which `remove` returns true.
+    pub fn remove(&mut self, mut remove: impl FnMut(&T) -> bool) {
+        for slot in &mut self.slots {
+            slot.retain(|(_, item)| !remove(item));
+        }
+        self.len = self.slots.iter().map(Vec::len).sum();
+    }
+
+    /// Removes the first entry filed under `deadline`, as returned by `insert`, for which `remove` returns
+    /// true, and returns it. Only looks at one slot.
+    pub fn remove_at(&mut self, deadline: u64, mut remove: impl FnMut(&T) -> bool) -> Option<T> {
+        let slot = &mut self.slots[deadline as usize % SLOTS];
+        let index = slot
+            .iter()
+            .position(|(at, item)| *at == deadline && remove(item))?;
+
+        self.len -= 1;
+        Some(slot.swap_remove(index).1)
+    }
+
+    /// The tick the wheel was last advanced to.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
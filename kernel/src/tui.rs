@@ -0,0 +1,234 @@
+//! A text-mode UI toolkit for the kernel's built-in tools.
+//!
+//! Tools draw into a `Screen`, a grid of character cells, using the boxes and lists in `widget`. The screen
+//! is rendered to the serial console as ANSI escape sequences, so the tools are used from the terminal
+//! QEMU's serial port is attached to, while the framebuffer keeps showing the kernel's output.
+//!
+//! Every tool is an async task that waits for events from `input`, which hands the keyboard to one tool at
+//! a time: F1, F2, ... focus the tools in the order they were registered, and Escape gives the keyboard
+//! back to the console. Only the focused tool draws.
+
+use alloc::{string::String, vec, vec::Vec};
+use core::fmt::{self, Write};
+use futures_util::StreamExt;
+
+use crate::serial_print;
+
+pub mod file_browser;
+pub mod input;
+pub mod memory_browser;
+pub mod monitor;
+pub mod widget;
+
+pub use input::{Event, Key};
+
+/// Size of the screen: the rows of a classic terminal, and enough columns for a line of `memory::dump`
+/// inside a box.
+pub const WIDTH: usize = 88;
+pub const HEIGHT: usize = 24;
+
+/// Hides the cursor and clears the terminal.
+const ENTER: &str = "\x1b[?25l\x1b[2J";
+/// Clears the terminal and shows the cursor again.
+pub(crate) const LEAVE: &str = "\x1b[0m\x1b[2J\x1b[H\x1b[?25h";
+
+/// A rectangle of cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub const fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// The area inside a one-cell border.
+    pub fn inner(&self) -> Rect {
+        Rect {
+            x: self.x + 1,
+            y: self.y + 1,
+            width: self.width.saturating_sub(2),
+            height: self.height.saturating_sub(2),
+        }
+    }
+
+    /// Splits off the last `rows` rows, returning the rest and the rows.
+    pub fn split_bottom(&self, rows: usize) -> (Rect, Rect) {
+        let rows = rows.min(self.height);
+        let top = Rect {
+            height: self.height - rows,
+            ..*self
+        };
+        let bottom = Rect {
+            y: self.y + top.height,
+            height: rows,
+            ..*self
+        };
+        (top, bottom)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub character: char,
+    /// Drawn with foreground and background swapped, e.g. for the selected item of a list.
+    pub reverse: bool,
+}
+
+impl Cell {
+    const BLANK: Cell = Cell {
+        character: ' ',
+        reverse: false,
+    };
+}
+
+/// A grid of cells that tools draw into, then render to the terminal in one go.
+pub struct Screen {
+    cells: Vec<Cell>,
+    width: usize,
+    height: usize,
+}
+
+impl Screen {
+    pub fn new(width: usize, height: usize) -> Self {
+        Screen {
+            cells: vec![Cell::BLANK; width * height],
+            width,
+            height,
+        }
+    }
+
+    /// The whole screen.
+    pub fn area(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.fill(Cell::BLANK);
+    }
+
+    pub fn cell(&self, x: usize, y: usize) -> Option<Cell> {
+        (x < self.width && y < self.height).then(|| self.cells[y * self.width + x])
+    }
+
+    /// Sets a cell. Cells outside the screen are ignored.
+    pub fn put(&mut self, x: usize, y: usize, character: char, reverse: bool) {
+        if x < self.width && y < self.height {
+            self.cells[y * self.width + x] = Cell { character, reverse };
+        }
+    }
+
+    /// Writes `text` from `(x, y)`, cut off after `max_width` columns. Control characters are shown as
+    /// `.`. Returns the number of columns written.
+    pub fn text(
+        &mut self,
+        x: usize,
+        y: usize,
+        max_width: usize,
+        text: &str,
+        reverse: bool,
+    ) -> usize {
+        let mut written = 0;
+        for character in text.chars().take(max_width) {
+            let character = if character.is_control() {
+                '.'
+            } else {
+                character
+            };
+            self.put(x + written, y, character, reverse);
+            written += 1;
+        }
+        written
+    }
+
+    /// Fills a row of `area` with `text` in reverse video, padded to the full width.
+    pub fn bar(&mut self, area: Rect, y: usize, text: &str) {
+        let written = self.text(area.x, y, area.width, text, true);
+        for x in area.x + written..area.x + area.width {
+            self.put(x, y, ' ', true);
+        }
+    }
+
+    /// The characters of row `y`, without trailing spaces.
+    pub fn row(&self, y: usize) -> String {
+        let start = y * self.width;
+        let row: String = self.cells[start..start + self.width]
+            .iter()
+            .map(|cell| cell.character)
+            .collect();
+        String::from(row.trim_end())
+    }
+
+    /// Writes the screen to a terminal from its top left corner.
+    pub fn render(&self, output: &mut impl Write) -> fmt::Result {
+        output.write_str("\x1b[H")?;
+
+        let mut reverse = false;
+        for (y, row) in self.cells.chunks(self.width).enumerate() {
+            if y > 0 {
+                output.write_str("\r\n")?;
+            }
+
+            for cell in row {
+                if cell.reverse != reverse {
+                    reverse = cell.reverse;
+                    output.write_str(if reverse { "\x1b[7m" } else { "\x1b[0m" })?;
+                }
+                output.write_char(cell.character)?;
+            }
+        }
+
+        if reverse {
+            output.write_str("\x1b[0m")?;
+        }
+        Ok(())
+    }
+}
+
+/// A tool built on the toolkit.
+pub trait App {
+    /// Shown in the title of the tool's box.
+    fn title(&self) -> &str;
+
+    /// Draws the tool into `area`, inside its box.
+    fn draw(&mut self, screen: &mut Screen, area: Rect);
+
+    fn handle_key(&mut self, key: Key);
+}
+
+/// Runs `app` as a tool, focused with the `n`th function key where `n` is its registration order.
+pub async fn run(name: &'static str, mut app: impl App) {
+    let mut events = input::register(name).expect("tool registered twice");
+    let mut screen = Screen::new(WIDTH, HEIGHT);
+
+    while let Some(event) = events.next().await {
+        let mut frame = String::new();
+
+        match event {
+            Event::Focus => frame.push_str(ENTER),
+            Event::Key(key) => app.handle_key(key),
+        }
+
+        // Keys may have been routed elsewhere meanwhile, e.g. an Escape right behind this key.
+        if input::focused() != Some(name) {
+            continue;
+        }
+
+        screen.clear();
+        let area = screen.area();
+        widget::draw_box(&mut screen, area, app.title());
+        app.draw(&mut screen, area.inner());
+
+        let _ = screen.render(&mut frame);
+        serial_print!("{}", frame);
+    }
+}
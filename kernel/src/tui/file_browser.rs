@@ -0,0 +1,116 @@
+//! The file browser: the files of the initrd, and a viewer for their contents.
+
+use alloc::{format, string::String, vec::Vec};
+
+use super::{App, Key, Rect, Screen, widget::List};
+use crate::initrd::{self, Initrd};
+
+/// Bytes shown per line of a binary file.
+const BYTES_PER_LINE: usize = 16;
+
+pub struct FileBrowser {
+    initrd: Option<Initrd<'static>>,
+    files: List,
+    /// The file being viewed, as lines.
+    viewer: Option<(String, List)>,
+}
+
+impl FileBrowser {
+    /// Browses the initrd loaded at boot, if there is one.
+    pub fn new() -> Self {
+        Self::with_initrd(initrd::get().copied())
+    }
+
+    pub fn with_initrd(initrd: Option<Initrd<'static>>) -> Self {
+        let rows = initrd
+            .iter()
+            .flat_map(|initrd| initrd.files())
+            .map(|file| format!("{:<60} {:>10}", file.name, file.data.len()))
+            .collect();
+
+        FileBrowser {
+            initrd,
+            files: List::new(rows),
+            viewer: None,
+        }
+    }
+
+    /// Name of the file being viewed.
+    pub fn viewing(&self) -> Option<&str> {
+        self.viewer.as_ref().map(|(name, _)| name.as_str())
+    }
+
+    fn open_selected(&mut self) {
+        let Some((initrd, index)) = self.initrd.zip(self.files.selected()) else {
+            return;
+        };
+        let Some(file) = initrd.files().nth(index) else {
+            return;
+        };
+
+        self.viewer = Some((String::from(file.name), List::new(lines(file.data))));
+    }
+}
+
+impl Default for FileBrowser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Text files as their lines, anything else as hex.
+fn lines(data: &[u8]) -> Vec<String> {
+    match core::str::from_utf8(data) {
+        Ok(text) => text.lines().map(String::from).collect(),
+        Err(_) => data
+            .chunks(BYTES_PER_LINE)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut line = format!("{:08x} ", index * BYTES_PER_LINE);
+                for byte in chunk {
+                    line.push_str(&format!(" {:02x}", byte));
+                }
+                line
+            })
+            .collect(),
+    }
+}
+
+impl App for FileBrowser {
+    fn title(&self) -> &str {
+        self.viewing().unwrap_or("Files")
+    }
+
+    fn draw(&mut self, screen: &mut Screen, area: Rect) {
+        let (content, bar) = area.split_bottom(1);
+
+        let hints = match &mut self.viewer {
+            Some((_, lines)) => {
+                lines.draw(screen, content);
+                "↑↓ scroll  Backspace back  Esc console"
+            }
+            None if self.initrd.is_none() => {
+                screen.text(content.x, content.y, content.width, "no initrd", false);
+                "Esc console"
+            }
+            None => {
+                self.files.draw(screen, content);
+                "↑↓ select  Enter view  Esc console"
+            }
+        };
+        screen.bar(bar, bar.y, hints);
+    }
+
+    fn handle_key(&mut self, key: Key) {
+        match (&mut self.viewer, key) {
+            (Some(_), Key::Backspace) => self.viewer = None,
+            (Some((_, lines)), key) => {
+                lines.handle_key(key);
+            }
+            (None, Key::Enter) => self.open_selected(),
+            (None, key) => {
+                self.files.handle_key(key);
+            }
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Routing of keyboard input between the console and the tools.
+//!
+//! `route_keys` takes the key presses from the input bus in place of `keyboard::print_keypresses` once
+//! the tools are in use. Keys go to the focused tool, or are echoed to the console like
+//! `keyboard::print_keypresses` does while no tool has focus. Function keys switch between tools and Escape leaves the focused one.
+
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use futures_util::stream::{Stream, StreamExt};
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+
+use crate::{console, init_state::AlreadyInitialized, serial_print, task::keyboard};
+
+/// Keys queued for a tool that hasn't caught up, beyond which the oldest are dropped.
+const MAX_QUEUED: usize = 64;
+
+static ROUTER: Mutex<Router> = Mutex::new(Router {
+    tools: Vec::new(),
+    focus: None,
+});
+
+/// A key, as far as the tools care.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Enter,
+    Escape,
+    Backspace,
+    Tab,
+    /// F1 to F12.
+    Function(u8),
+    Char(char),
+}
+
+impl Key {
+    /// The key for a decoded key press, if it is one the tools understand.
+    pub fn from_decoded(key: DecodedKey) -> Option<Self> {
+        let key = match key {
+            DecodedKey::Unicode('\n') => Key::Enter,
+            DecodedKey::Unicode('\x1b') => Key::Escape,
+            DecodedKey::Unicode('\x08') => Key::Backspace,
+            DecodedKey::Unicode('\t') => Key::Tab,
+            DecodedKey::Unicode(character) => Key::Char(character),
+            DecodedKey::RawKey(code) => match code {
+                KeyCode::ArrowUp => Key::Up,
+                KeyCode::ArrowDown => Key::Down,
+                KeyCode::ArrowLeft => Key::Left,
+                KeyCode::ArrowRight => Key::Right,
+                KeyCode::PageUp => Key::PageUp,
+                KeyCode::PageDown => Key::PageDown,
+                KeyCode::Home => Key::Home,
+                KeyCode::End => Key::End,
+                KeyCode::F1 => Key::Function(1),
+                KeyCode::F2 => Key::Function(2),
+                KeyCode::F3 => Key::Function(3),
+                KeyCode::F4 => Key::Function(4),
+                KeyCode::F5 => Key::Function(5),
+                KeyCode::F6 => Key::Function(6),
+                KeyCode::F7 => Key::Function(7),
+                KeyCode::F8 => Key::Function(8),
+                KeyCode::F9 => Key::Function(9),
+                KeyCode::F10 => Key::Function(10),
+                KeyCode::F11 => Key::Function(11),
+                KeyCode::F12 => Key::Function(12),
+                _ => return None,
+            },
+        };
+
+        Some(key)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The tool got the keyboard and must draw itself from scratch.
+    Focus,
+    Key(Key),
+}
+
+/// Where `route` sent a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Routed {
+    /// To a tool, possibly by focusing it.
+    Tool,
+    /// It made the focused tool give the keyboard back to the console.
+    Released,
+    /// No tool has focus, so the key is for the console.
+    Console,
+}
+
+struct Tool {
+    name: &'static str,
+    events: VecDeque<Event>,
+    waker: Option<Waker>,
+}
+
+impl Tool {
+    fn push(&mut self, event: Event) {
+        if self.events.len() == MAX_QUEUED {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct Router {
+    tools: Vec<Tool>,
+    /// Index of the focused tool.
+    focus: Option<usize>,
+}
+
+/// Registers a tool, focused with F1 if it is the first, F2 if the second and so on. Returns the stream of
+/// its events.
+pub fn register(name: &'static str) -> Result<Events, AlreadyInitialized> {
+    let mut router = ROUTER.lock();
+    if router.tools.iter().any(|tool| tool.name == name) {
+        return Err(AlreadyInitialized { subsystem: name });
+    }
+
+    router.tools.push(Tool {
+        name,
+        events: VecDeque::new(),
+        waker: None,
+    });
+    Ok(Events {
+        tool: router.tools.len() - 1,
+    })
+}
+
+/// Name of the focused tool.
+pub fn focused() -> Option<&'static str> {
+    let router = ROUTER.lock();
+    router.focus.map(|index| router.tools[index].name)
+}
+
+/// Names of the registered tools, in the order of their function keys.
+pub fn tools() -> Vec<&'static str> {
+    ROUTER.lock().tools.iter().map(|tool| tool.name).collect()
+}
+
+/// Sends `key` to where it belongs.
+pub fn route(key: Key) -> Routed {
+    let mut router = ROUTER.lock();
+
+    if let Key::Function(number) = key
+        && let Some(index) = usize::from(number)
+            .checked_sub(1)
+            .filter(|&index| index < router.tools.len())
+    {
+        if router.focus != Some(index) {
+            router.focus = Some(index);
+            // The tool draws on the serial terminal now.
+            console::pause_mirroring(true);
+            router.tools[index].push(Event::Focus);
+        }
+        return Routed::Tool;
+    }
+
+    match (router.focus, key) {
+        (None, _) => Routed::Console,
+        (Some(_), Key::Escape) => {
+            router.focus = None;
+            console::pause_mirroring(false);
+            Routed::Released
+        }
+        (Some(index), key) => {
+            router.tools[index].push(Event::Key(key));
+            Routed::Tool
+        }
+    }
+}
+
+/// Events for one tool, from `register`.
+pub struct Events {
+    tool: usize,
+}
+
+impl Events {
+    /// The next event, if one is queued.
+    pub fn try_next(&mut self) -> Option<Event> {
+        ROUTER.lock().tools[self.tool].events.pop_front()
+    }
+}
+
+impl Stream for Events {
+    type Item = Event;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<Event>> {
+        let mut router = ROUTER.lock();
+        let tool = &mut router.tools[self.tool];
+
+        // The waker is stored under the same lock `route` pushes under, so no wakeup is lost.
+        match tool.events.pop_front() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => {
+                tool.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Routes every key press, echoing those for the console. Replaces `keyboard::print_keypresses` when
+/// the tools are spawned.
+pub async fn route_keys() {
+    let mut events = crate::input::subscribe();
+
+    while let Some(input) = events.next().await {
+        if let Some(console) = keyboard::console_switch(&input.event) {
+            console::switch(console);
+            continue;
+        }
+        let crate::input::Event::KeyPress {
+            key: Some(decoded), ..
+        } = input.event
+        else {
+            continue;
+        };
+
+        match Key::from_decoded(decoded).map_or(Routed::Console, route) {
+            Routed::Tool => {}
+            Routed::Released => serial_print!("{}", super::LEAVE),
+            Routed::Console => keyboard::echo(decoded),
+        }
+    }
+}
+
+#[test_case]
+fn test_keys_are_decoded() {
+    assert_eq!(
+        Key::from_decoded(DecodedKey::Unicode('\n')),
+        Some(Key::Enter)
+    );
+    assert_eq!(
+        Key::from_decoded(DecodedKey::Unicode('q')),
+        Some(Key::Char('q'))
+    );
+    assert_eq!(
+        Key::from_decoded(DecodedKey::RawKey(KeyCode::F3)),
+        Some(Key::Function(3))
+    );
+    assert_eq!(Key::from_decoded(DecodedKey::RawKey(KeyCode::LShift)), None);
+}
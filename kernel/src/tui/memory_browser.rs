@@ -0,0 +1,146 @@
+//! The memory browser: a hex dump of kernel virtual memory that scrolls with the arrow keys.
+//!
+//! `g` starts typing an address in hex, Enter jumps there. Unmapped memory is reported instead of read,
+//! see `memory::dump`.
+
+use alloc::{format, string::String};
+use core::fmt::Write;
+use x86_64::VirtAddr;
+
+use super::{App, Key, Rect, Screen};
+use crate::{
+    allocator::HEAP_START,
+    memory::dump::{self, DumpError},
+};
+
+const BYTES_PER_ROW: u64 = 16;
+
+pub struct MemoryBrowser {
+    /// Address of the first row.
+    address: u64,
+    /// The address being typed after `g`.
+    input: Option<String>,
+    /// Rows visible at the last draw, how far Page Up/Down move.
+    rows: u64,
+    message: String,
+}
+
+impl MemoryBrowser {
+    /// Starts at the beginning of the kernel heap.
+    pub fn new() -> Self {
+        Self::at(VirtAddr::new(HEAP_START as u64))
+    }
+
+    pub fn at(address: VirtAddr) -> Self {
+        MemoryBrowser {
+            address: address.align_down(BYTES_PER_ROW).as_u64(),
+            input: None,
+            rows: 1,
+            message: String::new(),
+        }
+    }
+
+    /// Address of the first row.
+    pub fn address(&self) -> VirtAddr {
+        VirtAddr::new_truncate(self.address)
+    }
+
+    fn scroll(&mut self, rows: i64) {
+        let bytes = rows.unsigned_abs() * BYTES_PER_ROW;
+        let address = if rows < 0 {
+            self.address.checked_sub(bytes)
+        } else {
+            self.address.checked_add(bytes)
+        };
+
+        // Scrolling stops at the ends of the address space and at the hole in the middle.
+        if let Some(address) = address.and_then(|address| VirtAddr::try_new(address).ok()) {
+            self.address = address.as_u64();
+        }
+    }
+
+    fn handle_input(&mut self, key: Key) {
+        let Some(input) = self.input.as_mut() else {
+            return;
+        };
+
+        match key {
+            Key::Char(digit) if digit.is_ascii_hexdigit() && input.len() < 16 => input.push(digit),
+            Key::Backspace => {
+                input.pop();
+            }
+            Key::Enter => {
+                let text = self.input.take().unwrap_or_default();
+                match u64::from_str_radix(&text, 16)
+                    .ok()
+                    .and_then(|address| VirtAddr::try_new(address).ok())
+                {
+                    Some(address) => self.address = address.align_down(BYTES_PER_ROW).as_u64(),
+                    None => self.message = format!("not an address: {:?}", text),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Default for MemoryBrowser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl App for MemoryBrowser {
+    fn title(&self) -> &str {
+        "Memory browser"
+    }
+
+    fn draw(&mut self, screen: &mut Screen, area: Rect) {
+        let (dump_area, bar) = area.split_bottom(1);
+        self.rows = dump_area.height as u64;
+
+        let mut text = String::new();
+        let len = self.rows * BYTES_PER_ROW;
+        match dump::hexdump(&mut text, self.address(), len) {
+            Ok(()) => {}
+            Err(DumpError::Unmapped(address)) => {
+                text.clear();
+                let _ = write!(text, "{:#x} is not mapped", address.as_u64());
+            }
+            Err(error) => {
+                text.clear();
+                let _ = write!(text, "can't dump {:#x}: {:?}", self.address, error);
+            }
+        }
+
+        for (y, line) in (dump_area.y..dump_area.y + dump_area.height).zip(text.lines()) {
+            screen.text(dump_area.x, y, dump_area.width, line, false);
+        }
+
+        let status = match &self.input {
+            Some(input) => format!("go to address: {}_", input),
+            None if !self.message.is_empty() => self.message.clone(),
+            None => String::from("↑↓ scroll  PgUp/PgDn page  g go to  Esc console"),
+        };
+        screen.bar(bar, bar.y, &status);
+    }
+
+    fn handle_key(&mut self, key: Key) {
+        self.message.clear();
+
+        if self.input.is_some() {
+            self.handle_input(key);
+            return;
+        }
+
+        let page = self.rows.max(1) as i64;
+        match key {
+            Key::Up => self.scroll(-1),
+            Key::Down => self.scroll(1),
+            Key::PageUp => self.scroll(-page),
+            Key::PageDown => self.scroll(page),
+            Key::Char('g') => self.input = Some(String::new()),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! The task monitor: uptime, memory use and the process table, with a way to kill processes.
+
+use alloc::{format, string::String, vec::Vec};
+use core::time::Duration;
+
+use super::{App, Key, Rect, Screen, widget::List};
+use crate::{
+    memory,
+    process::{
+        self, Pid, State,
+        signal::{self, Signal},
+    },
+    time,
+};
+
+const HEADER: &str = "  PID PARENT STATE        NICE HEAP KiB";
+
+pub struct Monitor {
+    processes: List,
+    /// PIDs of the rows of `processes`.
+    pids: Vec<Pid>,
+    /// Outcome of the last command, shown until the next key.
+    message: String,
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        Monitor {
+            processes: List::new(Vec::new()),
+            pids: Vec::new(),
+            message: String::new(),
+        }
+    }
+
+    fn refresh(&mut self) {
+        let mut rows = Vec::new();
+        self.pids.clear();
+
+        for (pid, state) in process::list() {
+            let (parent, nice, heap_pages) = process::with_process(pid, |process| {
+                let heap_pages = process
+                    .address_space()
+                    .map_or(0, |space| space.anonymous_pages());
+                (process.parent(), process.priority().nice(), heap_pages)
+            })
+            .unwrap_or((None, 0, 0));
+
+            let parent = parent.map_or(String::from("-"), |parent| format!("{}", parent));
+            let state = match state {
+                State::Ready => String::from("ready"),
+                State::Running => String::from("running"),
+                State::Blocked => String::from("blocked"),
+                State::Zombie { exit_code } => format!("exited {}", exit_code),
+            };
+
+            rows.push(format!(
+                "{:>5} {:>6} {:<12} {:>4} {:>8}",
+                pid,
+                parent,
+                state,
+                nice,
+                heap_pages * 4
+            ));
+            self.pids.push(pid);
+        }
+
+        self.processes.set_items(rows);
+    }
+
+    fn kill_selected(&mut self) {
+        let Some(&pid) = self
+            .processes
+            .selected()
+            .and_then(|index| self.pids.get(index))
+        else {
+            return;
+        };
+
+        self.message = match signal::send(pid, Signal::KILL) {
+            Ok(()) => format!("killed process {}", pid),
+            Err(error) => format!("can't kill process {}: {:?}", pid, error),
+        };
+    }
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl App for Monitor {
+    fn title(&self) -> &str {
+        "Task monitor"
+    }
+
+    fn draw(&mut self, screen: &mut Screen, area: Rect) {
+        // Redrawn on every key, so the table is as fresh as the last key press.
+        self.refresh();
+
+        let uptime = Duration::from_nanos(time::uptime_nanos());
+        screen.text(
+            area.x,
+            area.y,
+            area.width,
+            &format!(
+                "uptime {}.{:02} s   processes {}",
+                uptime.as_secs(),
+                uptime.subsec_millis() / 10,
+                self.pids.len()
+            ),
+            false,
+        );
+        screen.text(
+            area.x,
+            area.y + 1,
+            area.width,
+            &format!(
+                "memory {} KiB used of {} KiB, {} frames quarantined",
+                memory::allocated_frames() * 4,
+                memory::usable_bytes() / 1024,
+                memory::quarantined_frames()
+            ),
+            false,
+        );
+        screen.text(area.x, area.y + 3, area.width, HEADER, false);
+
+        let table = Rect::new(
+            area.x,
+            area.y + 4,
+            area.width,
+            area.height.saturating_sub(4),
+        );
+        let (table, bar) = table.split_bottom(1);
+        self.processes.draw(screen, table);
+
+        let hints = "↑↓ select  k kill  r refresh  Esc console";
+        let status = if self.message.is_empty() {
+            hints
+        } else {
+            &self.message
+        };
+        screen.bar(bar, bar.y, status);
+    }
+
+    fn handle_key(&mut self, key: Key) {
+        self.message.clear();
+
+        if !self.processes.handle_key(key) && key == Key::Char('k') {
+            self.kill_selected();
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Boxes and lists.
+
+use alloc::{string::String, vec::Vec};
+
+use super::{Key, Rect, Screen};
+
+/// Draws a border around `area` with `title` in its top edge.
+pub fn draw_box(screen: &mut Screen, area: Rect, title: &str) {
+    if area.width < 2 || area.height < 2 {
+        return;
+    }
+
+    let right = area.x + area.width - 1;
+    let bottom = area.y + area.height - 1;
+
+    for x in area.x + 1..right {
+        screen.put(x, area.y, '─', false);
+        screen.put(x, bottom, '─', false);
+    }
+    for y in area.y + 1..bottom {
+        screen.put(area.x, y, '│', false);
+        screen.put(right, y, '│', false);
+    }
+    screen.put(area.x, area.y, '┌', false);
+    screen.put(right, area.y, '┐', false);
+    screen.put(area.x, bottom, '└', false);
+    screen.put(right, bottom, '┘', false);
+
+    if !title.is_empty() && area.width > 4 {
+        let written = screen.text(area.x + 2, area.y, area.width - 4, title, false);
+        // A space on each side sets the title apart from the border.
+        screen.put(area.x + 1, area.y, ' ', false);
+        screen.put(area.x + 2 + written, area.y, ' ', false);
+    }
+}
+
+/// A scrolling list of lines with one selected, moved with the arrow keys, Page Up/Down, Home and End.
+#[derive(Debug, Clone, Default)]
+pub struct List {
+    items: Vec<String>,
+    selected: usize,
+    /// Index of the first visible item.
+    offset: usize,
+    /// Visible rows at the last draw, how far Page Up/Down move.
+    page: usize,
+}
+
+impl List {
+    pub fn new(items: Vec<String>) -> Self {
+        List {
+            items,
+            selected: 0,
+            offset: 0,
+            page: 1,
+        }
+    }
+
+    pub fn items(&self) -> &[String] {
+        &self.items
+    }
+
+    /// Replaces the items, keeping the selection where it was as far as possible.
+    pub fn set_items(&mut self, items: Vec<String>) {
+        self.items = items;
+        self.select(self.selected);
+    }
+
+    /// Index of the selected item, `None` if the list is empty.
+    pub fn selected(&self) -> Option<usize> {
+        (!self.items.is_empty()).then_some(self.selected)
+    }
+
+    pub fn selected_item(&self) -> Option<&str> {
+        self.items.get(self.selected).map(String::as_str)
+    }
+
+    /// Selects the item at `index`, or the last one if there are fewer.
+    pub fn select(&mut self, index: usize) {
+        self.selected = index.min(self.items.len().saturating_sub(1));
+    }
+
+    /// Moves the selection. Returns whether the key was a navigation key.
+    pub fn handle_key(&mut self, key: Key) -> bool {
+        let page = self.page.max(1);
+        let index = match key {
+            Key::Up => self.selected.saturating_sub(1),
+            Key::Down => self.selected + 1,
+            Key::PageUp => self.selected.saturating_sub(page),
+            Key::PageDown => self.selected + page,
+            Key::Home => 0,
+            Key::End => usize::MAX,
+            _ => return false,
+        };
+
+        self.select(index);
+        true
+    }
+
+    /// Draws the visible items into `area`, scrolled so the selected one is visible and highlighted.
+    pub fn draw(&mut self, screen: &mut Screen, area: Rect) {
+        self.page = area.height;
+        if area.height == 0 {
+            return;
+        }
+
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        } else if self.selected >= self.offset + area.height {
+            self.offset = self.selected + 1 - area.height;
+        }
+
+        let visible = self.items.iter().enumerate().skip(self.offset);
+        for (y, (index, item)) in (area.y..area.y + area.height).zip(visible) {
+            if index == self.selected {
+                screen.bar(area, y, item);
+            } else {
+                screen.text(area.x, y, area.width, item, false);
+            }
+        }
+    }
+}
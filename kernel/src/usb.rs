@@ -0,0 +1,259 @@
+//! USB: devices on the Universal Serial Bus.
+//!
+//! The host controller, bound by `xhci::DRIVER`, enumerates the devices plugged into its ports: it gives
+//! each one an address and reads its descriptors, the tables every device describes itself with. Keyboards and mice offering the HID boot protocol, the simple fixed reports firmware
+//! uses, are then read by `xhci::run`, which decodes their reports with `hid` and emits them on the
+//! input bus like the PS/2 ones.
+
+pub mod hid;
+pub mod xhci;
+
+pub const DESCRIPTOR_DEVICE: u8 = 1;
+pub const DESCRIPTOR_CONFIGURATION: u8 = 2;
+pub const DESCRIPTOR_INTERFACE: u8 = 4;
+pub const DESCRIPTOR_ENDPOINT: u8 = 5;
+
+const REQUEST_GET_DESCRIPTOR: u8 = 6;
+const REQUEST_SET_CONFIGURATION: u8 = 9;
+const REQUEST_HID_SET_IDLE: u8 = 0x0a;
+const REQUEST_HID_SET_PROTOCOL: u8 = 0x0b;
+
+/// Bits of `SetupPacket::request_type`.
+const REQUEST_DEVICE_TO_HOST: u8 = 1 << 7;
+const REQUEST_CLASS: u8 = 1 << 5;
+const REQUEST_TO_INTERFACE: u8 = 1;
+
+pub const CLASS_HID: u8 = 3;
+pub const SUBCLASS_BOOT: u8 = 1;
+pub const PROTOCOL_KEYBOARD: u8 = 1;
+pub const PROTOCOL_MOUSE: u8 = 2;
+
+const ENDPOINT_IN: u8 = 1 << 7;
+const ENDPOINT_INTERRUPT: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speed {
+    Low,
+    Full,
+    High,
+    Super,
+}
+
+impl Speed {
+    /// The largest packet the default control endpoint surely takes before the device descriptor says.
+    pub fn default_max_packet_size(self) -> u16 {
+        match self {
+            Speed::Low | Speed::Full => 8,
+            Speed::High => 64,
+            Speed::Super => 512,
+        }
+    }
+}
+
+/// The first stage of a control transfer, which says what the request is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    /// The bytes of the data stage, if there is one.
+    pub length: u16,
+}
+
+impl SetupPacket {
+    /// Reads `length` bytes of descriptor `kind` number `index`.
+    pub fn get_descriptor(kind: u8, index: u8, length: u16) -> SetupPacket {
+        SetupPacket {
+            request_type: REQUEST_DEVICE_TO_HOST,
+            request: REQUEST_GET_DESCRIPTOR,
+            value: (u16::from(kind) << 8) | u16::from(index),
+            index: 0,
+            length,
+        }
+    }
+
+    pub fn set_configuration(value: u8) -> SetupPacket {
+        SetupPacket {
+            request_type: 0,
+            request: REQUEST_SET_CONFIGURATION,
+            value: value.into(),
+            index: 0,
+            length: 0,
+        }
+    }
+
+    /// Switches a HID interface to the boot protocol, 0, or the report protocol, 1.
+    pub fn set_protocol(interface: u8, protocol: u8) -> SetupPacket {
+        SetupPacket {
+            request_type: REQUEST_CLASS | REQUEST_TO_INTERFACE,
+            request: REQUEST_HID_SET_PROTOCOL,
+            value: protocol.into(),
+            index: interface.into(),
+            length: 0,
+        }
+    }
+
+    /// Makes a HID interface report only when something changes.
+    pub fn set_idle(interface: u8) -> SetupPacket {
+        SetupPacket {
+            request_type: REQUEST_CLASS | REQUEST_TO_INTERFACE,
+            request: REQUEST_HID_SET_IDLE,
+            value: 0,
+            index: interface.into(),
+            length: 0,
+        }
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.request_type & REQUEST_DEVICE_TO_HOST != 0
+    }
+
+    /// The packet as the eight bytes sent on the wire, in a little-endian integer.
+    pub fn to_u64(self) -> u64 {
+        u64::from(self.request_type)
+            | (u64::from(self.request) << 8)
+            | (u64::from(self.value) << 16)
+            | (u64::from(self.index) << 32)
+            | (u64::from(self.length) << 48)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceDescriptor {
+    /// In binary-coded decimal, e.g. 0x0200 for USB 2.0.
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    /// Of the default control endpoint, as a power of two for SuperSpeed devices.
+    pub max_packet_size0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub configurations: u8,
+}
+
+impl DeviceDescriptor {
+    pub const LENGTH: usize = 18;
+
+    pub fn parse(bytes: &[u8]) -> Option<DeviceDescriptor> {
+        if bytes.len() < Self::LENGTH || bytes[1] != DESCRIPTOR_DEVICE {
+            return None;
+        }
+        let word = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        Some(DeviceDescriptor {
+            usb_version: word(2),
+            class: bytes[4],
+            subclass: bytes[5],
+            protocol: bytes[6],
+            max_packet_size0: bytes[7],
+            vendor_id: word(8),
+            product_id: word(10),
+            configurations: bytes[17],
+        })
+    }
+}
+
+/// The length of a configuration descriptor with the interface and endpoint descriptors after it, and
+/// the value `SetupPacket::set_configuration` selects it with, from its first bytes.
+pub fn configuration_header(bytes: &[u8]) -> Option<(u16, u8)> {
+    if bytes.len() < 9 || bytes[1] != DESCRIPTOR_CONFIGURATION {
+        return None;
+    }
+    Some((u16::from_le_bytes([bytes[2], bytes[3]]), bytes[5]))
+}
+
+/// A HID interface speaking the boot protocol, and the endpoint it reports on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootInterface {
+    pub interface: u8,
+    /// `PROTOCOL_KEYBOARD` or `PROTOCOL_MOUSE`.
+    pub protocol: u8,
+    /// The interrupt IN endpoint's address, with `ENDPOINT_IN` set.
+    pub endpoint: u8,
+    pub max_packet_size: u16,
+    /// How often the endpoint is polled, in frames or in exponent form depending on the speed.
+    pub interval: u8,
+}
+
+/// The boot keyboards and mice in a full configuration descriptor.
+pub fn boot_interfaces(configuration: &[u8]) -> impl Iterator<Item = BootInterface> + '_ {
+    let mut offset = 0;
+    let mut current: Option<(u8, u8)> = None;
+
+    core::iter::from_fn(move || {
+        while offset + 2 <= configuration.len() {
+            let length = usize::from(configuration[offset]);
+            if length < 2 || offset + length > configuration.len() {
+                return None;
+            }
+            let descriptor = &configuration[offset..offset + length];
+            offset += length;
+
+            match descriptor[1] {
+                DESCRIPTOR_INTERFACE if length >= 9 => {
+                    let boot = descriptor[5] == CLASS_HID
+                        && descriptor[6] == SUBCLASS_BOOT
+                        && matches!(descriptor[7], PROTOCOL_KEYBOARD | PROTOCOL_MOUSE);
+                    current = boot.then_some((descriptor[2], descriptor[7]));
+                }
+                DESCRIPTOR_ENDPOINT if length >= 7 => {
+                    let Some((interface, protocol)) = current else {
+                        continue;
+                    };
+                    let endpoint = descriptor[2];
+                    if endpoint & ENDPOINT_IN == 0 || descriptor[3] & 3 != ENDPOINT_INTERRUPT {
+                        continue;
+                    }
+                    // Only the first such endpoint of the interface carries the reports.
+                    current = None;
+                    return Some(BootInterface {
+                        interface,
+                        protocol,
+                        endpoint,
+                        max_packet_size: u16::from_le_bytes([descriptor[4], descriptor[5]]) & 0x7ff,
+                        interval: descriptor[6],
+                    });
+                }
+                _ => {}
+            }
+        }
+        None
+    })
+}
+
+#[test_case]
+fn test_boot_interfaces() {
+    // A keyboard with a boot interface, then a vendor interface with its own interrupt endpoint.
+    #[rustfmt::skip]
+    const CONFIGURATION: [u8; 50] = [
+        9, 2, 50, 0, 2, 1, 0, 0xa0, 50,
+        9, 4, 0, 0, 1, 3, 1, 1, 0,
+        9, 0x21, 0x11, 1, 0, 1, 0x22, 63, 0,
+        7, 5, 0x81, 3, 8, 0, 10,
+        9, 4, 1, 0, 1, 0xff, 0, 0, 0,
+        7, 5, 0x82, 3, 64, 0, 1,
+    ];
+
+    assert_eq!(configuration_header(&CONFIGURATION), Some((50, 1)));
+    let mut interfaces = boot_interfaces(&CONFIGURATION);
+    assert_eq!(
+        interfaces.next(),
+        Some(BootInterface {
+            interface: 0,
+            protocol: PROTOCOL_KEYBOARD,
+            endpoint: 0x81,
+            max_packet_size: 8,
+            interval: 10,
+        })
+    );
+    assert_eq!(interfaces.next(), None);
+}
+
+#[test_case]
+fn test_setup_packet() {
+    let packet = SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, 18);
+    assert!(packet.is_in());
+    assert_eq!(packet.to_u64(), 0x0012_0000_0100_0680);
+    assert!(!SetupPacket::set_configuration(1).is_in());
+}
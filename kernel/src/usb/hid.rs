@@ -0,0 +1,276 @@
+//! HID boot protocol reports, from USB keyboards and mice.
+//!
+//! A keyboard report is a modifier bitmap, a reserved byte and the usage IDs of up to six other keys
+//! held down, so key presses and releases are what differs from the previous report. A mouse report is
+//! a button bitmap and signed movements, with the wheel in a fourth byte on mice that have one.
+
+use pc_keyboard::{KeyCode, KeyEvent, KeyState};
+
+use super::PROTOCOL_KEYBOARD;
+use crate::{
+    input::{self, DeviceId, DeviceKind, MouseReport},
+    task::keyboard::KeyDecoder,
+};
+
+pub const KEYBOARD_REPORT: usize = 8;
+/// What a keyboard reports in every key slot when more keys are held than fit.
+const ERROR_ROLLOVER: u8 = 0x01;
+
+/// The keys of the modifier bitmap, by bit.
+const MODIFIERS: [KeyCode; 8] = [
+    KeyCode::LControl,
+    KeyCode::LShift,
+    KeyCode::LAlt,
+    KeyCode::LWin,
+    KeyCode::RControl,
+    KeyCode::RShift,
+    KeyCode::RAltGr,
+    KeyCode::RWin,
+];
+
+/// The key with HID usage ID `usage` on the keyboard page, named after where it is on a US keyboard
+/// like `pc_keyboard` names them.
+pub fn keycode(usage: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    const LETTERS: [KeyCode; 26] = [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    ];
+    const DIGITS: [KeyCode; 10] = [Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0];
+    const FUNCTION: [KeyCode; 12] = [F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12];
+    const NUMPAD: [KeyCode; 10] = [
+        Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9, Numpad0,
+    ];
+
+    Some(match usage {
+        0x04..=0x1d => LETTERS[usize::from(usage - 0x04)],
+        0x1e..=0x27 => DIGITS[usize::from(usage - 0x1e)],
+        0x28 => Return,
+        0x29 => Escape,
+        0x2a => Backspace,
+        0x2b => Tab,
+        0x2c => Spacebar,
+        0x2d => OemMinus,
+        0x2e => OemPlus,
+        0x2f => Oem4,
+        0x30 => Oem6,
+        // The non-US # key sits where the US backslash does.
+        0x31 | 0x32 => Oem7,
+        0x33 => Oem1,
+        0x34 => Oem3,
+        0x35 => Oem8,
+        0x36 => OemComma,
+        0x37 => OemPeriod,
+        0x38 => Oem2,
+        0x39 => CapsLock,
+        0x3a..=0x45 => FUNCTION[usize::from(usage - 0x3a)],
+        0x46 => PrintScreen,
+        0x47 => ScrollLock,
+        0x48 => PauseBreak,
+        0x49 => Insert,
+        0x4a => Home,
+        0x4b => PageUp,
+        0x4c => Delete,
+        0x4d => End,
+        0x4e => PageDown,
+        0x4f => ArrowRight,
+        0x50 => ArrowLeft,
+        0x51 => ArrowDown,
+        0x52 => ArrowUp,
+        0x53 => NumpadLock,
+        0x54 => NumpadDivide,
+        0x55 => NumpadMultiply,
+        0x56 => NumpadSubtract,
+        0x57 => NumpadAdd,
+        0x58 => NumpadEnter,
+        0x59..=0x62 => NUMPAD[usize::from(usage - 0x59)],
+        0x63 => NumpadPeriod,
+        // The extra key of ISO keyboards, next to the left shift.
+        0x64 => Oem5,
+        0x65 => Apps,
+        // The ABNT2 key next to the right shift.
+        0x87 => Oem12,
+        _ => return None,
+    })
+}
+
+/// Turns keyboard reports into the key presses and releases between them.
+#[derive(Debug, Default)]
+pub struct KeyboardReports {
+    previous: [u8; KEYBOARD_REPORT],
+}
+
+impl KeyboardReports {
+    pub const fn new() -> Self {
+        KeyboardReports {
+            previous: [0; KEYBOARD_REPORT],
+        }
+    }
+
+    /// Calls `emit` for each key `report` presses or releases. Modifiers are pressed before and
+    /// released after the other keys, so a shifted key comes out shifted.
+    pub fn update(&mut self, report: [u8; KEYBOARD_REPORT], mut emit: impl FnMut(KeyEvent)) {
+        // The keys are unknown, so nothing changes until the keyboard can tell again.
+        if report[2] == ERROR_ROLLOVER {
+            return;
+        }
+        let previous = self.previous;
+        let modifier = |bit: usize, state| KeyEvent::new(MODIFIERS[bit], state);
+
+        for bit in 0..MODIFIERS.len() {
+            if report[0] & !previous[0] & (1 << bit) != 0 {
+                emit(modifier(bit, KeyState::Down));
+            }
+        }
+        for &usage in &previous[2..] {
+            if !report[2..].contains(&usage)
+                && let Some(code) = keycode(usage)
+            {
+                emit(KeyEvent::new(code, KeyState::Up));
+            }
+        }
+        for &usage in &report[2..] {
+            if !previous[2..].contains(&usage)
+                && let Some(code) = keycode(usage)
+            {
+                emit(KeyEvent::new(code, KeyState::Down));
+            }
+        }
+        for bit in 0..MODIFIERS.len() {
+            if previous[0] & !report[0] & (1 << bit) != 0 {
+                emit(modifier(bit, KeyState::Up));
+            }
+        }
+
+        self.previous = report;
+    }
+}
+
+/// A mouse report, or `None` if it is too short to be one.
+pub fn mouse_report(report: &[u8]) -> Option<MouseReport> {
+    let [buttons, dx, dy, rest @ ..] = report else {
+        return None;
+    };
+    Some(MouseReport {
+        dx: (*dx as i8).into(),
+        dy: (*dy as i8).into(),
+        buttons: buttons & 0b111,
+        // The wheel counts up when scrolled away from the user.
+        scroll: rest.first().map_or(0, |&wheel| -i32::from(wheel as i8)),
+    })
+}
+
+/// A boot keyboard or mouse, registered on the input bus.
+pub enum BootDevice {
+    Keyboard {
+        device: DeviceId,
+        reports: KeyboardReports,
+        decoder: KeyDecoder,
+    },
+    Mouse {
+        device: DeviceId,
+        buttons: u8,
+    },
+}
+
+impl BootDevice {
+    /// Registers a device speaking boot protocol `protocol`, `PROTOCOL_KEYBOARD` or `PROTOCOL_MOUSE`.
+    pub fn new(protocol: u8) -> BootDevice {
+        if protocol == PROTOCOL_KEYBOARD {
+            BootDevice::Keyboard {
+                device: input::register_device("USB keyboard", DeviceKind::Keyboard),
+                reports: KeyboardReports::new(),
+                decoder: KeyDecoder::new(),
+            }
+        } else {
+            BootDevice::Mouse {
+                device: input::register_device("USB mouse", DeviceKind::Mouse),
+                buttons: 0,
+            }
+        }
+    }
+
+    /// Emits what `report` says happened.
+    pub fn report(&mut self, report: &[u8]) {
+        match self {
+            BootDevice::Keyboard {
+                device,
+                reports,
+                decoder,
+            } => {
+                let Some(&report) = report.first_chunk::<KEYBOARD_REPORT>() else {
+                    return;
+                };
+                decoder.follow_settings();
+                reports.update(report, |key_event| {
+                    if let Some(event) = decoder.process(key_event) {
+                        input::emit(*device, event);
+                    }
+                });
+            }
+            BootDevice::Mouse { device, buttons } => {
+                if let Some(report) = mouse_report(report) {
+                    input::emit_mouse(*device, *buttons, report);
+                    *buttons = report.buttons;
+                }
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_keyboard_reports() {
+    use KeyState::{Down, Up};
+
+    let mut reports = KeyboardReports::new();
+    let mut update = |report| {
+        let mut events = [None; 3];
+        let mut count = 0;
+        reports.update(report, |event| {
+            events[count] = Some((event.code, event.state));
+            count += 1;
+        });
+        events
+    };
+
+    assert_eq!(
+        update([0x02, 0, 0x04, 0, 0, 0, 0, 0]),
+        [
+            Some((KeyCode::LShift, Down)),
+            Some((KeyCode::A, Down)),
+            None
+        ]
+    );
+    assert_eq!(
+        update([0x02, 0, 0x05, 0, 0, 0, 0, 0]),
+        [Some((KeyCode::A, Up)), Some((KeyCode::B, Down)), None]
+    );
+    assert_eq!(update([0x02, 0, ERROR_ROLLOVER, 1, 1, 1, 1, 1]), [None; 3]);
+    assert_eq!(
+        update([0; KEYBOARD_REPORT]),
+        [Some((KeyCode::B, Up)), Some((KeyCode::LShift, Up)), None]
+    );
+}
+
+#[test_case]
+fn test_keycode() {
+    assert_eq!(keycode(0x04), Some(KeyCode::A));
+    assert_eq!(keycode(0x27), Some(KeyCode::Key0));
+    assert_eq!(keycode(0x45), Some(KeyCode::F12));
+    assert_eq!(keycode(0x62), Some(KeyCode::Numpad0));
+    assert_eq!(keycode(0x00), None);
+}
+
+#[test_case]
+fn test_mouse_report() {
+    assert_eq!(
+        mouse_report(&[0b101, 0xff, 3, 1]),
+        Some(MouseReport {
+            dx: -1,
+            dy: 3,
+            buttons: 0b101,
+            scroll: -1,
+        })
+    );
+    assert_eq!(mouse_report(&[0, 1]), None);
+}
@@ -0,0 +1,1037 @@
+//! The eXtensible Host Controller Interface, which USB 3 controllers implement for every USB speed.
+//!
+//! The registers are in BAR 0: the capability registers first, which say where the others are, then the
+//! operational ones with a register per root hub port, the runtime ones for the interrupters and the
+//! doorbells. Everything else goes through rings of TRBs, 16-byte transfer request blocks, in memory. The
+//! driver enqueues commands on the command ring and transfers on a ring per endpoint, and rings a
+//! doorbell; the controller reports their completion on the event ring. A cycle bit in every TRB says
+//! whose it is, and flips each time round a ring.
+//!
+//! `DRIVER` takes the first controller from the firmware, resets it and enumerates the devices already
+//! plugged in, waiting for each command. It is an early driver, as the firmware may be emulating the PS/2
+//! controller with it until then. Boot keyboards and mice get their interrupt endpoint configured with a
+//! few transfers queued, started once enumeration is over, which `run` collects by polling the event
+//! ring. Devices plugged in later aren't noticed.
+
+use alloc::{vec, vec::Vec};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{
+    BootInterface, DESCRIPTOR_CONFIGURATION, DESCRIPTOR_DEVICE, DeviceDescriptor,
+    PROTOCOL_KEYBOARD, SetupPacket, Speed, hid::BootDevice,
+};
+use crate::{
+    dma::{DeviceBuffer, DmaBuffer},
+    driver::{Bus, Busy, Claims, Driver, PciMatch, Priority, ProbeError},
+    init_state::{AlreadyInitialized, InitState},
+    memory,
+    pci::{self, Bar},
+    println,
+    task::timer,
+    time::pit,
+};
+
+const CLASS_SERIAL_BUS: u8 = 0x0c;
+const SUBCLASS_USB: u8 = 0x03;
+const PROG_IF_XHCI: u8 = 0x30;
+
+// Capability registers.
+const CAP_LENGTH: u64 = 0x00;
+const CAP_HCSPARAMS1: u64 = 0x04;
+const CAP_HCSPARAMS2: u64 = 0x08;
+const CAP_HCCPARAMS1: u64 = 0x10;
+const CAP_DBOFF: u64 = 0x14;
+const CAP_RTSOFF: u64 = 0x18;
+
+const HCCPARAMS1_64_BIT: u32 = 1 << 0;
+const HCCPARAMS1_CONTEXT_64: u32 = 1 << 2;
+
+// Operational registers.
+const OP_USBCMD: u64 = 0x00;
+const OP_USBSTS: u64 = 0x04;
+const OP_CRCR: u64 = 0x18;
+const OP_DCBAAP: u64 = 0x30;
+const OP_CONFIG: u64 = 0x38;
+/// The first port's PORTSC, followed by the others every `PORT_STRIDE` bytes.
+const OP_PORTSC: u64 = 0x400;
+const PORT_STRIDE: u64 = 0x10;
+
+const USBCMD_RUN: u32 = 1 << 0;
+const USBCMD_RESET: u32 = 1 << 1;
+const USBSTS_HALTED: u32 = 1 << 0;
+const USBSTS_NOT_READY: u32 = 1 << 11;
+const CRCR_CYCLE: u64 = 1 << 0;
+
+const PORTSC_CONNECTED: u32 = 1 << 0;
+/// Cleared, not set, by writing 1.
+const PORTSC_ENABLED: u32 = 1 << 1;
+const PORTSC_RESET: u32 = 1 << 4;
+const PORTSC_SPEED_SHIFT: u32 = 10;
+const PORTSC_RESET_CHANGE: u32 = 1 << 21;
+/// The change bits, cleared by writing 1.
+const PORTSC_CHANGES: u32 = 0x7f << 17;
+/// The bits to write back as read: port power, the indicator and the wake enables.
+const PORTSC_PRESERVE: u32 = (1 << 9) | (0b11 << 14) | (0b111 << 25);
+
+// Runtime registers of interrupter 0, the only one used.
+const IR0_ERSTSZ: u64 = 0x28;
+const IR0_ERSTBA: u64 = 0x30;
+const IR0_ERDP: u64 = 0x38;
+/// Cleared by writing 1 along with the dequeue pointer.
+const ERDP_BUSY: u64 = 1 << 3;
+
+/// The extended capability by which the firmware hands the controller over.
+const XECP_LEGACY: u32 = 1;
+const LEGACY_BIOS_OWNED: u32 = 1 << 16;
+const LEGACY_OS_OWNED: u32 = 1 << 24;
+/// The SMI event bits of the legacy control register, cleared by writing 1, with the enables left 0.
+const LEGACY_SMI_EVENTS: u32 = 0b111 << 29;
+
+const TRB_NORMAL: u32 = 1;
+const TRB_SETUP: u32 = 2;
+const TRB_DATA: u32 = 3;
+const TRB_STATUS: u32 = 4;
+const TRB_LINK: u32 = 6;
+const TRB_ENABLE_SLOT: u32 = 9;
+const TRB_ADDRESS_DEVICE: u32 = 11;
+const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+const TRB_EVALUATE_CONTEXT: u32 = 13;
+const TRB_TRANSFER_EVENT: u32 = 32;
+const TRB_COMMAND_COMPLETION: u32 = 33;
+
+const TRB_CYCLE: u32 = 1 << 0;
+/// Makes the controller flip its cycle bit when it follows a link TRB.
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+const TRB_INTERRUPT_ON_COMPLETION: u32 = 1 << 5;
+/// The TRB holds the data itself, rather than its address.
+const TRB_IMMEDIATE: u32 = 1 << 6;
+const TRB_DIRECTION_IN: u32 = 1 << 16;
+const TRB_SETUP_IN: u32 = 3 << 16;
+const TRB_SETUP_OUT: u32 = 2 << 16;
+const TRB_LENGTH: u32 = 0x1ffff;
+
+const COMPLETION_SUCCESS: u8 = 1;
+const COMPLETION_SHORT_PACKET: u8 = 13;
+
+const ENDPOINT_CONTROL: u32 = 4;
+const ENDPOINT_INTERRUPT_IN: u32 = 7;
+/// The device context index of the default control endpoint.
+const CONTROL_DCI: u8 = 1;
+/// Times a transfer is retried after an error, the most the context allows.
+const ERROR_RETRIES: u32 = 3;
+
+const PAGE_SIZE: usize = 4096;
+const TRB_SIZE: usize = 16;
+/// TRBs in each ring, which fill one page, the last being the link back to the first.
+const RING_SIZE: usize = PAGE_SIZE / TRB_SIZE;
+/// Where the event ring segment table is in the page of the device context base address array.
+const ERST_OFFSET: usize = 2048;
+const MAX_SLOTS: u32 = 32;
+/// Transfers kept queued on each boot interface, and the room for the report of each.
+const TRANSFERS: usize = 8;
+const REPORT_SIZE: usize = PAGE_SIZE / TRANSFERS;
+/// How long the controller is waited for, in milliseconds.
+const TIMEOUT_MILLIS: u32 = 500;
+/// How long devices are given to connect after the controller starts, in milliseconds.
+const CONNECT_MILLIS: u32 = 100;
+/// How often `run` collects reports. Boot devices ask for 10 ms or less.
+const POLL_PERIOD: Duration = Duration::from_millis(8);
+
+static INIT: InitState = InitState::new("xhci");
+static CONTROLLER: Mutex<Option<Xhci>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XhciError {
+    /// The controller has no memory BAR 0.
+    NoRegisters,
+    /// The registers aren't in the physical memory mapping.
+    NotMapped(PhysAddr),
+    /// The controller didn't halt or come out of reset.
+    ResetTimeout,
+    OutOfMemory,
+    /// A command or transfer didn't complete.
+    Timeout,
+    /// A command completed with this completion code.
+    CommandFailed(u8),
+    /// A transfer completed with this completion code.
+    TransferFailed(u8),
+    /// The port didn't come out of reset enabled.
+    PortResetFailed,
+    /// The port reported a speed ID without a standard meaning.
+    UnknownSpeed(u8),
+    InvalidDescriptor,
+    /// Another driver holds the registers.
+    Busy(Busy),
+    AlreadyInitialized(AlreadyInitialized),
+}
+
+impl From<Busy> for XhciError {
+    fn from(error: Busy) -> Self {
+        XhciError::Busy(error)
+    }
+}
+
+impl From<AlreadyInitialized> for XhciError {
+    fn from(error: AlreadyInitialized) -> Self {
+        XhciError::AlreadyInitialized(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Registers(VirtAddr);
+
+impl Registers {
+    fn read(self, offset: u64) -> u32 {
+        unsafe { (self.0 + offset).as_ptr::<u32>().read_volatile() }
+    }
+
+    fn write(self, offset: u64, value: u32) {
+        unsafe { (self.0 + offset).as_mut_ptr::<u32>().write_volatile(value) }
+    }
+
+    /// Writes a 64-bit register, low half first, as controllers without 64-bit accesses need.
+    fn write64(self, offset: u64, value: u64) {
+        self.write(offset, value as u32);
+        self.write(offset + 4, (value >> 32) as u32);
+    }
+
+    /// Waits until `done` returns true for register `offset`, and returns its last value if it did in
+    /// time.
+    fn wait(self, offset: u64, done: impl Fn(u32) -> bool) -> Option<u32> {
+        for _ in 0..TIMEOUT_MILLIS {
+            let value = self.read(offset);
+            if done(value) {
+                return Some(value);
+            }
+            pit::busy_wait_micros(1000);
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct Trb {
+    parameter: u64,
+    status: u32,
+    control: u32,
+}
+
+impl Trb {
+    fn new(kind: u32, parameter: u64, status: u32, flags: u32) -> Trb {
+        Trb {
+            parameter,
+            status,
+            control: (kind << 10) | flags,
+        }
+    }
+
+    /// A command for device slot `slot`.
+    fn command(kind: u32, slot: u8, parameter: u64) -> Trb {
+        Trb::new(kind, parameter, 0, u32::from(slot) << 24)
+    }
+
+    fn kind(&self) -> u32 {
+        (self.control >> 10) & 0x3f
+    }
+
+    fn completion_code(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+
+    fn slot(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+
+    /// The device context index of the endpoint a transfer event is about.
+    fn endpoint(&self) -> u8 {
+        ((self.control >> 16) & 0x1f) as u8
+    }
+
+    fn with_cycle(self, cycle: bool) -> Trb {
+        Trb {
+            control: (self.control & !TRB_CYCLE) | u32::from(cycle),
+            ..self
+        }
+    }
+}
+
+/// Reads TRB `index` of `ring`.
+fn read_trb(ring: &DeviceBuffer, index: usize) -> Trb {
+    let offset = index * TRB_SIZE;
+    // The control word holds the cycle bit, so it goes first: the rest is only valid once it is set.
+    let control = ring.read(offset + 12);
+    Trb {
+        parameter: ring.read(offset),
+        status: ring.read(offset + 8),
+        control,
+    }
+}
+
+fn write_trb(ring: &DeviceBuffer, index: usize, value: Trb) {
+    let offset = index * TRB_SIZE;
+    // And the other way round here, so the controller never sees its cycle bit on a half-written TRB.
+    ring.write(offset, value.parameter);
+    ring.write(offset + 8, value.status);
+    ring.write(offset + 12, value.control);
+}
+
+fn trb_address(ring: &DeviceBuffer, index: usize) -> PhysAddr {
+    ring.bus_address() + (index * TRB_SIZE) as u64
+}
+
+/// The index in `ring` of the TRB at `address`, if it is there.
+fn trb_index(ring: &DeviceBuffer, address: u64) -> Option<usize> {
+    let offset = address.checked_sub(ring.bus_address().as_u64())? as usize;
+    Some(offset / TRB_SIZE).filter(|&index| index < RING_SIZE)
+}
+
+/// A ring the driver enqueues TRBs on: the command ring, or the transfer ring of an endpoint.
+#[derive(Debug)]
+struct Ring {
+    memory: DeviceBuffer,
+    enqueue: usize,
+    cycle: bool,
+}
+
+impl Ring {
+    fn new(memory: DeviceBuffer) -> Ring {
+        let link = Trb::new(TRB_LINK, memory.bus_address().as_u64(), 0, TRB_TOGGLE_CYCLE);
+        write_trb(&memory, RING_SIZE - 1, link);
+        Ring {
+            memory,
+            enqueue: 0,
+            cycle: true,
+        }
+    }
+
+    fn address(&self) -> PhysAddr {
+        self.memory.bus_address()
+    }
+
+    /// Hands `trb` to the controller, and returns where it is.
+    fn push(&mut self, trb: Trb) -> PhysAddr {
+        let address = trb_address(&self.memory, self.enqueue);
+        write_trb(&self.memory, self.enqueue, trb.with_cycle(self.cycle));
+
+        self.enqueue += 1;
+        if self.enqueue == RING_SIZE - 1 {
+            let link = read_trb(&self.memory, self.enqueue);
+            write_trb(&self.memory, self.enqueue, link.with_cycle(self.cycle));
+            self.enqueue = 0;
+            self.cycle = !self.cycle;
+        }
+        address
+    }
+}
+
+/// The ring the controller enqueues events on, one segment of `RING_SIZE` TRBs without a link.
+#[derive(Debug)]
+struct EventRing {
+    memory: DeviceBuffer,
+    dequeue: usize,
+    cycle: bool,
+}
+
+impl EventRing {
+    fn pop(&mut self) -> Option<Trb> {
+        let trb = read_trb(&self.memory, self.dequeue);
+        if (trb.control & TRB_CYCLE != 0) != self.cycle {
+            return None;
+        }
+
+        self.dequeue += 1;
+        if self.dequeue == RING_SIZE {
+            self.dequeue = 0;
+            self.cycle = !self.cycle;
+        }
+        Some(trb)
+    }
+
+    fn dequeue_address(&self) -> PhysAddr {
+        trb_address(&self.memory, self.dequeue)
+    }
+}
+
+/// A device enumerated by `init`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsbDevice {
+    /// The root hub port it is plugged into, from 1.
+    pub port: u8,
+    pub slot: u8,
+    pub speed: Speed,
+    pub descriptor: DeviceDescriptor,
+    /// Its boot keyboard or mouse interface, if it has one.
+    pub boot: Option<BootInterface>,
+}
+
+/// The interrupt IN endpoint of a boot interface, with its transfers queued.
+#[derive(Debug)]
+struct BootEndpoint {
+    slot: u8,
+    dci: u8,
+    protocol: u8,
+    ring: Ring,
+    /// `TRANSFERS` reports of `REPORT_SIZE` bytes, one per transfer.
+    reports: DeviceBuffer,
+    /// Cleared once a transfer fails, which leaves the endpoint halted.
+    running: bool,
+}
+
+pub struct Xhci {
+    address: pci::Address,
+    operational: Registers,
+    runtime: Registers,
+    doorbells: Registers,
+    /// Bytes in each context, 32 or 64.
+    context_size: usize,
+    /// Whether the controller reaches memory above 4 GiB.
+    wide_dma: bool,
+    dcbaa: DeviceBuffer,
+    commands: Ring,
+    events: EventRing,
+    devices: Vec<UsbDevice>,
+    endpoints: Vec<BootEndpoint>,
+}
+
+impl Xhci {
+    pub fn address(&self) -> pci::Address {
+        self.address
+    }
+
+    /// The devices enumerated, by port.
+    pub fn devices(&self) -> &[UsbDevice] {
+        &self.devices
+    }
+
+    fn allocate(&self) -> Result<DeviceBuffer, XhciError> {
+        allocate_page(self.wide_dma)
+    }
+
+    fn port_register(port: u8) -> u64 {
+        OP_PORTSC + u64::from(port - 1) * PORT_STRIDE
+    }
+
+    fn ring_doorbell(&self, slot: u8, target: u8) {
+        self.doorbells.write(u64::from(slot) * 4, target.into());
+    }
+
+    /// Tells the controller the events up to the dequeue pointer were handled.
+    fn acknowledge_events(&self) {
+        self.runtime
+            .write64(IR0_ERDP, self.events.dequeue_address().as_u64() | ERDP_BUSY);
+    }
+
+    /// Waits for an event `matches` accepts. Others are dropped, which is only right while `init`
+    /// runs, before the report transfers are started.
+    fn wait_event(&mut self, matches: impl Fn(&Trb) -> bool) -> Result<Trb, XhciError> {
+        for _ in 0..TIMEOUT_MILLIS {
+            while let Some(event) = self.events.pop() {
+                self.acknowledge_events();
+                if matches(&event) {
+                    return Ok(event);
+                }
+            }
+            pit::busy_wait_micros(1000);
+        }
+        Err(XhciError::Timeout)
+    }
+
+    /// Runs a command, and returns its completion event.
+    fn command(&mut self, trb: Trb) -> Result<Trb, XhciError> {
+        let address = self.commands.push(trb).as_u64();
+        self.ring_doorbell(0, 0);
+        let event = self.wait_event(|event| {
+            event.kind() == TRB_COMMAND_COMPLETION && event.parameter == address
+        })?;
+        match event.completion_code() {
+            COMPLETION_SUCCESS => Ok(event),
+            code => Err(XhciError::CommandFailed(code)),
+        }
+    }
+
+    /// Runs a request on the default control endpoint of `slot`, whose ring is `control`. Its data
+    /// goes to or from the start of `buffer`.
+    fn control_transfer(
+        &mut self,
+        slot: u8,
+        control: &mut Ring,
+        buffer: &DeviceBuffer,
+        setup: SetupPacket,
+    ) -> Result<(), XhciError> {
+        let has_data = setup.length > 0;
+        let direction = match (has_data, setup.is_in()) {
+            (false, _) => 0,
+            (true, true) => TRB_SETUP_IN,
+            (true, false) => TRB_SETUP_OUT,
+        };
+        control.push(Trb::new(
+            TRB_SETUP,
+            setup.to_u64(),
+            8,
+            TRB_IMMEDIATE | direction,
+        ));
+        if has_data {
+            control.push(Trb::new(
+                TRB_DATA,
+                buffer.bus_address().as_u64(),
+                setup.length.into(),
+                if setup.is_in() { TRB_DIRECTION_IN } else { 0 },
+            ));
+        }
+        // The status stage goes the other way from the data, and in when there is none.
+        let status_in = !(has_data && setup.is_in());
+        control.push(Trb::new(
+            TRB_STATUS,
+            0,
+            0,
+            TRB_INTERRUPT_ON_COMPLETION | if status_in { TRB_DIRECTION_IN } else { 0 },
+        ));
+        self.ring_doorbell(slot, CONTROL_DCI);
+
+        // A failed stage reports itself, and the ones after it never complete.
+        let event = self.wait_event(|event| {
+            event.kind() == TRB_TRANSFER_EVENT
+                && event.slot() == slot
+                && event.endpoint() == CONTROL_DCI
+        })?;
+        match event.completion_code() {
+            COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET => Ok(()),
+            code => Err(XhciError::TransferFailed(code)),
+        }
+    }
+
+    /// Writes `dwords` to context `index` of the context page `page`.
+    fn write_context(&self, page: &DeviceBuffer, index: usize, dwords: &[u32]) {
+        let context = index * self.context_size;
+        for (offset, &dword) in dwords.iter().enumerate() {
+            page.write(context + 4 * offset, dword);
+        }
+    }
+
+    fn reset(&self) -> Result<(), XhciError> {
+        self.operational
+            .write(OP_USBCMD, self.operational.read(OP_USBCMD) & !USBCMD_RUN);
+        self.operational
+            .wait(OP_USBSTS, |value| value & USBSTS_HALTED != 0)
+            .ok_or(XhciError::ResetTimeout)?;
+        self.operational.write(OP_USBCMD, USBCMD_RESET);
+        self.operational
+            .wait(OP_USBCMD, |value| value & USBCMD_RESET == 0)
+            .ok_or(XhciError::ResetTimeout)?;
+        self.operational
+            .wait(OP_USBSTS, |value| value & USBSTS_NOT_READY == 0)
+            .ok_or(XhciError::ResetTimeout)?;
+        Ok(())
+    }
+
+    /// Points the controller at the device contexts and the rings, and starts it.
+    fn start(&self, slots: u32, scratchpads: u32) -> Result<(), XhciError> {
+        // Only the controller knows what is in the scratchpad pages, and it needs them as long as it runs.
+        if scratchpads > 0 {
+            let array = self.allocate()?;
+            for index in 0..scratchpads as usize {
+                let page = self.allocate()?;
+                array.write(index * 8, page.bus_address().as_u64());
+            }
+            self.dcbaa.write(0, array.bus_address().as_u64());
+        }
+
+        // One segment table entry, for the whole event ring.
+        self.dcbaa
+            .write(ERST_OFFSET, self.events.memory.bus_address().as_u64());
+        self.dcbaa.write(ERST_OFFSET + 8, RING_SIZE as u32);
+
+        self.operational.write(OP_CONFIG, slots);
+        self.operational
+            .write64(OP_DCBAAP, self.dcbaa.bus_address().as_u64());
+        self.operational
+            .write64(OP_CRCR, self.commands.address().as_u64() | CRCR_CYCLE);
+        self.runtime.write(IR0_ERSTSZ, 1);
+        self.acknowledge_events();
+        self.runtime.write64(
+            IR0_ERSTBA,
+            self.dcbaa.bus_address().as_u64() + ERST_OFFSET as u64,
+        );
+
+        self.operational
+            .write(OP_USBCMD, self.operational.read(OP_USBCMD) | USBCMD_RUN);
+        self.operational
+            .wait(OP_USBSTS, |value| value & USBSTS_HALTED == 0)
+            .ok_or(XhciError::ResetTimeout)?;
+        Ok(())
+    }
+
+    /// Resets `port` if it needs it to be enabled, and returns the speed of the device on it.
+    fn reset_port(&self, port: u8) -> Result<Speed, XhciError> {
+        let register = Self::port_register(port);
+        let mut status = self.operational.read(register);
+
+        // USB 2 ports are enabled by a reset, USB 3 ones by themselves once the link is up.
+        if status & PORTSC_ENABLED == 0 {
+            self.operational
+                .write(register, (status & PORTSC_PRESERVE) | PORTSC_RESET);
+            status = self
+                .operational
+                .wait(register, |value| value & PORTSC_RESET_CHANGE != 0)
+                .ok_or(XhciError::PortResetFailed)?;
+            self.operational
+                .write(register, (status & PORTSC_PRESERVE) | PORTSC_CHANGES);
+            if status & PORTSC_ENABLED == 0 {
+                return Err(XhciError::PortResetFailed);
+            }
+        }
+
+        let speed = ((status >> PORTSC_SPEED_SHIFT) & 0xf) as u8;
+        speed_from_id(speed).ok_or(XhciError::UnknownSpeed(speed))
+    }
+
+    /// Gives the device on `port` an address, reads its descriptors and, if it is a boot keyboard or
+    /// mouse, configures it and queues transfers for its reports.
+    fn enumerate(&mut self, port: u8) -> Result<(), XhciError> {
+        let speed = self.reset_port(port)?;
+        let slot = self.command(Trb::command(TRB_ENABLE_SLOT, 0, 0))?.slot();
+
+        let output = self.allocate()?;
+        let input = self.allocate()?;
+        let buffer = self.allocate()?;
+        let mut control = Ring::new(self.allocate()?);
+        self.dcbaa
+            .write(usize::from(slot) * 8, output.bus_address().as_u64());
+
+        let mut max_packet_size = speed.default_max_packet_size();
+        self.write_context(&input, 0, &[0, 0b11]);
+        self.write_context(&input, 1, &slot_context(speed, port, CONTROL_DCI));
+        self.write_context(
+            &input,
+            2,
+            &endpoint_context(ENDPOINT_CONTROL, max_packet_size, 0, control.address()),
+        );
+        self.command(Trb::command(
+            TRB_ADDRESS_DEVICE,
+            slot,
+            input.bus_address().as_u64(),
+        ))?;
+
+        // What the device sent, copied out of `buffer` once each transfer is done.
+        let mut bytes = vec![0; PAGE_SIZE];
+
+        // Full-speed devices take 8 to 64 bytes at once, which the first 8 of the descriptor say.
+        self.control_transfer(
+            slot,
+            &mut control,
+            &buffer,
+            SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, 8),
+        )?;
+        buffer.read_bytes(0, &mut bytes[..8]);
+        if speed == Speed::Full && u16::from(bytes[7]) != max_packet_size {
+            max_packet_size = bytes[7].into();
+            self.write_context(&input, 0, &[0, 0b10]);
+            self.write_context(
+                &input,
+                2,
+                &endpoint_context(ENDPOINT_CONTROL, max_packet_size, 0, control.address()),
+            );
+            self.command(Trb::command(
+                TRB_EVALUATE_CONTEXT,
+                slot,
+                input.bus_address().as_u64(),
+            ))?;
+        }
+
+        self.control_transfer(
+            slot,
+            &mut control,
+            &buffer,
+            SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, DeviceDescriptor::LENGTH as u16),
+        )?;
+        buffer.read_bytes(0, &mut bytes[..DeviceDescriptor::LENGTH]);
+        let descriptor = DeviceDescriptor::parse(&bytes).ok_or(XhciError::InvalidDescriptor)?;
+
+        self.control_transfer(
+            slot,
+            &mut control,
+            &buffer,
+            SetupPacket::get_descriptor(DESCRIPTOR_CONFIGURATION, 0, 9),
+        )?;
+        buffer.read_bytes(0, &mut bytes[..9]);
+        let (length, configuration) =
+            super::configuration_header(&bytes).ok_or(XhciError::InvalidDescriptor)?;
+        let length = length.min(PAGE_SIZE as u16);
+        self.control_transfer(
+            slot,
+            &mut control,
+            &buffer,
+            SetupPacket::get_descriptor(DESCRIPTOR_CONFIGURATION, 0, length),
+        )?;
+        buffer.read_bytes(0, &mut bytes[..length.into()]);
+        let boot = super::boot_interfaces(&bytes[..length.into()]).next();
+
+        if let Some(boot) = boot {
+            self.control_transfer(
+                slot,
+                &mut control,
+                &buffer,
+                SetupPacket::set_configuration(configuration),
+            )?;
+            self.control_transfer(
+                slot,
+                &mut control,
+                &buffer,
+                SetupPacket::set_protocol(boot.interface, 0),
+            )?;
+            // Mice needn't support it, and stall the endpoint if they don't.
+            if boot.protocol == PROTOCOL_KEYBOARD {
+                self.control_transfer(
+                    slot,
+                    &mut control,
+                    &buffer,
+                    SetupPacket::set_idle(boot.interface),
+                )?;
+            }
+            self.configure_boot_endpoint(slot, port, speed, &input, boot)?;
+        }
+
+        self.devices.push(UsbDevice {
+            port,
+            slot,
+            speed,
+            descriptor,
+            boot,
+        });
+        Ok(())
+    }
+
+    fn configure_boot_endpoint(
+        &mut self,
+        slot: u8,
+        port: u8,
+        speed: Speed,
+        input: &DeviceBuffer,
+        boot: BootInterface,
+    ) -> Result<(), XhciError> {
+        let dci = (boot.endpoint & 0x0f) * 2 + 1;
+        let mut ring = Ring::new(self.allocate()?);
+        let reports = self.allocate()?;
+
+        self.write_context(input, 0, &[0, 1 | (1 << dci)]);
+        self.write_context(input, 1, &slot_context(speed, port, dci));
+        self.write_context(
+            input,
+            usize::from(dci) + 1,
+            &endpoint_context(
+                ENDPOINT_INTERRUPT_IN,
+                boot.max_packet_size,
+                endpoint_interval(speed, boot.interval),
+                ring.address(),
+            ),
+        );
+        self.command(Trb::command(
+            TRB_CONFIGURE_ENDPOINT,
+            slot,
+            input.bus_address().as_u64(),
+        ))?;
+
+        let length = usize::from(boot.max_packet_size).min(REPORT_SIZE) as u32;
+        for transfer in 0..TRANSFERS {
+            let report = reports.bus_address() + (transfer * REPORT_SIZE) as u64;
+            ring.push(Trb::new(
+                TRB_NORMAL,
+                report.as_u64(),
+                length,
+                TRB_INTERRUPT_ON_COMPLETION,
+            ));
+        }
+        // The doorbell is rung once every device is enumerated.
+
+        self.endpoints.push(BootEndpoint {
+            slot,
+            dci,
+            protocol: boot.protocol,
+            ring,
+            reports,
+            running: true,
+        });
+        Ok(())
+    }
+
+    /// Hands each report received since the last call to `report`, with the index of its endpoint, and
+    /// queues its transfer again.
+    fn poll(&mut self, mut report: impl FnMut(usize, &[u8])) {
+        let mut any = false;
+
+        while let Some(event) = self.events.pop() {
+            any = true;
+            if event.kind() != TRB_TRANSFER_EVENT {
+                continue;
+            }
+            let Some(index) = self.endpoints.iter().position(|endpoint| {
+                endpoint.slot == event.slot() && endpoint.dci == event.endpoint()
+            }) else {
+                continue;
+            };
+            let endpoint = &mut self.endpoints[index];
+            if !matches!(
+                event.completion_code(),
+                COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET
+            ) {
+                // Most likely the device was unplugged. The endpoint is halted, and stays so.
+                endpoint.running = false;
+                continue;
+            }
+
+            let Some(transfer) = trb_index(&endpoint.ring.memory, event.parameter) else {
+                continue;
+            };
+            let transfer = read_trb(&endpoint.ring.memory, transfer);
+            let length = (transfer.status & TRB_LENGTH).saturating_sub(event.status & TRB_LENGTH);
+            let offset = transfer.parameter - endpoint.reports.bus_address().as_u64();
+            let mut data = [0; REPORT_SIZE];
+            let data = &mut data[..(length as usize).min(REPORT_SIZE)];
+            endpoint.reports.read_bytes(offset as usize, data);
+            report(index, data);
+
+            endpoint.ring.push(transfer);
+            let (slot, dci) = (endpoint.slot, endpoint.dci);
+            self.ring_doorbell(slot, dci);
+        }
+
+        if any {
+            self.acknowledge_events();
+        }
+    }
+}
+
+/// Takes the controller from the firmware, which may be emulating a PS/2 keyboard with it.
+fn take_ownership(capabilities: Registers, hccparams1: u32) {
+    let mut offset = u64::from(hccparams1 >> 16) * 4;
+    while offset != 0 {
+        let capability = capabilities.read(offset);
+        if capability & 0xff == XECP_LEGACY {
+            capabilities.write(offset, capability | LEGACY_OS_OWNED);
+            // Firmware that doesn't let go in time is ignored, as there is no one else to ask.
+            capabilities.wait(offset, |value| value & LEGACY_BIOS_OWNED == 0);
+            capabilities.write(offset + 4, LEGACY_SMI_EVENTS);
+            return;
+        }
+        match (capability >> 8) & 0xff {
+            0 => return,
+            next => offset += u64::from(next) * 4,
+        }
+    }
+}
+
+/// A zeroed page for the controller. Everything the controller is pointed at stays its own, as only a
+/// reset makes it forget, so the page is given to it right away and never reclaimed.
+fn allocate_page(wide_dma: bool) -> Result<DeviceBuffer, XhciError> {
+    let page = if wide_dma {
+        DmaBuffer::new(PAGE_SIZE)
+    } else {
+        DmaBuffer::new_32bit(PAGE_SIZE)
+    };
+    page.map(DmaBuffer::give)
+        .map_err(|_| XhciError::OutOfMemory)
+}
+
+/// The speed a port reports with the default speed IDs, which every controller uses for the standard
+/// speeds.
+fn speed_from_id(id: u8) -> Option<Speed> {
+    match id {
+        1 => Some(Speed::Full),
+        2 => Some(Speed::Low),
+        3 => Some(Speed::High),
+        4.. => Some(Speed::Super),
+        0 => None,
+    }
+}
+
+fn speed_id(speed: Speed) -> u32 {
+    match speed {
+        Speed::Full => 1,
+        Speed::Low => 2,
+        Speed::High => 3,
+        Speed::Super => 4,
+    }
+}
+
+/// A slot context for a device on root hub `port` whose last endpoint has device context index
+/// `last_dci`.
+fn slot_context(speed: Speed, port: u8, last_dci: u8) -> [u32; 4] {
+    [
+        (speed_id(speed) << 20) | (u32::from(last_dci) << 27),
+        u32::from(port) << 16,
+        0,
+        0,
+    ]
+}
+
+fn endpoint_context(kind: u32, max_packet_size: u16, interval: u8, ring: PhysAddr) -> [u32; 5] {
+    // The ring starts with the cycle bit set, like `Ring::new` leaves it.
+    let dequeue = ring.as_u64() | 1;
+    // Control transfers average 8 bytes, and interrupt ones a packet.
+    let average = if kind == ENDPOINT_CONTROL {
+        8
+    } else {
+        u32::from(max_packet_size)
+    };
+    let max_payload = if kind == ENDPOINT_CONTROL {
+        0
+    } else {
+        u32::from(max_packet_size)
+    };
+    [
+        u32::from(interval) << 16,
+        (ERROR_RETRIES << 1) | (kind << 3) | (u32::from(max_packet_size) << 16),
+        dequeue as u32,
+        (dequeue >> 32) as u32,
+        average | (max_payload << 16),
+    ]
+}
+
+/// The interval of an endpoint context, 2^n units of 125 µs, for an endpoint descriptor's `interval`.
+fn endpoint_interval(speed: Speed, interval: u8) -> u8 {
+    match speed {
+        // Already an exponent, of 2^(n - 1) units.
+        Speed::High | Speed::Super => interval.clamp(1, 16) - 1,
+        // In frames of 1 ms, rounded down to a power of two.
+        Speed::Low | Speed::Full => (u32::from(interval.max(1)) * 8).ilog2() as u8,
+    }
+}
+
+pub static DRIVER: Driver = Driver {
+    name: "xhci",
+    priority: Priority::Early,
+    bus: Bus::Pci {
+        matches: &[PciMatch::Class {
+            class: CLASS_SERIAL_BUS,
+            subclass: SUBCLASS_USB,
+            prog_if: Some(PROG_IF_XHCI),
+        }],
+        probe,
+    },
+};
+
+fn probe(device: &pci::Device, claims: &mut Claims) -> Result<(), ProbeError> {
+    let xhci = init(device, claims).map_err(ProbeError::new)?;
+    for device in xhci.devices() {
+        println!(
+            "usb: port {}: {:04x}:{:04x}, {:?} speed{}",
+            device.port,
+            device.descriptor.vendor_id,
+            device.descriptor.product_id,
+            device.speed,
+            match device.boot {
+                Some(boot) if boot.protocol == PROTOCOL_KEYBOARD => ", keyboard",
+                Some(_) => ", mouse",
+                None => "",
+            }
+        );
+    }
+    *CONTROLLER.lock() = Some(xhci);
+    Ok(())
+}
+
+/// Sets up `device`, the first controller only, and enumerates the devices plugged into it. Devices that
+/// fail to are left out, with a warning.
+fn init(device: &pci::Device, claims: &mut Claims) -> Result<Xhci, XhciError> {
+    INIT.begin()?;
+
+    let Some(bar @ Bar::Memory { address, .. }) = device.bars[0] else {
+        return Err(XhciError::NoRegisters);
+    };
+    claims.claim(bar.into())?;
+    // Events are polled, but the line is still the controller's, and no other driver's to share.
+    claims.claim_irq(device.interrupt_line);
+    let base = memory::phys_to_virt(PhysAddr::new(address));
+    if !memory::is_mapped(base) {
+        return Err(XhciError::NotMapped(PhysAddr::new(address)));
+    }
+    pci::enable(device.address);
+
+    let capabilities = Registers(base);
+    let cap_length = capabilities.read(CAP_LENGTH) & 0xff;
+    let hcsparams1 = capabilities.read(CAP_HCSPARAMS1);
+    let hcsparams2 = capabilities.read(CAP_HCSPARAMS2);
+    let hccparams1 = capabilities.read(CAP_HCCPARAMS1);
+    let wide_dma = hccparams1 & HCCPARAMS1_64_BIT != 0;
+
+    let dcbaa = allocate_page(wide_dma)?;
+    let commands = Ring::new(allocate_page(wide_dma)?);
+    let events = EventRing {
+        memory: allocate_page(wide_dma)?,
+        dequeue: 0,
+        cycle: true,
+    };
+
+    let mut xhci = Xhci {
+        address: device.address,
+        operational: Registers(base + u64::from(cap_length)),
+        runtime: Registers(base + u64::from(capabilities.read(CAP_RTSOFF) & !0x1f)),
+        doorbells: Registers(base + u64::from(capabilities.read(CAP_DBOFF) & !0x3)),
+        context_size: if hccparams1 & HCCPARAMS1_CONTEXT_64 != 0 {
+            64
+        } else {
+            32
+        },
+        wide_dma,
+        dcbaa,
+        commands,
+        events,
+        devices: Vec::new(),
+        endpoints: Vec::new(),
+    };
+    take_ownership(capabilities, hccparams1);
+    xhci.reset()?;
+
+    let slots = (hcsparams1 & 0xff).min(MAX_SLOTS);
+    let scratchpads = ((hcsparams2 >> 27) & 0x1f) | (((hcsparams2 >> 21) & 0x1f) << 5);
+    xhci.start(slots, scratchpads)?;
+
+    for _ in 0..CONNECT_MILLIS {
+        pit::busy_wait_micros(1000);
+    }
+    let ports = (hcsparams1 >> 24) as u8;
+    for port in 1..=ports {
+        if xhci.operational.read(Xhci::port_register(port)) & PORTSC_CONNECTED == 0 {
+            continue;
+        }
+        if let Err(error) = xhci.enumerate(port) {
+            println!("WARNING: usb: port {} not enumerated: {:?}", port, error);
+        }
+    }
+    for endpoint in &xhci.endpoints {
+        xhci.ring_doorbell(endpoint.slot, endpoint.dci);
+    }
+
+    Ok(xhci)
+}
+
+/// The controller `DRIVER` set up, for `run`. Only the first call gets it.
+pub fn take() -> Option<Xhci> {
+    CONTROLLER.lock().take()
+}
+
+/// Reads the boot keyboards and mice `DRIVER` found, emitting what they report on the input bus.
+pub async fn run(mut xhci: Xhci) {
+    let mut devices: Vec<BootDevice> = xhci
+        .endpoints
+        .iter()
+        .map(|endpoint| BootDevice::new(endpoint.protocol))
+        .collect();
+    let mut interval = timer::interval(POLL_PERIOD);
+
+    while xhci.endpoints.iter().any(|endpoint| endpoint.running) {
+        interval.tick().await;
+        xhci.poll(|index, report| devices[index].report(report));
+    }
+}
+
+#[test_case]
+fn test_endpoint_interval() {
+    assert_eq!(endpoint_interval(Speed::Full, 10), 6);
+    assert_eq!(endpoint_interval(Speed::Low, 0), 3);
+    assert_eq!(endpoint_interval(Speed::High, 4), 3);
+    assert_eq!(endpoint_interval(Speed::Super, 0), 0);
+}
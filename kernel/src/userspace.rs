@@ -1,90 +1,74 @@
-use crate::println;
-use core::arch::asm;
-use x86_64::{
-    PrivilegeLevel, VirtAddr,
-    registers::segmentation::{CS, Segment},
-    structures::{
-        gdt::SegmentSelector,
-        paging::{PageTable, PageTableFlags, PhysFrame},
-    },
-};
+use core::{arch::asm, mem::offset_of};
+use x86_64::registers::segmentation::{CS, Segment};
 
-use crate::memory;
+use crate::{
+    gdt,
+    process::{self, Context},
+};
 
-unsafe fn prepare_paging(physical_memory_offset: VirtAddr) {
-    // FIXME: HADOUKEN
+/// Makes `user_code` process #1 and runs it.
+pub unsafe fn jump_to_userspace() {
     unsafe {
-        for entry in memory::active_level_4_table(physical_memory_offset).iter_mut() {
-            if entry.is_unused() {
-                continue;
-            }
-
-            entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
-
-            if let Ok(level_3_page_table) = entry.frame().map(|frame: PhysFrame| {
-                &mut *(physical_memory_offset + frame.start_address().as_u64())
-                    .as_mut_ptr::<PageTable>()
-            }) {
-                for entry in level_3_page_table.iter_mut() {
-                    if entry.is_unused() {
-                        continue;
-                    }
-
-                    entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
-
-                    if let Ok(level_2_page_table) = entry.frame().map(|frame| {
-                        &mut *(physical_memory_offset + frame.start_address().as_u64())
-                            .as_mut_ptr::<PageTable>()
-                    }) {
-                        for entry in level_2_page_table.iter_mut() {
-                            if entry.is_unused() {
-                                continue;
-                            }
-
-                            entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
-
-                            if let Ok(level_1_page_table) = entry.frame().map(|frame| {
-                                &mut *(physical_memory_offset + frame.start_address().as_u64())
-                                    .as_mut_ptr::<PageTable>()
-                            }) {
-                                for entry in level_1_page_table.iter_mut() {
-                                    if entry.is_unused() {
-                                        continue;
-                                    }
-
-                                    entry
-                                        .set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
+        let pid = process::spawn_user(user_code).expect("failed to create process 1");
+        process::run(pid);
     }
 }
 
-pub unsafe fn jump_to_userspace(physical_memory_offset: VirtAddr) {
+/// Loads the user segments and the registers in `context`, and returns to ring 3.
+///
+/// # Safety
+///
+/// `context` must describe valid user code and stack, mapped user-accessible.
+pub unsafe fn enter(context: &Context) -> ! {
     unsafe {
-        prepare_paging(physical_memory_offset);
-
         asm!(
-            "mov ax, dx",
-            "mov ds, ax",
-            "mov es, ax",
-            "mov fs, ax",
-            "mov gs, ax",
-            "mov {tmp:r}, rsp",
-            "push rdx", // SS (DS)
-            "push {tmp:r}", // Current ESP
-            "pushf", // EFLAGS
-            "push {code_selector:r}", // CS
-            "push {user_code:r}", // EIP
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "mov fs, {data:x}",
+            "mov gs, {data:x}",
+            "push {data}", // SS
+            "push [rdi + {rsp}]",
+            "push [rdi + {rflags}]",
+            "push {code}", // CS
+            "push [rdi + {rip}]",
+            "mov rax, [rdi + {rax}]",
+            "mov rbx, [rdi + {rbx}]",
+            "mov rcx, [rdi + {rcx}]",
+            "mov rdx, [rdi + {rdx}]",
+            "mov rsi, [rdi + {rsi}]",
+            "mov rbp, [rdi + {rbp}]",
+            "mov r8, [rdi + {r8}]",
+            "mov r9, [rdi + {r9}]",
+            "mov r10, [rdi + {r10}]",
+            "mov r11, [rdi + {r11}]",
+            "mov r12, [rdi + {r12}]",
+            "mov r13, [rdi + {r13}]",
+            "mov r14, [rdi + {r14}]",
+            "mov r15, [rdi + {r15}]",
+            "mov rdi, [rdi + {rdi}]",
             "iretq",
-            user_code = in(reg) user_code as usize,
-            tmp = out(reg) _,
-            in("rdx") SegmentSelector::new(4, PrivilegeLevel::Ring3).0,
-            code_selector = in(reg) SegmentSelector::new(3, PrivilegeLevel::Ring3).0,
+            in("rdi") context,
+            data = in(reg) u64::from(gdt::selectors().user_data_selector.0),
+            code = in(reg) u64::from(gdt::selectors().user_code_selector.0),
+            rax = const offset_of!(Context, rax),
+            rbx = const offset_of!(Context, rbx),
+            rcx = const offset_of!(Context, rcx),
+            rdx = const offset_of!(Context, rdx),
+            rsi = const offset_of!(Context, rsi),
+            rdi = const offset_of!(Context, rdi),
+            rbp = const offset_of!(Context, rbp),
+            r8 = const offset_of!(Context, r8),
+            r9 = const offset_of!(Context, r9),
+            r10 = const offset_of!(Context, r10),
+            r11 = const offset_of!(Context, r11),
+            r12 = const offset_of!(Context, r12),
+            r13 = const offset_of!(Context, r13),
+            r14 = const offset_of!(Context, r14),
+            r15 = const offset_of!(Context, r15),
+            rip = const offset_of!(Context, rip),
+            rsp = const offset_of!(Context, rsp),
+            rflags = const offset_of!(Context, rflags),
+            options(noreturn),
         );
     }
 }
@@ -98,9 +82,202 @@ pub fn is_user_ring() -> bool {
 }
 
 pub fn user_code() {
-    println!(
-        "Estamos executando codigo de usuario? Ring {:#?}",
-        current_ring()
+    use core::fmt::Write;
+    use libsys::mman::{MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+
+    let mut stdout = libsys::Stdout;
+    let _ = writeln!(
+        stdout,
+        "Estamos executando codigo de usuario? Ring {:#?}, pid {}",
+        current_ring(),
+        libsys::getpid()
+    );
+
+    let _ = libsys::log(libsys::log::INFO, "user code started");
+
+    match libsys::open("README", libsys::fcntl::O_RDONLY) {
+        Ok(fd) => {
+            let mut buffer = [0; 64];
+            let read = libsys::read(fd, &mut buffer).unwrap_or(0);
+            let text = core::str::from_utf8(&buffer[..read]).unwrap_or("?");
+            let _ = writeln!(stdout, "README: {}", text.lines().next().unwrap_or(""));
+            let _ = libsys::close(fd);
+        }
+        Err(error) => {
+            let _ = writeln!(stdout, "open failed: {}", error);
+        }
+    }
+
+    match libsys::mmap(4096, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS) {
+        Ok(memory) => {
+            unsafe { memory.write(42) };
+            let _ = writeln!(stdout, "mmap: {:p} holds {}", memory, unsafe {
+                memory.read()
+            });
+            let _ = unsafe { libsys::munmap(memory, 4096) };
+        }
+        Err(error) => {
+            let _ = writeln!(stdout, "mmap failed: {}", error);
+        }
+    }
+
+    static THREAD_LOCAL: u64 = 0x7157;
+    if libsys::set_fs_base(&raw const THREAD_LOCAL as u64).is_ok() {
+        let value: u64;
+        unsafe { core::arch::asm!("mov {}, fs:[0]", out(reg) value, options(nostack, readonly)) };
+        let _ = writeln!(stdout, "fs:[0] holds {:#x}", value);
+    }
+
+    // Three lines, one syscall.
+    let mut buffered = libsys::BufferedStdout::<256>::new();
+    for square in [1, 4, 9] {
+        let _ = writeln!(buffered, "buffered: {}", square);
+    }
+    let _ = buffered.flush();
+
+    let _ = libsys::sigaction(
+        libsys::signal::SIGUSR1,
+        libsys::signal::Action::Handler(on_sigusr1),
     );
-    loop {}
+    let _ = libsys::kill(libsys::getpid(), libsys::signal::SIGUSR1);
+
+    match libsys::fork() {
+        Ok(0) => {
+            let nice = libsys::nice(5).unwrap_or(0);
+            let _ = writeln!(stdout, "child: pid {}, nice {}", libsys::getpid(), nice);
+            libsys::exit(7);
+        }
+        Ok(child) => match libsys::wait(Some(child)) {
+            Ok((pid, exit_code)) => {
+                let _ = writeln!(stdout, "parent: child {} exited with {}", pid, exit_code);
+            }
+            Err(error) => {
+                let _ = writeln!(stdout, "wait failed: {}", error);
+            }
+        },
+        Err(error) => {
+            let _ = writeln!(stdout, "fork failed: {}", error);
+        }
+    }
+
+    if let Ok((read_end, write_end)) = libsys::pipe() {
+        match libsys::fork() {
+            Ok(0) => {
+                let _ = libsys::close(read_end);
+                let _ = libsys::write(write_end, b"hello through a pipe");
+                libsys::exit(0);
+            }
+            Ok(child) => {
+                // Without this the read below would never see the end of the pipe.
+                let _ = libsys::close(write_end);
+
+                let mut buffer = [0; 64];
+                let mut len = 0;
+                while let Ok(read @ 1..) = libsys::read(read_end, &mut buffer[len..]) {
+                    len += read;
+                }
+                let text = core::str::from_utf8(&buffer[..len]).unwrap_or("?");
+                let _ = writeln!(stdout, "pipe: {}", text);
+
+                let _ = libsys::close(read_end);
+                let _ = libsys::wait(Some(child));
+            }
+            Err(error) => {
+                let _ = writeln!(stdout, "fork failed: {}", error);
+            }
+        }
+    }
+
+    use libsys::time::{CLOCK_MONOTONIC, Timespec};
+    let nap = Timespec::from(core::time::Duration::from_millis(50));
+    if let (Ok(before), Ok(()), Ok(after)) = (
+        libsys::clock_gettime(CLOCK_MONOTONIC),
+        libsys::nanosleep(&nap),
+        libsys::clock_gettime(CLOCK_MONOTONIC),
+    ) {
+        let slept = (after.tv_sec - before.tv_sec) * 1_000_000_000 + after.tv_nsec - before.tv_nsec;
+        let _ = writeln!(stdout, "slept {} ms", slept / 1_000_000);
+    }
+
+    #[cfg(feature = "sched-latency")]
+    latency_test();
+
+    libsys::exit(0);
+}
+
+/// The scheduler latency smoke test of the `sched-latency` feature. At each of a few time slices, the
+/// process renices itself to the top and sleeps `PERIOD` at a time while `SPINNERS` children at the default
+/// nice value spin, and prints how late it woke up, one line of `key=value` pairs per slice.
+#[cfg(feature = "sched-latency")]
+fn latency_test() {
+    use core::{fmt::Write, time::Duration};
+    use libsys::{
+        sysctl::SCHED_SLICE_TICKS,
+        time::{CLOCK_MONOTONIC, Timespec},
+    };
+
+    const SPINNERS: usize = 3;
+    const SAMPLES: u64 = 50;
+    const PERIOD: Duration = Duration::from_millis(10);
+
+    let mut stdout = libsys::Stdout;
+    let nanos = |time: Timespec| time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64;
+    let Ok(default) = libsys::sysctl(SCHED_SLICE_TICKS, None) else {
+        let _ = writeln!(stdout, "sched-latency: no slice setting");
+        return;
+    };
+
+    for slice in [1, 2, 5, 10] {
+        let _ = libsys::sysctl(SCHED_SLICE_TICKS, Some(slice));
+        let spinners = core::array::from_fn::<_, SPINNERS, _>(|_| match libsys::fork() {
+            Ok(0) => loop {
+                core::hint::spin_loop();
+            },
+            Ok(child) => Some(child),
+            Err(_) => None,
+        });
+        // As far up as nice values go, and back down after.
+        let _ = libsys::nice(-20);
+
+        let (mut min, mut total, mut max) = (u64::MAX, 0, 0);
+        for _ in 0..SAMPLES {
+            let (Ok(before), Ok(()), Ok(after)) = (
+                libsys::clock_gettime(CLOCK_MONOTONIC),
+                libsys::nanosleep(&Timespec::from(PERIOD)),
+                libsys::clock_gettime(CLOCK_MONOTONIC),
+            ) else {
+                continue;
+            };
+            let late =
+                (nanos(after) - nanos(before)).saturating_sub(PERIOD.as_nanos() as u64) / 1000;
+            min = min.min(late);
+            total += late;
+            max = max.max(late);
+        }
+
+        let _ = libsys::nice(20);
+        let spinning = spinners.iter().flatten().count();
+        for child in spinners.into_iter().flatten() {
+            let _ = libsys::kill(child, libsys::signal::SIGKILL);
+            let _ = libsys::wait(Some(child));
+        }
+        let _ = writeln!(
+            stdout,
+            "sched-latency slice_ticks={} spinners={} samples={} min_us={} avg_us={} max_us={}",
+            slice,
+            spinning,
+            SAMPLES,
+            min,
+            total / SAMPLES,
+            max
+        );
+    }
+
+    let _ = libsys::sysctl(SCHED_SLICE_TICKS, Some(default));
+}
+
+extern "C" fn on_sigusr1(signal: u64) {
+    use core::fmt::Write;
+
+    let _ = writeln!(libsys::Stdout, "caught signal {}", signal);
 }
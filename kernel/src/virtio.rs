@@ -0,0 +1,381 @@
+//! virtio: the paravirtualized devices hypervisors like QEMU provide.
+//!
+//! A virtio device is found on the PCI bus and reached through one of two transports. Legacy devices
+//! (and transitional ones, which also speak the legacy interface) have their registers in I/O BAR 0.
+//! Modern devices have vendor capabilities pointing at memory mapped register blocks in their BARs:
+//! common configuration, notifications, the interrupt status and the device's own configuration.
+//! `Transport` hides the difference from drivers. virtio-mmio devices, which are found through the
+//! device tree, don't exist on x86 and aren't supported.
+//!
+//! Drivers talk to the device through virtqueues, rings in memory shared with it (see `queue`).
+
+use x86_64::{PhysAddr, VirtAddr, instructions::port::Port};
+
+use crate::{
+    driver::Busy,
+    init_state::AlreadyInitialized,
+    memory,
+    pci::{self, Bar},
+};
+
+pub mod blk;
+pub mod gpu;
+pub mod queue;
+
+pub use queue::VirtQueue;
+
+/// The PCI vendor ID of every virtio device.
+pub const VENDOR_ID: u16 = 0x1af4;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// The feature modern devices require the driver to accept.
+const FEATURE_VERSION_1: u64 = 1 << 32;
+
+/// Interrupt status bit set when a queue has new used buffers.
+const ISR_QUEUE: u8 = 1;
+
+/// The capability ID of virtio's vendor capabilities.
+const CAPABILITY_VENDOR: u8 = 0x09;
+/// The kind of capability that points at the notification registers.
+const CAP_NOTIFY_CFG: u8 = 2;
+
+// Legacy registers, as offsets into I/O BAR 0.
+const LEGACY_DEVICE_FEATURES: u16 = 0;
+const LEGACY_DRIVER_FEATURES: u16 = 4;
+const LEGACY_QUEUE_PFN: u16 = 8;
+const LEGACY_QUEUE_SIZE: u16 = 12;
+const LEGACY_QUEUE_SELECT: u16 = 14;
+const LEGACY_QUEUE_NOTIFY: u16 = 16;
+const LEGACY_STATUS: u16 = 18;
+const LEGACY_ISR: u16 = 19;
+/// Where the device's configuration starts, without MSI-X.
+const LEGACY_DEVICE_CONFIG: u16 = 20;
+
+// Modern common configuration registers, as offsets into its block.
+const DEVICE_FEATURE_SELECT: u64 = 0;
+const DEVICE_FEATURE: u64 = 4;
+const DRIVER_FEATURE_SELECT: u64 = 8;
+const DRIVER_FEATURE: u64 = 12;
+const DEVICE_STATUS: u64 = 20;
+const QUEUE_SELECT: u64 = 22;
+const QUEUE_SIZE: u64 = 24;
+const QUEUE_ENABLE: u64 = 28;
+const QUEUE_NOTIFY_OFF: u64 = 30;
+const QUEUE_DESC: u64 = 32;
+const QUEUE_DRIVER: u64 = 40;
+const QUEUE_DEVICE: u64 = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// The device has neither virtio capabilities nor an I/O BAR 0.
+    NoTransport,
+    /// A register block isn't in the physical memory mapping.
+    NotMapped(PhysAddr),
+    /// The device doesn't accept the features the driver needs.
+    FeaturesRejected,
+    /// The device has no queue with this index, or one too small for the driver.
+    QueueUnavailable(u16),
+    OutOfMemory,
+    Busy(Busy),
+    AlreadyInitialized(AlreadyInitialized),
+}
+
+impl From<Busy> for VirtioError {
+    fn from(error: Busy) -> Self {
+        VirtioError::Busy(error)
+    }
+}
+
+impl From<AlreadyInitialized> for VirtioError {
+    fn from(error: AlreadyInitialized) -> Self {
+        VirtioError::AlreadyInitialized(error)
+    }
+}
+
+/// Registers of a legacy device, in I/O space.
+#[derive(Debug, Clone, Copy)]
+pub struct LegacyTransport {
+    base: u16,
+}
+
+/// Register blocks of a modern device, in memory.
+#[derive(Debug, Clone, Copy)]
+pub struct ModernTransport {
+    common: VirtAddr,
+    notify: VirtAddr,
+    /// Bytes between the notification registers of queues whose notify offsets follow each other.
+    notify_multiplier: u32,
+    isr: VirtAddr,
+    device: VirtAddr,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Transport {
+    Legacy(LegacyTransport),
+    Modern(ModernTransport),
+}
+
+impl LegacyTransport {
+    fn read<T: x86_64::instructions::port::PortRead>(&self, offset: u16) -> T {
+        unsafe { Port::<T>::new(self.base + offset).read() }
+    }
+
+    fn write<T: x86_64::instructions::port::PortWrite>(&self, offset: u16, value: T) {
+        unsafe { Port::<T>::new(self.base + offset).write(value) }
+    }
+}
+
+impl ModernTransport {
+    /// Finds the register blocks the virtio capabilities of `device` point at.
+    fn probe(device: &pci::Device) -> Result<Option<Self>, VirtioError> {
+        // By capability kind: common configuration, notifications, interrupt status and device
+        // configuration are 1 to 4.
+        let mut blocks = [None; 5];
+        let mut notify_multiplier = 0;
+
+        for (id, offset) in pci::capabilities(device.address) {
+            if id != CAPABILITY_VENDOR {
+                continue;
+            }
+            let kind = (pci::read_config(device.address, offset) >> 24) as u8;
+            let bar = pci::read_config(device.address, offset + 4) as u8;
+            let start = pci::read_config(device.address, offset + 8);
+            if kind == CAP_NOTIFY_CFG {
+                notify_multiplier = pci::read_config(device.address, offset + 16);
+            }
+
+            // The first capability of a kind is the one to use.
+            let Some(block) = blocks.get_mut(usize::from(kind)) else {
+                continue;
+            };
+            if block.is_some() {
+                continue;
+            }
+            if let Some(Some(Bar::Memory { address, .. })) = device.bars.get(usize::from(bar)) {
+                *block = Some(PhysAddr::new(address + u64::from(start)));
+            }
+        }
+
+        let [_, Some(common), Some(notify), Some(isr), Some(device)] = blocks else {
+            return Ok(None);
+        };
+        for block in [common, notify, isr, device] {
+            if !memory::is_mapped(memory::phys_to_virt(block)) {
+                return Err(VirtioError::NotMapped(block));
+            }
+        }
+
+        Ok(Some(ModernTransport {
+            common: memory::phys_to_virt(common),
+            notify: memory::phys_to_virt(notify),
+            notify_multiplier,
+            isr: memory::phys_to_virt(isr),
+            device: memory::phys_to_virt(device),
+        }))
+    }
+
+    fn read<T>(&self, block: VirtAddr, offset: u64) -> T {
+        unsafe { (block + offset).as_ptr::<T>().read_volatile() }
+    }
+
+    fn write<T>(&self, block: VirtAddr, offset: u64, value: T) {
+        unsafe { (block + offset).as_mut_ptr::<T>().write_volatile(value) }
+    }
+
+    /// Writes a 64-bit register as two halves, low first.
+    fn write_u64(&self, offset: u64, value: u64) {
+        self.write(self.common, offset, value as u32);
+        self.write(self.common, offset + 4, (value >> 32) as u32);
+    }
+}
+
+impl Transport {
+    /// The transport of `device`: modern if it has virtio capabilities, legacy otherwise.
+    pub fn probe(device: &pci::Device) -> Result<Self, VirtioError> {
+        if let Some(modern) = ModernTransport::probe(device)? {
+            return Ok(Transport::Modern(modern));
+        }
+        match device.bars[0] {
+            Some(Bar::Io { port, .. }) => Ok(Transport::Legacy(LegacyTransport { base: port })),
+            _ => Err(VirtioError::NoTransport),
+        }
+    }
+
+    pub fn is_modern(&self) -> bool {
+        matches!(self, Transport::Modern(_))
+    }
+
+    fn status(&self) -> u8 {
+        match self {
+            Transport::Legacy(legacy) => legacy.read(LEGACY_STATUS),
+            Transport::Modern(modern) => modern.read(modern.common, DEVICE_STATUS),
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match self {
+            Transport::Legacy(legacy) => legacy.write(LEGACY_STATUS, status),
+            Transport::Modern(modern) => modern.write(modern.common, DEVICE_STATUS, status),
+        }
+    }
+
+    fn add_status(&self, status: u8) {
+        self.set_status(self.status() | status);
+    }
+
+    /// Resets the device, which forgets its queues and stops using their memory.
+    pub fn reset(&self) {
+        self.set_status(0);
+        // A modern device may take a while; it reads back 0 once done.
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        match self {
+            Transport::Legacy(legacy) => u64::from(legacy.read::<u32>(LEGACY_DEVICE_FEATURES)),
+            Transport::Modern(modern) => {
+                let mut features = 0;
+                for half in 0..2u32 {
+                    modern.write(modern.common, DEVICE_FEATURE_SELECT, half);
+                    let bits: u32 = modern.read(modern.common, DEVICE_FEATURE);
+                    features |= u64::from(bits) << (32 * half);
+                }
+                features
+            }
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match self {
+            Transport::Legacy(legacy) => legacy.write(LEGACY_DRIVER_FEATURES, features as u32),
+            Transport::Modern(modern) => {
+                for half in 0..2u32 {
+                    modern.write(modern.common, DRIVER_FEATURE_SELECT, half);
+                    modern.write(
+                        modern.common,
+                        DRIVER_FEATURE,
+                        (features >> (32 * half)) as u32,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Resets the device and agrees on the features of `supported` it offers, which are returned. Queues
+    /// are set up next, then `finish_init` lets the device run.
+    pub fn begin_init(&self, supported: u64) -> Result<u64, VirtioError> {
+        self.reset();
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+
+        let offered = self.device_features();
+        let required = if self.is_modern() {
+            FEATURE_VERSION_1
+        } else {
+            0
+        };
+        if offered & required != required {
+            self.add_status(STATUS_FAILED);
+            return Err(VirtioError::FeaturesRejected);
+        }
+        let features = offered & (supported | required);
+        self.set_driver_features(features);
+
+        // Legacy devices have no way to refuse features.
+        if self.is_modern() {
+            self.add_status(STATUS_FEATURES_OK);
+            if self.status() & STATUS_FEATURES_OK == 0 {
+                self.add_status(STATUS_FAILED);
+                return Err(VirtioError::FeaturesRejected);
+            }
+        }
+
+        Ok(features)
+    }
+
+    /// Tells the device the driver is ready, after its queues were set up.
+    pub fn finish_init(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device the driver gave up on it.
+    pub fn fail(&self) {
+        self.add_status(STATUS_FAILED);
+    }
+
+    /// Sets up the queue `index` with at most `max_size` descriptors, or exactly as many as the device
+    /// has on the legacy transport, where the size can't be changed.
+    pub fn setup_queue(&self, index: u16, max_size: u16) -> Result<VirtQueue, VirtioError> {
+        match self {
+            Transport::Legacy(legacy) => {
+                legacy.write(LEGACY_QUEUE_SELECT, index);
+                let size: u16 = legacy.read(LEGACY_QUEUE_SIZE);
+                if size == 0 {
+                    return Err(VirtioError::QueueUnavailable(index));
+                }
+                let queue = VirtQueue::new(index, size, index).ok_or(VirtioError::OutOfMemory)?;
+                let pfn = queue.descriptors_address().as_u64() >> 12;
+                legacy.write(LEGACY_QUEUE_PFN, pfn as u32);
+                Ok(queue)
+            }
+            Transport::Modern(modern) => {
+                modern.write(modern.common, QUEUE_SELECT, index);
+                let size: u16 = modern.read(modern.common, QUEUE_SIZE);
+                if size == 0 {
+                    return Err(VirtioError::QueueUnavailable(index));
+                }
+                let size = size.min(max_size);
+                let notify_offset = modern.read(modern.common, QUEUE_NOTIFY_OFF);
+                let queue =
+                    VirtQueue::new(index, size, notify_offset).ok_or(VirtioError::OutOfMemory)?;
+
+                modern.write(modern.common, QUEUE_SIZE, size);
+                modern.write_u64(QUEUE_DESC, queue.descriptors_address().as_u64());
+                modern.write_u64(QUEUE_DRIVER, queue.available_address().as_u64());
+                modern.write_u64(QUEUE_DEVICE, queue.used_address().as_u64());
+                modern.write(modern.common, QUEUE_ENABLE, 1u16);
+                Ok(queue)
+            }
+        }
+    }
+
+    /// Tells the device `queue` has new available buffers.
+    pub fn notify(&self, queue: &VirtQueue) {
+        match self {
+            Transport::Legacy(legacy) => legacy.write(LEGACY_QUEUE_NOTIFY, queue.index()),
+            Transport::Modern(modern) => {
+                let offset = u64::from(queue.notify_offset()) * u64::from(modern.notify_multiplier);
+                modern.write(modern.notify, offset, queue.index());
+            }
+        }
+    }
+
+    /// Acknowledges an interrupt, and returns whether it was for new used buffers in a queue.
+    pub fn ack_interrupt(&self) -> bool {
+        // Reading the status clears it, and deasserts the interrupt line.
+        let status: u8 = match self {
+            Transport::Legacy(legacy) => legacy.read(LEGACY_ISR),
+            Transport::Modern(modern) => modern.read(modern.isr, 0),
+        };
+        status & ISR_QUEUE != 0
+    }
+
+    /// Reads 32 bits at `offset` in the device's own configuration.
+    pub fn read_config(&self, offset: u16) -> u32 {
+        match self {
+            Transport::Legacy(legacy) => legacy.read(LEGACY_DEVICE_CONFIG + offset),
+            Transport::Modern(modern) => modern.read(modern.device, u64::from(offset)),
+        }
+    }
+
+    pub fn read_config_u64(&self, offset: u16) -> u64 {
+        u64::from(self.read_config(offset)) | u64::from(self.read_config(offset + 4)) << 32
+    }
+}
@@ -0,0 +1,418 @@
+//! virtio-blk: a disk provided by the hypervisor, e.g. QEMU's `-drive if=virtio`.
+//!
+//! Requests go through the device's only queue. Each is a chain of three descriptors: a header with the
+//! operation and the first sector, the data, and a status byte the device writes once done. Up to
+//! `SLOTS` requests are in flight at once, each with descriptors of its own and a page of memory its
+//! data goes through, so requests are split into chunks of a page.
+//!
+//! A task waiting for a request sleeps until the device interrupts. The interrupt handler only wakes the
+//! waiting tasks; they take completed requests off the used ring themselves. Without an interrupt line,
+//! waiting tasks poll the ring each time the executor runs them.
+
+use super::{
+    Transport, VENDOR_ID, VirtQueue, VirtioError,
+    queue::{DESC_NEXT, DESC_WRITE},
+};
+use crate::{
+    dma::{DeviceBuffer, DmaBuffer},
+    driver::{Bus, Claims, Driver, PciMatch, Priority, ProbeError},
+    init_state::InitState,
+    interrupts, pci, println,
+    storage::{self, BlockDevice, BlockError},
+    task,
+};
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+/// The device ID of transitional virtio-blk devices, which also have the legacy interface.
+const DEVICE_ID_TRANSITIONAL: u16 = 0x1001;
+const DEVICE_ID_MODERN: u16 = 0x1042;
+
+pub const SECTOR_SIZE: usize = 512;
+/// Requests in flight at once.
+const SLOTS: usize = 8;
+/// Descriptors used by each request.
+const DESCRIPTORS_PER_REQUEST: usize = 3;
+/// The most descriptors asked for on the modern transport, which lets the driver choose.
+const QUEUE_SIZE: u16 = 64;
+/// The most data moved by a request: a page.
+const CHUNK_SIZE: usize = 4096;
+
+/// The device can't be written to.
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+
+/// Offset of the capacity, in sectors, in the device's configuration.
+const CONFIG_CAPACITY: u16 = 0;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+/// Where the status byte is in the memory of a request, after the header.
+const STATUS_OFFSET: usize = 16;
+/// Where the data is in the memory of a request, on the page after the header.
+const DATA_OFFSET: usize = 4096;
+
+static INIT: InitState = InitState::new("virtio-blk");
+static DEVICE: OnceCell<VirtioBlk> = OnceCell::uninit();
+
+/// What a request in flight needs.
+struct Slot {
+    /// The request's memory: its header, its status byte at `STATUS_OFFSET` and `CHUNK_SIZE` bytes of
+    /// data at `DATA_OFFSET`. Held by the task whose request uses the slot, and only `None` while the
+    /// device owns it.
+    memory: task::sync::Mutex<Option<DmaBuffer>>,
+    /// Set once the device is done with the request.
+    done: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl Slot {
+    fn new() -> Option<Self> {
+        let memory = DmaBuffer::new(DATA_OFFSET + CHUNK_SIZE).ok()?;
+
+        Some(Slot {
+            memory: task::sync::Mutex::new(Some(memory)),
+            done: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        })
+    }
+}
+
+/// The data of a request between requests.
+fn data(memory: &mut Option<DmaBuffer>) -> &mut [u8] {
+    let memory = memory.as_mut().expect("a request is still running");
+    &mut memory.as_mut_slice()[DATA_OFFSET..]
+}
+
+pub struct VirtioBlk {
+    address: pci::Address,
+    transport: Transport,
+    queue: Mutex<VirtQueue>,
+    slots: Vec<Slot>,
+    /// The slot to wait for next when all of them are in use.
+    next_slot: AtomicUsize,
+    /// The size of the disk in sectors.
+    capacity: u64,
+    read_only: bool,
+    /// Whether the device interrupts when done, rather than waiting tasks polling it.
+    interrupts: bool,
+}
+
+impl VirtioBlk {
+    fn new(device: &pci::Device) -> Result<Self, VirtioError> {
+        let transport = Transport::probe(device)?;
+        pci::enable(device.address);
+
+        let features = transport.begin_init(FEATURE_READ_ONLY)?;
+        let result = Self::setup(device.address, transport, features);
+        match &result {
+            Ok(_) => transport.finish_init(),
+            Err(_) => transport.fail(),
+        }
+        result
+    }
+
+    fn setup(
+        address: pci::Address,
+        transport: Transport,
+        features: u64,
+    ) -> Result<Self, VirtioError> {
+        let queue = transport.setup_queue(0, QUEUE_SIZE)?;
+        if usize::from(queue.size()) < SLOTS * DESCRIPTORS_PER_REQUEST {
+            transport.reset();
+            return Err(VirtioError::QueueUnavailable(0));
+        }
+
+        let mut slots = Vec::with_capacity(SLOTS);
+        for _ in 0..SLOTS {
+            match Slot::new() {
+                Some(slot) => slots.push(slot),
+                None => {
+                    transport.reset();
+                    return Err(VirtioError::OutOfMemory);
+                }
+            }
+        }
+
+        Ok(VirtioBlk {
+            address,
+            transport,
+            queue: Mutex::new(queue),
+            slots,
+            next_slot: AtomicUsize::new(0),
+            capacity: transport.read_config_u64(CONFIG_CAPACITY),
+            read_only: features & FEATURE_READ_ONLY != 0,
+            interrupts: false,
+        })
+    }
+
+    pub fn address(&self) -> pci::Address {
+        self.address
+    }
+
+    pub fn is_modern(&self) -> bool {
+        self.transport.is_modern()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Locks a free slot, or waits for one, and returns its index and memory.
+    async fn lock_slot(&self) -> (usize, task::sync::MutexGuard<'_, Option<DmaBuffer>>) {
+        for (index, slot) in self.slots.iter().enumerate() {
+            if let Some(guard) = slot.memory.try_lock() {
+                return (index, guard);
+            }
+        }
+        let index = self.next_slot.fetch_add(1, Ordering::Relaxed) % SLOTS;
+        (index, self.slots[index].memory.lock().await)
+    }
+
+    /// Sends a request for `len` bytes from `sector` on, through `memory` of the slot `index`, and
+    /// waits until the device is done with it. The memory is back once this completes.
+    async fn transfer(
+        &self,
+        index: usize,
+        memory: &mut Option<DmaBuffer>,
+        request: u32,
+        sector: u64,
+        len: usize,
+    ) -> Result<(), BlockError> {
+        let slot = &self.slots[index];
+        let mut header = memory.take().expect("a request is already running");
+        header.write(0, request);
+        header.write(4, 0u32);
+        header.write(8, sector);
+        header.write(STATUS_OFFSET, 0xffu8);
+        let given = header.give();
+        let address = given.bus_address();
+        slot.done.store(false, Ordering::Relaxed);
+
+        let head = (index * DESCRIPTORS_PER_REQUEST) as u16;
+        let data_flags = if request == REQUEST_IN {
+            DESC_NEXT | DESC_WRITE
+        } else {
+            DESC_NEXT
+        };
+        {
+            let mut queue = self.queue.lock();
+            queue.set_descriptor(head, address, 16, DESC_NEXT, head + 1);
+            queue.set_descriptor(
+                head + 1,
+                address + DATA_OFFSET as u64,
+                len as u32,
+                data_flags,
+                head + 2,
+            );
+            queue.set_descriptor(head + 2, address + STATUS_OFFSET as u64, 1, DESC_WRITE, 0);
+            queue.submit(head);
+            self.transport.notify(&queue);
+        }
+
+        Completion {
+            device: self,
+            index,
+            memory: &mut *memory,
+            given: Some(given),
+        }
+        .await;
+
+        let status: u8 = memory
+            .as_ref()
+            .expect("request not done")
+            .read(STATUS_OFFSET);
+        match status {
+            STATUS_OK => Ok(()),
+            STATUS_UNSUPPORTED => Err(BlockError::Unsupported),
+            _ => Err(BlockError::Io),
+        }
+    }
+
+    /// Takes the requests the device is done with off the used ring, and wakes the tasks waiting for
+    /// them.
+    fn collect_completions(&self) {
+        let mut queue = self.queue.lock();
+        while let Some(used) = queue.pop_used() {
+            if let Some(slot) = self
+                .slots
+                .get(usize::from(used.head) / DESCRIPTORS_PER_REQUEST)
+            {
+                slot.done.store(true, Ordering::Release);
+                slot.waker.wake();
+            }
+        }
+    }
+
+    fn on_interrupt(&self) {
+        if self.transport.ack_interrupt() {
+            for slot in &self.slots {
+                slot.waker.wake();
+            }
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.capacity
+    }
+
+    async fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        storage::check_range(start, buffer.len(), SECTOR_SIZE, self.capacity)?;
+
+        let mut sector = start;
+        for chunk in buffer.chunks_mut(CHUNK_SIZE) {
+            let (index, mut memory) = self.lock_slot().await;
+            self.transfer(index, &mut memory, REQUEST_IN, sector, chunk.len())
+                .await?;
+            chunk.copy_from_slice(&data(&mut memory)[..chunk.len()]);
+            sector += (chunk.len() / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    async fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        storage::check_range(start, buffer.len(), SECTOR_SIZE, self.capacity)?;
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+
+        let mut sector = start;
+        for chunk in buffer.chunks(CHUNK_SIZE) {
+            let (index, mut memory) = self.lock_slot().await;
+            data(&mut memory)[..chunk.len()].copy_from_slice(chunk);
+            self.transfer(index, &mut memory, REQUEST_OUT, sector, chunk.len())
+                .await?;
+            sector += (chunk.len() / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Waits until the device is done with the request in the slot `index`, then gives its memory back.
+struct Completion<'a> {
+    device: &'a VirtioBlk,
+    index: usize,
+    /// Where the memory goes back to.
+    memory: &'a mut Option<DmaBuffer>,
+    /// Only `None` once the request is done.
+    given: Option<DeviceBuffer>,
+}
+
+impl Completion<'_> {
+    fn finish(&mut self) {
+        if let Some(given) = self.given.take() {
+            // SAFETY: the device put the request on the used ring, so it is done with its memory.
+            *self.memory = Some(unsafe { given.reclaim() });
+        }
+    }
+}
+
+impl Future for Completion<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let slot = &self.device.slots[self.index];
+
+        self.device.collect_completions();
+        if !slot.done.load(Ordering::Acquire) {
+            slot.waker.register(cx.waker());
+            // The interrupt may have come in between.
+            self.device.collect_completions();
+            if !slot.done.load(Ordering::Acquire) {
+                if !self.device.interrupts {
+                    cx.waker().wake_by_ref();
+                }
+                return Poll::Pending;
+            }
+        }
+
+        self.finish();
+        Poll::Ready(())
+    }
+}
+
+impl Drop for Completion<'_> {
+    /// Waits for a request whose task was dropped, so that its slot isn't reused while the device still
+    /// writes to it.
+    fn drop(&mut self) {
+        while self.given.is_some() && !self.device.slots[self.index].done.load(Ordering::Acquire) {
+            self.device.collect_completions();
+            core::hint::spin_loop();
+        }
+        self.finish();
+    }
+}
+
+fn interrupt_handler() {
+    if let Some(device) = DEVICE.get() {
+        device.on_interrupt();
+    }
+}
+
+pub static DRIVER: Driver = Driver {
+    name: "virtio-blk",
+    priority: Priority::Normal,
+    bus: Bus::Pci {
+        matches: &[PciMatch::Id {
+            vendor_id: VENDOR_ID,
+            device_ids: &[DEVICE_ID_TRANSITIONAL, DEVICE_ID_MODERN],
+        }],
+        probe,
+    },
+};
+
+fn probe(device: &pci::Device, claims: &mut Claims) -> Result<(), ProbeError> {
+    let disk = init(device, claims).map_err(ProbeError::new)?;
+    println!(
+        "virtio-blk: {} ({}), {} sectors{}",
+        disk.address(),
+        if disk.is_modern() { "modern" } else { "legacy" },
+        disk.num_blocks(),
+        if disk.is_read_only() {
+            ", read-only"
+        } else {
+            ""
+        },
+    );
+    Ok(())
+}
+
+/// Sets up `device`, the first virtio-blk device only, and returns it.
+fn init(device: &pci::Device, claims: &mut Claims) -> Result<&'static VirtioBlk, VirtioError> {
+    INIT.begin()?;
+
+    claims.claim_bars(device)?;
+    let mut blk = VirtioBlk::new(device)?;
+
+    // The line stays masked until the device is in place for the handler. A line already taken by
+    // another device is left alone, and requests are polled instead.
+    let irq = device.interrupt_line;
+    blk.interrupts =
+        claims.claim_irq(irq) && interrupts::set_irq_handler(irq, interrupt_handler).is_ok();
+    let blk = DEVICE.get_or_init(|| blk);
+    if blk.interrupts {
+        interrupts::unmask_irq(irq);
+    }
+
+    Ok(blk)
+}
+
+/// The device `DRIVER` set up.
+pub fn device() -> Option<&'static VirtioBlk> {
+    DEVICE.get()
+}
@@ -0,0 +1,581 @@
+//! virtio-gpu: a display provided by the hypervisor, e.g. QEMU's `-device virtio-gpu`.
+//!
+//! The device shows resources, images it keeps on the host, on its scanouts, one per display. A 2D
+//! resource is backed by guest memory the driver draws into; the device copies a rectangle of it to the
+//! host when sent a transfer command, and shows it once sent a flush for it. So any resolution works,
+//! and only what changed is copied, without the slow framebuffer memory of emulated VGA.
+//!
+//! `set_resolution` makes a resource of the size asked for, shows it on scanout 0 and hands its backing
+//! to the framebuffer with `framebuffer::switch_framebuffer`, which then tells the device about each
+//! rectangle it presents. Commands go through the control queue one at a time, each a request and a
+//! response in a page of their own, and are waited for by polling: they come from the framebuffer's
+//! flushes, which run with interrupts disabled.
+
+use alloc::vec::Vec;
+use bootloader_api::info::{FrameBuffer, FrameBufferInfo, PixelFormat};
+use conquer_once::spin::OnceCell;
+use core::{
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::{Transport, VENDOR_ID, VirtQueue, VirtioError, queue::DESC_NEXT, queue::DESC_WRITE};
+use crate::{
+    dma::{DmaBuffer, DmaError, PAGE_SIZE},
+    driver::{Bus, Claims, Driver, PciMatch, Priority, ProbeError},
+    framebuffer::{self, SwitchError, gfx::Rect},
+    init_state::InitState,
+    pci, println,
+};
+
+const DEVICE_ID: u16 = 0x1050;
+
+/// Offset of the number of scanouts in the device's configuration.
+const CONFIG_NUM_SCANOUTS: u16 = 8;
+const MAX_SCANOUTS: usize = 16;
+
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_RESOURCE_UNREF: u32 = 0x0102;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+/// Blue, green, red and an unused byte, which the framebuffer calls `PixelFormat::Bgr`.
+const FORMAT_B8G8R8X8: u32 = 2;
+const BYTES_PER_PIXEL: usize = 4;
+/// The largest side of a mode, which keeps a screen within a compositor window's pixels.
+pub const MAX_SIDE: u32 = 4096;
+
+const QUEUE_SIZE: u16 = 8;
+/// Where the response is in the page of a command.
+const RESPONSE_OFFSET: usize = PAGE_SIZE / 2;
+
+static INIT: InitState = InitState::new("virtio-gpu");
+static DEVICE: OnceCell<VirtioGpu> = OnceCell::uninit();
+
+#[derive(Debug)]
+pub enum GpuError {
+    /// `DRIVER` didn't set up a device.
+    NoDevice,
+    /// The device answered a command with this error response.
+    Rejected(u32),
+    /// The mode is empty or larger than `MAX_SIDE`.
+    InvalidMode,
+    OutOfMemory,
+    Framebuffer(SwitchError),
+}
+
+impl From<DmaError> for GpuError {
+    fn from(_: DmaError) -> Self {
+        GpuError::OutOfMemory
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct Header {
+    kind: u32,
+    flags: u32,
+    fence_id: u64,
+    context_id: u32,
+    padding: u32,
+}
+
+impl Header {
+    fn new(kind: u32) -> Header {
+        Header {
+            kind,
+            ..Header::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct GpuRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl From<Rect> for GpuRect {
+    fn from(rect: Rect) -> Self {
+        GpuRect {
+            x: rect.x as u32,
+            y: rect.y as u32,
+            width: rect.width as u32,
+            height: rect.height as u32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct DisplayOne {
+    rect: GpuRect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct DisplayInfo {
+    header: Header,
+    modes: [DisplayOne; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ResourceCreate2d {
+    header: Header,
+    resource: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ResourceCommand {
+    header: Header,
+    resource: u32,
+    padding: u32,
+}
+
+/// Attaches a single run of memory.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct AttachBacking {
+    header: Header,
+    resource: u32,
+    entries: u32,
+    address: u64,
+    length: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SetScanout {
+    header: Header,
+    rect: GpuRect,
+    scanout: u32,
+    resource: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TransferToHost2d {
+    header: Header,
+    rect: GpuRect,
+    offset: u64,
+    resource: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ResourceFlush {
+    header: Header,
+    rect: GpuRect,
+    resource: u32,
+    padding: u32,
+}
+
+/// A mode a scanout's display prefers, as the device reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayMode {
+    pub scanout: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A 2D resource, and the guest memory it is drawn in.
+#[derive(Debug)]
+struct Resource {
+    id: u32,
+    width: u32,
+    height: u32,
+    backing: DmaBuffer,
+}
+
+impl Resource {
+    fn len(&self) -> usize {
+        self.width as usize * self.height as usize * BYTES_PER_PIXEL
+    }
+
+    /// The backing as a framebuffer to draw on.
+    fn framebuffer(&self) -> FrameBuffer {
+        let info = FrameBufferInfo {
+            byte_len: self.len(),
+            width: self.width as usize,
+            height: self.height as usize,
+            pixel_format: PixelFormat::Bgr,
+            bytes_per_pixel: BYTES_PER_PIXEL,
+            stride: self.width as usize,
+        };
+        // SAFETY: the backing is only reached through the framebuffer until the resource is freed,
+        // which happens once the framebuffer was switched away from it. The device only reads it when
+        // sent a transfer, which the framebuffer sends for what it finished drawing.
+        unsafe { FrameBuffer::new(self.backing.virt_address().as_u64(), info) }
+    }
+}
+
+/// The control queue, and the page commands go through.
+struct Control {
+    queue: VirtQueue,
+    /// Only `None` while the device owns it, during a command.
+    page: Option<DmaBuffer>,
+}
+
+pub struct VirtioGpu {
+    address: pci::Address,
+    transport: Transport,
+    control: Mutex<Control>,
+    scanouts: u32,
+    /// The resource on scanout 0, once `set_resolution` put one there. Only locked with interrupts
+    /// disabled, like `control`.
+    shown: Mutex<Option<Resource>>,
+    next_resource: AtomicU32,
+}
+
+impl VirtioGpu {
+    fn new(device: &pci::Device) -> Result<Self, VirtioError> {
+        let transport = Transport::probe(device)?;
+        pci::enable(device.address);
+
+        transport.begin_init(0)?;
+        let result = Self::setup(device.address, transport);
+        match &result {
+            Ok(_) => transport.finish_init(),
+            Err(_) => transport.fail(),
+        }
+        result
+    }
+
+    fn setup(address: pci::Address, transport: Transport) -> Result<Self, VirtioError> {
+        let queue = transport.setup_queue(0, QUEUE_SIZE)?;
+        if queue.size() < 2 {
+            transport.reset();
+            return Err(VirtioError::QueueUnavailable(0));
+        }
+        let Ok(page) = DmaBuffer::new(PAGE_SIZE) else {
+            transport.reset();
+            return Err(VirtioError::OutOfMemory);
+        };
+
+        Ok(VirtioGpu {
+            address,
+            transport,
+            control: Mutex::new(Control {
+                queue,
+                page: Some(page),
+            }),
+            scanouts: transport.read_config(CONFIG_NUM_SCANOUTS),
+            shown: Mutex::new(None),
+            next_resource: AtomicU32::new(1),
+        })
+    }
+
+    pub fn address(&self) -> pci::Address {
+        self.address
+    }
+
+    pub fn is_modern(&self) -> bool {
+        self.transport.is_modern()
+    }
+
+    pub fn scanouts(&self) -> u32 {
+        self.scanouts
+    }
+
+    fn with_shown<R>(&self, f: impl FnOnce(&mut Option<Resource>) -> R) -> R {
+        without_interrupts(|| f(&mut self.shown.lock()))
+    }
+
+    /// Sends `request` and waits for the response, which must be of kind `expected`.
+    fn command<Request: Copy, Response: Copy>(
+        &self,
+        request: Request,
+        expected: u32,
+    ) -> Result<Response, GpuError> {
+        const {
+            assert!(size_of::<Request>() <= RESPONSE_OFFSET);
+            assert!(size_of::<Response>() <= PAGE_SIZE - RESPONSE_OFFSET);
+        }
+
+        // An interrupt handler printing would flush, and wait for this lock forever.
+        let response: Response = without_interrupts(|| {
+            let mut control = self.control.lock();
+            let mut page = control.page.take().expect("a command is already running");
+            page.write(0, request);
+            page.as_mut_slice()[RESPONSE_OFFSET..][..size_of::<Response>()].fill(0);
+
+            let page = page.give();
+            let request = page.bus_address();
+            let response = request + RESPONSE_OFFSET as u64;
+            control
+                .queue
+                .set_descriptor(0, request, size_of::<Request>() as u32, DESC_NEXT, 1);
+            control
+                .queue
+                .set_descriptor(1, response, size_of::<Response>() as u32, DESC_WRITE, 0);
+            control.queue.submit(0);
+            self.transport.notify(&control.queue);
+            while control.queue.pop_used().is_none() {
+                core::hint::spin_loop();
+            }
+
+            // SAFETY: the device returned the chain, so it is done with the page.
+            let page = unsafe { page.reclaim() };
+            let response = page.read(RESPONSE_OFFSET);
+            control.page = Some(page);
+            response
+        });
+
+        // Every response starts with a header.
+        let kind = unsafe { ptr::addr_of!(response).cast::<Header>().read().kind };
+        if kind != expected {
+            return Err(GpuError::Rejected(kind));
+        }
+        Ok(response)
+    }
+
+    /// The modes the displays on the scanouts prefer, for the scanouts that have one.
+    pub fn display_modes(&self) -> Result<Vec<DisplayMode>, GpuError> {
+        let info: DisplayInfo =
+            self.command(Header::new(CMD_GET_DISPLAY_INFO), RESP_OK_DISPLAY_INFO)?;
+        Ok(info
+            .modes
+            .iter()
+            .zip(0..self.scanouts.min(MAX_SCANOUTS as u32))
+            .filter(|(mode, _)| mode.enabled != 0)
+            .map(|(mode, scanout)| DisplayMode {
+                scanout,
+                width: mode.rect.width,
+                height: mode.rect.height,
+            })
+            .collect())
+    }
+
+    fn ok<Request: Copy>(&self, request: Request) -> Result<(), GpuError> {
+        self.command::<Request, Header>(request, RESP_OK_NODATA)
+            .map(|_| ())
+    }
+
+    fn create_resource(&self, width: u32, height: u32) -> Result<Resource, GpuError> {
+        let id = self.next_resource.fetch_add(1, Ordering::Relaxed);
+        let len = width as usize * height as usize * BYTES_PER_PIXEL;
+        let backing = DmaBuffer::new(len)?;
+        let address = backing.bus_address().as_u64();
+        let resource = Resource {
+            id,
+            width,
+            height,
+            backing,
+        };
+
+        let created = self.ok(ResourceCreate2d {
+            header: Header::new(CMD_RESOURCE_CREATE_2D),
+            resource: id,
+            format: FORMAT_B8G8R8X8,
+            width,
+            height,
+        });
+        created?;
+        let attached = self.ok(AttachBacking {
+            header: Header::new(CMD_RESOURCE_ATTACH_BACKING),
+            resource: id,
+            entries: 1,
+            address,
+            length: len as u32,
+            padding: 0,
+        });
+        if let Err(error) = attached {
+            self.destroy_resource(resource);
+            return Err(error);
+        }
+        Ok(resource)
+    }
+
+    /// Frees `resource`, which must not be shown or drawn on anymore.
+    fn destroy_resource(&self, resource: Resource) {
+        // Failures leave a resource on the host, which is all that can be done about them.
+        let _ = self.ok(ResourceCommand {
+            header: Header::new(CMD_RESOURCE_DETACH_BACKING),
+            resource: resource.id,
+            padding: 0,
+        });
+        let _ = self.ok(ResourceCommand {
+            header: Header::new(CMD_RESOURCE_UNREF),
+            resource: resource.id,
+            padding: 0,
+        });
+        drop(resource);
+    }
+
+    fn set_scanout(&self, resource: &Resource) -> Result<(), GpuError> {
+        self.ok(SetScanout {
+            header: Header::new(CMD_SET_SCANOUT),
+            rect: GpuRect {
+                x: 0,
+                y: 0,
+                width: resource.width,
+                height: resource.height,
+            },
+            scanout: 0,
+            resource: resource.id,
+        })
+    }
+
+    /// Shows a new `width` by `height` resource on scanout 0, and draws the screen on it from now on.
+    pub fn set_resolution(&self, width: u32, height: u32) -> Result<(), GpuError> {
+        if width == 0 || height == 0 || width > MAX_SIDE || height > MAX_SIDE {
+            return Err(GpuError::InvalidMode);
+        }
+        let resource = self.create_resource(width, height)?;
+        if let Err(error) = self.set_scanout(&resource) {
+            self.destroy_resource(resource);
+            return Err(error);
+        }
+
+        // Flushes already go to the new resource while the framebuffer switches to it.
+        let framebuffer = resource.framebuffer();
+        let previous = self.with_shown(|shown| shown.replace(resource));
+        if let Err(error) = framebuffer::switch_framebuffer(framebuffer, Some(flush_scanout)) {
+            let resource = self.with_shown(|shown| {
+                if let Some(previous) = &previous {
+                    let _ = self.set_scanout(previous);
+                }
+                core::mem::replace(shown, previous)
+            });
+            if let Some(resource) = resource {
+                self.destroy_resource(resource);
+            }
+            return Err(GpuError::Framebuffer(error));
+        }
+        if let Some(previous) = previous {
+            self.destroy_resource(previous);
+        }
+        Ok(())
+    }
+
+    /// The size of the resource shown, if `set_resolution` was called.
+    pub fn resolution(&self) -> Option<(u32, u32)> {
+        self.with_shown(|shown| {
+            shown
+                .as_ref()
+                .map(|resource| (resource.width, resource.height))
+        })
+    }
+
+    /// Copies `rect` of the shown resource's backing to the host, and shows it.
+    pub fn flush(&self, rect: Rect) -> Result<(), GpuError> {
+        self.with_shown(|shown| match shown {
+            Some(resource) => self.flush_resource(resource, rect),
+            None => Ok(()),
+        })
+    }
+
+    fn flush_resource(&self, resource: &Resource, rect: Rect) -> Result<(), GpuError> {
+        let rect = GpuRect::from(rect);
+        let offset = (u64::from(rect.y) * u64::from(resource.width) + u64::from(rect.x))
+            * BYTES_PER_PIXEL as u64;
+
+        self.ok(TransferToHost2d {
+            header: Header::new(CMD_TRANSFER_TO_HOST_2D),
+            rect,
+            offset,
+            resource: resource.id,
+            padding: 0,
+        })?;
+        self.ok(ResourceFlush {
+            header: Header::new(CMD_RESOURCE_FLUSH),
+            rect,
+            resource: resource.id,
+            padding: 0,
+        })
+    }
+}
+
+/// The framebuffer's `flush_scanout`.
+fn flush_scanout(rect: Rect) {
+    if let Some(gpu) = DEVICE.get() {
+        // Whatever went wrong shows on the screen, where there is nothing to report it on.
+        let _ = gpu.flush(rect);
+    }
+}
+
+pub static DRIVER: Driver = Driver {
+    name: "virtio-gpu",
+    priority: Priority::Normal,
+    bus: Bus::Pci {
+        matches: &[PciMatch::Id {
+            vendor_id: VENDOR_ID,
+            device_ids: &[DEVICE_ID],
+        }],
+        probe,
+    },
+};
+
+/// Sets up the device and switches to the preferred mode of the display on scanout 0, rather than
+/// whichever the bootloader picked. Failing to switch leaves the device bound, drawing on the
+/// bootloader's framebuffer.
+fn probe(device: &pci::Device, claims: &mut Claims) -> Result<(), ProbeError> {
+    let gpu = init(device, claims).map_err(ProbeError::new)?;
+    match gpu
+        .display_modes()
+        .map(|modes| modes.into_iter().find(|mode| mode.scanout == 0))
+    {
+        Ok(Some(mode)) => match gpu.set_resolution(mode.width, mode.height) {
+            Ok(()) => println!(
+                "virtio-gpu: {}, {}x{}",
+                gpu.address(),
+                mode.width,
+                mode.height
+            ),
+            Err(error) => println!("WARNING: virtio-gpu mode not set: {:?}", error),
+        },
+        Ok(None) => println!("virtio-gpu: {}, no display", gpu.address()),
+        Err(error) => println!("WARNING: virtio-gpu display not read: {:?}", error),
+    }
+    Ok(())
+}
+
+/// Sets up `device`, the first virtio-gpu device only, and returns it.
+fn init(device: &pci::Device, claims: &mut Claims) -> Result<&'static VirtioGpu, VirtioError> {
+    INIT.begin()?;
+
+    claims.claim_bars(device)?;
+    let gpu = VirtioGpu::new(device)?;
+    Ok(DEVICE.get_or_init(|| gpu))
+}
+
+/// The device `DRIVER` set up.
+pub fn device() -> Option<&'static VirtioGpu> {
+    DEVICE.get()
+}
+
+/// Switches the screen to `width` by `height`, on the virtio-gpu device `DRIVER` set up.
+pub fn set_resolution(width: u32, height: u32) -> Result<(), GpuError> {
+    device()
+        .ok_or(GpuError::NoDevice)?
+        .set_resolution(width, height)
+}
+
+#[test_case]
+fn test_command_layouts() {
+    assert_eq!(size_of::<Header>(), 24);
+    assert_eq!(size_of::<DisplayInfo>(), 24 + 16 * 24);
+    assert_eq!(size_of::<AttachBacking>(), 48);
+    assert_eq!(size_of::<TransferToHost2d>(), 56);
+    assert_eq!(size_of::<ResourceFlush>(), 48);
+}
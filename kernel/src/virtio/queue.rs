@@ -0,0 +1,194 @@
+//! Split virtqueues: the rings a driver and a virtio device exchange buffers through.
+//!
+//! A queue is three parts in physically contiguous memory, laid out as the legacy transport requires
+//! (modern devices accept the same layout). The descriptor table lists buffers, each with its physical
+//! address and length, chained by `DESC_NEXT`. The driver puts the first descriptor of a chain in the
+//! available ring, and the device puts it in the used ring once it is done with it.
+//!
+//! Which descriptors are free is up to the driver; `VirtQueue` only writes and reads the rings.
+
+use core::sync::atomic::{Ordering, fence};
+use x86_64::PhysAddr;
+
+use crate::dma::{DeviceBuffer, DmaBuffer};
+
+/// The descriptor continues in the one in its `next` field.
+pub const DESC_NEXT: u16 = 1;
+/// The device writes the buffer, instead of reading it.
+pub const DESC_WRITE: u16 = 2;
+
+const PAGE_SIZE: usize = 4096;
+const DESCRIPTOR_SIZE: usize = 16;
+
+/// Where the parts of a queue are, in bytes from the descriptor table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Layout {
+    available: usize,
+    used: usize,
+    len: usize,
+}
+
+/// The legacy layout of a queue of `size` descriptors: the available ring right after the descriptor
+/// table, and the used ring on the next page boundary.
+const fn layout(size: u16) -> Layout {
+    let size = size as usize;
+    let available = DESCRIPTOR_SIZE * size;
+    // Flags, index, a ring entry per descriptor, and the used event.
+    let used = (available + 6 + 2 * size).next_multiple_of(PAGE_SIZE);
+    Layout {
+        available,
+        used,
+        len: used + (6 + 8 * size).next_multiple_of(PAGE_SIZE),
+    }
+}
+
+/// A chain the device is done with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Used {
+    /// The first descriptor of the chain.
+    pub head: u16,
+    /// Bytes the device wrote to the chain.
+    pub len: u32,
+}
+
+#[derive(Debug)]
+pub struct VirtQueue {
+    index: u16,
+    notify_offset: u16,
+    size: u16,
+    /// The device's from the start, since it may look at the rings once told where they are. Only `None`
+    /// once the queue is dropped.
+    memory: Option<DeviceBuffer>,
+    layout: Layout,
+    /// The next index of the available ring, as the device will read it.
+    next_available: u16,
+    /// The index of the used ring seen last.
+    last_used: u16,
+}
+
+impl VirtQueue {
+    /// Allocates queue `index` with `size` descriptors, whose notifications go to `notify_offset`.
+    pub fn new(index: u16, size: u16, notify_offset: u16) -> Option<Self> {
+        let layout = layout(size);
+        let memory = DmaBuffer::new(layout.len).ok()?;
+
+        Some(VirtQueue {
+            index,
+            notify_offset,
+            size,
+            memory: Some(memory.give()),
+            layout,
+            next_available: 0,
+            last_used: 0,
+        })
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn notify_offset(&self) -> u16 {
+        self.notify_offset
+    }
+
+    /// The number of descriptors.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn descriptors_address(&self) -> PhysAddr {
+        self.memory().bus_address()
+    }
+
+    pub fn available_address(&self) -> PhysAddr {
+        self.descriptors_address() + self.layout.available as u64
+    }
+
+    pub fn used_address(&self) -> PhysAddr {
+        self.descriptors_address() + self.layout.used as u64
+    }
+
+    fn memory(&self) -> &DeviceBuffer {
+        self.memory.as_ref().expect("queue dropped")
+    }
+
+    /// Points descriptor `index` at `len` bytes at `address`, followed by descriptor `next` if `flags`
+    /// has `DESC_NEXT`.
+    pub fn set_descriptor(
+        &mut self,
+        index: u16,
+        address: PhysAddr,
+        len: u32,
+        flags: u16,
+        next: u16,
+    ) {
+        assert!(index < self.size && next < self.size);
+        let offset = usize::from(index) * DESCRIPTOR_SIZE;
+        let memory = self.memory();
+        memory.write(offset, address.as_u64());
+        memory.write(offset + 8, len);
+        memory.write(offset + 12, flags);
+        memory.write(offset + 14, next);
+    }
+
+    /// Hands the chain starting at descriptor `head` to the device. It only looks at it once notified.
+    pub fn submit(&mut self, head: u16) {
+        let slot = usize::from(self.next_available % self.size);
+        self.next_available = self.next_available.wrapping_add(1);
+        let memory = self.memory();
+        memory.write(self.layout.available + 4 + 2 * slot, head);
+        // The device must see the entry before the index that includes it.
+        fence(Ordering::Release);
+        memory.write(self.layout.available + 2, self.next_available);
+        fence(Ordering::SeqCst);
+    }
+
+    /// The next chain the device is done with, if any.
+    pub fn pop_used(&mut self) -> Option<Used> {
+        let index: u16 = self.memory().read(self.layout.used + 2);
+        if index == self.last_used {
+            return None;
+        }
+        // The entry is only read after the index that includes it.
+        fence(Ordering::Acquire);
+
+        let offset = self.layout.used + 4 + 8 * usize::from(self.last_used % self.size);
+        self.last_used = self.last_used.wrapping_add(1);
+        let head: u32 = self.memory().read(offset);
+        let len = self.memory().read(offset + 4);
+        Some(Used {
+            head: head as u16,
+            len,
+        })
+    }
+}
+
+impl Drop for VirtQueue {
+    /// Frees the queue's memory. The device must have been reset first, so that it no longer uses it.
+    fn drop(&mut self) {
+        if let Some(memory) = self.memory.take() {
+            drop(unsafe { memory.reclaim() });
+        }
+    }
+}
+
+#[test_case]
+fn test_legacy_layout() {
+    assert_eq!(
+        layout(256),
+        Layout {
+            available: 4096,
+            used: 8192,
+            len: 12288,
+        }
+    );
+    // Small enough for the descriptor table and the available ring to share a page.
+    assert_eq!(
+        layout(128),
+        Layout {
+            available: 2048,
+            used: 4096,
+            len: 8192,
+        }
+    );
+}
@@ -0,0 +1,164 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::panic::PanicInfo;
+use kernel::acpi::aml::{self, AmlError, Namespace, Object, Resource};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+/// Compiled from:
+///
+/// ```text
+/// Scope (\_SB) {
+///     Device (KBD) {
+///         Name (_HID, EisaId ("PNP0303"))
+///         Name (_CRS, ResourceTemplate () {
+///             IO (Decode16, 0x60, 0x60, 0x01, 0x01)
+///             IO (Decode16, 0x64, 0x64, 0x01, 0x01)
+///             IRQNoFlags () {1}
+///         })
+///         Method (_STA, 0) { Return (0x0F) }
+///     }
+///     Device (COM1) {
+///         Name (_HID, "PNP0501")
+///         Name (RBUF, ResourceTemplate () {
+///             IO (Decode16, 0x3F8, 0x3F8, 0x01, 0x08)
+///             IRQNoFlags () {4}
+///         })
+///         Method (_CRS, 0) { Return (RBUF) }
+///     }
+/// }
+/// Name (_S5, Package () { 5, 5, 0, 0 })
+/// ```
+static AML: [u8; 134] = [
+    0x10, 0x47, 0x07, 0x5C, 0x5F, 0x53, 0x42, 0x5F, 0x5B, 0x82, 0x37, 0x4B, 0x42, 0x44, 0x5F, 0x08,
+    0x5F, 0x48, 0x49, 0x44, 0x0C, 0x41, 0xD0, 0x03, 0x03, 0x08, 0x5F, 0x43, 0x52, 0x53, 0x11, 0x18,
+    0x0A, 0x15, 0x47, 0x01, 0x60, 0x00, 0x60, 0x00, 0x01, 0x01, 0x47, 0x01, 0x64, 0x00, 0x64, 0x00,
+    0x01, 0x01, 0x22, 0x02, 0x00, 0x79, 0x00, 0x14, 0x09, 0x5F, 0x53, 0x54, 0x41, 0x00, 0xA4, 0x0A,
+    0x0F, 0x5B, 0x82, 0x35, 0x43, 0x4F, 0x4D, 0x31, 0x08, 0x5F, 0x48, 0x49, 0x44, 0x0D, 0x50, 0x4E,
+    0x50, 0x30, 0x35, 0x30, 0x31, 0x00, 0x08, 0x52, 0x42, 0x55, 0x46, 0x11, 0x10, 0x0A, 0x0D, 0x47,
+    0x01, 0xF8, 0x03, 0xF8, 0x03, 0x01, 0x08, 0x22, 0x10, 0x00, 0x79, 0x00, 0x14, 0x0B, 0x5F, 0x43,
+    0x52, 0x53, 0x00, 0xA4, 0x52, 0x42, 0x55, 0x46, 0x08, 0x5F, 0x53, 0x35, 0x5F, 0x12, 0x08, 0x04,
+    0x0A, 0x05, 0x0A, 0x05, 0x00, 0x00,
+];
+
+fn namespace() -> Namespace {
+    let mut namespace = Namespace::new();
+    namespace.load(&AML).expect("failed to load AML");
+    namespace
+}
+
+#[test_case]
+fn devices_are_enumerated_with_ids_and_resources() {
+    let namespace = namespace();
+    let devices: Vec<_> = namespace.devices().collect();
+
+    assert_eq!(devices.len(), 2);
+
+    let keyboard = devices
+        .iter()
+        .find(|device| device.path == "\\_SB_.KBD_")
+        .unwrap();
+    assert_eq!(keyboard.hid.as_deref(), Some("PNP0303"));
+    assert_eq!(
+        keyboard.resources,
+        [
+            Resource::Io {
+                base: 0x60,
+                length: 1
+            },
+            Resource::Io {
+                base: 0x64,
+                length: 1
+            },
+            Resource::Irq(1),
+        ]
+    );
+
+    // Here `_CRS` is a method returning another named object.
+    let serial = devices
+        .iter()
+        .find(|device| device.path == "\\_SB_.COM1")
+        .unwrap();
+    assert_eq!(serial.hid.as_deref(), Some("PNP0501"));
+    assert_eq!(
+        serial.resources,
+        [
+            Resource::Io {
+                base: 0x3F8,
+                length: 8
+            },
+            Resource::Irq(4),
+        ]
+    );
+}
+
+#[test_case]
+fn simple_methods_are_evaluated() {
+    let namespace = namespace();
+    assert_eq!(
+        namespace.evaluate("\\_SB_.KBD_._STA"),
+        Ok(Object::Integer(0x0F))
+    );
+}
+
+#[test_case]
+fn sleep_types_come_from_the_sx_package() {
+    let namespace = namespace();
+    assert_eq!(namespace.sleep_types(5), Some((5, 5)));
+    assert_eq!(namespace.sleep_types(3), None);
+}
+
+#[test_case]
+fn packages_shorter_than_their_contents_are_rejected() {
+    // `Method (_STA)` with a PkgLength of 2, which ends inside its own name.
+    static METHOD: [u8; 7] = [0x14, 0x02, 0x5F, 0x53, 0x54, 0x41, 0x00];
+    // `Name (BUFF, Buffer (5) {})` with a PkgLength of 1, which ends before the size.
+    static BUFFER: [u8; 9] = [0x08, 0x42, 0x55, 0x46, 0x46, 0x11, 0x01, 0x0A, 0x05];
+
+    assert_eq!(Namespace::new().load(&METHOD), Err(AmlError::UnexpectedEnd));
+    assert_eq!(Namespace::new().load(&BUFFER), Err(AmlError::UnexpectedEnd));
+}
+
+#[test_case]
+fn eisa_ids_are_decoded() {
+    assert_eq!(aml::decode_eisa_id(0x0303D041), "PNP0303");
+}
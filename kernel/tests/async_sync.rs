@@ -0,0 +1,168 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, vec::Vec};
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::{cell::RefCell, panic::PanicInfo};
+use kernel::task::{
+    executor::Executor,
+    sync::{Mutex, RwLock},
+    yield_now,
+};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+#[test_case]
+fn mutex_guards_can_be_held_across_awaits() {
+    let mut executor = Executor::new();
+    let mutex = Rc::new(Mutex::new(Vec::new()));
+
+    for name in ["first", "second"] {
+        let mutex = mutex.clone();
+        executor.spawn(async move {
+            let mut log = mutex.lock().await;
+            log.push((name, "locked"));
+            // The other task runs meanwhile and waits for the lock.
+            yield_now().await;
+            log.push((name, "unlocked"));
+        });
+    }
+    executor.run_until_idle();
+
+    assert!(!mutex.is_locked());
+    assert_eq!(
+        *mutex.try_lock().unwrap(),
+        [
+            ("first", "locked"),
+            ("first", "unlocked"),
+            ("second", "locked"),
+            ("second", "unlocked"),
+        ]
+    );
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn try_lock_fails_while_locked() {
+    let mutex = Mutex::new(1);
+    let guard = mutex.try_lock().unwrap();
+
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+    assert_eq!(mutex.into_inner(), 1);
+}
+
+#[test_case]
+fn dropped_waiters_pass_the_lock_on() {
+    let mut executor = Executor::new();
+    let mutex = Rc::new(Mutex::new(0));
+    let guard = mutex.try_lock().unwrap();
+
+    let abandoned = executor.spawn({
+        let mutex = mutex.clone();
+        async move { *mutex.lock().await += 1 }
+    });
+    let waiting = mutex.clone();
+    executor.spawn(async move { *waiting.lock().await += 10 });
+    executor.run_until_idle();
+    assert_eq!(executor.task_count(), 2);
+
+    // The first waiter is woken by the release, but is aborted before it takes the lock.
+    abandoned.abort();
+    drop(guard);
+    executor.run_until_idle();
+
+    assert_eq!(*mutex.try_lock().unwrap(), 10);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn readers_share_the_lock_and_writers_wait_for_them() {
+    let mut executor = Executor::new();
+    let lock = Rc::new(RwLock::new(0));
+    let log = Rc::new(RefCell::new(Vec::new()));
+
+    for reader in 0..2 {
+        let lock = lock.clone();
+        let log = log.clone();
+        executor.spawn(async move {
+            let value = lock.read().await;
+            log.borrow_mut().push((reader, *value));
+            yield_now().await;
+            log.borrow_mut().push((reader, *value));
+        });
+    }
+    let writer = lock.clone();
+    executor.spawn(async move { *writer.write().await = 1 });
+    executor.run_until_idle();
+
+    // Both readers held the lock at once, and the write only happened after they let go.
+    assert_eq!(*log.borrow(), [(0, 0), (1, 0), (0, 0), (1, 0)]);
+    assert_eq!(lock.reader_count(), 0);
+    assert!(!lock.is_write_locked());
+    assert_eq!(*lock.try_read().unwrap(), 1);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn readers_wait_for_writers() {
+    let mut executor = Executor::new();
+    let lock = Rc::new(RwLock::new(0));
+    let guard = lock.try_write().unwrap();
+    let read = Rc::new(RefCell::new(Vec::new()));
+
+    for _ in 0..2 {
+        let lock = lock.clone();
+        let read = read.clone();
+        executor.spawn(async move {
+            let value = *lock.read().await;
+            read.borrow_mut().push(value);
+        });
+    }
+    executor.run_until_idle();
+    assert!(read.borrow().is_empty());
+    assert!(lock.try_read().is_none());
+
+    let mut guard = guard;
+    *guard = 5;
+    drop(guard);
+    executor.run_until_idle();
+
+    assert_eq!(*read.borrow(), [5, 5]);
+}
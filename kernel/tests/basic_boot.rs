@@ -4,15 +4,19 @@
 #![test_runner(kernel::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+use bootloader_api::{BootInfo, entry_point};
 use core::panic::PanicInfo;
 
-use kernel::println;
+use kernel::{framebuffer, println};
 
-#[unsafe(no_mangle)]
-pub extern "C" fn _start() -> ! {
-    test_main();
+entry_point!(main);
 
-    loop {}
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    if let Some(framebuffer) = boot_info.framebuffer.take() {
+        framebuffer::init(framebuffer).unwrap();
+    }
+    test_main();
+    kernel::hlt_loop();
 }
 
 #[panic_handler]
@@ -24,3 +28,31 @@ fn panic(info: &PanicInfo) -> ! {
 fn test_println() {
     println!("Hello, World!");
 }
+
+#[test_case]
+fn test_println_output() {
+    if !framebuffer::is_initialized() {
+        return;
+    }
+    let text = "Some test string that fits on a single line";
+    println!("{}", text);
+
+    // The cursor is on the line after it.
+    assert_eq!(
+        framebuffer::capture::text_line(1, |line| line == text),
+        Some(true)
+    );
+    framebuffer::capture::dump_text_region(0..4);
+}
+
+#[test_case]
+fn test_crc32_changes_with_output() {
+    if !framebuffer::is_initialized() {
+        return;
+    }
+    let before = framebuffer::capture::crc32();
+    println!("more output");
+
+    assert_ne!(framebuffer::capture::crc32(), before);
+    framebuffer::capture::dump_crc32();
+}
@@ -0,0 +1,174 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, vec::Vec};
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::{cell::RefCell, panic::PanicInfo};
+use futures_util::StreamExt;
+use kernel::{
+    queue::QueueId,
+    task::{
+        channel::{
+            mpsc::{self, SendError, TrySendError},
+            oneshot::{self, Canceled},
+        },
+        executor::Executor,
+        irq_stream::{IrqStream, PushError},
+    },
+};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+#[test_case]
+fn receiver_gets_values_in_order_until_senders_are_gone() {
+    let mut executor = Executor::new();
+    let (sender, mut receiver) = mpsc::channel(4);
+    let received = Rc::new(RefCell::new(Vec::new()));
+
+    let output = received.clone();
+    executor.spawn(async move {
+        while let Some(value) = receiver.recv().await {
+            output.borrow_mut().push(value);
+        }
+        output.borrow_mut().push(0);
+    });
+    executor.run_until_idle();
+
+    let other = sender.clone();
+    sender.try_send(1).unwrap();
+    other.try_send(2).unwrap();
+    executor.run_until_idle();
+    assert_eq!(*received.borrow(), [1, 2]);
+
+    drop(sender);
+    other.try_send(3).unwrap();
+    drop(other);
+    executor.run_until_idle();
+    assert_eq!(*received.borrow(), [1, 2, 3, 0]);
+}
+
+#[test_case]
+fn try_send_fails_when_full_or_closed() {
+    let (sender, mut receiver) = mpsc::channel(1);
+
+    assert_eq!(sender.try_send(1), Ok(()));
+    assert_eq!(sender.try_send(2), Err(TrySendError::Full(2)));
+    assert_eq!(receiver.try_recv(), Some(1));
+    assert_eq!(receiver.try_recv(), None);
+
+    drop(receiver);
+    assert_eq!(sender.try_send(3), Err(TrySendError::Closed(3)));
+}
+
+#[test_case]
+fn send_waits_for_room() {
+    let mut executor = Executor::new();
+    let (sender, mut receiver) = mpsc::channel(1);
+    let results = Rc::new(RefCell::new(Vec::new()));
+
+    let output = results.clone();
+    executor.spawn(async move {
+        for value in 1..=3 {
+            let result = sender.send(value).await;
+            output.borrow_mut().push(result);
+        }
+    });
+    executor.run_until_idle();
+    assert_eq!(results.borrow().len(), 1);
+
+    assert_eq!(receiver.try_recv(), Some(1));
+    executor.run_until_idle();
+    assert_eq!(results.borrow().len(), 2);
+
+    drop(receiver);
+    executor.run_until_idle();
+    assert_eq!(*results.borrow(), [Ok(()), Ok(()), Err(SendError(3))]);
+}
+
+#[test_case]
+fn oneshot_delivers_one_value_or_cancels() {
+    let mut executor = Executor::new();
+    let (sender, receiver) = oneshot::channel();
+    let (dropped, canceled) = oneshot::channel::<u32>();
+    let results = Rc::new(RefCell::new(Vec::new()));
+
+    let output = results.clone();
+    executor.spawn(async move {
+        let value = receiver.await;
+        output.borrow_mut().push(value);
+        let value = canceled.await;
+        output.borrow_mut().push(value);
+    });
+    executor.run_until_idle();
+    assert!(results.borrow().is_empty());
+
+    sender.send(7).unwrap();
+    drop(dropped);
+    executor.run_until_idle();
+    assert_eq!(*results.borrow(), [Ok(7), Err(Canceled)]);
+}
+
+static EVENTS: IrqStream<u32> = IrqStream::new("test events", QueueId::Scancodes);
+
+#[test_case]
+fn irq_streams_deliver_events_once_taken() {
+    assert_eq!(EVENTS.push(1), Err(PushError::NoConsumer(1)));
+    assert!(!EVENTS.is_taken());
+
+    let mut events = EVENTS.take().unwrap();
+    assert!(EVENTS.take().is_err());
+    assert_eq!(events.try_next(), None);
+
+    let mut executor = Executor::new();
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let output = received.clone();
+    executor.spawn(async move {
+        while let Some(event) = events.next().await {
+            output.borrow_mut().push(event);
+        }
+    });
+    executor.run_until_idle();
+
+    // Pushed like an interrupt handler would, between two runs of the executor.
+    EVENTS.push(2).unwrap();
+    EVENTS.push(3).unwrap();
+    executor.run_until_idle();
+
+    assert_eq!(*received.borrow(), [2, 3]);
+}
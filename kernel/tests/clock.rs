@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::panic::PanicInfo;
+use kernel::time;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::{acpi, memory};
+    use x86_64::{PhysAddr, VirtAddr};
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    unsafe { memory::init(phys_mem_offset) };
+    if let Some(rsdp_address) = boot_info.rsdp_addr.into_option() {
+        acpi::init(PhysAddr::new(rsdp_address)).expect("invalid ACPI tables");
+    }
+
+    // `time::init` is left to the tests, which watch the clock while it switches sources.
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+#[test_case]
+fn the_clock_never_goes_back_when_switching_sources() {
+    // Let the tick clock move off zero first.
+    let start = time::ticks();
+    while time::ticks() == start {
+        core::hint::spin_loop();
+    }
+
+    let before = time::monotonic_nanos();
+    time::init();
+    let mut last = time::monotonic_nanos();
+    assert!(last >= before, "went back from {} to {}", before, last);
+
+    for _ in 0..10_000 {
+        let now = time::monotonic_nanos();
+        assert!(now >= last, "went back from {} to {}", last, now);
+        last = now;
+    }
+}
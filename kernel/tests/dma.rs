@@ -0,0 +1,107 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::panic::PanicInfo;
+use kernel::{
+    dma::{DmaBuffer, DmaError, PAGE_SIZE},
+    memory,
+};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+#[test_case]
+fn buffers_are_zeroed_and_page_aligned() {
+    let buffer = DmaBuffer::new(PAGE_SIZE + 1).unwrap();
+    assert_eq!(buffer.len(), PAGE_SIZE + 1);
+    assert!(buffer.bus_address().is_aligned(PAGE_SIZE as u64));
+    assert!(buffer.as_slice().iter().all(|&byte| byte == 0));
+}
+
+#[test_case]
+fn large_alignments_are_honoured() {
+    let align = 16 * PAGE_SIZE;
+    let buffer = DmaBuffer::new_aligned(PAGE_SIZE, align).unwrap();
+    assert!(buffer.bus_address().is_aligned(align as u64));
+}
+
+#[test_case]
+fn invalid_requests_are_refused() {
+    assert_eq!(DmaBuffer::new(0).unwrap_err(), DmaError::Empty);
+    assert_eq!(
+        DmaBuffer::new_aligned(8, 3 * PAGE_SIZE).unwrap_err(),
+        DmaError::InvalidAlignment(3 * PAGE_SIZE)
+    );
+}
+
+#[test_case]
+fn the_cpu_sees_the_bus_address_through_the_physical_mapping() {
+    let mut buffer = DmaBuffer::new(64).unwrap();
+    assert_eq!(
+        buffer.virt_address(),
+        memory::phys_to_virt(buffer.bus_address())
+    );
+    buffer.write(3, 0x1234_5678u32);
+    assert_eq!(buffer.read::<u32>(3), 0x1234_5678);
+    assert_eq!(buffer.as_slice()[3], 0x78);
+}
+
+#[test_case]
+fn buffers_given_to_a_device_come_back_intact() {
+    let mut buffer = DmaBuffer::new(PAGE_SIZE).unwrap();
+    buffer.as_mut_slice()[0] = 0xaa;
+    let address = buffer.bus_address();
+
+    let device = buffer.give();
+    assert_eq!(device.bus_address(), address);
+    assert_eq!(device.read::<u8>(0), 0xaa);
+    device.write(8, 0xdead_beef_u64);
+
+    let buffer = unsafe { device.reclaim() };
+    assert_eq!(buffer.bus_address(), address);
+    assert_eq!(buffer.read::<u64>(8), 0xdead_beef);
+}
+
+#[test_case]
+fn dropped_buffers_free_their_frames() {
+    let before = memory::allocated_frames();
+    let buffer = DmaBuffer::new_aligned(3 * PAGE_SIZE, 8 * PAGE_SIZE).unwrap();
+    assert_eq!(memory::allocated_frames(), before + 3);
+    drop(buffer);
+    assert_eq!(memory::allocated_frames(), before);
+}
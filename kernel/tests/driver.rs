@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::panic::PanicInfo;
+use kernel::driver::{self, Bus, Claims, DeviceId, Driver, Priority, ProbeError, Resource};
+use spin::Mutex;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+/// The drivers probed, in order.
+static PROBED: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+/// Both claim the same port, which only the first probed gets.
+const PORT: Resource = Resource::Io {
+    port: 0x510,
+    len: 2,
+};
+
+static LEGACY: Driver = Driver {
+    name: "legacy",
+    priority: Priority::Normal,
+    bus: Bus::Platform {
+        probe: |claims| probe("legacy", claims),
+    },
+};
+
+static EMULATOR: Driver = Driver {
+    name: "emulator",
+    priority: Priority::Early,
+    bus: Bus::Platform {
+        probe: |claims| probe("emulator", claims),
+    },
+};
+
+fn probe(name: &'static str, claims: &mut Claims) -> Result<(), ProbeError> {
+    PROBED.lock().push(name);
+    claims.claim(PORT).map_err(ProbeError::new)
+}
+
+#[test_case]
+fn early_drivers_are_probed_first() {
+    driver::register(&LEGACY);
+    driver::register(&EMULATOR);
+
+    // No PCI or ACPI devices here, and the legacy driver finds its port taken.
+    assert_eq!(driver::probe_all(), 1);
+    assert_eq!(*PROBED.lock(), ["emulator", "legacy"]);
+
+    let bindings = driver::bindings();
+    assert_eq!(bindings.len(), 1);
+    assert_eq!(bindings[0].driver, "emulator");
+    assert_eq!(bindings[0].device, DeviceId::Platform("emulator"));
+    assert_eq!(bindings[0].resources, [PORT]);
+}
@@ -0,0 +1,676 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, sync::Arc};
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::{
+    cell::{Cell, RefCell},
+    future::Future,
+    panic::PanicInfo,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+use kernel::{
+    queue::{self, QueueId},
+    task::{
+        Priority, Task, blocking,
+        channel::mpsc,
+        executor::{Executor, JoinError, SpawnError, Spawner, TaskState, WakeReason},
+        spawn_blocking,
+        timer::{self, Elapsed},
+        watchdog, yield_now,
+    },
+    thread, time,
+};
+use x86_64::instructions::interrupts;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+/// Shared view of a `FakeFuture`, so tests can inspect and drive it after it was moved into a task.
+#[derive(Default)]
+struct Probe {
+    polls: Cell<usize>,
+    waker: RefCell<Option<Waker>>,
+    /// The future completes on the poll after this flag is set.
+    ready: Cell<bool>,
+    /// Number of times the future wakes itself from inside `poll` before returning `Pending`.
+    self_wakes: Cell<usize>,
+}
+
+impl Probe {
+    fn wake(&self) {
+        self.waker
+            .borrow()
+            .as_ref()
+            .expect("never polled")
+            .wake_by_ref();
+    }
+}
+
+/// A future that only makes progress when the test tells it to.
+struct FakeFuture {
+    probe: Rc<Probe>,
+}
+
+impl Future for FakeFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        let probe = &self.probe;
+        probe.polls.set(probe.polls.get() + 1);
+        *probe.waker.borrow_mut() = Some(context.waker().clone());
+
+        if probe.ready.get() {
+            return Poll::Ready(());
+        }
+
+        if probe.self_wakes.get() > 0 {
+            probe.self_wakes.set(probe.self_wakes.get() - 1);
+            context.waker().wake_by_ref();
+        }
+
+        Poll::Pending
+    }
+}
+
+fn spawn_fake(executor: &mut Executor) -> Rc<Probe> {
+    let probe = Rc::new(Probe::default());
+    executor.spawn(FakeFuture {
+        probe: probe.clone(),
+    });
+    probe
+}
+
+#[test_case]
+fn pending_task_is_not_polled_again_without_wake() {
+    let mut executor = Executor::new();
+    let probe = spawn_fake(&mut executor);
+
+    executor.run_until_idle();
+    executor.run_until_idle();
+
+    assert_eq!(probe.polls.get(), 1);
+    assert_eq!(executor.task_count(), 1);
+}
+
+#[test_case]
+fn task_states_follow_polls_and_wakes() {
+    let mut executor = Executor::new();
+    let probe = spawn_fake(&mut executor);
+    let state = |executor: &Executor| executor.tasks().next().unwrap().state;
+
+    assert_eq!(state(&executor), TaskState::Queued);
+
+    executor.run_until_idle();
+    assert_eq!(state(&executor), TaskState::Waiting);
+
+    probe.wake();
+    assert_eq!(state(&executor), TaskState::Queued);
+}
+
+#[test_case]
+fn wake_during_first_poll_is_not_lost() {
+    let mut executor = Executor::new();
+    let probe = Rc::new(Probe::default());
+    probe.self_wakes.set(1);
+    executor.spawn(FakeFuture {
+        probe: probe.clone(),
+    });
+
+    executor.run_until_idle();
+
+    assert_eq!(probe.polls.get(), 2);
+}
+
+#[test_case]
+fn multiple_wakes_collapse_into_one_poll() {
+    let mut executor = Executor::new();
+    let probe = spawn_fake(&mut executor);
+    executor.run_until_idle();
+
+    for _ in 0..10 {
+        probe.wake();
+    }
+    executor.run_until_idle();
+
+    assert_eq!(probe.polls.get(), 2);
+}
+
+#[test_case]
+fn wake_after_completion_is_ignored() {
+    let mut executor = Executor::new();
+    let probe = spawn_fake(&mut executor);
+    executor.run_until_idle();
+
+    probe.ready.set(true);
+    probe.wake();
+    executor.run_until_idle();
+    assert_eq!(probe.polls.get(), 2);
+    assert_eq!(executor.task_count(), 0);
+
+    // The waker outlives the task; waking it must neither panic nor poll anything.
+    probe.wake();
+    executor.run_until_idle();
+    assert_eq!(probe.polls.get(), 2);
+}
+
+#[test_case]
+fn repeated_wakes_do_not_saturate_the_queue() {
+    let mut executor = Executor::new();
+    let probe = spawn_fake(&mut executor);
+    executor.run_until_idle();
+
+    // Far more wakes than the queue capacity: they collapse, so the queue never overflows.
+    for _ in 0..1000 {
+        probe.wake();
+    }
+    executor.run_until_idle();
+
+    assert_eq!(probe.polls.get(), 2);
+}
+
+#[test_case]
+fn queue_accepts_tasks_up_to_capacity() {
+    let mut executor = Executor::new();
+    let probes: alloc::vec::Vec<_> = (0..100).map(|_| spawn_fake(&mut executor)).collect();
+
+    executor.run_until_idle();
+
+    assert!(probes.iter().all(|probe| probe.polls.get() == 1));
+    assert_eq!(executor.task_count(), 100);
+}
+
+#[test_case]
+fn spawner_adds_tasks_to_its_executor() {
+    let mut executor = Executor::new();
+    let runs = Arc::new(AtomicUsize::new(0));
+
+    let spawner = executor.spawner();
+    for spawner in [spawner.clone(), spawner] {
+        let runs = runs.clone();
+        spawner.spawn(async move {
+            runs.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    assert_eq!(runs.load(Ordering::Relaxed), 0);
+
+    executor.run_until_idle();
+
+    assert_eq!(runs.load(Ordering::Relaxed), 2);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn running_tasks_can_spawn_more() {
+    async fn spawn_chain(spawner: Spawner, runs: Arc<AtomicUsize>, left: usize) {
+        runs.fetch_add(1, Ordering::Relaxed);
+        if left > 0 {
+            spawner
+                .clone()
+                .spawn(spawn_chain_boxed(spawner, runs, left - 1));
+        }
+    }
+
+    // Recursive async functions need their future boxed.
+    fn spawn_chain_boxed(
+        spawner: Spawner,
+        runs: Arc<AtomicUsize>,
+        left: usize,
+    ) -> Pin<alloc::boxed::Box<dyn Future<Output = ()> + Send>> {
+        alloc::boxed::Box::pin(spawn_chain(spawner, runs, left))
+    }
+
+    let mut executor = Executor::new();
+    let runs = Arc::new(AtomicUsize::new(0));
+    executor
+        .spawner()
+        .spawn(spawn_chain_boxed(executor.spawner(), runs.clone(), 3));
+
+    // Spawned tasks run in the same pass as the task that spawned them.
+    executor.run_until_idle();
+
+    assert_eq!(runs.load(Ordering::Relaxed), 4);
+}
+
+#[test_case]
+fn join_handles_resolve_to_task_output() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let answer = executor.spawn(async { 6 * 7 });
+    let total = Rc::new(Cell::new(0));
+
+    let result = total.clone();
+    executor.spawn(async move {
+        let doubled = spawner.spawn(async { 21 * 2 }).await;
+        result.set(answer.await.unwrap() + doubled.unwrap());
+    });
+    executor.run_until_idle();
+
+    assert_eq!(total.get(), 84);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn join_handles_fail_if_the_task_is_dropped() {
+    let mut executor = Executor::new();
+    let probe = Rc::new(Probe::default());
+    let mut handle = executor.spawn(FakeFuture {
+        probe: probe.clone(),
+    });
+    executor.run_until_idle();
+    drop(executor);
+
+    let waker = probe.waker.borrow().clone().unwrap();
+    let mut context = Context::from_waker(&waker);
+    assert_eq!(
+        Pin::new(&mut handle).poll(&mut context),
+        Poll::Ready(Err(JoinError))
+    );
+}
+
+#[test_case]
+fn wakes_that_find_the_queue_full_are_not_lost() {
+    queue::set_capacity(QueueId::Tasks, 1).unwrap();
+    let mut executor = Executor::new();
+    queue::set_capacity(QueueId::Tasks, 100).unwrap();
+
+    // With interrupts disabled the queue can't grow, like when an interrupt handler wakes tasks.
+    let probes: alloc::vec::Vec<_> =
+        interrupts::without_interrupts(|| (0..3).map(|_| spawn_fake(&mut executor)).collect());
+    executor.run_until_idle();
+
+    assert!(probes.iter().all(|probe| probe.polls.get() == 1));
+}
+
+/// Runs `executor`, halting between passes so timer ticks come in, until `done` returns true.
+fn run_until(executor: &mut Executor, done: impl Fn() -> bool) {
+    while !done() {
+        executor.run_until_idle();
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn sleeps_complete_after_their_deadline() {
+    let mut executor = Executor::new();
+    executor.spawn(timer::run_timers());
+
+    let sleep = timer::sleep(core::time::Duration::from_millis(30));
+    let deadline = sleep.deadline();
+    let woken_at = Rc::new(Cell::new(None));
+    let result = woken_at.clone();
+    executor.spawn(async move {
+        sleep.await;
+        result.set(Some(time::ticks()));
+    });
+
+    run_until(&mut executor, || woken_at.get().is_some());
+
+    assert!(woken_at.get().unwrap() >= deadline);
+}
+
+#[test_case]
+fn timeouts_give_up_on_slow_futures() {
+    let mut executor = Executor::new();
+    executor.spawn(timer::run_timers());
+
+    let results = Rc::new(RefCell::new(alloc::vec::Vec::new()));
+    let output = results.clone();
+    executor.spawn(async move {
+        let short = core::time::Duration::from_millis(20);
+        let slow = timer::timeout(short, core::future::pending::<()>()).await;
+        let fast = timer::timeout(short, async { 1 }).await;
+        output.borrow_mut().push(slow.map(|()| 0));
+        output.borrow_mut().push(fast);
+    });
+
+    run_until(&mut executor, || results.borrow().len() == 2);
+
+    assert_eq!(*results.borrow(), [Err(Elapsed), Ok(1)]);
+}
+
+#[test_case]
+fn dropped_sleeps_leave_the_wheel() {
+    let mut executor = Executor::new();
+    let before = timer::pending_count();
+
+    // The sleep is on the wheel once the future has yielded, and dropped when it completes.
+    executor.spawn(async {
+        let long = core::time::Duration::from_secs(3600);
+        let _ = timer::timeout(long, yield_now()).await;
+    });
+    executor.run_until_idle();
+
+    assert_eq!(timer::pending_count(), before);
+}
+
+#[test_case]
+fn intervals_tick_at_later_and_later_ticks() {
+    let mut executor = Executor::new();
+    executor.spawn(timer::run_timers());
+
+    let ticks = Rc::new(RefCell::new(alloc::vec::Vec::new()));
+    let output = ticks.clone();
+    executor.spawn(async move {
+        let mut interval = timer::interval(core::time::Duration::from_millis(10));
+        for _ in 0..3 {
+            interval.tick().await;
+            output.borrow_mut().push(time::ticks());
+        }
+    });
+
+    run_until(&mut executor, || ticks.borrow().len() == 3);
+
+    let ticks = ticks.borrow();
+    assert!(ticks.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[test_case]
+fn high_priority_tasks_run_first() {
+    let mut executor = Executor::new();
+    let order = Rc::new(RefCell::new(alloc::vec::Vec::new()));
+
+    for (name, priority) in [
+        ("bulk", Priority::Normal),
+        ("input", Priority::High),
+        ("more bulk", Priority::Normal),
+    ] {
+        let order = order.clone();
+        executor.spawn_with_priority(async move { order.borrow_mut().push(name) }, priority);
+    }
+    executor
+        .spawner()
+        .spawn_with_priority(async {}, Priority::High);
+
+    let priorities: alloc::vec::Vec<_> = executor.tasks().map(|task| task.priority).collect();
+    assert_eq!(
+        priorities,
+        [Priority::Normal, Priority::High, Priority::Normal]
+    );
+
+    executor.run_until_idle();
+
+    assert_eq!(*order.borrow(), ["input", "bulk", "more bulk"]);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn aborted_tasks_are_dropped_without_another_poll() {
+    let mut executor = Executor::new();
+    let probe = Rc::new(Probe::default());
+    let mut handle = executor.spawn(FakeFuture {
+        probe: probe.clone(),
+    });
+    let never_polled = executor.spawn(FakeFuture {
+        probe: Rc::new(Probe::default()),
+    });
+    never_polled.abort();
+    executor.run_until_idle();
+    assert_eq!(probe.polls.get(), 1);
+    assert_eq!(executor.task_count(), 1);
+
+    let abort = handle.abort_handle();
+    abort.abort();
+    assert!(abort.is_aborted());
+    executor.run_until_idle();
+
+    assert_eq!(probe.polls.get(), 1);
+    assert_eq!(executor.task_count(), 0);
+    // The future was dropped along with the task.
+    assert_eq!(Rc::strong_count(&probe), 1);
+
+    let waker = probe.waker.borrow().clone().unwrap();
+    let mut context = Context::from_waker(&waker);
+    assert_eq!(
+        Pin::new(&mut handle).poll(&mut context),
+        Poll::Ready(Err(JoinError))
+    );
+}
+
+#[test_case]
+fn panicking_tasks_are_removed_and_fail_their_join_handle() {
+    let mut executor = Executor::new();
+    let failed = executor.spawn(async {
+        panic!("task gave up");
+    });
+    let result = Rc::new(Cell::new(None));
+    let ran = Rc::new(Cell::new(false));
+
+    let joined = result.clone();
+    executor.spawn(async move { joined.set(Some(failed.await)) });
+    let other = ran.clone();
+    executor.spawn(async move { other.set(true) });
+    executor.run_until_idle();
+
+    assert_eq!(result.get(), Some(Err(JoinError)));
+    assert!(ran.get());
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn yielding_tasks_let_others_run() {
+    let mut executor = Executor::new();
+    let order = Rc::new(RefCell::new(alloc::vec::Vec::new()));
+
+    for name in ["first", "second"] {
+        let order = order.clone();
+        executor.spawn(async move {
+            for step in 0..2 {
+                order.borrow_mut().push((name, step));
+                yield_now().await;
+            }
+        });
+    }
+    executor.run_until_idle();
+
+    assert_eq!(
+        *order.borrow(),
+        [("first", 0), ("second", 0), ("first", 1), ("second", 1)]
+    );
+}
+
+#[test_case]
+fn busy_receivers_yield_once_their_budget_is_spent() {
+    let mut executor = Executor::new();
+    executor.set_budget(Some(4));
+    let (sender, mut receiver) = mpsc::channel(16);
+    for value in 0..8 {
+        sender.try_send(value).unwrap();
+    }
+    drop(sender);
+
+    let log = Rc::new(RefCell::new(alloc::vec::Vec::new()));
+    let received = log.clone();
+    executor.spawn(async move {
+        while let Some(value) = receiver.recv().await {
+            received.borrow_mut().push(value);
+        }
+    });
+    // Logs -1 whenever the receiving task lets it run.
+    let other = log.clone();
+    executor.spawn(async move {
+        for _ in 0..3 {
+            other.borrow_mut().push(-1);
+            yield_now().await;
+        }
+    });
+    executor.run_until_idle();
+
+    assert_eq!(*log.borrow(), [0, 1, 2, 3, -1, 4, 5, 6, 7, -1, -1]);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn blocking_jobs_run_on_worker_threads() {
+    let mut executor = Executor::new();
+    let results = Rc::new(RefCell::new(alloc::vec::Vec::new()));
+
+    for value in 0..3 {
+        let results = results.clone();
+        executor.spawn(async move {
+            let result = spawn_blocking(move || {
+                // Other jobs and the executor get the CPU meanwhile.
+                thread::yield_now();
+                (value * 2, thread::current())
+            })
+            .await;
+            results.borrow_mut().push(result);
+        });
+    }
+
+    while results.borrow().len() < 3 {
+        executor.run_until_idle();
+        thread::yield_now();
+    }
+
+    let mut values: alloc::vec::Vec<_> = results.borrow().iter().map(|&(value, _)| value).collect();
+    values.sort();
+    assert_eq!(values, [0, 2, 4]);
+    assert!(
+        results
+            .borrow()
+            .iter()
+            .all(|&(_, worker)| worker != thread::ThreadId::BOOT)
+    );
+    assert!(blocking::worker_count() <= blocking::MAX_WORKERS);
+}
+
+#[test_case]
+fn stats_count_polls_and_record_wake_reasons() {
+    let mut executor = Executor::new();
+    executor.spawn_task(Task::new_named("yielder", async {
+        yield_now().await;
+        core::future::pending::<()>().await;
+    }));
+    let probe = spawn_fake(&mut executor);
+
+    let stats: alloc::vec::Vec<_> = executor.stats().collect();
+    assert_eq!(stats[0].name, Some("yielder"));
+    assert_eq!(stats[1].name, None);
+    assert!(stats.iter().all(|stats| stats.polls == 0));
+    assert!(
+        stats
+            .iter()
+            .all(|stats| stats.last_wake == Some(WakeReason::Spawned))
+    );
+
+    executor.run_until_idle();
+    probe.waker.borrow().as_ref().unwrap().wake_by_ref();
+
+    let stats: alloc::vec::Vec<_> = executor.stats().collect();
+    assert_eq!(stats[0].polls, 2);
+    assert_eq!(stats[0].last_wake, Some(WakeReason::Itself));
+    assert!(stats[0].poll_cycles > 0);
+    assert_eq!(stats[1].polls, 1);
+    assert_eq!(stats[1].last_wake, Some(WakeReason::Other));
+}
+
+#[test_case]
+fn spawning_fails_once_the_task_limit_is_reached() {
+    let mut executor = Executor::new();
+    executor.set_task_limit(Some(2));
+    let spawner = executor.spawner();
+
+    let first = executor.try_spawn(async { 1 });
+    let second = spawner.try_spawn(async { 2 });
+    assert!(first.is_ok() && second.is_ok());
+    assert_eq!(
+        executor.try_spawn(async {}).err(),
+        Some(SpawnError::TooManyTasks)
+    );
+    assert_eq!(
+        spawner.try_spawn(async {}).err(),
+        Some(SpawnError::TooManyTasks)
+    );
+
+    // Completed tasks free their slots.
+    executor.run_until_idle();
+    assert_eq!(executor.task_count(), 0);
+    assert!(executor.try_spawn(async {}).is_ok());
+    assert!(spawner.try_spawn(async {}).is_ok());
+}
+
+#[test_case]
+fn watchdog_reports_each_stall_once() {
+    let timeout = core::time::Duration::from_millis(1);
+    watchdog::watch("stalling", timeout);
+    assert!(watchdog::check().is_empty());
+
+    let start = time::monotonic_nanos();
+    while time::monotonic_nanos() - start <= 2 * timeout.as_nanos() as u64 {
+        x86_64::instructions::hlt();
+    }
+
+    let stalls = watchdog::check();
+    assert_eq!(stalls.len(), 1);
+    assert_eq!(stalls[0].name, "stalling");
+    assert!(stalls[0].stalled_for > timeout);
+    assert!(watchdog::check().is_empty());
+
+    watchdog::feed("stalling");
+    assert!(watchdog::check().is_empty());
+    watchdog::unwatch("stalling");
+}
+
+#[test_case]
+fn idle_workers_steal_queued_tasks() {
+    let mut executor = Executor::with_workers(2);
+    let steps = Rc::new(Cell::new(0));
+
+    let busy = steps.clone();
+    executor.spawn(async move {
+        for _ in 0..4 {
+            busy.set(busy.get() + 1);
+            yield_now().await;
+        }
+    });
+    // Done after one poll, which leaves its worker idle while the other has the busy task queued.
+    executor.spawn(async {});
+    executor.run_until_idle();
+
+    assert_eq!(steps.get(), 4);
+    let (polls, steals) = executor
+        .worker_stats()
+        .fold((0, 0), |(polls, steals), stats| {
+            (polls + stats.polls, steals + stats.steals)
+        });
+    assert_eq!(polls, 6);
+    assert!(steals > 0);
+}
@@ -0,0 +1,336 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::panic::PanicInfo;
+use kernel::{
+    fs::{self, FileSystem, FileType, FsError, NodeId, ext2::Ext2},
+    storage::RamDisk,
+    task::block_on,
+};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+const BLOCK_SIZE: usize = 1024;
+const BLOCKS: usize = 32;
+const INODE_TABLE: usize = 5;
+const INODE_SIZE: usize = 128;
+
+const ROOT: u32 = 2;
+const HELLO: u32 = 11;
+const BIN: u32 = 12;
+const INIT: u32 = 13;
+const SPARSE: u32 = 14;
+const LINK: u32 = 15;
+
+const HELLO_TEXT: &[u8] = b"Hello from ext2!\n";
+const INIT_SIZE: usize = 3000;
+/// The blocks of the sparse file that aren't holes: one direct, then one under each indirect block.
+const SPARSE_BLOCKS: [u64; 4] = [0, 12 + 3, 12 + 256 + 2, 12 + 256 + 65536 + 1];
+/// It ends 10 bytes before the end of its last block.
+const SPARSE_SIZE: u64 = (12 + 256 + 65536 + 2) * BLOCK_SIZE as u64 - 10;
+
+fn init_byte(i: usize) -> u8 {
+    (i % 251) as u8
+}
+
+/// A volume being written.
+struct Image(Vec<u8>);
+
+impl Image {
+    fn put(&mut self, block: usize, offset: usize, bytes: &[u8]) {
+        let start = block * BLOCK_SIZE + offset;
+        self.0[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn inode(&mut self, number: u32, mode: u16, size: u32, blocks: &[u32]) {
+        let offset = (number as usize - 1) * INODE_SIZE;
+        let (block, offset) = (INODE_TABLE + offset / BLOCK_SIZE, offset % BLOCK_SIZE);
+        self.put(block, offset, &mode.to_le_bytes());
+        self.put(block, offset + 4, &size.to_le_bytes());
+        for (i, number) in blocks.iter().enumerate() {
+            self.put(block, offset + 40 + 4 * i, &number.to_le_bytes());
+        }
+    }
+
+    /// Fills `block` with directory entries, the last one taking the rest of it.
+    fn directory(&mut self, block: usize, entries: &[(u32, &str, u8)]) {
+        let mut offset = 0;
+        for (i, &(inode, name, kind)) in entries.iter().enumerate() {
+            let len = if i + 1 == entries.len() {
+                BLOCK_SIZE - offset
+            } else {
+                (8 + name.len()).next_multiple_of(4)
+            };
+            self.put(block, offset, &inode.to_le_bytes());
+            self.put(block, offset + 4, &(len as u16).to_le_bytes());
+            self.put(block, offset + 6, &[name.len() as u8, kind]);
+            self.put(block, offset + 8, name.as_bytes());
+            offset += len;
+        }
+    }
+}
+
+/// A volume of 32 KiB, laid out like `mkfs.ext2 -b 1024` does with a single group, holding:
+///
+/// ```text
+/// /hello.txt
+/// /bin/init    3 blocks
+/// /sparse      a block under each level of indirection, and holes between
+/// /link        a symbolic link to bin/init
+/// ```
+fn image(feature_incompat: u32) -> RamDisk {
+    let mut image = Image(vec![0; BLOCKS * BLOCK_SIZE]);
+
+    // The superblock, then the group descriptor with its bitmaps and inode table.
+    image.put(1, 0, &32u32.to_le_bytes());
+    image.put(1, 4, &(BLOCKS as u32).to_le_bytes());
+    image.put(1, 20, &1u32.to_le_bytes());
+    image.put(1, 32, &8192u32.to_le_bytes());
+    image.put(1, 40, &32u32.to_le_bytes());
+    image.put(1, 56, &0xef53u16.to_le_bytes());
+    image.put(1, 76, &1u32.to_le_bytes());
+    image.put(1, 84, &11u32.to_le_bytes());
+    image.put(1, 88, &(INODE_SIZE as u16).to_le_bytes());
+    image.put(1, 96, &feature_incompat.to_le_bytes());
+    image.put(2, 0, &3u32.to_le_bytes());
+    image.put(2, 4, &4u32.to_le_bytes());
+    image.put(2, 8, &(INODE_TABLE as u32).to_le_bytes());
+
+    image.inode(ROOT, 0x41ed, BLOCK_SIZE as u32, &[9]);
+    image.inode(HELLO, 0x81a4, HELLO_TEXT.len() as u32, &[11]);
+    image.inode(BIN, 0x41ed, BLOCK_SIZE as u32, &[10]);
+    image.inode(INIT, 0x81ed, INIT_SIZE as u32, &[12, 13, 14]);
+    let mut sparse = [0; 15];
+    sparse[0] = 15;
+    sparse[12] = 16;
+    sparse[13] = 18;
+    sparse[14] = 21;
+    image.inode(SPARSE, 0x81a4, SPARSE_SIZE as u32, &sparse);
+    // A fast symbolic link, with its target where the block numbers would be.
+    let target = [*b"bin/", *b"init"].map(u32::from_le_bytes);
+    image.inode(LINK, 0xa1ff, 8, &target);
+
+    image.directory(
+        9,
+        &[
+            (ROOT, ".", 2),
+            (ROOT, "..", 2),
+            (HELLO, "hello.txt", 1),
+            (BIN, "bin", 2),
+            (SPARSE, "sparse", 1),
+            (LINK, "link", 7),
+        ],
+    );
+    image.directory(10, &[(BIN, ".", 2), (ROOT, "..", 2), (INIT, "init", 1)]);
+
+    image.put(11, 0, HELLO_TEXT);
+    let init: Vec<u8> = (0..INIT_SIZE).map(init_byte).collect();
+    image.put(12, 0, &init);
+
+    // The indirect blocks of the sparse file, each pointing at a single block, and its blocks, filled
+    // with their number among the blocks that aren't holes.
+    image.put(16, 4 * 3, &17u32.to_le_bytes());
+    image.put(18, 0, &19u32.to_le_bytes());
+    image.put(19, 4 * 2, &20u32.to_le_bytes());
+    image.put(21, 0, &22u32.to_le_bytes());
+    image.put(22, 0, &23u32.to_le_bytes());
+    image.put(23, 4, &24u32.to_le_bytes());
+    for (tag, block) in [15, 17, 20, 24].into_iter().enumerate() {
+        image.put(block, 0, &[tag as u8 + 1; BLOCK_SIZE]);
+    }
+
+    RamDisk::from_bytes(image.0, 512)
+}
+
+/// A volume with only the features of plain ext2.
+fn ext2_image() -> RamDisk {
+    image(0x0002)
+}
+
+#[test_case]
+fn lists_directories() {
+    let (root, bin) = block_on(async {
+        let ext2 = Ext2::mount(ext2_image()).await.unwrap();
+        let root = ext2.read_dir(ext2.root()).await.unwrap();
+        let bin = ext2.lookup(ext2.root(), "bin").await.unwrap();
+        (root, ext2.read_dir(bin).await.unwrap())
+    });
+
+    let listing: Vec<(&str, NodeId, FileType, u64)> = root
+        .iter()
+        .map(|entry| (entry.name.as_str(), entry.node, entry.kind, entry.size))
+        .collect();
+    assert_eq!(
+        listing,
+        [
+            (
+                "hello.txt",
+                HELLO.into(),
+                FileType::File,
+                HELLO_TEXT.len() as u64
+            ),
+            ("bin", BIN.into(), FileType::Directory, 0),
+            ("sparse", SPARSE.into(), FileType::File, SPARSE_SIZE),
+            ("link", LINK.into(), FileType::File, 8),
+        ]
+    );
+    assert_eq!(bin.len(), 1);
+    assert_eq!(bin[0].name, "init");
+}
+
+#[test_case]
+fn reads_files() {
+    let (hello, init) = block_on(async {
+        let ext2 = Ext2::mount(ext2_image()).await.unwrap();
+        let mut hello = [0; 64];
+        let len = ext2.read(HELLO.into(), 0, &mut hello).await.unwrap();
+
+        // Across the three blocks of the file, and past its end.
+        let mut init = vec![0; 2500];
+        let init_len = ext2.read(INIT.into(), 1000, &mut init).await.unwrap();
+        init.truncate(init_len);
+        (Vec::from(&hello[..len]), init)
+    });
+
+    assert_eq!(hello, HELLO_TEXT);
+    assert_eq!(init.len(), INIT_SIZE - 1000);
+    assert!(
+        init.iter()
+            .enumerate()
+            .all(|(i, &byte)| byte == init_byte(1000 + i))
+    );
+}
+
+#[test_case]
+fn reads_indirect_blocks_and_holes() {
+    let reads = block_on(async {
+        let ext2 = Ext2::mount(ext2_image()).await.unwrap();
+        let mut reads = Vec::new();
+        for block in SPARSE_BLOCKS {
+            // From the middle of the block before, a hole, into the middle of the one after.
+            let offset = (block * BLOCK_SIZE as u64).saturating_sub(512);
+            let mut data = vec![0; 2 * BLOCK_SIZE];
+            let len = ext2.read(SPARSE.into(), offset, &mut data).await.unwrap();
+            data.truncate(len);
+            reads.push((offset, data));
+        }
+        reads
+    });
+
+    for (tag, (offset, data)) in reads.iter().enumerate() {
+        let tag = tag as u8 + 1;
+        let start = SPARSE_BLOCKS[tag as usize - 1] * BLOCK_SIZE as u64 - offset;
+        let start = start as usize;
+        let end = (start + BLOCK_SIZE).min(data.len());
+        assert!(data[..start].iter().all(|&byte| byte == 0));
+        assert!(data[start..end].iter().all(|&byte| byte == tag));
+        assert!(data[end..].iter().all(|&byte| byte == 0));
+    }
+    // The last read stops at the end of the file.
+    assert_eq!(reads[3].1.len(), 512 + BLOCK_SIZE - 10);
+}
+
+#[test_case]
+fn reports_errors() {
+    let results = block_on(async {
+        let ext2 = Ext2::mount(ext2_image()).await.unwrap();
+        let mut buffer = [0; 16];
+        [
+            ext2.lookup(ext2.root(), "missing").await.err(),
+            ext2.lookup(ext2.root(), "HELLO.TXT").await.err(),
+            ext2.lookup(HELLO.into(), "x").await.err(),
+            ext2.read(BIN.into(), 0, &mut buffer).await.err(),
+            ext2.read(LINK.into(), 0, &mut buffer).await.err(),
+            ext2.write(HELLO.into(), 0, b"no").await.err(),
+            ext2.create(ext2.root(), "new", FileType::File).await.err(),
+        ]
+    });
+
+    assert_eq!(
+        results,
+        [
+            Some(FsError::NotFound),
+            Some(FsError::NotFound),
+            Some(FsError::NotADirectory),
+            Some(FsError::IsADirectory),
+            Some(FsError::Unsupported),
+            Some(FsError::ReadOnly),
+            Some(FsError::ReadOnly),
+        ]
+    );
+}
+
+#[test_case]
+fn rejects_other_volumes() {
+    let results = block_on(async {
+        [
+            Ext2::mount(RamDisk::new(512, 8)).await.err(),
+            // Extents, which ext4 uses instead of indirect blocks.
+            Ext2::mount(image(0x0002 | 0x0040)).await.err(),
+        ]
+    });
+
+    assert_eq!(results, [Some(FsError::Unsupported); 2]);
+}
+
+#[test_case]
+fn mounts_in_the_vfs() {
+    let (listing, init, hello) = block_on(async {
+        let ext2 = Ext2::mount(ext2_image()).await.unwrap();
+        fs::mount("/ext", Arc::new(ext2)).unwrap();
+
+        let listing: Vec<String> = fs::read_dir("/ext/bin")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        let init = fs::read_to_end("/ext/bin/../bin/init").await.unwrap();
+        let hello = fs::write_file("/ext/hello.txt", b"bye").await;
+        fs::unmount("/ext").unwrap();
+        (listing, init, hello)
+    });
+
+    assert_eq!(listing, ["init"]);
+    assert_eq!(init.len(), INIT_SIZE);
+    assert_eq!(init[INIT_SIZE - 1], init_byte(INIT_SIZE - 1));
+    assert_eq!(hello, Err(FsError::ReadOnly));
+}
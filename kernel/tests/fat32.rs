@@ -0,0 +1,326 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::panic::PanicInfo;
+use kernel::{
+    fs::{self, FileSystem, FileType, FsError, fat32::Fat32},
+    klog,
+    kmsg::file::{BOOT_MARKER, LogFile},
+    storage::RamDisk,
+    task::block_on,
+};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+const SECTORS: usize = 64;
+const RESERVED: usize = 4;
+const SECTORS_PER_FAT: usize = 1;
+/// Clusters of a sector, after the reserved sectors and two FATs.
+const CLUSTERS: u32 = (SECTORS - RESERVED - 2 * SECTORS_PER_FAT) as u32;
+
+/// An empty FAT32 volume laid out like `mkfs.fat -F 32 -s 1` does, but of only 32 KiB so that it fits
+/// in the heap. Real FAT32 volumes have at least 65525 clusters.
+fn format() -> RamDisk {
+    let mut image = vec![0u8; SECTORS * 512];
+    let mut put =
+        |offset: usize, bytes: &[u8]| image[offset..offset + bytes.len()].copy_from_slice(bytes);
+
+    put(0, &[0xeb, 0x58, 0x90]);
+    put(3, b"MSWIN4.1");
+    put(11, &512u16.to_le_bytes());
+    put(13, &[1]);
+    put(14, &(RESERVED as u16).to_le_bytes());
+    put(16, &[2]);
+    put(21, &[0xf8]);
+    put(32, &(SECTORS as u32).to_le_bytes());
+    put(36, &(SECTORS_PER_FAT as u32).to_le_bytes());
+    put(44, &2u32.to_le_bytes());
+    put(48, &1u16.to_le_bytes());
+    put(66, &[0x29]);
+    put(71, b"NO NAME    FAT32   ");
+    put(510, &[0x55, 0xaa]);
+
+    put(512, &0x4161_5252u32.to_le_bytes());
+    put(512 + 484, &0x6141_7272u32.to_le_bytes());
+    put(512 + 488, &(CLUSTERS - 1).to_le_bytes());
+    put(512 + 492, &3u32.to_le_bytes());
+    put(512 + 508, &0xaa55_0000u32.to_le_bytes());
+
+    for fat in 0..2 {
+        let start = (RESERVED + fat * SECTORS_PER_FAT) * 512;
+        put(start, &0x0fff_fff8u32.to_le_bytes());
+        put(start + 4, &0x0fff_ffffu32.to_le_bytes());
+        // The root directory.
+        put(start + 8, &0x0fff_ffffu32.to_le_bytes());
+    }
+
+    RamDisk::from_bytes(image, 512)
+}
+
+fn names(entries: &[fs::DirEntry]) -> Vec<&str> {
+    entries.iter().map(|entry| entry.name.as_str()).collect()
+}
+
+#[test_case]
+fn mounts_an_empty_volume() {
+    let (clusters, free, entries) = block_on(async {
+        let fat = Fat32::mount(format()).await.unwrap();
+        let entries = fat.read_dir(fat.root()).await.unwrap();
+        (
+            fat.volume().clusters,
+            fat.free_clusters().await,
+            entries.len(),
+        )
+    });
+
+    assert_eq!(clusters, CLUSTERS);
+    assert_eq!(free, Some(CLUSTERS - 1));
+    assert_eq!(entries, 0);
+}
+
+#[test_case]
+fn rejects_other_volumes() {
+    let result = block_on(async { Fat32::mount(RamDisk::new(512, 64)).await.err() });
+
+    assert_eq!(result, Some(FsError::Unsupported));
+}
+
+#[test_case]
+fn creates_files_with_long_names() {
+    let (entries, data, same) = block_on(async {
+        let fat = Fat32::mount(format()).await.unwrap();
+        let root = fat.root();
+        let long = fat
+            .create(root, "A rather long name.text", FileType::File)
+            .await
+            .unwrap();
+        fat.write(long, 0, b"hello").await.unwrap();
+        fat.create(root, "SHORT.TXT", FileType::File).await.unwrap();
+        fat.create(root, "lower.txt", FileType::File).await.unwrap();
+
+        let entries = fat.read_dir(root).await.unwrap();
+        let node = fat.lookup(root, "a RATHER long NAME.TEXT").await.unwrap();
+        let mut data = [0; 16];
+        let len = fat.read(node, 0, &mut data).await.unwrap();
+        let short = fat.lookup(root, "arathe~1.tex").await;
+        (entries, Vec::from(&data[..len]), short == Ok(node))
+    });
+
+    assert_eq!(
+        names(&entries),
+        ["A rather long name.text", "SHORT.TXT", "lower.txt"]
+    );
+    assert_eq!(entries[0].size, 5);
+    assert_eq!(data, b"hello");
+    // Only the long name is shown, but the short one may still be looked up.
+    assert!(same);
+}
+
+#[test_case]
+fn writes_across_clusters() {
+    let (data, size, free_after_write, free_after_truncate) = block_on(async {
+        let fat = Fat32::mount(format()).await.unwrap();
+        let file = fat
+            .create(fat.root(), "big.bin", FileType::File)
+            .await
+            .unwrap();
+        let pattern: Vec<u8> = (0..1500).map(|i| (i % 251) as u8).collect();
+        fat.write(file, 0, &pattern).await.unwrap();
+        // Past the end, leaving a gap that reads as zeros.
+        fat.write(file, 2000, b"end").await.unwrap();
+        let free_after_write = fat.free_clusters().await.unwrap();
+
+        let size = fat.metadata(file).await.unwrap().size;
+        let mut data = vec![0; 4096];
+        let len = fat.read(file, 0, &mut data).await.unwrap();
+        data.truncate(len);
+
+        fat.truncate(file, 600).await.unwrap();
+        (
+            data,
+            size,
+            free_after_write,
+            fat.free_clusters().await.unwrap(),
+        )
+    });
+
+    assert_eq!(size, 2003);
+    assert_eq!(data.len(), 2003);
+    assert!(
+        data[..1500]
+            .iter()
+            .enumerate()
+            .all(|(i, &byte)| byte == (i % 251) as u8)
+    );
+    assert!(data[1500..2000].iter().all(|&byte| byte == 0));
+    assert_eq!(&data[2000..], b"end");
+    assert_eq!(free_after_write, CLUSTERS - 1 - 4);
+    assert_eq!(free_after_truncate, CLUSTERS - 1 - 2);
+}
+
+#[test_case]
+fn removes_only_empty_directories() {
+    let (not_empty, entries, free) = block_on(async {
+        let fat = Fat32::mount(format()).await.unwrap();
+        let root = fat.root();
+        let dir = fat
+            .create(root, "Documents", FileType::Directory)
+            .await
+            .unwrap();
+        let file = fat.create(dir, "notes.txt", FileType::File).await.unwrap();
+        fat.write(file, 0, b"notes").await.unwrap();
+
+        let not_empty = fat.remove(root, "documents").await;
+        fat.remove(dir, "notes.txt").await.unwrap();
+        fat.remove(root, "Documents").await.unwrap();
+        (
+            not_empty,
+            fat.read_dir(root).await.unwrap(),
+            fat.free_clusters().await,
+        )
+    });
+
+    assert_eq!(not_empty, Err(FsError::NotEmpty));
+    assert!(entries.is_empty());
+    assert_eq!(free, Some(CLUSTERS - 1));
+}
+
+#[test_case]
+fn fills_directories_past_a_cluster() {
+    let entries = block_on(async {
+        let fat = Fat32::mount(format()).await.unwrap();
+        // Each takes a long entry and a short one, so 8 fill a cluster.
+        for i in 0..20 {
+            let name = alloc::format!("file {}", i);
+            fat.create(fat.root(), &name, FileType::File).await.unwrap();
+        }
+        fat.read_dir(fat.root()).await.unwrap()
+    });
+
+    assert_eq!(entries.len(), 20);
+    assert_eq!(entries[19].name, "file 19");
+}
+
+#[test_case]
+fn changes_persist_across_mounts() {
+    let (entries, data) = block_on(async {
+        let disk = format();
+        {
+            let fat = Fat32::mount(&disk).await.unwrap();
+            let dir = fat
+                .create(fat.root(), "sub dir", FileType::Directory)
+                .await
+                .unwrap();
+            let file = fat.create(dir, "Data.bin", FileType::File).await.unwrap();
+            fat.write(file, 0, &[0x5a; 700]).await.unwrap();
+        }
+
+        let fat = Fat32::mount(&disk).await.unwrap();
+        let dir = fat.lookup(fat.root(), "sub dir").await.unwrap();
+        let entries = fat.read_dir(dir).await.unwrap();
+        let mut data = vec![0; 1024];
+        let len = fat.read(entries[0].node, 0, &mut data).await.unwrap();
+        data.truncate(len);
+        (entries, data)
+    });
+
+    assert_eq!(names(&entries), ["Data.bin"]);
+    assert_eq!(data, [0x5a; 700]);
+}
+
+#[test_case]
+fn mounts_in_the_vfs() {
+    let (listing, data, twice, outside) = block_on(async {
+        let fat: Arc<dyn FileSystem> = Arc::new(Fat32::mount(format()).await.unwrap());
+        fs::mount("/fat", fat.clone()).unwrap();
+        let twice = fs::mount("/fat/", fat);
+
+        fs::create("/fat/etc", FileType::Directory).await.unwrap();
+        fs::write_file("/fat/etc/motd", b"welcome").await.unwrap();
+        fs::write_file("/fat/etc/./motd", b"hi").await.unwrap();
+        let listing: Vec<String> = fs::read_dir("/fat/etc/../etc")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        let data = fs::read_to_end("/fat/etc/motd").await.unwrap();
+        let outside = fs::metadata("/elsewhere").await;
+        fs::unmount("/fat").unwrap();
+        (listing, data, twice, outside)
+    });
+
+    assert_eq!(listing, ["motd"]);
+    assert_eq!(data, b"hi");
+    assert_eq!(twice, Err(FsError::AlreadyMounted));
+    assert_eq!(outside.err(), Some(FsError::NotFound));
+    assert!(fs::mounts().is_empty());
+}
+
+#[test_case]
+fn kernel_log_is_appended_across_boots() {
+    let (sequence, appended, log) = block_on(async {
+        let fat: Arc<dyn FileSystem> = Arc::new(Fat32::mount(format()).await.unwrap());
+        fs::mount("/log", fat).unwrap();
+
+        let mut file = LogFile::open("/log/kernel.log").await.unwrap();
+        // Whatever was logged before the test.
+        file.flush().await.unwrap();
+        let sequence = klog!(Info, "kept on disk");
+        let appended = file.flush().await.unwrap();
+
+        // As the next boot does.
+        LogFile::open("/log/kernel.log").await.unwrap();
+        let log = fs::read_to_end("/log/kernel.log").await.unwrap();
+        fs::unmount("/log").unwrap();
+        (sequence, appended, String::from_utf8(log).unwrap())
+    });
+
+    assert_eq!(appended, 1);
+    assert!(log.starts_with(BOOT_MARKER));
+    assert!(log.ends_with(BOOT_MARKER));
+    assert_eq!(log.matches(BOOT_MARKER).count(), 2);
+    let line = log
+        .lines()
+        .find(|line| line.starts_with(&format!("{} ", sequence)))
+        .unwrap();
+    assert!(line.ends_with("kernel info: kept on disk"));
+}
@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::panic::PanicInfo;
+use kernel::fpu::{self, FpuState};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+fn write_xmm0(value: u64) {
+    unsafe { core::arch::asm!("movq xmm0, {}", in(reg) value, options(nomem, nostack)) };
+}
+
+fn read_xmm0() -> u64 {
+    let value;
+    unsafe { core::arch::asm!("movq {}, xmm0", out(reg) value, options(nomem, nostack)) };
+    value
+}
+
+#[test_case]
+fn contexts_keep_their_own_sse_registers() {
+    let mut first = FpuState::new();
+    let mut second = FpuState::new();
+
+    unsafe { fpu::switch_to(&mut first) };
+    write_xmm0(1);
+
+    unsafe { fpu::switch_to(&mut second) };
+    assert_eq!(read_xmm0(), 0);
+    write_xmm0(2);
+
+    unsafe { fpu::switch_to(&mut first) };
+    assert_eq!(read_xmm0(), 1);
+
+    unsafe { fpu::switch_to(&mut second) };
+    assert_eq!(read_xmm0(), 2);
+
+    unsafe { fpu::switch_to(core::ptr::null_mut()) };
+}
+
+#[test_case]
+fn forked_states_start_with_the_live_registers() {
+    let mut parent = FpuState::new();
+
+    unsafe { fpu::switch_to(&mut parent) };
+    write_xmm0(3);
+
+    // The parent still owns the registers, so its area doesn't hold 3 yet.
+    let mut child = parent.fork();
+    write_xmm0(4);
+
+    unsafe { fpu::switch_to(&mut child) };
+    assert_eq!(read_xmm0(), 3);
+
+    unsafe { fpu::switch_to(&mut parent) };
+    assert_eq!(read_xmm0(), 4);
+
+    unsafe { fpu::switch_to(core::ptr::null_mut()) };
+}
+
+#[test_case]
+fn kernel_fpu_use_does_not_clobber_the_context() {
+    let mut state = FpuState::new();
+
+    unsafe { fpu::switch_to(&mut state) };
+    write_xmm0(7);
+
+    fpu::with_fpu(|| write_xmm0(42));
+    assert_eq!(read_xmm0(), 7);
+
+    unsafe { fpu::switch_to(core::ptr::null_mut()) };
+}
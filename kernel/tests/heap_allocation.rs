@@ -36,7 +36,7 @@ fn main(boot_info: &'static mut BootInfo) -> ! {
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
 
     test_main();
-    loop {}
+    kernel::hlt_loop();
 }
 
 #[panic_handler]
@@ -0,0 +1,177 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::{panic::PanicInfo, sync::atomic::Ordering};
+use kernel::{
+    input::{self, Button, DeviceKind, Event, InputEvent, Modifiers},
+    task::keyboard::{KeyDecoder, Layout},
+};
+use libsys::input::{InputRing, RING_LEN, RingEvent, SCROLL};
+use pc_keyboard::{DecodedKey, KeyCode};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+#[test_case]
+fn subscribers_get_events_emitted_after_subscribing() {
+    let mouse = input::register_device("test mouse", DeviceKind::Mouse);
+    input::emit(mouse, Event::Scroll { delta: 1 });
+
+    let mut first = input::subscribe();
+    let mut second = input::subscribe();
+    let pressed = Event::Button {
+        button: Button::Left,
+        pressed: true,
+    };
+    input::emit(mouse, Event::MouseMove { dx: 2, dy: -1 });
+    drop(second);
+    input::emit(mouse, pressed);
+
+    assert_eq!(
+        first.try_next(),
+        Some(InputEvent {
+            device: mouse,
+            event: Event::MouseMove { dx: 2, dy: -1 },
+        })
+    );
+    assert_eq!(first.try_next().map(|input| input.event), Some(pressed));
+    assert_eq!(first.try_next(), None);
+
+    // A new subscriber doesn't get what was queued for the dropped one.
+    second = input::subscribe();
+    assert_eq!(second.try_next(), None);
+    assert!(
+        input::devices()
+            .iter()
+            .any(|device| device.id == mouse && device.name == "test mouse")
+    );
+}
+
+#[test_case]
+fn key_decoder_emits_presses_and_releases() {
+    let mut decoder = KeyDecoder::new();
+    // Left shift, then a with it held, then both released.
+    let events = [0x2a, 0x1e, 0x9e, 0xaa].map(|scancode| decoder.feed(scancode));
+
+    let shift = Modifiers {
+        shift: true,
+        ..Modifiers::default()
+    };
+    assert_eq!(
+        events,
+        [
+            Some(Event::KeyPress {
+                code: KeyCode::LShift,
+                key: None,
+                modifiers: shift,
+            }),
+            Some(Event::KeyPress {
+                code: KeyCode::A,
+                key: Some(DecodedKey::Unicode('A')),
+                modifiers: shift,
+            }),
+            Some(Event::KeyRelease { code: KeyCode::A }),
+            Some(Event::KeyRelease {
+                code: KeyCode::LShift
+            }),
+        ]
+    );
+}
+
+#[test_case]
+fn key_decoder_applies_layout_and_remaps() {
+    let mut decoder = KeyDecoder::new();
+    decoder.set_layout(Layout::Dvorak);
+    decoder.remap(KeyCode::CapsLock, KeyCode::LShift);
+
+    // Caps Lock, acting as shift, then the key that types s on US.
+    let events = [0x3a, 0x1f].map(|scancode| decoder.feed(scancode));
+    assert_eq!(
+        events[0],
+        Some(Event::KeyPress {
+            code: KeyCode::LShift,
+            key: None,
+            modifiers: Modifiers {
+                shift: true,
+                ..Modifiers::default()
+            },
+        })
+    );
+    assert!(matches!(
+        events[1],
+        Some(Event::KeyPress {
+            key: Some(DecodedKey::Unicode('O')),
+            ..
+        })
+    ));
+
+    assert_eq!(
+        decoder.feed(0xba),
+        Some(Event::KeyRelease {
+            code: KeyCode::LShift
+        })
+    );
+    decoder.remap(KeyCode::CapsLock, KeyCode::CapsLock);
+    decoder.set_layout(Layout::Us);
+    assert_eq!(decoder.decode(0x1f), Some(DecodedKey::Unicode('s')));
+}
+
+#[test_case]
+fn full_rings_count_dropped_events() {
+    let ring = Box::new(InputRing::new());
+    let event = |x| RingEvent {
+        kind: SCROLL,
+        x,
+        ..RingEvent::default()
+    };
+
+    for x in 0..RING_LEN as i32 {
+        assert!(ring.push(event(x)));
+    }
+    assert!(!ring.push(event(-1)));
+    assert_eq!(ring.dropped.load(Ordering::Relaxed), 1);
+
+    assert_eq!(ring.pop(), Some(event(0)));
+    assert!(ring.push(event(RING_LEN as i32)));
+    for x in 1..=RING_LEN as i32 {
+        assert_eq!(ring.pop(), Some(event(x)));
+    }
+    assert_eq!(ring.pop(), None);
+}
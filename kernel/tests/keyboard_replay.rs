@@ -0,0 +1,97 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, string::String};
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::{cell::RefCell, panic::PanicInfo};
+use futures_util::stream::StreamExt;
+use kernel::task::{
+    Task,
+    keyboard::{self, KeyDecoder, ScancodeStream},
+    simple_executor::SimpleExecutor,
+};
+use pc_keyboard::DecodedKey;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+/// Scancode set 1 for typing "Hi" followed by Enter: make codes, with break codes (make | 0x80) on release.
+const SCRIPT: &[u8] = &[
+    0x2A, // left shift pressed
+    0x23, 0xA3, // h
+    0xAA, // left shift released
+    0x17, 0x97, // i
+    0x1C, 0x9C, // enter
+];
+
+#[test_case]
+fn replayed_scancodes_are_decoded_in_order() {
+    let mut scancodes = ScancodeStream::new().expect("scancode stream already taken");
+    keyboard::replay_scancodes(SCRIPT);
+
+    let typed = Rc::new(RefCell::new(String::new()));
+    let output = typed.clone();
+
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async move {
+        let mut decoder = KeyDecoder::new();
+
+        while let Some(scancode) = scancodes.next().await {
+            if let Some(DecodedKey::Unicode(character)) = decoder.decode(scancode) {
+                output.borrow_mut().push(character);
+
+                if character == '\n' {
+                    break;
+                }
+            }
+        }
+    }));
+    executor.run();
+
+    assert_eq!(typed.borrow().as_str(), "Hi\n");
+}
+
+#[test_case]
+fn scancode_stream_has_a_single_consumer() {
+    assert!(ScancodeStream::new().is_err());
+}
+
+#[test_case]
+fn kernel_init_can_be_called_again() {
+    kernel::init();
+}
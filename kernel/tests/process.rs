@@ -0,0 +1,485 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::{panic::PanicInfo, sync::atomic::AtomicU64};
+use kernel::{
+    memory::{
+        self, AddressSpace,
+        address_space::{USER_END, USER_START},
+    },
+    process::{
+        self, File, HeapError, SpawnError, State,
+        fd::{FdError, FileError},
+        pipe::{self, PIPE_SIZE},
+        rlimit::{LimitError, Resource, Rlimit},
+        sched,
+        signal::{self, Action, Signal, SignalError},
+    },
+};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{Page, PageTableFlags},
+};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+fn user_program() {}
+
+#[test_case]
+fn first_process_is_pid_1() {
+    let pid = process::spawn_user(user_program).unwrap();
+    assert_eq!(pid.as_u64(), 1);
+}
+
+#[test_case]
+fn spawned_processes_are_ready_in_the_table() {
+    let first = process::spawn_user(user_program).unwrap();
+    let second = process::spawn_user(user_program).unwrap();
+    assert!(second > first);
+
+    let processes = process::list();
+    assert!(processes.contains(&(first, State::Ready)));
+    assert!(processes.contains(&(second, State::Ready)));
+
+    process::with_process(first, |process| {
+        assert_eq!(process.context().rip, user_program as *const () as u64);
+        assert_eq!(process.context().rsp, process::USER_STACK_TOP.as_u64() - 8);
+
+        let space = process
+            .address_space()
+            .expect("user processes have an address space");
+        assert_ne!(*space, AddressSpace::kernel());
+        assert!(!space.is_active());
+    })
+    .expect("process missing from the table");
+}
+
+#[test_case]
+fn only_zombies_are_reaped() {
+    let pid = process::spawn_user(user_program).unwrap();
+
+    assert_eq!(process::reap(pid), None);
+    assert!(process::with_process(pid, |_| ()).is_some());
+    assert_eq!(process::current(), None);
+}
+
+#[test_case]
+fn hard_limits_can_only_be_lowered() {
+    let pid = process::spawn_user(user_program).unwrap();
+
+    process::with_process(pid, |process| {
+        let limits = process.limits_mut();
+        let lowered = Rlimit { soft: 4, hard: 8 };
+
+        assert_eq!(limits.set(Resource::Children, lowered), Ok(()));
+        assert_eq!(limits.get(Resource::Children), lowered);
+        assert_eq!(
+            limits.set(Resource::Children, Rlimit { soft: 4, hard: 9 }),
+            Err(LimitError::RaisesHardLimit)
+        );
+        assert_eq!(
+            limits.set(Resource::Children, Rlimit { soft: 9, hard: 8 }),
+            Err(LimitError::SoftAboveHard)
+        );
+        assert!(limits.check(Resource::Children, 4).is_ok());
+        assert!(limits.check(Resource::Children, 5).is_err());
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn forked_address_spaces_copy_user_pages() {
+    let page = Page::containing_address(USER_START + 0x1000u64);
+    let mut parent = AddressSpace::new_user().unwrap();
+
+    let frame = parent.map_user(page, PageTableFlags::WRITABLE).unwrap();
+    let parent_byte = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+    unsafe { parent_byte.write(0x42) };
+
+    let mut child = parent.fork().unwrap();
+    let (copy, flags) = child.translate(page).expect("page missing from the child");
+    let child_byte = memory::phys_to_virt(copy.start_address()).as_mut_ptr::<u8>();
+
+    assert_ne!(copy, frame);
+    assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE));
+    assert_eq!(unsafe { child_byte.read() }, 0x42);
+
+    unsafe { child_byte.write(0x17) };
+    assert_eq!(unsafe { parent_byte.read() }, 0x42);
+}
+
+#[test_case]
+fn dropping_an_address_space_frees_its_frames() {
+    let before = memory::allocated_frames();
+
+    let mut space = AddressSpace::new_user().unwrap();
+    space
+        .map_user(
+            Page::containing_address(USER_START),
+            PageTableFlags::empty(),
+        )
+        .unwrap();
+    space
+        .map_user(
+            Page::containing_address(USER_END - 1u64),
+            PageTableFlags::WRITABLE,
+        )
+        .unwrap();
+    assert!(memory::allocated_frames() > before);
+
+    drop(space);
+    assert_eq!(memory::allocated_frames(), before);
+}
+
+#[test_case]
+fn borrowed_frames_are_left_to_their_owner() {
+    let page = Page::containing_address(USER_START + 0x2000u64);
+    let frame = memory::allocate_zeroed_frame().unwrap();
+    let before = memory::allocated_frames();
+
+    let mut space = AddressSpace::new_user().unwrap();
+    space
+        .map_borrowed(page, frame, PageTableFlags::WRITABLE)
+        .unwrap();
+    assert_eq!(space.translate(page).map(|(mapped, _)| mapped), Some(frame));
+
+    let mut child = space.fork().unwrap();
+    assert_eq!(child.translate(page), None);
+    drop(child);
+
+    drop(space);
+    assert_eq!(memory::allocated_frames(), before);
+}
+
+#[test_case]
+fn invalid_images_are_not_spawned() {
+    assert!(matches!(
+        process::spawn_elf(b"not an executable"),
+        Err(process::SpawnError::InvalidImage(
+            process::elf::ElfError::NotAnExecutable
+        ))
+    ));
+}
+
+#[test_case]
+fn anonymous_pages_are_mapped_when_touched() {
+    let start = Page::containing_address(USER_START + 0x10000u64);
+    let mut space = AddressSpace::new_user().unwrap();
+
+    space
+        .add_anonymous(Page::range(start, start + 4), PageTableFlags::WRITABLE)
+        .unwrap();
+    assert_eq!(space.anonymous_pages(), 4);
+    assert_eq!(space.translate(start + 1), None);
+
+    assert!(space.handle_fault((start + 1).start_address() + 0x123u64));
+    let (frame, flags) = space
+        .translate(start + 1)
+        .expect("touched page isn't mapped");
+    assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE));
+    assert_eq!(
+        unsafe {
+            memory::phys_to_virt(frame.start_address())
+                .as_ptr::<u64>()
+                .read()
+        },
+        0
+    );
+
+    // Faults on mapped pages are protection violations, and outside the regions there is nothing to map.
+    assert!(!space.handle_fault((start + 1).start_address()));
+    assert!(!space.handle_fault((start + 4).start_address()));
+}
+
+#[test_case]
+fn anonymous_regions_merge_and_split() {
+    let start = Page::containing_address(USER_START);
+    let mut space = AddressSpace::new_user().unwrap();
+    let flags = PageTableFlags::WRITABLE;
+
+    space
+        .add_anonymous(Page::range(start, start + 2), flags)
+        .unwrap();
+    space
+        .add_anonymous(Page::range(start + 2, start + 6), flags)
+        .unwrap();
+    assert_eq!(space.anonymous_regions().len(), 1);
+    assert_eq!(
+        space.add_anonymous(Page::range(start + 5, start + 7), flags),
+        Err(memory::address_space::RegionError::Overlaps)
+    );
+
+    let before = memory::allocated_frames();
+    assert!(space.handle_fault((start + 3).start_address()));
+    space.remove_anonymous(Page::range(start + 2, start + 4));
+
+    assert_eq!(memory::allocated_frames(), before);
+    let regions = space.anonymous_regions();
+    assert_eq!(regions.len(), 2);
+    assert_eq!(regions[0].pages, Page::range(start, start + 2));
+    assert_eq!(regions[1].pages, Page::range(start + 4, start + 6));
+}
+
+#[test_case]
+fn free_ranges_are_found_top_down() {
+    let start = Page::containing_address(USER_START);
+    let within = Page::range(start, start + 16);
+    let mut space = AddressSpace::new_user().unwrap();
+
+    assert_eq!(
+        space.find_free(4, within),
+        Some(Page::range(start + 12, start + 16))
+    );
+
+    space
+        .add_anonymous(Page::range(start + 10, start + 16), PageTableFlags::empty())
+        .unwrap();
+    space
+        .add_anonymous(Page::range(start + 3, start + 8), PageTableFlags::empty())
+        .unwrap();
+
+    assert_eq!(
+        space.find_free(2, within),
+        Some(Page::range(start + 8, start + 10))
+    );
+    assert_eq!(
+        space.find_free(3, within),
+        Some(Page::range(start, start + 3))
+    );
+    assert_eq!(space.find_free(4, within), None);
+}
+
+#[test_case]
+fn heap_grows_within_the_heap_limit() {
+    let pid = process::spawn_user(user_program).unwrap();
+
+    process::with_process(pid, |process| {
+        let start = process.heap_start();
+        assert_eq!(process.brk(), start);
+
+        let limit = Rlimit {
+            soft: 4 * 4096,
+            hard: 4 * 4096,
+        };
+        process.limits_mut().set(Resource::Heap, limit).unwrap();
+
+        assert_eq!(process.set_brk(start + 100u64), Ok(()));
+        assert_eq!(process.brk(), start + 100u64);
+        assert_eq!(process.set_brk(start - 1u64), Err(HeapError::NoRoom));
+
+        let mapped = process
+            .map_anonymous(2, PageTableFlags::WRITABLE)
+            .expect("mmap within the limit failed");
+        assert!(mapped >= start + 4096u64);
+        assert_eq!(
+            process.set_brk(start + 3 * 4096u64),
+            Err(HeapError::LimitExceeded)
+        );
+
+        process.unmap_anonymous(Page::range(
+            Page::containing_address(mapped),
+            Page::containing_address(mapped) + 2,
+        ));
+        assert_eq!(process.set_brk(start + 4 * 4096u64), Ok(()));
+        assert_eq!(process.set_brk(start), Ok(()));
+        assert_eq!(
+            process.map_anonymous(u64::MAX.div_ceil(4096), PageTableFlags::WRITABLE),
+            Err(HeapError::NoRoom)
+        );
+        assert_eq!(process.address_space().unwrap().anonymous_pages(), 0);
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn signals_stay_pending_until_delivered() {
+    let pid = process::spawn_user(user_program).unwrap();
+
+    assert_eq!(
+        signal::set_action(pid, Signal::KILL, Action::Ignore),
+        Err(SignalError::Uncatchable)
+    );
+    assert_eq!(
+        signal::set_action(pid, Signal::SEGV, Action::Ignore),
+        Ok(Action::Default)
+    );
+
+    signal::send(pid, Signal::SEGV).unwrap();
+    process::with_process(pid, |process| {
+        assert!(process.signals().is_pending(Signal::SEGV));
+        assert_eq!(process.signals().action(Signal::SEGV), Action::Ignore);
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn descriptors_are_allocated_lowest_first_within_the_limit() {
+    let pid = process::spawn_user(user_program).unwrap();
+
+    process::with_process(pid, |process| {
+        assert_eq!(process.files().open_count(), 3);
+        assert!(matches!(
+            process.files().get(libsys::STDOUT),
+            Some(File::Console)
+        ));
+
+        process
+            .limits_mut()
+            .set(Resource::OpenFiles, Rlimit { soft: 5, hard: 5 })
+            .unwrap();
+        assert_eq!(process.open_file(File::Serial), Ok(3));
+        assert_eq!(process.open_file(File::Serial), Ok(4));
+        assert_eq!(process.open_file(File::Serial), Err(FdError::TooManyOpen));
+
+        assert!(process.close_file(1).is_ok());
+        assert_eq!(process.close_file(1).err(), Some(FdError::BadDescriptor));
+        assert_eq!(process.open_file(File::Console), Ok(1));
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn pipes_carry_bytes_until_an_end_is_closed() {
+    let (reader, writer) = pipe::pipe();
+    let reader = File::PipeReader(Arc::new(reader));
+    let writer = File::PipeWriter(Arc::new(writer));
+    let mut buffer = [0; 8];
+
+    assert_eq!(reader.read(&mut buffer), Err(FileError::WouldBlock));
+    assert!(reader.waiters(false).is_some());
+    assert!(!reader.is_writable());
+
+    assert_eq!(writer.write(b"hello"), Ok(5));
+    assert_eq!(reader.read(&mut buffer[..3]), Ok(3));
+    assert_eq!(&buffer[..3], b"hel");
+
+    // Another descriptor for the same end keeps it open.
+    let second_writer = writer.clone();
+    drop(writer);
+    assert_eq!(second_writer.write(b"!"), Ok(1));
+    drop(second_writer);
+
+    assert_eq!(reader.read(&mut buffer), Ok(3));
+    assert_eq!(&buffer[..3], b"lo!");
+    assert_eq!(reader.read(&mut buffer), Ok(0));
+}
+
+#[test_case]
+fn full_pipes_block_writers_and_broken_ones_fail() {
+    let (reader, writer) = pipe::pipe();
+    let reader = File::PipeReader(Arc::new(reader));
+    let writer = File::PipeWriter(Arc::new(writer));
+
+    let bytes = vec![7; PIPE_SIZE + 10];
+    assert_eq!(writer.write(&bytes), Ok(PIPE_SIZE));
+    assert_eq!(writer.write(&bytes), Err(FileError::WouldBlock));
+
+    // The ring buffer wraps around.
+    let mut buffer = [0; 16];
+    assert_eq!(reader.read(&mut buffer), Ok(16));
+    assert_eq!(writer.write(&[1, 2, 3]), Ok(3));
+    let mut rest = vec![0; PIPE_SIZE];
+    assert_eq!(reader.read(&mut rest), Ok(PIPE_SIZE - 13));
+    assert_eq!(&rest[PIPE_SIZE - 16..PIPE_SIZE - 13], &[1, 2, 3]);
+
+    drop(reader);
+    assert_eq!(writer.write(b"x"), Err(FileError::BrokenPipe));
+}
+
+#[test_case]
+fn new_processes_are_queued_at_their_highest_level() {
+    let before = sched::ready_counts();
+    let pid = process::spawn_user(user_program).unwrap();
+    let after = sched::ready_counts();
+
+    let priority = process::with_process(pid, |process| process.priority()).unwrap();
+    assert_eq!(priority.nice(), 0);
+    assert_eq!(priority.level(), priority.top_level());
+    assert_eq!(after[priority.level()], before[priority.level()] + 1);
+}
+
+static KERNEL_DATA: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn ring_3_only_reaches_the_kernel_code_of_built_in_programs() {
+    let code = VirtAddr::from_ptr(user_program as *const ());
+    let heap = Box::new(0u64);
+    let kernel_only = [
+        VirtAddr::from_ptr(&*heap),
+        VirtAddr::from_ptr(&KERNEL_DATA),
+        memory::phys_to_virt(PhysAddr::new(0)),
+    ];
+
+    assert!(!AddressSpace::kernel().is_user_accessible(code));
+    assert!(!AddressSpace::new_user().unwrap().is_user_accessible(code));
+
+    let pid = process::spawn_user(user_program).unwrap();
+    process::with_process(pid, |process| {
+        let space = process.address_space().unwrap();
+        assert!(space.is_user_accessible(code));
+        assert!(space.is_user_accessible(process::USER_STACK_TOP - 8u64));
+        for address in kernel_only {
+            assert!(!space.is_user_accessible(address));
+        }
+
+        let child = space.fork().unwrap();
+        assert!(child.is_user_accessible(code));
+    })
+    .expect("process missing from the table");
+
+    assert!(!AddressSpace::kernel().is_user_accessible(code));
+}
+
+// Fills the process table, so it stays the last test.
+#[test_case]
+fn fork_bombs_run_out_of_processes_before_memory() {
+    let error = loop {
+        if let Err(error) = process::spawn_user(user_program) {
+            break error;
+        }
+    };
+
+    assert_eq!(error, SpawnError::TooManyProcesses);
+    assert_eq!(process::list().len(), process::MAX_PROCESSES);
+    assert!(Vec::<u8>::new().try_reserve(4096).is_ok());
+}
@@ -0,0 +1,123 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::panic::PanicInfo;
+use kernel::queue::{self, Overflow, Queue, QueueError, QueueId};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+/// A queue of `capacity` holding `0..capacity`. Nothing else uses the registered queues in this test.
+fn full_queue(id: QueueId, capacity: usize) -> Queue<usize> {
+    queue::set_capacity(id, capacity).unwrap();
+
+    let queue = Queue::new(id);
+    for value in 0..capacity {
+        queue.push(value).unwrap();
+    }
+
+    queue
+}
+
+fn drain(queue: &Queue<usize>) -> alloc::vec::Vec<usize> {
+    core::iter::from_fn(|| queue.pop()).collect()
+}
+
+#[test_case]
+fn drop_newest_rejects_pushes_when_full() {
+    queue::set_overflow(QueueId::Scancodes, Overflow::DropNewest).unwrap();
+    let queue = full_queue(QueueId::Scancodes, 3);
+    let before = queue::stats(QueueId::Scancodes);
+
+    assert_eq!(queue.push(3), Err(3));
+    assert_eq!(drain(&queue), [0, 1, 2]);
+
+    let after = queue::stats(QueueId::Scancodes);
+    assert_eq!(after.capacity, 3);
+    assert_eq!(after.overflows, before.overflows + 1);
+    assert_eq!(after.dropped, before.dropped + 1);
+    assert!(after.high_water >= 3);
+}
+
+#[test_case]
+fn drop_oldest_makes_room() {
+    queue::set_overflow(QueueId::DeferredWork, Overflow::DropOldest).unwrap();
+    let queue = full_queue(QueueId::DeferredWork, 3);
+    let before = queue::stats(QueueId::DeferredWork);
+
+    assert_eq!(queue.push(3), Ok(()));
+    assert_eq!(queue.push(4), Ok(()));
+    assert_eq!(drain(&queue), [2, 3, 4]);
+    assert_eq!(
+        queue::stats(QueueId::DeferredWork).dropped,
+        before.dropped + 2
+    );
+}
+
+#[test_case]
+fn grown_queues_keep_their_order() {
+    let queue = full_queue(QueueId::Tasks, 2);
+    let before = queue::stats(QueueId::Tasks);
+
+    queue.push(2).unwrap();
+    assert_eq!(queue.pop(), Some(0));
+    // There is room in the ring again, but 2 is still waiting in the overflow buffer.
+    queue.push(3).unwrap();
+
+    assert_eq!(queue.len(), 3);
+    assert_eq!(drain(&queue), [1, 2, 3]);
+    assert!(queue.is_empty());
+
+    let after = queue::stats(QueueId::Tasks);
+    assert_eq!(after.overflows, before.overflows + 2);
+    assert_eq!(after.dropped, before.dropped);
+}
+
+#[test_case]
+fn lossless_queues_only_grow() {
+    assert_eq!(
+        queue::set_overflow(QueueId::Tasks, Overflow::DropOldest),
+        Err(QueueError::Lossless)
+    );
+    assert_eq!(queue::stats(QueueId::Tasks).overflow, Overflow::Grow);
+    assert_eq!(
+        queue::set_capacity(QueueId::Tasks, 0),
+        Err(QueueError::ZeroCapacity)
+    );
+}
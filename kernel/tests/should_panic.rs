@@ -3,16 +3,18 @@
 #![no_std]
 #![no_main]
 
+use bootloader_api::{BootInfo, entry_point};
 use core::panic::PanicInfo;
 
 use kernel::{QemuExitCode, exit_qemu, serial_print, serial_println};
 
-#[unsafe(no_mangle)]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(_boot_info: &'static mut BootInfo) -> ! {
     should_fail();
     serial_println!("[test did not panic]");
     exit_qemu(QemuExitCode::Failed);
-    loop {}
+    kernel::hlt_loop();
 }
 
 fn should_fail() {
@@ -24,5 +26,5 @@ fn should_fail() {
 fn panic_handler(_info: &PanicInfo) -> ! {
     serial_println!("[ok]");
     exit_qemu(QemuExitCode::Success);
-    loop {}
+    kernel::hlt_loop();
 }
@@ -2,6 +2,7 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 
+use bootloader_api::{BootInfo, entry_point};
 use core::{panic::PanicInfo, ptr::NonNull};
 use kernel::{
     QemuExitCode, exit_qemu, gdt::DOUBLE_FAULT_IST_INDEX, serial_print, serial_println,
@@ -10,8 +11,9 @@ use kernel::{
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
-#[unsafe(no_mangle)]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(_boot_info: &'static mut BootInfo) -> ! {
     serial_print!("stack_overflow::stack_overflow...\t");
 
     kernel::gdt::init();
@@ -26,7 +28,7 @@ pub extern "C" fn _start() -> ! {
 fn stack_overflow() {
     // o endereço de retorno é puxado para pilha em cada chamada.
     // causa um stack overflow.
-    // isso chega na "guard page" que é criada pelo bootloader.
+    // isso chega na "guard page" que o bootloader deixa abaixo da stack do kernel.
     // essa guard page não permite leitura nem escrita.
     // e fica abaixo da stack.
     // ao acessarmos a guard page, ocorre um page fault.
@@ -78,7 +80,7 @@ extern "x86-interrupt" fn test_double_flat_handler(
     serial_println!("[ok]");
     exit_qemu(QemuExitCode::Success);
     // a CPU não permite retorno de handlers de double faults.
-    loop {}
+    kernel::hlt_loop();
 }
 
 #[panic_handler]
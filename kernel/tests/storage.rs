@@ -0,0 +1,147 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, vec, vec::Vec};
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::{cell::RefCell, panic::PanicInfo};
+use kernel::{
+    storage::{BlockDevice, BlockError, RamDisk, Request, RequestQueue},
+    task::{block_on, executor::Executor},
+};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+#[test_case]
+fn ram_disk_reads_back_what_was_written() {
+    let disk = RamDisk::new(512, 4);
+    let data: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+
+    let (disk, read) = block_on(async move {
+        disk.write_blocks(1, &data).await.unwrap();
+        let mut read = vec![0; 1536];
+        disk.read_blocks(0, &mut read).await.unwrap();
+        (disk, read)
+    });
+
+    assert!(read[..512].iter().all(|&byte| byte == 0));
+    assert!(
+        read[512..]
+            .iter()
+            .enumerate()
+            .all(|(i, &byte)| byte == i as u8)
+    );
+    assert_eq!(disk.into_bytes().len(), 2048);
+}
+
+#[test_case]
+fn ram_disk_rejects_bad_requests() {
+    let mut disk = RamDisk::from_bytes(vec![7; 1000], 512);
+    assert_eq!(disk.num_blocks(), 1);
+    disk.set_read_only(true);
+
+    let results = block_on(async move {
+        let mut buffer = [0; 512];
+        [
+            disk.read_blocks(1, &mut buffer).await,
+            disk.read_blocks(0, &mut buffer[..100]).await,
+            disk.write_blocks(0, &buffer).await,
+        ]
+    });
+
+    assert_eq!(
+        results,
+        [
+            Err(BlockError::OutOfRange),
+            Err(BlockError::InvalidLength),
+            Err(BlockError::ReadOnly),
+        ]
+    );
+}
+
+#[test_case]
+fn request_queue_serves_requests_in_order() {
+    let mut executor = Executor::new();
+    let (queue, serve) = RequestQueue::new(RamDisk::new(16, 4), 2);
+    executor.spawn(serve);
+    let log = Rc::new(RefCell::new(Vec::new()));
+
+    for block in 0..4u8 {
+        let queue = queue.clone();
+        let log = log.clone();
+        executor.spawn(async move {
+            queue
+                .write_blocks(block.into(), &[block; 16])
+                .await
+                .unwrap();
+            log.borrow_mut().push(block);
+        });
+    }
+    let reader = queue.clone();
+    let read = Rc::new(RefCell::new(Vec::new()));
+    let blocks = read.clone();
+    executor.spawn(async move {
+        let data = reader.submit(Request::Read { start: 0, count: 4 }).await;
+        *blocks.borrow_mut() = data.unwrap();
+    });
+    executor.run_until_idle();
+
+    assert_eq!(*log.borrow(), [0, 1, 2, 3]);
+    assert_eq!(read.borrow().len(), 64);
+    assert!(
+        read.borrow()
+            .chunks(16)
+            .enumerate()
+            .all(|(block, data)| data == [block as u8; 16])
+    );
+
+    // The serving task ends with the last handle.
+    drop(queue);
+    executor.run_until_idle();
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn request_queue_without_server_is_closed() {
+    let (queue, serve) = RequestQueue::new(RamDisk::new(16, 1), 1);
+    drop(serve);
+
+    let result = block_on(async move { queue.read_blocks(0, &mut [0; 16]).await });
+
+    assert_eq!(result, Err(BlockError::QueueClosed));
+}
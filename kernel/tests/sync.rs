@@ -0,0 +1,166 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use kernel::{
+    sync::{Mutex, Semaphore, WaitQueue},
+    thread::{self, State},
+};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+/// Yields until every thread in `threads` has returned.
+fn join(threads: &[thread::ThreadId]) {
+    while threads.iter().any(|&id| thread::state(id).is_some()) {
+        thread::yield_now();
+    }
+}
+
+static LOG: spin::Mutex<Vec<&str>> = spin::Mutex::new(Vec::new());
+
+fn log(event: &'static str) {
+    LOG.lock().push(event);
+}
+
+static SHARED: Mutex<u32> = Mutex::new(0);
+
+fn holder() {
+    let mut value = SHARED.lock();
+    log("holder locked");
+    // Give the contender a chance to run while the lock is held.
+    thread::yield_now();
+    *value += 1;
+    log("holder unlocks");
+}
+
+fn contender() {
+    log("contender waits");
+    *SHARED.lock() += 1;
+    log("contender locked");
+}
+
+#[test_case]
+fn mutex_waiters_sleep_until_it_is_unlocked() {
+    LOG.lock().clear();
+    let holder = thread::spawn(holder);
+    let contender = thread::spawn(contender);
+
+    thread::yield_now();
+    assert_eq!(thread::state(contender), Some(State::Blocked));
+    assert!(SHARED.is_locked());
+
+    join(&[holder, contender]);
+    assert_eq!(*SHARED.lock(), 2);
+    assert_eq!(
+        *LOG.lock(),
+        [
+            "holder locked",
+            "contender waits",
+            "holder unlocks",
+            "contender locked"
+        ]
+    );
+}
+
+static PERMITS: Semaphore = Semaphore::new(1);
+
+fn first_user() {
+    PERMITS.acquire();
+    log("first acquired");
+    thread::yield_now();
+    log("first releases");
+    PERMITS.release();
+}
+
+fn second_user() {
+    PERMITS.acquire();
+    log("second acquired");
+    PERMITS.release();
+}
+
+#[test_case]
+fn semaphores_block_when_out_of_permits() {
+    LOG.lock().clear();
+    let first = thread::spawn(first_user);
+    let second = thread::spawn(second_user);
+
+    join(&[first, second]);
+    assert_eq!(
+        *LOG.lock(),
+        ["first acquired", "first releases", "second acquired"]
+    );
+    assert_eq!(PERMITS.available(), 1);
+    assert!(PERMITS.try_acquire());
+    assert!(!PERMITS.try_acquire());
+    PERMITS.release();
+}
+
+static EVENT: WaitQueue = WaitQueue::new();
+static FLAG: AtomicBool = AtomicBool::new(false);
+
+fn flag_waiter() {
+    EVENT.wait_until(|| FLAG.load(Ordering::SeqCst));
+    log("flag seen");
+}
+
+#[test_case]
+fn wait_queues_recheck_their_condition() {
+    LOG.lock().clear();
+    let waiter = thread::spawn(flag_waiter);
+
+    thread::yield_now();
+    assert_eq!(EVENT.len(), 1);
+
+    // A wakeup without the condition puts the waiter straight back to sleep.
+    assert_eq!(EVENT.wake_all(), 1);
+    thread::yield_now();
+    assert_eq!(thread::state(waiter), Some(State::Blocked));
+
+    FLAG.store(true, Ordering::SeqCst);
+    assert!(EVENT.wake_one());
+    join(&[waiter]);
+
+    assert_eq!(*LOG.lock(), ["flag seen"]);
+    assert!(EVENT.is_empty());
+}
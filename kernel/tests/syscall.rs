@@ -0,0 +1,342 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::{mem::size_of, panic::PanicInfo, slice};
+use kernel::{
+    memory::{AddressSpace, address_space::USER_START},
+    syscall::{SyscallFrame, dispatch},
+    time::Timespec,
+};
+use libsys::{
+    errno, number,
+    uio::{IoVec, Submission},
+};
+use x86_64::structures::paging::{Page, PageTableFlags};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+/// A user address space with one writable page at `USER_START`, active while this lives. Syscalls only
+/// accept buffers in the user part, so the arguments of the tests are put there.
+struct UserMemory {
+    space: AddressSpace,
+    used: u64,
+}
+
+impl UserMemory {
+    fn new() -> Self {
+        let mut space = AddressSpace::new_user().unwrap();
+        space
+            .map_user(
+                Page::containing_address(USER_START),
+                PageTableFlags::WRITABLE,
+            )
+            .unwrap();
+        // `new_user` maps the kernel like every other address space does.
+        unsafe { space.activate() };
+
+        UserMemory { space, used: 0 }
+    }
+
+    /// Copies `value` into the page and returns its address.
+    fn put<T: Copy>(&mut self, value: T) -> u64 {
+        let address = (USER_START + self.used).align_up(8u64);
+        self.used = (address - USER_START) + size_of::<T>() as u64;
+        assert!(self.used <= 4096, "user page full");
+
+        unsafe { address.as_mut_ptr::<T>().write(value) };
+        address.as_u64()
+    }
+
+    /// Copies `bytes` into the page.
+    fn bytes(&mut self, bytes: &[u8]) -> &'static mut [u8] {
+        let address = USER_START + self.used;
+        self.used += bytes.len() as u64;
+        assert!(self.used <= 4096, "user page full");
+
+        let copy = unsafe { slice::from_raw_parts_mut(address.as_mut_ptr(), bytes.len()) };
+        copy.copy_from_slice(bytes);
+        copy
+    }
+
+    fn read<T: Copy>(&self, address: u64) -> T {
+        unsafe { (address as *const T).read() }
+    }
+}
+
+impl Drop for UserMemory {
+    fn drop(&mut self) {
+        assert!(self.space.is_active());
+        unsafe { AddressSpace::kernel().activate() };
+    }
+}
+
+fn syscall(number: u64, args: [u64; 6]) -> i64 {
+    let mut frame = SyscallFrame {
+        number,
+        args,
+        ..SyscallFrame::default()
+    };
+    dispatch(&mut frame)
+}
+
+#[test_case]
+fn write_checks_descriptor_and_buffer() {
+    let mut user = UserMemory::new();
+    let message = user.bytes(b"syscall write\n");
+    let mut args = [7, message.as_ptr() as u64, message.len() as u64, 0, 0, 0];
+
+    assert_eq!(syscall(number::WRITE, args), -errno::EBADF);
+
+    args[0] = libsys::STDOUT;
+    assert_eq!(syscall(number::WRITE, args), message.len() as i64);
+
+    args[1] = 0;
+    assert_eq!(syscall(number::WRITE, args), -errno::EFAULT);
+
+    // Past the end of the mapped page.
+    args[1] = (USER_START + 4090u64).as_u64();
+    assert_eq!(syscall(number::WRITE, args), -errno::EFAULT);
+}
+
+#[test_case]
+fn read_only_pages_are_not_written() {
+    let mut user = UserMemory::new();
+    let page = Page::containing_address(USER_START + 4096u64);
+    user.space.map_user(page, PageTableFlags::empty()).unwrap();
+    let read_only = page.start_address().as_u64();
+
+    // Reading from it is fine.
+    assert_eq!(
+        syscall(number::WRITE, [libsys::STDOUT, read_only, 1, 0, 0, 0]),
+        1
+    );
+
+    assert_eq!(
+        syscall(
+            number::CLOCK_GETTIME,
+            [libsys::time::CLOCK_MONOTONIC, read_only, 0, 0, 0, 0]
+        ),
+        -errno::EFAULT
+    );
+    // A buffer that starts on the writable page and runs into the read-only one.
+    let straddling = read_only - 8;
+    assert_eq!(
+        syscall(
+            number::CLOCK_GETTIME,
+            [libsys::time::CLOCK_MONOTONIC, straddling, 0, 0, 0, 0]
+        ),
+        -errno::EFAULT
+    );
+}
+
+#[test_case]
+fn kernel_buffers_are_efault() {
+    let _user = UserMemory::new();
+    let message = b"kernel memory\n";
+
+    assert_eq!(
+        syscall(
+            number::WRITE,
+            [
+                libsys::STDOUT,
+                message.as_ptr() as u64,
+                message.len() as u64,
+                0,
+                0,
+                0
+            ]
+        ),
+        -errno::EFAULT
+    );
+}
+
+#[test_case]
+fn writev_writes_every_buffer() {
+    let mut user = UserMemory::new();
+    let buffers = [
+        IoVec::new(user.bytes(b"syscall ")),
+        IoVec::new(&[]),
+        IoVec::new(user.bytes(b"writev\n")),
+    ];
+    let buffers = user.put(buffers);
+    let mut args = [libsys::STDOUT, buffers, 3, 0, 0, 0];
+
+    assert_eq!(syscall(number::WRITEV, args), 15);
+
+    args[2] = libsys::uio::IOV_MAX as u64 + 1;
+    assert_eq!(syscall(number::WRITEV, args), -errno::EINVAL);
+
+    // One of the buffers is in the kernel.
+    let kernel = user.put([IoVec::new(b"kernel memory\n")]);
+    assert_eq!(
+        syscall(number::WRITEV, [libsys::STDOUT, kernel, 1, 0, 0, 0]),
+        -errno::EFAULT
+    );
+}
+
+#[test_case]
+fn submit_completes_every_entry() {
+    let mut user = UserMemory::new();
+    let entries = [
+        Submission::write(libsys::STDOUT, user.bytes(b"syscall submit\n")),
+        Submission::write(7, user.bytes(b"bad descriptor")),
+        Submission::read(libsys::STDOUT, &mut []),
+    ];
+    let entries = user.put(entries);
+
+    assert_eq!(syscall(number::SUBMIT, [entries, 3, 0, 0, 0, 0]), 3);
+
+    let entries: [Submission; 3] = user.read(entries);
+    assert_eq!(entries[0].result, 15);
+    assert_eq!(entries[1].result, -errno::EBADF);
+    assert_eq!(entries[2].result, -errno::EBADF);
+}
+
+#[test_case]
+fn futex_wait_compares_the_word() {
+    let mut user = UserMemory::new();
+    let word = user.put(1u32);
+    let mut args = [word, libsys::futex::FUTEX_WAIT, 2, 0, 0, 0];
+
+    assert_eq!(syscall(number::FUTEX, args), -errno::EAGAIN);
+
+    args[1] = 7;
+    assert_eq!(syscall(number::FUTEX, args), -errno::EINVAL);
+
+    args[1] = libsys::futex::FUTEX_WAKE;
+    assert_eq!(syscall(number::FUTEX, args), -errno::ESRCH);
+}
+
+#[test_case]
+fn clock_gettime_writes_the_monotonic_clock() {
+    let mut user = UserMemory::new();
+    let timespec = user.put(Timespec {
+        tv_sec: -1,
+        tv_nsec: -1,
+    });
+    let mut args = [libsys::time::CLOCK_MONOTONIC, timespec, 0, 0, 0, 0];
+
+    assert_eq!(syscall(number::CLOCK_GETTIME, args), 0);
+    let timespec: Timespec = user.read(timespec);
+    assert!(timespec.tv_sec >= 0);
+    assert!((0..1_000_000_000).contains(&timespec.tv_nsec));
+
+    args[0] = 7;
+    assert_eq!(syscall(number::CLOCK_GETTIME, args), -errno::EINVAL);
+}
+
+#[test_case]
+fn nanosleep_checks_the_duration() {
+    let mut user = UserMemory::new();
+    let mut sleep = |tv_nsec| {
+        let timespec = user.put(Timespec { tv_sec: 0, tv_nsec });
+        syscall(number::NANOSLEEP, [timespec, 0, 0, 0, 0, 0])
+    };
+
+    assert_eq!(sleep(1_000_000_000), -errno::EINVAL);
+    assert_eq!(sleep(-1), -errno::EINVAL);
+    assert_eq!(sleep(0), 0);
+    assert_eq!(sleep(1), -errno::ESRCH);
+
+    // A deadline past the end of time is as good as never.
+    let forever = user.put(Timespec {
+        tv_sec: i64::MAX,
+        tv_nsec: 0,
+    });
+    assert_eq!(
+        syscall(number::NANOSLEEP, [forever, 0, 0, 0, 0, 0]),
+        -errno::ESRCH
+    );
+}
+
+#[test_case]
+fn sysctl_sets_the_time_slice() {
+    use libsys::sysctl::SCHED_SLICE_TICKS;
+
+    let default = syscall(number::SYSCTL, [SCHED_SLICE_TICKS, 0, 0, 0, 0, 0]);
+    assert_eq!(default, 2);
+
+    assert_eq!(
+        syscall(number::SYSCTL, [SCHED_SLICE_TICKS, 1, 5, 0, 0, 0]),
+        default
+    );
+    assert_eq!(
+        syscall(number::SYSCTL, [SCHED_SLICE_TICKS, 0, 0, 0, 0, 0]),
+        5
+    );
+
+    assert_eq!(
+        syscall(number::SYSCTL, [SCHED_SLICE_TICKS, 1, 0, 0, 0, 0]),
+        -errno::EINVAL
+    );
+    assert_eq!(
+        syscall(number::SYSCTL, [SCHED_SLICE_TICKS, 1, 1 << 32, 0, 0, 0]),
+        -errno::EINVAL
+    );
+    assert_eq!(syscall(number::SYSCTL, [7, 0, 0, 0, 0, 0]), -errno::EINVAL);
+
+    syscall(
+        number::SYSCTL,
+        [SCHED_SLICE_TICKS, 1, default as u64, 0, 0, 0],
+    );
+}
+
+#[test_case]
+fn input_ring_needs_a_process() {
+    assert_eq!(syscall(number::INPUT_RING, [0; 6]), -errno::ESRCH);
+}
+
+#[test_case]
+fn surface_flush_checks_the_rects() {
+    let mut user = UserMemory::new();
+    let rects = user.put([libsys::gfx::Rect::default(); 2]);
+
+    assert_eq!(
+        syscall(number::SURFACE_FLUSH, [rects, 65, 0, 0, 0, 0]),
+        -errno::EINVAL
+    );
+    assert_eq!(
+        syscall(number::SURFACE_FLUSH, [0, 1, 0, 0, 0, 0]),
+        -errno::EFAULT
+    );
+    assert_eq!(
+        syscall(number::SURFACE_FLUSH, [rects, 2, 0, 0, 0, 0]),
+        -errno::ESRCH
+    );
+}
@@ -0,0 +1,119 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use kernel::thread::{self, State, ThreadId};
+use spin::Mutex;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+fn count_run() {
+    RUNS.fetch_add(1, Ordering::SeqCst);
+}
+
+#[test_case]
+fn spawned_threads_run_when_the_boot_thread_yields() {
+    let before = RUNS.load(Ordering::SeqCst);
+    let id = thread::spawn(count_run);
+
+    assert_eq!(thread::state(id), Some(State::Ready));
+    thread::yield_now();
+
+    assert_eq!(RUNS.load(Ordering::SeqCst), before + 1);
+    assert_eq!(thread::state(id), None);
+    assert_eq!(thread::current(), ThreadId::BOOT);
+}
+
+static ORDER: Mutex<Vec<&str>> = Mutex::new(Vec::new());
+
+fn ping() {
+    for _ in 0..2 {
+        ORDER.lock().push("ping");
+        thread::yield_now();
+    }
+}
+
+fn pong() {
+    for _ in 0..2 {
+        ORDER.lock().push("pong");
+        thread::yield_now();
+    }
+}
+
+#[test_case]
+fn yielding_threads_take_turns() {
+    ORDER.lock().clear();
+    let ping = thread::spawn(ping);
+    let pong = thread::spawn(pong);
+
+    while thread::state(ping).is_some() || thread::state(pong).is_some() {
+        thread::yield_now();
+    }
+
+    assert_eq!(*ORDER.lock(), ["ping", "pong", "ping", "pong"]);
+}
+
+static SLEEPER_WOKEN: AtomicU64 = AtomicU64::new(0);
+
+fn sleeper() {
+    thread::block_current();
+    SLEEPER_WOKEN.store(1, Ordering::SeqCst);
+}
+
+#[test_case]
+fn blocked_threads_only_run_once_woken() {
+    let id = thread::spawn(sleeper);
+
+    thread::yield_now();
+    assert_eq!(thread::state(id), Some(State::Blocked));
+    thread::yield_now();
+    assert_eq!(SLEEPER_WOKEN.load(Ordering::SeqCst), 0);
+
+    thread::wake(id);
+    thread::yield_now();
+    assert_eq!(SLEEPER_WOKEN.load(Ordering::SeqCst), 1);
+    assert_eq!(thread::state(id), None);
+}
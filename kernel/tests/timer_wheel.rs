@@ -0,0 +1,120 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::panic::PanicInfo;
+use kernel::time::wheel::TimerWheel;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+fn expire(wheel: &mut TimerWheel<u32>, now: u64) -> Vec<u32> {
+    let mut expired = Vec::new();
+    wheel.expire(now, |item| expired.push(item));
+    expired.sort_unstable();
+    expired
+}
+
+#[test_case]
+fn entries_expire_once_their_deadline_is_reached() {
+    let mut wheel = TimerWheel::new(10);
+    wheel.insert(12, 1);
+    wheel.insert(11, 2);
+    wheel.insert(12, 3);
+
+    assert_eq!(expire(&mut wheel, 10), []);
+    assert_eq!(expire(&mut wheel, 11), [2]);
+    assert_eq!(expire(&mut wheel, 12), [1, 3]);
+    assert!(wheel.is_empty());
+}
+
+#[test_case]
+fn far_deadlines_wait_for_later_turns() {
+    let mut wheel = TimerWheel::new(0);
+    wheel.insert(5, 1);
+    wheel.insert(5 + 64, 2);
+    wheel.insert(5 + 3 * 64, 3);
+
+    assert_eq!(expire(&mut wheel, 5), [1]);
+    assert_eq!(expire(&mut wheel, 64), []);
+    assert_eq!(expire(&mut wheel, 100), [2]);
+    assert_eq!(wheel.len(), 1);
+
+    // A jump of several turns still reaches every slot.
+    assert_eq!(expire(&mut wheel, 1000), [3]);
+    assert_eq!(wheel.now(), 1000);
+}
+
+#[test_case]
+fn past_deadlines_expire_next() {
+    let mut wheel = TimerWheel::new(50);
+    wheel.insert(3, 1);
+
+    assert_eq!(expire(&mut wheel, 51), [1]);
+}
+
+#[test_case]
+fn removed_entries_never_expire() {
+    let mut wheel = TimerWheel::new(0);
+    wheel.insert(1, 1);
+    wheel.insert(2, 2);
+    wheel.remove(|&item| item == 1);
+
+    assert_eq!(wheel.len(), 1);
+    assert_eq!(expire(&mut wheel, 2), [2]);
+}
+
+#[test_case]
+fn entries_are_removed_where_they_were_filed() {
+    let mut wheel = TimerWheel::new(10);
+    let late = wheel.insert(3, 1);
+    let far = wheel.insert(20 + 64, 2);
+    wheel.insert(20, 3);
+
+    assert_eq!(late, 11);
+    assert_eq!(wheel.remove_at(late, |&item| item == 1), Some(1));
+    assert_eq!(wheel.remove_at(late, |&item| item == 1), None);
+    // Same slot, different turn of the wheel.
+    assert_eq!(wheel.remove_at(20, |&item| item == 2), None);
+    assert_eq!(wheel.remove_at(far, |&item| item == 2), Some(2));
+
+    assert_eq!(wheel.len(), 1);
+    assert_eq!(expire(&mut wheel, 100), [3]);
+}
@@ -0,0 +1,158 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{format, string::String, vec::Vec};
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::panic::PanicInfo;
+use kernel::tui::{
+    App, Event, Key, Rect, Screen,
+    input::{self, Routed},
+    memory_browser::MemoryBrowser,
+    widget::{self, List},
+};
+use x86_64::VirtAddr;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+fn items(count: usize) -> Vec<String> {
+    (0..count).map(|index| format!("item {}", index)).collect()
+}
+
+#[test_case]
+fn boxes_are_drawn_with_their_title() {
+    let mut screen = Screen::new(12, 3);
+    let area = screen.area();
+    widget::draw_box(&mut screen, area, "Title");
+
+    assert_eq!(screen.row(0), "┌ Title ───┐");
+    assert_eq!(screen.row(1), "│          │");
+    assert_eq!(screen.row(2), "└──────────┘");
+}
+
+#[test_case]
+fn text_is_clipped_to_its_width() {
+    let mut screen = Screen::new(10, 1);
+
+    assert_eq!(screen.text(2, 0, 4, "abc\tdefgh", false), 4);
+    assert_eq!(screen.row(0), "  abc.");
+}
+
+#[test_case]
+fn lists_scroll_to_keep_the_selection_visible() {
+    let mut screen = Screen::new(10, 3);
+    let mut list = List::new(items(10));
+    let area = screen.area();
+
+    list.draw(&mut screen, area);
+    assert!(list.handle_key(Key::PageDown));
+    assert!(list.handle_key(Key::Down));
+    screen.clear();
+    list.draw(&mut screen, area);
+
+    assert_eq!(list.selected(), Some(4));
+    assert_eq!(screen.row(0), "item 2");
+    assert_eq!(screen.row(2), "item 4");
+    assert!(screen.cell(0, 2).unwrap().reverse);
+    assert!(!screen.cell(0, 1).unwrap().reverse);
+
+    assert!(list.handle_key(Key::End));
+    assert_eq!(list.selected_item(), Some("item 9"));
+    assert!(!list.handle_key(Key::Char('x')));
+
+    list.set_items(items(3));
+    assert_eq!(list.selected(), Some(2));
+    list.set_items(Vec::new());
+    assert_eq!(list.selected(), None);
+}
+
+#[test_case]
+fn screens_render_as_ansi() {
+    let mut screen = Screen::new(3, 2);
+    screen.text(0, 0, 3, "ab", false);
+    screen.bar(Rect::new(0, 1, 3, 1), 1, "c");
+
+    let mut output = String::new();
+    screen.render(&mut output).unwrap();
+
+    assert_eq!(output, "\x1b[Hab \r\n\x1b[7mc  \x1b[0m");
+}
+
+#[test_case]
+fn keys_go_to_the_focused_tool() {
+    let mut first = input::register("first").unwrap();
+    let mut second = input::register("second").unwrap();
+    assert!(input::register("first").is_err());
+    assert_eq!(input::tools(), ["first", "second"]);
+
+    assert_eq!(input::route(Key::Char('a')), Routed::Console);
+
+    assert_eq!(input::route(Key::Function(2)), Routed::Tool);
+    assert_eq!(input::route(Key::Char('b')), Routed::Tool);
+    assert_eq!(input::focused(), Some("second"));
+    assert_eq!(second.try_next(), Some(Event::Focus));
+    assert_eq!(second.try_next(), Some(Event::Key(Key::Char('b'))));
+    assert_eq!(first.try_next(), None);
+
+    // F12 doesn't belong to any tool, so it goes to the focused one.
+    assert_eq!(input::route(Key::Function(12)), Routed::Tool);
+    assert_eq!(second.try_next(), Some(Event::Key(Key::Function(12))));
+
+    assert_eq!(input::route(Key::Escape), Routed::Released);
+    assert_eq!(input::focused(), None);
+    assert_eq!(input::route(Key::Char('c')), Routed::Console);
+    assert_eq!(second.try_next(), None);
+}
+
+#[test_case]
+fn the_memory_browser_scrolls_by_rows() {
+    let mut browser = MemoryBrowser::at(VirtAddr::new(0x1008));
+    assert_eq!(browser.address(), VirtAddr::new(0x1000));
+
+    browser.handle_key(Key::Down);
+    assert_eq!(browser.address(), VirtAddr::new(0x1010));
+
+    for key in [Key::Char('g'), Key::Char('2'), Key::Char('f'), Key::Enter] {
+        browser.handle_key(key);
+    }
+    assert_eq!(browser.address(), VirtAddr::new(0x20));
+
+    browser.handle_key(Key::Up);
+    browser.handle_key(Key::Up);
+    browser.handle_key(Key::Up);
+    assert_eq!(browser.address(), VirtAddr::new(0));
+}
@@ -0,0 +1,176 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{collections::VecDeque, vec, vec::Vec};
+use bootloader_api::{
+    BootInfo,
+    config::{BootloaderConfig, Mapping},
+    entry_point,
+};
+use core::{panic::PanicInfo, time::Duration};
+use kernel::serial::xmodem::{self, Link, XmodemError, crc16};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x20000000000));
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+
+/// A sender that answers every byte the receiver writes with the next burst of the script. Reads time out
+/// as soon as the current burst is used up.
+struct ScriptedSender {
+    bursts: VecDeque<Vec<u8>>,
+    pending: VecDeque<u8>,
+    received: Vec<u8>,
+}
+
+impl ScriptedSender {
+    fn new(bursts: Vec<Vec<u8>>) -> Self {
+        ScriptedSender {
+            bursts: bursts.into(),
+            pending: VecDeque::new(),
+            received: Vec::new(),
+        }
+    }
+}
+
+impl Link for ScriptedSender {
+    fn read_byte(&mut self, _timeout: Duration) -> Option<u8> {
+        self.pending.pop_front()
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.received.push(byte);
+
+        if let Some(burst) = self.bursts.pop_front() {
+            self.pending.extend(burst);
+        }
+    }
+}
+
+fn crc_block(number: u8, data: &[u8]) -> Vec<u8> {
+    let header = if data.len() == 1024 { STX } else { SOH };
+
+    let mut block = vec![header, number, !number];
+    block.extend_from_slice(data);
+    block.extend_from_slice(&crc16(data).to_be_bytes());
+    block
+}
+
+fn sum_block(number: u8, data: &[u8]) -> Vec<u8> {
+    let mut block = vec![SOH, number, !number];
+    block.extend_from_slice(data);
+    block.push(data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)));
+    block
+}
+
+#[test_case]
+fn crc16_matches_the_xmodem_check_value() {
+    assert_eq!(crc16(b"123456789"), 0x31C3);
+}
+
+#[test_case]
+fn receives_short_and_1k_blocks() {
+    let first = [0x11; 128];
+    let second = [0x22; 1024];
+    let mut sender =
+        ScriptedSender::new(vec![crc_block(1, &first), crc_block(2, &second), vec![EOT]]);
+
+    let mut progress = Vec::new();
+    let file = xmodem::receive(&mut sender, |received| progress.push(received)).unwrap();
+
+    assert_eq!(file.len(), 128 + 1024);
+    assert_eq!(&file[..128], &first);
+    assert_eq!(&file[128..], &second);
+    assert_eq!(progress, [128, 128 + 1024]);
+    assert_eq!(sender.received, [b'C', ACK, ACK, ACK]);
+}
+
+#[test_case]
+fn corrupted_block_is_rejected_and_resent() {
+    let data = [0x33; 128];
+    let mut corrupted = crc_block(1, &data);
+    corrupted[10] ^= 0xFF;
+
+    let mut sender = ScriptedSender::new(vec![corrupted, crc_block(1, &data), vec![EOT]]);
+    let file = xmodem::receive(&mut sender, |_| {}).unwrap();
+
+    assert_eq!(file, data);
+    assert_eq!(sender.received, [b'C', NAK, ACK, ACK]);
+}
+
+#[test_case]
+fn repeated_block_is_acknowledged_once() {
+    let data = [0x44; 128];
+    let mut sender = ScriptedSender::new(vec![crc_block(1, &data), crc_block(1, &data), vec![EOT]]);
+
+    let file = xmodem::receive(&mut sender, |_| {}).unwrap();
+    assert_eq!(file, data);
+}
+
+#[test_case]
+fn falls_back_to_checksums() {
+    let data = [0x55; 128];
+    let mut sender =
+        ScriptedSender::new(vec![vec![], vec![], vec![], sum_block(1, &data), vec![EOT]]);
+
+    let file = xmodem::receive(&mut sender, |_| {}).unwrap();
+
+    assert_eq!(file, data);
+    assert_eq!(sender.received, [b'C', b'C', b'C', NAK, ACK, ACK]);
+}
+
+#[test_case]
+fn sender_can_cancel() {
+    let mut sender = ScriptedSender::new(vec![vec![CAN, CAN]]);
+    assert_eq!(
+        xmodem::receive(&mut sender, |_| {}),
+        Err(XmodemError::Cancelled)
+    );
+}
+
+#[test_case]
+fn skipped_block_aborts() {
+    let mut sender = ScriptedSender::new(vec![crc_block(2, &[0; 128])]);
+
+    assert_eq!(
+        xmodem::receive(&mut sender, |_| {}),
+        Err(XmodemError::OutOfSequence)
+    );
+    assert_eq!(sender.received, [b'C', CAN, CAN]);
+}
@@ -0,0 +1,947 @@
+//! System call interface of the kernel, for programs running in ring 3.
+//!
+//! The numbers and error codes in `number` and `errno` are the ABI shared with the kernel and don't change
+//! once assigned. A syscall is made with the `syscall` instruction: the number goes in RAX, up to six
+//! arguments in RDI, RSI, RDX, R10, R8 and R9, and the result comes back in RAX. Negative results are
+//! negated error numbers.
+
+#![no_std]
+
+use core::{arch::asm, fmt, sync::atomic::AtomicU32};
+
+/// Syscall numbers.
+pub mod number {
+    pub const WRITE: u64 = 0;
+    pub const EXIT: u64 = 1;
+    pub const GETPID: u64 = 2;
+    pub const SETRLIMIT: u64 = 3;
+    pub const GETRLIMIT: u64 = 4;
+    pub const FORK: u64 = 5;
+    pub const EXECVE: u64 = 6;
+    pub const WAIT: u64 = 7;
+    pub const LOG: u64 = 8;
+    pub const BRK: u64 = 9;
+    pub const MMAP: u64 = 10;
+    pub const MUNMAP: u64 = 11;
+    pub const TRACE: u64 = 12;
+    pub const KILL: u64 = 13;
+    pub const SIGACTION: u64 = 14;
+    pub const SIGRETURN: u64 = 15;
+    pub const READ: u64 = 16;
+    pub const OPEN: u64 = 17;
+    pub const CLOSE: u64 = 18;
+    pub const WRITEV: u64 = 19;
+    pub const SUBMIT: u64 = 20;
+    pub const ARCH_PRCTL: u64 = 21;
+    pub const PIPE: u64 = 22;
+    pub const NICE: u64 = 23;
+    pub const FUTEX: u64 = 24;
+    pub const CLOCK_GETTIME: u64 = 25;
+    pub const NANOSLEEP: u64 = 26;
+    pub const SYSCTL: u64 = 27;
+    pub const INPUT_RING: u64 = 28;
+    pub const SURFACE_OPEN: u64 = 29;
+    pub const SURFACE_FLUSH: u64 = 30;
+}
+
+/// Error numbers, returned negated by the kernel.
+pub mod errno {
+    pub const EPERM: i64 = 1;
+    pub const ENOENT: i64 = 2;
+    pub const ESRCH: i64 = 3;
+    pub const EINTR: i64 = 4;
+    pub const ENOEXEC: i64 = 8;
+    pub const EBADF: i64 = 9;
+    pub const ECHILD: i64 = 10;
+    pub const EAGAIN: i64 = 11;
+    pub const ENOMEM: i64 = 12;
+    pub const EFAULT: i64 = 14;
+    pub const EBUSY: i64 = 16;
+    pub const ENODEV: i64 = 19;
+    pub const EINVAL: i64 = 22;
+    pub const EMFILE: i64 = 24;
+    pub const EROFS: i64 = 30;
+    pub const EPIPE: i64 = 32;
+    pub const ENOSYS: i64 = 38;
+}
+
+/// Resource limits, see `setrlimit`.
+pub mod rlimit {
+    /// Bytes of heap (`brk` and anonymous mappings).
+    pub const HEAP: u64 = 0;
+    /// Open file descriptors.
+    pub const OPEN_FILES: u64 = 1;
+    /// CPU time in milliseconds.
+    pub const CPU_TIME: u64 = 2;
+    /// Child processes that exist at the same time.
+    pub const CHILDREN: u64 = 3;
+    /// Records written to the kernel log per second.
+    pub const LOG_RATE: u64 = 4;
+
+    /// No limit.
+    pub const INFINITY: u64 = u64::MAX;
+
+    /// A soft limit, which is what gets enforced, and a hard limit, the most the soft limit can be raised
+    /// to. Hard limits can only be lowered.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[repr(C)]
+    pub struct Rlimit {
+        pub soft: u64,
+        pub hard: u64,
+    }
+}
+
+/// Levels of kernel log records, see `log`.
+pub mod log {
+    pub const ERROR: u64 = 0;
+    pub const WARNING: u64 = 1;
+    pub const INFO: u64 = 2;
+    pub const DEBUG: u64 = 3;
+}
+
+/// Access modes of `open`.
+pub mod fcntl {
+    pub const O_RDONLY: u64 = 0;
+    pub const O_WRONLY: u64 = 1;
+    pub const O_RDWR: u64 = 2;
+}
+
+/// Batched I/O, see `writev` and `submit`.
+pub mod uio {
+    /// Most buffers in one `writev`, and most entries in one `submit`.
+    pub const IOV_MAX: usize = 64;
+
+    /// One buffer of a `writev`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[repr(C)]
+    pub struct IoVec {
+        pub base: u64,
+        pub len: u64,
+    }
+
+    impl IoVec {
+        pub fn new(bytes: &[u8]) -> Self {
+            IoVec {
+                base: bytes.as_ptr() as u64,
+                len: bytes.len() as u64,
+            }
+        }
+    }
+
+    /// Operations of a `Submission`.
+    pub const OP_READ: u64 = 0;
+    pub const OP_WRITE: u64 = 1;
+
+    /// One operation of a `submit`, which the kernel completes by filling in `result`: the number of
+    /// bytes transferred, or a negated error number.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[repr(C)]
+    pub struct Submission {
+        pub op: u64,
+        pub fd: u64,
+        pub address: u64,
+        pub len: u64,
+        pub result: i64,
+    }
+
+    impl Submission {
+        pub fn write(fd: u64, bytes: &[u8]) -> Self {
+            Submission {
+                op: OP_WRITE,
+                fd,
+                address: bytes.as_ptr() as u64,
+                len: bytes.len() as u64,
+                result: 0,
+            }
+        }
+
+        pub fn read(fd: u64, buffer: &mut [u8]) -> Self {
+            Submission {
+                op: OP_READ,
+                fd,
+                address: buffer.as_mut_ptr() as u64,
+                len: buffer.len() as u64,
+                result: 0,
+            }
+        }
+    }
+}
+
+/// Codes of `arch_prctl`, with Linux's values.
+pub mod prctl {
+    pub const ARCH_SET_GS: u64 = 0x1001;
+    pub const ARCH_SET_FS: u64 = 0x1002;
+    pub const ARCH_GET_FS: u64 = 0x1003;
+    pub const ARCH_GET_GS: u64 = 0x1004;
+}
+
+/// Protection and flags of memory mappings, see `mmap`.
+pub mod mman {
+    pub const PROT_NONE: u64 = 0;
+    pub const PROT_READ: u64 = 1 << 0;
+    pub const PROT_WRITE: u64 = 1 << 1;
+    pub const PROT_EXEC: u64 = 1 << 2;
+
+    pub const MAP_SHARED: u64 = 0x01;
+    pub const MAP_PRIVATE: u64 = 0x02;
+    pub const MAP_FIXED: u64 = 0x10;
+    pub const MAP_ANONYMOUS: u64 = 0x20;
+}
+
+/// Signal numbers and actions, see `kill` and `sigaction`.
+pub mod signal {
+    pub const SIGHUP: u64 = 1;
+    pub const SIGINT: u64 = 2;
+    pub const SIGQUIT: u64 = 3;
+    pub const SIGILL: u64 = 4;
+    pub const SIGTRAP: u64 = 5;
+    pub const SIGABRT: u64 = 6;
+    pub const SIGBUS: u64 = 7;
+    pub const SIGFPE: u64 = 8;
+    /// Always terminates; can't be handled or ignored.
+    pub const SIGKILL: u64 = 9;
+    pub const SIGUSR1: u64 = 10;
+    pub const SIGSEGV: u64 = 11;
+    pub const SIGUSR2: u64 = 12;
+    pub const SIGPIPE: u64 = 13;
+    pub const SIGALRM: u64 = 14;
+    pub const SIGTERM: u64 = 15;
+    pub const SIGCHLD: u64 = 17;
+    /// Sent every second a process runs past its soft `CPU_TIME` limit.
+    pub const SIGXCPU: u64 = 24;
+
+    /// Handler values of `sigaction` that aren't functions.
+    pub const SIG_DFL: u64 = 0;
+    pub const SIG_IGN: u64 = 1;
+
+    /// What a signal does to the process that receives it.
+    #[derive(Debug, Clone, Copy)]
+    pub enum Action {
+        /// Terminate the process, which then exits with code `128 + signal`.
+        Default,
+        Ignore,
+        /// Call the function with the signal number, then resume where the process was.
+        Handler(extern "C" fn(u64)),
+    }
+}
+
+/// Kernel settings, see `sysctl`.
+pub mod sysctl {
+    /// Timer ticks in a time slice at a process's highest scheduler level, 1 to 100. Each level down gets
+    /// twice as many.
+    pub const SCHED_SLICE_TICKS: u64 = 0;
+}
+
+/// Operations of `futex`.
+pub mod futex {
+    /// Sleep while the word holds the expected value.
+    pub const FUTEX_WAIT: u64 = 0;
+    /// Wake sleepers.
+    pub const FUTEX_WAKE: u64 = 1;
+}
+
+/// Clocks of `clock_gettime`, and the time values it and `nanosleep` use.
+pub mod time {
+    /// Time since the Unix epoch. Unavailable with `ENODEV` until the kernel has read the wall clock.
+    pub const CLOCK_REALTIME: u64 = 0;
+    /// Time since boot, which never goes backwards.
+    pub const CLOCK_MONOTONIC: u64 = 1;
+
+    /// Like `struct timespec`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    #[repr(C)]
+    pub struct Timespec {
+        pub tv_sec: i64,
+        /// Nanoseconds, below a second.
+        pub tv_nsec: i64,
+    }
+
+    impl From<core::time::Duration> for Timespec {
+        fn from(duration: core::time::Duration) -> Self {
+            Timespec {
+                tv_sec: duration.as_secs() as i64,
+                tv_nsec: i64::from(duration.subsec_nanos()),
+            }
+        }
+    }
+}
+
+/// Input events shared with the kernel through a page, see `input_ring`.
+pub mod input {
+    use core::{
+        cell::UnsafeCell,
+        ptr,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    /// Events the ring holds.
+    pub const RING_LEN: usize = 128;
+
+    /// Kinds of `RingEvent`.
+    pub const KEY_PRESS: u8 = 0;
+    pub const KEY_RELEASE: u8 = 1;
+    pub const MOUSE_MOVE: u8 = 2;
+    pub const BUTTON: u8 = 3;
+    pub const SCROLL: u8 = 4;
+
+    /// Bits of `RingEvent::modifiers`.
+    pub const MOD_SHIFT: u8 = 1 << 0;
+    pub const MOD_CTRL: u8 = 1 << 1;
+    pub const MOD_ALT: u8 = 1 << 2;
+
+    /// One event from an input device.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    #[repr(C)]
+    pub struct RingEvent {
+        pub kind: u8,
+        /// `MOD_*` bits held, for `KEY_PRESS`.
+        pub modifiers: u8,
+        /// The device, numbered by the kernel in the order devices appear.
+        pub device: u16,
+        /// The key for key events, in the order of `pc_keyboard::KeyCode`. For `BUTTON`, 0 for the left
+        /// button, 1 for the right one and 2 for the middle one.
+        pub code: u32,
+        /// Movement for `MOUSE_MOVE`, with `y` growing downwards. Otherwise `x` alone is used: the
+        /// character the layout makes of the key for `KEY_PRESS` (-1 if none), 1 if pressed and 0 if
+        /// released for `BUTTON`, and the wheel movement for `SCROLL`, positive towards the user.
+        pub x: i32,
+        pub y: i32,
+    }
+
+    /// A single-producer, single-consumer queue of events in a page shared with the kernel, which
+    /// `push`es at `head` while the process `pop`s at `tail`. Both counters only grow, wrapping around,
+    /// and index `events` modulo `RING_LEN`. Events that arrive while the ring is full are counted in
+    /// `dropped` instead.
+    ///
+    /// `head` is also the doorbell: the kernel wakes `futex_wait`ers on it after every event, so `wait`
+    /// sleeps without polling.
+    #[repr(C)]
+    pub struct InputRing {
+        pub head: AtomicU32,
+        pub tail: AtomicU32,
+        pub dropped: AtomicU32,
+        events: [UnsafeCell<RingEvent>; RING_LEN],
+    }
+
+    // The kernel maps the ring in a single page.
+    const _: () = assert!(size_of::<InputRing>() <= 4096);
+
+    impl InputRing {
+        pub const fn new() -> Self {
+            InputRing {
+                head: AtomicU32::new(0),
+                tail: AtomicU32::new(0),
+                dropped: AtomicU32::new(0),
+                events: [const {
+                    UnsafeCell::new(RingEvent {
+                        kind: 0,
+                        modifiers: 0,
+                        device: 0,
+                        code: 0,
+                        x: 0,
+                        y: 0,
+                    })
+                }; RING_LEN],
+            }
+        }
+
+        /// Queues `event`, or counts it as dropped if the ring is full. Returns whether it was queued.
+        /// Only the kernel pushes.
+        pub fn push(&self, event: RingEvent) -> bool {
+            let head = self.head.load(Ordering::Relaxed);
+            if head.wrapping_sub(self.tail.load(Ordering::Acquire)) as usize >= RING_LEN {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+
+            // The slot is free until `head` moves past it, and the reader doesn't look at it before.
+            unsafe { ptr::write_volatile(self.events[head as usize % RING_LEN].get(), event) };
+            self.head.store(head.wrapping_add(1), Ordering::Release);
+            true
+        }
+
+        /// Takes the oldest queued event. Only one reader may pop.
+        pub fn pop(&self) -> Option<RingEvent> {
+            let tail = self.tail.load(Ordering::Relaxed);
+            if tail == self.head.load(Ordering::Acquire) {
+                return None;
+            }
+
+            let event = unsafe { ptr::read_volatile(self.events[tail as usize % RING_LEN].get()) };
+            self.tail.store(tail.wrapping_add(1), Ordering::Release);
+            Some(event)
+        }
+
+        /// Sleeps until an event is queued, returning right away if one is. Like `futex_wait`, it may
+        /// return early, e.g. when a signal arrives.
+        pub fn wait(&self) -> Result<(), super::Errno> {
+            let tail = self.tail.load(Ordering::Relaxed);
+            match super::futex_wait(&self.head, tail) {
+                Err(super::Errno(super::errno::EAGAIN)) => Ok(()),
+                result => result,
+            }
+        }
+    }
+
+    impl Default for InputRing {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+}
+
+/// Windows drawn into by processes, see `surface_open`.
+pub mod gfx {
+    /// Most rectangles in one `surface_flush`.
+    pub const MAX_DAMAGE_RECTS: usize = 64;
+
+    /// Pixel formats of `SurfaceInfo`.
+    pub const FORMAT_RGB: u32 = 0;
+    pub const FORMAT_BGR: u32 = 1;
+    /// One byte of gray per pixel.
+    pub const FORMAT_U8: u32 = 2;
+    pub const FORMAT_UNKNOWN: u32 = 3;
+
+    /// A rectangle of pixels, whose top left corner is at `x`, `y`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    #[repr(C)]
+    pub struct Rect {
+        pub x: u32,
+        pub y: u32,
+        pub width: u32,
+        pub height: u32,
+    }
+
+    /// The layout of a surface's pixels: rows of `width` pixels are `stride` pixels apart, each pixel
+    /// taking `bytes_per_pixel` bytes in the `FORMAT_*` of `format`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    #[repr(C)]
+    pub struct SurfaceInfo {
+        pub byte_len: u64,
+        pub width: u32,
+        pub height: u32,
+        pub stride: u32,
+        pub bytes_per_pixel: u32,
+        pub format: u32,
+    }
+}
+
+/// File descriptors every program starts with.
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// An error number returned by a syscall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub i64);
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "errno {}", self.0)
+    }
+}
+
+fn result(value: i64) -> Result<u64, Errno> {
+    if value < 0 {
+        Err(Errno(-value))
+    } else {
+        Ok(value as u64)
+    }
+}
+
+/// Makes a raw syscall with up to three arguments.
+///
+/// # Safety
+///
+/// The arguments must be valid for the syscall, e.g. pointers must point to memory of the given length.
+pub unsafe fn syscall3(number: u64, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    let value: i64;
+
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") number as i64 => value,
+            in("rdi") arg0,
+            in("rsi") arg1,
+            in("rdx") arg2,
+            // SYSCALL saves the return address and RFLAGS in these.
+            out("rcx") _,
+            out("r11") _,
+            options(nostack),
+        );
+    }
+
+    value
+}
+
+/// Makes a raw syscall with up to six arguments.
+///
+/// # Safety
+///
+/// The arguments must be valid for the syscall, e.g. pointers must point to memory of the given length.
+pub unsafe fn syscall6(number: u64, args: [u64; 6]) -> i64 {
+    let value: i64;
+
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") number as i64 => value,
+            in("rdi") args[0],
+            in("rsi") args[1],
+            in("rdx") args[2],
+            in("r10") args[3],
+            in("r8") args[4],
+            in("r9") args[5],
+            out("rcx") _,
+            out("r11") _,
+            options(nostack),
+        );
+    }
+
+    value
+}
+
+/// Writes `bytes` to the file descriptor `fd`, returning how many were written.
+pub fn write(fd: u64, bytes: &[u8]) -> Result<usize, Errno> {
+    let written = unsafe { syscall3(number::WRITE, fd, bytes.as_ptr() as u64, bytes.len() as u64) };
+    result(written).map(|written| written as usize)
+}
+
+/// Writes the buffers to the file descriptor `fd` one after the other in a single syscall, returning how
+/// many bytes were written in total. At most `uio::IOV_MAX` buffers.
+pub fn writev(fd: u64, buffers: &[uio::IoVec]) -> Result<usize, Errno> {
+    let written = unsafe {
+        syscall3(
+            number::WRITEV,
+            fd,
+            buffers.as_ptr() as u64,
+            buffers.len() as u64,
+        )
+    };
+    result(written).map(|written| written as usize)
+}
+
+/// Runs a batch of reads and writes in a single syscall, in order, filling in the `result` of each.
+/// Returns how many entries were run. At most `uio::IOV_MAX` entries.
+///
+/// # Safety
+///
+/// The buffers of the entries must be valid for their length, and those of reads writable.
+pub unsafe fn submit(entries: &mut [uio::Submission]) -> Result<usize, Errno> {
+    let value = unsafe {
+        syscall3(
+            number::SUBMIT,
+            entries.as_mut_ptr() as u64,
+            entries.len() as u64,
+            0,
+        )
+    };
+    result(value).map(|count| count as usize)
+}
+
+/// Reads up to `buffer.len()` bytes from the file descriptor `fd`, returning how many were read, 0 at the
+/// end of the file. Blocks until a pipe has something to read; other files fail with `EAGAIN` if they
+/// have nothing to read yet.
+pub fn read(fd: u64, buffer: &mut [u8]) -> Result<usize, Errno> {
+    let read = unsafe {
+        syscall3(
+            number::READ,
+            fd,
+            buffer.as_mut_ptr() as u64,
+            buffer.len() as u64,
+        )
+    };
+    result(read).map(|read| read as usize)
+}
+
+/// Opens the file at `path` with an `fcntl` access mode, returning its file descriptor. Besides the
+/// files of the initrd, which are read-only, there are `/dev/console` and `/dev/serial`.
+pub fn open(path: &str, mode: u64) -> Result<u64, Errno> {
+    result(unsafe { syscall3(number::OPEN, path.as_ptr() as u64, path.len() as u64, mode) })
+}
+
+/// Creates a pipe, returning the descriptors of its read end and its write end. Reads block until
+/// something is written and return 0 once every write end is closed. Writing once every read end is
+/// closed fails with `EPIPE` and sends `SIGPIPE`.
+pub fn pipe() -> Result<(u64, u64), Errno> {
+    let mut fds = [0u64; 2];
+    result(unsafe { syscall3(number::PIPE, fds.as_mut_ptr() as u64, 0, 0) })
+        .map(|_| (fds[0], fds[1]))
+}
+
+/// Closes the file descriptor `fd`.
+pub fn close(fd: u64) -> Result<(), Errno> {
+    result(unsafe { syscall3(number::CLOSE, fd, 0, 0) }).map(|_| ())
+}
+
+/// Adds `increment` to the nice value of the calling process, which is clamped to -20..=19, and returns
+/// the new value. Higher values mean less CPU time when other processes are ready to run.
+pub fn nice(increment: i64) -> Result<i64, Errno> {
+    result(unsafe { syscall3(number::NICE, increment as u64, 0, 0) })
+        .map(|offset| offset as i64 - 20)
+}
+
+/// Reads the kernel setting `name`, one of the `sysctl` constants, and changes it to `value` if that isn't
+/// `None`. Returns the value it had.
+pub fn sysctl(name: u64, value: Option<u64>) -> Result<u64, Errno> {
+    let (set, value) = match value {
+        Some(value) => (1, value),
+        None => (0, 0),
+    };
+    result(unsafe { syscall3(number::SYSCTL, name, set, value) })
+}
+
+/// Sleeps until woken by `futex_wake` on the same word, unless the word no longer holds `expected`, in
+/// which case it fails with `EAGAIN` right away. It may also return early, e.g. when a signal arrives, so
+/// the caller has to check the word again either way.
+pub fn futex_wait(word: &AtomicU32, expected: u32) -> Result<(), Errno> {
+    let value = unsafe {
+        syscall3(
+            number::FUTEX,
+            word.as_ptr() as u64,
+            futex::FUTEX_WAIT,
+            u64::from(expected),
+        )
+    };
+    result(value).map(|_| ())
+}
+
+/// Wakes up to `count` callers sleeping in `futex_wait` on `word`, returning how many were woken.
+pub fn futex_wake(word: &AtomicU32, count: u64) -> Result<u64, Errno> {
+    result(unsafe {
+        syscall3(
+            number::FUTEX,
+            word.as_ptr() as u64,
+            futex::FUTEX_WAKE,
+            count,
+        )
+    })
+}
+
+/// Reads the clock `clock`, one of `time::CLOCK_REALTIME` and `time::CLOCK_MONOTONIC`.
+pub fn clock_gettime(clock: u64) -> Result<time::Timespec, Errno> {
+    let mut timespec = time::Timespec::default();
+    result(unsafe { syscall3(number::CLOCK_GETTIME, clock, &raw mut timespec as u64, 0) })
+        .map(|_| timespec)
+}
+
+/// Sleeps for at least `duration`, rounded up to the kernel's timer tick. Fails with `EINTR` if woken
+/// early, e.g. when a signal arrives.
+pub fn nanosleep(duration: &time::Timespec) -> Result<(), Errno> {
+    result(unsafe { syscall3(number::NANOSLEEP, duration as *const _ as u64, 0, 0) }).map(|_| ())
+}
+
+/// Maps the input ring into the calling process, which then receives every keyboard and mouse event
+/// through it. Only processes the kernel started itself may hold it, the others get `EPERM`. One process
+/// holds the ring at a time: the others get `EBUSY` until it exits or execs. Asking again returns the
+/// same ring, emptied.
+pub fn input_ring() -> Result<&'static input::InputRing, Errno> {
+    result(unsafe { syscall3(number::INPUT_RING, 0, 0, 0) })
+        .map(|address| unsafe { &*(address as *const input::InputRing) })
+}
+
+/// Opens a window at `rect` on the screen, above the console, and maps its pixels into the calling
+/// process, which keeps it until it exits or execs. Returns the pixels and their layout. What is drawn
+/// only shows up once it is flushed with `surface_flush`. A process has one window at most: asking for
+/// another fails with `EBUSY`, and so does asking while every window is taken. Fails with `ENODEV`
+/// without a framebuffer.
+pub fn surface_open(rect: &gfx::Rect) -> Result<(&'static mut [u8], gfx::SurfaceInfo), Errno> {
+    let mut info = gfx::SurfaceInfo::default();
+    let address = result(unsafe {
+        syscall3(
+            number::SURFACE_OPEN,
+            rect as *const gfx::Rect as u64,
+            &raw mut info as u64,
+            0,
+        )
+    })?;
+
+    let pixels =
+        unsafe { core::slice::from_raw_parts_mut(address as *mut u8, info.byte_len as usize) };
+    Ok((pixels, info))
+}
+
+/// Shows the `rects` of the caller's window, in its own coordinates, on the screen, copying nothing
+/// else. At most `gfx::MAX_DAMAGE_RECTS` rectangles.
+pub fn surface_flush(rects: &[gfx::Rect]) -> Result<(), Errno> {
+    let value = unsafe {
+        syscall3(
+            number::SURFACE_FLUSH,
+            rects.as_ptr() as u64,
+            rects.len() as u64,
+            0,
+        )
+    };
+    result(value).map(|_| ())
+}
+
+/// Ends the calling program with the given exit code.
+pub fn exit(code: i32) -> ! {
+    unsafe { syscall3(number::EXIT, code as u64, 0, 0) };
+    unreachable!("exit returned");
+}
+
+/// The process id of the caller.
+pub fn getpid() -> u64 {
+    // getpid can't fail.
+    unsafe { syscall3(number::GETPID, 0, 0, 0) as u64 }
+}
+
+/// Creates a copy of the calling process. Returns the PID of the child in the parent, and 0 in the child.
+pub fn fork() -> Result<u64, Errno> {
+    result(unsafe { syscall3(number::FORK, 0, 0, 0) })
+}
+
+/// Replaces the program of the calling process with the executable at `path`. Only returns on errors.
+pub fn execve(path: &str) -> Errno {
+    let value = unsafe { syscall3(number::EXECVE, path.as_ptr() as u64, path.len() as u64, 0) };
+    Errno(-value)
+}
+
+/// Waits until the child with the given PID, or any child if `pid` is `None`, exits. Returns its PID and
+/// exit code.
+pub fn wait(pid: Option<u64>) -> Result<(u64, i32), Errno> {
+    let mut exit_code = 0i32;
+    let value = unsafe {
+        syscall3(
+            number::WAIT,
+            pid.unwrap_or(0),
+            &mut exit_code as *mut i32 as u64,
+            0,
+        )
+    };
+    result(value).map(|pid| (pid, exit_code))
+}
+
+/// Changes the limit of a `rlimit` resource for the calling process.
+pub fn setrlimit(resource: u64, limit: &rlimit::Rlimit) -> Result<(), Errno> {
+    let value = unsafe {
+        syscall3(
+            number::SETRLIMIT,
+            resource,
+            limit as *const rlimit::Rlimit as u64,
+            0,
+        )
+    };
+    result(value).map(|_| ())
+}
+
+/// The current limit of a `rlimit` resource.
+pub fn getrlimit(resource: u64) -> Result<rlimit::Rlimit, Errno> {
+    let mut limit = rlimit::Rlimit { soft: 0, hard: 0 };
+    let value = unsafe {
+        syscall3(
+            number::GETRLIMIT,
+            resource,
+            &mut limit as *mut rlimit::Rlimit as u64,
+            0,
+        )
+    };
+    result(value).map(|_| limit)
+}
+
+/// Writes `message` to the kernel log at a `log` level, tagged with the caller's PID. Fails with `EAGAIN`
+/// when the caller logs faster than its `LOG_RATE` limit allows.
+pub fn log(level: u64, message: &str) -> Result<(), Errno> {
+    let value = unsafe {
+        syscall3(
+            number::LOG,
+            level,
+            message.as_ptr() as u64,
+            message.len() as u64,
+        )
+    };
+    result(value).map(|_| ())
+}
+
+/// Moves the end of the heap to `address` and returns the end it has now, which is the old one if it
+/// couldn't be moved. With 0, just returns the current end.
+pub fn brk(address: u64) -> u64 {
+    // brk reports failure by not moving the end.
+    unsafe { syscall3(number::BRK, address, 0, 0) as u64 }
+}
+
+/// Grows (or shrinks) the heap by `increment` bytes, returning the old end of the heap, where new memory
+/// starts.
+pub fn sbrk(increment: i64) -> Result<*mut u8, Errno> {
+    let old = brk(0);
+    let new = old
+        .checked_add_signed(increment)
+        .ok_or(Errno(errno::ENOMEM))?;
+
+    if increment != 0 && brk(new) != new {
+        return Err(Errno(errno::ENOMEM));
+    }
+    Ok(old as *mut u8)
+}
+
+/// Maps `len` bytes of zeroed memory with the given `mman::PROT_*` protection. Only private anonymous
+/// mappings are supported, so `flags` must be `MAP_PRIVATE | MAP_ANONYMOUS`.
+pub fn mmap(len: usize, prot: u64, flags: u64) -> Result<*mut u8, Errno> {
+    let value = unsafe { syscall6(number::MMAP, [0, len as u64, prot, flags, u64::MAX, 0]) };
+    result(value).map(|address| address as *mut u8)
+}
+
+/// Unmaps the pages in the `len` bytes at `address`, which must be page-aligned.
+///
+/// # Safety
+///
+/// Nothing may use the memory afterwards.
+pub unsafe fn munmap(address: *mut u8, len: usize) -> Result<(), Errno> {
+    let value = unsafe { syscall3(number::MUNMAP, address as u64, len as u64, 0) };
+    result(value).map(|_| ())
+}
+
+/// Single-steps the process `pid`, or the caller if `pid` is 0, and records every instruction it executes
+/// in `start..end` in the kernel's trace buffer. Only the caller itself and its children can be traced.
+/// An empty range stops tracing and writes the recorded instructions to the serial port.
+pub fn trace(pid: u64, start: u64, end: u64) -> Result<(), Errno> {
+    result(unsafe { syscall3(number::TRACE, pid, start, end) }).map(|_| ())
+}
+
+/// Sets the FS base of the calling process, where thread-local storage is addressed from.
+pub fn set_fs_base(base: u64) -> Result<(), Errno> {
+    result(unsafe { syscall3(number::ARCH_PRCTL, prctl::ARCH_SET_FS, base, 0) }).map(|_| ())
+}
+
+/// The FS base of the calling process.
+pub fn fs_base() -> Result<u64, Errno> {
+    let mut base = 0u64;
+    let value = unsafe {
+        syscall3(
+            number::ARCH_PRCTL,
+            prctl::ARCH_GET_FS,
+            &mut base as *mut u64 as u64,
+            0,
+        )
+    };
+    result(value).map(|_| base)
+}
+
+/// Sets or gets the FS or GS base of the calling process with a `prctl` code. For the `GET` codes,
+/// `address` points to where the base is stored.
+///
+/// # Safety
+///
+/// For the `GET` codes, `address` must point to a writable `u64`.
+pub unsafe fn arch_prctl(code: u64, address: u64) -> Result<(), Errno> {
+    result(unsafe { syscall3(number::ARCH_PRCTL, code, address, 0) }).map(|_| ())
+}
+
+/// Sends a `signal` to the process `pid`. A signal of 0 only checks that the process exists.
+pub fn kill(pid: u64, signal: u64) -> Result<(), Errno> {
+    result(unsafe { syscall3(number::KILL, pid, signal, 0) }).map(|_| ())
+}
+
+/// Sets what `signal` does to the calling process.
+pub fn sigaction(signal: u64, action: signal::Action) -> Result<(), Errno> {
+    let handler = match action {
+        signal::Action::Default => signal::SIG_DFL,
+        signal::Action::Ignore => signal::SIG_IGN,
+        signal::Action::Handler(handler) => handler as usize as u64,
+    };
+
+    let value = unsafe {
+        syscall3(
+            number::SIGACTION,
+            signal,
+            handler,
+            sigreturn_trampoline as *const () as u64,
+        )
+    };
+    result(value).map(|_| ())
+}
+
+/// Where signal handlers return to: resumes the process where the signal interrupted it.
+#[unsafe(naked)]
+extern "C" fn sigreturn_trampoline() {
+    core::arch::naked_asm!(
+        "mov eax, {sigreturn}",
+        "syscall",
+        "ud2",
+        sigreturn = const number::SIGRETURN,
+    );
+}
+
+/// Writes all of `bytes` to `fd`, retrying after partial writes.
+fn write_all(fd: u64, mut bytes: &[u8]) -> Result<(), Errno> {
+    while !bytes.is_empty() {
+        match write(fd, bytes)? {
+            0 => return Err(Errno(errno::EAGAIN)),
+            written => bytes = &bytes[written..],
+        }
+    }
+    Ok(())
+}
+
+/// Standard output as a `fmt::Write`, so `write!` can be used without an allocator.
+pub struct Stdout;
+
+impl fmt::Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_all(STDOUT, s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// Standard output collected in a buffer of `N` bytes, written when the buffer fills up, on `flush` and
+/// when dropped. `write!` makes a `write_str` call per piece of the format string, each of which would
+/// otherwise be a syscall.
+pub struct BufferedStdout<const N: usize = 1024> {
+    buffer: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> BufferedStdout<N> {
+    pub const fn new() -> Self {
+        BufferedStdout {
+            buffer: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Writes out what is buffered.
+    pub fn flush(&mut self) -> Result<(), Errno> {
+        let len = core::mem::take(&mut self.len);
+        write_all(STDOUT, &self.buffer[..len])
+    }
+}
+
+impl<const N: usize> Default for BufferedStdout<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> fmt::Write for BufferedStdout<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let bytes = s.as_bytes();
+
+        if self.len + bytes.len() > N {
+            // Both go out in one syscall rather than two.
+            let buffered = &self.buffer[..self.len];
+            let written = writev(STDOUT, &[uio::IoVec::new(buffered), uio::IoVec::new(bytes)])
+                .map_err(|_| fmt::Error)?;
+
+            // A partial write is finished the slow way.
+            let (buffered_left, bytes_left) = match written.checked_sub(buffered.len()) {
+                Some(past_buffered) => (&[][..], &bytes[past_buffered..]),
+                None => (&buffered[written..], bytes),
+            };
+            write_all(STDOUT, buffered_left).map_err(|_| fmt::Error)?;
+            write_all(STDOUT, bytes_left).map_err(|_| fmt::Error)?;
+
+            self.len = 0;
+            return Ok(());
+        }
+
+        self.buffer[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+}
+
+impl<const N: usize> Drop for BufferedStdout<N> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}